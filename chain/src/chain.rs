@@ -2,51 +2,115 @@ use bigint::{H256, U256};
 use channel::{self, Receiver, Sender};
 use ckb_chain_spec::consensus::Consensus;
 use ckb_core::block::Block;
-use ckb_core::extras::BlockExt;
-use ckb_core::header::BlockNumber;
+use ckb_core::extras::{BlockEconomicState, BlockExt, BlockRejection, EpochStats};
+use ckb_core::header::{BlockNumber, Header};
 use ckb_core::service::{Request, DEFAULT_CHANNEL_SIZE};
+use ckb_core::transaction::Transaction;
+use ckb_core::Capacity;
 use ckb_db::batch::Batch;
 use ckb_notify::{ForkBlocks, NotifyController, NotifyService};
 use ckb_shared::error::SharedError;
 use ckb_shared::index::ChainIndex;
 use ckb_shared::shared::{ChainProvider, Shared, TipHeader};
 use ckb_time::now_ms;
-use ckb_verification::{BlockVerifier, Verifier};
+use ckb_verification::{BlockVerifier, Error as VerifyError, ScriptProfiler, Verifier};
+use config::Config;
 use error::ProcessBlockError;
+use fnv::FnvHashMap;
 use log;
+use quarantine::Quarantine;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use script_profile::ScriptProfileReport;
 use std::cmp;
 use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 
+/// Blocks of a batch whose context-free checks run together, the checks of the next chunk run
+/// while the current one is committed
+const CONTEXT_FREE_CHUNK_SIZE: usize = 16;
+
 pub struct ChainService<CI> {
     shared: Shared<CI>,
     notify: NotifyController,
+    config: Config,
+    quarantine: Option<Quarantine>,
+    script_profile: Option<Arc<ScriptProfileReport>>,
+    // Runs the verification of the batches, apart from the global pool whose tasks may wait
+    // for the tip lock a batch holds
+    verify_pool: ThreadPool,
 }
 
+/// The block to process and the index of the peer it was received from, if any
+type ProcessBlockArgs = (Arc<Block>, Option<usize>);
+/// The blocks to process in order and the index of the peer they were received from, if any
+type ProcessBlocksArgs = (Vec<Arc<Block>>, Option<usize>);
+
 #[derive(Clone)]
 pub struct ChainController {
-    process_block_sender: Sender<Request<Arc<Block>, Result<(), ProcessBlockError>>>,
+    process_block_sender: Sender<Request<ProcessBlockArgs, Result<(), ProcessBlockError>>>,
+    process_blocks_sender: Sender<Request<ProcessBlocksArgs, Result<(), ProcessBlockError>>>,
 }
 
 pub struct ChainReceivers {
-    process_block_receiver: Receiver<Request<Arc<Block>, Result<(), ProcessBlockError>>>,
+    process_block_receiver: Receiver<Request<ProcessBlockArgs, Result<(), ProcessBlockError>>>,
+    process_blocks_receiver: Receiver<Request<ProcessBlocksArgs, Result<(), ProcessBlockError>>>,
 }
 
 impl ChainController {
     pub fn new() -> (ChainController, ChainReceivers) {
         let (process_block_sender, process_block_receiver) = channel::bounded(DEFAULT_CHANNEL_SIZE);
+        let (process_blocks_sender, process_blocks_receiver) =
+            channel::bounded(DEFAULT_CHANNEL_SIZE);
         (
             ChainController {
                 process_block_sender,
+                process_blocks_sender,
             },
             ChainReceivers {
                 process_block_receiver,
+                process_blocks_receiver,
             },
         )
     }
 
+    /// Verifies and stores the block.
+    ///
+    /// Processing a block is idempotent: a block already stored is neither verified nor
+    /// inserted again, it returns `Ok(())` like the first time and is only counted in
+    /// `SyncStats::duplicate_blocks`. Relay, sync and the RPC may all hand over the same block.
     pub fn process_block(&self, block: Arc<Block>) -> Result<(), ProcessBlockError> {
-        Request::call(&self.process_block_sender, block).expect("process_block() failed")
+        Request::call(&self.process_block_sender, (block, None)).expect("process_block() failed")
+    }
+
+    /// Same as `process_block`, the peer is recorded if the block gets rejected
+    pub fn process_block_from_peer(
+        &self,
+        block: Arc<Block>,
+        peer: usize,
+    ) -> Result<(), ProcessBlockError> {
+        Request::call(&self.process_block_sender, (block, Some(peer)))
+            .expect("process_block_from_peer() failed")
+    }
+
+    /// Processes the blocks in order like `process_block`, for importing many blocks at once,
+    /// a parent must come before its children. The batch is committed under a single
+    /// acquisition of the tip lock, while the cheap checks not reading the chain run in
+    /// parallel for the next blocks.
+    ///
+    /// Stops at the first invalid block and returns its error, the blocks before it stay stored.
+    pub fn process_blocks(&self, blocks: Vec<Arc<Block>>) -> Result<(), ProcessBlockError> {
+        Request::call(&self.process_blocks_sender, (blocks, None)).expect("process_blocks() failed")
+    }
+
+    /// Same as `process_blocks`, the peer is recorded if a block gets rejected
+    pub fn process_blocks_from_peer(
+        &self,
+        blocks: Vec<Arc<Block>>,
+        peer: usize,
+    ) -> Result<(), ProcessBlockError> {
+        Request::call(&self.process_blocks_sender, (blocks, Some(peer)))
+            .expect("process_blocks_from_peer() failed")
     }
 }
 
@@ -57,8 +121,27 @@ pub struct BlockInsertionResult {
 }
 
 impl<CI: ChainIndex + 'static> ChainService<CI> {
-    pub fn new(shared: Shared<CI>, notify: NotifyController) -> ChainService<CI> {
-        ChainService { shared, notify }
+    pub fn new(shared: Shared<CI>, notify: NotifyController, config: Config) -> ChainService<CI> {
+        let quarantine = config
+            .quarantine_dir
+            .as_ref()
+            .map(|dir| Quarantine::new(dir, config.quarantine_max_bytes));
+        let script_profile = config
+            .script_profile_path
+            .as_ref()
+            .map(|path| Arc::new(ScriptProfileReport::new(path)));
+        let verify_pool = ThreadPoolBuilder::new()
+            .thread_name(|index| format!("ChainVerify{}", index))
+            .build()
+            .expect("Start the chain verification pool failed");
+        ChainService {
+            shared,
+            notify,
+            config,
+            quarantine,
+            script_profile,
+            verify_pool,
+        }
     }
 
     pub fn start<S: ToString>(
@@ -72,34 +155,260 @@ impl<CI: ChainIndex + 'static> ChainService<CI> {
             thread_builder = thread_builder.name(name.to_string());
         }
         thread_builder
-            .spawn(move || loop {
-                select! {
-                    recv(receivers.process_block_receiver, msg) => match msg {
-                        Some(Request { responder, arguments: block }) => {
-                            responder.send(self.process_block(block));
-                        },
-                        None => {
-                            error!(target: "chain", "process_block_receiver closed");
-                            break;
-                        },
+            .spawn(move || {
+                self.resume_reorg();
+                loop {
+                    select! {
+                        recv(receivers.process_block_receiver, msg) => match msg {
+                            Some(Request { responder, arguments: (block, peer) }) => {
+                                let result = self.process_block(Arc::clone(&block));
+                                if let Err(ref err) = result {
+                                    self.notify_rejected_block(&block, peer, err);
+                                }
+                                responder.send(result);
+                            },
+                            None => {
+                                error!(target: "chain", "process_block_receiver closed");
+                                break;
+                            },
+                        }
+                        recv(receivers.process_blocks_receiver, msg) => match msg {
+                            Some(Request { responder, arguments: (blocks, peer) }) => {
+                                responder.send(self.process_blocks(&blocks, peer));
+                            },
+                            None => {
+                                error!(target: "chain", "process_blocks_receiver closed");
+                                break;
+                            },
+                        }
                     }
                 }
             }).expect("Start ChainService failed")
     }
 
+    /// Checks the genesis against the chain spec and the store, through the same
+    /// `BlockVerifier` as the other blocks. Run at startup on the spec's own genesis, it
+    /// detects a database initialized from another spec.
+    pub fn verify_genesis(&self, genesis: &Block) -> Result<(), ProcessBlockError> {
+        BlockVerifier::new(self.shared.clone())
+            .verify(genesis)
+            .map_err(ProcessBlockError::Verification)
+    }
+
+    // The tip lock is only taken to store the verified block
     fn process_block(&mut self, block: Arc<Block>) -> Result<(), ProcessBlockError> {
+        let verifier = self.block_verifier(self.shared.clone());
+        let result = self
+            .check_block(&block, |block| verifier.verify(block))
+            .and_then(|insert| {
+                if insert {
+                    let mut tip_header = self.shared.tip_header().write();
+                    self.commit_block(&mut tip_header, &block).map(Some)
+                } else {
+                    Ok(None)
+                }
+            });
+        self.persist_sync_stats();
+        result.map(|inserted| {
+            if let Some(insert_result) = inserted {
+                self.post_insert_result(block, insert_result);
+            }
+        })
+    }
+
+    // The notifications are sent and the sync statistics written once the batch is committed
+    // and the tip lock released
+    fn process_blocks(
+        &mut self,
+        blocks: &[Arc<Block>],
+        peer: Option<usize>,
+    ) -> Result<(), ProcessBlockError> {
+        let (inserted, rejected) = self.commit_blocks(blocks);
+        for (block, insert_result) in inserted {
+            self.post_insert_result(block, insert_result);
+        }
+        self.persist_sync_stats();
+        match rejected {
+            Some((block, err)) => {
+                self.notify_rejected_block(&block, peer, &err);
+                Err(err)
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Verifies and stores the blocks in order, up to the first rejected one, holding the tip
+    // lock from the first block to the last. The blocks are verified through a handle reading
+    // the tip from the store meanwhile, and the context-free checks of the next chunk run
+    // while a chunk is committed.
+    fn commit_blocks(
+        &self,
+        blocks: &[Arc<Block>],
+    ) -> (
+        Vec<(Arc<Block>, BlockInsertionResult)>,
+        Option<(Arc<Block>, ProcessBlockError)>,
+    ) {
+        let context_free_verifier = self.block_verifier(self.shared.clone());
+        let verifier = self.block_verifier(self.shared.tip_lock_holder());
+        let chunks = blocks.chunks(CONTEXT_FREE_CHUNK_SIZE).collect::<Vec<_>>();
+
+        let mut inserted = Vec::with_capacity(blocks.len());
+        let mut tip_header = self.shared.tip_header().write();
+        let mut next = chunks
+            .first()
+            .map(|chunk| self.check_context_free(&context_free_verifier, chunk));
+        for (index, chunk) in chunks.iter().enumerate() {
+            let context_free = next
+                .take()
+                .and_then(|receiver| receiver.recv())
+                .expect("context-free checks of the chunk");
+            next = chunks
+                .get(index + 1)
+                .map(|chunk| self.check_context_free(&context_free_verifier, chunk));
+            for (block, checked) in chunk.iter().zip(context_free) {
+                let result = self
+                    .check_block(block, |block| {
+                        checked.and_then(|_| verifier.verify_contextual(block))
+                    }).and_then(|insert| {
+                        if insert {
+                            self.commit_block(&mut tip_header, block).map(Some)
+                        } else {
+                            Ok(None)
+                        }
+                    });
+                match result {
+                    Ok(Some(insert_result)) => inserted.push((Arc::clone(block), insert_result)),
+                    Ok(None) => {}
+                    Err(err) => return (inserted, Some((Arc::clone(block), err))),
+                }
+            }
+        }
+        (inserted, None)
+    }
+
+    // Runs the context-free checks of the blocks on the verification pool, the results are
+    // sent in the order of the blocks
+    fn check_context_free(
+        &self,
+        verifier: &BlockVerifier<Shared<CI>>,
+        blocks: &[Arc<Block>],
+    ) -> Receiver<Vec<Result<(), VerifyError>>> {
+        let (sender, receiver) = channel::bounded(1);
+        let verifier = verifier.clone();
+        let blocks = blocks.to_vec();
+        let verification = self.shared.consensus().verification;
+        self.verify_pool.spawn(move || {
+            let checked = blocks
+                .par_iter()
+                .map(|block| {
+                    if verification {
+                        verifier.verify_context_free(block)
+                    } else {
+                        Ok(())
+                    }
+                }).collect();
+            sender.send(checked);
+        });
+        receiver
+    }
+
+    fn block_verifier(&self, shared: Shared<CI>) -> BlockVerifier<Shared<CI>> {
+        let verifier = BlockVerifier::new(shared);
+        if self.config.skip_script_verify {
+            verifier.skip_script_verify()
+        } else if let Some(ref report) = self.script_profile {
+            verifier.profile_scripts(Arc::clone(report) as Arc<dyn ScriptProfiler>)
+        } else {
+            verifier
+        }
+    }
+
+    // Verifies a block, `true` if it's left to store. The sync statistics are only updated in
+    // memory.
+    fn check_block<F>(&self, block: &Block, verify: F) -> Result<bool, ProcessBlockError>
+    where
+        F: FnOnce(&Block) -> Result<(), VerifyError>,
+    {
         debug!(target: "chain", "begin processing block: {}", block.header().hash());
+        // only valid blocks are stored, a stored block has nothing left to do
+        if self.shared.block_header(&block.header().hash()).is_some() {
+            debug!(target: "chain", "block {} already stored", block.header().hash());
+            self.shared.sync_stats().lock().duplicate_blocks += 1;
+            return Ok(false);
+        }
+        // The genesis is stored when the shared is initialized, a received one is only checked,
+        // whatever the verification setting, as a mismatch means a peer on another chain
+        if block.is_genesis() {
+            let result = self.verify_genesis(block);
+            if let Err(ref err) = result {
+                self.shared
+                    .sync_stats()
+                    .lock()
+                    .record_invalid_block(err.class());
+            }
+            return result.map(|_| false);
+        }
         if self.shared.consensus().verification {
-            BlockVerifier::new(self.shared.clone())
-                .verify(&block)
-                .map_err(ProcessBlockError::Verification)?
+            if let Err(err) = verify(block) {
+                let err = ProcessBlockError::Verification(err);
+                self.shared
+                    .sync_stats()
+                    .lock()
+                    .record_invalid_block(err.class());
+                return Err(err);
+            }
         }
+        Ok(true)
+    }
+
+    // Stores a verified block under the tip lock, the notifications are left to
+    // `post_insert_result`
+    fn commit_block(
+        &self,
+        tip_header: &mut TipHeader,
+        block: &Block,
+    ) -> Result<BlockInsertionResult, ProcessBlockError> {
         let insert_result = self
-            .insert_block(&block)
+            .insert_block(tip_header, block)
             .map_err(ProcessBlockError::Shared)?;
-        self.post_insert_result(block, insert_result);
+        let reorg_depth = insert_result.fork_blks.old_blks().len() as u64;
+        let mut stats = self.shared.sync_stats().lock();
+        stats.blocks_processed += 1;
+        if reorg_depth > 0 {
+            stats.record_reorg(reorg_depth);
+        }
         debug!(target: "chain", "finish processing block");
-        Ok(())
+        Ok(insert_result)
+    }
+
+    fn notify_rejected_block(&self, block: &Block, peer: Option<usize>, err: &ProcessBlockError) {
+        let rejection = BlockRejection {
+            hash: block.header().hash(),
+            peer,
+            reason: err.class().to_string(),
+            error: format!("{:?}", err),
+            code: err.code(),
+        };
+        info!(target: "chain", "block rejected: {:?}", rejection);
+        if let Some(ref quarantine) = self.quarantine {
+            match quarantine.store(now_ms(), block, &rejection, err) {
+                Ok(path) => info!(target: "chain", "rejected block saved to {:?}", path),
+                Err(err) => warn!(target: "chain", "failed to save rejected block: {:?}", err),
+            }
+        }
+        self.notify.notify_rejected_block(Arc::new(rejection));
+    }
+
+    // Also flushes the counters updated by the network protocols since the last block
+    fn persist_sync_stats(&self) {
+        let stats = self.shared.sync_stats().lock();
+        let result = self.shared.store().save_with_batch(|batch| {
+            self.shared.store().insert_sync_stats(batch, &stats);
+            Ok(())
+        });
+        if let Err(err) = result {
+            warn!(target: "chain", "failed to persist sync stats: {:?}", err);
+        }
     }
 
     fn check_transactions(&self, batch: &mut Batch, b: &Block) -> Result<H256, SharedError> {
@@ -127,15 +436,15 @@ impl<CI: ChainIndex + 'static> ChainService<CI> {
             .ok_or(SharedError::InvalidOutput)
     }
 
-    fn insert_block(&self, block: &Block) -> Result<BlockInsertionResult, SharedError> {
+    fn insert_block(
+        &self,
+        tip_header: &mut TipHeader,
+        block: &Block,
+    ) -> Result<BlockInsertionResult, SharedError> {
         let mut new_best_block = false;
         let mut output_root = H256::zero();
         let mut total_difficulty = U256::zero();
 
-        let mut old_cumulative_blks = Vec::new();
-        let mut new_cumulative_blks = Vec::new();
-
-        let mut tip_header = self.shared.tip_header().write();
         let tip_number = tip_header.number();
         self.shared.store().save_with_batch(|batch| {
             let root = self.check_transactions(batch, block)?;
@@ -177,40 +486,205 @@ impl<CI: ChainIndex + 'static> ChainService<CI> {
             Ok(())
         })?;
 
-        if new_best_block {
-            debug!(target: "chain", "update index");
-            let new_tip_header =
-                TipHeader::new(block.header().clone(), total_difficulty, output_root);
-            self.shared.store().save_with_batch(|batch| {
-                self.update_index(
-                    batch,
-                    tip_number,
-                    block,
-                    &mut old_cumulative_blks,
-                    &mut new_cumulative_blks,
-                );
-                self.shared
-                    .store()
-                    .insert_tip_header(batch, &block.header());
-                self.shared.store().rebuild_tree(output_root);
-                Ok(())
-            })?;
-            *tip_header = new_tip_header;
-            debug!(target: "chain", "update index release");
-        }
+        let fork_blks = if new_best_block {
+            self.reorg_to(tip_header, block, output_root, total_difficulty)?
+        } else {
+            ForkBlocks::new(Vec::new(), Vec::new())
+        };
 
         Ok(BlockInsertionResult {
             new_best_block,
-            fork_blks: ForkBlocks::new(old_cumulative_blks, new_cumulative_blks),
+            fork_blks,
         })
     }
 
+    // Makes the block the tip. A reorg deeper than `max_reorg_blocks` first moves the main chain
+    // through intermediate blocks, detaching the old fork from its tip down then attaching the
+    // new one, each pass committed on its own. The block is saved as the reorg target until it's
+    // the tip, so a reorg interrupted between passes is carried on at the next start.
+    fn reorg_to(
+        &self,
+        tip_header: &mut TipHeader,
+        block: &Block,
+        output_root: H256,
+        total_difficulty: U256,
+    ) -> Result<ForkBlocks, SharedError> {
+        while let Some(step) = self.next_reorg_step(tip_header.number(), block) {
+            let hash = step.header().hash();
+            let step_root = self
+                .shared
+                .store()
+                .get_output_root(&hash)
+                .expect("stored block output root");
+            let step_difficulty = self
+                .shared
+                .block_ext(&hash)
+                .expect("stored block ext")
+                .total_difficulty;
+            debug!(target: "chain", "reorg to {} through {} => {}", block.header().hash(), step.header().number(), hash);
+            let attached = self.shared.block_hash(step.header().number()) != Some(hash);
+            let mut fork_blks = self.switch_tip(
+                tip_header,
+                &step,
+                step_root,
+                step_difficulty,
+                Some(&block.header().hash()),
+            )?;
+            // the intermediate tips are not announced, only the blocks they switch
+            let step = Arc::new(step);
+            self.update_uncle_candidates(&step, true, &fork_blks);
+            if attached {
+                fork_blks.push_new(Block::clone(&step));
+            }
+            if !fork_blks.old_blks().is_empty() || !fork_blks.new_blks().is_empty() {
+                self.notify.notify_switch_fork(Arc::new(fork_blks));
+            }
+        }
+        self.switch_tip(tip_header, block, output_root, total_difficulty, None)
+    }
+
+    // The block the main chain moves to on the way to `block`, `None` once the rest of the reorg
+    // fits in a single pass
+    fn next_reorg_step(&self, tip_number: BlockNumber, block: &Block) -> Option<Block> {
+        let limit = self.config.max_reorg_blocks;
+        if limit == 0 {
+            return None;
+        }
+        let fork_number = self.fork_number(block);
+        let hash = if tip_number - fork_number > limit {
+            self.shared.block_hash(tip_number - limit)?
+        } else if block.header().number() - fork_number > limit {
+            self.shared
+                .get_ancestor(&block.header().hash(), fork_number + limit)?
+                .hash()
+        } else {
+            return None;
+        };
+        self.shared.block(&hash)
+    }
+
+    // The number of the last block the main chain and the chain of `block` share, only the
+    // headers of the fork are read
+    fn fork_number(&self, block: &Block) -> BlockNumber {
+        let mut header = block.header().clone();
+        while self.shared.block_hash(header.number()) != Some(header.hash()) {
+            header = self
+                .shared
+                .block_header(&header.parent_hash())
+                .expect("stored block parent");
+        }
+        header.number()
+    }
+
+    // Switches the main chain to the stored block in a single batch, `reorg_target` is the block
+    // a longer reorg goes on to
+    fn switch_tip(
+        &self,
+        tip_header: &mut TipHeader,
+        block: &Block,
+        output_root: H256,
+        total_difficulty: U256,
+        reorg_target: Option<&H256>,
+    ) -> Result<ForkBlocks, SharedError> {
+        debug!(target: "chain", "update index");
+        let tip_number = tip_header.number();
+        let mut old_cumulative_blks = Vec::new();
+        let mut new_cumulative_blks = Vec::new();
+        let new_tip_header = TipHeader::new(block.header().clone(), total_difficulty, output_root);
+        self.shared.store().save_with_batch(|batch| {
+            self.update_index(
+                batch,
+                tip_number,
+                block,
+                &mut old_cumulative_blks,
+                &mut new_cumulative_blks,
+            );
+            if self.config.cell_consumer_index {
+                self.update_cell_consumer(batch, block, &old_cumulative_blks, &new_cumulative_blks);
+            }
+            self.update_cell_indexes(batch, block, &old_cumulative_blks, &new_cumulative_blks);
+            let attached = new_cumulative_blks
+                .iter()
+                .chain(Some(block))
+                .collect::<Vec<_>>();
+            // the inputs of the attached transactions may be in the blocks attached before them
+            let transactions = attached
+                .iter()
+                .flat_map(|b| b.commit_transactions())
+                .map(|tx| (tx.hash(), tx.clone()))
+                .collect::<FnvHashMap<_, _>>();
+            let states = self.update_economic_states(batch, &attached, &transactions)?;
+            self.update_epoch_stats(batch, tip_number, &attached, &transactions, &states)?;
+            self.shared
+                .store()
+                .insert_tip_header(batch, &block.header());
+            match reorg_target {
+                Some(hash) => self.shared.store().insert_reorg_target(batch, hash),
+                None => self.shared.store().delete_reorg_target(batch),
+            }
+            self.shared.store().rebuild_tree(output_root);
+            Ok(())
+        })?;
+        *tip_header = new_tip_header;
+        // a reorg step back on the main chain is detached and attached again in the batch, it
+        // stays on the main chain
+        let hash = block.header().hash();
+        old_cumulative_blks.retain(|old_block| old_block.header().hash() != hash);
+        // the transactions were verified against cells the detached blocks may have created
+        if !old_cumulative_blks.is_empty() {
+            self.shared.txs_verify_cache().lock().clear();
+        }
+        debug!(target: "chain", "update index release");
+        Ok(ForkBlocks::new(old_cumulative_blks, new_cumulative_blks))
+    }
+
+    // A reorg interrupted between its passes, see `reorg_to`, is carried on to its target
+    fn resume_reorg(&mut self) {
+        let target = match self
+            .shared
+            .store()
+            .get_reorg_target()
+            .and_then(|hash| self.shared.block(&hash))
+        {
+            Some(target) => target,
+            None => return,
+        };
+        info!(target: "chain", "resume the reorg to {}", target.header().hash());
+        let hash = target.header().hash();
+        let output_root = self
+            .shared
+            .store()
+            .get_output_root(&hash)
+            .expect("stored block output root");
+        let total_difficulty = self
+            .shared
+            .block_ext(&hash)
+            .expect("stored block ext")
+            .total_difficulty;
+        let result = {
+            let mut tip_header = self.shared.tip_header().write();
+            self.reorg_to(&mut tip_header, &target, output_root, total_difficulty)
+        };
+        match result {
+            Ok(fork_blks) => self.post_insert_result(
+                Arc::new(target),
+                BlockInsertionResult {
+                    new_best_block: true,
+                    fork_blks,
+                },
+            ),
+            Err(err) => error!(target: "chain", "failed to resume the reorg: {:?}", err),
+        }
+    }
+
     fn post_insert_result(&mut self, block: Arc<Block>, result: BlockInsertionResult) {
         let BlockInsertionResult {
             new_best_block,
             mut fork_blks,
         } = result;
-        if !fork_blks.old_blks().is_empty() {
+        self.update_uncle_candidates(&block, new_best_block, &fork_blks);
+        // the last pass of a deep reorg may attach blocks without detaching any
+        if !fork_blks.old_blks().is_empty() || !fork_blks.new_blks().is_empty() {
             fork_blks.push_new(Block::clone(&block));
             self.notify.notify_switch_fork(Arc::new(fork_blks.clone()));
         }
@@ -225,7 +699,33 @@ impl<CI: ChainIndex + 'static> ChainService<CI> {
         }
     }
 
+    // A stale block may be an uncle of the next blocks, until it's included or too old. The
+    // blocks detached by a reorg become stale, the attached ones leave the candidates.
+    fn update_uncle_candidates(&self, block: &Arc<Block>, new_best_block: bool, fork: &ForkBlocks) {
+        let mut candidates = self.shared.uncle_candidates().lock();
+        if !new_best_block {
+            candidates.insert(block.header().hash(), Arc::clone(block));
+            return;
+        }
+
+        for detached in fork.old_blks() {
+            candidates.insert(detached.header().hash(), Arc::new(detached.clone()));
+        }
+        for attached in fork.new_blks().iter().chain(::std::iter::once(&**block)) {
+            candidates.remove(&attached.header().hash());
+            for uncle in attached.uncles() {
+                candidates.remove(&uncle.header().hash());
+            }
+        }
+        // the next block is the child of this one, deeper candidates can't be its uncles
+        let max_uncles_age = self.shared.consensus().max_uncles_age() as BlockNumber;
+        let min_number = (block.header().number() + 1).saturating_sub(max_uncles_age);
+        candidates.retain(|_, candidate| candidate.header().number() >= min_number);
+    }
+
     // we found new best_block total_difficulty > old_chain.total_difficulty
+    // The detached blocks are collected from the old tip down, the attached ones from the fork
+    // point up, excluding the new best block itself.
     fn update_index(
         &self,
         batch: &mut Batch,
@@ -238,7 +738,7 @@ impl<CI: ChainIndex + 'static> ChainService<CI> {
 
         // The old fork may longer than new fork
         if tip_number >= number {
-            for n in number..=tip_number {
+            for n in (number..=tip_number).rev() {
                 let hash = self.shared.block_hash(n).unwrap();
                 let old_block = self.shared.block(&hash).unwrap();
                 self.shared.store().delete_block_hash(batch, n);
@@ -304,6 +804,219 @@ impl<CI: ChainIndex + 'static> ChainService<CI> {
         new_cumulative_blks.reverse();
     }
 
+    // The epochs the new main chain completes from the first attached block get their
+    // statistics, those it no longer completes lose them. Written in the index batch, the main
+    // chain below the first attached block is unchanged, the attached blocks are at hand.
+    fn update_epoch_stats(
+        &self,
+        batch: &mut Batch,
+        old_tip_number: BlockNumber,
+        attached: &[&Block],
+        transactions: &FnvHashMap<H256, Transaction>,
+        states: &FnvHashMap<H256, BlockEconomicState>,
+    ) -> Result<(), SharedError> {
+        let interval = self.shared.consensus().difficulty_adjustment_interval();
+        let store = self.shared.store();
+        let new_tip_number = attached[attached.len() - 1].header().number();
+        for epoch in (new_tip_number + 1) / interval..(old_tip_number + 1) / interval {
+            store.delete_epoch_stats(batch, epoch);
+        }
+
+        let completed = attached
+            .iter()
+            .filter(|b| (b.header().number() + 1) % interval == 0)
+            .collect::<Vec<_>>();
+        for last in completed {
+            let stats = self.epoch_stats(last.header(), attached, transactions, states)?;
+            store.insert_epoch_stats(batch, &stats);
+        }
+        Ok(())
+    }
+
+    fn epoch_stats(
+        &self,
+        last: &Header,
+        attached: &[&Block],
+        transactions: &FnvHashMap<H256, Transaction>,
+        states: &FnvHashMap<H256, BlockEconomicState>,
+    ) -> Result<EpochStats, SharedError> {
+        let consensus = self.shared.consensus();
+        let interval = consensus.difficulty_adjustment_interval();
+        let epoch = consensus.epoch_number(last.number());
+        let start_number = epoch * interval;
+        let fork_number = attached[0].header().number();
+        let block_at = |number: BlockNumber| {
+            if number >= fork_number {
+                Block::clone(attached[(number - fork_number) as usize])
+            } else {
+                self.shared
+                    .block_hash(number)
+                    .and_then(|hash| self.shared.block(&hash))
+                    .expect("main chain block")
+            }
+        };
+
+        let mut uncles_count = 0;
+        let mut total_fees = 0;
+        for number in start_number..=last.number() {
+            let block = block_at(number);
+            uncles_count += block.uncles().len() as u64;
+            // the genesis pays no fees
+            if block.header().is_genesis() {
+                continue;
+            }
+            let hash = block.header().hash();
+            let fees = states.get(&hash).map(|state| state.fees).or_else(|| {
+                self.shared
+                    .store()
+                    .get_block_economic_state(&hash)
+                    .map(|state| state.fees)
+            });
+            total_fees += match fees {
+                Some(fees) => fees,
+                // the blocks attached before their states were recorded
+                None => self.block_fees(&block, transactions)?,
+            };
+        }
+        // the first interval is the one from the parent of the first block, the genesis has none
+        let first = block_at(start_number.saturating_sub(1)).header().clone();
+        let duration = last.timestamp().saturating_sub(first.timestamp());
+        let average_interval = duration
+            .checked_div(last.number() - first.number())
+            .unwrap_or(0);
+
+        Ok(EpochStats {
+            epoch,
+            start_number,
+            block_count: interval,
+            uncles_count,
+            average_interval,
+            total_fees,
+            difficulty: last.difficulty(),
+            last_hash: last.hash(),
+            duration,
+        })
+    }
+
+    // Recorded once per block, a block claims the same rewards on any chain it's attached to.
+    // Written in the index batch, the states of all the attached blocks are returned, those
+    // recorded before included.
+    fn update_economic_states(
+        &self,
+        batch: &mut Batch,
+        attached: &[&Block],
+        transactions: &FnvHashMap<H256, Transaction>,
+    ) -> Result<FnvHashMap<H256, BlockEconomicState>, SharedError> {
+        let store = self.shared.store();
+        let mut states = FnvHashMap::default();
+        for block in attached {
+            let hash = block.header().hash();
+            let state = match store.get_block_economic_state(&hash) {
+                Some(state) => state,
+                None => {
+                    let state = self.economic_state(block, transactions)?;
+                    store.insert_block_economic_state(batch, &hash, &state);
+                    state
+                }
+            };
+            states.insert(hash, state);
+        }
+        Ok(states)
+    }
+
+    fn economic_state(
+        &self,
+        block: &Block,
+        transactions: &FnvHashMap<H256, Transaction>,
+    ) -> Result<BlockEconomicState, SharedError> {
+        let number = block.header().number();
+        let miner_reward = block
+            .commit_transactions()
+            .iter()
+            .find(|tx| tx.is_cellbase())
+            .and_then(|cellbase| cellbase.outputs_capacity())
+            .unwrap_or(0);
+        Ok(BlockEconomicState {
+            primary_issuance: self.shared.block_reward(number),
+            uncles_reward: self.shared.uncles_reward(number, block.uncles().len()),
+            fees: self.block_fees(block, transactions)?,
+            miner_reward,
+        })
+    }
+
+    // The inputs of the committed transactions are in `transactions`, the attached blocks, or
+    // on the main chain
+    fn block_fees(
+        &self,
+        block: &Block,
+        transactions: &FnvHashMap<H256, Transaction>,
+    ) -> Result<Capacity, SharedError> {
+        let get_transaction = |hash: &H256| {
+            transactions
+                .get(hash)
+                .cloned()
+                .or_else(|| self.shared.get_transaction(hash))
+        };
+        let mut fees = 0;
+        for transaction in block.commit_transactions() {
+            if !transaction.is_cellbase() {
+                fees += self
+                    .shared
+                    .calculate_transaction_fee_with(transaction, &get_transaction)?;
+            }
+        }
+        Ok(fees)
+    }
+
+    // Detached blocks must be removed before the attached ones are inserted,
+    // both forks may spend the same cell.
+    fn update_cell_consumer(
+        &self,
+        batch: &mut Batch,
+        block: &Block,
+        old_cumulative_blks: &[Block],
+        new_cumulative_blks: &[Block],
+    ) {
+        let store = self.shared.store();
+        for old_block in old_cumulative_blks {
+            store.delete_cell_consumer(batch, old_block.commit_transactions());
+        }
+        for new_block in new_cumulative_blks {
+            store.insert_cell_consumer(batch, new_block.commit_transactions());
+        }
+        store.insert_cell_consumer(batch, block.commit_transactions());
+    }
+
+    // The cell set is always kept, the lock index only when enabled
+    fn update_cell_indexes(
+        &self,
+        batch: &mut Batch,
+        block: &Block,
+        old_cumulative_blks: &[Block],
+        new_cumulative_blks: &[Block],
+    ) {
+        // the indexes need both forks in chain order, the detached blocks come from the tip down
+        let old_blks = old_cumulative_blks.iter().rev().collect::<Vec<_>>();
+        let new_blks = new_cumulative_blks
+            .iter()
+            .chain(Some(block))
+            .collect::<Vec<_>>();
+
+        let detached = old_blks
+            .iter()
+            .flat_map(|b| b.commit_transactions())
+            .collect::<Vec<_>>();
+        let attached = new_blks
+            .iter()
+            .flat_map(|b| b.commit_transactions())
+            .collect::<Vec<_>>();
+        let store = self.shared.store();
+        store.update_cell_set(batch, &detached, &attached);
+        if self.config.lock_hash_index {
+            store.update_lock_index(batch, &detached, &attached);
+        }
+    }
+
     fn print_chain(&self, len: u64) {
         debug!(target: "chain", "Chain {{");
 
@@ -349,6 +1062,7 @@ impl<CI: ChainIndex + 'static> ChainService<CI> {
 pub struct ChainBuilder<CI> {
     shared: Shared<CI>,
     notify: Option<NotifyController>,
+    config: Config,
 }
 
 impl<CI: ChainIndex + 'static> ChainBuilder<CI> {
@@ -358,6 +1072,7 @@ impl<CI: ChainIndex + 'static> ChainBuilder<CI> {
         ChainBuilder {
             shared,
             notify: None,
+            config: Config::default(),
         }
     }
 
@@ -366,13 +1081,18 @@ impl<CI: ChainIndex + 'static> ChainBuilder<CI> {
         self
     }
 
+    pub fn config(mut self, value: Config) -> Self {
+        self.config = value;
+        self
+    }
+
     pub fn build(mut self) -> ChainService<CI> {
         let notify = self.notify.take().unwrap_or_else(|| {
             // FIXME: notify should not be optional
             let (_handle, notify) = NotifyService::default().start::<&str>(None);
             notify
         });
-        ChainService::new(self.shared, notify)
+        ChainService::new(self.shared, notify, self.config)
     }
 }
 
@@ -380,6 +1100,7 @@ impl<CI: ChainIndex + 'static> ChainBuilder<CI> {
 pub mod test {
     use super::*;
     use bigint::U256;
+    use ckb_chain_spec::hardfork::HardForkSwitch;
     use ckb_core::block::BlockBuilder;
     use ckb_core::cell::CellProvider;
     use ckb_core::header::{Header, HeaderBuilder};
@@ -389,7 +1110,8 @@ pub mod test {
     use ckb_core::uncle::UncleBlock;
     use ckb_db::memorydb::MemoryKeyValueDB;
     use ckb_shared::shared::SharedBuilder;
-    use ckb_shared::store::ChainKVStore;
+    use ckb_shared::store::{ChainKVStore, ChainStore};
+    use ckb_verification::{Error as VerifyError, GenesisError};
 
     fn start_chain(
         consensus: Option<Consensus>,
@@ -438,10 +1160,9 @@ pub mod test {
             .build()
     }
 
+    // Splits the first output of `parent` into 100 empty cells, the whole input is the fee
     fn create_transaction(parent: H256) -> Transaction {
-        let mut output = CellOutput::default();
-        output.capacity = 100_000_000 / 100 as u64;
-        let outputs: Vec<CellOutput> = vec![output.clone(); 100];
+        let outputs: Vec<CellOutput> = vec![CellOutput::default(); 100];
 
         TransactionBuilder::default()
             .input(CellInput::new(OutPoint::new(parent, 0), Default::default()))
@@ -449,6 +1170,36 @@ pub mod test {
             .build()
     }
 
+    #[test]
+    fn test_process_genesis() {
+        let (chain_controller, shared) = start_chain(None);
+        let genesis = shared.consensus().genesis_block().clone();
+        assert!(
+            chain_controller
+                .process_block(Arc::new(genesis.clone()))
+                .is_ok()
+        );
+        assert_eq!(shared.tip_header().read().number(), 0);
+
+        let other = BlockBuilder::default()
+            .block(genesis.clone())
+            .header(
+                HeaderBuilder::default()
+                    .difficulty(&genesis.header().difficulty())
+                    .timestamp(1)
+                    .build(),
+            ).build();
+        assert_eq!(
+            chain_controller.process_block(Arc::new(other.clone())),
+            Err(ProcessBlockError::Verification(VerifyError::Genesis(
+                GenesisError::Mismatch {
+                    expected: genesis.header().hash(),
+                    actual: other.header().hash(),
+                }
+            )))
+        );
+    }
+
     #[test]
     fn test_genesis_transaction_spend() {
         let tx = TransactionBuilder::default()
@@ -526,6 +1277,131 @@ pub mod test {
         assert!(state.is_current());
     }
 
+    #[test]
+    fn test_cell_consumer_index() {
+        let tx = TransactionBuilder::default()
+            .input(CellInput::new(OutPoint::null(), Default::default()))
+            .output(CellOutput::new(100_000_000, vec![], H256::default(), None))
+            .build();
+        let out_point = OutPoint::new(tx.hash(), 0);
+
+        let genesis_block = BlockBuilder::default()
+            .commit_transaction(tx)
+            .with_header_builder(HeaderBuilder::default().difficulty(&U256::from(1000)));
+        let consensus = Consensus::default()
+            .set_genesis_block(genesis_block)
+            .set_verification(false);
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+            .consensus(consensus)
+            .build();
+        let (chain_controller, chain_receivers) = ChainController::new();
+        let chain_service = ChainBuilder::new(shared.clone())
+            .config(Config {
+                cell_consumer_index: true,
+                ..Default::default()
+            }).build();
+        let _handle = chain_service.start::<&str>(None, chain_receivers);
+
+        let genesis = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
+        let spend = create_transaction(out_point.hash);
+        let block = gen_block(
+            genesis.clone(),
+            1,
+            genesis.difficulty() + U256::from(100),
+            vec![spend.clone()],
+            vec![],
+        );
+        chain_controller
+            .process_block(Arc::new(block))
+            .expect("process block ok");
+        assert_eq!(
+            shared.store().get_cell_consumer(&out_point),
+            Some(spend.hash())
+        );
+
+        // a heavier fork which does not spend the cell detaches the consumer
+        let fork_block = gen_block(
+            genesis.clone(),
+            2,
+            genesis.difficulty() + U256::from(200),
+            vec![],
+            vec![],
+        );
+        chain_controller
+            .process_block(Arc::new(fork_block))
+            .expect("process block ok");
+        assert_eq!(shared.store().get_cell_consumer(&out_point), None);
+    }
+
+    #[test]
+    fn test_lock_hash_index() {
+        let consensus = Consensus::default().set_verification(false);
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+            .consensus(consensus)
+            .build();
+        let (chain_controller, chain_receivers) = ChainController::new();
+        let chain_service = ChainBuilder::new(shared.clone())
+            .config(Config {
+                lock_hash_index: true,
+                ..Default::default()
+            }).build();
+        let _handle = chain_service.start::<&str>(None, chain_receivers);
+        let lock_hash = H256::from(0);
+
+        let genesis = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
+        let block1 = gen_block(
+            genesis.clone(),
+            1,
+            genesis.difficulty() + U256::from(100),
+            vec![],
+            vec![],
+        );
+        let cellbase = block1.commit_transactions()[0].clone();
+        chain_controller
+            .process_block(Arc::new(block1.clone()))
+            .expect("process block ok");
+        assert_eq!(
+            shared.store().get_cells_by_lock_hash(&lock_hash),
+            vec![OutPoint::new(cellbase.hash(), 0)]
+        );
+
+        // spends block1's cellbase and creates 100 cells
+        let spend = create_transaction(cellbase.hash());
+        let block2 = gen_block(
+            block1.header().clone(),
+            2,
+            block1.header().difficulty() + U256::from(100),
+            vec![spend.clone()],
+            vec![],
+        );
+        chain_controller
+            .process_block(Arc::new(block2.clone()))
+            .expect("process block ok");
+        let cells = shared.store().get_cells_by_lock_hash(&lock_hash);
+        assert_eq!(cells.len(), 101);
+        assert!(!cells.contains(&OutPoint::new(cellbase.hash(), 0)));
+        assert!(cells.contains(&OutPoint::new(spend.hash(), 99)));
+
+        // a heavier fork on top of block1 reverts the spend
+        let fork_block = gen_block(
+            block1.header().clone(),
+            3,
+            block1.header().difficulty() + U256::from(200),
+            vec![],
+            vec![],
+        );
+        chain_controller
+            .process_block(Arc::new(fork_block.clone()))
+            .expect("process block ok");
+        let cells = shared.store().get_cells_by_lock_hash(&lock_hash);
+        assert_eq!(cells.len(), 2);
+        assert!(cells.contains(&OutPoint::new(cellbase.hash(), 0)));
+        assert!(cells.contains(&OutPoint::new(
+            fork_block.commit_transactions()[0].hash(),
+            0
+        )));
+    }
+
     #[test]
     fn test_chain_fork_by_total_difficulty() {
         let (chain_controller, shared) = start_chain(None);
@@ -569,12 +1445,475 @@ pub mod test {
     }
 
     #[test]
-    fn test_chain_fork_by_hash() {
+    fn test_sync_stats() {
         let (chain_controller, shared) = start_chain(None);
-        let final_number = 20;
+        let genesis = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
 
         let mut chain1: Vec<Block> = Vec::new();
-        let mut chain2: Vec<Block> = Vec::new();
+        let mut parent = genesis.clone();
+        for i in 1..4 {
+            let difficulty = parent.difficulty() + U256::from(100);
+            let new_block = gen_block(parent, i, difficulty, vec![], vec![]);
+            chain1.push(new_block.clone());
+            parent = new_block.header().clone();
+        }
+
+        // heavier at every height and longer, it must take over
+        let mut chain2: Vec<Block> = Vec::new();
+        let mut parent = genesis;
+        for i in 1..5 {
+            let difficulty = parent.difficulty() + U256::from(200);
+            let new_block = gen_block(parent, i + 1000, difficulty, vec![], vec![]);
+            chain2.push(new_block.clone());
+            parent = new_block.header().clone();
+        }
+
+        for block in chain1.iter().chain(chain2.iter()) {
+            chain_controller
+                .process_block(Arc::new(block.clone()))
+                .expect("process block ok");
+        }
+        assert_eq!(
+            shared.block_hash(4),
+            chain2.last().map(|b| b.header().hash())
+        );
+
+        let stats = shared.sync_stats().lock().clone();
+        assert_eq!(stats.blocks_processed, 7);
+        assert!(stats.reorgs >= 1);
+        assert_eq!(stats.reorg_depths.values().sum::<u64>(), stats.reorgs);
+        assert_eq!(shared.store().get_sync_stats(), Some(stats));
+    }
+
+    #[test]
+    fn test_epoch_stats() {
+        let mut consensus = Consensus::default().set_verification(false);
+        consensus.pow_time_span = 4;
+        consensus.pow_spacing = 1;
+        let (chain_controller, shared) = start_chain(Some(consensus));
+        let genesis = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
+
+        let mut chain1: Vec<Block> = Vec::new();
+        let mut parent = genesis.clone();
+        for i in 1..10 {
+            let difficulty = parent.difficulty() + U256::from(100);
+            let new_block = gen_block(parent, i, difficulty, vec![], vec![]);
+            chain_controller
+                .process_block(Arc::new(new_block.clone()))
+                .expect("process block ok");
+            chain1.push(new_block.clone());
+            parent = new_block.header().clone();
+        }
+
+        // blocks 0..=3 and 4..=7 complete the first two epochs
+        let stats = shared.store().get_epoch_stats(1).unwrap();
+        assert_eq!(stats.start_number, 4);
+        assert_eq!(stats.block_count, 4);
+        assert_eq!(stats.uncles_count, 0);
+        assert_eq!(stats.difficulty, chain1[6].header().difficulty());
+        assert!(shared.store().get_epoch_stats(0).is_some());
+        assert_eq!(shared.store().get_epoch_stats(2), None);
+
+        // a heavier but shorter fork from block 2 replaces the first epoch and drops the second
+        let mut parent = chain1[1].header().clone();
+        for i in 1..4 {
+            let difficulty = parent.difficulty() + U256::from(100_000);
+            let new_block = gen_block(parent, i + 1000, difficulty, vec![], vec![]);
+            chain_controller
+                .process_block(Arc::new(new_block.clone()))
+                .expect("process block ok");
+            parent = new_block.header().clone();
+        }
+        assert_eq!(shared.tip_header().read().number(), 5);
+        let fork_block = shared.block_header(&shared.block_hash(3).unwrap()).unwrap();
+        assert_eq!(
+            shared.store().get_epoch_stats(0).unwrap().difficulty,
+            fork_block.difficulty()
+        );
+        assert_eq!(shared.store().get_epoch_stats(1), None);
+    }
+
+    #[test]
+    fn test_epoch_stats_of_attached_blocks() {
+        let tx = TransactionBuilder::default()
+            .input(CellInput::new(OutPoint::null(), Default::default()))
+            .output(CellOutput::new(100_000_000, vec![], H256::default(), None))
+            .build();
+        let genesis_block = BlockBuilder::default()
+            .commit_transaction(tx.clone())
+            .with_header_builder(HeaderBuilder::default().difficulty(&U256::from(1000)));
+        let mut consensus = Consensus::default()
+            .set_genesis_block(genesis_block)
+            .set_verification(false);
+        consensus.pow_time_span = 4;
+        consensus.pow_spacing = 1;
+        let (chain_controller, shared) = start_chain(Some(consensus));
+        let genesis = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
+
+        let mut parent = genesis.clone();
+        for i in 1..4 {
+            let new_block = gen_block(parent, i, U256::from(100), vec![], vec![]);
+            chain_controller
+                .process_block(Arc::new(new_block.clone()))
+                .expect("process block ok");
+            parent = new_block.header().clone();
+        }
+        assert_eq!(shared.store().get_epoch_stats(0).unwrap().total_fees, 0);
+
+        // the fork becomes the main chain with its last block, the second block spends the
+        // first one's output in the same batch, each pays a fee of 1000
+        let spend = |tx: &Transaction| {
+            TransactionBuilder::default()
+                .input(CellInput::new(
+                    OutPoint::new(tx.hash(), 0),
+                    Default::default(),
+                ))
+                .output(CellOutput::new(
+                    tx.outputs()[0].capacity - 1_000,
+                    vec![],
+                    H256::default(),
+                    None,
+                ))
+                .build()
+        };
+        let spend1 = spend(&tx);
+        let spend2 = spend(&spend1);
+        let fork = vec![vec![spend1], vec![spend2], vec![]];
+        let mut parent = genesis;
+        let mut fork_hashes = Vec::new();
+        for (i, txs) in fork.into_iter().enumerate() {
+            let difficulty = U256::from(if i == 2 { 200 } else { 100 });
+            let new_block = gen_block(parent, i as u64 + 100, difficulty, txs, vec![]);
+            chain_controller
+                .process_block(Arc::new(new_block.clone()))
+                .expect("process block ok");
+            fork_hashes.push(new_block.header().hash());
+            parent = new_block.header().clone();
+        }
+        assert_eq!(shared.tip_header().read().hash(), parent.hash());
+        let stats = shared.store().get_epoch_stats(0).unwrap();
+        assert_eq!(stats.last_hash, parent.hash());
+        assert_eq!(stats.total_fees, 2_000);
+        // the states are recorded in the same batch, with the same fees
+        let fees = fork_hashes
+            .iter()
+            .map(|hash| shared.store().get_block_economic_state(hash).unwrap().fees)
+            .collect::<Vec<_>>();
+        assert_eq!(fees, vec![1_000, 1_000, 0]);
+    }
+
+    #[test]
+    fn test_block_economic_state() {
+        let tx = TransactionBuilder::default()
+            .input(CellInput::new(OutPoint::null(), Default::default()))
+            .output(CellOutput::new(100_000_000, vec![], H256::default(), None))
+            .build();
+        let out_point = OutPoint::new(tx.hash(), 0);
+
+        let genesis_block = BlockBuilder::default()
+            .commit_transaction(tx)
+            .with_header_builder(HeaderBuilder::default().difficulty(&U256::from(1000)));
+        let consensus = Consensus::default()
+            .set_genesis_block(genesis_block)
+            .set_initial_block_reward(5_000)
+            .set_verification(false);
+        let (chain_controller, shared) = start_chain(Some(consensus));
+        let genesis = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
+
+        // the cellbase claims the reward and half of the fee
+        let spend = TransactionBuilder::default()
+            .input(CellInput::new(out_point, Default::default()))
+            .output(CellOutput::new(99_999_000, vec![], H256::default(), None))
+            .build();
+        let cellbase = TransactionBuilder::default()
+            .input(CellInput::new_cellbase_input(1))
+            .output(CellOutput::new(5_500, vec![], H256::from(0), None))
+            .build();
+        let header = HeaderBuilder::default()
+            .parent_hash(&genesis.hash())
+            .timestamp(now_ms())
+            .number(1)
+            .difficulty(&U256::from(100))
+            .build();
+        let block = BlockBuilder::default()
+            .header(header)
+            .commit_transaction(cellbase)
+            .commit_transaction(spend)
+            .build();
+        chain_controller
+            .process_block(Arc::new(block.clone()))
+            .expect("process block ok");
+
+        let state = BlockEconomicState {
+            primary_issuance: 5_000,
+            uncles_reward: 0,
+            fees: 1_000,
+            miner_reward: 5_500,
+        };
+        let hash = block.header().hash();
+        assert_eq!(shared.store().get_block_economic_state(&hash), Some(state));
+        assert_eq!(
+            shared.store().get_block_economic_state(&genesis.hash()),
+            None
+        );
+
+        // a detached block keeps its state
+        let fork_block = gen_block(genesis, 2, U256::from(200), vec![], vec![]);
+        chain_controller
+            .process_block(Arc::new(fork_block.clone()))
+            .expect("process block ok");
+        assert_eq!(shared.block_number(&hash), None);
+        assert!(shared.store().get_block_economic_state(&hash).is_some());
+        let fork_state = shared
+            .store()
+            .get_block_economic_state(&fork_block.header().hash())
+            .unwrap();
+        assert_eq!(fork_state.fees, 0);
+        assert_eq!(fork_state.miner_reward, 0);
+    }
+
+    #[test]
+    fn test_process_block_idempotent() {
+        let (chain_controller, shared) = start_chain(None);
+        let genesis = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
+
+        let block = gen_block(genesis.clone(), 1, genesis.difficulty(), vec![], vec![]);
+        // lighter than the tip, it is stored on a side chain
+        let fork_block = gen_block(genesis.clone(), 2, genesis.difficulty(), vec![], vec![]);
+        let tip = gen_block(
+            block.header().clone(),
+            3,
+            genesis.difficulty(),
+            vec![],
+            vec![],
+        );
+        for block in &[&block, &tip, &fork_block] {
+            chain_controller
+                .process_block(Arc::new((*block).clone()))
+                .expect("process block ok");
+        }
+
+        for block in &[&block, &tip, &fork_block] {
+            assert_eq!(
+                chain_controller.process_block(Arc::new((*block).clone())),
+                Ok(())
+            );
+        }
+        assert_eq!(shared.tip_header().read().hash(), tip.header().hash());
+        assert_eq!(shared.block_hash(1), Some(block.header().hash()));
+
+        let stats = shared.sync_stats().lock().clone();
+        assert_eq!(stats.blocks_processed, 3);
+        assert_eq!(stats.duplicate_blocks, 3);
+    }
+
+    #[test]
+    fn test_process_blocks() {
+        let (chain_controller, shared) = start_chain(None);
+        let genesis = shared.consensus().genesis_block().clone();
+
+        let mut blocks: Vec<Arc<Block>> = Vec::new();
+        let mut parent = genesis.header().clone();
+        for i in 1..6 {
+            let difficulty = parent.difficulty() + U256::from(100);
+            let new_block = gen_block(parent, i, difficulty, vec![], vec![]);
+            parent = new_block.header().clone();
+            blocks.push(Arc::new(new_block));
+        }
+        assert_eq!(
+            chain_controller.process_blocks(blocks[..3].to_vec()),
+            Ok(())
+        );
+        assert_eq!(shared.tip_header().read().number(), 3);
+
+        // the genesis of another chain stops the batch, the blocks before it are kept
+        let other = BlockBuilder::default()
+            .block(genesis.clone())
+            .header(
+                HeaderBuilder::default()
+                    .difficulty(&genesis.header().difficulty())
+                    .timestamp(1)
+                    .build(),
+            ).build();
+        let batch = vec![
+            Arc::clone(&blocks[2]),
+            Arc::clone(&blocks[3]),
+            Arc::new(other),
+            Arc::clone(&blocks[4]),
+        ];
+        assert!(chain_controller.process_blocks(batch).is_err());
+        assert_eq!(shared.tip_header().read().hash(), blocks[3].header().hash());
+
+        let stats = shared.sync_stats().lock().clone();
+        assert_eq!(stats.blocks_processed, 4);
+        assert_eq!(stats.duplicate_blocks, 1);
+        assert_eq!(shared.store().get_sync_stats(), Some(stats));
+    }
+
+    #[test]
+    fn test_process_blocks_in_chunks() {
+        let (_handle, notify) = NotifyService::default().start::<&str>(None);
+        let new_tip_receiver = notify.subscribe_new_tip("test");
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+            .consensus(Consensus::default().set_verification(false))
+            .build();
+        let (chain_controller, chain_receivers) = ChainController::new();
+        let chain_service = ChainBuilder::new(shared.clone()).notify(notify).build();
+        let _handle = chain_service.start::<&str>(None, chain_receivers);
+
+        let mut blocks: Vec<Arc<Block>> = Vec::new();
+        let mut parent = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
+        for i in 0..CONTEXT_FREE_CHUNK_SIZE * 2 + 3 {
+            let difficulty = parent.difficulty() + U256::from(100);
+            let new_block = gen_block(parent, i as u64, difficulty, vec![], vec![]);
+            parent = new_block.header().clone();
+            blocks.push(Arc::new(new_block));
+        }
+        assert_eq!(chain_controller.process_blocks(blocks.clone()), Ok(()));
+        assert_eq!(shared.tip_header().read().hash(), parent.hash());
+
+        // the new tips are announced in order once the batch is committed
+        for block in &blocks {
+            let tip = new_tip_receiver.recv().expect("new tip event");
+            assert_eq!(tip.header().hash(), block.header().hash());
+        }
+        assert_eq!(
+            shared.sync_stats().lock().blocks_processed,
+            blocks.len() as u64
+        );
+    }
+
+    #[test]
+    fn test_uncle_candidates() {
+        let (chain_controller, shared) = start_chain(None);
+        let genesis = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
+        let difficulty = genesis.difficulty();
+        let process = |block: &Block| {
+            chain_controller
+                .process_block(Arc::new(block.clone()))
+                .expect("process block ok");
+        };
+
+        let heavier = difficulty + U256::from(100);
+        let block1 = gen_block(genesis.clone(), 1, heavier, vec![], vec![]);
+        let fork_a = gen_block(genesis.clone(), 2, difficulty, vec![], vec![]);
+        let fork_b = gen_block(genesis.clone(), 3, difficulty, vec![], vec![]);
+        for block in &[&block1, &fork_a, &fork_b] {
+            process(block);
+        }
+        assert_eq!(shared.uncle_candidates().lock().len(), 2);
+
+        // an included uncle is no candidate anymore
+        let mut parent = gen_block(
+            block1.header().clone(),
+            4,
+            difficulty,
+            vec![],
+            vec![fork_a.clone().into()],
+        );
+        process(&parent);
+        {
+            let candidates = shared.uncle_candidates().lock();
+            assert_eq!(candidates.len(), 1);
+            assert!(candidates.contains_key(&fork_b.header().hash()));
+        }
+
+        // neither is one too old for the next block
+        let max_uncles_age = shared.consensus().max_uncles_age() as u64;
+        while parent.header().number() <= max_uncles_age {
+            assert_eq!(shared.uncle_candidates().lock().len(), 1);
+            let block = gen_block(parent.header().clone(), 5, difficulty, vec![], vec![]);
+            process(&block);
+            parent = block;
+        }
+        assert!(shared.uncle_candidates().lock().is_empty());
+    }
+
+    #[test]
+    fn test_detached_block_uncles() {
+        let (chain_controller, shared) = start_chain(None);
+        let genesis = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
+        let difficulty = genesis.difficulty();
+        let process = |block: &Block| {
+            chain_controller
+                .process_block(Arc::new(block.clone()))
+                .expect("process block ok");
+        };
+
+        let heavier = difficulty + U256::from(100);
+        let block1 = gen_block(genesis.clone(), 1, heavier, vec![], vec![]);
+        let uncle = gen_block(genesis.clone(), 2, difficulty, vec![], vec![]);
+        let block2 = gen_block(
+            block1.header().clone(),
+            3,
+            difficulty,
+            vec![],
+            vec![uncle.clone().into()],
+        );
+        for block in &[&block1, &uncle, &block2] {
+            process(block);
+        }
+        let uncle_hash = uncle.header().hash();
+        assert!(shared.store().get_uncle(&uncle_hash).is_some());
+
+        // the including block is detached, the uncle is kept for it
+        let fork = gen_block(genesis.clone(), 4, heavier * U256::from(3), vec![], vec![]);
+        process(&fork);
+        assert_eq!(shared.tip_header().read().hash(), fork.header().hash());
+        assert!(shared.store().get_uncle(&uncle_hash).is_some());
+        assert_eq!(shared.block(&block2.header().hash()), Some(block2.clone()));
+
+        // and attached again
+        let block3 = gen_block(
+            block2.header().clone(),
+            5,
+            heavier * U256::from(3),
+            vec![],
+            vec![],
+        );
+        process(&block3);
+        assert_eq!(shared.tip_header().read().hash(), block3.header().hash());
+        assert_eq!(shared.block(&block2.header().hash()), Some(block2.clone()));
+    }
+
+    #[test]
+    fn test_rejected_block_notify() {
+        let (_handle, notify) = NotifyService::default().start::<&str>(None);
+        let rejected_block_receiver = notify.subscribe_rejected_block("test");
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+            .consensus(Consensus::default())
+            .build();
+        let (chain_controller, chain_receivers) = ChainController::new();
+        let chain_service = ChainBuilder::new(shared.clone()).notify(notify).build();
+        let _handle = chain_service.start::<&str>(None, chain_receivers);
+
+        let parent = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
+        let header = HeaderBuilder::default()
+            .parent_hash(&parent.hash())
+            .number(parent.number() + 1)
+            .difficulty(&parent.difficulty())
+            .build();
+        let block = BlockBuilder::default().header(header).build();
+
+        assert!(
+            chain_controller
+                .process_block_from_peer(Arc::new(block.clone()), 7)
+                .is_err()
+        );
+        let rejection = rejected_block_receiver.recv().expect("rejected block event");
+        assert_eq!(rejection.hash, block.header().hash());
+        assert_eq!(rejection.peer, Some(7));
+        assert_eq!(rejection.reason, "CommitTransactionsEmpty");
+        assert_eq!(rejection.code, 309);
+    }
+
+    #[test]
+    fn test_chain_fork_by_hash() {
+        let (chain_controller, shared) = start_chain(None);
+        let final_number = 20;
+
+        let mut chain1: Vec<Block> = Vec::new();
+        let mut chain2: Vec<Block> = Vec::new();
 
         let mut parent = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
         for i in 1..final_number {
@@ -632,6 +1971,287 @@ pub mod test {
         );
     }
 
+    #[test]
+    fn test_cellbase_number_at() {
+        let (chain_controller, shared) = start_chain(None);
+
+        let mut main: Vec<Block> = Vec::new();
+        let mut parent = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
+        for i in 1..4 {
+            let difficulty = parent.difficulty() + U256::from(100);
+            let new_block = gen_block(parent, i, difficulty, vec![], vec![]);
+            parent = new_block.header().clone();
+            main.push(new_block);
+        }
+        // a lighter fork from block 1, its cellbases differ from those of the main chain
+        let mut fork: Vec<Block> = Vec::new();
+        let mut parent = main[0].header().clone();
+        for _ in 0..2 {
+            let number = parent.number() + 1;
+            let cellbase = TransactionBuilder::default()
+                .input(CellInput::new_cellbase_input(number))
+                .output(CellOutput::new(1, vec![], H256::from(0), None))
+                .build();
+            let new_block = BlockBuilder::default()
+                .commit_transaction(cellbase)
+                .with_header_builder(
+                    HeaderBuilder::default()
+                        .parent_hash(&parent.hash())
+                        .number(number)
+                        .timestamp(parent.timestamp() + 1)
+                        .difficulty(&parent.difficulty()),
+                );
+            parent = new_block.header().clone();
+            fork.push(new_block);
+        }
+        for block in main.iter().chain(fork.iter()) {
+            chain_controller
+                .process_block(Arc::new(block.clone()))
+                .expect("process block ok");
+        }
+        assert_eq!(shared.tip_header().read().hash(), main[2].header().hash());
+
+        let cellbase_of = |block: &Block| OutPoint::new(block.commit_transactions()[0].hash(), 0);
+        let tip = main[2].header().hash();
+        let fork_tip = fork[1].header().hash();
+        assert_eq!(
+            shared.cellbase_number_at(&cellbase_of(&main[1]), &tip),
+            Some(2)
+        );
+        assert_eq!(
+            shared.cellbase_number_at(&cellbase_of(&fork[0]), &tip),
+            None
+        );
+        // the fork resolves its own cellbases and those of the main chain up to the fork point
+        assert_eq!(
+            shared.cellbase_number_at(&cellbase_of(&fork[0]), &fork_tip),
+            Some(2)
+        );
+        assert_eq!(
+            shared.cellbase_number_at(&cellbase_of(&main[0]), &fork_tip),
+            Some(1)
+        );
+        assert_eq!(
+            shared.cellbase_number_at(&cellbase_of(&main[1]), &fork_tip),
+            None
+        );
+    }
+
+    #[test]
+    fn test_reorg_reverts_cells() {
+        let tx = TransactionBuilder::default()
+            .input(CellInput::new(OutPoint::null(), Default::default()))
+            .output(CellOutput::new(100_000_000, vec![], H256::default(), None))
+            .build();
+        let out_point = OutPoint::new(tx.hash(), 0);
+
+        let genesis_block = BlockBuilder::default()
+            .commit_transaction(tx)
+            .with_header_builder(HeaderBuilder::default().difficulty(&U256::from(1000)));
+        let consensus = Consensus::default()
+            .set_genesis_block(genesis_block)
+            .set_verification(false);
+        let (_handle, notify) = NotifyService::default().start::<&str>(None);
+        let switch_fork_receiver = notify.subscribe_switch_fork("test");
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+            .consensus(consensus)
+            .build();
+        let (chain_controller, chain_receivers) = ChainController::new();
+        let chain_service = ChainBuilder::new(shared.clone()).notify(notify).build();
+        let _handle = chain_service.start::<&str>(None, chain_receivers);
+        let genesis = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
+
+        // the first block of the main chain spends the genesis cell
+        let spend = create_transaction(out_point.hash);
+        let mut chain1: Vec<Block> = Vec::new();
+        let mut parent = genesis.clone();
+        for i in 1..4 {
+            let txs = if i == 1 { vec![spend.clone()] } else { vec![] };
+            let new_block = gen_block(parent, i, U256::from(100), txs, vec![]);
+            chain1.push(new_block.clone());
+            parent = new_block.header().clone();
+        }
+        // shorter but heavier, it only takes over with its last block
+        let mut chain2: Vec<Block> = Vec::new();
+        let mut parent = genesis;
+        for (i, difficulty) in [50u64, 1000].iter().enumerate() {
+            let new_block = gen_block(
+                parent,
+                i as u64 + 1000,
+                U256::from(*difficulty),
+                vec![],
+                vec![],
+            );
+            chain2.push(new_block.clone());
+            parent = new_block.header().clone();
+        }
+
+        for block in chain1.iter().chain(chain2.iter()) {
+            chain_controller
+                .process_block(Arc::new(block.clone()))
+                .expect("process block ok");
+        }
+        assert_eq!(shared.tip_header().read().hash(), chain2[1].header().hash());
+        assert!(shared.cell(&out_point).is_current());
+        assert!(shared.cell(&OutPoint::new(spend.hash(), 0)).is_unknown());
+        // the cell set is rolled back as well
+        assert_eq!(shared.cell_set_len(), shared.cell_set_iter().count() as u64);
+        assert!(shared.cell_set_iter().any(|(cell, _)| cell == out_point));
+
+        let fork_blks = switch_fork_receiver.recv().expect("switch fork event");
+        let numbers =
+            |blks: &Vec<Block>| blks.iter().map(|b| b.header().number()).collect::<Vec<_>>();
+        assert_eq!(numbers(fork_blks.old_blks()), vec![3, 2, 1]);
+        assert_eq!(numbers(fork_blks.new_blks()), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_reorg_in_passes() {
+        let tx = TransactionBuilder::default()
+            .input(CellInput::new(OutPoint::null(), Default::default()))
+            .output(CellOutput::new(100_000_000, vec![], H256::default(), None))
+            .build();
+        let out_point = OutPoint::new(tx.hash(), 0);
+
+        let genesis_block = BlockBuilder::default()
+            .commit_transaction(tx)
+            .with_header_builder(HeaderBuilder::default().difficulty(&U256::from(1000)));
+        let consensus = Consensus::default()
+            .set_genesis_block(genesis_block)
+            .set_verification(false);
+        let (_handle, notify) = NotifyService::default().start::<&str>(None);
+        let switch_fork_receiver = notify.subscribe_switch_fork("test");
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+            .consensus(consensus)
+            .build();
+        let (chain_controller, chain_receivers) = ChainController::new();
+        let chain_service = ChainBuilder::new(shared.clone())
+            .notify(notify)
+            .config(Config {
+                max_reorg_blocks: 2,
+                ..Default::default()
+            }).build();
+        let _handle = chain_service.start::<&str>(None, chain_receivers);
+        let genesis = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
+
+        let spend = create_transaction(out_point.hash);
+        let mut chain1: Vec<Block> = Vec::new();
+        let mut parent = genesis.clone();
+        for i in 1..8 {
+            let txs = if i == 1 { vec![spend.clone()] } else { vec![] };
+            let new_block = gen_block(parent, i, U256::from(100), txs, vec![]);
+            chain1.push(new_block.clone());
+            parent = new_block.header().clone();
+        }
+        // one block longer and heavier, the reorg detaches 7 blocks and attaches 8
+        let mut chain2: Vec<Block> = Vec::new();
+        let mut parent = genesis;
+        for i in 1..9 {
+            let new_block = gen_block(parent, i + 1000, U256::from(100), vec![], vec![]);
+            chain2.push(new_block.clone());
+            parent = new_block.header().clone();
+        }
+
+        for block in chain1.iter().chain(chain2.iter()) {
+            chain_controller
+                .process_block(Arc::new(block.clone()))
+                .expect("process block ok");
+        }
+        assert_eq!(shared.tip_header().read().hash(), chain2[7].header().hash());
+        for block in &chain2 {
+            assert_eq!(
+                shared.block_hash(block.header().number()),
+                Some(block.header().hash())
+            );
+        }
+        assert!(shared.cell(&out_point).is_current());
+        assert!(shared.cell(&OutPoint::new(spend.hash(), 0)).is_unknown());
+        assert_eq!(shared.cell_set_len(), shared.cell_set_iter().count() as u64);
+        assert_eq!(shared.store().get_reorg_target(), None);
+
+        // each pass announces the blocks it switches, a step on the main chain stays out of them
+        let numbers =
+            |blks: &Vec<Block>| blks.iter().map(|b| b.header().number()).collect::<Vec<_>>();
+        let expected: Vec<(Vec<BlockNumber>, Vec<BlockNumber>)> = vec![
+            (vec![7, 6], vec![]),
+            (vec![5, 4], vec![]),
+            (vec![3, 2], vec![]),
+            (vec![1], vec![1, 2]),
+            (vec![], vec![3, 4]),
+            (vec![], vec![5, 6]),
+            (vec![], vec![7, 8]),
+        ];
+        let mut detached = Vec::new();
+        let mut attached = Vec::new();
+        for (old_numbers, new_numbers) in expected {
+            let fork_blks = switch_fork_receiver.recv().expect("switch fork event");
+            assert_eq!(numbers(fork_blks.old_blks()), old_numbers);
+            assert_eq!(numbers(fork_blks.new_blks()), new_numbers);
+            detached.extend(fork_blks.old_blks().iter().rev().cloned());
+            attached.extend(fork_blks.new_blks().iter().cloned());
+        }
+        detached.sort_by_key(|b| b.header().number());
+        assert_eq!(detached, chain1);
+        assert_eq!(attached, chain2);
+    }
+
+    #[test]
+    fn test_resume_reorg() {
+        let (chain_controller, shared) = start_chain(None);
+        let genesis = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
+
+        let mut chain1: Vec<Block> = Vec::new();
+        let mut parent = genesis.clone();
+        for i in 1..6 {
+            let new_block = gen_block(parent, i, U256::from(100), vec![], vec![]);
+            chain1.push(new_block.clone());
+            parent = new_block.header().clone();
+        }
+        let mut chain2: Vec<Block> = Vec::new();
+        let mut parent = genesis;
+        for i in 1..5 {
+            let new_block = gen_block(parent, i + 1000, U256::from(100), vec![], vec![]);
+            chain2.push(new_block.clone());
+            parent = new_block.header().clone();
+        }
+        for block in chain1.iter().chain(chain2.iter()) {
+            chain_controller
+                .process_block(Arc::new(block.clone()))
+                .expect("process block ok");
+        }
+        assert_eq!(shared.tip_header().read().hash(), chain1[4].header().hash());
+
+        // a reorg to the fork was stopped before its first pass
+        shared
+            .store()
+            .save_with_batch(|batch| {
+                shared
+                    .store()
+                    .insert_reorg_target(batch, &chain2[3].header().hash());
+                Ok(())
+            }).expect("save reorg target");
+        let (controller, chain_receivers) = ChainController::new();
+        let chain_service = ChainBuilder::new(shared.clone())
+            .config(Config {
+                max_reorg_blocks: 2,
+                ..Default::default()
+            }).build();
+        let handle = chain_service.start::<&str>(None, chain_receivers);
+        // the service stops once its controller is gone, after the resumed reorg
+        drop(controller);
+        handle.join().expect("chain service stopped");
+
+        assert_eq!(shared.tip_header().read().hash(), chain2[3].header().hash());
+        assert_eq!(shared.block_hash(5), None);
+        for block in &chain2 {
+            assert_eq!(
+                shared.block_hash(block.header().number()),
+                Some(block.header().hash())
+            );
+        }
+        assert_eq!(shared.store().get_reorg_target(), None);
+    }
+
     #[test]
     fn test_chain_get_ancestor() {
         let (chain_controller, shared) = start_chain(None);
@@ -689,6 +2309,48 @@ pub mod test {
         );
     }
 
+    #[test]
+    fn test_calculate_difficulty_with_target_interval() {
+        let genesis_block = BlockBuilder::default()
+            .with_header_builder(HeaderBuilder::default().difficulty(&U256::from(1000)));
+        let consensus = Consensus::default()
+            .set_genesis_block(genesis_block)
+            .set_verification(false)
+            .set_target_block_interval(Some(1000));
+        let (chain_controller, shared) = start_chain(Some(consensus));
+
+        let mut parent = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
+        assert_eq!(
+            shared.calculate_difficulty(&parent),
+            Some(U256::from(1000))
+        );
+
+        // 4 times faster than the target, capped to double
+        // 4 times slower on average, capped to the genesis difficulty
+        for (timestamp, expected) in &[(250, 2000), (8250, 1000)] {
+            let number = parent.number() + 1;
+            let cellbase = create_cellbase(number);
+            let header_builder = HeaderBuilder::default()
+                .parent_hash(&parent.hash())
+                .timestamp(*timestamp)
+                .number(number)
+                .difficulty(&shared.calculate_difficulty(&parent).unwrap())
+                .cellbase_id(&cellbase.hash());
+            let block = BlockBuilder::default()
+                .commit_transaction(cellbase)
+                .with_header_builder(header_builder);
+            chain_controller
+                .process_block(Arc::new(block.clone()))
+                .expect("process block ok");
+
+            parent = block.header().clone();
+            assert_eq!(
+                shared.calculate_difficulty(&parent),
+                Some(U256::from(*expected))
+            );
+        }
+    }
+
     #[test]
     fn test_calculate_difficulty() {
         let genesis_block = BlockBuilder::default()
@@ -798,4 +2460,60 @@ pub mod test {
         // max[150 * 10 * 1000 / 200, 2 * 1000]
         assert_eq!(difficulty, U256::from(2000));
     }
+
+    #[test]
+    fn test_calculate_epoch_difficulty() {
+        let genesis_block = BlockBuilder::default()
+            .with_header_builder(HeaderBuilder::default().difficulty(&U256::from(1000)));
+        let mut consensus = Consensus::default()
+            .set_genesis_block(genesis_block)
+            .set_verification(false)
+            .set_min_difficulty(Some(U256::one()))
+            .set_hardfork_switch(HardForkSwitch {
+                epoch_duration_difficulty: Some(0),
+                ..Default::default()
+            });
+        consensus.pow_time_span = 4;
+        consensus.pow_spacing = 1;
+        let (chain_controller, shared) = start_chain(Some(consensus));
+
+        let process = |parent: &Header, timestamp: u64, nonce: u64| {
+            let number = parent.number() + 1;
+            let header_builder = HeaderBuilder::default()
+                .parent_hash(&parent.hash())
+                .timestamp(timestamp)
+                .number(number)
+                .difficulty(&shared.calculate_difficulty(parent).unwrap())
+                .nonce(nonce);
+            let block = BlockBuilder::default()
+                .commit_transaction(create_cellbase(number))
+                .with_header_builder(header_builder);
+            chain_controller
+                .process_block(Arc::new(block.clone()))
+                .expect("process block ok");
+            block.header().clone()
+        };
+
+        // the first epoch lasts 6ms from the genesis, twice the 3ms expected
+        let genesis = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
+        let mut parent = genesis;
+        for timestamp in &[2, 4, 6] {
+            parent = process(&parent, *timestamp, 0);
+        }
+        let stats = shared.store().get_epoch_stats(0).unwrap();
+        assert_eq!(stats.last_hash, parent.hash());
+        assert_eq!(stats.duration, 6);
+        assert_eq!(shared.calculate_difficulty(&parent), Some(U256::from(500)));
+        let tip = process(&parent, 7, 0);
+        assert_eq!(tip.difficulty(), U256::from(500));
+
+        // the epoch of a fork is measured from its headers
+        let fork_parent = shared.block_header(&shared.block_hash(2).unwrap()).unwrap();
+        let fork_last = process(&fork_parent, 5, 1);
+        assert_ne!(shared.block_hash(3), Some(fork_last.hash()));
+        assert_eq!(
+            shared.calculate_difficulty(&fork_last),
+            Some(U256::from(600))
+        );
+    }
 }
@@ -0,0 +1,59 @@
+use std::path::PathBuf;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Config {
+    // Maintain the out_point => consuming transaction hash index,
+    // required by the `get_cell_consumer` RPC
+    #[serde(default)]
+    pub cell_consumer_index: bool,
+    // Maintain the lock hash => live cells index, required by the `get_cells_by_lock_hash` RPC
+    #[serde(default)]
+    pub lock_hash_index: bool,
+    // Save rejected blocks to `<data_dir>/quarantine` for post-mortem analysis
+    #[serde(default)]
+    pub quarantine: bool,
+    // Total size of the quarantine directory, the oldest blocks are removed beyond it
+    #[serde(default = "default_quarantine_max_bytes")]
+    pub quarantine_max_bytes: u64,
+    // Blocks a reorg may detach or attach in a single pass, a deeper reorg moves the main chain
+    // through intermediate blocks so the forks are never held in memory at once. 0 is no limit
+    #[serde(default = "default_max_reorg_blocks")]
+    pub max_reorg_blocks: u64,
+    // Append the cycles and syscalls of every script run by block verification to
+    // `<data_dir>/script_profile.json`, one line per transaction. The verify cache is bypassed
+    #[serde(default)]
+    pub script_profile: bool,
+    // Resolved from `data_dir` when `quarantine` is enabled
+    #[serde(skip)]
+    pub quarantine_dir: Option<PathBuf>,
+    // Resolved from `data_dir` when `script_profile` is enabled
+    #[serde(skip)]
+    pub script_profile_path: Option<PathBuf>,
+    // Skip transaction scripts in block verification, only set by `ckb import --no-script-verify`
+    #[serde(skip)]
+    pub skip_script_verify: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            cell_consumer_index: false,
+            lock_hash_index: false,
+            quarantine: false,
+            quarantine_max_bytes: default_quarantine_max_bytes(),
+            max_reorg_blocks: default_max_reorg_blocks(),
+            script_profile: false,
+            quarantine_dir: None,
+            script_profile_path: None,
+            skip_script_verify: false,
+        }
+    }
+}
+
+fn default_quarantine_max_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_max_reorg_blocks() -> u64 {
+    1024
+}
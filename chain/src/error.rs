@@ -6,3 +6,46 @@ pub enum ProcessBlockError {
     Shared(SharedError),
     Verification(VerifyError),
 }
+
+impl ProcessBlockError {
+    /// Stable code of the rejection, a verification error has the code of its block `Error`
+    pub fn code(&self) -> u32 {
+        match self {
+            ProcessBlockError::Shared(_) => 401,
+            ProcessBlockError::Verification(err) => err.code(),
+        }
+    }
+
+    /// Name of the error kind, used to classify rejected blocks in the sync statistics
+    pub fn class(&self) -> &'static str {
+        match self {
+            ProcessBlockError::Shared(_) => "Shared",
+            ProcessBlockError::Verification(err) => match err {
+                VerifyError::Pow(_) => "Pow",
+                VerifyError::Version(_) => "Version",
+                VerifyError::Timestamp(_) => "Timestamp",
+                VerifyError::Number(_) => "Number",
+                VerifyError::Difficulty(_) => "Difficulty",
+                VerifyError::Transactions(_) => "Transactions",
+                VerifyError::Conflict { .. } => "Conflict",
+                VerifyError::Chain(_) => "Chain",
+                VerifyError::CommitTransactionsEmpty => "CommitTransactionsEmpty",
+                VerifyError::ProposalTransactionDuplicate => "ProposalTransactionDuplicate",
+                VerifyError::ExceededMaximumProposalsLimit => "ExceededMaximumProposalsLimit",
+                VerifyError::ExceededMaximumBlockBytes { .. } => "ExceededMaximumBlockBytes",
+                VerifyError::ExceededMaximumBlockDataBytes { .. } => {
+                    "ExceededMaximumBlockDataBytes"
+                }
+                VerifyError::ExceededMaximumBlockCycles { .. } => "ExceededMaximumBlockCycles",
+                VerifyError::CommitTransactionDuplicate => "CommitTransactionDuplicate",
+                VerifyError::ProposalTransactionsRoot => "ProposalTransactionsRoot",
+                VerifyError::CommitTransactionsRoot => "CommitTransactionsRoot",
+                VerifyError::UnknownParent(_) => "UnknownParent",
+                VerifyError::Uncles(_) => "Uncles",
+                VerifyError::Cellbase(_) => "Cellbase",
+                VerifyError::Commit(_) => "Commit",
+                VerifyError::Genesis(_) => "Genesis",
+            },
+        }
+    }
+}
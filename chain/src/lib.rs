@@ -19,6 +19,11 @@ extern crate ckb_verification;
 extern crate log;
 #[macro_use]
 extern crate crossbeam_channel as channel;
+extern crate fnv;
+extern crate rayon;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 
 #[cfg(test)]
 extern crate rand;
@@ -26,4 +31,9 @@ extern crate rand;
 extern crate tempfile;
 
 pub mod chain;
+mod config;
 pub mod error;
+pub mod quarantine;
+pub mod script_profile;
+
+pub use config::Config;
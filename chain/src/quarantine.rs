@@ -0,0 +1,143 @@
+//! Rejected blocks are written to the quarantine directory so a consensus bug reported by a
+//! user can be reproduced from the artifact.
+//!
+//! Every rejected block is saved as `<received_at>-<hash>.json`, holding the rejection, the
+//! transactions failing verification and the whole block. When the directory grows beyond
+//! the configured size, the oldest artifacts are removed first.
+
+use ckb_core::block::Block;
+use ckb_core::extras::BlockRejection;
+use ckb_core::transaction::Transaction;
+use ckb_verification::Error as VerifyError;
+use error::ProcessBlockError;
+use serde_json;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize)]
+struct Artifact<'a> {
+    rejection: &'a BlockRejection,
+    /// Index in the block => transaction
+    failing_transactions: Vec<(usize, &'a Transaction)>,
+    block: &'a Block,
+}
+
+pub struct Quarantine {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl Quarantine {
+    pub fn new<P: AsRef<Path>>(dir: P, max_bytes: u64) -> Self {
+        Quarantine {
+            dir: dir.as_ref().to_path_buf(),
+            max_bytes,
+        }
+    }
+
+    /// Save the rejected block and prune the oldest artifacts, returns the artifact path
+    pub fn store(
+        &self,
+        received_at: u64,
+        block: &Block,
+        rejection: &BlockRejection,
+        err: &ProcessBlockError,
+    ) -> io::Result<PathBuf> {
+        let failing_transactions = match err {
+            ProcessBlockError::Verification(VerifyError::Transactions(errors)) => errors
+                .iter()
+                .filter_map(|(index, _)| {
+                    block
+                        .commit_transactions()
+                        .get(*index)
+                        .map(|tx| (*index, tx))
+                }).collect(),
+            _ => Vec::new(),
+        };
+        let artifact = Artifact {
+            rejection,
+            failing_transactions,
+            block,
+        };
+
+        fs::create_dir_all(&self.dir)?;
+        let path = self
+            .dir
+            .join(format!("{:020}-{:x}.json", received_at, rejection.hash));
+        serde_json::to_writer(File::create(&path)?, &artifact)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        self.prune()?;
+        Ok(path)
+    }
+
+    fn prune(&self) -> io::Result<()> {
+        let mut artifacts = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            artifacts.push((entry.file_name(), entry.path(), entry.metadata()?.len()));
+        }
+        // Names start with the zero padded receiving time, newest first after sorting
+        artifacts.sort_by(|a, b| b.0.cmp(&a.0));
+
+        let mut total = 0;
+        for (_, path, len) in artifacts {
+            total += len;
+            if total > self.max_bytes {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_core::header::HeaderBuilder;
+    use tempfile;
+
+    fn rejected(number: u64) -> (Block, BlockRejection) {
+        let block = Block::new(
+            HeaderBuilder::default().number(number).build(),
+            vec![],
+            vec![],
+            vec![],
+        );
+        let rejection = BlockRejection {
+            hash: block.header().hash(),
+            peer: None,
+            reason: "CommitTransactionsEmpty".to_string(),
+            error: "Verification(CommitTransactionsEmpty)".to_string(),
+            code: 309,
+        };
+        (block, rejection)
+    }
+
+    #[test]
+    fn test_quarantine_prune() {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("test_quarantine_prune")
+            .tempdir()
+            .unwrap();
+        let err = ProcessBlockError::Verification(VerifyError::CommitTransactionsEmpty);
+
+        let (block, rejection) = rejected(1);
+        let first = Quarantine::new(tmp_dir.path(), u64::max_value())
+            .store(1, &block, &rejection, &err)
+            .expect("store artifact");
+        let first_len = fs::metadata(&first).unwrap().len();
+
+        // room for a single artifact
+        let quarantine = Quarantine::new(tmp_dir.path(), first_len + 1);
+        let (block, rejection) = rejected(2);
+        let second = quarantine
+            .store(2, &block, &rejection, &err)
+            .expect("store artifact");
+
+        assert!(!first.exists());
+        assert!(second.exists());
+        let content = fs::read_to_string(&second).unwrap();
+        assert!(content.contains("CommitTransactionsEmpty"));
+    }
+}
@@ -0,0 +1,93 @@
+//! Profiles of the transaction scripts run by block verification, for the contract developers
+//! to see what their scripts cost on a real chain.
+//!
+//! Every verified transaction appends a line to the report, the JSON of its
+//! `TransactionProfile`: the cycles, the syscall counts and the error of each script.
+
+use ckb_verification::{ScriptProfiler, TransactionProfile};
+use serde_json;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub struct ScriptProfileReport {
+    path: PathBuf,
+    // Opened on the first profile, so a node verifying no transaction creates no file
+    file: Mutex<Option<File>>,
+}
+
+impl ScriptProfileReport {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        ScriptProfileReport {
+            path: path.as_ref().to_path_buf(),
+            file: Mutex::new(None),
+        }
+    }
+
+    fn append(&self, profile: &TransactionProfile) -> io::Result<()> {
+        let mut line =
+            serde_json::to_vec(profile).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        line.push(b'\n');
+
+        let mut file = self.file.lock().expect("script profile lock");
+        if file.is_none() {
+            *file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+        // a whole line in a single write, the verifier threads never interleave
+        file.as_mut().expect("opened above").write_all(&line)
+    }
+}
+
+impl ScriptProfiler for ScriptProfileReport {
+    fn record(&self, profile: &TransactionProfile) {
+        if let Err(err) = self.append(profile) {
+            warn!(target: "chain", "failed to write script profile to {:?}: {:?}", self.path, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigint::H256;
+    use serde_json::Value;
+    use std::fs;
+    use tempfile;
+
+    #[test]
+    fn test_script_profile_report() {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("test_script_profile_report")
+            .tempdir()
+            .unwrap();
+        let path = tmp_dir.path().join("script_profile.json");
+        let report = ScriptProfileReport::new(&path);
+        assert!(!path.exists());
+
+        for hash in &[H256::from(1), H256::from(2)] {
+            report.record(&TransactionProfile {
+                hash: hash.clone(),
+                scripts: vec![],
+            });
+        }
+
+        let content = fs::read_to_string(&path).unwrap();
+        let hashes: Vec<Value> = content
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap()["hash"].clone())
+            .collect();
+        assert_eq!(
+            hashes,
+            vec![
+                serde_json::to_value(H256::from(1)).unwrap(),
+                serde_json::to_value(H256::from(2)).unwrap(),
+            ]
+        );
+    }
+}
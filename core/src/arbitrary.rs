@@ -0,0 +1,232 @@
+//! proptest strategies for the core types, enabled by the `testing` feature.
+//!
+//! Generated transactions and headers carry their correct hash, generated blocks
+//! have a cellbase and consistent commitments, so they pass `BlockBuilder::build_checked`.
+//! Everything is built from proptest combinators and shrinks accordingly.
+
+use bigint::{H256, U256};
+use block::{Block, BlockBuilder};
+use header::{Header, HeaderBuilder};
+use proptest::collection::vec;
+use proptest::option;
+use proptest::prelude::*;
+use script::Script;
+use transaction::{
+    CellInput, CellOutput, OutPoint, ProposalShortId, Transaction, TransactionBuilder,
+};
+use uncle::UncleBlock;
+
+pub fn h256() -> BoxedStrategy<H256> {
+    prop::array::uniform32(any::<u8>())
+        .prop_map(H256::from)
+        .boxed()
+}
+
+fn bytes(max_len: usize) -> BoxedStrategy<Vec<u8>> {
+    vec(any::<u8>(), 0..max_len).boxed()
+}
+
+impl Arbitrary for OutPoint {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (h256(), any::<u32>())
+            .prop_map(|(hash, index)| OutPoint::new(hash, index))
+            .boxed()
+    }
+}
+
+impl Arbitrary for Script {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            any::<u8>(),
+            vec(bytes(32), 0..4),
+            option::of(h256()),
+            option::of(bytes(64)),
+            vec(bytes(32), 0..4),
+        )
+            .prop_map(|(version, args, reference, binary, signed_args)| {
+                Script::new(version, args, reference, binary, signed_args)
+            }).boxed()
+    }
+}
+
+impl Arbitrary for CellInput {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (any::<OutPoint>(), any::<Script>())
+            .prop_map(|(previous_output, unlock)| CellInput::new(previous_output, unlock))
+            .boxed()
+    }
+}
+
+impl Arbitrary for CellOutput {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (any::<u64>(), bytes(64), h256(), option::of(any::<Script>()))
+            .prop_map(|(capacity, data, lock, contract)| {
+                CellOutput::new(capacity, data, lock, contract)
+            }).boxed()
+    }
+}
+
+impl Arbitrary for Transaction {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            any::<u32>(),
+            vec(any::<OutPoint>(), 0..3),
+            vec(any::<CellInput>(), 0..4),
+            vec(any::<CellOutput>(), 0..4),
+        )
+            .prop_map(|(version, deps, inputs, outputs)| {
+                TransactionBuilder::default()
+                    .version(version)
+                    .deps(deps)
+                    .inputs(inputs)
+                    .outputs(outputs)
+                    .build()
+            }).boxed()
+    }
+}
+
+impl Arbitrary for ProposalShortId {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        vec(any::<u8>(), 10)
+            .prop_map(|id| ProposalShortId::from_slice(&id).expect("10 bytes"))
+            .boxed()
+    }
+}
+
+impl Arbitrary for Header {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            (any::<u32>(), h256(), any::<u64>(), any::<u64>()),
+            (h256(), h256(), any::<u64>(), h256()),
+            (h256(), any::<u32>(), any::<u64>(), bytes(64)),
+        )
+            .prop_map(
+                |(
+                    (version, parent_hash, timestamp, number),
+                    (txs_commit, txs_proposal, difficulty, cellbase_id),
+                    (uncles_hash, uncles_count, nonce, proof),
+                )| {
+                    HeaderBuilder::default()
+                        .version(version)
+                        .parent_hash(&parent_hash)
+                        .timestamp(timestamp)
+                        .number(number)
+                        .txs_commit(&txs_commit)
+                        .txs_proposal(&txs_proposal)
+                        .difficulty(&U256::from(difficulty))
+                        .cellbase_id(&cellbase_id)
+                        .uncles_hash(&uncles_hash)
+                        .uncles_count(uncles_count)
+                        .nonce(nonce)
+                        .proof(&proof)
+                        .build()
+                },
+            ).boxed()
+    }
+}
+
+/// A cellbase transaction for the given block number
+pub fn cellbase(number: u64) -> BoxedStrategy<Transaction> {
+    vec(any::<CellOutput>(), 1..4)
+        .prop_map(move |outputs| {
+            TransactionBuilder::default()
+                .input(CellInput::new_cellbase_input(number))
+                .outputs(outputs)
+                .build()
+        }).boxed()
+}
+
+/// A block with a cellbase and without uncles, built on top of `header`
+fn block_on(header: Header) -> BoxedStrategy<Block> {
+    (
+        cellbase(header.number()),
+        vec(any::<Transaction>(), 0..4),
+        vec(any::<ProposalShortId>(), 0..4),
+    )
+        .prop_map(move |(cellbase, transactions, proposals)| {
+            let header_builder = HeaderBuilder::default()
+                .header(header.clone())
+                .cellbase_id(&cellbase.hash());
+            BlockBuilder::default()
+                .commit_transaction(cellbase)
+                .commit_transactions(transactions)
+                .proposal_transactions(proposals)
+                .with_header_builder(header_builder)
+        }).boxed()
+}
+
+impl Arbitrary for UncleBlock {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        any::<Header>()
+            .prop_flat_map(block_on)
+            .prop_map(UncleBlock::from)
+            .boxed()
+    }
+}
+
+impl Arbitrary for Block {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (any::<Header>(), vec(any::<UncleBlock>(), 0..3))
+            .prop_flat_map(|(header, uncles)| {
+                block_on(header).prop_map(move |block| {
+                    let header_builder = HeaderBuilder::default().header(block.header().clone());
+                    BlockBuilder::default()
+                        .block(block)
+                        .uncles(uncles.clone())
+                        .with_header_builder(header_builder)
+                })
+            }).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bincode::{deserialize, serialize};
+
+    proptest! {
+        #[test]
+        fn transaction_roundtrip(ref tx in any::<Transaction>()) {
+            let decoded: Transaction = deserialize(&serialize(tx).unwrap()).unwrap();
+            prop_assert_eq!(&TransactionBuilder::default().transaction(decoded).build(), tx);
+        }
+
+        #[test]
+        fn header_roundtrip(ref header in any::<Header>()) {
+            let decoded: Header = deserialize(&serialize(header).unwrap()).unwrap();
+            prop_assert_eq!(&HeaderBuilder::default().header(decoded).build(), header);
+        }
+
+        #[test]
+        fn block_is_consistent(ref block in any::<Block>()) {
+            prop_assert!(BlockBuilder::default().block(block.clone()).build_checked().is_ok());
+        }
+    }
+}
@@ -1,9 +1,12 @@
 use bigint::H256;
+use bincode::serialized_size;
+use error::BlockError;
 use fnv::FnvHashSet;
 use header::{Header, HeaderBuilder};
 use merkle_root::merkle_root;
 use transaction::{ProposalShortId, Transaction};
 use uncle::{uncles_hash, UncleBlock};
+use Cycle;
 
 #[derive(Clone, Serialize, Deserialize, Eq, Default, Debug)]
 pub struct Block {
@@ -48,16 +51,42 @@ impl Block {
         &self.uncles
     }
 
+    pub fn serialized_size(&self) -> u64 {
+        serialized_size(self).expect("block serialized_size")
+    }
+
     pub fn cal_uncles_hash(&self) -> H256 {
         uncles_hash(&self.uncles)
     }
 
+    pub fn cal_txs_commit(&self) -> H256 {
+        merkle_root(
+            &self
+                .commit_transactions
+                .iter()
+                .map(|t| t.hash())
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    pub fn cal_txs_proposal(&self) -> H256 {
+        merkle_root(
+            &self
+                .proposal_transactions
+                .iter()
+                .map(|t| t.hash())
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    /// Proposals of the block and of all its uncles. Uncle proposals open the commit
+    /// window just like the block's own, the same union the commit verifier checks against.
     pub fn union_proposal_ids(&self) -> Vec<ProposalShortId> {
         let mut ids = FnvHashSet::default();
 
-        ids.extend(self.proposal_transactions.clone());
+        ids.extend(self.proposal_transactions());
 
-        for uc in self.uncles.clone() {
+        for uc in &self.uncles {
             ids.extend(uc.proposal_transactions());
         }
 
@@ -81,76 +110,149 @@ impl PartialEq for Block {
     }
 }
 
-#[derive(Default)]
+/// Builds a block, keeping count of its serialized size and of the cycles of the committed
+/// transactions so a block can be packed up to the consensus limits without serializing it
+/// after every transaction.
 pub struct BlockBuilder {
     inner: Block,
+    size: u64,
+    cycles: Cycle,
+}
+
+impl Default for BlockBuilder {
+    fn default() -> Self {
+        let inner = Block::default();
+        BlockBuilder {
+            size: inner.serialized_size(),
+            inner,
+            cycles: 0,
+        }
+    }
 }
 
 impl BlockBuilder {
+    /// Starts from `block`, the cycles of its transactions are unknown and not counted
     pub fn block(mut self, block: Block) -> Self {
+        self.size = block.serialized_size();
+        self.cycles = 0;
         self.inner = block;
         self
     }
 
     pub fn header(mut self, header: Header) -> Self {
+        self.size -= serialized_size(&self.inner.header).expect("header serialized_size");
+        self.size += serialized_size(&header).expect("header serialized_size");
         self.inner.header = header;
         self
     }
 
     pub fn uncle(mut self, uncle: UncleBlock) -> Self {
+        self.size += serialized_size(&uncle).expect("uncle serialized_size");
         self.inner.uncles.push(uncle);
         self
     }
 
-    pub fn uncles(mut self, uncles: Vec<UncleBlock>) -> Self {
-        self.inner.uncles.extend(uncles);
-        self
+    pub fn uncles(self, uncles: Vec<UncleBlock>) -> Self {
+        uncles
+            .into_iter()
+            .fold(self, |builder, uncle| builder.uncle(uncle))
     }
 
     pub fn commit_transaction(mut self, transaction: Transaction) -> Self {
+        self.size += transaction.serialized_size();
         self.inner.commit_transactions.push(transaction);
         self
     }
 
-    pub fn commit_transactions(mut self, transactions: Vec<Transaction>) -> Self {
-        self.inner.commit_transactions.extend(transactions);
-        self
+    /// Commits a transaction whose scripts run in `cycles`
+    pub fn commit_transaction_with_cycles(
+        mut self,
+        transaction: Transaction,
+        cycles: Cycle,
+    ) -> Self {
+        self.cycles += cycles;
+        self.commit_transaction(transaction)
+    }
+
+    pub fn commit_transactions(self, transactions: Vec<Transaction>) -> Self {
+        transactions.into_iter().fold(self, |builder, transaction| {
+            builder.commit_transaction(transaction)
+        })
     }
 
     pub fn proposal_transaction(mut self, proposal_short_id: ProposalShortId) -> Self {
+        self.size += serialized_size(&proposal_short_id).expect("proposal serialized_size");
         self.inner.proposal_transactions.push(proposal_short_id);
         self
     }
 
-    pub fn proposal_transactions(mut self, proposal_short_ids: Vec<ProposalShortId>) -> Self {
-        self.inner.proposal_transactions.extend(proposal_short_ids);
-        self
+    pub fn proposal_transactions(self, proposal_short_ids: Vec<ProposalShortId>) -> Self {
+        proposal_short_ids
+            .into_iter()
+            .fold(self, |builder, id| builder.proposal_transaction(id))
+    }
+
+    /// The serialized size of the block built so far, as `Block::serialized_size`
+    pub fn current_size(&self) -> u64 {
+        self.size
+    }
+
+    /// The cycles of the transactions committed with `commit_transaction_with_cycles`
+    pub fn current_cycles(&self) -> Cycle {
+        self.cycles
     }
 
     pub fn build(self) -> Block {
         self.inner
     }
 
-    pub fn with_header_builder(mut self, header_builder: HeaderBuilder) -> Block {
-        let txs_commit = merkle_root(
-            &self
-                .inner
-                .commit_transactions
-                .iter()
-                .map(|t| t.hash())
-                .collect::<Vec<_>>(),
-        );
+    /// Builds the block, failing when the header commitments don't match the content,
+    /// use `with_header_builder` to compute them instead.
+    pub fn build_checked(self) -> Result<Block, BlockError> {
+        let block = self.inner;
+        {
+            let header = block.header();
+            let check = |committed: H256, computed: H256, err: fn(H256, H256) -> BlockError| {
+                if committed == computed {
+                    Ok(())
+                } else {
+                    Err(err(committed, computed))
+                }
+            };
 
-        let txs_proposal = merkle_root(
-            &self
-                .inner
-                .proposal_transactions
-                .iter()
-                .map(|t| t.hash())
-                .collect::<Vec<_>>(),
-        );
+            let hash = HeaderBuilder::default().header(header.clone()).build().hash();
+            check(header.hash(), hash, BlockError::HeaderHash)?;
+            let cellbase_id = block
+                .commit_transactions
+                .first()
+                .map(|tx| tx.hash())
+                .ok_or(BlockError::EmptyCommitTransactions)?;
+            check(header.cellbase_id(), cellbase_id, BlockError::CellbaseId)?;
+            check(header.txs_commit(), block.cal_txs_commit(), BlockError::TxsCommit)?;
+            check(
+                header.txs_proposal(),
+                block.cal_txs_proposal(),
+                BlockError::TxsProposal,
+            )?;
+            check(
+                header.uncles_hash(),
+                block.cal_uncles_hash(),
+                BlockError::UnclesHash,
+            )?;
+            if header.uncles_count() as usize != block.uncles.len() {
+                return Err(BlockError::UnclesCount(
+                    header.uncles_count(),
+                    block.uncles.len() as u32,
+                ));
+            }
+        }
+        Ok(block)
+    }
 
-        let uncles_hash = uncles_hash(&self.inner.uncles);
+    pub fn with_header_builder(mut self, header_builder: HeaderBuilder) -> Block {
+        let txs_commit = self.inner.cal_txs_commit();
+        let txs_proposal = self.inner.cal_txs_proposal();
+        let uncles_hash = self.inner.cal_uncles_hash();
 
         self.inner.header = header_builder
             .txs_commit(&txs_commit)
@@ -161,3 +263,92 @@ impl BlockBuilder {
         self.inner
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transaction::{CellInput, CellOutput, TransactionBuilder};
+
+    fn cellbase(number: u64) -> Transaction {
+        TransactionBuilder::default()
+            .input(CellInput::new_cellbase_input(number))
+            .output(CellOutput::new(50, Vec::new(), H256::zero(), None))
+            .build()
+    }
+
+    #[test]
+    fn test_build_checked() {
+        let cellbase = cellbase(1);
+        let header_builder = HeaderBuilder::default()
+            .number(1)
+            .cellbase_id(&cellbase.hash());
+        let block = BlockBuilder::default()
+            .commit_transaction(cellbase.clone())
+            .proposal_transaction(ProposalShortId::from_slice(&[1; 10]).unwrap())
+            .with_header_builder(header_builder);
+
+        assert_eq!(
+            BlockBuilder::default().block(block.clone()).build_checked(),
+            Ok(block.clone())
+        );
+
+        let empty = BlockBuilder::default()
+            .header(block.header().clone())
+            .build_checked();
+        assert_eq!(empty, Err(BlockError::EmptyCommitTransactions));
+
+        let other = cellbase(2);
+        let mismatch = BlockBuilder::default()
+            .header(block.header().clone())
+            .commit_transaction(other.clone())
+            .build_checked();
+        assert_eq!(
+            mismatch,
+            Err(BlockError::CellbaseId(cellbase.hash(), other.hash()))
+        );
+
+        let missing_proposal = BlockBuilder::default()
+            .header(block.header().clone())
+            .commit_transaction(cellbase.clone())
+            .build_checked();
+        assert_eq!(
+            missing_proposal,
+            Err(BlockError::TxsProposal(
+                block.header().txs_proposal(),
+                merkle_root(&[])
+            ))
+        );
+
+        let forged = HeaderBuilder::default()
+            .header(block.header().clone())
+            .with_hash(&H256::from(1));
+        let forged = BlockBuilder::default()
+            .block(block.clone())
+            .header(forged)
+            .build_checked();
+        assert_eq!(
+            forged,
+            Err(BlockError::HeaderHash(H256::from(1), block.header().hash()))
+        );
+    }
+
+    #[test]
+    fn test_current_size() {
+        let mut builder = BlockBuilder::default();
+        assert_eq!(builder.current_size(), Block::default().serialized_size());
+
+        let uncle = BlockBuilder::default()
+            .commit_transaction(cellbase(1))
+            .with_header_builder(HeaderBuilder::default().number(1).proof(&[1, 2, 3]));
+        builder = builder
+            .header(uncle.header().clone())
+            .uncle(UncleBlock::from(uncle))
+            .commit_transaction(cellbase(2))
+            .commit_transaction_with_cycles(cellbase(3), 100)
+            .commit_transaction_with_cycles(cellbase(4), 20)
+            .proposal_transaction(ProposalShortId::from_slice(&[1; 10]).unwrap());
+        assert_eq!(builder.current_cycles(), 120);
+        let size = builder.current_size();
+        assert_eq!(size, builder.build().serialized_size());
+    }
+}
@@ -1,4 +1,5 @@
 use bigint::H256;
+use BlockNumber;
 use std::collections::HashSet;
 use std::iter::Chain;
 use std::slice;
@@ -58,6 +59,17 @@ pub trait CellProvider {
 
     fn cell_at(&self, out_point: &OutPoint, parent: &H256) -> CellStatus;
 
+    /// The number of the block whose cellbase created the cell, `None` for the other cells.
+    /// The outputs of a cellbase can only be spent once it matured.
+    fn cellbase_number(&self, _out_point: &OutPoint) -> Option<BlockNumber> {
+        None
+    }
+
+    /// Like `cellbase_number` on the chain ending at `parent`, which may be a fork
+    fn cellbase_number_at(&self, out_point: &OutPoint, _parent: &H256) -> Option<BlockNumber> {
+        self.cellbase_number(out_point)
+    }
+
     fn resolve_transaction(&self, transaction: &Transaction) -> ResolvedTransaction {
         let mut seen_inputs = HashSet::new();
 
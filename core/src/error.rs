@@ -25,3 +25,16 @@ pub enum TxError {
     EmptyGroup,
     WrongFormat,
 }
+
+/// Inconsistencies between a block header and the block content, the first value is
+/// the one committed in the header, the second the one recomputed from the content.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BlockError {
+    EmptyCommitTransactions,
+    HeaderHash(H256, H256),
+    CellbaseId(H256, H256),
+    TxsCommit(H256, H256),
+    TxsProposal(H256, H256),
+    UnclesHash(H256, H256),
+    UnclesCount(u32, u32),
+}
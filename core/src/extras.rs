@@ -1,4 +1,6 @@
 use bigint::{H256, U256};
+use std::collections::BTreeMap;
+use Capacity;
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Default, Debug)]
 pub struct BlockExt {
@@ -15,3 +17,112 @@ pub struct TransactionAddress {
     pub offset: usize,
     pub length: usize,
 }
+
+/// A block rejected by the chain service
+#[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub struct BlockRejection {
+    pub hash: H256,
+    /// Index of the peer the block was received from, none if it was mined locally
+    pub peer: Option<usize>,
+    /// Error class, e.g. `Pow` or `Transactions`
+    pub reason: String,
+    /// The full verification error
+    pub error: String,
+    /// Stable code of the error, see `ProcessBlockError::code`
+    pub code: u32,
+}
+
+/// Summary of an epoch of the main chain, kept for difficulty analysis and monitoring
+#[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub struct EpochStats {
+    pub epoch: u64,
+    pub start_number: u64,
+    pub block_count: u64,
+    pub uncles_count: u64,
+    /// Milliseconds between the blocks of the epoch on average
+    pub average_interval: u64,
+    /// Fees of the committed transactions, the cellbases don't have to claim them all
+    pub total_fees: u64,
+    /// Difficulty of the last block
+    pub difficulty: U256,
+    // The fields below were added later, they are kept last so the serialized prefix of the
+    // earlier records stays the same
+    /// Hash of the last block, the epoch ends with it
+    pub last_hash: H256,
+    /// Milliseconds from the parent of the first block to the last block, from the genesis for
+    /// the first epoch, the duration the next difficulty is retargeted from
+    pub duration: u64,
+}
+
+/// What the cellbase of a block could claim and what it paid, recorded when the block is first
+/// attached to the main chain. There's no secondary issuance nor proposal reward to record, the
+/// issuance is the block reward alone and the fees all go to the committing block.
+#[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub struct BlockEconomicState {
+    /// Issued by the block itself
+    pub primary_issuance: Capacity,
+    /// Issued for the uncles the block includes
+    pub uncles_reward: Capacity,
+    /// Fees of the committed transactions
+    pub fees: Capacity,
+    /// Capacity of the cellbase outputs, paid to the miner lock, it may claim less than the rest
+    pub miner_reward: Capacity,
+}
+
+/// Reorgs deeper than this are counted in the last histogram bucket
+pub const MAX_REORG_DEPTH_BUCKET: u64 = 64;
+
+/// Cumulative sync statistics, persisted so they survive restarts
+#[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Default, Debug)]
+pub struct SyncStats {
+    pub blocks_processed: u64,
+    pub reorgs: u64,
+    /// Number of detached blocks => number of reorgs of that depth
+    pub reorg_depths: BTreeMap<u64, u64>,
+    /// Verification error class => number of rejected blocks
+    pub invalid_blocks: BTreeMap<String, u64>,
+    /// Protocol name => received bytes
+    pub bytes_received: BTreeMap<String, u64>,
+    /// Blocks handed over again after they were stored, e.g. by both relay and sync
+    pub duplicate_blocks: u64,
+    /// Peers found on a valid chain forking deeper than the sync `long_range_fork_depth`
+    pub long_range_forks: u64,
+}
+
+impl SyncStats {
+    pub fn record_reorg(&mut self, depth: u64) {
+        self.reorgs += 1;
+        *self
+            .reorg_depths
+            .entry(depth.min(MAX_REORG_DEPTH_BUCKET))
+            .or_insert(0) += 1;
+    }
+
+    pub fn record_invalid_block(&mut self, class: &str) {
+        *self.invalid_blocks.entry(class.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn record_received(&mut self, protocol: &str, bytes: usize) {
+        *self
+            .bytes_received
+            .entry(protocol.to_string())
+            .or_insert(0) += bytes as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_stats_reorg_histogram() {
+        let mut stats = SyncStats::default();
+        stats.record_reorg(1);
+        stats.record_reorg(1);
+        stats.record_reorg(MAX_REORG_DEPTH_BUCKET + 10);
+
+        assert_eq!(stats.reorgs, 3);
+        assert_eq!(stats.reorg_depths.get(&1), Some(&2));
+        assert_eq!(stats.reorg_depths.get(&MAX_REORG_DEPTH_BUCKET), Some(&1));
+    }
+}
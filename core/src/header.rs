@@ -1,6 +1,6 @@
 use bigint::{H256, U256};
 use bincode::{deserialize, serialize};
-use hash::sha3_256;
+use hash::consensus_hash;
 
 pub use BlockNumber;
 
@@ -41,7 +41,7 @@ pub struct RawHeader {
 
 impl RawHeader {
     pub fn pow_hash(&self) -> H256 {
-        sha3_256(serialize(self).unwrap()).into()
+        consensus_hash(serialize(self).unwrap()).into()
     }
 
     pub fn with_seal(self, seal: Seal) -> Header {
@@ -59,6 +59,10 @@ impl RawHeader {
         self.number
     }
 
+    pub fn parent_hash(&self) -> H256 {
+        self.parent_hash
+    }
+
     pub fn difficulty(&self) -> U256 {
         self.difficulty
     }
@@ -225,7 +229,7 @@ impl HeaderBuilder {
     }
 
     pub fn build(self) -> Header {
-        let hash = H256::from_slice(&sha3_256(serialize(&self.inner).unwrap()));
+        let hash = H256::from_slice(&consensus_hash(serialize(&self.inner).unwrap()));
         self.with_hash(&hash)
     }
 
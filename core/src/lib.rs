@@ -13,7 +13,12 @@ extern crate bit_vec;
 extern crate crossbeam_channel as channel;
 extern crate fnv;
 extern crate merkle_root;
+#[cfg(feature = "testing")]
+#[macro_use]
+extern crate proptest;
 
+#[cfg(feature = "testing")]
+pub mod arbitrary;
 pub mod block;
 pub mod cell;
 pub mod chain;
@@ -32,3 +37,5 @@ pub use error::Error;
 pub type PublicKey = bigint::H512;
 pub type BlockNumber = u64;
 pub type Capacity = u64;
+/// Units of script execution cost
+pub type Cycle = u64;
@@ -1,5 +1,5 @@
 use bigint::H256;
-use hash::sha3_256;
+use hash::consensus_hash;
 use std::io::Write;
 
 // TODO: when flatbuffer work is done, remove Serialize/Deserialize here and
@@ -31,7 +31,9 @@ pub struct Script {
     // Pre-defined arguments that are considered part of the script.
     // When signed_args contains <a>, <b>, and args contains <c>,
     // <d>, <e>, binary will then be executed with arguments <a>, <b>,
-    // <c>, <d>, <e>.
+    // <c>, <d>, <e>, followed by the chain id from version 2 on.
+    // Only signed_args are fixed by the lock hash of the cell, args
+    // are the witness supplied when the cell is spent.
     // This can be useful when binary is fixed, but depending on different
     // use case, we might have different initial parameters. For example, in
     // secp256k1 verification, we need to provide pubkey first, this cannot be
@@ -73,32 +75,41 @@ impl Script {
 
     pub fn type_hash(&self) -> H256 {
         match self.version {
-            0 => {
-                let mut bytes = vec![];
-                // TODO: switch to flatbuffer serialization once we
-                // can do stable serialization using flatbuffer.
-                if let Some(data) = self.reference {
-                    bytes.write_all(&data).unwrap();
-                }
-                // A separator is used here to prevent the rare case
-                // that some binary might contain the exactly
-                // same data as reference. In this case we might
-                // still want to distinguish between the 2 script in
-                // the hash. Note this might not solve every problem,
-                // when flatbuffer change is done, we can leverage flatbuffer
-                // serialization directly, which will be more reliable.
-                bytes.write_all(b"|").unwrap();
-                if let Some(ref data) = self.binary {
-                    bytes.write_all(&data).unwrap()
-                }
-                for argument in &self.signed_args {
-                    bytes.write_all(argument).unwrap();
-                }
-                sha3_256(bytes).into()
+            0 => consensus_hash(self.hash_bytes()).into(),
+            // Versions from 1 on are committed to in the hash, so the same script
+            // declared under different VM versions gets different lock hashes.
+            1 | 2 => {
+                let mut bytes = vec![self.version];
+                bytes.extend(self.hash_bytes());
+                consensus_hash(bytes).into()
             }
             _ => H256::from(0),
         }
     }
+
+    fn hash_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        // TODO: switch to flatbuffer serialization once we
+        // can do stable serialization using flatbuffer.
+        if let Some(data) = self.reference {
+            bytes.write_all(&data).unwrap();
+        }
+        // A separator is used here to prevent the rare case
+        // that some binary might contain the exactly
+        // same data as reference. In this case we might
+        // still want to distinguish between the 2 script in
+        // the hash. Note this might not solve every problem,
+        // when flatbuffer change is done, we can leverage flatbuffer
+        // serialization directly, which will be more reliable.
+        bytes.write_all(b"|").unwrap();
+        if let Some(ref data) = self.binary {
+            bytes.write_all(&data).unwrap()
+        }
+        for argument in &self.signed_args {
+            bytes.write_all(argument).unwrap();
+        }
+        bytes
+    }
 }
 
 // impl From<&'static str> for Script {
@@ -1,9 +1,9 @@
 //! Transaction using Cell.
 //! It is similar to Bitcoin Tx <https://en.bitcoin.it/wiki/Protocol_documentation#tx/>
 use bigint::H256;
-use bincode::{deserialize, serialize};
+use bincode::{deserialize, serialize, serialized_size};
 use ckb_util::u64_to_bytes;
-use hash::sha3_256;
+use hash::consensus_hash;
 use header::BlockNumber;
 use script::Script;
 use std::ops::{Deref, DerefMut};
@@ -59,6 +59,12 @@ impl CellInput {
         }
     }
 
+    /// The hash of the lock script revealed by this input, it must match the
+    /// `lock_hash` committed by the spent output. The script args act as the witness.
+    pub fn lock_hash(&self) -> H256 {
+        self.unlock.type_hash()
+    }
+
     pub fn new_cellbase_input(block_number: BlockNumber) -> Self {
         CellInput {
             previous_output: OutPoint::null(),
@@ -92,7 +98,13 @@ impl CellOutput {
     }
 
     pub fn data_hash(&self) -> H256 {
-        sha3_256(&self.data).into()
+        consensus_hash(&self.data).into()
+    }
+
+    /// An output only commits to the hash of its lock script, the script itself
+    /// is revealed by the input spending it.
+    pub fn lock_hash(&self) -> H256 {
+        self.lock
     }
 }
 
@@ -148,7 +160,7 @@ impl ProposalShortId {
     }
 
     pub fn hash(&self) -> H256 {
-        sha3_256(serialize(self).unwrap()).into()
+        consensus_hash(serialize(self).unwrap()).into()
     }
 
     pub fn zero() -> Self {
@@ -178,7 +190,27 @@ impl Transaction {
     }
 
     pub fn hash(&self) -> H256 {
-        sha3_256(serialize(self).unwrap()).into()
+        consensus_hash(serialize(self).unwrap()).into()
+    }
+
+    /// Serialized size in bytes, the same encoding used for hashing and storage.
+    pub fn serialized_size(&self) -> u64 {
+        serialized_size(self).expect("transaction serialized_size")
+    }
+
+    /// Total capacity of the outputs, `None` if it overflows
+    pub fn outputs_capacity(&self) -> Option<Capacity> {
+        self.outputs
+            .iter()
+            .try_fold(0, |acc: Capacity, output| acc.checked_add(output.capacity))
+    }
+
+    /// Total size of the outputs data in bytes, the state the transaction adds to the cells
+    pub fn outputs_data_bytes(&self) -> u64 {
+        self.outputs
+            .iter()
+            .map(|output| output.data.len() as u64)
+            .sum()
     }
 
     pub fn check_lock(&self, unlock: &[u8], lock: &[u8]) -> bool {
@@ -280,7 +312,7 @@ impl TransactionBuilder {
     }
 
     pub fn build(self) -> Transaction {
-        let hash = H256::from_slice(&sha3_256(serialize(&self.inner).unwrap()));
+        let hash = H256::from_slice(&consensus_hash(serialize(&self.inner).unwrap()));
         self.with_hash(&hash)
     }
 
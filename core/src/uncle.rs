@@ -1,7 +1,7 @@
 use bigint::H256;
 use bincode::serialize;
 use block::Block;
-use hash::sha3_256;
+use hash::consensus_hash;
 use header::Header;
 use transaction::{ProposalShortId, Transaction};
 use BlockNumber;
@@ -49,6 +49,6 @@ pub fn uncles_hash(uncles: &[UncleBlock]) -> H256 {
     if uncles.is_empty() {
         H256::zero()
     } else {
-        sha3_256(serialize(uncles).unwrap()).into()
+        consensus_hash(serialize(uncles).unwrap()).into()
     }
 }
@@ -0,0 +1,27 @@
+/// Tuning of the RocksDB backend, the defaults suit a node with a few GB of memory.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct DBConfig {
+    // Bytes of the LRU cache shared by the blocks of all the column families
+    pub block_cache_size: usize,
+    // Bytes of each memtable before it is flushed to disk
+    pub write_buffer_size: usize,
+    // -1 keeps every file open
+    pub max_open_files: i32,
+    // Threads used for flushes and compactions
+    pub background_jobs: i32,
+    // Headers cached in memory in front of the database
+    pub header_cache_size: usize,
+}
+
+impl Default for DBConfig {
+    fn default() -> Self {
+        DBConfig {
+            block_cache_size: 256 * 1024 * 1024,
+            write_buffer_size: 64 * 1024 * 1024,
+            max_open_files: 512,
+            background_jobs: 4,
+            header_cache_size: 4096,
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use batch::{Batch, Col, Operation};
-use kvdb::{ErrorKind, KeyValueDB, Result};
-use rocksdb::{ColumnFamily, Options, WriteBatch, DB};
+use config::DBConfig;
+use kvdb::{ErrorKind, KeyValueDB, KeyValueIter, Result};
+use rocksdb::{BlockBasedOptions, ColumnFamily, IteratorMode, Options, WriteBatch, DB};
 use std::ops::Range;
 use std::path::Path;
 
@@ -15,9 +16,21 @@ pub struct RocksDB {
 
 impl RocksDB {
     pub fn open<P: AsRef<Path>>(path: P, columns: u32) -> Self {
+        Self::open_with_config(path, columns, &DBConfig::default())
+    }
+
+    /// Every column is a column family, a batch is written atomically across the columns.
+    pub fn open_with_config<P: AsRef<Path>>(path: P, columns: u32, config: &DBConfig) -> Self {
+        let mut block_opts = BlockBasedOptions::default();
+        block_opts.set_lru_cache(config.block_cache_size);
+
         let mut opts = Options::default();
         opts.create_if_missing(true);
         opts.create_missing_column_families(true);
+        opts.set_block_based_table_factory(&block_opts);
+        opts.set_write_buffer_size(config.write_buffer_size);
+        opts.set_max_open_files(config.max_open_files);
+        opts.increase_parallelism(config.background_jobs);
         let cfnames: Vec<_> = (0..columns).map(|c| format!("c{}", c)).collect();
         let cf_options: Vec<&str> = cfnames.iter().map(|n| n as &str).collect();
         let db = DB::open_cf(&opts, path, &cf_options).expect("rocksdb open");
@@ -87,6 +100,16 @@ impl KeyValueDB for RocksDB {
         }.map(|v| v.and_then(|vi| vi.get(range.start..range.end).map(|slice| slice.to_vec())))
         .map_err(Into::into)
     }
+
+    fn iter<'a>(&'a self, col: Col) -> Result<KeyValueIter<'a>> {
+        let iter = match self.cf_handle(col)? {
+            Some(cf) => self.inner.db.iterator_cf(cf, IteratorMode::Start)?,
+            None => self.inner.db.iterator(IteratorMode::Start),
+        };
+        Ok(Box::new(
+            iter.map(|(key, value)| (key.to_vec(), value.to_vec())),
+        ))
+    }
 }
 
 #[cfg(test)]
@@ -116,6 +139,32 @@ mod tests {
         assert!(db.read(Some(2), &vec![0, 0]).is_err());
     }
 
+    #[test]
+    fn reopen_with_config() {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("reopen_with_config")
+            .tempdir()
+            .unwrap();
+        let config = DBConfig {
+            block_cache_size: 1024 * 1024,
+            write_buffer_size: 1024 * 1024,
+            ..Default::default()
+        };
+        {
+            let db = RocksDB::open_with_config(tmp_dir.path(), 2, &config);
+            let mut batch = Batch::default();
+            batch.insert(Some(0), vec![0, 0], vec![0, 0, 0]);
+            batch.insert(Some(1), vec![1, 1], vec![1, 1, 1]);
+            batch.delete(Some(1), vec![1, 1]);
+            db.write(batch).unwrap();
+        }
+
+        // the data survives a restart
+        let db = RocksDB::open_with_config(tmp_dir.path(), 2, &config);
+        assert_eq!(Some(vec![0, 0, 0]), db.read(Some(0), &vec![0, 0]).unwrap());
+        assert_eq!(None, db.read(Some(1), &vec![1, 1]).unwrap());
+    }
+
     #[test]
     fn write_and_len() {
         let tmp_dir = tempfile::Builder::new()
@@ -135,6 +184,24 @@ mod tests {
         assert!(db.len(Some(2), &vec![1, 1]).is_err());
     }
 
+    #[test]
+    fn write_and_iter() {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("write_and_iter")
+            .tempdir()
+            .unwrap();
+        let db = RocksDB::open(tmp_dir, 2);
+        let mut batch = Batch::default();
+        batch.insert(Some(1), vec![2, 2], vec![2]);
+        batch.insert(Some(1), vec![1, 1], vec![1]);
+        batch.insert(Some(0), vec![3, 3], vec![3]);
+        db.write(batch).unwrap();
+
+        let pairs = db.iter(Some(1)).unwrap().collect::<Vec<_>>();
+        assert_eq!(pairs, vec![(vec![1, 1], vec![1]), (vec![2, 2], vec![2])]);
+        assert!(db.iter(Some(2)).is_err());
+    }
+
     #[test]
     fn write_and_partial_read() {
         let tmp_dir = tempfile::Builder::new()
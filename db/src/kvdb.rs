@@ -7,6 +7,7 @@ use std::result;
 
 pub type Error = ErrorKind;
 pub type Result<T> = result::Result<T, Error>;
+pub type KeyValueIter<'a> = Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ErrorKind {
@@ -31,6 +32,8 @@ pub trait KeyValueDB: Sync + Send {
     fn read(&self, col: Col, key: &[u8]) -> Result<Option<Vec<u8>>>;
     fn len(&self, col: Col, key: &[u8]) -> Result<Option<usize>>;
     fn partial_read(&self, col: Col, key: &[u8], range: &Range<usize>) -> Result<Option<Vec<u8>>>;
+    /// Every key and value of the column, in no particular order
+    fn iter<'a>(&'a self, col: Col) -> Result<KeyValueIter<'a>>;
     fn cols(&self) -> u32;
     fn batch(&self) -> Batch {
         Batch::new()
@@ -7,11 +7,17 @@ extern crate bincode;
 extern crate ckb_util;
 extern crate fnv;
 extern crate rocksdb;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 
 pub mod batch;
+pub mod config;
 pub mod diskdb;
 pub mod kvdb;
 pub mod memorydb;
 
 #[cfg(test)]
 extern crate tempfile;
+
+pub use config::DBConfig;
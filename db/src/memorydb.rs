@@ -1,7 +1,7 @@
 use batch::{Batch, Col, Operation};
 use ckb_util::RwLock;
 use fnv::FnvHashMap;
-use kvdb::{ErrorKind, KeyValueDB, Result};
+use kvdb::{ErrorKind, KeyValueDB, KeyValueIter, Result};
 use std::ops::Range;
 
 pub type MemoryKey = Vec<u8>;
@@ -77,6 +77,22 @@ impl KeyValueDB for MemoryKeyValueDB {
                 .map(|slice| slice.to_vec())),
         }
     }
+
+    fn iter<'a>(&'a self, col: Col) -> Result<KeyValueIter<'a>> {
+        let db = self.db.read();
+
+        match db.get(&col) {
+            None => Err(ErrorKind::DBError(format!("column {:?} not found ", col))),
+            // a snapshot, the iterator can't hold the lock
+            Some(map) => {
+                let pairs = map
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect::<Vec<_>>();
+                Ok(Box::new(pairs.into_iter()))
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -150,4 +166,19 @@ mod tests {
             db.partial_read(None, &vec![0, 0], &(1..4)).unwrap()
         );
     }
+
+    #[test]
+    fn write_and_iter() {
+        let db = MemoryKeyValueDB::open(2);
+        let mut batch = Batch::default();
+        batch.insert(Some(1), vec![1, 1], vec![1]);
+        batch.insert(Some(1), vec![2, 2], vec![2]);
+        batch.insert(Some(0), vec![3, 3], vec![3]);
+        db.write(batch).unwrap();
+
+        let mut pairs = db.iter(Some(1)).unwrap().collect::<Vec<_>>();
+        pairs.sort();
+        assert_eq!(pairs, vec![(vec![1, 1], vec![1]), (vec![2, 2], vec![2])]);
+        assert!(db.iter(Some(2)).is_err());
+    }
 }
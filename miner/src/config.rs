@@ -1,12 +1,31 @@
 use bigint::H256;
+use ckb_rpc::{MAX_TEMPLATE_BYTES, MAX_TEMPLATE_CYCLES};
+use stratum::StratumConfig;
 
 #[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct Config {
-    // Max number of transactions this miner will assemble in a block
-    pub max_tx: usize,
+    // Max serialized size of the transactions this miner will assemble in a block
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: usize,
+    // Max cycles of the transactions this miner will assemble in a block
+    #[serde(default = "default_max_cycles")]
+    pub max_cycles: u64,
     // the max number of transactions this miner can propose
     pub max_prop: usize,
     pub new_transactions_threshold: u16,
     pub ethash_path: Option<String>,
     pub type_hash: H256,
+    // Serve the PoW jobs to external workers on this address instead of solving them in process
+    pub worker_listen_addr: Option<String>,
+    // Serve the PoW jobs to stratum mining pool sessions, exclusive with `worker_listen_addr`
+    pub stratum: Option<StratumConfig>,
+}
+
+// The limits of the RPC block assembler
+fn default_max_bytes() -> usize {
+    MAX_TEMPLATE_BYTES
+}
+
+fn default_max_cycles() -> u64 {
+    MAX_TEMPLATE_CYCLES
 }
@@ -11,14 +11,23 @@ extern crate crossbeam_channel as channel;
 #[macro_use]
 extern crate log;
 extern crate ckb_sync;
+extern crate ckb_util;
 extern crate flatbuffers;
+extern crate fnv;
 extern crate rand;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
 extern crate ckb_pow;
 
 mod config;
 mod miner;
+mod stratum;
+mod worker;
 
 pub use config::Config;
 pub use miner::MinerService;
+pub use stratum::{ShareStats, StratumConfig, StratumServer};
+pub use worker::{Job, JobServer, WorkerServer};
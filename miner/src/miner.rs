@@ -1,22 +1,28 @@
 use super::Config;
-use channel::Receiver;
+use bigint::H256;
+use channel::{self, Receiver, Sender};
 use ckb_chain::chain::ChainController;
 use ckb_core::block::{Block, BlockBuilder};
 use ckb_core::header::{RawHeader, Seal};
 use ckb_core::BlockNumber;
-use ckb_network::NetworkService;
-use ckb_notify::{MsgNewTip, MsgNewTransaction, NotifyController, MINER_SUBSCRIBER};
+use ckb_network::{NetworkService, PeerIndex};
+use ckb_notify::{
+    coalesce_new_tips, MsgNewTip, MsgNewTransaction, NotifyController, MINER_SUBSCRIBER,
+};
 use ckb_pow::PowEngine;
 use ckb_protocol::RelayMessage;
 use ckb_rpc::{BlockTemplate, RpcController};
 use ckb_shared::index::ChainIndex;
 use ckb_shared::shared::Shared;
-use ckb_sync::RELAY_PROTOCOL_ID;
+use ckb_sync::{announce_order, ANNOUNCE_STAGGER, RELAY_PROTOCOL_ID};
 use flatbuffers::FlatBufferBuilder;
 use rand::{thread_rng, Rng};
 use std::collections::HashSet;
 use std::sync::Arc;
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use stratum::StratumServer;
+use worker::{JobServer, WorkerServer};
 
 pub struct MinerService {
     config: Config,
@@ -27,6 +33,48 @@ pub struct MinerService {
     new_tx_receiver: Receiver<MsgNewTransaction>,
     new_tip_receiver: Receiver<MsgNewTip>,
     mining_number: BlockNumber,
+    // the tip the mining template builds on
+    mining_parent: H256,
+    worker: Option<(Arc<dyn JobServer>, Receiver<(u64, Seal)>)>,
+    announcer: Sender<DelayedAnnouncement>,
+}
+
+// A mined block announced to the slower peers once `ANNOUNCE_STAGGER` has passed
+struct DelayedAnnouncement {
+    due: Instant,
+    number: BlockNumber,
+    data: Vec<u8>,
+    peers: Vec<PeerIndex>,
+}
+
+// A single thread sends the delayed announcements of all the mined blocks, they wait the same
+// stagger so they are due in the order they are queued. It stops with the miner.
+fn start_announcer(network: Arc<NetworkService>) -> Sender<DelayedAnnouncement> {
+    let (sender, receiver) = channel::unbounded::<DelayedAnnouncement>();
+    thread::Builder::new()
+        .name("announce".to_string())
+        .spawn(move || {
+            while let Some(announcement) = receiver.recv() {
+                let now = Instant::now();
+                if announcement.due > now {
+                    thread::sleep(announcement.due - now);
+                }
+                let DelayedAnnouncement {
+                    number,
+                    data,
+                    peers,
+                    ..
+                } = announcement;
+                network.with_protocol_context(RELAY_PROTOCOL_ID, |nc| {
+                    // peers disconnected meanwhile are skipped by the network
+                    for peer in peers {
+                        debug!(target: "miner", "announce new block to peer#{}, {}", peer, number);
+                        let _ = nc.send(peer, data.clone());
+                    }
+                });
+            }
+        }).expect("Start block announcer failed");
+    sender
 }
 
 impl MinerService {
@@ -42,7 +90,26 @@ impl MinerService {
         let new_tx_receiver = notify.subscribe_new_transaction(MINER_SUBSCRIBER);
         let new_tip_receiver = notify.subscribe_new_tip(MINER_SUBSCRIBER);
 
-        let mining_number = shared.tip_header().read().number();
+        let (mining_number, mining_parent) = {
+            let tip_header = shared.tip_header().read();
+            (tip_header.number(), tip_header.hash())
+        };
+
+        let worker = match (&config.worker_listen_addr, &config.stratum) {
+            (Some(_), Some(_)) => panic!("Miner worker_listen_addr and stratum are exclusive"),
+            (Some(listen_addr), None) => {
+                let (server, seal_receiver) = WorkerServer::start(listen_addr, Arc::clone(&pow))
+                    .expect("Start miner worker server failed");
+                Some((Arc::new(server) as Arc<dyn JobServer>, seal_receiver))
+            }
+            (None, Some(stratum)) => {
+                let (server, seal_receiver) = StratumServer::start(stratum, Arc::clone(&pow))
+                    .expect("Start miner stratum server failed");
+                Some((Arc::new(server) as Arc<dyn JobServer>, seal_receiver))
+            }
+            (None, None) => None,
+        };
+        let announcer = start_announcer(Arc::clone(&network));
 
         MinerService {
             config,
@@ -53,6 +120,9 @@ impl MinerService {
             new_tip_receiver,
             network,
             mining_number,
+            mining_parent,
+            worker,
+            announcer,
         }
     }
 
@@ -74,11 +144,13 @@ impl MinerService {
     fn commit_new_block(&mut self) {
         match self.rpc.get_block_template(
             self.config.type_hash,
-            self.config.max_tx,
+            self.config.max_bytes,
+            self.config.max_cycles,
             self.config.max_prop,
         ) {
             Ok(block_template) => {
                 self.mining_number = block_template.raw_header.number();
+                self.mining_parent = block_template.raw_header.parent_hash();
                 if let Some(block) = self.mine(block_template) {
                     let block = Arc::new(block);
                     debug!(target: "miner", "new block mined: {} -> (number: {}, difficulty: {}, timestamp: {})",
@@ -113,33 +185,27 @@ impl MinerService {
     }
 
     fn mine_loop(&self, header: &RawHeader) -> Option<Seal> {
-        let new_transactions_threshold = self.config.new_transactions_threshold;
+        match self.worker {
+            Some((ref server, ref seal_receiver)) => {
+                self.wait_worker_seal(header, server.as_ref(), seal_receiver)
+            }
+            None => self.solve_locally(header),
+        }
+    }
+
+    fn solve_locally(&self, header: &RawHeader) -> Option<Seal> {
         let mut new_transactions_counter = 0;
         let mut nonce: u64 = thread_rng().gen();
         loop {
             loop {
                 select! {
-                    recv(self.new_tx_receiver, msg) => match msg {
-                        Some(()) => {
-                            if new_transactions_counter >= new_transactions_threshold {
-                                return None;
-                            } else {
-                                new_transactions_counter += 1;
-                            }
-                        }
-                        None => {
-                            error!(target: "miner", "channel new_tx_receiver closed");
+                    recv(self.new_tx_receiver, msg) => {
+                        if self.new_transaction_interrupts(msg, &mut new_transactions_counter) {
                             return None;
                         }
                     }
-                    recv(self.new_tip_receiver, msg) => match msg {
-                        Some(block) => {
-                            if block.header().number() >= self.mining_number {
-                                return None;
-                            }
-                        }
-                        None => {
-                            error!(target: "miner", "channel new_tip_receiver closed");
+                    recv(self.new_tip_receiver, msg) => {
+                        if self.new_tip_interrupts(msg) {
                             return None;
                         }
                     }
@@ -154,16 +220,110 @@ impl MinerService {
         }
     }
 
-    fn announce_new_block(&self, block: &Arc<Block>) {
-        self.network.with_protocol_context(RELAY_PROTOCOL_ID, |nc| {
-            let fbb = &mut FlatBufferBuilder::new();
-            let message = RelayMessage::build_compact_block(fbb, &block, &HashSet::new());
-            fbb.finish(message, None);
-            for peer in nc.connected_peers() {
-                debug!(target: "miner", "announce new block to peer#{}, {} => {}",
-                       peer, block.header().number(), block.header().hash());
-                let _ = nc.send(peer, fbb.finished_data().to_vec());
+    // The external workers solve the job, the seals they submit are verified by the server
+    fn wait_worker_seal(
+        &self,
+        header: &RawHeader,
+        server: &dyn JobServer,
+        seal_receiver: &Receiver<(u64, Seal)>,
+    ) -> Option<Seal> {
+        let mut new_transactions_counter = 0;
+        let job_id = server.set_job(header);
+        loop {
+            select! {
+                recv(self.new_tx_receiver, msg) => {
+                    if self.new_transaction_interrupts(msg, &mut new_transactions_counter) {
+                        return None;
+                    }
+                }
+                recv(self.new_tip_receiver, msg) => {
+                    if self.new_tip_interrupts(msg) {
+                        // the solutions in flight would only make uncles
+                        server.cancel_jobs();
+                        return None;
+                    }
+                }
+                recv(seal_receiver, msg) => match msg {
+                    Some((id, seal)) => {
+                        if id == job_id {
+                            debug!(target: "miner", "worker found seal: {:?}", seal);
+                            return Some(seal);
+                        }
+                    }
+                    None => {
+                        error!(target: "miner", "channel seal_receiver closed");
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+
+    fn new_transaction_interrupts(
+        &self,
+        msg: Option<MsgNewTransaction>,
+        counter: &mut u16,
+    ) -> bool {
+        match msg {
+            Some(()) => {
+                if *counter >= self.config.new_transactions_threshold {
+                    true
+                } else {
+                    *counter += 1;
+                    false
+                }
             }
+            None => {
+                error!(target: "miner", "channel new_tx_receiver closed");
+                true
+            }
+        }
+    }
+
+    fn new_tip_interrupts(&self, msg: Option<MsgNewTip>) -> bool {
+        match msg {
+            Some(block) => {
+                // a template is only rebuilt for the latest tip of a burst, a reorg replacing
+                // the parent at the same height makes the template stale as well
+                let tips = coalesce_new_tips(&self.new_tip_receiver, block);
+                tips.last()
+                    .map_or(false, |tip| tip.header().hash() != self.mining_parent)
+            }
+            None => {
+                error!(target: "miner", "channel new_tip_receiver closed");
+                true
+            }
+        }
+    }
+
+    // The fastest peers get the block first, they relay it on while the others wait for
+    // `ANNOUNCE_STAGGER`, every peer still gets the block once.
+    fn announce_new_block(&self, block: &Arc<Block>) {
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_compact_block(fbb, &block, &HashSet::new());
+        fbb.finish(message, None);
+        let data = fbb.finished_data().to_vec();
+
+        let rest = self
+            .network
+            .with_protocol_context(RELAY_PROTOCOL_ID, |nc| {
+                let (first, rest) = announce_order(nc, nc.connected_peers());
+                for peer in first {
+                    debug!(target: "miner", "announce new block to peer#{}, {} => {}",
+                           peer, block.header().number(), block.header().hash());
+                    let _ = nc.send(peer, data.clone());
+                }
+                rest
+            }).unwrap_or_default();
+        if rest.is_empty() {
+            return;
+        }
+
+        self.announcer.send(DelayedAnnouncement {
+            due: Instant::now() + Duration::from_millis(ANNOUNCE_STAGGER),
+            number: block.header().number(),
+            data,
+            peers: rest,
         });
     }
 }
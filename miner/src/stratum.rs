@@ -0,0 +1,634 @@
+use bigint::{H256, U256};
+use channel::{self, Receiver, Sender};
+use ckb_core::difficulty::difficulty_to_boundary;
+use ckb_core::header::{BlockNumber, RawHeader, Seal};
+use ckb_pow::PowEngine;
+use ckb_util::Mutex;
+use fnv::{FnvHashMap, FnvHashSet};
+use serde_json::{self, Value};
+use std::cmp;
+use std::io::{self, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use worker::{read_line, ConnectionLimit, JobServer, Jobs, MAX_CONNECTIONS, MAX_LINE_BYTES};
+
+pub type SessionId = usize;
+
+/// Worker names accounted in the share stats, which are kept as long as the server runs
+const MAX_WORKERS: usize = 1024;
+/// A session not reading its notifications for this long is dropped
+const WRITE_TIMEOUT_SECS: u64 = 5;
+
+// Error codes of the stratum protocol
+const ERROR_UNKNOWN: i64 = 20;
+const ERROR_JOB_NOT_FOUND: i64 = 21;
+const ERROR_DUPLICATE_SHARE: i64 = 22;
+const ERROR_LOW_DIFFICULTY: i64 = 23;
+const ERROR_UNAUTHORIZED: i64 = 24;
+const ERROR_NOT_SUBSCRIBED: i64 = 25;
+const ERROR_STALE_JOB: i64 = 26;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct StratumConfig {
+    pub listen_addr: String,
+    // Difficulty of the shares accounted to the workers, capped at the block difficulty
+    pub share_difficulty: U256,
+}
+
+/// Shares submitted by a worker since the server started
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ShareStats {
+    pub accepted: u64,
+    pub rejected: u64,
+    /// Rejected shares of jobs on a replaced tip
+    pub stale: u64,
+    /// Sum of the difficulty of the accepted shares, the work done by the worker
+    pub accepted_difficulty: U256,
+    /// Accepted shares which also sealed a block
+    pub blocks: u64,
+}
+
+impl ShareStats {
+    fn new() -> Self {
+        ShareStats {
+            accepted: 0,
+            rejected: 0,
+            stale: 0,
+            accepted_difficulty: U256::zero(),
+            blocks: 0,
+        }
+    }
+}
+
+/// Requests of the stratum protocol, a JSON-RPC object per line:
+///
+/// - `mining.subscribe` returns the session id, the session receives the notifications
+/// - `mining.authorize` with `[worker_name, password]` returns `true`, any worker name is
+///   accepted until `MAX_WORKERS` names are, and its shares are accounted under that name
+/// - `mining.submit` with `[worker_name, job_id, nonce, proof]` returns `true` when the
+///   share meets the share difficulty of the job, the shares of the jobs on a replaced tip
+///   are rejected with the stale error code
+///
+/// The server notifies the subscribed sessions with `mining.set_difficulty`
+/// `[share_difficulty]` followed by `mining.notify`
+/// `[job_id, number, pow_hash, target, true, generation]` whenever the job changes, the previous
+/// jobs are always abandoned. Once the tip changes `mining.cancel_jobs` `[generation]` tells the
+/// sessions to stop working on the jobs older than the new generation.
+#[derive(Debug, Deserialize)]
+struct Request {
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct Response {
+    id: Value,
+    result: Value,
+    error: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct Notification<'a> {
+    id: Value,
+    method: &'a str,
+    params: Value,
+}
+
+struct StratumJob {
+    job_id: u64,
+    generation: u64,
+    header: RawHeader,
+    share_difficulty: U256,
+    // nonces of the shares submitted for this job
+    nonces: FnvHashSet<u64>,
+}
+
+struct Session {
+    writer: Arc<Mutex<TcpStream>>,
+    subscribed: bool,
+    workers: FnvHashSet<String>,
+}
+
+/// Serves the PoW jobs to multiple stratum worker sessions and accounts the shares they submit,
+/// shares meeting the block difficulty are handed to the miner as seals.
+#[derive(Clone)]
+pub struct StratumServer {
+    pow: Arc<dyn PowEngine>,
+    share_difficulty: U256,
+    jobs: Arc<Mutex<Jobs<StratumJob>>>,
+    sessions: Arc<Mutex<FnvHashMap<SessionId, Session>>>,
+    stats: Arc<Mutex<FnvHashMap<String, ShareStats>>>,
+    last_session_id: Arc<AtomicUsize>,
+    connections: ConnectionLimit,
+    seal_sender: Sender<(u64, Seal)>,
+}
+
+impl StratumServer {
+    pub fn start(
+        config: &StratumConfig,
+        pow: Arc<dyn PowEngine>,
+    ) -> io::Result<(StratumServer, Receiver<(u64, Seal)>)> {
+        let listener = TcpListener::bind(&config.listen_addr)?;
+        info!(target: "miner", "Serving stratum sessions on {}", config.listen_addr);
+        Self::listen(listener, config.share_difficulty, pow)
+    }
+
+    fn listen(
+        listener: TcpListener,
+        share_difficulty: U256,
+        pow: Arc<dyn PowEngine>,
+    ) -> io::Result<(StratumServer, Receiver<(u64, Seal)>)> {
+        let (seal_sender, seal_receiver) = channel::unbounded();
+        let server = StratumServer {
+            pow,
+            share_difficulty,
+            jobs: Arc::new(Mutex::new(Jobs::default())),
+            sessions: Arc::new(Mutex::new(FnvHashMap::default())),
+            stats: Arc::new(Mutex::new(FnvHashMap::default())),
+            last_session_id: Arc::new(AtomicUsize::new(0)),
+            connections: ConnectionLimit::new(MAX_CONNECTIONS),
+            seal_sender,
+        };
+
+        let acceptor = server.clone();
+        thread::Builder::new()
+            .name("MinerStratum".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => acceptor.serve(stream),
+                        Err(err) => warn!(target: "miner", "accept stratum error: {:?}", err),
+                    }
+                }
+            })?;
+        Ok((server, seal_receiver))
+    }
+
+    /// Shares accounted per worker name
+    pub fn share_stats(&self) -> FnvHashMap<String, ShareStats> {
+        self.stats.lock().clone()
+    }
+
+    pub fn sessions_count(&self) -> usize {
+        self.sessions.lock().len()
+    }
+
+    fn serve(&self, stream: TcpStream) {
+        // dropping the stream closes the connection
+        let slot = match self.connections.acquire() {
+            Some(slot) => slot,
+            None => {
+                warn!(target: "miner", "too many stratum sessions, refuse {:?}", stream.peer_addr());
+                return;
+            }
+        };
+        let server = self.clone();
+        let spawned = thread::Builder::new()
+            .name("MinerStratumSession".to_string())
+            .spawn(move || {
+                let _slot = slot;
+                let peer = stream.peer_addr();
+                let session_id = server.last_session_id.fetch_add(1, Ordering::SeqCst);
+                if let Err(err) = server.handle_connection(session_id, stream) {
+                    debug!(target: "miner", "stratum session {:?} disconnected: {:?}", peer, err);
+                }
+                server.sessions.lock().remove(&session_id);
+            });
+        if let Err(err) = spawned {
+            warn!(target: "miner", "spawn stratum session error: {:?}", err);
+        }
+    }
+
+    fn handle_connection(&self, session_id: SessionId, stream: TcpStream) -> io::Result<()> {
+        let writer = stream.try_clone()?;
+        writer.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_SECS)))?;
+        let writer = Arc::new(Mutex::new(writer));
+        self.sessions.lock().insert(
+            session_id,
+            Session {
+                writer: Arc::clone(&writer),
+                subscribed: false,
+                workers: FnvHashSet::default(),
+            },
+        );
+        let mut reader = BufReader::new(stream);
+        while let Some(line) = read_line(&mut reader, MAX_LINE_BYTES)? {
+            match serde_json::from_str::<Request>(&line) {
+                Ok(request) => {
+                    let subscribe = request.method == "mining.subscribe";
+                    let result = self.handle_request(session_id, &request);
+                    let subscribed = subscribe && result.is_ok();
+                    write_line(&mut writer.lock(), &response(request.id, result))?;
+                    // a new subscriber starts working on the current job right away, the
+                    // broadcasts of the later jobs wait for the writer
+                    if subscribed {
+                        let mut writer = writer.lock();
+                        let current = self
+                            .jobs
+                            .lock()
+                            .current
+                            .as_ref()
+                            .map(|job| [set_difficulty(job), notify(job)]);
+                        for message in current.iter().flat_map(|messages| messages.iter()) {
+                            write_line(&mut writer, message)?;
+                        }
+                    }
+                }
+                Err(err) => write_line(
+                    &mut writer.lock(),
+                    &response(Value::Null, Err((ERROR_UNKNOWN, err.to_string()))),
+                )?,
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_request(&self, session_id: SessionId, request: &Request) -> Result<Value, Error> {
+        match request.method.as_str() {
+            "mining.subscribe" => {
+                self.with_session(session_id, |session| session.subscribed = true);
+                Ok(Value::String(session_id.to_string()))
+            }
+            "mining.authorize" => {
+                let worker = param_str(&request.params, 0)?;
+                {
+                    let mut stats = self.stats.lock();
+                    if !stats.contains_key(worker) {
+                        if stats.len() >= MAX_WORKERS {
+                            return Err((ERROR_UNAUTHORIZED, "Too many workers".to_string()));
+                        }
+                        stats.insert(worker.to_string(), ShareStats::new());
+                    }
+                }
+                self.with_session(session_id, |session| {
+                    session.workers.insert(worker.to_string())
+                });
+                Ok(Value::Bool(true))
+            }
+            "mining.submit" => {
+                let worker = param_str(&request.params, 0)?;
+                let job_id = param_u64(&request.params, 1)?;
+                let nonce = param_u64(&request.params, 2)?;
+                let proof: Vec<u8> = request
+                    .params
+                    .get(3)
+                    .and_then(|proof| serde_json::from_value(proof.clone()).ok())
+                    .ok_or_else(|| invalid_params("proof"))?;
+
+                let authorized = self
+                    .with_session(session_id, |session| {
+                        if !session.subscribed {
+                            Err((ERROR_NOT_SUBSCRIBED, "Not subscribed".to_string()))
+                        } else if !session.workers.contains(worker) {
+                            Err((ERROR_UNAUTHORIZED, "Unauthorized worker".to_string()))
+                        } else {
+                            Ok(())
+                        }
+                    }).unwrap_or_else(|| Err((ERROR_UNKNOWN, "Unknown session".to_string())));
+                authorized?;
+
+                let submitted = self.submit(job_id, nonce, proof);
+                self.account_share(worker, &submitted);
+                submitted.map(|_| Value::Bool(true))
+            }
+            method => Err((ERROR_UNKNOWN, format!("Unknown method {}", method))),
+        }
+    }
+
+    fn with_session<F, T>(&self, session_id: SessionId, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut Session) -> T,
+    {
+        self.sessions.lock().get_mut(&session_id).map(f)
+    }
+
+    /// Returns the difficulty of the accepted share, and whether it sealed the block
+    fn submit(&self, job_id: u64, nonce: u64, proof: Vec<u8>) -> Result<(U256, bool), Error> {
+        let (header, share_difficulty) = {
+            let mut jobs = self.jobs.lock();
+            let job = current_job(&mut jobs, job_id)?;
+            if job.nonces.contains(&nonce) {
+                return Err((ERROR_DUPLICATE_SHARE, "Duplicate share".to_string()));
+            }
+            (job.header.clone(), job.share_difficulty)
+        };
+
+        let seal = Seal::new(nonce, proof);
+
+        let header = header.with_seal(seal.clone());
+        if !self.pow.verify_header_difficulty(&header, &share_difficulty) {
+            return Err((ERROR_LOW_DIFFICULTY, "Low difficulty share".to_string()));
+        }
+        // recorded once verified, an invalid share doesn't take the nonce from a valid one
+        if !current_job(&mut self.jobs.lock(), job_id)?
+            .nonces
+            .insert(nonce)
+        {
+            return Err((ERROR_DUPLICATE_SHARE, "Duplicate share".to_string()));
+        }
+        let sealed = self.pow.verify_header(&header);
+        if sealed {
+            self.seal_sender.send((job_id, seal));
+        }
+        Ok((share_difficulty, sealed))
+    }
+
+    fn account_share(&self, worker: &str, submitted: &Result<(U256, bool), Error>) {
+        let mut stats = self.stats.lock();
+        let stats = stats
+            .entry(worker.to_string())
+            .or_insert_with(ShareStats::new);
+        match *submitted {
+            Ok((difficulty, sealed)) => {
+                stats.accepted += 1;
+                stats.accepted_difficulty = stats.accepted_difficulty + difficulty;
+                if sealed {
+                    stats.blocks += 1;
+                }
+            }
+            Err((code, _)) => {
+                stats.rejected += 1;
+                if code == ERROR_STALE_JOB {
+                    stats.stale += 1;
+                }
+            }
+        }
+    }
+
+    /// Writes to the sessions without holding the server locks, a session failing a write, as
+    /// when it stops reading until the write timeout, is disconnected.
+    fn broadcast(&self, messages: &[Notification]) {
+        let writers: Vec<(SessionId, Arc<Mutex<TcpStream>>)> = self
+            .sessions
+            .lock()
+            .iter()
+            .filter(|(_, session)| session.subscribed)
+            .map(|(session_id, session)| (*session_id, Arc::clone(&session.writer)))
+            .collect();
+        for (session_id, writer) in writers {
+            let mut writer = writer.lock();
+            let written = messages
+                .iter()
+                .try_for_each(|message| write_line(&mut writer, message));
+            if let Err(err) = written {
+                debug!(target: "miner", "notify stratum session {} error: {:?}", session_id, err);
+                // the session thread reads the end of the stream and exits
+                let _ = writer.shutdown(Shutdown::Both);
+                self.sessions.lock().remove(&session_id);
+            }
+        }
+    }
+}
+
+impl JobServer for StratumServer {
+    fn set_job(&self, header: &RawHeader) -> u64 {
+        let (job_id, messages) = {
+            let mut jobs = self.jobs.lock();
+            let (job_id, generation) = jobs.next_job();
+            let job = StratumJob {
+                job_id,
+                generation,
+                header: header.clone(),
+                share_difficulty: cmp::min(self.share_difficulty, header.difficulty()),
+                nonces: FnvHashSet::default(),
+            };
+            let messages = [set_difficulty(&job), notify(&job)];
+            jobs.current = Some(job);
+            (job_id, messages)
+        };
+        self.broadcast(&messages);
+        job_id
+    }
+
+    fn cancel_jobs(&self) {
+        let generation = self.jobs.lock().cancel();
+        self.broadcast(&[Notification {
+            id: Value::Null,
+            method: "mining.cancel_jobs",
+            params: json!([generation]),
+        }]);
+    }
+}
+
+type Error = (i64, String);
+
+fn current_job(jobs: &mut Jobs<StratumJob>, job_id: u64) -> Result<&mut StratumJob, Error> {
+    if jobs.is_stale(job_id) {
+        return Err((ERROR_STALE_JOB, "Stale job".to_string()));
+    }
+    match jobs.current {
+        Some(ref mut job) if job.job_id == job_id => Ok(job),
+        _ => Err((ERROR_JOB_NOT_FOUND, "Job not found".to_string())),
+    }
+}
+
+fn response(id: Value, result: Result<Value, Error>) -> Response {
+    match result {
+        Ok(result) => Response {
+            id,
+            result,
+            error: Value::Null,
+        },
+        Err((code, message)) => Response {
+            id,
+            result: Value::Null,
+            error: json!([code, message, Value::Null]),
+        },
+    }
+}
+
+fn set_difficulty(job: &StratumJob) -> Notification<'static> {
+    Notification {
+        id: Value::Null,
+        method: "mining.set_difficulty",
+        params: json!([job.share_difficulty]),
+    }
+}
+
+fn notify(job: &StratumJob) -> Notification<'static> {
+    let target: H256 = difficulty_to_boundary(&job.header.difficulty());
+    let number: BlockNumber = job.header.number();
+    Notification {
+        id: Value::Null,
+        method: "mining.notify",
+        params: json!([
+            job.job_id,
+            number,
+            job.header.pow_hash(),
+            target,
+            true,
+            job.generation
+        ]),
+    }
+}
+
+fn write_line<T: ::serde::Serialize>(writer: &mut TcpStream, message: &T) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, message)?;
+    writer.write_all(b"\n")
+}
+
+fn invalid_params(name: &str) -> Error {
+    (ERROR_UNKNOWN, format!("Invalid param {}", name))
+}
+
+fn param_str(params: &Value, index: usize) -> Result<&str, Error> {
+    params
+        .get(index)
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid_params(&index.to_string()))
+}
+
+fn param_u64(params: &Value, index: usize) -> Result<u64, Error> {
+    params
+        .get(index)
+        .and_then(Value::as_u64)
+        .ok_or_else(|| invalid_params(&index.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_core::header::{Header, HeaderBuilder};
+    use std::any::Any;
+    use std::net::SocketAddr;
+
+    /// Accepts the shares with a proof
+    struct ProofPowEngine;
+
+    impl PowEngine for ProofPowEngine {
+        fn init(&self, _number: BlockNumber) {}
+
+        fn verify_header_difficulty(&self, header: &Header, _difficulty: &U256) -> bool {
+            !header.proof().is_empty()
+        }
+
+        fn solve(&self, _number: BlockNumber, _message: &[u8]) -> Option<Vec<u8>> {
+            None
+        }
+
+        fn verify(&self, _number: BlockNumber, _message: &[u8], _proof: &[u8]) -> bool {
+            false
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn start_server() -> (StratumServer, Receiver<(u64, Seal)>, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (server, seal_receiver) =
+            StratumServer::listen(listener, U256::from(100), Arc::new(ProofPowEngine)).unwrap();
+        (server, seal_receiver, addr)
+    }
+
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn connect(addr: SocketAddr) -> Self {
+            let writer = TcpStream::connect(addr).unwrap();
+            Client {
+                reader: BufReader::new(writer.try_clone().unwrap()),
+                writer,
+            }
+        }
+
+        fn request(&mut self, method: &str, params: Value) -> Value {
+            write_line(
+                &mut self.writer,
+                &json!({"id": 1, "method": method, "params": params}),
+            )
+            .unwrap();
+            self.receive()
+        }
+
+        fn receive(&mut self) -> Value {
+            let line = read_line(&mut self.reader, MAX_LINE_BYTES)
+                .unwrap()
+                .unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_submit_shares() {
+        let (server, seal_receiver, addr) = start_server();
+        let header = HeaderBuilder::default()
+            .number(1)
+            .difficulty(&U256::from(1000))
+            .build()
+            .into_raw();
+        let job_id = server.set_job(&header);
+
+        let mut client = Client::connect(addr);
+        assert_eq!(
+            client.request("mining.subscribe", json!([]))["error"],
+            Value::Null
+        );
+        assert_eq!(client.receive()["method"], "mining.set_difficulty");
+        assert_eq!(client.receive()["params"][0], json!(job_id));
+        assert_eq!(
+            client.request("mining.authorize", json!(["w", ""]))["result"],
+            true
+        );
+
+        // the invalid share doesn't take the nonce of the valid one
+        let invalid = client.request("mining.submit", json!(["w", job_id, 7, []]));
+        assert_eq!(invalid["error"][0], ERROR_LOW_DIFFICULTY);
+        let valid = client.request("mining.submit", json!(["w", job_id, 7, [1]]));
+        assert_eq!(valid["result"], true);
+        let duplicate = client.request("mining.submit", json!(["w", job_id, 7, [1]]));
+        assert_eq!(duplicate["error"][0], ERROR_DUPLICATE_SHARE);
+        assert_eq!(
+            seal_receiver.try_recv(),
+            Some((job_id, Seal::new(7, vec![1])))
+        );
+
+        server.cancel_jobs();
+        assert_eq!(client.receive()["method"], "mining.cancel_jobs");
+        let stale = client.request("mining.submit", json!(["w", job_id, 8, [1]]));
+        assert_eq!(stale["error"][0], ERROR_STALE_JOB);
+
+        assert_eq!(
+            server.share_stats()["w"],
+            ShareStats {
+                accepted: 1,
+                rejected: 3,
+                stale: 1,
+                accepted_difficulty: U256::from(100),
+                blocks: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_workers_limit() {
+        let (server, _seal_receiver, _addr) = start_server();
+        let authorize = |worker: &str| {
+            server.handle_request(
+                0,
+                &Request {
+                    id: Value::Null,
+                    method: "mining.authorize".to_string(),
+                    params: json!([worker, ""]),
+                },
+            )
+        };
+        for worker in 0..MAX_WORKERS {
+            assert!(authorize(&worker.to_string()).is_ok());
+        }
+        assert_eq!(authorize("new").unwrap_err().0, ERROR_UNAUTHORIZED);
+        // the known ones are still accepted
+        assert!(authorize("0").is_ok());
+        assert_eq!(server.share_stats().len(), MAX_WORKERS);
+    }
+}
@@ -0,0 +1,452 @@
+use bigint::H256;
+use channel::{self, Receiver, Sender};
+use ckb_core::difficulty::difficulty_to_boundary;
+use ckb_core::header::{BlockNumber, RawHeader, Seal};
+use ckb_pow::PowEngine;
+use ckb_util::Mutex;
+use serde_json;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Longest request line, the proof of a solution is well below it
+pub(crate) const MAX_LINE_BYTES: usize = 16 * 1024;
+/// Connections served at once, each by its own thread
+pub(crate) const MAX_CONNECTIONS: usize = 64;
+/// A worker sending no request for this long is disconnected, it polls `get_job` far more often
+const READ_TIMEOUT_SECS: u64 = 60;
+/// A worker not reading its responses for this long is disconnected
+const WRITE_TIMEOUT_SECS: u64 = 5;
+
+/// The PoW job of the block being mined, a worker searches a nonce and a proof for `pow_hash`
+/// whose blake2b hash is below `target`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Job {
+    pub job_id: u64,
+    /// Jobs of the same generation build on the same tip
+    pub generation: u64,
+    pub number: BlockNumber,
+    pub pow_hash: H256,
+    pub target: H256,
+}
+
+/// Requests of the worker protocol, a JSON object per line, each answered by a line:
+///
+/// - `{"method": "get_job"}` returns `{"job": <Job or null>}`, the job is null once the tip
+///   changed until the miner hands out a job on the new tip
+/// - `{"method": "submit", "job_id": 1, "nonce": 42, "proof": [..]}` returns
+///   `{"accepted": <bool>}`, a solution is accepted when it seals the current job, otherwise
+///   `"reason"` is one of `"stale"`, the tip of the job changed, `"unknown_job"` or `"invalid"`
+///
+/// A malformed request is answered by `{"error": <reason>}`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum Request {
+    GetJob,
+    Submit {
+        job_id: u64,
+        nonce: u64,
+        proof: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum Response {
+    Job { job: Option<Job> },
+    Submit {
+        accepted: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<Rejection>,
+    },
+    Error { error: String },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rejection {
+    /// The job builds on a replaced tip
+    Stale,
+    /// The job was never handed out or is replaced by a newer template on the same tip
+    UnknownJob,
+    /// The solution doesn't seal the job
+    Invalid,
+}
+
+/// Hands the PoW jobs of the miner to solvers outside the miner thread.
+pub trait JobServer: Send + Sync {
+    /// Replaces the current job, returns its id. Solutions of the previous jobs are rejected.
+    fn set_job(&self, header: &RawHeader) -> u64;
+
+    /// The tip changed, the jobs handed out so far are stale and the workers should drop them.
+    fn cancel_jobs(&self);
+}
+
+/// Jobs handed out by a job server, grouped in generations.
+///
+/// Every job of a generation builds on the same tip and the generation ends once the tip
+/// changes. Solutions of ended generations are rejected as stale, the blocks would at best
+/// become uncles.
+pub struct Jobs<T> {
+    next_job_id: u64,
+    generation: u64,
+    // the first job id of the current generation
+    generation_start: u64,
+    pub current: Option<T>,
+}
+
+impl<T> Default for Jobs<T> {
+    fn default() -> Self {
+        Jobs {
+            next_job_id: 0,
+            generation: 0,
+            generation_start: 0,
+            current: None,
+        }
+    }
+}
+
+impl<T> Jobs<T> {
+    /// Returns the id and the generation of the next job
+    pub fn next_job(&mut self) -> (u64, u64) {
+        let job_id = self.next_job_id;
+        self.next_job_id += 1;
+        (job_id, self.generation)
+    }
+
+    /// Ends the current generation and drops the current job
+    pub fn cancel(&mut self) -> u64 {
+        self.current = None;
+        self.generation += 1;
+        self.generation_start = self.next_job_id;
+        self.generation
+    }
+
+    pub fn is_stale(&self, job_id: u64) -> bool {
+        job_id < self.generation_start
+    }
+}
+
+/// Reads the next line without its line break, `None` at the end of the stream. A line longer
+/// than `max_bytes` is an error, a peer can't make the server buffer without limit.
+pub(crate) fn read_line<R: BufRead>(
+    reader: &mut R,
+    max_bytes: usize,
+) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(max_bytes as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    } else if line.len() > max_bytes {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Counts the open connections of a server against a maximum.
+#[derive(Clone)]
+pub(crate) struct ConnectionLimit {
+    open: Arc<AtomicUsize>,
+    max: usize,
+}
+
+/// Holds a connection slot until dropped
+pub(crate) struct ConnectionSlot {
+    open: Arc<AtomicUsize>,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> Self {
+        ConnectionLimit {
+            open: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// Takes a slot for a new connection, `None` when all are taken
+    pub fn acquire(&self) -> Option<ConnectionSlot> {
+        if self.open.fetch_add(1, Ordering::SeqCst) >= self.max {
+            self.open.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(ConnectionSlot {
+            open: Arc::clone(&self.open),
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct CurrentJob {
+    job: Job,
+    header: RawHeader,
+}
+
+/// Serves the PoW jobs to external workers over TCP, and hands the accepted seals to the miner.
+#[derive(Clone)]
+pub struct WorkerServer {
+    pow: Arc<dyn PowEngine>,
+    jobs: Arc<Mutex<Jobs<CurrentJob>>>,
+    connections: ConnectionLimit,
+    seal_sender: Sender<(u64, Seal)>,
+}
+
+impl WorkerServer {
+    pub fn start(
+        listen_addr: &str,
+        pow: Arc<dyn PowEngine>,
+    ) -> io::Result<(WorkerServer, Receiver<(u64, Seal)>)> {
+        let listener = TcpListener::bind(listen_addr)?;
+        info!(target: "miner", "Serving PoW workers on {}", listen_addr);
+        Self::listen(listener, pow)
+    }
+
+    fn listen(
+        listener: TcpListener,
+        pow: Arc<dyn PowEngine>,
+    ) -> io::Result<(WorkerServer, Receiver<(u64, Seal)>)> {
+        let (seal_sender, seal_receiver) = channel::unbounded();
+        let server = WorkerServer {
+            pow,
+            jobs: Arc::new(Mutex::new(Jobs::default())),
+            connections: ConnectionLimit::new(MAX_CONNECTIONS),
+            seal_sender,
+        };
+
+        let acceptor = server.clone();
+        thread::Builder::new()
+            .name("MinerWorkers".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => acceptor.serve(stream),
+                        Err(err) => warn!(target: "miner", "accept worker error: {:?}", err),
+                    }
+                }
+            })?;
+        Ok((server, seal_receiver))
+    }
+
+    fn serve(&self, stream: TcpStream) {
+        // dropping the stream closes the connection
+        let slot = match self.connections.acquire() {
+            Some(slot) => slot,
+            None => {
+                warn!(target: "miner", "too many workers, refuse {:?}", stream.peer_addr());
+                return;
+            }
+        };
+        let server = self.clone();
+        let spawned = thread::Builder::new()
+            .name("MinerWorker".to_string())
+            .spawn(move || {
+                let _slot = slot;
+                let peer = stream.peer_addr();
+                if let Err(err) = server.handle_connection(stream) {
+                    debug!(target: "miner", "worker {:?} disconnected: {:?}", peer, err);
+                }
+            });
+        if let Err(err) = spawned {
+            warn!(target: "miner", "spawn worker connection error: {:?}", err);
+        }
+    }
+
+    fn handle_connection(&self, stream: TcpStream) -> io::Result<()> {
+        // an idle or stuck worker would hold one of the connection slots forever
+        stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECS)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_SECS)))?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        while let Some(line) = read_line(&mut reader, MAX_LINE_BYTES)? {
+            let response = match serde_json::from_str(&line) {
+                Ok(request) => self.handle_request(request),
+                Err(err) => Response::Error {
+                    error: err.to_string(),
+                },
+            };
+            serde_json::to_writer(&mut writer, &response)?;
+            writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    fn handle_request(&self, request: Request) -> Response {
+        match request {
+            Request::GetJob => Response::Job {
+                job: self
+                    .jobs
+                    .lock()
+                    .current
+                    .as_ref()
+                    .map(|current| current.job.clone()),
+            },
+            Request::Submit {
+                job_id,
+                nonce,
+                proof,
+            } => {
+                let submitted = self.submit(job_id, Seal::new(nonce, proof));
+                Response::Submit {
+                    accepted: submitted.is_ok(),
+                    reason: submitted.err(),
+                }
+            }
+        }
+    }
+
+    fn submit(&self, job_id: u64, seal: Seal) -> Result<(), Rejection> {
+        let header = {
+            let jobs = self.jobs.lock();
+            match jobs.current {
+                Some(ref current) if current.job.job_id == job_id => current.header.clone(),
+                _ if jobs.is_stale(job_id) => return Err(Rejection::Stale),
+                _ => return Err(Rejection::UnknownJob),
+            }
+        };
+        if !self.pow.verify_header(&header.with_seal(seal.clone())) {
+            return Err(Rejection::Invalid);
+        }
+        self.seal_sender.send((job_id, seal));
+        Ok(())
+    }
+}
+
+impl JobServer for WorkerServer {
+    fn set_job(&self, header: &RawHeader) -> u64 {
+        let mut jobs = self.jobs.lock();
+        let (job_id, generation) = jobs.next_job();
+        jobs.current = Some(CurrentJob {
+            job: Job {
+                job_id,
+                generation,
+                number: header.number(),
+                pow_hash: header.pow_hash(),
+                target: difficulty_to_boundary(&header.difficulty()),
+            },
+            header: header.clone(),
+        });
+        job_id
+    }
+
+    fn cancel_jobs(&self) {
+        self.jobs.lock().cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_core::header::HeaderBuilder;
+    use ckb_pow::DummyPowEngine;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_jobs_generations() {
+        let mut jobs: Jobs<()> = Jobs::default();
+        assert_eq!(jobs.next_job(), (0, 0));
+        assert_eq!(jobs.next_job(), (1, 0));
+        jobs.current = Some(());
+        assert!(!jobs.is_stale(0));
+
+        // the tip changed, every job handed out so far is stale
+        assert_eq!(jobs.cancel(), 1);
+        assert!(jobs.current.is_none());
+        assert!(jobs.is_stale(0) && jobs.is_stale(1));
+        assert_eq!(jobs.next_job(), (2, 1));
+        assert!(!jobs.is_stale(2));
+        // ids handed out later are never stale either
+        assert!(!jobs.is_stale(3));
+    }
+
+    #[test]
+    fn test_submit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (server, seal_receiver) =
+            WorkerServer::listen(listener, Arc::new(DummyPowEngine::new())).unwrap();
+        let header = HeaderBuilder::default().number(1).build().into_raw();
+        let seal = Seal::new(1, vec![]);
+
+        let first = server.set_job(&header);
+        let second = server.set_job(&header);
+        // replaced on the same tip
+        assert_eq!(
+            server.submit(first, seal.clone()),
+            Err(Rejection::UnknownJob)
+        );
+        assert_eq!(
+            server.submit(second + 1, seal.clone()),
+            Err(Rejection::UnknownJob)
+        );
+        assert_eq!(server.submit(second, seal.clone()), Ok(()));
+        assert_eq!(seal_receiver.try_recv(), Some((second, seal.clone())));
+
+        server.cancel_jobs();
+        assert_eq!(server.submit(second, seal.clone()), Err(Rejection::Stale));
+        let third = server.set_job(&header);
+        assert_eq!(server.submit(third, seal), Ok(()));
+    }
+
+    #[test]
+    fn test_read_line() {
+        let mut reader = Cursor::new(b"a\nbcd\r\nef".to_vec());
+        assert_eq!(read_line(&mut reader, 3).unwrap(), Some("a".to_string()));
+        assert_eq!(read_line(&mut reader, 3).unwrap(), Some("bcd".to_string()));
+        assert_eq!(read_line(&mut reader, 3).unwrap(), Some("ef".to_string()));
+        assert_eq!(read_line(&mut reader, 3).unwrap(), None);
+
+        let mut reader = Cursor::new(b"abcd\n".to_vec());
+        assert_eq!(
+            read_line(&mut reader, 3).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let limit = ConnectionLimit::new(2);
+        let first = limit.acquire();
+        let second = limit.acquire();
+        assert!(first.is_some() && second.is_some());
+        assert!(limit.acquire().is_none());
+
+        drop(first);
+        assert!(limit.acquire().is_some());
+    }
+
+    #[test]
+    fn test_long_line_closes_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_server, _seal_receiver) =
+            WorkerServer::listen(listener, Arc::new(DummyPowEngine::new())).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        stream.write_all(b"{\"method\": \"get_job\"}\n").unwrap();
+        assert_eq!(
+            read_line(&mut reader, MAX_LINE_BYTES).unwrap(),
+            Some("{\"job\":null}".to_string())
+        );
+
+        stream.write_all(&vec![b' '; MAX_LINE_BYTES + 1]).unwrap();
+        assert_eq!(read_line(&mut reader, MAX_LINE_BYTES).unwrap(), None);
+    }
+}
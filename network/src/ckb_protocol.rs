@@ -1,6 +1,6 @@
 #![cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
 
-use super::{Error, ProtocolId};
+use super::{Error, NetworkMagic, ProtocolId};
 use bytes::BufMut;
 use bytes::{Buf, IntoBuf};
 use bytes::{Bytes, BytesMut};
@@ -25,6 +25,8 @@ pub struct CKBProtocol<T> {
     base_name: Bytes,
     // supported version, used to check protocol version
     supported_versions: Vec<ProtocolVersion>,
+    // prefix of every message, received messages without it are rejected
+    magic: NetworkMagic,
     protocol_handler: T,
 }
 
@@ -46,9 +48,15 @@ impl<T> CKBProtocol<T> {
                 versions.sort_by(|a, b| b.cmp(a));
                 versions.to_vec()
             },
+            magic: [0; 4],
             protocol_handler,
         }
     }
+
+    pub fn magic(mut self, magic: NetworkMagic) -> Self {
+        self.magic = magic;
+        self
+    }
     pub fn protocol_handler(&self) -> &T {
         &self.protocol_handler
     }
@@ -148,6 +156,7 @@ impl<T> CKBProtocol<T> {
         C: AsyncWrite + AsyncRead + Send + 'static,
     {
         let (msg_tx, msg_rx) = mpsc::unbounded();
+        let magic = self.magic;
 
         // Build the sink for outgoing network bytes, and the stream for
         // incoming instructions. `stream` implements `Stream<Item = Message>`.
@@ -177,7 +186,17 @@ impl<T> CKBProtocol<T> {
 
                 Some(stream.into_future().map_err(|(err, _)| err).and_then(
                     move |(message, stream)| match message {
-                        Some(Message::Recv(compressed_data)) => {
+                        Some(Message::Recv(mut compressed_data)) => {
+                            // checked before touching the payload, the peer is on another chain
+                            if compressed_data.len() < magic.len()
+                                || compressed_data[..magic.len()] != magic[..]
+                            {
+                                return future::Either::A(future::err(IoError::new(
+                                    IoErrorKind::InvalidData,
+                                    "network magic mismatch",
+                                )));
+                            }
+                            let _ = compressed_data.split_to(magic.len());
                             if compressed_data.is_empty() {
                                 debug!("receive a empty message, ignoring");
                                 let f = future::ok((None, (sink, stream, false)));
@@ -206,7 +225,9 @@ impl<T> CKBProtocol<T> {
                                 Ok(_) => {
                                     match compresser.into_inner() {
                                         Ok(compressed_data) => {
-                                            let compressed_data : Bytes = compressed_data.into_inner().into();
+                                            let mut framed_data = BytesMut::from(&magic[..]);
+                                            framed_data.extend_from_slice(&compressed_data.into_inner());
+                                            let compressed_data : Bytes = framed_data.freeze();
                                             let fut = sink
                                                 .send(compressed_data)
                                                 .map(move |sink| (None, (sink, stream, false)));
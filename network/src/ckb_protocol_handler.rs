@@ -1,7 +1,6 @@
 use super::errors::{Error, ErrorKind};
-use super::{Network, SessionInfo, Timer};
+use super::{Network, SessionInfo};
 use super::{PeerIndex, ProtocolId, TimerToken};
-use ckb_util::Mutex;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -12,6 +11,24 @@ pub enum Severity<'a> {
     Bad(&'a str),
 }
 
+impl<'a> Severity<'a> {
+    /// Reason code carried by the final disconnect message sent to the peer
+    pub fn code(&self) -> u32 {
+        match self {
+            Severity::Timeout => 1,
+            Severity::Useless(_) => 2,
+            Severity::Bad(_) => 3,
+        }
+    }
+
+    pub fn message(&self) -> &'a str {
+        match self {
+            Severity::Timeout => "timeout",
+            Severity::Useless(message) | Severity::Bad(message) => message,
+        }
+    }
+}
+
 pub trait CKBProtocolContext: Send {
     fn send(&self, peer_index: PeerIndex, data: Vec<u8>) -> Result<(), Error>;
     fn send_protocol(
@@ -24,7 +41,10 @@ pub trait CKBProtocolContext: Send {
     fn report_peer(&self, peer_index: PeerIndex, reason: Severity);
     fn ban_peer(&self, peer_index: PeerIndex, timeout: Duration);
     fn disconnect(&self, peer_index: PeerIndex);
+    /// Triggers `timer_triggered` with the token every `delay`, registering the token again
+    /// only changes its delay
     fn register_timer(&self, token: TimerToken, delay: Duration) -> Result<(), Error>;
+    fn cancel_timer(&self, token: TimerToken) -> Result<(), Error>;
     fn session_info(&self, peer_index: PeerIndex) -> Option<SessionInfo>;
     fn protocol_version(&self, peer_index: PeerIndex, protocol_id: ProtocolId) -> Option<u8>;
     fn protocol_id(&self) -> ProtocolId;
@@ -42,23 +62,13 @@ pub trait CKBProtocolContext: Send {
 pub(crate) struct DefaultCKBProtocolContext {
     pub protocol_id: ProtocolId,
     pub network: Arc<Network>,
-    pub timer_registry: Arc<Mutex<Option<Vec<Timer>>>>,
 }
 
 impl DefaultCKBProtocolContext {
     pub fn new(network: Arc<Network>, protocol_id: ProtocolId) -> Self {
-        Self::with_timer_registry(network, protocol_id, Arc::new(Mutex::new(None)))
-    }
-
-    pub fn with_timer_registry(
-        network: Arc<Network>,
-        protocol_id: ProtocolId,
-        timer_registry: Arc<Mutex<Option<Vec<Timer>>>>,
-    ) -> Self {
         DefaultCKBProtocolContext {
             network,
             protocol_id,
-            timer_registry,
         }
     }
 }
@@ -106,12 +116,13 @@ impl CKBProtocolContext for DefaultCKBProtocolContext {
             .ok_or(ErrorKind::BadProtocol)?
             .protocol_handler()
             .to_owned();
-        match *self.timer_registry.lock() {
-            Some(ref mut timer_registry) => {
-                timer_registry.push((handler, self.protocol_id, token, duration))
-            }
-            None => return Err(ErrorKind::TimerRegisterNotAvailable.into()),
-        }
+        self.network
+            .timer_scheduler
+            .register(self.protocol_id, token, duration, handler);
+        Ok(())
+    }
+    fn cancel_timer(&self, token: TimerToken) -> Result<(), Error> {
+        self.network.timer_scheduler.cancel(self.protocol_id, token);
         Ok(())
     }
     fn session_info(&self, peer_index: PeerIndex) -> Option<SessionInfo> {
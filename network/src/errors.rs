@@ -14,7 +14,6 @@ pub enum ErrorKind {
     InvalidNewPeer(String),
     ParseAddress,
     BadProtocol,
-    TimerRegisterNotAvailable,
     Io(IoError),
     Other(String),
 }
@@ -11,6 +11,8 @@ extern crate log;
 extern crate fnv;
 #[macro_use]
 extern crate serde_derive;
+extern crate bincode;
+extern crate ckb_db;
 extern crate ckb_util;
 
 mod ckb_protocol;
@@ -29,27 +31,27 @@ mod peers_registry;
 mod ping_service;
 mod protocol;
 mod protocol_service;
-mod timer_service;
+mod timer_scheduler;
 mod transport;
 
 pub use self::errors::{Error, ErrorKind};
 pub use self::network::{Network, PeerInfo, SessionInfo};
 pub use self::network_config::NetworkConfig;
 pub use self::network_service::NetworkService;
+pub use self::peer_store::Score;
 pub use ckb_protocol::{CKBProtocol, CKBProtocols};
 pub use ckb_protocol_handler::{CKBProtocolContext, CKBProtocolHandler, Severity};
 pub use libp2p::{core::Endpoint, multiaddr::AddrComponent, Multiaddr, PeerId};
 
 pub type TimerToken = usize;
 pub type ProtocolId = [u8; 3];
+/// Prefixes every protocol message, distinct for each chain
+pub type NetworkMagic = [u8; 4];
 
 use libp2p::secio;
 use rand::Rng;
-use std::sync::Arc;
-use std::time::Duration;
 
 const DEFAULT_OUTGOING_PEERS_RATIO: u32 = 3;
-pub(crate) type Timer = (Arc<CKBProtocolHandler>, ProtocolId, TimerToken, Duration);
 
 // used in CKBProtocolContext
 pub type PeerIndex = usize;
@@ -72,12 +74,12 @@ pub struct Config {
 }
 
 impl Config {
-    fn max_outgoing_peers(&self) -> u32 {
+    pub fn max_outgoing_peers(&self) -> u32 {
         self.max_peers / self
             .outgoing_peers_ratio
             .unwrap_or_else(|| DEFAULT_OUTGOING_PEERS_RATIO)
     }
-    fn max_incoming_peers(&self) -> u32 {
+    pub fn max_incoming_peers(&self) -> u32 {
         self.max_peers - self.max_outgoing_peers()
     }
 }
@@ -99,7 +101,8 @@ impl From<Config> for NetworkConfig {
         }
         if let Some(dir_path) = config.config_dir_path {
             cfg.config_dir_path = Some(dir_path.clone());
-            cfg.secret_key_path = Some(format!("{}/secret_key", dir_path))
+            cfg.secret_key_path = Some(format!("{}/secret_key", dir_path));
+            cfg.peer_store_path = Some(format!("{}/peer_store", dir_path));
         }
         cfg.client_version = "ckb network".to_string();
         match cfg.read_secret_key() {
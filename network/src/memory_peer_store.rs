@@ -1,21 +1,22 @@
 use super::PeerId;
 use fnv::FnvHashMap;
 use libp2p::core::Multiaddr;
-use peer_store::{Behaviour, PeerStore, Status};
-use std::time::Instant;
+use peer_store::{Behaviour, PeerStore, Score, Status};
+use std::time::{Duration, Instant};
 
 // peer_id -> addresses,
 // sort by score
 // addr -> peer_id
 // last report or updated_time
-const INITIALIZED_SCORE: u32 = 0;
+const INITIALIZED_SCORE: Score = 0;
 
 #[derive(Debug)]
 struct PeerInfo {
     addresses: Vec<Multiaddr>,
     last_updated_at: Instant,
-    score: u32,
+    score: Score,
     status: Status,
+    banned_until: Option<Instant>,
 }
 
 // NOTICE MemoryPeerStore is used for test environment only!!!
@@ -48,6 +49,7 @@ impl MemoryPeerStore {
             last_updated_at: now,
             score: INITIALIZED_SCORE,
             status: Status::Unknown,
+            banned_until: None,
         };
         self.peers.insert(peer_id, peer);
         true
@@ -75,10 +77,32 @@ impl PeerStore for MemoryPeerStore {
         self.add_peer(peer_id.to_owned(), addresses);
         Ok(len)
     }
-    // TODO
-    fn report(&mut self, _peer_id: &PeerId, _behaviour: Behaviour) {}
-    // TODO
-    fn report_address(&mut self, _address: &Multiaddr, _behaviour: Behaviour) {}
+    fn report(&mut self, peer_id: &PeerId, behaviour: Behaviour) {
+        // an inbound peer may not be known yet
+        if !self.peers.contains_key(peer_id) {
+            self.add_peer(peer_id.to_owned(), Vec::new());
+        }
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.last_updated_at = Instant::now();
+            peer.score = peer.score.saturating_add(behaviour.score());
+        }
+    }
+
+    fn peer_score(&self, peer_id: &PeerId) -> Score {
+        self.peers
+            .get(peer_id)
+            .map_or(INITIALIZED_SCORE, |peer| peer.score)
+    }
+
+    fn report_address(&mut self, address: &Multiaddr, behaviour: Behaviour) {
+        for peer in self
+            .peers
+            .values_mut()
+            .filter(|peer| peer.addresses.contains(address))
+        {
+            peer.score = peer.score.saturating_add(behaviour.score());
+        }
+    }
     // TODO
     fn report_status(&mut self, peer_id: &PeerId, status: Status) {
         if let Some(peer) = self.peers.get_mut(&peer_id) {
@@ -95,6 +119,22 @@ impl PeerStore for MemoryPeerStore {
         }
     }
 
+    fn ban(&mut self, peer_id: &PeerId, timeout: Duration) {
+        if !self.peers.contains_key(peer_id) {
+            self.add_peer(peer_id.to_owned(), Vec::new());
+        }
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.banned_until = Some(Instant::now() + timeout);
+        }
+    }
+
+    fn is_banned(&self, peer_id: &PeerId) -> bool {
+        self.peers
+            .get(peer_id)
+            .and_then(|peer| peer.banned_until)
+            .map_or(false, |banned_until| banned_until > Instant::now())
+    }
+
     fn bootnodes<'a>(&'a self) -> Box<Iterator<Item = (&'a PeerId, &'a Multiaddr)> + 'a> {
         let mut bootnodes = self
             .peers_to_attempt()
@@ -120,8 +160,14 @@ impl PeerStore for MemoryPeerStore {
             "try fetch attempt peers from {:?}",
             self.peers.iter().collect::<Vec<_>>()
         );
+        let now = Instant::now();
         let peers = self.peers.iter().filter_map(move |(peer_id, peer_info)| {
-            if peer_info.status == Status::Connected || peer_info.addresses.is_empty() {
+            if peer_info.status == Status::Connected
+                || peer_info.addresses.is_empty()
+                || peer_info
+                    .banned_until
+                    .map_or(false, |banned_until| banned_until > now)
+            {
                 None
             } else {
                 Some((peer_id, &peer_info.addresses[0]))
@@ -154,3 +200,42 @@ impl PeerStore for MemoryPeerStore {
         self.reserved_nodes.remove(peer_id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use random_peer_id;
+
+    #[test]
+    fn test_report() {
+        let mut peer_store = MemoryPeerStore::new(vec![]);
+        let peer_id = random_peer_id().unwrap();
+        assert_eq!(peer_store.peer_score(&peer_id), INITIALIZED_SCORE);
+
+        // an unknown peer is added with the initial score
+        peer_store.report(&peer_id, Behaviour::Connect);
+        peer_store.report(&peer_id, Behaviour::Ping);
+        assert_eq!(peer_store.peer_score(&peer_id), INITIALIZED_SCORE + 2);
+        peer_store.report(&peer_id, Behaviour::FailedToPing);
+        assert_eq!(peer_store.peer_score(&peer_id), INITIALIZED_SCORE - 8);
+
+        peer_store.peers.get_mut(&peer_id).unwrap().score = Score::min_value();
+        peer_store.report(&peer_id, Behaviour::UnexpectedDisconnect);
+        assert_eq!(peer_store.peer_score(&peer_id), Score::min_value());
+    }
+
+    #[test]
+    fn test_report_address() {
+        let mut peer_store = MemoryPeerStore::new(vec![]);
+        let peer_id = random_peer_id().unwrap();
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        peer_store
+            .add_discovered_addresses(&peer_id, vec![address.clone()])
+            .unwrap();
+        peer_store.report_address(&address, Behaviour::FailedToConnect);
+        assert_eq!(
+            peer_store.peer_score(&peer_id),
+            INITIALIZED_SCORE + Behaviour::FailedToConnect.score()
+        );
+    }
+}
@@ -3,12 +3,14 @@
 use super::NetworkConfig;
 use super::{Error, ErrorKind, PeerIndex, ProtocolId};
 use bytes::Bytes;
+use ckb_db::diskdb::RocksDB;
 use ckb_protocol::{CKBProtocol, CKBProtocols};
 use ckb_protocol_handler::CKBProtocolHandler;
 use ckb_protocol_handler::DefaultCKBProtocolContext;
 use ckb_service::CKBService;
 use ckb_util::{Mutex, RwLock};
 use discovery_service::{DiscoveryQueryService, DiscoveryService, KadManage};
+use fnv::FnvHashSet;
 use futures::future::{self, select_all, Future};
 use futures::sync::mpsc::UnboundedSender;
 use futures::sync::oneshot;
@@ -20,7 +22,8 @@ use libp2p::core::{PublicKey, SwarmController};
 use libp2p::{self, identify, kad, ping, secio, Transport, TransportTimeout};
 use memory_peer_store::MemoryPeerStore;
 use outgoing_service::OutgoingService;
-use peer_store::{Behaviour, PeerStore};
+use peer_store::db_peer_store::{self, DBPeerStore};
+use peer_store::{Behaviour, PeerStore, Score};
 use peers_registry::{ConnectionStatus, PeerConnection, PeerIdentifyInfo, PeersRegistry};
 use ping_service::PingService;
 use protocol::Protocol;
@@ -31,7 +34,7 @@ use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
 use std::usize;
-use timer_service::TimerService;
+use timer_scheduler::TimerScheduler;
 use tokio::io::{AsyncRead, AsyncWrite};
 use transport::{new_transport, TransportOutput};
 
@@ -50,6 +53,10 @@ pub struct PeerInfo {
     pub peer_id: PeerId,
     pub endpoint_role: Endpoint,
     pub last_ping_time: Option<Instant>,
+    /// Round trip time of the last answered ping, `None` until the peer answers one
+    pub ping: Option<Duration>,
+    /// Score of the peer in the peer store, it grows with the peer's good behaviours
+    pub score: Score,
     pub remote_addresses: Vec<Multiaddr>,
     pub identify_info: Option<PeerIdentifyInfo>,
 }
@@ -74,6 +81,7 @@ pub struct Network {
     pub(crate) ckb_protocols: CKBProtocols<Arc<CKBProtocolHandler>>,
     local_private_key: secio::SecioKeyPair,
     local_peer_id: PeerId,
+    pub(crate) timer_scheduler: TimerScheduler,
 }
 
 impl Network {
@@ -85,6 +93,12 @@ impl Network {
         &self.local_peer_id
     }
 
+    pub(crate) fn set_max_peers(&self, max_incoming: u32, max_outgoing: u32) {
+        self.peers_registry
+            .write()
+            .set_max_peers(max_incoming, max_outgoing);
+    }
+
     pub(crate) fn add_peer(&self, peer_id: PeerId, peer: PeerConnection) {
         let mut peers_registry = self.peers_registry.write();
         peers_registry.add_peer(peer_id, peer);
@@ -109,6 +123,11 @@ impl Network {
         peers_registry.connection_status()
     }
 
+    pub(crate) fn outbound_ip_buckets(&self) -> FnvHashSet<Vec<u8>> {
+        let peers_registry = self.peers_registry.read();
+        peers_registry.outbound_ip_buckets()
+    }
+
     pub(crate) fn get_peer_identify_info(&self, peer_id: &PeerId) -> Option<PeerIdentifyInfo> {
         let peers_registry = self.peers_registry.read();
         peers_registry
@@ -131,6 +150,13 @@ impl Network {
         }
     }
 
+    pub(crate) fn set_peer_ping(&self, peer_id: &PeerId, ping: Duration) {
+        let mut peers_registry = self.peers_registry.write();
+        if let Some(peer) = peers_registry.get_mut(peer_id) {
+            peer.ping = Some(ping);
+        }
+    }
+
     pub(crate) fn get_peer_pinger(&self, peer_id: &PeerId) -> Option<UniqueConnec<ping::Pinger>> {
         let peers_registry = self.peers_registry.read();
         peers_registry
@@ -164,8 +190,7 @@ impl Network {
 
     #[inline]
     pub(crate) fn ban_peer(&self, peer_id: PeerId, timeout: Duration) {
-        let mut peers_registry = self.peers_registry.write();
-        peers_registry.ban_peer(peer_id, timeout);
+        self.peers_registry.write().ban_peer(peer_id, timeout);
     }
 
     #[inline]
@@ -188,6 +213,14 @@ impl Network {
             .map(|addr| self.to_external_url(addr))
     }
 
+    pub fn external_urls(&self) -> Vec<String> {
+        self.original_listened_addresses
+            .read()
+            .iter()
+            .map(|addr| self.to_external_url(addr))
+            .collect()
+    }
+
     fn to_external_url(&self, addr: &Multiaddr) -> String {
         format!(
             "{}/p2p/{}",
@@ -268,6 +301,8 @@ impl Network {
         }
     }
     pub fn session_info(&self, peer_id: &PeerId, protocol_id: ProtocolId) -> Option<SessionInfo> {
+        // the ping service locks the peer store first, never hold it along the registry
+        let score = self.peer_store.read().peer_score(peer_id);
         let peers_registry = self.peers_registry.read();
         match peers_registry.get(peer_id) {
             Some(peer) => {
@@ -283,6 +318,8 @@ impl Network {
                         peer_id: peer_id.to_owned(),
                         endpoint_role: peer.endpoint_role,
                         last_ping_time: peer.last_ping_time,
+                        ping: peer.ping,
+                        score,
                         remote_addresses: peer.remote_addresses.clone(),
                         identify_info: peer.identify_info.clone(),
                     },
@@ -436,9 +473,15 @@ impl Network {
             None => return Err(ErrorKind::Other("secret_key not set".to_owned()).into()),
         };
         let listened_addresses = config.public_addresses.clone();
-        let peer_store: Arc<RwLock<Box<PeerStore>>> = Arc::new(RwLock::new(Box::new(
-            MemoryPeerStore::new(config.bootnodes()?),
-        ) as Box<_>));
+        let bootnodes = config.bootnodes()?;
+        let peer_store: Box<PeerStore> = match config.peer_store_path {
+            Some(ref path) => Box::new(DBPeerStore::new(
+                RocksDB::open(path, db_peer_store::COLUMNS),
+                bootnodes,
+            )),
+            None => Box::new(MemoryPeerStore::new(bootnodes)),
+        };
+        let peer_store = Arc::new(RwLock::new(peer_store));
         let reserved_peers = config.reserved_peers()?;
         {
             let mut peer_store = peer_store.write();
@@ -458,9 +501,15 @@ impl Network {
             peer_store: Arc::clone(&peer_store),
             listened_addresses: RwLock::new(listened_addresses),
             original_listened_addresses: RwLock::new(Vec::new()),
-            ckb_protocols: CKBProtocols(ckb_protocols),
+            ckb_protocols: CKBProtocols(
+                ckb_protocols
+                    .into_iter()
+                    .map(|protocol| protocol.magic(config.magic))
+                    .collect(),
+            ),
             local_private_key: local_private_key.clone(),
             local_peer_id: local_private_key.to_peer_id(),
+            timer_scheduler: TimerScheduler::default(),
         });
         Ok(network)
     }
@@ -509,16 +558,15 @@ impl Network {
         };
 
         // initialize ckb_protocols
-        let timer_registry = Arc::new(Mutex::new(Some(Vec::new())));
         for protocol in &network.ckb_protocols.0 {
-            protocol.protocol_handler().initialize(Box::new(
-                DefaultCKBProtocolContext::with_timer_registry(
+            protocol
+                .protocol_handler()
+                .initialize(Box::new(DefaultCKBProtocolContext::new(
                     Arc::clone(&network),
                     protocol.id(),
-                    Arc::clone(&timer_registry),
-                ),
-            ));
+                )));
         }
+        network.timer_scheduler.start(Arc::downgrade(&network));
         let kad_upgrade = kad::KadConnecConfig::new();
         let kad_manage = Arc::new(Mutex::new(KadManage::new(
             Arc::clone(&network),
@@ -560,9 +608,6 @@ impl Network {
         let ckb_protocol_service = Arc::new(CKBService {
             kad_system: Arc::clone(&kad_system),
         });
-        let timer_service = Arc::new(TimerService {
-            timer_registry: Arc::clone(&timer_registry),
-        });
         let outgoing_service = Arc::new(OutgoingService {
             outgoing_interval: config.outgoing_interval,
             timeout: config.outgoing_timeout,
@@ -711,11 +756,6 @@ impl Network {
                 swarm_controller.clone(),
                 basic_transport.clone(),
             ),
-            timer_service.start_protocol(
-                Arc::clone(&network),
-                swarm_controller.clone(),
-                basic_transport.clone(),
-            ),
             outgoing_service.start_protocol(
                 Arc::clone(&network),
                 swarm_controller.clone(),
@@ -1,4 +1,4 @@
-use super::PeerId;
+use super::{NetworkMagic, PeerId};
 use super::{Error, ErrorKind};
 use bytes::Bytes;
 use libp2p::core::{AddrComponent, Multiaddr};
@@ -27,6 +27,8 @@ pub struct NetworkConfig {
     pub reserved_peers: Vec<String>,
     pub secret_key: Option<Bytes>,
     pub secret_key_path: Option<String>,
+    // the known peers are saved there, the peers are only kept in memory without it
+    pub peer_store_path: Option<String>,
     // peer_store path
     pub config_dir_path: Option<String>,
     pub bootnodes: Vec<String>,
@@ -39,6 +41,8 @@ pub struct NetworkConfig {
     pub identify_interval: Duration,
     pub outgoing_timeout: Duration,
     pub outgoing_interval: Duration,
+    // messages not starting with it are from a node of another chain, the connection is dropped
+    pub magic: NetworkMagic,
 }
 
 impl NetworkConfig {
@@ -159,6 +163,7 @@ impl Default for NetworkConfig {
             secret_key_path: None,
             bootnodes: vec![],
             config_dir_path: None,
+            peer_store_path: None,
             // protocol services config
             ping_interval: Duration::from_secs(30),
             ping_timeout: Duration::from_secs(30),
@@ -169,6 +174,7 @@ impl Default for NetworkConfig {
             identify_interval: Duration::from_secs(15),
             outgoing_timeout: Duration::from_secs(30),
             outgoing_interval: Duration::from_secs(15),
+            magic: [0; 4],
         }
     }
 }
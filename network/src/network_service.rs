@@ -34,12 +34,32 @@ impl NetworkService {
         self.network.external_url()
     }
 
+    #[inline]
+    pub fn external_urls(&self) -> Vec<String> {
+        self.network.external_urls()
+    }
+
+    #[inline]
+    pub fn node_id(&self) -> String {
+        self.network.local_peer_id().to_base58()
+    }
+
     #[allow(dead_code)]
     #[inline]
     pub(crate) fn peer_store<'a>(&'a self) -> &'a RwLock<Box<PeerStore>> {
         &self.network.peer_store()
     }
 
+    #[inline]
+    pub fn connected_peers_count(&self) -> usize {
+        self.network.peers_indexes().len()
+    }
+
+    /// Applies new peer limits to a running network
+    pub fn set_max_peers(&self, max_incoming: u32, max_outgoing: u32) {
+        self.network.set_max_peers(max_incoming, max_outgoing);
+    }
+
     #[inline]
     pub fn add_peer(&self, peer_id: PeerId, peer: PeerConnection) {
         self.network.add_peer(peer_id, peer);
@@ -102,6 +122,7 @@ impl NetworkService {
     // thread_handle to achieve that.
     fn shutdown(&mut self) -> Result<(), IoError> {
         debug!(target: "network", "shutdown network service self: {:?}", self.external_url());
+        self.network.timer_scheduler.stop();
         if let Some(close_tx) = self.close_tx.take() {
             let _ = close_tx
                 .send(())
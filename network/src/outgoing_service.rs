@@ -1,10 +1,12 @@
 use super::Network;
 use super::PeerId;
+use fnv::FnvHashSet;
 use futures::future::{self, lazy, Future};
 use futures::Stream;
 use libp2p::core::Multiaddr;
 use libp2p::core::MuxedTransport;
 use libp2p::core::SwarmController;
+use peers_registry::ip_bucket;
 use protocol::Protocol;
 use protocol_service::ProtocolService;
 use std::boxed::Box;
@@ -81,21 +83,19 @@ impl<T: Send + 'static> ProtocolService<T> for OutgoingService {
             let network = Arc::clone(&network);
             move |_| {
                 let connection_status = network.connection_status();
-                let new_outgoing = (connection_status.max_outgoing
-                    - connection_status.unreserved_outgoing)
+                // the limit may have been lowered below the connected peers
+                let new_outgoing = connection_status
+                    .max_outgoing
+                    .saturating_sub(connection_status.unreserved_outgoing)
                     as usize;
                 if new_outgoing > 0 {
-                    let peer_store = network.peer_store().read();
-                    for (peer_id, addr) in peer_store
-                        .peers_to_attempt()
-                        .take(new_outgoing)
-                        .filter_map(|(peer_id, addr)| {
-                            if network.local_peer_id() != peer_id {
-                                Some((peer_id.clone(), addr.clone()))
-                            } else {
-                                None
-                            }
-                        }) {
+                    let peers = outgoing_peers(
+                        network.peer_store().read().peers_to_attempt(),
+                        network.local_peer_id(),
+                        network.outbound_ip_buckets(),
+                        new_outgoing,
+                    );
+                    for (peer_id, addr) in peers {
                         network.dial_to_peer(
                             transport.clone(),
                             &addr,
@@ -115,3 +115,71 @@ impl<T: Send + 'static> ProtocolService<T> for OutgoingService {
         Box::new(outgoing_future) as Box<Future<Item = _, Error = _> + Send>
     }
 }
+
+// The first `n` candidates to dial, at most one outbound peer per IP bucket so the outbound
+// peers can't all be hosts of a single operator
+fn outgoing_peers<'a, I>(
+    candidates: I,
+    local_peer_id: &PeerId,
+    mut ip_buckets: FnvHashSet<Vec<u8>>,
+    n: usize,
+) -> Vec<(PeerId, Multiaddr)>
+where
+    I: Iterator<Item = (&'a PeerId, &'a Multiaddr)>,
+{
+    candidates
+        .filter(|(peer_id, addr)| {
+            local_peer_id != *peer_id
+                && ip_bucket(addr).map_or(true, |bucket| ip_buckets.insert(bucket))
+        }).take(n)
+        .map(|(peer_id, addr)| (peer_id.clone(), addr.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::core::AddrComponent;
+    use libp2p::multiaddr::ToMultiaddr;
+    use random_peer_id;
+    use std::iter;
+
+    #[test]
+    fn test_outgoing_peers() {
+        let local_peer_id = random_peer_id().unwrap();
+        let candidates: Vec<(PeerId, Multiaddr)> = vec![
+            (local_peer_id.clone(), "/ip4/10.3.0.1/tcp/8115"),
+            // the bucket of a connected outbound peer
+            (random_peer_id().unwrap(), "/ip4/10.1.0.1/tcp/8115"),
+            (random_peer_id().unwrap(), "/ip4/10.2.0.1/tcp/8115"),
+            (random_peer_id().unwrap(), "/ip4/10.2.0.2/tcp/8115"),
+            (random_peer_id().unwrap(), "/ip4/10.4.0.1/tcp/8115"),
+        ].into_iter()
+        .map(|(peer_id, addr)| (peer_id, addr.to_multiaddr().unwrap()))
+        .collect();
+        let mut ip_buckets = FnvHashSet::default();
+        ip_buckets.insert(vec![10, 1]);
+
+        let peers = outgoing_peers(
+            candidates.iter().map(|(peer_id, addr)| (peer_id, addr)),
+            &local_peer_id,
+            ip_buckets.clone(),
+            2,
+        );
+        assert_eq!(peers, vec![candidates[2].clone(), candidates[4].clone()]);
+
+        // the addresses without IP aren't bucketed
+        let addr: Multiaddr = iter::once(AddrComponent::TCP(8115)).collect();
+        let candidates = vec![
+            (random_peer_id().unwrap(), addr.clone()),
+            (random_peer_id().unwrap(), addr),
+        ];
+        let peers = outgoing_peers(
+            candidates.iter().map(|(peer_id, addr)| (peer_id, addr)),
+            &local_peer_id,
+            ip_buckets,
+            2,
+        );
+        assert_eq!(peers, candidates);
+    }
+}
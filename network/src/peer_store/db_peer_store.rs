@@ -0,0 +1,442 @@
+//! A peer store persisted in a key-value store, so a restarted node dials the peers it knew
+//! instead of starting over from the bootnodes.
+//!
+//! Every peer is a record keyed by its base58 id. The records are loaded when the store is
+//! opened and written again on every change, the connection status only lives as long as the
+//! process.
+
+use super::{Behaviour, PeerStore, Score, Status};
+use bincode::{deserialize, serialize};
+use ckb_db::batch::Col;
+use ckb_db::kvdb::KeyValueDB;
+use fnv::FnvHashMap;
+use libp2p::core::Multiaddr;
+use libp2p::multiaddr::ToMultiaddr;
+use peers_registry::{parse_peer_id, unix_time_ms};
+use rand::{thread_rng, Rng};
+use std::cmp;
+use std::str;
+use std::time::Duration;
+use PeerId;
+
+pub const COLUMNS: u32 = 1;
+const COLUMN_PEERS: Col = Some(0);
+
+const INITIALIZED_SCORE: Score = 0;
+/// The scores at or below it weigh the same, a peer is never out of the dialing for good
+const MIN_WEIGHT_SCORE: Score = -20;
+/// Hours after which a peer not seen since is half as likely to be dialed
+const FRESHNESS_HALF_LIFE: u64 = 24;
+/// Peers not seen for this many milliseconds are forgotten when the store is opened
+const PEER_EXPIRY: u64 = 30 * 24 * 60 * 60 * 1000;
+
+/// A peer as stored, the times are unix times in milliseconds and 0 when they never happened
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StoredPeer {
+    addresses: Vec<String>,
+    score: Score,
+    last_connected_at: u64,
+    last_seen_at: u64,
+    banned_until: u64,
+}
+
+#[derive(Debug)]
+struct PeerRecord {
+    addresses: Vec<Multiaddr>,
+    score: Score,
+    last_connected_at: u64,
+    last_seen_at: u64,
+    banned_until: u64,
+    status: Status,
+}
+
+impl PeerRecord {
+    fn new(addresses: Vec<Multiaddr>, now: u64) -> Self {
+        PeerRecord {
+            addresses,
+            score: INITIALIZED_SCORE,
+            last_connected_at: 0,
+            last_seen_at: now,
+            banned_until: 0,
+            status: Status::Unknown,
+        }
+    }
+
+    fn from_stored(stored: StoredPeer) -> Self {
+        PeerRecord {
+            addresses: stored
+                .addresses
+                .iter()
+                .filter_map(|address| address.to_multiaddr().ok())
+                .collect(),
+            score: stored.score,
+            last_connected_at: stored.last_connected_at,
+            last_seen_at: stored.last_seen_at,
+            banned_until: stored.banned_until,
+            status: Status::Unknown,
+        }
+    }
+
+    fn to_stored(&self) -> StoredPeer {
+        StoredPeer {
+            addresses: self.addresses.iter().map(|addr| addr.to_string()).collect(),
+            score: self.score,
+            last_connected_at: self.last_connected_at,
+            last_seen_at: self.last_seen_at,
+            banned_until: self.banned_until,
+        }
+    }
+
+    /// How likely the dialer picks the peer, the reliable peers seen lately weigh the most
+    fn weight(&self, now: u64) -> u64 {
+        let reliability = (self.score.max(MIN_WEIGHT_SCORE) - MIN_WEIGHT_SCORE + 1) as u64;
+        let seen_at = self.last_seen_at.max(self.last_connected_at);
+        let hours = now.saturating_sub(seen_at) / (60 * 60 * 1000);
+        // a peer connected to once was reachable, unlike a merely discovered address
+        let connected = if self.last_connected_at > 0 { 2 } else { 1 };
+        cmp::max(
+            1,
+            connected * reliability * FRESHNESS_HALF_LIFE * 1000 / (FRESHNESS_HALF_LIFE + hours),
+        )
+    }
+}
+
+pub struct DBPeerStore<T: KeyValueDB> {
+    db: T,
+    bootnodes: Vec<(PeerId, Multiaddr)>,
+    peers: FnvHashMap<PeerId, PeerRecord>,
+    reserved_nodes: FnvHashMap<PeerId, Vec<Multiaddr>>,
+}
+
+impl<T: KeyValueDB> DBPeerStore<T> {
+    /// Loads the peers stored in `db`, the peers not seen for a month are deleted
+    pub fn new(db: T, bootnodes: Vec<(PeerId, Multiaddr)>) -> Self {
+        let now = unix_time_ms();
+        let mut peers = FnvHashMap::default();
+        let mut expired = db.batch();
+        match db.iter(COLUMN_PEERS) {
+            Ok(iter) => {
+                for (key, value) in iter {
+                    // the records which can't be read anymore are deleted along the expired ones
+                    let peer = str::from_utf8(&key)
+                        .ok()
+                        .and_then(parse_peer_id)
+                        .and_then(|peer_id| {
+                            deserialize::<StoredPeer>(&value)
+                                .ok()
+                                .map(|stored| (peer_id, stored))
+                        }).filter(|(_, stored)| {
+                            now.saturating_sub(stored.last_seen_at) < PEER_EXPIRY
+                        });
+                    match peer {
+                        Some((peer_id, stored)) => {
+                            peers.insert(peer_id, PeerRecord::from_stored(stored));
+                        }
+                        None => expired.delete(COLUMN_PEERS, key),
+                    }
+                }
+            }
+            Err(err) => warn!(target: "network", "load stored peers failed: {:?}", err),
+        }
+        if let Err(err) = db.write(expired) {
+            warn!(target: "network", "delete expired peers failed: {:?}", err);
+        }
+
+        for (peer_id, addr) in bootnodes.clone() {
+            peers
+                .entry(peer_id)
+                .or_insert_with(|| PeerRecord::new(vec![addr], now));
+        }
+        DBPeerStore {
+            db,
+            bootnodes,
+            peers,
+            reserved_nodes: Default::default(),
+        }
+    }
+
+    /// Unix time in milliseconds of the last connection to the peer, `None` if never connected
+    pub fn last_connected_at(&self, peer_id: &PeerId) -> Option<u64> {
+        self.peers
+            .get(peer_id)
+            .map(|peer| peer.last_connected_at)
+            .filter(|last_connected_at| *last_connected_at > 0)
+    }
+
+    fn peer_mut(&mut self, peer_id: &PeerId) -> &mut PeerRecord {
+        self.peers
+            .entry(peer_id.to_owned())
+            .or_insert_with(|| PeerRecord::new(Vec::new(), unix_time_ms()))
+    }
+
+    fn save(&self, peer_id: &PeerId) {
+        let peer = match self.peers.get(peer_id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let value = serialize(&peer.to_stored()).expect("serialize stored peer");
+        let mut batch = self.db.batch();
+        batch.insert(COLUMN_PEERS, peer_id.to_base58().into_bytes(), value);
+        if let Err(err) = self.db.write(batch) {
+            warn!(target: "network", "save peer {:?} failed: {:?}", peer_id, err);
+        }
+    }
+}
+
+impl<T: KeyValueDB> PeerStore for DBPeerStore<T> {
+    fn add_discovered_addresses(
+        &mut self,
+        peer_id: &PeerId,
+        addresses: Vec<Multiaddr>,
+    ) -> Result<usize, ()> {
+        let added = {
+            let peer = self.peer_mut(peer_id);
+            let origin_addrs_len = peer.addresses.len();
+            for addr in addresses {
+                if !peer.addresses.contains(&addr) {
+                    peer.addresses.push(addr);
+                }
+            }
+            peer.last_seen_at = unix_time_ms();
+            peer.addresses.len() - origin_addrs_len
+        };
+        self.save(peer_id);
+        Ok(added)
+    }
+
+    fn report(&mut self, peer_id: &PeerId, behaviour: Behaviour) {
+        {
+            let peer = self.peer_mut(peer_id);
+            peer.last_seen_at = unix_time_ms();
+            peer.score = peer.score.saturating_add(behaviour.score());
+        }
+        self.save(peer_id);
+    }
+
+    fn peer_score(&self, peer_id: &PeerId) -> Score {
+        self.peers
+            .get(peer_id)
+            .map_or(INITIALIZED_SCORE, |peer| peer.score)
+    }
+
+    /// Scores the peers listening on the address, a failed dial doesn't count as seen
+    fn report_address(&mut self, address: &Multiaddr, behaviour: Behaviour) {
+        let peer_ids = self
+            .peers
+            .iter_mut()
+            .filter(|(_, peer)| peer.addresses.contains(address))
+            .map(|(peer_id, peer)| {
+                peer.score = peer.score.saturating_add(behaviour.score());
+                peer_id.to_owned()
+            }).collect::<Vec<_>>();
+        for peer_id in peer_ids {
+            self.save(&peer_id);
+        }
+    }
+
+    fn report_status(&mut self, peer_id: &PeerId, status: Status) {
+        let connected = match self.peers.get_mut(peer_id) {
+            Some(peer) => {
+                peer.status = status;
+                if status == Status::Connected {
+                    peer.last_connected_at = unix_time_ms();
+                }
+                status == Status::Connected
+            }
+            None => false,
+        };
+        if connected {
+            self.save(peer_id);
+        }
+    }
+
+    fn peer_status(&self, peer_id: &PeerId) -> Status {
+        match self.peers.get(peer_id) {
+            Some(peer) => peer.status,
+            None => Status::Unknown,
+        }
+    }
+
+    fn ban(&mut self, peer_id: &PeerId, timeout: Duration) {
+        let now = unix_time_ms();
+        self.peer_mut(peer_id).banned_until =
+            now + timeout.as_secs() * 1000 + u64::from(timeout.subsec_millis());
+        self.save(peer_id);
+    }
+
+    fn is_banned(&self, peer_id: &PeerId) -> bool {
+        self.peers
+            .get(peer_id)
+            .map_or(false, |peer| peer.banned_until > unix_time_ms())
+    }
+
+    fn bootnodes<'a>(&'a self) -> Box<Iterator<Item = (&'a PeerId, &'a Multiaddr)> + 'a> {
+        let mut bootnodes = self
+            .peers_to_attempt()
+            .chain(self.bootnodes.iter().map(|(peer_id, addr)| (peer_id, addr)))
+            .collect::<Vec<_>>();
+        bootnodes.dedup();
+        Box::new(bootnodes.into_iter()) as Box<_>
+    }
+
+    fn peer_addrs<'a>(
+        &'a self,
+        peer_id: &'a PeerId,
+    ) -> Option<Box<Iterator<Item = &'a Multiaddr> + 'a>> {
+        let iter = match self.peers.get(peer_id) {
+            Some(peer) => peer.addresses.iter(),
+            None => return None,
+        };
+        Some(Box::new(iter) as Box<_>)
+    }
+
+    /// The peers in a random order, weighted by their scores and how lately they were seen
+    fn peers_to_attempt<'a>(&'a self) -> Box<Iterator<Item = (&'a PeerId, &'a Multiaddr)> + 'a> {
+        let now = unix_time_ms();
+        let mut rng = thread_rng();
+        // weighted sampling without replacement, each peer is keyed by u ^ (1 / weight)
+        let mut peers = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| {
+                peer.status != Status::Connected
+                    && !peer.addresses.is_empty()
+                    && peer.banned_until <= now
+            }).map(|(peer_id, peer)| {
+                let key = rng.gen::<f64>().powf(1.0 / peer.weight(now) as f64);
+                (key, peer_id, &peer.addresses[0])
+            }).collect::<Vec<_>>();
+        peers.sort_by(|a, b| b.0.partial_cmp(&a.0).expect("keys are never NaN"));
+        let iter = peers
+            .into_iter()
+            .map(|(_, peer_id, address)| (peer_id, address));
+        Box::new(iter) as Box<_>
+    }
+
+    fn reserved_nodes<'a>(&'a self) -> Box<Iterator<Item = (&'a PeerId, &'a Multiaddr)> + 'a> {
+        let iter = self
+            .reserved_nodes
+            .iter()
+            .filter_map(|(peer_id, addresses)| addresses.get(0).map(|address| (peer_id, address)));
+        Box::new(iter) as Box<_>
+    }
+
+    fn is_reserved(&self, peer_id: &PeerId) -> bool {
+        self.reserved_nodes.contains_key(peer_id)
+    }
+
+    fn add_reserved_node(
+        &mut self,
+        peer_id: PeerId,
+        addresses: Vec<Multiaddr>,
+    ) -> Option<Vec<Multiaddr>> {
+        self.reserved_nodes.insert(peer_id, addresses)
+    }
+
+    fn remove_reserved_node(&mut self, peer_id: &PeerId) -> Option<Vec<Multiaddr>> {
+        self.reserved_nodes.remove(peer_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_db::memorydb::MemoryKeyValueDB;
+    use random_peer_id;
+
+    const HOUR: u64 = 60 * 60 * 1000;
+
+    fn new_peer_store() -> DBPeerStore<MemoryKeyValueDB> {
+        DBPeerStore::new(MemoryKeyValueDB::open(COLUMNS as usize), vec![])
+    }
+
+    fn new_address(port: u16) -> Multiaddr {
+        format!("/ip4/127.0.0.1/tcp/{}", port)
+            .to_multiaddr()
+            .expect("parse multiaddr")
+    }
+
+    #[test]
+    fn test_peer_record_weight() {
+        let now = 1000 * HOUR;
+        let mut peer = PeerRecord::new(vec![new_address(1)], now);
+        let fresh = peer.weight(now);
+        assert_eq!(fresh, (-MIN_WEIGHT_SCORE + 1) as u64 * 1000);
+
+        // a half life later the peer weighs half as much
+        assert_eq!(peer.weight(now + FRESHNESS_HALF_LIFE * HOUR), fresh / 2);
+
+        peer.score = 10;
+        assert!(peer.weight(now) > fresh);
+        // the scores below the floor weigh the same and never nothing
+        peer.score = MIN_WEIGHT_SCORE;
+        let floor = peer.weight(now);
+        peer.score = Score::min_value();
+        assert_eq!(peer.weight(now), floor);
+        assert!(peer.weight(now + 1_000_000 * HOUR) >= 1);
+
+        peer.score = INITIALIZED_SCORE;
+        peer.last_connected_at = now;
+        assert_eq!(peer.weight(now), fresh * 2);
+    }
+
+    #[test]
+    fn test_peers_to_attempt() {
+        let mut peer_store = new_peer_store();
+        let connected = random_peer_id().unwrap();
+        let banned = random_peer_id().unwrap();
+        let no_address = random_peer_id().unwrap();
+        let attempted = random_peer_id().unwrap();
+        for (port, peer_id) in [&connected, &banned, &attempted].iter().enumerate() {
+            peer_store
+                .add_discovered_addresses(peer_id, vec![new_address(port as u16)])
+                .unwrap();
+        }
+        peer_store.report(&no_address, Behaviour::Connect);
+        peer_store.report_status(&connected, Status::Connected);
+        peer_store.ban(&banned, Duration::from_secs(60));
+
+        let peers = peer_store.peers_to_attempt().collect::<Vec<_>>();
+        assert_eq!(peers, vec![(&attempted, &new_address(2))]);
+
+        // an expired ban doesn't keep the peer out
+        peer_store.peer_mut(&banned).banned_until = unix_time_ms() - 1;
+        let mut peers = peer_store
+            .peers_to_attempt()
+            .map(|(peer_id, _)| peer_id.clone())
+            .collect::<Vec<_>>();
+        peers.sort_by_key(|peer_id| peer_id.to_base58());
+        let mut expected = vec![banned, attempted];
+        expected.sort_by_key(|peer_id| peer_id.to_base58());
+        assert_eq!(peers, expected);
+    }
+
+    #[test]
+    fn test_report_address() {
+        let mut peer_store = new_peer_store();
+        let peer_id = random_peer_id().unwrap();
+        let other = random_peer_id().unwrap();
+        peer_store
+            .add_discovered_addresses(&peer_id, vec![new_address(1), new_address(2)])
+            .unwrap();
+        peer_store
+            .add_discovered_addresses(&other, vec![new_address(3)])
+            .unwrap();
+        let last_seen_at = peer_store.peers[&peer_id].last_seen_at;
+
+        peer_store.report_address(&new_address(2), Behaviour::FailedToConnect);
+        assert_eq!(
+            peer_store.peer_score(&peer_id),
+            INITIALIZED_SCORE + Behaviour::FailedToConnect.score()
+        );
+        assert_eq!(peer_store.peers[&peer_id].last_seen_at, last_seen_at);
+        assert_eq!(peer_store.peer_score(&other), INITIALIZED_SCORE);
+
+        // the score is stored along the peer
+        let peer_store = DBPeerStore::new(peer_store.db, vec![]);
+        assert_eq!(
+            peer_store.peer_score(&peer_id),
+            INITIALIZED_SCORE + Behaviour::FailedToConnect.score()
+        );
+    }
+}
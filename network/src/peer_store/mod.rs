@@ -1,5 +1,11 @@
 use super::PeerId;
 use libp2p::core::Multiaddr;
+use std::time::Duration;
+
+pub mod db_peer_store;
+
+pub use self::db_peer_store::DBPeerStore;
+
 // TODO
 // 1. maintain peer and addresses
 // 2. provide interface to score peer by difference behaviours
@@ -8,6 +14,8 @@ use libp2p::core::Multiaddr;
 // 5. limit peers from same ip group
 // 6. maintain reserved_node behaviours?
 
+pub type Score = i32;
+
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub enum Behaviour {
     FailedToConnect,
@@ -16,6 +24,20 @@ pub enum Behaviour {
     Connect,
     UnexpectedDisconnect,
 }
+
+impl Behaviour {
+    /// How the behaviour changes the score of the peer, the inbound peers with the lowest
+    /// scores are evicted first
+    pub fn score(self) -> Score {
+        match self {
+            Behaviour::FailedToConnect => -5,
+            Behaviour::FailedToPing => -10,
+            Behaviour::Ping => 1,
+            Behaviour::Connect => 1,
+            Behaviour::UnexpectedDisconnect => -5,
+        }
+    }
+}
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Status {
     Connected,
@@ -32,9 +54,14 @@ pub trait PeerStore: Send + Sync {
         addresses: Vec<Multiaddr>,
     ) -> Result<usize, ()>;
     fn report(&mut self, peer_id: &PeerId, behaviour: Behaviour);
+    // the initial score for unknown peers
+    fn peer_score(&self, peer_id: &PeerId) -> Score;
     fn report_address(&mut self, address: &Multiaddr, behaviour: Behaviour);
     fn report_status(&mut self, peer_id: &PeerId, status: Status);
     fn peer_status(&self, peer_id: &PeerId) -> Status;
+    // a banned peer is not attempted until the ban ends
+    fn ban(&mut self, peer_id: &PeerId, timeout: Duration);
+    fn is_banned(&self, peer_id: &PeerId) -> bool;
     // should return high scored nodes if possible, otherwise, return boostrap nodes
     fn bootnodes<'a>(&'a self) -> Box<Iterator<Item = (&'a PeerId, &'a Multiaddr)> + 'a>;
     fn reserved_nodes<'a>(&'a self) -> Box<Iterator<Item = (&'a PeerId, &'a Multiaddr)> + 'a>;
@@ -51,3 +78,24 @@ pub trait PeerStore: Send + Sync {
     ) -> Option<Box<Iterator<Item = &'a Multiaddr> + 'a>>;
     fn peers_to_attempt<'a>(&'a self) -> Box<Iterator<Item = (&'a PeerId, &'a Multiaddr)> + 'a>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_behaviour_score() {
+        for behaviour in &[Behaviour::Ping, Behaviour::Connect] {
+            assert!(behaviour.score() > 0);
+        }
+        for behaviour in &[
+            Behaviour::FailedToConnect,
+            Behaviour::FailedToPing,
+            Behaviour::UnexpectedDisconnect,
+        ] {
+            assert!(behaviour.score() < 0);
+        }
+        // a peer that stops answering pings loses more than a few pings earned
+        assert!(Behaviour::FailedToPing.score() + 5 * Behaviour::Ping.score() < 0);
+    }
+}
@@ -1,15 +1,15 @@
 use super::{Error, ErrorKind, PeerId, PeerIndex, ProtocolId};
 use bytes::Bytes;
-use ckb_util::{Mutex, RwLock};
-use fnv::FnvHashMap;
+use ckb_util::RwLock;
+use fnv::{FnvHashMap, FnvHashSet};
 use futures::sync::mpsc::UnboundedSender;
-use libp2p::core::{Endpoint, Multiaddr, UniqueConnec};
+use libp2p::core::{AddrComponent, Endpoint, Multiaddr, UniqueConnec};
+use libp2p::multiaddr::ToMultiaddr;
 use libp2p::ping;
 use peer_store::PeerStore;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 struct PeerConnections {
     id_allocator: AtomicUsize,
@@ -95,6 +95,9 @@ pub struct PeerConnection {
     pub identify_info: Option<PeerIdentifyInfo>,
     pub(crate) ckb_protocols: Vec<ProtocolConnec>,
     pub last_ping_time: Option<Instant>,
+    // Round trip time of the last answered ping
+    pub ping: Option<Duration>,
+    pub connected_at: Instant,
 }
 
 impl PeerConnection {
@@ -107,7 +110,9 @@ impl PeerConnection {
             identify_info: None,
             ckb_protocols: Vec::with_capacity(1),
             last_ping_time: None,
+            ping: None,
             peer_index: None,
+            connected_at: Instant::now(),
         }
     }
 
@@ -124,7 +129,6 @@ impl PeerConnection {
         self.endpoint_role == Endpoint::Dialer
     }
 
-    #[allow(dead_code)]
     #[inline]
     pub fn is_incoming(&self) -> bool {
         !self.is_outgoing()
@@ -160,7 +164,6 @@ pub(crate) struct PeersRegistry {
     max_outgoing: u32,
     // Only reserved peers or allow all peers.
     reserved_only: bool,
-    deny_list: PeersDenyList,
 }
 
 impl PeersRegistry {
@@ -170,17 +173,21 @@ impl PeersRegistry {
         max_outgoing: u32,
         reserved_only: bool,
     ) -> Self {
-        let deny_list = PeersDenyList::new();
         PeersRegistry {
             peer_store,
             peer_connections: Default::default(),
             max_incoming,
             max_outgoing,
             reserved_only,
-            deny_list,
         }
     }
 
+    /// The connected peers beyond new limits are kept, only new connections are refused
+    pub fn set_max_peers(&mut self, max_incoming: u32, max_outgoing: u32) {
+        self.max_incoming = max_incoming;
+        self.max_outgoing = max_outgoing;
+    }
+
     #[cfg_attr(feature = "cargo-clippy", allow(needless_lifetimes))]
     #[inline]
     pub fn get_peer_id<'a>(&'a self, peer_index: PeerIndex) -> Option<&'a PeerId> {
@@ -202,7 +209,7 @@ impl PeersRegistry {
                     peer_id
                 )).into());
             }
-            if self.deny_list.is_denied(&peer_id) {
+            if self.peer_store.read().is_banned(&peer_id) {
                 return Err(
                     ErrorKind::InvalidNewPeer(format!("peer {:?} is denied", peer_id)).into(),
                 );
@@ -213,10 +220,18 @@ impl PeersRegistry {
                 Endpoint::Listener
                     if connection_status.unreserved_incoming >= self.max_incoming =>
                 {
-                    return Err(ErrorKind::InvalidNewPeer(format!(
-                        "reach max incoming peers limitation, reject peer {:?}",
-                        peer_id
-                    )).into())
+                    match self.eviction_candidate(&peer_id) {
+                        Some(evicted) => {
+                            debug!(target: "network", "evict inbound peer {:?} for peer {:?}", evicted, peer_id);
+                            self.drop_peer(&evicted);
+                        }
+                        None => {
+                            return Err(ErrorKind::InvalidNewPeer(format!(
+                                "reach max incoming peers limitation, reject peer {:?}",
+                                peer_id
+                            )).into())
+                        }
+                    }
                 }
                 Endpoint::Dialer if connection_status.unreserved_outgoing >= self.max_outgoing => {
                     return Err(ErrorKind::InvalidNewPeer(format!(
@@ -255,6 +270,34 @@ impl PeersRegistry {
         self.peer_connections.get_mut(peer_id)
     }
 
+    // The unreserved inbound peer with the lowest score, the latest connected among equals,
+    // unless it scores higher than the new peer
+    fn eviction_candidate(&self, new_peer_id: &PeerId) -> Option<PeerId> {
+        let peer_store = self.peer_store.read();
+        let new_peer_score = peer_store.peer_score(new_peer_id);
+        self.peer_connections
+            .iter()
+            .filter(|(peer_id, peer)| peer.is_incoming() && !peer_store.is_reserved(peer_id))
+            .map(|(peer_id, peer)| (peer_store.peer_score(peer_id), peer.connected_at, peer_id))
+            .min_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)))
+            .and_then(|(score, _, peer_id)| {
+                if score <= new_peer_score {
+                    Some(peer_id.to_owned())
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// The IP buckets of the outbound peers, the outbound connections are spread over buckets
+    pub fn outbound_ip_buckets(&self) -> FnvHashSet<Vec<u8>> {
+        self.peer_connections
+            .iter()
+            .filter(|(_, peer)| peer.is_outgoing())
+            .flat_map(|(_, peer)| peer.remote_addresses.iter().filter_map(ip_bucket))
+            .collect()
+    }
+
     pub fn connection_status(&self) -> ConnectionStatus {
         let mut total: u32 = 0;
         let mut unreserved_incoming: u32 = 0;
@@ -297,46 +340,182 @@ impl PeersRegistry {
         self.peer_connections = Default::default();
     }
 
+    /// The ban is kept by the peer store, which persists it when it is stored on disk
     pub(crate) fn ban_peer(&mut self, peer_id: PeerId, timeout: Duration) {
         debug!(target: "network", "ban_peer: {:?}", peer_id);
         self.drop_peer(&peer_id);
-        self.deny_list.ban_peer(peer_id, timeout);
+        self.peer_store.write().ban(&peer_id, timeout);
     }
 }
 
-struct PeersDenyList {
-    deny_list: Mutex<FnvHashMap<PeerId, Instant>>,
-    size: usize,
+/// The /16 network of an IPv4 address or the /32 network of an IPv6 address, the addresses
+/// without IP have no bucket
+pub(crate) fn ip_bucket(addr: &Multiaddr) -> Option<Vec<u8>> {
+    for component in addr.iter() {
+        match component {
+            AddrComponent::IP4(ip) => return Some(ip.octets()[..2].to_vec()),
+            AddrComponent::IP6(ip) => return Some(ip.octets()[..4].to_vec()),
+            _ => (),
+        }
+    }
+    None
 }
 
-impl PeersDenyList {
-    pub fn new() -> Self {
-        PeersDenyList {
-            deny_list: Mutex::new(Default::default()),
-            size: 4096,
-        }
+pub(crate) fn parse_peer_id(base58: &str) -> Option<PeerId> {
+    let mut addr = format!("/p2p/{}", base58).to_multiaddr().ok()?;
+    match addr.pop() {
+        Some(AddrComponent::P2P(key)) => PeerId::from_bytes(key.into_bytes()).ok(),
+        _ => None,
     }
+}
 
-    pub fn ban_peer(&self, peer_id: PeerId, timeout: Duration) {
-        let now = Instant::now();
-        let timeout_stamp = now + timeout;
-        let mut deny_list = self.deny_list.lock();
-        deny_list.insert(peer_id, timeout_stamp);
-        // release memories
-        if deny_list.len() > self.size {
-            deny_list.retain(move |_peer_id, &mut timeout| timeout < now);
-        }
+pub(crate) fn unix_time_ms() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time before unix epoch");
+    now.as_secs() * 1000 + u64::from(now.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory_peer_store::MemoryPeerStore;
+    use peer_store::Behaviour;
+    use random_peer_id;
+    use std::iter;
+
+    fn new_registry() -> PeersRegistry {
+        let peer_store: Box<PeerStore> = Box::new(MemoryPeerStore::new(vec![]));
+        PeersRegistry::new(Arc::new(RwLock::new(peer_store)), 8, 8, false)
     }
 
-    pub fn is_denied(&self, peer_id: &PeerId) -> bool {
-        let mut deny_list = self.deny_list.lock();
-        if let Some(timeout) = deny_list.get(peer_id).cloned() {
-            if timeout > Instant::now() {
-                return true;
-            } else {
-                deny_list.remove(peer_id);
-            }
+    #[test]
+    fn test_ban_peer() {
+        let mut registry = new_registry();
+        let peer_id = random_peer_id().unwrap();
+        registry
+            .new_peer(peer_id.clone(), Endpoint::Listener)
+            .unwrap();
+        assert!(registry.get(&peer_id).is_some());
+
+        registry.ban_peer(peer_id.clone(), Duration::from_secs(60));
+        assert!(registry.get(&peer_id).is_none());
+        // the banned peer can't reconnect, the others still can
+        assert!(
+            registry
+                .new_peer(peer_id.clone(), Endpoint::Listener)
+                .is_err()
+        );
+        assert!(
+            registry
+                .new_peer(random_peer_id().unwrap(), Endpoint::Listener)
+                .is_ok()
+        );
+    }
+
+    fn add_peer(
+        registry: &mut PeersRegistry,
+        endpoint: Endpoint,
+        connected_secs_ago: u64,
+    ) -> PeerId {
+        let peer_id = random_peer_id().unwrap();
+        let mut peer = PeerConnection::new(endpoint);
+        peer.connected_at = Instant::now() - Duration::from_secs(connected_secs_ago);
+        registry.add_peer(peer_id.clone(), peer);
+        peer_id
+    }
+
+    #[test]
+    fn test_eviction_candidate() {
+        let mut registry = new_registry();
+        let old = add_peer(&mut registry, Endpoint::Listener, 20);
+        let recent = add_peer(&mut registry, Endpoint::Listener, 10);
+        let outbound = add_peer(&mut registry, Endpoint::Dialer, 0);
+        let reserved = add_peer(&mut registry, Endpoint::Listener, 0);
+        {
+            let mut peer_store = registry.peer_store.write();
+            peer_store.add_reserved_node(reserved.clone(), Vec::new());
+            // the outbound and the reserved peers are never evicted, whatever their scores
+            peer_store.report(&outbound, Behaviour::FailedToPing);
+            peer_store.report(&reserved, Behaviour::FailedToPing);
         }
-        false
+
+        // the latest connected among the lowest scored
+        let new_peer = random_peer_id().unwrap();
+        assert_eq!(registry.eviction_candidate(&new_peer), Some(recent.clone()));
+
+        registry.peer_store.write().report(&recent, Behaviour::Ping);
+        assert_eq!(registry.eviction_candidate(&new_peer), Some(old.clone()));
+
+        // a new peer scoring lower than all the inbound peers evicts none
+        registry
+            .peer_store
+            .write()
+            .report(&new_peer, Behaviour::FailedToConnect);
+        assert_eq!(registry.eviction_candidate(&new_peer), None);
+    }
+
+    #[test]
+    fn test_evict_inbound_peer() {
+        let peer_store: Box<PeerStore> = Box::new(MemoryPeerStore::new(vec![]));
+        let mut registry = PeersRegistry::new(Arc::new(RwLock::new(peer_store)), 1, 1, false);
+        let first = random_peer_id().unwrap();
+        registry
+            .new_peer(first.clone(), Endpoint::Listener)
+            .unwrap();
+
+        registry
+            .peer_store
+            .write()
+            .report(&first, Behaviour::UnexpectedDisconnect);
+        let second = random_peer_id().unwrap();
+        registry
+            .new_peer(second.clone(), Endpoint::Listener)
+            .unwrap();
+        assert!(registry.get(&first).is_none());
+        assert!(registry.get(&second).is_some());
+
+        registry.peer_store.write().report(&second, Behaviour::Ping);
+        assert!(
+            registry
+                .new_peer(random_peer_id().unwrap(), Endpoint::Listener)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_ip_bucket() {
+        let bucket = |addr: &str| ip_bucket(&addr.to_multiaddr().unwrap());
+        assert_eq!(bucket("/ip4/10.1.2.3/tcp/8115"), Some(vec![10, 1]));
+        assert_eq!(
+            bucket("/ip4/10.1.2.3/tcp/8115"),
+            bucket("/ip4/10.1.200.4/tcp/1")
+        );
+        assert_ne!(
+            bucket("/ip4/10.1.2.3/tcp/8115"),
+            bucket("/ip4/10.2.2.3/tcp/8115")
+        );
+        assert_eq!(
+            bucket("/ip6/2001:db8:1:2::1/tcp/8115"),
+            Some(vec![0x20, 0x01, 0x0d, 0xb8])
+        );
+        // an address without IP
+        let tcp: Multiaddr = iter::once(AddrComponent::TCP(8115)).collect();
+        assert_eq!(ip_bucket(&tcp), None);
+    }
+
+    #[test]
+    fn test_outbound_ip_buckets() {
+        let mut registry = new_registry();
+        let outbound = add_peer(&mut registry, Endpoint::Dialer, 0);
+        let inbound = add_peer(&mut registry, Endpoint::Listener, 0);
+        registry.get_mut(&outbound).unwrap().remote_addresses =
+            vec!["/ip4/10.1.2.3/tcp/8115".to_multiaddr().unwrap()];
+        registry.get_mut(&inbound).unwrap().remote_addresses =
+            vec!["/ip4/10.2.2.3/tcp/8115".to_multiaddr().unwrap()];
+
+        let buckets = registry.outbound_ip_buckets();
+        assert_eq!(buckets.len(), 1);
+        assert!(buckets.contains(&vec![10, 1]));
     }
 }
@@ -142,6 +142,12 @@ impl<T: Send> ProtocolService<T> for PingService {
                                         Future::then(Timeout::new(ping_future, ping_timeout), {
                                             let network = Arc::clone(&network);
                                             move |result| -> Result<(), IoError> {
+                                                if let Ok(ref peer_id) = result {
+                                                    network.set_peer_ping(
+                                                        peer_id,
+                                                        ping_start_time.elapsed(),
+                                                    );
+                                                }
                                                 let mut peer_store = network.peer_store().write();
                                                 match result {
                                                     Ok(peer_id) => {
@@ -0,0 +1,193 @@
+use super::{CKBProtocolHandler, Network, ProtocolId, TimerToken};
+use ckb_protocol_handler::DefaultCKBProtocolContext;
+use ckb_util::{Condvar, Mutex};
+use fnv::FnvHashMap;
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Timer {
+    handler: Arc<CKBProtocolHandler>,
+    interval: Duration,
+    next: Instant,
+}
+
+#[derive(Default)]
+struct Timers {
+    registry: FnvHashMap<(ProtocolId, TimerToken), Timer>,
+    stopped: bool,
+}
+
+/// Recurring timers of the protocol handlers, each identified by its protocol and token.
+///
+/// A timer can be registered again with another interval or cancelled at any time, from
+/// `timer_triggered` too. The handlers are invoked one at a time on a dedicated thread.
+#[derive(Default)]
+pub(crate) struct TimerScheduler {
+    timers: Arc<(Mutex<Timers>, Condvar)>,
+}
+
+impl TimerScheduler {
+    /// Schedules the timer every `interval`, replacing the previous registration if any
+    pub fn register(
+        &self,
+        protocol_id: ProtocolId,
+        token: TimerToken,
+        interval: Duration,
+        handler: Arc<CKBProtocolHandler>,
+    ) {
+        let (ref timers, ref condvar) = *self.timers;
+        let timer = Timer {
+            handler,
+            interval,
+            next: Instant::now() + interval,
+        };
+        timers.lock().registry.insert((protocol_id, token), timer);
+        condvar.notify_one();
+    }
+
+    /// Returns whether the timer was registered
+    pub fn cancel(&self, protocol_id: ProtocolId, token: TimerToken) -> bool {
+        let (ref timers, ref condvar) = *self.timers;
+        let cancelled = timers
+            .lock()
+            .registry
+            .remove(&(protocol_id, token))
+            .is_some();
+        condvar.notify_one();
+        cancelled
+    }
+
+    /// Timers registered before the start are triggered after their interval from the
+    /// registration, as the later ones. The thread exits once stopped or the network dropped.
+    pub fn start(&self, network: Weak<Network>) -> thread::JoinHandle<()> {
+        let timers = Arc::clone(&self.timers);
+        thread::Builder::new()
+            .name("NetworkTimer".to_string())
+            .spawn(move || {
+                while let Some((handler, protocol_id, token)) = next_due(&timers) {
+                    let network = match network.upgrade() {
+                        Some(network) => network,
+                        None => break,
+                    };
+                    trace!(
+                        target: "network",
+                        "trigger timer: token {} protocol {:?}",
+                        token,
+                        protocol_id
+                    );
+                    handler.timer_triggered(
+                        Box::new(DefaultCKBProtocolContext::new(network, protocol_id)),
+                        token,
+                    );
+                }
+            }).expect("Start network timer thread failed")
+    }
+
+    pub fn stop(&self) {
+        let (ref timers, ref condvar) = *self.timers;
+        timers.lock().stopped = true;
+        condvar.notify_one();
+    }
+}
+
+// Blocks until a timer is due and schedules its next trigger, `None` once stopped
+fn next_due(
+    timers: &(Mutex<Timers>, Condvar),
+) -> Option<(Arc<CKBProtocolHandler>, ProtocolId, TimerToken)> {
+    let (ref timers, ref condvar) = *timers;
+    let mut guard = timers.lock();
+    loop {
+        if guard.stopped {
+            return None;
+        }
+        let now = Instant::now();
+        let earliest = guard
+            .registry
+            .iter()
+            .min_by_key(|(_, timer)| timer.next)
+            .map(|(key, timer)| (*key, timer.next));
+        match earliest {
+            Some((key, next)) if next <= now => {
+                let timer = guard.registry.get_mut(&key).expect("found above");
+                timer.next = now + timer.interval;
+                return Some((Arc::clone(&timer.handler), key.0, key.1));
+            }
+            Some((_, next)) => {
+                condvar.wait_for(&mut guard, next - now);
+            }
+            None => {
+                condvar.wait(&mut guard);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {CKBProtocolContext, PeerIndex};
+
+    const PROTOCOL_ID: ProtocolId = *b"tst";
+
+    struct DummyHandler;
+
+    impl CKBProtocolHandler for DummyHandler {
+        fn initialize(&self, _nc: Box<CKBProtocolContext>) {}
+        fn received(&self, _nc: Box<CKBProtocolContext>, _peer: PeerIndex, _data: &[u8]) {}
+        fn connected(&self, _nc: Box<CKBProtocolContext>, _peer: PeerIndex) {}
+        fn disconnected(&self, _nc: Box<CKBProtocolContext>, _peer: PeerIndex) {}
+    }
+
+    fn due(scheduler: &TimerScheduler) -> Option<TimerToken> {
+        next_due(&scheduler.timers).map(|(_, protocol_id, token)| {
+            assert_eq!(protocol_id, PROTOCOL_ID);
+            token
+        })
+    }
+
+    #[test]
+    fn test_register_and_cancel() {
+        let scheduler = TimerScheduler::default();
+        let handler: Arc<CKBProtocolHandler> = Arc::new(DummyHandler);
+        scheduler.register(
+            PROTOCOL_ID,
+            1,
+            Duration::from_secs(60),
+            Arc::clone(&handler),
+        );
+        scheduler.register(
+            PROTOCOL_ID,
+            2,
+            Duration::from_millis(10),
+            Arc::clone(&handler),
+        );
+
+        // the short timer keeps coming back before the long one
+        assert_eq!(due(&scheduler), Some(2));
+        assert_eq!(due(&scheduler), Some(2));
+
+        assert!(scheduler.cancel(PROTOCOL_ID, 2));
+        assert!(!scheduler.cancel(PROTOCOL_ID, 2));
+        // registered again, the long timer is now the short one
+        scheduler.register(PROTOCOL_ID, 1, Duration::from_millis(10), handler);
+        assert_eq!(due(&scheduler), Some(1));
+    }
+
+    #[test]
+    fn test_stop_wakes_the_timer_thread() {
+        let scheduler = TimerScheduler::default();
+        scheduler.register(
+            PROTOCOL_ID,
+            1,
+            Duration::from_secs(60),
+            Arc::new(DummyHandler),
+        );
+        let timers = Arc::clone(&scheduler.timers);
+        let waiting = thread::spawn(move || next_due(&timers).is_none());
+
+        scheduler.stop();
+        assert!(waiting.join().unwrap());
+        assert!(due(&scheduler).is_none());
+    }
+}
@@ -1,28 +1,47 @@
 #![cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
 
 extern crate ckb_core;
+extern crate ckb_time;
 #[macro_use]
 extern crate crossbeam_channel as channel;
 extern crate fnv;
 #[macro_use]
 extern crate log;
 
+use std::fmt;
 use std::sync::Arc;
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 use channel::{Receiver, Sender};
 use ckb_core::block::Block;
+use ckb_core::extras::BlockRejection;
 use ckb_core::service::Request;
+use ckb_core::transaction::Transaction;
+use ckb_time::now_ms;
 use fnv::FnvHashMap;
 
 pub const MINER_SUBSCRIBER: &str = "miner";
 pub const TXS_POOL_SUBSCRIBER: &str = "txs_pool";
 pub const RPC_SUBSCRIBER: &str = "rpc";
+pub const GRPC_SUBSCRIBER: &str = "grpc";
 
 pub const REGISTER_CHANNEL_SIZE: usize = 2;
 pub const NOTIFY_CHANNEL_SIZE: usize = 128;
 
+/// Tips older than this are still being downloaded during the initial block download
+pub const MAX_TIP_AGE: u64 = 60 * 60 * 1000;
+/// Time waited for the next tip of a burst during the initial block download, in ms
+pub const TIP_DEBOUNCE_INTERVAL: u64 = 100;
+/// Maximum number of tips coalesced, so that a long download is still processed as it goes
+pub const MAX_COALESCED_TIPS: usize = 64;
+
+/// The blocks of a chain reorganization.
+///
+/// The old blocks are in detach order, from the old tip down to the fork point, the new blocks
+/// in attach order, from the fork point up to the new tip. Reverting and applying them in
+/// these orders keeps every transaction after the transactions it depends on.
 #[derive(Clone, PartialEq, Debug, Default)]
 pub struct ForkBlocks {
     olds: Vec<Block>,
@@ -52,6 +71,9 @@ pub type MsgNewTransaction = ();
 pub type MsgNewTip = Arc<Block>;
 pub type MsgNewUncle = Arc<Block>;
 pub type MsgSwitchFork = Arc<ForkBlocks>;
+pub type MsgRejectedBlock = Arc<BlockRejection>;
+/// Transactions proposed but not committed within the proposal window, they are proposed again
+pub type MsgProposalExpired = Arc<Vec<Transaction>>;
 pub type NotifyRegister<M> = Sender<Request<(String, usize), Receiver<M>>>;
 
 #[derive(Default)]
@@ -64,10 +86,14 @@ pub struct NotifyController {
     new_tip_register: NotifyRegister<MsgNewTip>,
     new_uncle_register: NotifyRegister<MsgNewUncle>,
     switch_fork_register: NotifyRegister<MsgSwitchFork>,
+    rejected_block_register: NotifyRegister<MsgRejectedBlock>,
+    proposal_expired_register: NotifyRegister<MsgProposalExpired>,
     new_transaction_notifier: Sender<MsgNewTransaction>,
     new_tip_notifier: Sender<MsgNewTip>,
     new_uncle_notifier: Sender<MsgNewUncle>,
     switch_fork_notifier: Sender<MsgSwitchFork>,
+    rejected_block_notifier: Sender<MsgRejectedBlock>,
+    proposal_expired_notifier: Sender<MsgProposalExpired>,
 }
 
 impl NotifyService {
@@ -80,6 +106,10 @@ impl NotifyService {
             channel::bounded(REGISTER_CHANNEL_SIZE);
         let (switch_fork_register, switch_fork_register_receiver) =
             channel::bounded(REGISTER_CHANNEL_SIZE);
+        let (rejected_block_register, rejected_block_register_receiver) =
+            channel::bounded(REGISTER_CHANNEL_SIZE);
+        let (proposal_expired_register, proposal_expired_register_receiver) =
+            channel::bounded(REGISTER_CHANNEL_SIZE);
 
         let (new_transaction_sender, new_transaction_receiver) =
             channel::bounded::<MsgNewTransaction>(NOTIFY_CHANNEL_SIZE);
@@ -88,11 +118,17 @@ impl NotifyService {
             channel::bounded::<MsgNewUncle>(NOTIFY_CHANNEL_SIZE);
         let (switch_fork_sender, switch_fork_receiver) =
             channel::bounded::<MsgSwitchFork>(NOTIFY_CHANNEL_SIZE);
+        let (rejected_block_sender, rejected_block_receiver) =
+            channel::bounded::<MsgRejectedBlock>(NOTIFY_CHANNEL_SIZE);
+        let (proposal_expired_sender, proposal_expired_receiver) =
+            channel::bounded::<MsgProposalExpired>(NOTIFY_CHANNEL_SIZE);
 
         let mut new_transaction_subscribers = FnvHashMap::default();
         let mut new_tip_subscribers = FnvHashMap::default();
         let mut new_uncle_subscribers = FnvHashMap::default();
         let mut switch_fork_subscribers = FnvHashMap::default();
+        let mut rejected_block_subscribers = FnvHashMap::default();
+        let mut proposal_expired_subscribers = FnvHashMap::default();
 
         let mut thread_builder = thread::Builder::new();
         // Mainly for test: give a empty thread_name
@@ -106,30 +142,42 @@ impl NotifyService {
                         break;
                     }
 
-                    recv(new_transaction_register_receiver, msg) => Self::handle_register_new_transaction(
-                        &mut new_transaction_subscribers, msg
+                    recv(new_transaction_register_receiver, msg) => Self::handle_register(
+                        "new_transaction", &mut new_transaction_subscribers, msg
+                    ),
+                    recv(new_tip_register_receiver, msg) => Self::handle_register(
+                        "new_tip", &mut new_tip_subscribers, msg
+                    ),
+                    recv(new_uncle_register_receiver, msg) => Self::handle_register(
+                        "new_uncle", &mut new_uncle_subscribers, msg
                     ),
-                    recv(new_tip_register_receiver, msg) => Self::handle_register_new_tip(
-                        &mut new_tip_subscribers, msg
+                    recv(switch_fork_register_receiver, msg) => Self::handle_register(
+                        "switch_fork", &mut switch_fork_subscribers, msg
                     ),
-                    recv(new_uncle_register_receiver, msg) => Self::handle_register_new_uncle(
-                        &mut new_uncle_subscribers, msg
+                    recv(rejected_block_register_receiver, msg) => Self::handle_register(
+                        "rejected_block", &mut rejected_block_subscribers, msg
                     ),
-                    recv(switch_fork_register_receiver, msg) => Self::handle_register_switch_fork(
-                        &mut switch_fork_subscribers, msg
+                    recv(proposal_expired_register_receiver, msg) => Self::handle_register(
+                        "proposal_expired", &mut proposal_expired_subscribers, msg
                     ),
 
                     recv(new_transaction_receiver, msg) => Self::handle_notify_new_transaction(
                         &new_transaction_subscribers, msg
                     ),
-                    recv(new_tip_receiver, msg) => Self::handle_notify_new_tip(
-                        &new_tip_subscribers, msg
+                    recv(new_tip_receiver, msg) => Self::handle_notify(
+                        "new_tip", &new_tip_subscribers, msg
                     ),
-                    recv(new_uncle_receiver, msg) => Self::handle_notify_new_uncle(
-                        &new_uncle_subscribers, msg
+                    recv(new_uncle_receiver, msg) => Self::handle_notify(
+                        "new_uncle", &new_uncle_subscribers, msg
                     ),
-                    recv(switch_fork_receiver, msg) => Self::handle_notify_switch_fork(
-                        &switch_fork_subscribers, msg
+                    recv(switch_fork_receiver, msg) => Self::handle_notify(
+                        "switch_fork", &switch_fork_subscribers, msg
+                    ),
+                    recv(rejected_block_receiver, msg) => Self::handle_notify(
+                        "rejected_block", &rejected_block_subscribers, msg
+                    ),
+                    recv(proposal_expired_receiver, msg) => Self::handle_notify(
+                        "proposal_expired", &proposal_expired_subscribers, msg
                     )
                 }
             }).expect("Start notify service failed");
@@ -141,146 +189,102 @@ impl NotifyService {
                 new_tip_register,
                 new_uncle_register,
                 switch_fork_register,
+                rejected_block_register,
+                proposal_expired_register,
                 new_transaction_notifier: new_transaction_sender,
                 new_tip_notifier: new_tip_sender,
                 new_uncle_notifier: new_uncle_sender,
                 switch_fork_notifier: switch_fork_sender,
+                rejected_block_notifier: rejected_block_sender,
+                proposal_expired_notifier: proposal_expired_sender,
                 signal: signal_sender,
             },
         )
     }
 
-    fn handle_register_new_transaction(
-        subscribers: &mut FnvHashMap<String, Sender<MsgNewTransaction>>,
-        msg: Option<Request<(String, usize), Receiver<MsgNewTransaction>>>,
-    ) {
-        match msg {
-            Some(Request {
-                responder,
-                arguments: (name, capacity),
-            }) => {
-                debug!(target: "notify", "Register new_transaction {:?}", name);
-                let (sender, receiver) = channel::bounded::<MsgNewTransaction>(capacity);
-                subscribers.insert(name, sender);
-                responder.send(receiver);
-            }
-            None => warn!(target: "notify", "Register new_transaction channel is closed"),
-        }
-    }
-
-    fn handle_register_new_tip(
-        subscribers: &mut FnvHashMap<String, Sender<MsgNewTip>>,
-        msg: Option<Request<(String, usize), Receiver<MsgNewTip>>>,
+    fn handle_register<M>(
+        event: &str,
+        subscribers: &mut FnvHashMap<String, Sender<M>>,
+        msg: Option<Request<(String, usize), Receiver<M>>>,
     ) {
         match msg {
             Some(Request {
                 responder,
                 arguments: (name, capacity),
             }) => {
-                debug!(target: "notify", "Register new_tip {:?}", name);
-                let (sender, receiver) = channel::bounded::<MsgNewTip>(capacity);
+                debug!(target: "notify", "Register {} {:?}", event, name);
+                // registering a name again replaces its queue, the previous receiver gets nothing
+                let (sender, receiver) = channel::bounded::<M>(capacity);
                 subscribers.insert(name, sender);
                 responder.send(receiver);
             }
-            None => warn!(target: "notify", "Register new_tip channel is closed"),
+            None => warn!(target: "notify", "Register {} channel is closed", event),
         }
     }
 
-    fn handle_register_new_uncle(
-        subscribers: &mut FnvHashMap<String, Sender<MsgNewUncle>>,
-        msg: Option<Request<(String, usize), Receiver<MsgNewUncle>>>,
+    // Waits for a subscriber whose queue is full, an event is never lost
+    fn handle_notify<M: Clone + fmt::Debug>(
+        event: &str,
+        subscribers: &FnvHashMap<String, Sender<M>>,
+        msg: Option<M>,
     ) {
         match msg {
-            Some(Request {
-                responder,
-                arguments: (name, capacity),
-            }) => {
-                debug!(target: "notify", "Register new_uncle {:?}", name);
-                let (sender, receiver) = channel::bounded::<MsgNewUncle>(capacity);
-                subscribers.insert(name, sender);
-                responder.send(receiver);
-            }
-            None => warn!(target: "notify", "Register new_uncle channel is closed"),
-        }
-    }
-
-    fn handle_register_switch_fork(
-        subscribers: &mut FnvHashMap<String, Sender<MsgSwitchFork>>,
-        msg: Option<Request<(String, usize), Receiver<MsgSwitchFork>>>,
-    ) {
-        match msg {
-            Some(Request {
-                responder,
-                arguments: (name, capacity),
-            }) => {
-                debug!(target: "notify", "Register switch_fork {:?}", name);
-                let (sender, receiver) = channel::bounded::<MsgSwitchFork>(capacity);
-                subscribers.insert(name, sender);
-                responder.send(receiver);
+            Some(msg) => {
+                trace!(target: "notify", "event {} {:?}", event, msg);
+                for subscriber in subscribers.values() {
+                    subscriber.send(msg.clone());
+                }
             }
-            None => warn!(target: "notify", "Register switch_fork channel is closed"),
+            None => warn!(target: "notify", "{} channel is closed", event),
         }
     }
 
+    // A new transaction only wakes the subscribers up, one which still has the previous wake-up
+    // queued is skipped, so a busy subscriber never holds up the other events
     fn handle_notify_new_transaction(
         subscribers: &FnvHashMap<String, Sender<MsgNewTransaction>>,
         msg: Option<MsgNewTransaction>,
     ) {
         match msg {
             Some(()) => {
-                trace!(target: "notify", "event new transaction {:?}", msg);
+                trace!(target: "notify", "event new_transaction");
                 for subscriber in subscribers.values() {
-                    subscriber.send(());
-                }
-            }
-            None => warn!(target: "notify", "new transaction channel is closed"),
-        }
-    }
-
-    fn handle_notify_new_tip(
-        subscribers: &FnvHashMap<String, Sender<MsgNewTip>>,
-        msg: Option<MsgNewTip>,
-    ) {
-        match msg {
-            Some(msg) => {
-                trace!(target: "notify", "event new tip {:?}", msg);
-                for subscriber in subscribers.values() {
-                    subscriber.send(Arc::clone(&msg));
+                    if subscriber.is_empty() {
+                        subscriber.send(());
+                    }
                 }
             }
-            None => warn!(target: "notify", "new tip channel is closed"),
+            None => warn!(target: "notify", "new_transaction channel is closed"),
         }
     }
+}
 
-    fn handle_notify_new_uncle(
-        subscribers: &FnvHashMap<String, Sender<MsgNewUncle>>,
-        msg: Option<MsgNewUncle>,
-    ) {
-        match msg {
-            Some(msg) => {
-                trace!(target: "notify", "event new uncle {:?}", msg);
-                for subscriber in subscribers.values() {
-                    subscriber.send(Arc::clone(&msg));
-                }
+/// Collects the burst of tips following `first`, so that a subscriber acts once on the latest
+/// state rather than on every tip. The tips already queued are always taken. While the latest
+/// one is older than `MAX_TIP_AGE` the next one is also waited for, up to
+/// `TIP_DEBOUNCE_INTERVAL`, otherwise the burst is returned immediately.
+pub fn coalesce_new_tips(receiver: &Receiver<MsgNewTip>, first: MsgNewTip) -> Vec<MsgNewTip> {
+    let mut tips = vec![first];
+    while tips.len() < MAX_COALESCED_TIPS {
+        let initial_block_download =
+            now_ms().saturating_sub(tips[tips.len() - 1].header().timestamp()) > MAX_TIP_AGE;
+        let next = if initial_block_download {
+            select! {
+                recv(receiver, msg) => msg,
+                recv(channel::after(Duration::from_millis(TIP_DEBOUNCE_INTERVAL))) => None,
             }
-            None => warn!(target: "notify", "new uncle channel is closed"),
-        }
-    }
-
-    fn handle_notify_switch_fork(
-        subscribers: &FnvHashMap<String, Sender<MsgSwitchFork>>,
-        msg: Option<MsgSwitchFork>,
-    ) {
-        match msg {
-            Some(msg) => {
-                trace!(target: "notify", "event switch fork {:?}", msg);
-                for subscriber in subscribers.values() {
-                    subscriber.send(Arc::clone(&msg));
-                }
+        } else {
+            select! {
+                recv(receiver, msg) => msg,
+                default => None,
             }
-            None => warn!(target: "notify", "event 3 channel is closed"),
+        };
+        match next {
+            Some(tip) => tips.push(tip),
+            None => break,
         }
     }
+    tips
 }
 
 impl NotifyController {
@@ -304,6 +308,14 @@ impl NotifyController {
         Request::call(&self.switch_fork_register, (name.to_string(), 128))
             .expect("Subscribe switch fork failed")
     }
+    pub fn subscribe_rejected_block<S: ToString>(&self, name: S) -> Receiver<MsgRejectedBlock> {
+        Request::call(&self.rejected_block_register, (name.to_string(), 128))
+            .expect("Subscribe rejected block failed")
+    }
+    pub fn subscribe_proposal_expired<S: ToString>(&self, name: S) -> Receiver<MsgProposalExpired> {
+        Request::call(&self.proposal_expired_register, (name.to_string(), 128))
+            .expect("Subscribe proposal expired failed")
+    }
 
     pub fn notify_new_transaction(&self) {
         self.new_transaction_notifier.send(());
@@ -317,11 +329,19 @@ impl NotifyController {
     pub fn notify_switch_fork(&self, txs: MsgSwitchFork) {
         self.switch_fork_notifier.send(txs);
     }
+    pub fn notify_rejected_block(&self, rejection: MsgRejectedBlock) {
+        self.rejected_block_notifier.send(rejection);
+    }
+    pub fn notify_proposal_expired(&self, txs: MsgProposalExpired) {
+        self.proposal_expired_notifier.send(txs);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ckb_core::block::BlockBuilder;
+    use ckb_core::header::HeaderBuilder;
 
     #[test]
     fn test_new_transaction() {
@@ -349,6 +369,66 @@ mod tests {
         handle.join().expect("join failed");
     }
 
+    #[test]
+    fn test_idle_new_transaction_subscriber() {
+        let tip = Arc::new(Block::default());
+
+        let (handle, notify) = NotifyService::default().start::<&str>(None);
+        let idle = notify.subscribe_new_transaction("miner1");
+        let receiver = notify.subscribe_new_tip("miner2");
+        // more wake-ups than both the service and the idle subscriber can queue
+        for _ in 0..NOTIFY_CHANNEL_SIZE * 3 {
+            notify.notify_new_transaction();
+        }
+        notify.notify_new_tip(Arc::clone(&tip));
+        assert_eq!(receiver.recv(), Some(tip));
+        assert_eq!(idle.recv(), Some(()));
+        notify.stop();
+        handle.join().expect("join failed");
+    }
+
+    #[test]
+    fn test_register_again() {
+        let tip = Arc::new(Block::default());
+
+        let (handle, notify) = NotifyService::default().start::<&str>(None);
+        let receiver1 = notify.subscribe_new_tip("miner1");
+        let receiver2 = notify.subscribe_new_tip("miner1");
+        notify.notify_new_tip(Arc::clone(&tip));
+        assert_eq!(receiver2.recv(), Some(tip));
+        // the first queue was dropped with its registration
+        assert_eq!(receiver1.recv(), None);
+        notify.stop();
+        handle.join().expect("join failed");
+    }
+
+    #[test]
+    fn test_coalesce_new_tips() {
+        let tip = |number| {
+            let header = HeaderBuilder::default()
+                .number(number)
+                .timestamp(now_ms())
+                .build();
+            Arc::new(BlockBuilder::default().header(header).build())
+        };
+
+        let (handle, notify) = NotifyService::default().start::<&str>(None);
+        let receiver = notify.subscribe_new_tip("miner1");
+        for number in 1..4 {
+            notify.notify_new_tip(tip(number));
+        }
+        let first = receiver.recv().expect("new tip");
+        // the tips are recent, the burst ends with the last queued one
+        thread::sleep(Duration::from_millis(TIP_DEBOUNCE_INTERVAL));
+        let tips = coalesce_new_tips(&receiver, first);
+        assert_eq!(
+            tips.iter().map(|tip| tip.header().number()).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        notify.stop();
+        handle.join().expect("join failed");
+    }
+
     #[test]
     fn test_switch_fork() {
         let blks = Arc::new(ForkBlocks::default());
@@ -362,4 +442,38 @@ mod tests {
         notify.stop();
         handle.join().expect("join failed");
     }
+
+    #[test]
+    fn test_rejected_block() {
+        let rejection = Arc::new(BlockRejection {
+            hash: Default::default(),
+            peer: Some(1),
+            reason: "Pow".to_string(),
+            error: "Pow(InvalidProof)".to_string(),
+            code: 301,
+        });
+
+        let (handle, notify) = NotifyService::default().start::<&str>(None);
+        let receiver1 = notify.subscribe_rejected_block("rpc1");
+        let receiver2 = notify.subscribe_rejected_block("rpc2");
+        notify.notify_rejected_block(Arc::clone(&rejection));
+        assert_eq!(receiver1.recv(), Some(Arc::clone(&rejection)));
+        assert_eq!(receiver2.recv(), Some(rejection));
+        notify.stop();
+        handle.join().expect("join failed");
+    }
+
+    #[test]
+    fn test_proposal_expired() {
+        let txs = Arc::new(vec![Transaction::default()]);
+
+        let (handle, notify) = NotifyService::default().start::<&str>(None);
+        let receiver1 = notify.subscribe_proposal_expired("rpc1");
+        let receiver2 = notify.subscribe_proposal_expired("rpc2");
+        notify.notify_proposal_expired(Arc::clone(&txs));
+        assert_eq!(receiver1.recv(), Some(Arc::clone(&txs)));
+        assert_eq!(receiver2.recv(), Some(txs));
+        notify.stop();
+        handle.join().expect("join failed");
+    }
 }
@@ -1,18 +1,23 @@
-use bigint::H256;
+use bigint::{H256, U256};
 use channel::{self, Receiver};
 use ckb_chain::chain::{ChainBuilder, ChainController};
-use ckb_chain_spec::consensus::Consensus;
+use ckb_chain_spec::consensus::{Consensus, ProposalWindow};
+use ckb_chain_spec::hardfork::HardForkSwitch;
 use ckb_core::block::{Block, BlockBuilder};
 use ckb_core::cell::{CellProvider, CellStatus};
 use ckb_core::header::HeaderBuilder;
 use ckb_core::script::Script;
 use ckb_core::transaction::*;
+use ckb_core::uncle::UncleBlock;
 use ckb_db::memorydb::MemoryKeyValueDB;
-use ckb_notify::{ForkBlocks, MsgNewTip, MsgSwitchFork, NotifyService, TXS_POOL_SUBSCRIBER};
+use ckb_notify::{
+    ForkBlocks, MsgNewTip, MsgProposalExpired, MsgSwitchFork, NotifyService, TXS_POOL_SUBSCRIBER,
+};
 use ckb_shared::index::ChainIndex;
 use ckb_shared::shared::{ChainProvider, Shared, SharedBuilder};
 use ckb_shared::store::ChainKVStore;
 use ckb_time::now_ms;
+use ckb_verification::{CommitVerifier, ScriptError, ScriptFailure, TransactionError};
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
@@ -37,7 +42,7 @@ macro_rules! expect_output_parent {
     }
 }
 
-// Work only when TRANSACTION_PROPAGATION_TIME = 1, TRANSACTION_PROPAGATION_TIMEOUT = 10
+// Work only with the default proposal window, close = 1, far = 10
 #[test]
 fn test_proposal_pool() {
     let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple();
@@ -246,6 +251,191 @@ pub fn test_add_pool_error() {
     assert_eq!(pool.service.total_size(), 1);
 }
 
+#[test]
+fn test_add_dead_cell() {
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple();
+
+    let committed = test_transaction(vec![OutPoint::new(pool.tx_hash, 0)], 2);
+    apply_transactions(vec![committed], vec![], &mut pool);
+
+    // the cell is spent on chain, the transaction is rejected rather than held as orphan
+    let dead = test_transaction(vec![OutPoint::new(pool.tx_hash, 0)], 1);
+    match pool.service.add_to_pool(dead) {
+        Err(PoolError::DeadCell(out_point)) => {
+            assert_eq!(out_point, OutPoint::new(pool.tx_hash, 0))
+        }
+        x => panic!("Unexpected result when adding tx spending dead cell: {:?}", x),
+    }
+    assert_eq!(pool.service.orphan_size(), 0);
+    assert_eq!(pool.service.cache_size(), 0);
+
+    let unknown = test_transaction(vec![OutPoint::new(H256::from(1), 0)], 1);
+    match pool.service.add_to_pool(unknown) {
+        Ok(InsertionResult::Orphan(missing)) => {
+            assert_eq!(missing, vec![OutPoint::new(H256::from(1), 0)])
+        }
+        x => panic!("Unexpected result when adding tx spending unknown cell: {:?}", x),
+    }
+}
+
+#[test]
+fn test_recent_rejects() {
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple();
+
+    let committed = test_transaction(vec![OutPoint::new(pool.tx_hash, 0)], 2);
+    apply_transactions(vec![committed], vec![], &mut pool);
+
+    let invalid =
+        test_transaction_with_capacity(vec![OutPoint::new(pool.tx_hash, 1)], 1, 200_000_000);
+    let dead = test_transaction(vec![OutPoint::new(pool.tx_hash, 0)], 1);
+    apply_transactions(
+        vec![],
+        vec![invalid.proposal_short_id(), dead.proposal_short_id()],
+        &mut pool,
+    );
+
+    match pool.service.add_transaction(invalid.clone()) {
+        Err(PoolError::InvalidTx(TransactionError::InvalidCapacity)) => {}
+        x => panic!("Unexpected result when adding tx over capacity: {:?}", x),
+    }
+    match pool.service.add_transaction(dead) {
+        Err(PoolError::DeadCell(_)) => {}
+        x => panic!("Unexpected result when adding tx spending dead cell: {:?}", x),
+    }
+    assert_eq!(pool.service.rejects_size(), 2);
+
+    // answered from the rejects, the transaction isn't proposed any more
+    match pool.service.add_transaction(invalid.clone()) {
+        Err(PoolError::InvalidTx(TransactionError::InvalidCapacity)) => {}
+        x => panic!("Unexpected result when adding rejected tx: {:?}", x),
+    }
+    assert_eq!(pool.service.pending_size(), 0);
+
+    // a new tip only lifts the rejections depending on the chain state
+    apply_transactions(vec![], vec![], &mut pool);
+    assert_eq!(pool.service.rejects_size(), 1);
+    assert!(pool.service.add_transaction(invalid).is_err());
+}
+
+#[test]
+fn test_exceeded_maximum_bytes() {
+    let small = test_transaction(vec![OutPoint::new(H256::zero(), 0)], 1);
+    let large = test_transaction(vec![OutPoint::new(H256::zero(), 1)], 2);
+    let max = small.serialized_size();
+    let consensus = Consensus::default()
+        .set_verification(false)
+        .set_max_tx_bytes(max);
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::with_consensus(consensus);
+
+    assert!(pool.service.add_transaction(small).is_ok());
+    match pool.service.add_transaction(large.clone()) {
+        Err(PoolError::InvalidTx(TransactionError::ExceededMaximumBytes { max: m, actual })) => {
+            assert_eq!((m, actual), (max, large.serialized_size()))
+        }
+        x => panic!("Unexpected result when adding oversized tx: {:?}", x),
+    }
+    // the oversized transaction is neither waiting for a proposal nor verified again
+    assert_eq!(pool.service.pending_size(), 1);
+    assert_eq!(pool.service.rejects_size(), 1);
+}
+
+#[test]
+fn test_exceeded_maximum_data_bytes() {
+    let data_transaction = |index, data: Vec<Vec<u8>>| {
+        let lock = create_valid_script().type_hash();
+        TransactionBuilder::default()
+            .input(CellInput::new(
+                OutPoint::new(H256::zero(), index),
+                create_valid_script(),
+            )).outputs(
+                data.into_iter()
+                    .map(|data| CellOutput::new(100, data, lock, None))
+                    .collect(),
+            ).build()
+    };
+    let consensus = Consensus::default()
+        .set_verification(false)
+        .set_max_cell_data_bytes(10)
+        .set_max_block_data_bytes(15);
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::with_consensus(consensus);
+
+    assert!(pool
+        .service
+        .add_transaction(data_transaction(0, vec![vec![1; 10]]))
+        .is_ok());
+    match pool
+        .service
+        .add_transaction(data_transaction(1, vec![vec![1; 11]]))
+    {
+        Err(PoolError::InvalidTx(TransactionError::ExceededMaximumCellDataBytes {
+            max,
+            actual,
+        })) => assert_eq!((max, actual), (10, 11)),
+        x => panic!("Unexpected result when adding an oversized cell: {:?}", x),
+    }
+    // every cell fits, all of them together don't fit in a block
+    match pool
+        .service
+        .add_transaction(data_transaction(2, vec![vec![1; 8], vec![1; 8]]))
+    {
+        Err(PoolError::InvalidTx(TransactionError::ExceededMaximumDataBytes { max, actual })) => {
+            assert_eq!((max, actual), (15, 16))
+        }
+        x => panic!("Unexpected result when adding oversized data: {:?}", x),
+    }
+    assert_eq!(pool.service.pending_size(), 1);
+}
+
+#[test]
+fn test_exceeded_maximum_cycles() {
+    let consensus = Consensus::default()
+        .set_verification(false)
+        .set_cellbase_maturity(0)
+        .set_max_block_cycles(1);
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::with_consensus(consensus);
+    let funding = funding_transaction();
+    pool.tx_hash = funding.hash();
+    apply_transactions(vec![funding], vec![], &mut pool);
+
+    // the scripts of the inputs resolve, the VM stops them once they cost what a block allows
+    let tx = test_transaction(vec![OutPoint::new(pool.tx_hash, 0)], 1);
+    match pool.service.add_to_pool(tx) {
+        Err(PoolError::InvalidTx(TransactionError::ScriptFailure(ScriptFailure {
+            error: ScriptError::ExceededMaximumCycles,
+            ..
+        }))) => {}
+        x => panic!("Unexpected result when adding a costly tx: {:?}", x),
+    }
+    assert_eq!(pool.service.pool_size(), 0);
+}
+
+#[test]
+fn test_cellbase_maturity() {
+    let consensus = Consensus::default()
+        .set_verification(false)
+        .set_cellbase_maturity(2)
+        .set_hardfork_switch(HardForkSwitch {
+            cellbase_maturity: Some(0),
+            ..Default::default()
+        });
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::with_consensus(consensus);
+    let funding = funding_transaction();
+    pool.tx_hash = funding.hash();
+    apply_transactions(vec![funding], vec![], &mut pool);
+
+    // the funding cellbase is in block 1, its outputs can be spent from block 3
+    let tx = test_transaction(vec![OutPoint::new(pool.tx_hash, 0)], 1);
+    match pool.service.add_to_pool(tx.clone()) {
+        Err(PoolError::InvalidTx(TransactionError::CellbaseImmaturity)) => {}
+        x => panic!("Unexpected result when spending a young cellbase: {:?}", x),
+    }
+    assert_eq!(pool.service.pool_size(), 0);
+
+    apply_transactions(vec![], vec![], &mut pool);
+    assert!(pool.service.add_to_pool(tx).is_ok());
+    assert_eq!(pool.service.pool_size(), 1);
+}
+
 // #[test]
 // /// Testing an expected orphan
 // fn test_add_orphan() {
@@ -301,6 +491,283 @@ fn test_get_mineable_transactions() {
     assert_eq!(txs, vec![tx1, tx2, tx3, tx4])
 }
 
+#[test]
+fn test_package() {
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple();
+
+    let tx1 = test_transaction(
+        vec![
+            OutPoint::new(pool.tx_hash, 0),
+            OutPoint::new(pool.tx_hash, 1),
+            OutPoint::new(pool.tx_hash, 4),
+            OutPoint::new(pool.tx_hash, 5),
+        ],
+        4,
+    );
+    let tx1_hash = tx1.hash();
+    let tx2 = test_transaction(vec![OutPoint::new(tx1_hash, 0)], 1);
+    let tx3 = test_transaction(vec![OutPoint::new(pool.tx_hash, 2)], 1);
+    let tx4 = test_transaction(vec![OutPoint::new(pool.tx_hash, 3)], 1);
+
+    pool.service.add_to_pool(tx2.clone()).unwrap();
+    pool.service.add_to_pool(tx1.clone()).unwrap();
+    pool.service.add_to_pool(tx3.clone()).unwrap();
+    pool.service.add_to_pool(tx4.clone()).unwrap();
+    assert_eq!(pool.service.pool_size(), 4);
+
+    let (tip_hash, tip_number) = {
+        let tip_header = pool.shared.tip_header().read();
+        (tip_header.hash(), tip_header.number())
+    };
+    let package = pool.service.package(usize::max_value(), u64::max_value(), 10);
+    assert_eq!(package.tip_hash, tip_hash);
+    assert_eq!(package.tip_number, tip_number);
+    // tx2 spends more than its input holds, it pays no fee and comes last
+    let txs = package
+        .commit_transactions
+        .into_iter()
+        .map(|(tx, _)| tx)
+        .collect::<Vec<_>>();
+    assert_eq!(txs.len(), 4);
+    assert!(txs.contains(&tx1) && txs.contains(&tx3) && txs.contains(&tx4));
+    assert_eq!(txs.last(), Some(&tx2));
+
+    // tx1 doesn't fit, tx2 spends it and must be left out as well
+    let max_bytes = (tx3.serialized_size() + tx4.serialized_size()) as usize;
+    assert!(tx1.serialized_size() as usize > max_bytes);
+    let package = pool.service.package(max_bytes, u64::max_value(), 10);
+    let txs = package
+        .commit_transactions
+        .into_iter()
+        .map(|(tx, _)| tx)
+        .collect::<Vec<_>>();
+    assert_eq!(txs, vec![tx3, tx4]);
+}
+
+#[test]
+fn test_evict_lowest_fee_rate() {
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple_with_config(PoolConfig {
+        max_pool_size: 2,
+        ..test_pool_config()
+    });
+
+    // all the same size, the fee is what's left of the spent funding output
+    let funding_hash = pool.tx_hash;
+    let tx_paying = |index: u32, fee: u64| {
+        test_transaction_with_capacity(
+            vec![OutPoint::new(funding_hash, index)],
+            1,
+            100_000_000 - fee,
+        )
+    };
+    let low = tx_paying(0, 1_000);
+    let high = tx_paying(1, 3_000);
+    let middle = tx_paying(2, 2_000);
+    let lowest = tx_paying(3, 500);
+
+    pool.service.add_to_pool(low.clone()).unwrap();
+    pool.service.add_to_pool(high.clone()).unwrap();
+    pool.service.add_to_pool(middle.clone()).unwrap();
+    assert_eq!(pool.service.pool_size(), 2);
+    assert!(pool
+        .service
+        .get_pool_transaction(&low.proposal_short_id())
+        .is_none());
+
+    match pool.service.add_to_pool(lowest.clone()) {
+        Err(PoolError::OverCapacity) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    assert_eq!(pool.service.get_top_n_by_fee(10), vec![high, middle]);
+}
+
+#[test]
+fn test_evict_keeps_ancestors() {
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple_with_config(PoolConfig {
+        max_pool_size: 2,
+        ..test_pool_config()
+    });
+
+    let funding_hash = pool.tx_hash;
+    let parent =
+        test_transaction_with_capacity(vec![OutPoint::new(funding_hash, 0)], 1, 100_000_000 - 500);
+    let other = test_transaction_with_capacity(
+        vec![OutPoint::new(funding_hash, 1)],
+        1,
+        100_000_000 - 1_000,
+    );
+    // pays the most but can't be mined without its parent, the cheapest entry
+    let child = test_transaction_with_capacity(
+        vec![OutPoint::new(parent.hash(), 0)],
+        1,
+        100_000_000 - 500 - 5_000,
+    );
+
+    pool.service.add_to_pool(parent.clone()).unwrap();
+    pool.service.add_to_pool(other.clone()).unwrap();
+    pool.service.add_to_pool(child.clone()).unwrap();
+    assert_eq!(pool.service.pool_size(), 2);
+    assert!(pool
+        .service
+        .get_pool_transaction(&other.proposal_short_id())
+        .is_none());
+    assert_eq!(
+        pool.service.get_top_n_by_fee(10),
+        vec![parent.clone(), child]
+    );
+
+    // nothing else can make room for a child of the parent
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple_with_config(PoolConfig {
+        max_pool_size: 1,
+        ..test_pool_config()
+    });
+    let child = test_transaction_with_capacity(
+        vec![OutPoint::new(parent.hash(), 0)],
+        1,
+        100_000_000 - 500 - 5_000,
+    );
+    pool.service.add_to_pool(parent.clone()).unwrap();
+    match pool.service.add_to_pool(child) {
+        Err(PoolError::OverCapacity) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    assert_eq!(pool.service.get_top_n_by_fee(10), vec![parent]);
+}
+
+#[test]
+fn test_orphan_outputs_overflow() {
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple();
+
+    // the fee of an orphan is only computed once its inputs are known
+    let output = CellOutput::new(u64::max_value(), Vec::new(), H256::zero(), None);
+    let orphan = TransactionBuilder::default()
+        .input(CellInput::new(
+            OutPoint::new(H256::from(1), 0),
+            create_valid_script(),
+        )).outputs(vec![output; 2])
+        .build();
+    match pool.service.add_to_pool(orphan) {
+        Ok(InsertionResult::Orphan(_)) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    assert_eq!(pool.service.orphan_size(), 1);
+}
+
+#[test]
+fn test_pending_capacity() {
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple_with_config(PoolConfig {
+        max_pending_size: 1,
+        ..test_pool_config()
+    });
+
+    let first = test_transaction(vec![OutPoint::new(pool.tx_hash, 0)], 1);
+    let second = test_transaction(vec![OutPoint::new(pool.tx_hash, 1)], 1);
+    match pool.service.add_transaction(first.clone()) {
+        Ok(InsertionResult::Unknown) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    match pool.service.add_transaction(second.clone()) {
+        Err(PoolError::OverCapacity) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    // the pending one can be relayed again
+    assert!(pool.service.add_transaction(first.clone()).is_ok());
+    assert_eq!(pool.service.pending_size(), 1);
+
+    assert_eq!(
+        pool.service
+            .get_transactions(&[second.proposal_short_id(), first.proposal_short_id()]),
+        vec![first]
+    );
+}
+
+#[test]
+fn test_proposed_capacity() {
+    // a proposed transaction waits a block before it can be committed
+    let consensus = Consensus::default()
+        .set_proposal_window(ProposalWindow { close: 2, far: 4 })
+        .set_verification(false)
+        .set_cellbase_maturity(0);
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::with_config(
+        consensus,
+        PoolConfig {
+            max_proposal_size: 1,
+            ..test_pool_config()
+        },
+    );
+    let tx = funding_transaction();
+    pool.tx_hash = tx.hash();
+    apply_transactions(vec![tx], vec![], &mut pool);
+
+    let first = test_transaction(vec![OutPoint::new(pool.tx_hash, 0)], 1);
+    let second = test_transaction(vec![OutPoint::new(pool.tx_hash, 1)], 1);
+    let prop_ids = vec![first.proposal_short_id(), second.proposal_short_id()];
+    apply_transactions(vec![], prop_ids, &mut pool);
+
+    match pool.service.add_transaction(first.clone()) {
+        Ok(InsertionResult::Proposed) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    match pool.service.add_transaction(second.clone()) {
+        Err(PoolError::OverCapacity) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    assert_eq!(pool.service.proposed_size(), 1);
+    // the buffered one can be relayed again
+    match pool.service.add_transaction(first.clone()) {
+        Ok(InsertionResult::Proposed) => {}
+        result => panic!("unexpected result {:?}", result),
+    }
+    assert_eq!(pool.service.proposed_size(), 1);
+    assert_eq!(
+        pool.service
+            .get_transactions(&[second.proposal_short_id(), first.proposal_short_id()]),
+        vec![first]
+    );
+}
+
+#[test]
+fn test_orphan_capacity() {
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple_with_config(PoolConfig {
+        max_orphan_size: 1,
+        ..test_pool_config()
+    });
+
+    let first = test_transaction(vec![OutPoint::new(H256::from(1), 0)], 1);
+    let second = test_transaction(vec![OutPoint::new(H256::from(2), 0)], 1);
+    assert!(pool.service.add_to_pool(first.clone()).is_ok());
+    assert!(pool.service.add_to_pool(second.clone()).is_ok());
+
+    // the oldest orphan made room for the new one
+    assert_eq!(pool.service.orphan_size(), 1);
+    assert!(pool
+        .service
+        .get_pool_transaction(&first.proposal_short_id())
+        .is_none());
+    assert_eq!(
+        pool.service
+            .get_pool_transaction(&second.proposal_short_id())
+            .map(|entry| entry.status),
+        Some(PoolTxStatus::Orphan)
+    );
+}
+
+#[test]
+fn test_orphans_out_of_pool_size() {
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple_with_config(PoolConfig {
+        max_pool_size: 1,
+        ..test_pool_config()
+    });
+
+    let orphan = test_transaction(vec![OutPoint::new(H256::from(1), 0)], 1);
+    let tx = test_transaction(vec![OutPoint::new(pool.tx_hash, 0)], 1);
+    assert!(pool.service.add_to_pool(orphan).is_ok());
+    // the orphans don't take the room of the verified transactions
+    assert!(pool.service.add_to_pool(tx).is_ok());
+    assert_eq!(pool.service.orphan_size(), 1);
+    assert_eq!(pool.service.total_size(), 1);
+}
+
 #[test]
 /// Testing block reconciliation
 fn test_block_reconciliation() {
@@ -384,11 +851,37 @@ fn test_block_reconciliation() {
     assert_eq!(0, pool.service.proposed_size());
     assert_eq!(0, pool.service.pool_size());
     assert_eq!(0, pool.service.orphan_size());
-    // when TRANSACTION_PROPAGATION_TIME = 1
+    // when the proposal window close = 1
     assert_eq!(1, pool.service.cache_size());
 }
 
-// Work only when TRANSACTION_PROPAGATION_TIME = 1, TRANSACTION_PROPAGATION_TIMEOUT = 10
+// Work only with the default proposal window, close = 1
+#[test]
+fn test_uncle_proposals() {
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple();
+
+    let tx = test_transaction(vec![OutPoint::new(pool.tx_hash, 0)], 2);
+    pool.service.add_transaction(tx.clone()).unwrap();
+    assert_eq!(pool.service.pending_size(), 1);
+
+    let parent = pool.shared.tip_header().read().inner().clone();
+    let uncle = UncleBlock {
+        header: HeaderBuilder::default()
+            .parent_hash(&parent.parent_hash())
+            .number(parent.number())
+            .nonce(1)
+            .build(),
+        cellbase: Default::default(),
+        proposal_transactions: vec![tx.proposal_short_id()],
+    };
+
+    // the transaction is only proposed by the uncle
+    apply_transactions_with_uncles(vec![], vec![uncle], vec![], &mut pool);
+    assert_eq!(pool.service.pending_size(), 0);
+    assert_eq!(pool.service.get_mineable_transactions(10), vec![tx]);
+}
+
+// Work only with the default proposal window, close = 1, far = 10
 #[test]
 fn test_switch_fork() {
     let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple();
@@ -450,6 +943,174 @@ fn test_switch_fork() {
     let mtxs = pool.service.get_mineable_transactions(10);
 
     assert_eq!(mtxs, vec![txs[3].clone(), txs[6].clone(), txs[5].clone()]);
+    // committed by the detached block only, it waits for a new proposal
+    assert_eq!(
+        pool.service
+            .get_pool_transaction(&prop_ids[0])
+            .map(|entry| entry.status),
+        Some(PoolTxStatus::Pending)
+    );
+}
+
+// Work only with the default proposal window, close = 1, far = 10
+#[test]
+fn test_switch_fork_resurrects_transactions() {
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple();
+    let block_number = { pool.shared.tip_header().read().number() };
+
+    let txs: Vec<Transaction> = (0..3)
+        .map(|i| test_transaction(vec![OutPoint::new(pool.tx_hash, i)], 2))
+        .collect();
+    for tx in &txs {
+        pool.service.add_transaction(tx.clone()).unwrap();
+    }
+    let ids: Vec<ProposalShortId> = txs.iter().map(|tx| tx.proposal_short_id()).collect();
+
+    let block01 = BlockBuilder::default()
+        .proposal_transactions(ids.clone())
+        .with_header_builder(HeaderBuilder::default().number(block_number + 1));
+    let block02 = BlockBuilder::default()
+        .commit_transactions(txs.clone())
+        .with_header_builder(HeaderBuilder::default().number(block_number + 2));
+    pool.service.reconcile_block(&block01);
+    pool.service.reconcile_block(&block02);
+    assert_eq!(pool.service.total_size(), 0);
+    assert_eq!(pool.service.pending_size(), 0);
+
+    // the new chain proposes txs[1] and txs[2], and commits txs[2] again
+    let block11 = BlockBuilder::default()
+        .proposal_transactions(vec![ids[1], ids[2]])
+        .with_header_builder(HeaderBuilder::default().number(block_number + 1));
+    let block12 = BlockBuilder::default()
+        .commit_transaction(txs[2].clone())
+        .with_header_builder(HeaderBuilder::default().number(block_number + 2));
+    let fb = ForkBlocks::new(vec![block02, block01], vec![block11, block12]);
+    pool.service.switch_fork(&fb);
+
+    let status = |pool: &TestPool<_>, id: &ProposalShortId| {
+        pool.service
+            .get_pool_transaction(id)
+            .map(|entry| entry.status)
+    };
+    assert_eq!(status(&pool, &ids[0]), Some(PoolTxStatus::Pending));
+    assert_eq!(status(&pool, &ids[1]), Some(PoolTxStatus::Mineable));
+    assert_eq!(status(&pool, &ids[2]), None);
+    assert_eq!(
+        pool.service.get_mineable_transactions(10),
+        vec![txs[1].clone()]
+    );
+}
+
+#[test]
+fn test_reconcile_blocks() {
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple();
+
+    let block_number = { pool.shared.tip_header().read().number() };
+
+    let tx0 = test_transaction(vec![OutPoint::new(pool.tx_hash, 0)], 2);
+    let tx1 = test_transaction(vec![OutPoint::new(pool.tx_hash, 1)], 2);
+    pool.service.add_transaction(tx0.clone()).unwrap();
+    pool.service.add_transaction(tx1.clone()).unwrap();
+    assert_eq!(pool.service.pending_size(), 2);
+
+    let block1 = BlockBuilder::default()
+        .proposal_transactions(vec![tx0.proposal_short_id(), tx1.proposal_short_id()])
+        .with_header_builder(HeaderBuilder::default().number(block_number + 1));
+    let block2 = BlockBuilder::default()
+        .commit_transaction(tx0.clone())
+        .with_header_builder(HeaderBuilder::default().number(block_number + 2));
+
+    // tx0 is committed within the burst, it is not staged at all
+    pool.service.reconcile_blocks(&[Arc::new(block1), Arc::new(block2)]);
+
+    assert_eq!(0, pool.service.pending_size());
+    assert_eq!(1, pool.service.pool_size());
+    assert_eq!(0, pool.service.rejects_size());
+    assert_eq!(pool.service.get_mineable_transactions(10), vec![tx1]);
+}
+
+// A transaction proposed in block 1 can only be committed from block 3 to block 5, the pool
+// packages it for exactly the blocks the commit verifier accepts it in.
+#[test]
+fn test_proposal_window_near_genesis() {
+    let window = ProposalWindow { close: 2, far: 4 };
+    let funding = funding_transaction();
+    let genesis_block = BlockBuilder::default()
+        .commit_transaction(funding.clone())
+        .with_header_builder(HeaderBuilder::default().difficulty(&U256::one()));
+    let consensus = Consensus::default()
+        .set_genesis_block(genesis_block)
+        .set_proposal_window(window)
+        .set_cellbase_maturity(0)
+        .set_verification(false);
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::with_consensus(consensus);
+
+    let tx = test_transaction(vec![OutPoint::new(funding.hash(), 0)], 1);
+    pool.service.add_transaction(tx.clone()).unwrap();
+    let verifier = CommitVerifier::new(pool.shared.clone());
+
+    for number in 1..=window.far + 2 {
+        let expected = window.can_commit(1, number);
+        let package = pool.service.package(usize::max_value(), u64::max_value(), 10);
+        assert_eq!(
+            package
+                .commit_transactions
+                .iter()
+                .any(|&(ref committed, _)| committed == &tx),
+            expected,
+            "pool package for block {}",
+            number
+        );
+        assert_eq!(
+            verifier.verify(&next_block(vec![tx.clone()], &pool)).is_ok(),
+            expected,
+            "commit verifier for block {}",
+            number
+        );
+
+        let prop_ids = if number == 1 {
+            vec![tx.proposal_short_id()]
+        } else {
+            vec![]
+        };
+        apply_transactions(vec![], prop_ids, &mut pool);
+    }
+}
+
+// Work only with the default proposal window, close = 1, far = 10
+#[test]
+fn test_proposal_expired() {
+    let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple();
+
+    let tx = test_transaction(vec![OutPoint::new(pool.tx_hash, 0)], 2);
+    let id = tx.proposal_short_id();
+    pool.service.add_transaction(tx.clone()).unwrap();
+    let status = |pool: &TestPool<_>| {
+        pool.service
+            .get_pool_transaction(&id)
+            .map(|entry| (entry.status, entry.expired_proposals))
+    };
+    assert_eq!(status(&pool), Some((PoolTxStatus::Pending, 0)));
+
+    apply_transactions(vec![], vec![id], &mut pool);
+    // still committable by the 10th block after the proposal
+    for _ in 0..9 {
+        assert_eq!(status(&pool), Some((PoolTxStatus::Mineable, 0)));
+        apply_transactions(vec![], vec![], &mut pool);
+    }
+    assert_eq!(status(&pool), Some((PoolTxStatus::Mineable, 0)));
+
+    apply_transactions(vec![], vec![], &mut pool);
+    assert_eq!(status(&pool), Some((PoolTxStatus::ProposalExpired, 1)));
+    assert_eq!(
+        pool.proposal_expired_receiver.recv(),
+        Some(Arc::new(vec![tx.clone()]))
+    );
+    // proposed again by the next block
+    assert_eq!(pool.service.prepare_proposal(10), vec![id]);
+
+    apply_transactions(vec![], vec![id], &mut pool);
+    assert_eq!(status(&pool), Some((PoolTxStatus::Mineable, 1)));
 }
 
 struct TestPool<CI> {
@@ -459,15 +1120,42 @@ struct TestPool<CI> {
     tx_hash: H256,
     new_tip_receiver: Receiver<MsgNewTip>,
     switch_fork_receiver: Receiver<MsgSwitchFork>,
+    proposal_expired_receiver: Receiver<MsgProposalExpired>,
 }
 
 impl<CI: ChainIndex + 'static> TestPool<CI> {
     fn simple() -> TestPool<ChainKVStore<MemoryKeyValueDB>> {
+        TestPool::<ChainKVStore<MemoryKeyValueDB>>::simple_with_config(test_pool_config())
+    }
+
+    fn simple_with_config(config: PoolConfig) -> TestPool<ChainKVStore<MemoryKeyValueDB>> {
+        let mut pool = TestPool::<ChainKVStore<MemoryKeyValueDB>>::with_config(
+            // the funding transaction is a cellbase, spent right after its block
+            Consensus::default()
+                .set_verification(false)
+                .set_cellbase_maturity(0),
+            config,
+        );
+        let tx = funding_transaction();
+        pool.tx_hash = tx.hash();
+        apply_transactions(vec![tx], vec![], &mut pool);
+        pool
+    }
+
+    fn with_consensus(consensus: Consensus) -> TestPool<ChainKVStore<MemoryKeyValueDB>> {
+        TestPool::<ChainKVStore<MemoryKeyValueDB>>::with_config(consensus, test_pool_config())
+    }
+
+    fn with_config(
+        consensus: Consensus,
+        config: PoolConfig,
+    ) -> TestPool<ChainKVStore<MemoryKeyValueDB>> {
         let (_handle, notify) = NotifyService::default().start::<&str>(None);
         let new_tip_receiver = notify.subscribe_new_tip(TXS_POOL_SUBSCRIBER);
         let switch_fork_receiver = notify.subscribe_switch_fork(TXS_POOL_SUBSCRIBER);
+        let proposal_expired_receiver = notify.subscribe_proposal_expired(TXS_POOL_SUBSCRIBER);
         let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
-            .consensus(Consensus::default().set_verification(false))
+            .consensus(consensus)
             .build();
 
         let (chain_controller, chain_receivers) = ChainController::new();
@@ -476,42 +1164,18 @@ impl<CI: ChainIndex + 'static> TestPool<CI> {
             .build();
         let _handle = chain_service.start::<&str>(None, chain_receivers);
 
-        let tx_pool_service = TransactionPoolService::new(
-            PoolConfig {
-                max_pool_size: 1000,
-                max_orphan_size: 1000,
-                max_proposal_size: 1000,
-                max_cache_size: 1000,
-                max_pending_size: 1000,
-            },
-            shared.clone(),
-            notify.clone(),
-        );
+        let tx_pool_service =
+            TransactionPoolService::new(config, shared.clone(), notify.clone());
 
-        let default_script_hash = create_valid_script().type_hash();
-        let tx = TransactionBuilder::default()
-            .input(CellInput::new(OutPoint::null(), Default::default()))
-            .outputs(vec![
-                CellOutput::new(
-                    100_000_000,
-                    Vec::new(),
-                    default_script_hash.clone(),
-                    None,
-                );
-                100
-            ]).build();
-
-        let transactions = vec![tx.clone()];
-        let mut pool = TestPool {
+        TestPool {
             service: tx_pool_service,
             chain: chain_controller,
             shared,
-            tx_hash: tx.hash(),
+            tx_hash: H256::zero(),
             new_tip_receiver,
             switch_fork_receiver,
-        };
-        apply_transactions(transactions, vec![], &mut pool);
-        pool
+            proposal_expired_receiver,
+        }
     }
 
     fn handle_notify_messages(&mut self) {
@@ -539,10 +1203,52 @@ impl<CI: ChainIndex + 'static> TestPool<CI> {
     }
 }
 
+#[test]
+fn test_rejection_codes() {
+    let errors = vec![
+        PoolError::InvalidTx(TransactionError::Empty),
+        PoolError::InvalidTx(TransactionError::DoubleSpent),
+        PoolError::AlreadyInPool,
+        PoolError::DoubleSpent,
+        PoolError::DeadCell(OutPoint::null()),
+        PoolError::OverCapacity,
+        PoolError::DuplicateOutput,
+        PoolError::CellBase,
+        PoolError::TimeOut,
+        PoolError::InvalidBlockNumber,
+    ];
+    let codes: Vec<u32> = errors.iter().map(PoolError::code).collect();
+    // the codes are part of the RPC and of the relay protocol, they must never change
+    assert_eq!(
+        codes,
+        vec![104, 111, 201, 202, 203, 204, 205, 206, 207, 208]
+    );
+}
+
+fn test_pool_config() -> PoolConfig {
+    PoolConfig {
+        max_pool_size: 1000,
+        max_orphan_size: 1000,
+        max_proposal_size: 1000,
+        max_cache_size: 1000,
+        max_pending_size: 1000,
+        max_rejects_size: 1000,
+    }
+}
+
 fn apply_transactions<CI: ChainIndex + 'static>(
     transactions: Vec<Transaction>,
     prop_ids: Vec<ProposalShortId>,
     pool: &mut TestPool<CI>,
+) -> Block {
+    apply_transactions_with_uncles(transactions, vec![], prop_ids, pool)
+}
+
+fn apply_transactions_with_uncles<CI: ChainIndex + 'static>(
+    transactions: Vec<Transaction>,
+    uncles: Vec<UncleBlock>,
+    prop_ids: Vec<ProposalShortId>,
+    pool: &mut TestPool<CI>,
 ) -> Block {
     let cellbase_id = if let Some(cellbase) = transactions.first() {
         cellbase.hash()
@@ -561,6 +1267,7 @@ fn apply_transactions<CI: ChainIndex + 'static>(
 
     let block = BlockBuilder::default()
         .commit_transactions(transactions)
+        .uncles(uncles)
         .proposal_transactions(prop_ids)
         .with_header_builder(header_builder);
 
@@ -569,6 +1276,42 @@ fn apply_transactions<CI: ChainIndex + 'static>(
     block
 }
 
+// A block on top of the tip committing `transactions` after a cellbase, it isn't processed
+fn next_block<CI: ChainIndex + 'static>(
+    transactions: Vec<Transaction>,
+    pool: &TestPool<CI>,
+) -> Block {
+    let parent = pool.shared.tip_header().read().inner().clone();
+    let number = parent.number() + 1;
+    let cellbase = TransactionBuilder::default()
+        .input(CellInput::new_cellbase_input(number))
+        .build();
+
+    BlockBuilder::default()
+        .commit_transaction(cellbase)
+        .commit_transactions(transactions)
+        .with_header_builder(
+            HeaderBuilder::default()
+                .parent_hash(&parent.hash())
+                .number(number),
+        )
+}
+
+fn funding_transaction() -> Transaction {
+    let default_script_hash = create_valid_script().type_hash();
+    TransactionBuilder::default()
+        .input(CellInput::new(OutPoint::null(), Default::default()))
+        .outputs(vec![
+            CellOutput::new(
+                100_000_000,
+                Vec::new(),
+                default_script_hash,
+                None,
+            );
+            100
+        ]).build()
+}
+
 fn test_transaction(input_values: Vec<OutPoint>, output_num: usize) -> Transaction {
     test_transaction_with_capacity(input_values, output_num, 100_000)
 }
@@ -5,5 +5,6 @@ pub mod types;
 
 pub use self::pool::{TransactionPoolController, TransactionPoolService};
 pub use self::types::{
-    Orphan, PendingQueue, Pool, PoolConfig, PoolError, ProposedQueue, TxStage, TxoStatus,
+    FeeRate, InsertionResult, Orphan, PendingQueue, Pool, PoolConfig, PoolError, PoolTransaction,
+    PoolTxStatus, ProposedQueue, RecentRejects, TxStage, TxoStatus, TxsPackage,
 };
@@ -1,82 +1,105 @@
 //! Top-level Pool type, methods, and tests
 use super::types::{
-    InsertionResult, Orphan, PendingQueue, Pool, PoolConfig, PoolError, ProposedQueue, TxStage,
-    TxoStatus,
+    FeeRate, InsertionResult, Orphan, PendingQueue, Pool, PoolConfig, PoolError, PoolTransaction,
+    PoolTxStatus, ProposedQueue, RecentRejects, TxStage, TxoStatus, TxsPackage,
 };
 use bigint::H256;
 use channel::{self, Receiver, Sender};
 use ckb_core::block::Block;
-use ckb_core::cell::{CellProvider, CellStatus};
+use ckb_core::cell::{CellProvider, CellStatus, ResolvedTransaction};
 use ckb_core::service::{Request, DEFAULT_CHANNEL_SIZE};
-use ckb_core::transaction::{OutPoint, ProposalShortId, Transaction};
-use ckb_notify::{ForkBlocks, MsgNewTip, MsgSwitchFork, NotifyController, TXS_POOL_SUBSCRIBER};
+use ckb_core::transaction::{Capacity, OutPoint, ProposalShortId, Transaction};
+use ckb_notify::{
+    coalesce_new_tips, ForkBlocks, MsgNewTip, MsgSwitchFork, NotifyController,
+    TXS_POOL_SUBSCRIBER,
+};
 use ckb_shared::index::ChainIndex;
 use ckb_shared::shared::{ChainProvider, Shared};
-use ckb_verification::{TransactionError, TransactionVerifier};
+use ckb_time::now_ms;
+use ckb_verification::{DataSizeVerifier, MaturityVerifier, TransactionError, TransactionVerifier};
+use fnv::FnvHashSet;
 use lru_cache::LruCache;
+use std::cmp;
+use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 
-#[cfg(test)]
-use ckb_core::BlockNumber;
+use ckb_core::{BlockNumber, Cycle};
+
+/// Milliseconds an orphan waits for its parents before it is dropped
+const ORPHAN_TX_EXPIRY: u64 = 20 * 60 * 1000;
 
-pub type TxsArgs = (usize, usize);
-pub type TxsReturn = (Vec<ProposalShortId>, Vec<Transaction>);
+/// (max_bytes, max_cycles, max_proposals)
+pub type PackageArgs = (usize, u64, usize);
 
 #[derive(Clone)]
 pub struct TransactionPoolController {
-    get_proposal_commit_transactions_sender: Sender<Request<TxsArgs, TxsReturn>>,
+    package_sender: Sender<Request<PackageArgs, TxsPackage>>,
     get_potential_transactions_sender: Sender<Request<(), Vec<Transaction>>>,
     contains_key_sender: Sender<Request<ProposalShortId, bool>>,
     get_transaction_sender: Sender<Request<ProposalShortId, Option<Transaction>>>,
+    get_transactions_sender: Sender<Request<Vec<ProposalShortId>, Vec<Transaction>>>,
+    get_pool_transaction_sender: Sender<Request<ProposalShortId, Option<PoolTransaction>>>,
+    get_top_n_by_fee_sender: Sender<Request<usize, Vec<Transaction>>>,
     add_transaction_sender: Sender<Request<Transaction, Result<InsertionResult, PoolError>>>,
 }
 
 pub struct TransactionPoolReceivers {
-    get_proposal_commit_transactions_receiver: Receiver<Request<TxsArgs, TxsReturn>>,
+    package_receiver: Receiver<Request<PackageArgs, TxsPackage>>,
     get_potential_transactions_receiver: Receiver<Request<(), Vec<Transaction>>>,
     contains_key_receiver: Receiver<Request<ProposalShortId, bool>>,
     get_transaction_receiver: Receiver<Request<ProposalShortId, Option<Transaction>>>,
+    get_transactions_receiver: Receiver<Request<Vec<ProposalShortId>, Vec<Transaction>>>,
+    get_pool_transaction_receiver: Receiver<Request<ProposalShortId, Option<PoolTransaction>>>,
+    get_top_n_by_fee_receiver: Receiver<Request<usize, Vec<Transaction>>>,
     add_transaction_receiver: Receiver<Request<Transaction, Result<InsertionResult, PoolError>>>,
 }
 
 impl TransactionPoolController {
     pub fn new() -> (TransactionPoolController, TransactionPoolReceivers) {
-        let (get_proposal_commit_transactions_sender, get_proposal_commit_transactions_receiver) =
-            channel::bounded(DEFAULT_CHANNEL_SIZE);
+        let (package_sender, package_receiver) = channel::bounded(DEFAULT_CHANNEL_SIZE);
         let (get_potential_transactions_sender, get_potential_transactions_receiver) =
             channel::bounded(DEFAULT_CHANNEL_SIZE);
         let (contains_key_sender, contains_key_receiver) = channel::bounded(DEFAULT_CHANNEL_SIZE);
         let (get_transaction_sender, get_transaction_receiver) =
             channel::bounded(DEFAULT_CHANNEL_SIZE);
+        let (get_transactions_sender, get_transactions_receiver) =
+            channel::bounded(DEFAULT_CHANNEL_SIZE);
+        let (get_pool_transaction_sender, get_pool_transaction_receiver) =
+            channel::bounded(DEFAULT_CHANNEL_SIZE);
+        let (get_top_n_by_fee_sender, get_top_n_by_fee_receiver) =
+            channel::bounded(DEFAULT_CHANNEL_SIZE);
         let (add_transaction_sender, add_transaction_receiver) =
             channel::bounded(DEFAULT_CHANNEL_SIZE);
         (
             TransactionPoolController {
-                get_proposal_commit_transactions_sender,
+                package_sender,
                 get_potential_transactions_sender,
                 contains_key_sender,
                 get_transaction_sender,
+                get_transactions_sender,
+                get_pool_transaction_sender,
+                get_top_n_by_fee_sender,
                 add_transaction_sender,
             },
             TransactionPoolReceivers {
-                get_proposal_commit_transactions_receiver,
+                package_receiver,
                 get_potential_transactions_receiver,
                 contains_key_receiver,
                 get_transaction_receiver,
+                get_transactions_receiver,
+                get_pool_transaction_receiver,
+                get_top_n_by_fee_receiver,
                 add_transaction_receiver,
             },
         )
     }
 
-    pub fn get_proposal_commit_transactions(
-        &self,
-        max_prop: usize,
-        max_tx: usize,
-    ) -> (Vec<ProposalShortId>, Vec<Transaction>) {
+    /// Select the transactions for the next block, see `TransactionPoolService::package`.
+    pub fn package(&self, max_bytes: usize, max_cycles: u64, max_proposals: usize) -> TxsPackage {
         Request::call(
-            &self.get_proposal_commit_transactions_sender,
-            (max_prop, max_tx),
-        ).expect("get_proposal_commit_transactions() failed")
+            &self.package_sender,
+            (max_bytes, max_cycles, max_proposals),
+        ).expect("package() failed")
     }
 
     pub fn get_potential_transactions(&self) -> Vec<Transaction> {
@@ -92,6 +115,21 @@ impl TransactionPoolController {
         Request::call(&self.get_transaction_sender, id).expect("get_transaction() failed")
     }
 
+    /// The known transactions among `ids` in one request, pending, proposed or in the pool
+    pub fn get_transactions(&self, ids: Vec<ProposalShortId>) -> Vec<Transaction> {
+        Request::call(&self.get_transactions_sender, ids).expect("get_transactions() failed")
+    }
+
+    /// The transaction and where it stands in the pool
+    pub fn get_pool_transaction(&self, id: ProposalShortId) -> Option<PoolTransaction> {
+        Request::call(&self.get_pool_transaction_sender, id).expect("get_pool_transaction() failed")
+    }
+
+    /// At most `n` commit-ready transactions, the best paying per byte first
+    pub fn get_top_n_by_fee(&self, n: usize) -> Vec<Transaction> {
+        Request::call(&self.get_top_n_by_fee_sender, n).expect("get_top_n_by_fee() failed")
+    }
+
     pub fn add_transaction(&self, tx: Transaction) -> Result<InsertionResult, PoolError> {
         Request::call(&self.add_transaction_sender, tx).expect("add_transaction() failed")
     }
@@ -110,6 +148,13 @@ pub struct TransactionPoolService<CI> {
     orphan: Orphan,
     /// cache for conflict transaction
     cache: LruCache<ProposalShortId, Transaction>,
+    /// Transactions recently rejected, not verified again
+    recent_rejects: RecentRejects,
+    /// How often the proposals of uncommitted transactions expired
+    expired_proposals: LruCache<ProposalShortId, u32>,
+    /// The last block reconciled into the pool
+    tip_hash: H256,
+    tip_number: BlockNumber,
 
     shared: Shared<CI>,
     notify: NotifyController,
@@ -142,18 +187,28 @@ where
         shared: Shared<CI>,
         notify: NotifyController,
     ) -> TransactionPoolService<CI> {
-        let n = shared.tip_header().read().number();
+        let (n, tip_hash) = {
+            let tip_header = shared.tip_header().read();
+            (tip_header.number(), tip_header.hash())
+        };
         let cache_size = config.max_cache_size;
-        let prop_cap = ProposedQueue::cap();
+        let rejects_size = config.max_rejects_size;
+        let pending_size = config.max_pending_size;
+        let proposal_window = shared.consensus().proposal_window();
+        let prop_cap = ProposedQueue::cap(proposal_window);
         let ids = shared.union_proposal_ids_n(n, prop_cap);
 
         TransactionPoolService {
             config,
             pending: PendingQueue::new(),
-            proposed: ProposedQueue::new(n, ids),
+            proposed: ProposedQueue::new(proposal_window, n, ids),
             pool: Pool::new(),
             orphan: Orphan::new(),
             cache: LruCache::new(cache_size, false),
+            recent_rejects: RecentRejects::new(rejects_size),
+            expired_proposals: LruCache::new(pending_size, false),
+            tip_hash,
+            tip_number: n,
             shared,
             notify,
         }
@@ -175,11 +230,18 @@ where
         thread_builder
             .spawn(move || loop {
                 let failed = select!{
-                    recv(new_tip_receiver, msg) => self.handle_new_tip(msg),
+                    recv(new_tip_receiver, msg) => self.handle_new_tip(&new_tip_receiver, msg),
                     recv(switch_fork_receiver, msg) => self.handle_switch_fork(msg),
 
-                    recv(receivers.get_proposal_commit_transactions_receiver, msg) => {
-                        self.handle_get_proposal_commit_transactions(msg)
+                    recv(receivers.package_receiver, msg) => match msg {
+                        Some(Request { responder, arguments: (max_bytes, max_cycles, max_proposals) }) => {
+                            responder.send(self.package(max_bytes, max_cycles, max_proposals));
+                            false
+                        }
+                        None => {
+                            error!(target: "txs_pool", "channel package_receiver closed");
+                            true
+                        }
                     }
                     recv(receivers.get_potential_transactions_receiver, msg) => match msg {
                         Some(Request { responder, ..}) => {
@@ -211,6 +273,36 @@ where
                             true
                         }
                     }
+                    recv(receivers.get_transactions_receiver, msg) => match msg {
+                        Some(Request { responder, arguments: ids }) => {
+                            responder.send(self.get_transactions(&ids));
+                            false
+                        }
+                        None => {
+                            error!(target: "txs_pool", "channel get_transactions_receiver closed");
+                            true
+                        }
+                    }
+                    recv(receivers.get_pool_transaction_receiver, msg) => match msg {
+                        Some(Request { responder, arguments: id }) => {
+                            responder.send(self.get_pool_transaction(&id));
+                            false
+                        }
+                        None => {
+                            error!(target: "txs_pool", "channel get_pool_transaction_receiver closed");
+                            true
+                        }
+                    }
+                    recv(receivers.get_top_n_by_fee_receiver, msg) => match msg {
+                        Some(Request { responder, arguments: n }) => {
+                            responder.send(self.get_top_n_by_fee(n));
+                            false
+                        }
+                        None => {
+                            error!(target: "txs_pool", "channel get_top_n_by_fee_receiver closed");
+                            true
+                        }
+                    }
                     recv(receivers.add_transaction_receiver, msg) => match msg {
                         Some(Request { responder, arguments: tx }) => {
                             responder.send(self.add_transaction(tx));
@@ -228,9 +320,16 @@ where
             }).expect("Start TransactionPoolService failed!")
     }
 
-    fn handle_new_tip(&mut self, msg: Option<MsgNewTip>) -> bool {
+    fn handle_new_tip(
+        &mut self,
+        new_tip_receiver: &Receiver<MsgNewTip>,
+        msg: Option<MsgNewTip>,
+    ) -> bool {
         match msg {
-            Some(block) => self.reconcile_block(&block),
+            Some(block) => {
+                let blocks = coalesce_new_tips(new_tip_receiver, block);
+                self.reconcile_blocks(&blocks);
+            }
             None => {
                 error!(target: "txs_pool", "channel new_tip_receiver closed");
                 return true;
@@ -250,32 +349,17 @@ where
         false
     }
 
-    fn handle_get_proposal_commit_transactions(
-        &self,
-        msg: Option<Request<TxsArgs, TxsReturn>>,
-    ) -> bool {
-        match msg {
-            Some(Request {
-                responder,
-                arguments: (max_prop, max_tx),
-            }) => {
-                let proposal_transactions = self.prepare_proposal(max_prop);
-                let commit_transactions = self.get_mineable_transactions(max_tx);
-                responder.send((proposal_transactions, commit_transactions));
-                false
-            }
-            None => {
-                error!(target: "txs_pool", "channel get_proposal_commit_transactions_receiver closed");
-                true
-            }
-        }
-    }
-
     pub(crate) fn switch_fork(&mut self, blks: &ForkBlocks) {
+        // the old blocks come from the tip down, their transactions are brought back in chain order
+        let detached: Vec<Transaction> = blks
+            .old_blks()
+            .iter()
+            .rev()
+            .flat_map(|b| b.commit_transactions().iter().cloned())
+            .collect();
+
         for b in blks.old_blks() {
             let bn = b.header().number();
-            let mut txs = b.commit_transactions().to_vec();
-            txs.reverse();
 
             //remove proposed id, txs can be already in pool
             if let Some(rm_txs) = self.proposed.remove(bn) {
@@ -311,20 +395,79 @@ where
                     }
                 }
             }
-
-            //readd txs
-            for tx in txs {
-                if tx.is_cellbase() {
-                    continue;
-                }
-                self.pool.add_transaction(tx.clone());
-            }
         }
 
         // We may not need readd timeout transactions in pool, because new main chain is mostly longer
         for blk in blks.new_blks() {
             self.reconcile_block(&blk);
         }
+
+        let committed: FnvHashSet<H256> = blks
+            .new_blks()
+            .iter()
+            .flat_map(|b| b.commit_transactions().iter().map(|tx| tx.hash()))
+            .collect();
+        self.resurrect_transactions(detached, &committed);
+    }
+
+    // Checks the detached transactions the new chain doesn't commit against the new tip. Those
+    // proposed on the new chain go back to the pool, the others wait in pending for a new
+    // proposal, along with their descendants. Those spending a cell the new chain spent are
+    // dropped with their descendants.
+    fn resurrect_transactions(&mut self, detached: Vec<Transaction>, committed: &FnvHashSet<H256>) {
+        // detached transactions kept out of the pool, their children can't be mined before them
+        let mut held = FnvHashSet::default();
+        for tx in detached {
+            if tx.is_cellbase() || committed.contains(&tx.hash()) {
+                continue;
+            }
+            let tx_hash = tx.hash();
+            let rtx = self.resolve_transaction(&tx);
+            let has_held_parent = tx
+                .input_pts()
+                .iter()
+                .chain(tx.dep_pts().iter())
+                .any(|o| held.contains(&o.hash));
+            if rtx.is_double_spend() || (rtx.is_orphan() && !has_held_parent) {
+                let descendants = self.pool.remove_descendants(&tx);
+                debug!(target: "txs_pool", "drop detached transaction {} and {} descendants, they conflict with the new chain", tx_hash, descendants.len());
+                continue;
+            }
+
+            let stage = if has_held_parent {
+                TxStage::Unknown(tx)
+            } else {
+                self.proposed.insert(tx)
+            };
+            let tx = match stage {
+                TxStage::Mineable(tx) => {
+                    // the reorg cleared the verification cache, the scripts run again for
+                    // the cycles
+                    match self.verify_with_fee(&rtx) {
+                        Ok((fee, cycles)) => {
+                            self.pool.readd_transaction(tx, fee, cycles);
+                        }
+                        Err(e) => {
+                            let descendants = self.pool.remove_descendants(&tx);
+                            debug!(target: "txs_pool", "drop detached transaction {} and {} descendants: {:?}", tx_hash, descendants.len(), e);
+                        }
+                    }
+                    continue;
+                }
+                TxStage::Unknown(tx) => {
+                    self.pending.insert(tx.proposal_short_id(), tx.clone());
+                    tx
+                }
+                // waiting in the proposal queue
+                _ => rtx.transaction,
+            };
+            held.insert(tx_hash);
+            for descendant in self.pool.remove_descendants(&tx) {
+                held.insert(descendant.hash());
+                self.pending
+                    .insert(descendant.proposal_short_id(), descendant);
+            }
+        }
     }
 
     fn contains_key(&self, id: &ProposalShortId) -> bool {
@@ -345,6 +488,36 @@ where
             .or_else(|| self.cache.get(id).cloned())
     }
 
+    pub(crate) fn get_pool_transaction(&self, id: &ProposalShortId) -> Option<PoolTransaction> {
+        let expired_proposals = self.expired_proposals.get(id).cloned().unwrap_or(0);
+        let (transaction, status) = if let Some(tx) = self.pending.get(id) {
+            if expired_proposals > 0 {
+                (tx, PoolTxStatus::ProposalExpired)
+            } else {
+                (tx, PoolTxStatus::Pending)
+            }
+        } else if let Some(tx) = self.proposed.get(id) {
+            (tx, PoolTxStatus::Proposed)
+        } else if let Some(tx) = self.pool.get(id) {
+            (tx, PoolTxStatus::Mineable)
+        } else if let Some(tx) = self.orphan.get(id) {
+            (tx, PoolTxStatus::Orphan)
+        } else if let Some(tx) = self.cache.get(id) {
+            (tx, PoolTxStatus::Conflict)
+        } else {
+            return None;
+        };
+        Some(PoolTransaction {
+            transaction: transaction.clone(),
+            status,
+            expired_proposals,
+        })
+    }
+
+    pub(crate) fn get_top_n_by_fee(&self, n: usize) -> Vec<Transaction> {
+        self.pool.get_top_n_by_fee(n)
+    }
+
     /// Get the size of transactions in the pool
     pub(crate) fn pool_size(&self) -> usize {
         self.pool.size()
@@ -376,25 +549,63 @@ where
         self.cache.len()
     }
 
-    /// Get the total size (transactions + orphans) of the pool
+    pub(crate) fn rejects_size(&self) -> usize {
+        self.recent_rejects.len()
+    }
+
+    /// Get the total size of the verified transactions in the pool, the orphans are bounded
+    /// on their own by `max_orphan_size`
     pub(crate) fn total_size(&self) -> usize {
-        self.pool_size() + self.orphan_size()
+        self.pool_size()
     }
 
     pub(crate) fn add_transaction(
         &mut self,
         tx: Transaction,
     ) -> Result<InsertionResult, PoolError> {
+        let tx_hash = tx.hash();
+        if let Some(error) = self.recent_rejects.get(&tx_hash) {
+            return Err(error.clone());
+        }
+        // even a transaction waiting for its proposal is held in memory, check it first
+        if let Err(error) = self.check_size(&tx) {
+            self.recent_rejects.insert(tx_hash, &error);
+            return Err(error);
+        }
+        let id = tx.proposal_short_id();
+        // the buffer of the proposed transactions is limited like the pending queue, a new
+        // transaction is refused before it takes a place
+        if self.proposed.is_too_early(&id)
+            && self.proposed.size() >= self.config.max_proposal_size
+            && !self.proposed.contains_key(&id)
+        {
+            return Err(PoolError::OverCapacity);
+        }
         match { self.proposed.insert(tx) } {
-            TxStage::Mineable(x) => self.add_to_pool(x),
+            TxStage::Mineable(x) => self.add_to_pool(x).map_err(|error| {
+                self.recent_rejects.insert(tx_hash, &error);
+                error
+            }),
             TxStage::Unknown(x) => {
-                self.pending.insert(x.proposal_short_id(), x);
+                // the queues waiting for a proposal are limited on their own, unlike the pool
+                // nothing tells which of their transactions is worth more
+                if self.pending.size() >= self.config.max_pending_size
+                    && !self.pending.contains_key(&id)
+                {
+                    return Err(PoolError::OverCapacity);
+                }
+                self.pending.insert(id, x);
                 Ok(InsertionResult::Unknown)
             }
             _ => Ok(InsertionResult::Proposed),
         }
     }
 
+    /// The transactions of `ids` known to the pool, whatever their stage
+    pub(crate) fn get_transactions(&self, ids: &[ProposalShortId]) -> Vec<Transaction> {
+        ids.iter().filter_map(|id| self.get(id)).collect()
+    }
+
     pub(crate) fn prepare_proposal(&self, n: usize) -> Vec<ProposalShortId> {
         self.pending.fetch(n)
     }
@@ -413,6 +624,34 @@ where
         };
     }
 
+    /// Select proposal ids and commit candidates for the block on top of the last
+    /// reconciled tip. Commit transactions are topologically ordered, their total
+    /// serialized size stays within `max_bytes`, their outputs data within the
+    /// data budget of a block and their scripts within `max_cycles` and the cycles budget
+    /// of a block.
+    pub(crate) fn package(
+        &self,
+        max_bytes: usize,
+        max_cycles: Cycle,
+        max_proposals: usize,
+    ) -> TxsPackage {
+        let consensus = self.shared.consensus();
+        let max_data_bytes = consensus.max_block_data_bytes();
+        let max_cycles = cmp::min(max_cycles, consensus.max_block_cycles());
+        TxsPackage {
+            tip_hash: self.tip_hash,
+            tip_number: self.tip_number,
+            proposal_transactions: self.prepare_proposal(max_proposals),
+            commit_transactions: self.pool.package_transactions(
+                max_bytes,
+                max_data_bytes,
+                max_cycles,
+            ),
+        }
+    }
+
+    /// NOTE: may remove this method later
+    #[cfg(test)]
     pub(crate) fn get_mineable_transactions(&self, max: usize) -> Vec<Transaction> {
         self.pool.get_mineable_transactions(max)
     }
@@ -424,9 +663,6 @@ where
 
     /// Attempts to add a transaction to the memory pool.
     pub(crate) fn add_to_pool(&mut self, tx: Transaction) -> Result<InsertionResult, PoolError> {
-        // Do we have the capacity to accept this transaction?
-        self.is_acceptable()?;
-
         if tx.is_cellbase() {
             return Err(PoolError::CellBase);
         }
@@ -438,28 +674,26 @@ where
 
         let mut unknowns = Vec::new();
 
-        {
+        let (fee, cycles) = {
             let rtx = self.resolve_transaction(&tx);
 
-            for (i, cs) in rtx.input_cells.iter().enumerate() {
-                match cs {
-                    CellStatus::Unknown => {
-                        unknowns.push(inputs[i]);
-                    }
-                    CellStatus::Old => {
-                        self.cache.insert(tx.proposal_short_id(), tx);
-                        return Err(PoolError::DoubleSpent);
-                    }
-                    _ => {}
-                }
-            }
-
-            for (i, cs) in rtx.dep_cells.iter().enumerate() {
+            // Unknown cells may come later, the transaction is held as orphan. Old cells are
+            // either spent by another pool transaction, which may be undone by a fork, or
+            // already dead on chain, then the transaction is rejected for good.
+            let cells = rtx
+                .input_cells
+                .iter()
+                .zip(inputs.iter())
+                .chain(rtx.dep_cells.iter().zip(deps.iter()));
+            for (cs, out_point) in cells {
                 match cs {
                     CellStatus::Unknown => {
-                        unknowns.push(deps[i]);
+                        unknowns.push(*out_point);
                     }
                     CellStatus::Old => {
+                        if self.shared.cell(out_point).is_old() {
+                            return Err(PoolError::DeadCell(*out_point));
+                        }
                         self.cache.insert(tx.proposal_short_id(), tx);
                         return Err(PoolError::DoubleSpent);
                     }
@@ -467,19 +701,32 @@ where
                 }
             }
 
+            // TODO: Parallel
+            // the fee is only known once all the inputs are
             if unknowns.is_empty() {
-                // TODO: Parallel
-                TransactionVerifier::new(&rtx)
-                    .verify()
-                    .map_err(PoolError::InvalidTx)?;
+                self.verify_with_fee(&rtx).map_err(PoolError::InvalidTx)?
+            } else {
+                (0, 0)
             }
-        }
+        };
 
         if !unknowns.is_empty() {
-            self.orphan.add_transaction(tx, unknowns.into_iter());
-            return Ok(InsertionResult::Orphan);
+            // the fee of an orphan is unknown, it can't take the place of anything
+            self.is_acceptable()?;
+            let now = now_ms();
+            self.orphan.expire(now.saturating_sub(ORPHAN_TX_EXPIRY));
+            // the orphans are bounded on their own, the oldest make room for the new ones
+            while self.orphan.size() >= self.config.max_orphan_size {
+                if self.orphan.evict_oldest().is_none() {
+                    return Err(PoolError::OverCapacity);
+                }
+            }
+            self.orphan
+                .add_transaction(tx, unknowns.iter().cloned(), now);
+            return Ok(InsertionResult::Orphan(unknowns));
         } else {
-            self.pool.add_transaction(tx.clone());
+            self.make_room(&tx, fee)?;
+            self.pool.add_transaction_with_fee(tx.clone(), fee, cycles);
 
             self.reconcile_orphan(&tx);
 
@@ -495,11 +742,14 @@ where
 
         for tx in txs {
             let rtx = self.resolve_transaction(&tx);
-            let rs = TransactionVerifier::new(&rtx).verify();
-            if rs.is_ok() {
-                self.pool.add_transaction(tx);
-            } else if rs == Err(TransactionError::DoubleSpent) {
-                self.cache.insert(tx.proposal_short_id(), tx);
+            match self.verify_with_fee(&rtx) {
+                Ok((fee, cycles)) => {
+                    self.pool.add_transaction_with_fee(tx, fee, cycles);
+                }
+                Err(TransactionError::DoubleSpent) => {
+                    self.cache.insert(tx.proposal_short_id(), tx);
+                }
+                Err(_) => {}
             }
         }
     }
@@ -507,9 +757,36 @@ where
     /// Updates the pool with the details of a new block.
     // TODO: call it in order
     pub(crate) fn reconcile_block(&mut self, b: &Block) {
+        let new_txs = self.reconcile_proposals(b);
+        self.stage_proposed(new_txs);
+    }
+
+    /// Reconciles a burst of connected blocks, the transactions they propose are only staged
+    /// into the pool once all are reconciled.
+    pub(crate) fn reconcile_blocks(&mut self, blocks: &[MsgNewTip]) {
+        let mut new_txs = Vec::new();
+        for b in blocks {
+            new_txs.extend(self.reconcile_proposals(b));
+        }
+        // skip those committed later in the burst, their inputs are dead by now
+        let committed: FnvHashSet<H256> = blocks
+            .iter()
+            .flat_map(|b| b.commit_transactions().iter().map(|tx| tx.hash()))
+            .collect();
+        new_txs.retain(|tx| !committed.contains(&tx.hash()));
+        self.stage_proposed(new_txs);
+    }
+
+    // Updates the queues with the block and returns the transactions newly proposed
+    fn reconcile_proposals(&mut self, b: &Block) -> Vec<Transaction> {
         let txs = b.commit_transactions();
         let bn = b.header().number();
         let ids = b.union_proposal_ids();
+        self.tip_hash = b.header().hash();
+        self.tip_number = bn;
+        self.recent_rejects.clear_temporary();
+        self.orphan
+            .expire(now_ms().saturating_sub(ORPHAN_TX_EXPIRY));
 
         // must do this first
         {
@@ -530,40 +807,70 @@ where
                 }
 
                 self.pool.commit_transaction(tx);
+                self.expired_proposals.remove(&tx.proposal_short_id());
             }
         }
 
+        // the proposals closing with this block, their transactions go back to pending
+        let mut expired = Vec::new();
         {
             if let Some(time_out_ids) = self.proposed.mineable_front() {
                 for id in time_out_ids {
                     if let Some(txs) = self.pool.remove(id) {
                         for tx in txs {
+                            expired.push(tx.clone());
                             self.pending.insert(tx.proposal_short_id(), tx);
                         }
                     } else if let Some(tx) = self.orphan.remove(id) {
+                        expired.push(tx.clone());
                         self.pending.insert(tx.proposal_short_id(), tx);
                     }
                 }
             }
         }
 
-        let new_txs = {
+        {
             for id in &ids {
                 if let Some(tx) = self.pending.remove(id).or_else(|| self.cache.remove(id)) {
                     self.proposed.insert_without_check(id.clone(), tx);
                 }
             }
+        }
 
-            self.proposed.reconcile(bn, ids).unwrap_or_else(|error| {
-                error!(target: "txs_pool", "Failed to proposed reconcile {:?}", error);
-                vec![]
-            })
-        };
+        let new_txs = self.proposed.reconcile(bn, ids).unwrap_or_else(|error| {
+            error!(target: "txs_pool", "Failed to proposed reconcile {:?}", error);
+            vec![]
+        });
+
+        for tx in self.proposed.gc() {
+            expired.push(tx.clone());
+            self.pending.insert(tx.proposal_short_id(), tx);
+        }
+        self.expire_proposals(expired);
+        new_txs
+    }
+
+    // Marks the transactions whose proposals expired, they are pending again and proposed with
+    // the next block template
+    fn expire_proposals(&mut self, txs: Vec<Transaction>) {
+        if txs.is_empty() {
+            return;
+        }
+        for tx in &txs {
+            let id = tx.proposal_short_id();
+            let count = self.expired_proposals.get(&id).cloned().unwrap_or(0) + 1;
+            self.expired_proposals.insert(id, count);
+            warn!(target: "txs_pool", "Proposal of tx {} expired before commit ({} times), re-proposing", tx.hash(), count);
+        }
+        self.notify.notify_proposal_expired(Arc::new(txs));
+    }
 
+    fn stage_proposed(&mut self, new_txs: Vec<Transaction>) {
         // We can sort it by some rules
         for tx in new_txs {
             let tx_hash = tx.hash();
             if let Err(error) = self.add_to_pool(tx) {
+                self.recent_rejects.insert(tx_hash, &error);
                 error!(target: "txs_pool", "Failed to add proposed tx {:} to pool, reason: {:?}", tx_hash, error);
             }
         }
@@ -580,13 +887,99 @@ where
 
     /// Whether the pool is full
     fn is_acceptable(&self) -> Result<(), PoolError> {
-        if self.total_size() > self.config.max_pool_size {
-            // TODO evict old/large transactions instead
+        if self.total_size() >= self.config.max_pool_size {
             return Err(PoolError::OverCapacity);
         }
         Ok(())
     }
 
+    /// Evicts the transactions paying less per byte than `tx` until there is room for it, the
+    /// transactions spending an evicted one go with it. The pool ancestors of `tx` and their
+    /// descendants are kept. Fails if the pool is full of better paying or kept transactions.
+    fn make_room(&mut self, tx: &Transaction, fee: Capacity) -> Result<(), PoolError> {
+        let fee_rate = FeeRate::new(fee, tx.serialized_size() as usize);
+        let kept = self.pool.ancestors_and_descendants(tx);
+        while self.total_size() >= self.config.max_pool_size {
+            match self.pool.lowest_fee_rate_except(&kept) {
+                Some((id, lowest)) if lowest < fee_rate => {
+                    for tx in self.pool.remove(&id).unwrap_or_default() {
+                        debug!(target: "txs_pool", "Evict tx {} from the full pool", tx.hash());
+                    }
+                }
+                _ => return Err(PoolError::OverCapacity),
+            }
+        }
+        Ok(())
+    }
+
+    /// The highest script version active for the next block, pool transactions are
+    /// verified as if they were committed in it.
+    fn max_script_version(&self) -> u8 {
+        let consensus = self.shared.consensus();
+        consensus
+            .hardfork_switch()
+            .max_script_version(consensus.epoch_number(self.tip_number + 1))
+    }
+
+    fn max_cell_data_bytes(&self) -> u64 {
+        self.shared.consensus().max_cell_data_bytes()
+    }
+
+    // The cycles of a transaction whose cells are all resolved. Like the sizes in
+    // `check_size`, scripts costing more than a block allows can't be committed, the VM
+    // stops them.
+    fn verify_transaction(&self, rtx: &ResolvedTransaction) -> Result<Cycle, TransactionError> {
+        let consensus = self.shared.consensus();
+        // the cellbase outputs are on chain, the pool outputs never come from one
+        MaturityVerifier::new(
+            &rtx.transaction,
+            &self.shared,
+            self.tip_number + 1,
+            consensus.cellbase_maturity_at(self.tip_number + 1),
+        ).verify()?;
+        TransactionVerifier::new(
+            rtx,
+            self.max_script_version(),
+            self.max_cell_data_bytes(),
+            consensus.max_block_cycles(),
+            consensus.chain_id(),
+        ).verify_with_cache(self.shared.txs_verify_cache())
+    }
+
+    // The fee and the cycles of a transaction whose cells are all resolved
+    fn verify_with_fee(
+        &self,
+        rtx: &ResolvedTransaction,
+    ) -> Result<(Capacity, Cycle), TransactionError> {
+        let cycles = self.verify_transaction(rtx)?;
+        Ok((transaction_fee(rtx)?, cycles))
+    }
+
+    // A transaction over the consensus limits can't be committed in any block
+    fn check_size(&self, tx: &Transaction) -> Result<(), PoolError> {
+        let consensus = self.shared.consensus();
+        let max = consensus.max_tx_bytes();
+        let actual = tx.serialized_size();
+        if actual > max {
+            return Err(PoolError::InvalidTx(TransactionError::ExceededMaximumBytes {
+                max,
+                actual,
+            }));
+        }
+        DataSizeVerifier::new(tx, consensus.max_cell_data_bytes())
+            .verify()
+            .map_err(PoolError::InvalidTx)?;
+        let max = consensus.max_block_data_bytes();
+        let actual = tx.outputs_data_bytes();
+        if actual > max {
+            return Err(PoolError::InvalidTx(TransactionError::ExceededMaximumDataBytes {
+                max,
+                actual,
+            }));
+        }
+        Ok(())
+    }
+
     // Check that the transaction is not in the pool or chain
     fn check_duplicate(&self, tx: &Transaction) -> Result<(), PoolError> {
         let h = tx.hash();
@@ -604,3 +997,21 @@ where
         Ok(())
     }
 }
+
+// Inputs minus outputs capacity of a transaction whose inputs are all resolved
+fn transaction_fee(rtx: &ResolvedTransaction) -> Result<Capacity, TransactionError> {
+    let mut inputs: Capacity = 0;
+    for cell in &rtx.input_cells {
+        let output = cell.get_current().ok_or(TransactionError::UnknownInput)?;
+        inputs = inputs
+            .checked_add(output.capacity)
+            .ok_or(TransactionError::InvalidCapacity)?;
+    }
+    let outputs = rtx
+        .transaction
+        .outputs_capacity()
+        .ok_or(TransactionError::OutputsSumOverflow)?;
+    inputs
+        .checked_sub(outputs)
+        .ok_or(TransactionError::InvalidCapacity)
+}
@@ -1,27 +1,50 @@
 //! The primary module containing the implementations of the transaction pool
 //! and its top-level members.
 
-use ckb_chain_spec::consensus::{TRANSACTION_PROPAGATION_TIME, TRANSACTION_PROPAGATION_TIMEOUT};
+use bigint::H256;
+use ckb_chain_spec::consensus::ProposalWindow;
 use ckb_core::transaction::{CellOutput, OutPoint, ProposalShortId, Transaction};
-use ckb_core::BlockNumber;
+use ckb_core::{BlockNumber, Capacity, Cycle};
 use ckb_verification::TransactionError;
 use fnv::{FnvHashMap, FnvHashSet};
 use linked_hash_map::LinkedHashMap;
-use std::collections::VecDeque;
+use lru_cache::LruCache;
+use std::cmp::{self, Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, VecDeque};
 use std::hash::Hash;
 use std::iter::Iterator;
 
 const BUFF_QUE_LEN: u64 = 100;
 
+/// Commit candidates and proposal ids selected for the block on top of `tip_hash`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxsPackage {
+    /// The tip the pool was reconciled against when the package was made
+    pub tip_hash: H256,
+    pub tip_number: BlockNumber,
+    pub proposal_transactions: Vec<ProposalShortId>,
+    /// Topologically ordered, a transaction always comes after the ones it spends. Each comes
+    /// with the cycles of its scripts, 0 if unknown.
+    pub commit_transactions: Vec<(Transaction, Cycle)>,
+}
+
 /// Transaction pool configuration
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct PoolConfig {
-    /// Maximum capacity of the pool in number of transactions
+    /// Maximum capacity of the pool in number of transactions, once full the transactions
+    /// paying the lowest fee per byte are evicted for better paying ones
     pub max_pool_size: usize,
     pub max_orphan_size: usize,
     pub max_proposal_size: usize,
     pub max_cache_size: usize,
     pub max_pending_size: usize,
+    /// Maximum number of recently rejected transactions remembered, for each retention
+    #[serde(default = "default_max_rejects_size")]
+    pub max_rejects_size: usize,
+}
+
+fn default_max_rejects_size() -> usize {
+    10000
 }
 
 impl Default for PoolConfig {
@@ -32,6 +55,7 @@ impl Default for PoolConfig {
             max_proposal_size: 10000,
             max_cache_size: 1000,
             max_pending_size: 10000,
+            max_rejects_size: default_max_rejects_size(),
         }
     }
 }
@@ -47,11 +71,37 @@ pub enum TxoStatus {
 #[derive(Clone, Debug)]
 pub enum InsertionResult {
     Normal,
-    Orphan,
+    /// Held until the transactions creating these cells arrive
+    Orphan(Vec<OutPoint>),
     Proposed,
     Unknown,
 }
 
+/// Where a transaction stands in the pool
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoolTxStatus {
+    /// Waiting to be proposed
+    Pending,
+    /// Its proposal was not committed within the proposal window, waiting to be proposed again
+    ProposalExpired,
+    /// Proposed, waiting for the proposal window to open
+    Proposed,
+    /// Proposed and verified, a candidate for the next commit
+    Mineable,
+    Orphan,
+    /// Conflicts with another pool transaction
+    Conflict,
+}
+
+/// A pool transaction and its status, `expired_proposals` counts how often it was proposed
+/// without being committed in time
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PoolTransaction {
+    pub transaction: Transaction,
+    pub status: PoolTxStatus,
+    pub expired_proposals: u32,
+}
+
 #[derive(PartialEq, Clone, Debug)]
 pub enum TxStage {
     Unknown(Transaction),
@@ -63,7 +113,7 @@ pub enum TxStage {
 
 // TODO document this enum more accurately
 /// Enum of errors
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum PoolError {
     /// An invalid pool entry caused by underlying tx validation error
     InvalidTx(TransactionError),
@@ -71,7 +121,10 @@ pub enum PoolError {
     AlreadyInPool,
     /// A double spend
     DoubleSpent,
-    /// Transaction pool is over capacity, can't accept more transactions
+    /// An input or dep cell is already dead on chain
+    DeadCell(OutPoint),
+    /// Transaction pool is over capacity and the transaction doesn't pay more than the
+    /// cheapest one in it, or the queue the transaction would wait its proposal in is full
     OverCapacity,
     /// A duplicate output
     DuplicateOutput,
@@ -83,6 +136,130 @@ pub enum PoolError {
     InvalidBlockNumber,
 }
 
+impl PoolError {
+    /// Stable code of the rejection, an invalid transaction has the code of its
+    /// `TransactionError`
+    pub fn code(&self) -> u32 {
+        match self {
+            PoolError::InvalidTx(err) => err.code(),
+            PoolError::AlreadyInPool => 201,
+            PoolError::DoubleSpent => 202,
+            PoolError::DeadCell(_) => 203,
+            PoolError::OverCapacity => 204,
+            PoolError::DuplicateOutput => 205,
+            PoolError::CellBase => 206,
+            PoolError::TimeOut => 207,
+            PoolError::InvalidBlockNumber => 208,
+        }
+    }
+}
+
+/// Transactions rejected recently and the reason, consulted before verifying a transaction
+/// again so peers can't make the node re-execute the same failing scripts over and over.
+///
+/// A rejection which may be lifted by a later block, like a spent input, is forgotten as soon
+/// as the tip changes. The others stay until evicted by newer rejections.
+pub struct RecentRejects {
+    capacity: usize,
+    permanent: LruCache<H256, PoolError>,
+    temporary: LruCache<H256, PoolError>,
+}
+
+impl RecentRejects {
+    pub fn new(capacity: usize) -> Self {
+        RecentRejects {
+            capacity,
+            permanent: LruCache::new(capacity, false),
+            temporary: LruCache::new(capacity, false),
+        }
+    }
+
+    /// Records the rejection if the error is the transaction's fault, returns whether it was
+    pub fn insert(&mut self, hash: H256, error: &PoolError) -> bool {
+        match error {
+            PoolError::CellBase => {
+                self.permanent.insert(hash, error.clone());
+            }
+            PoolError::InvalidTx(err) => match err {
+                TransactionError::DoubleSpent
+                | TransactionError::UnknownInput
+                | TransactionError::ScriptVersion { .. }
+                | TransactionError::CellbaseImmaturity => {
+                    self.temporary.insert(hash, error.clone());
+                }
+                _ => {
+                    self.permanent.insert(hash, error.clone());
+                }
+            },
+            PoolError::DeadCell(_) => {
+                self.temporary.insert(hash, error.clone());
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&PoolError> {
+        self.permanent
+            .get(hash)
+            .or_else(|| self.temporary.get(hash))
+    }
+
+    /// Forgets the rejections which depend on the chain state
+    pub fn clear_temporary(&mut self) {
+        self.temporary = LruCache::new(self.capacity, false);
+    }
+
+    pub fn len(&self) -> usize {
+        self.permanent.len() + self.temporary.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Fee paid per byte of the serialized transaction, compared without rounding
+#[derive(Clone, Copy, Debug)]
+pub struct FeeRate {
+    fee: Capacity,
+    size: usize,
+}
+
+impl FeeRate {
+    pub fn new(fee: Capacity, size: usize) -> Self {
+        FeeRate {
+            fee,
+            size: cmp::max(size, 1),
+        }
+    }
+
+    pub fn fee_per_byte(&self) -> Capacity {
+        self.fee / self.size as Capacity
+    }
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other: &FeeRate) -> Ordering {
+        (u128::from(self.fee) * other.size as u128)
+            .cmp(&(u128::from(other.fee) * self.size as u128))
+    }
+}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other: &FeeRate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for FeeRate {
+    fn eq(&self, other: &FeeRate) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FeeRate {}
+
 /// An entry in the transaction pool.
 #[derive(Debug, PartialEq, Clone)]
 pub struct PoolEntry {
@@ -92,22 +269,35 @@ pub struct PoolEntry {
     pub refs_count: usize,
     /// Size estimate
     pub size_estimate: usize,
+    /// Inputs minus outputs capacity, 0 if unknown
+    pub fee: Capacity,
+    /// Cycles of the scripts, 0 if unknown
+    pub cycles: Cycle,
 }
 
 impl PoolEntry {
     /// Create new transaction pool entry
     pub fn new(tx: Transaction, count: usize) -> PoolEntry {
+        PoolEntry::with_fee(tx, count, 0, 0)
+    }
+
+    pub fn with_fee(tx: Transaction, count: usize, fee: Capacity, cycles: Cycle) -> PoolEntry {
         PoolEntry {
             size_estimate: estimate_transaction_size(&tx),
             transaction: tx,
             refs_count: count,
+            fee,
+            cycles,
         }
     }
+
+    pub fn fee_rate(&self) -> FeeRate {
+        FeeRate::new(self.fee, self.size_estimate)
+    }
 }
 
-/// TODO guessing this needs implementing
-fn estimate_transaction_size(_tx: &Transaction) -> usize {
-    0
+fn estimate_transaction_size(tx: &Transaction) -> usize {
+    tx.serialized_size() as usize
 }
 
 #[derive(Default, Debug)]
@@ -193,10 +383,20 @@ impl<K: Hash + Eq, V: Copy + Eq + Hash> Edges<K, V> {
     }
 }
 
+/// Orders the pool entries by fee rate, the earlier added one is greater among equals
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct FeeKey {
+    fee_rate: FeeRate,
+    order: Reverse<u64>,
+}
+
 #[derive(Default, Debug)]
 pub struct Pool {
     pub vertices: LinkedHashMap<ProposalShortId, PoolEntry>,
     pub edges: Edges<OutPoint, ProposalShortId>,
+    fee_index: BTreeMap<FeeKey, ProposalShortId>,
+    fee_keys: FnvHashMap<ProposalShortId, FeeKey>,
+    next_order: u64,
 }
 
 impl Pool {
@@ -249,8 +449,26 @@ impl Pool {
             .and_then(|x| x.transaction.get_output(o.index as usize))
     }
 
+    fn index_fee(&mut self, id: ProposalShortId, fee_rate: FeeRate) {
+        self.unindex_fee(&id);
+        let key = FeeKey {
+            fee_rate,
+            order: Reverse(self.next_order),
+        };
+        self.next_order += 1;
+        self.fee_index.insert(key, id);
+        self.fee_keys.insert(id, key);
+    }
+
+    fn unindex_fee(&mut self, id: &ProposalShortId) {
+        if let Some(key) = self.fee_keys.remove(id) {
+            self.fee_index.remove(&key);
+        }
+    }
+
     pub fn remove_vertex(&mut self, id: &ProposalShortId, rtxs: &mut Vec<Transaction>) {
         if let Some(x) = self.vertices.remove(id) {
+            self.unindex_fee(id);
             let tx = x.transaction;
             let inputs = tx.input_pts();
             let outputs = tx.output_pts();
@@ -296,8 +514,13 @@ impl Pool {
         }
     }
 
-    /// Add a verified transaction.
+    /// Add a verified transaction whose fee and cycles are unknown.
     pub fn add_transaction(&mut self, tx: Transaction) {
+        self.add_transaction_with_fee(tx, 0, 0);
+    }
+
+    /// Add a verified transaction paying `fee`, whose scripts cost `cycles`.
+    pub fn add_transaction_with_fee(&mut self, tx: Transaction, fee: Capacity, cycles: Cycle) {
         let inputs = tx.input_pts();
         let outputs = tx.output_pts();
         let deps = tx.dep_pts();
@@ -330,42 +553,90 @@ impl Pool {
             self.edges.mark_inpool(o);
         }
 
-        self.vertices.insert(id, PoolEntry::new(tx, count));
+        let entry = PoolEntry::with_fee(tx, count, fee, cycles);
+        self.index_fee(id, entry.fee_rate());
+        self.vertices.insert(id, entry);
     }
 
-    /// Readd a verified transaction which is rolled back from chain. Since the rolled back
-    /// transaction should depend on any transaction in the pool, it is safe to skip some checking.
-    pub fn readd_transaction(&mut self, tx: &Transaction) {
-        let inputs = tx.input_pts();
-        let outputs = tx.output_pts();
-        let deps = tx.dep_pts();
+    /// Readd a verified transaction which is rolled back from chain, paying `fee`. The pool
+    /// transactions which spent or depended on its outputs while it was on chain become its
+    /// descendants again, they are moved behind it. Rolled back transactions must be readded
+    /// in chain order.
+    pub fn readd_transaction(&mut self, tx: Transaction, fee: Capacity, cycles: Cycle) {
         let id = tx.proposal_short_id();
+        let outputs = tx.output_pts();
 
-        self.vertices
-            .insert_front(tx.proposal_short_id(), PoolEntry::new(tx.clone(), 0));
+        self.add_transaction_with_fee(tx, fee, cycles);
 
-        for i in inputs {
-            self.edges.insert_outer(i, id);
+        for o in outputs {
+            if let Some(cid) = self.edges.remove_outer(&o) {
+                self.inc_ref(&cid);
+                self.edges.insert_inner(o, cid);
+            }
+
+            if let Some(cids) = { self.edges.get_deps(&o).cloned() } {
+                for cid in cids {
+                    self.inc_ref(&cid);
+                }
+            }
         }
 
-        for d in deps {
-            self.edges.insert_deps(d, id);
+        self.move_descendants_back(&id);
+    }
+
+    // Moves the descendants of an entry behind all the other entries, in their order, so the
+    // vertices stay topologically ordered
+    fn move_descendants_back(&mut self, id: &ProposalShortId) {
+        let mut descendants = FnvHashSet::default();
+        let mut queue = vec![*id];
+        while let Some(id) = queue.pop() {
+            let outputs = match self.vertices.get(&id) {
+                Some(entry) => entry.transaction.output_pts(),
+                None => continue,
+            };
+            for o in outputs {
+                let spender = self.edges.get_inner(&o).and_then(|cid| *cid);
+                let deps = self
+                    .edges
+                    .get_deps(&o)
+                    .into_iter()
+                    .flat_map(|cids| cids.iter());
+                for cid in spender.iter().chain(deps) {
+                    if descendants.insert(*cid) {
+                        queue.push(*cid);
+                    }
+                }
+            }
         }
 
-        for o in outputs {
-            if let Some(id) = self.edges.remove_outer(&o) {
-                self.inc_ref(&id);
-                self.edges.insert_inner(o, id);
-            } else {
-                self.edges.mark_inpool(o);
+        let ordered = self
+            .vertices
+            .keys()
+            .filter(|cid| descendants.contains(cid))
+            .cloned()
+            .collect::<Vec<_>>();
+        for cid in ordered {
+            self.vertices.get_refresh(&cid);
+        }
+    }
+
+    /// Removes the pool transactions spending or depending on the outputs of a transaction
+    /// rolled back from chain which is not readded, together with their descendants.
+    pub fn remove_descendants(&mut self, tx: &Transaction) -> Vec<Transaction> {
+        let mut rtxs = Vec::new();
+
+        for o in tx.output_pts() {
+            if let Some(cid) = self.edges.remove_outer(&o) {
+                self.remove_vertex(&cid, &mut rtxs);
             }
 
-            if let Some(cids) = { self.edges.get_deps(&o).cloned() } {
+            if let Some(cids) = self.edges.remove_deps(&o) {
                 for cid in cids {
-                    self.inc_ref(&cid);
+                    self.remove_vertex(&cid, &mut rtxs);
                 }
             }
         }
+        rtxs
     }
 
     ///Commit proposed transaction
@@ -376,6 +647,7 @@ impl Pool {
         let id = tx.proposal_short_id();
 
         if self.vertices.remove(&id).is_some() {
+            self.unindex_fee(&id);
             for o in outputs {
                 if let Some(cid) = self.edges.remove_inner(&o) {
                     self.dec_ref(&cid);
@@ -426,6 +698,160 @@ impl Pool {
             .collect()
     }
 
+    /// Get transactions by fee priority whose total serialized size fits in `max_bytes`,
+    /// whose total outputs data fits in `max_data_bytes` and whose scripts fit in `max_cycles`.
+    /// A transaction that doesn't fit is skipped together with everything spending
+    /// or depending on it, so the result is always a valid commit sequence. Each transaction
+    /// comes with its cycles.
+    pub fn package_transactions(
+        &self,
+        max_bytes: usize,
+        max_data_bytes: u64,
+        max_cycles: Cycle,
+    ) -> Vec<(Transaction, Cycle)> {
+        let mut size = 0;
+        let mut data_bytes = 0;
+        let mut cycles = 0;
+        self.select_by_fee(|entry| {
+            let entry_data_bytes = entry.transaction.outputs_data_bytes();
+            if size + entry.size_estimate > max_bytes
+                || data_bytes + entry_data_bytes > max_data_bytes
+                || cycles + entry.cycles > max_cycles
+            {
+                false
+            } else {
+                size += entry.size_estimate;
+                data_bytes += entry_data_bytes;
+                cycles += entry.cycles;
+                true
+            }
+        }).into_iter()
+        .map(|entry| (entry.transaction.clone(), entry.cycles))
+        .collect()
+    }
+
+    /// Get at most `n` transactions by fee priority, a transaction always comes after the
+    /// pool transactions it spends or depends on.
+    pub fn get_top_n_by_fee(&self, n: usize) -> Vec<Transaction> {
+        let mut count = 0;
+        self.select_by_fee(|_entry| {
+            count += 1;
+            count <= n
+        }).into_iter()
+        .map(|entry| entry.transaction.clone())
+        .collect()
+    }
+
+    /// The entry paying the lowest fee per byte, the first added one among equals
+    pub fn lowest_fee_rate(&self) -> Option<(ProposalShortId, FeeRate)> {
+        self.lowest_fee_rate_except(&FnvHashSet::default())
+    }
+
+    /// The entry paying the lowest fee per byte outside of `excluded`, the first added one
+    /// among equals
+    pub fn lowest_fee_rate_except(
+        &self,
+        excluded: &FnvHashSet<ProposalShortId>,
+    ) -> Option<(ProposalShortId, FeeRate)> {
+        let mut entries = self
+            .fee_index
+            .iter()
+            .filter(|(_, id)| !excluded.contains(*id));
+        let first = entries.next()?;
+        // the greatest key of the lowest fee rate is the first added entry
+        let (key, id) = entries
+            .take_while(|(key, _)| key.fee_rate == first.0.fee_rate)
+            .last()
+            .unwrap_or(first);
+        Some((*id, key.fee_rate))
+    }
+
+    /// The entries `tx` spends or depends on, directly or through other entries, with all
+    /// their descendants. Evicting one of them would leave `tx` or a sibling without a parent.
+    pub fn ancestors_and_descendants(&self, tx: &Transaction) -> FnvHashSet<ProposalShortId> {
+        let parents = |tx: &Transaction| {
+            tx.input_pts()
+                .iter()
+                .chain(tx.dep_pts().iter())
+                .map(|o| ProposalShortId::from_h256(&o.hash))
+                .filter(|parent| self.vertices.contains_key(parent))
+                .collect::<Vec<_>>()
+        };
+        let mut ancestors = FnvHashSet::default();
+        let mut stack = parents(tx);
+        while let Some(id) = stack.pop() {
+            if ancestors.insert(id) {
+                stack.extend(parents(&self.vertices[&id].transaction));
+            }
+        }
+
+        let mut related = FnvHashSet::default();
+        let mut stack = ancestors.into_iter().collect::<Vec<_>>();
+        while let Some(id) = stack.pop() {
+            if !related.insert(id) {
+                continue;
+            }
+            for o in self.vertices[&id].transaction.output_pts() {
+                if let Some(Some(child)) = self.edges.get_inner(&o) {
+                    stack.push(*child);
+                }
+                if let Some(children) = self.edges.get_deps(&o) {
+                    stack.extend(children.iter().cloned());
+                }
+            }
+        }
+        related
+    }
+
+    // Picks the entries with the highest fee rate whose pool parents are already picked,
+    // until `accept` refuses one. A refused entry is dropped with all its descendants, which
+    // never become ready.
+    fn select_by_fee<F>(&self, mut accept: F) -> Vec<&PoolEntry>
+    where
+        F: FnMut(&PoolEntry) -> bool,
+    {
+        // the number of pool parents each entry waits for, and the entries waiting for each one
+        let mut waiting = FnvHashMap::default();
+        let mut children: FnvHashMap<ProposalShortId, Vec<ProposalShortId>> = FnvHashMap::default();
+        let mut ready = BinaryHeap::new();
+        for (key, id) in &self.fee_index {
+            let tx = &self.vertices[id].transaction;
+            let parents: FnvHashSet<ProposalShortId> = tx
+                .input_pts()
+                .iter()
+                .chain(tx.dep_pts().iter())
+                .map(|o| ProposalShortId::from_h256(&o.hash))
+                .filter(|parent| self.vertices.contains_key(parent))
+                .collect();
+            if parents.is_empty() {
+                ready.push(*key);
+            } else {
+                waiting.insert(*id, parents.len());
+                for parent in parents {
+                    children.entry(parent).or_insert_with(Vec::new).push(*id);
+                }
+            }
+        }
+
+        let mut entries = Vec::new();
+        while let Some(key) = ready.pop() {
+            let id = self.fee_index[&key];
+            let entry = &self.vertices[&id];
+            if !accept(entry) {
+                continue;
+            }
+            entries.push(entry);
+            for child in children.get(&id).into_iter().flat_map(|ids| ids.iter()) {
+                let parents = waiting.get_mut(child).expect("waiting child");
+                *parents -= 1;
+                if *parents == 0 {
+                    ready.push(self.fee_keys[child]);
+                }
+            }
+        }
+        entries
+    }
+
     pub fn inc_ref(&mut self, id: &ProposalShortId) {
         if let Some(x) = self.vertices.get_mut(&id) {
             x.refs_count += 1;
@@ -444,6 +870,8 @@ impl Pool {
 pub struct Orphan {
     pub vertices: FnvHashMap<ProposalShortId, PoolEntry>,
     pub edges: FnvHashMap<OutPoint, Vec<ProposalShortId>>,
+    /// When each orphan was added, in milliseconds, the oldest first
+    pub added: LinkedHashMap<ProposalShortId, u64>,
 }
 
 impl Orphan {
@@ -468,9 +896,16 @@ impl Orphan {
         self.vertices.contains_key(id)
     }
 
-    /// add orphan transaction
-    pub fn add_transaction(&mut self, tx: Transaction, unknown: impl Iterator<Item = OutPoint>) {
+    /// add orphan transaction, added at `now` in milliseconds
+    pub fn add_transaction(
+        &mut self,
+        tx: Transaction,
+        unknown: impl Iterator<Item = OutPoint>,
+        now: u64,
+    ) {
         let id = tx.proposal_short_id();
+        self.added.remove(&id);
+        self.added.insert(id, now);
 
         let mut count: usize = 0;
 
@@ -484,6 +919,7 @@ impl Orphan {
     }
 
     pub fn remove(&mut self, id: &ProposalShortId) -> Option<Transaction> {
+        self.added.remove(id);
         if let Some(x) = self.vertices.remove(id) {
             let tx = x.transaction;
 
@@ -516,6 +952,7 @@ impl Orphan {
                         if let Some(mut x) = self.vertices.remove(&cid) {
                             x.refs_count -= 1;
                             if x.refs_count == 0 {
+                                self.added.remove(&cid);
                                 q.push_back(x.transaction.output_pts());
                                 txs.push(x.transaction);
                             } else {
@@ -541,6 +978,24 @@ impl Orphan {
             }
         }
     }
+
+    /// Drops the orphans added before `before`, their parents are not coming
+    pub fn expire(&mut self, before: u64) -> Vec<Transaction> {
+        let mut expired = Vec::new();
+        while let Some((id, added)) = self.added.front().map(|(id, added)| (*id, *added)) {
+            if added >= before {
+                break;
+            }
+            expired.extend(self.remove(&id));
+        }
+        expired
+    }
+
+    /// Drops the oldest orphan to make room for a new one
+    pub fn evict_oldest(&mut self) -> Option<Transaction> {
+        let id = self.added.front().map(|(id, _)| *id)?;
+        self.remove(&id)
+    }
 }
 
 #[derive(Default, Debug)]
@@ -586,6 +1041,7 @@ impl PendingQueue {
 
 #[derive(Default, Debug)]
 pub struct ProposedQueue {
+    window: ProposalWindow,
     //the blocknumber at the back of the queue
     tip: BlockNumber,
     queue: VecDeque<FnvHashSet<ProposalShortId>>,
@@ -598,19 +1054,23 @@ impl ProposedQueue {
         self.buff.len()
     }
 
-    pub fn cap() -> usize {
-        (TRANSACTION_PROPAGATION_TIME + BUFF_QUE_LEN) as usize
+    pub fn cap(window: ProposalWindow) -> usize {
+        (window.far + BUFF_QUE_LEN) as usize
     }
 
-    pub fn new(n: BlockNumber, ids_list: Vec<Vec<ProposalShortId>>) -> Self {
+    pub fn new(
+        window: ProposalWindow,
+        n: BlockNumber,
+        ids_list: Vec<Vec<ProposalShortId>>,
+    ) -> Self {
         let tip = n;
-        let cap = (TRANSACTION_PROPAGATION_TIME + BUFF_QUE_LEN) as usize;
+        let cap = Self::cap(window);
         let mut queue = VecDeque::with_capacity(cap as usize + 1);
         let mut numbers = FnvHashMap::default();
-        let tail = if TRANSACTION_PROPAGATION_TIMEOUT > tip {
+        let tail = if window.far > tip {
             1
         } else {
-            tip + 1 - TRANSACTION_PROPAGATION_TIMEOUT
+            tip + 1 - window.far
         };
         let mut cur = tip;
 
@@ -635,6 +1095,7 @@ impl ProposedQueue {
         let buff = FnvHashMap::default();
 
         ProposedQueue {
+            window,
             tip,
             queue,
             numbers,
@@ -660,15 +1121,20 @@ impl ProposedQueue {
         self.queue.get(id)
     }
 
+    /// Whether `insert` would buffer the transaction until its proposal is old enough
+    pub fn is_too_early(&self, id: &ProposalShortId) -> bool {
+        self.numbers
+            .get(id)
+            .map_or(false, |bn| bn + self.window.close > self.tip + 1)
+    }
+
     pub fn insert(&mut self, tx: Transaction) -> TxStage {
         let id = tx.proposal_short_id();
-        if let Some(bn) = self.numbers.get(&id) {
-            if bn + TRANSACTION_PROPAGATION_TIME > self.tip + 1 {
-                self.buff.insert(id, tx);
-                TxStage::Proposed
-            } else {
-                TxStage::Mineable(tx)
-            }
+        if self.is_too_early(&id) {
+            self.buff.insert(id, tx);
+            TxStage::Proposed
+        } else if self.numbers.contains_key(&id) {
+            TxStage::Mineable(tx)
         } else {
             TxStage::Unknown(tx)
         }
@@ -676,7 +1142,7 @@ impl ProposedQueue {
 
     pub fn insert_with_n(&mut self, bn: BlockNumber, tx: Transaction) -> TxStage {
         if bn <= self.tip {
-            if bn + TRANSACTION_PROPAGATION_TIMEOUT <= self.tip {
+            if bn + self.window.far <= self.tip {
                 TxStage::TimeOut(tx)
             } else {
                 let mut is_in = false;
@@ -689,7 +1155,7 @@ impl ProposedQueue {
                 }
 
                 if is_in {
-                    if bn + TRANSACTION_PROPAGATION_TIME > self.tip + 1 {
+                    if bn + self.window.close > self.tip + 1 {
                         self.buff.insert(id, tx);
                         TxStage::Proposed
                     } else {
@@ -716,8 +1182,8 @@ impl ProposedQueue {
                 id
             }).collect();
 
-        if TRANSACTION_PROPAGATION_TIMEOUT <= self.tip + 1 {
-            let tail = self.tip + 1 - TRANSACTION_PROPAGATION_TIMEOUT;
+        if self.window.far <= self.tip + 1 {
+            let tail = self.tip + 1 - self.window.far;
             if let Some(ids) = self.get_ids(tail).cloned() {
                 for id in ids {
                     self.numbers.remove(&id);
@@ -745,8 +1211,8 @@ impl ProposedQueue {
             }
         }
 
-        if TRANSACTION_PROPAGATION_TIMEOUT <= self.tip + 1 {
-            let tail = self.tip + 1 - TRANSACTION_PROPAGATION_TIMEOUT;
+        if self.window.far <= self.tip + 1 {
+            let tail = self.tip + 1 - self.window.far;
             if let Some(ids) = self.get_ids(tail).cloned() {
                 for id in ids {
                     self.numbers.insert(id, tail);
@@ -762,7 +1228,7 @@ impl ProposedQueue {
         bn: BlockNumber,
         ids: Vec<ProposalShortId>,
     ) -> Result<Vec<Transaction>, PoolError> {
-        if bn < TRANSACTION_PROPAGATION_TIME {
+        if bn < self.window.close {
             self.push_back(ids);
             return Ok(Vec::new());
         }
@@ -771,7 +1237,7 @@ impl ProposedQueue {
             return Err(PoolError::InvalidBlockNumber);
         }
 
-        let m = bn + 1 - TRANSACTION_PROPAGATION_TIME;
+        let m = bn + 1 - self.window.close;
         self.push_back(ids);
 
         if let Some(x) = self.get_ids(m).cloned() {
@@ -808,22 +1274,37 @@ impl ProposedQueue {
         Some(txs)
     }
 
+    // Proposed transactions whose proposal has left the commit window before they became
+    // mineable, they can't be committed any more without being proposed again
+    pub fn gc(&mut self) -> Vec<Transaction> {
+        let expired: Vec<ProposalShortId> = self
+            .buff
+            .keys()
+            .filter(|id| !self.numbers.contains_key(id))
+            .cloned()
+            .collect();
+        expired
+            .iter()
+            .filter_map(|id| self.buff.remove(id))
+            .collect()
+    }
+
     // The oldest proposed shortids but still not mineable
     pub fn front(&self) -> Option<&FnvHashSet<ProposalShortId>> {
-        if self.tip < TRANSACTION_PROPAGATION_TIME || TRANSACTION_PROPAGATION_TIME <= 1 {
+        if self.tip < self.window.close || self.window.close <= 1 {
             return None;
         }
 
-        self.get_ids(self.tip + 2 - TRANSACTION_PROPAGATION_TIME)
+        self.get_ids(self.tip + 2 - self.window.close)
     }
 
     // The oldest mineable shortids
     pub fn mineable_front(&self) -> Option<&FnvHashSet<ProposalShortId>> {
-        if self.tip < TRANSACTION_PROPAGATION_TIMEOUT {
+        if self.tip < self.window.far {
             return None;
         }
 
-        let t = self.tip + 1 - TRANSACTION_PROPAGATION_TIMEOUT;
+        let t = self.tip + 1 - self.window.far;
         self.get_ids(t)
     }
 }
@@ -860,7 +1341,11 @@ mod tests {
         let id2 = tx2.proposal_short_id();
         let id3 = tx3.proposal_short_id();
 
-        let mut queue = ProposedQueue::new(1000, vec![vec![id2.clone()], vec![id1.clone()]]);
+        let mut queue = ProposedQueue::new(
+            ProposalWindow::default(),
+            1000,
+            vec![vec![id2.clone()], vec![id1.clone()]],
+        );
 
         let set1 = queue.get_ids(1000).unwrap().clone();
         let set2 = queue.get_ids(999).unwrap().clone();
@@ -877,7 +1362,7 @@ mod tests {
 
         let txs = queue.reconcile(1001, vec![id3]).unwrap();
 
-        // if TRANSACTION_PROPAGATION_TIME = 1:
+        // with the default proposal window, close = 1:
         assert_eq!(txs, vec![tx3]);
 
         let set1 = queue.get_ids(1000).unwrap().clone();
@@ -889,6 +1374,31 @@ mod tests {
         assert_eq!(Some(&1001), queue.numbers.get(&id3));
     }
 
+    #[test]
+    fn test_proposed_queue_gc() {
+        let tx1 = build_tx(vec![(H256::zero(), 1)], 1);
+        let tx2 = build_tx(vec![(H256::zero(), 2)], 1);
+        let id1 = tx1.proposal_short_id();
+        let id2 = tx2.proposal_short_id();
+
+        let mut queue =
+            ProposedQueue::new(ProposalWindow::default(), 1000, vec![vec![id2.clone()]]);
+        queue.insert_without_check(id1.clone(), tx1.clone());
+        queue.insert_without_check(id2.clone(), tx2.clone());
+
+        // id1 was never proposed within the window, id2 still is
+        assert_eq!(queue.gc(), vec![tx1]);
+        assert_eq!(queue.size(), 1);
+
+        for bn in 1001..(1001 + queue.window.far) {
+            queue.push_back(vec![]);
+            assert_eq!(queue.tip, bn);
+        }
+
+        assert_eq!(queue.gc(), vec![tx2]);
+        assert_eq!(queue.size(), 0);
+    }
+
     #[test]
     fn test_add_entry() {
         let tx1 = build_tx(vec![(H256::zero(), 1), (H256::zero(), 2)], 1);
@@ -955,6 +1465,108 @@ mod tests {
         assert_eq!(pool.edges.outer_len(), 2);
     }
 
+    #[test]
+    fn test_top_n_by_fee() {
+        let tx1 = build_tx(vec![(H256::zero(), 1), (H256::zero(), 2)], 1);
+        let tx2 = build_tx(vec![(tx1.hash(), 0)], 1);
+        let tx3 = build_tx(vec![(H256::zero(), 3)], 2);
+
+        let mut pool = Pool::new();
+        pool.add_transaction_with_fee(tx1.clone(), 10, 100);
+        pool.add_transaction_with_fee(tx2.clone(), 100_000, 100);
+        pool.add_transaction_with_fee(tx3.clone(), 1_000, 100);
+
+        // tx2 pays the most but has to wait for tx1
+        assert_eq!(
+            pool.get_top_n_by_fee(3),
+            vec![tx3.clone(), tx1.clone(), tx2.clone()]
+        );
+        assert_eq!(pool.get_top_n_by_fee(2), vec![tx3.clone(), tx1.clone()]);
+        assert_eq!(
+            pool.lowest_fee_rate().map(|(id, _)| id),
+            Some(tx1.proposal_short_id())
+        );
+
+        // without tx1 fitting, tx2 can't be packaged either
+        let max_bytes = tx3.serialized_size() as usize;
+        assert_eq!(
+            pool.package_transactions(max_bytes, u64::max_value(), u64::max_value()),
+            vec![(tx3.clone(), 100)]
+        );
+        // same for the cycles
+        assert_eq!(
+            pool.package_transactions(usize::max_value(), u64::max_value(), 100),
+            vec![(tx3, 100)]
+        );
+    }
+
+    #[test]
+    fn test_readd_transaction() {
+        let tx1 = build_tx(vec![(H256::zero(), 1)], 2);
+        let tx2 = build_tx(vec![(tx1.hash(), 0)], 1);
+        let tx3 = build_tx(vec![(H256::zero(), 2)], 1);
+        let tx4 = build_tx(vec![(tx2.hash(), 0), (tx1.hash(), 1)], 1);
+
+        // tx1 is rolled back from chain after its descendants entered the pool
+        let mut pool = Pool::new();
+        pool.add_transaction(tx2.clone());
+        pool.add_transaction(tx3.clone());
+        pool.add_transaction(tx4.clone());
+        pool.readd_transaction(tx1.clone(), 0, 0);
+
+        assert_eq!(
+            pool.get_mineable_transactions(4),
+            vec![tx3.clone(), tx1.clone(), tx2.clone(), tx4.clone()]
+        );
+        assert_eq!(pool.get_entry(&tx2.proposal_short_id()).unwrap().refs_count, 1);
+        assert_eq!(pool.get_entry(&tx4.proposal_short_id()).unwrap().refs_count, 2);
+    }
+
+    #[test]
+    fn test_fee_index() {
+        let tx1 = build_tx(vec![(H256::zero(), 1)], 1);
+        let tx2 = build_tx(vec![(H256::zero(), 2)], 1);
+        let tx3 = build_tx(vec![(tx1.hash(), 0), (tx2.hash(), 0)], 1);
+        let tx4 = build_tx(vec![(H256::zero(), 4)], 1);
+
+        let mut pool = Pool::new();
+        pool.add_transaction_with_fee(tx1.clone(), 100, 100);
+        pool.add_transaction_with_fee(tx2.clone(), 100, 100);
+        pool.add_transaction_with_fee(tx3.clone(), 1_000, 100);
+        pool.add_transaction_with_fee(tx4.clone(), 500, 100);
+
+        // equal fee rates keep the order of addition, tx3 waits for both its parents
+        assert_eq!(
+            pool.get_top_n_by_fee(4),
+            vec![tx4.clone(), tx1.clone(), tx2.clone(), tx3.clone()]
+        );
+        assert_eq!(
+            pool.lowest_fee_rate().map(|(id, _)| id),
+            Some(tx1.proposal_short_id())
+        );
+
+        // the removed entries leave the index, with their descendants
+        pool.remove(&tx1.proposal_short_id());
+        assert_eq!(pool.get_top_n_by_fee(4), vec![tx4.clone(), tx2.clone()]);
+        assert_eq!(
+            pool.lowest_fee_rate().map(|(id, _)| id),
+            Some(tx2.proposal_short_id())
+        );
+
+        pool.commit_transaction(&tx2);
+        pool.commit_transaction(&tx4);
+        assert!(pool.get_top_n_by_fee(4).is_empty());
+        assert!(pool.lowest_fee_rate().is_none());
+    }
+
+    #[test]
+    fn test_fee_rate() {
+        assert!(FeeRate::new(10, 3) > FeeRate::new(3, 1));
+        assert_eq!(FeeRate::new(10, 2), FeeRate::new(5, 1));
+        assert_eq!(FeeRate::new(10, 3).fee_per_byte(), 3);
+        assert_eq!(FeeRate::new(10, 0).fee_per_byte(), 10);
+    }
+
     #[test]
     fn test_pending_queue() {
         let mut pending = PendingQueue::new();
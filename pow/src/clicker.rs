@@ -1,4 +1,5 @@
 use super::PowEngine;
+use bigint::U256;
 use ckb_core::header::{BlockNumber, Header, RawHeader, Seal};
 use crossbeam_channel;
 use std::any::Any;
@@ -37,6 +38,10 @@ impl PowEngine for Clicker {
         true
     }
 
+    fn verify_header_difficulty(&self, _header: &Header, _difficulty: &U256) -> bool {
+        true
+    }
+
     fn solve_header(&self, _header: &RawHeader, _nonce: u64) -> Option<Seal> {
         self.rx.recv().map(|nonce| Seal::new(nonce, vec![]))
     }
@@ -7,7 +7,7 @@ use std::collections::HashMap;
 
 // Cuckatoo proofs take the form of a length 42 off-by-1-cycle in a bipartite graph with
 // 2^N+2^N nodes and 2^N edges, with N ranging from 10 up to 64.
-#[derive(Copy, Clone, Deserialize, Eq, PartialEq, Hash, Debug)]
+#[derive(Copy, Clone, Serialize, Deserialize, Eq, PartialEq, Hash, Debug)]
 pub struct CuckooParams {
     // the main parameter is the 2-log of the graph size,
     // which is the size in bits of the node identifiers
@@ -29,6 +29,17 @@ impl CuckooEngine {
     }
 }
 
+impl CuckooParams {
+    /// The edges are u32 indexes and a cycle alternates between the two halves of the
+    /// graph, other params can never have a verifiable solution
+    pub fn is_valid(&self) -> bool {
+        self.edge_bits > 0
+            && self.edge_bits < 32
+            && self.cycle_length > 0
+            && self.cycle_length % 2 == 0
+    }
+}
+
 impl Default for CuckooParams {
     fn default() -> Self {
         CuckooParams {
@@ -43,6 +54,10 @@ impl PowEngine for CuckooEngine {
 
     #[inline]
     fn verify(&self, _number: BlockNumber, message: &[u8], proof: &[u8]) -> bool {
+        // the proof comes from the network, its length isn't checked anywhere else
+        if proof.len() != self.cuckoo.cycle_length * 4 {
+            return false;
+        }
         let mut proof_u32 = vec![0u32; self.cuckoo.cycle_length];
         LittleEndian::read_u32_into(&proof, &mut proof_u32);
         self.cuckoo.verify(message, &proof_u32)
@@ -57,6 +72,10 @@ impl PowEngine for CuckooEngine {
         })
     }
 
+    fn proof_size(&self) -> usize {
+        self.cuckoo.cycle_length * 4
+    }
+
     fn as_any(&self) -> &dyn Any {
         self
     }
@@ -158,6 +177,11 @@ impl Cuckoo {
             return false;
         }
 
+        // The edges are in ascending order, as the solver finds them, so none is repeated
+        if proof.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return false;
+        }
+
         let keys = message_to_keys(message);
         let hasher = CuckooSip::new(keys[0], keys[1], keys[2], keys[3]);
 
@@ -184,14 +208,15 @@ impl Cuckoo {
         let mut cur_edge = hasher.edge(proof[0], self.edge_mask);
         let start = cur_edge.0;
         loop {
-            let next_lower = *from_upper[&cur_edge.0]
-                .iter()
-                .find(|v| **v != cur_edge.1)
-                .unwrap();
-            let next_upper = *from_lower[&next_lower]
-                .iter()
-                .find(|u| **u != cur_edge.0)
-                .unwrap();
+            // distinct edges may still hash to the same nodes, which is no cycle
+            let next_lower = match from_upper[&cur_edge.0].iter().find(|v| **v != cur_edge.1) {
+                Some(v) => *v,
+                None => return false,
+            };
+            let next_upper = match from_lower[&next_lower].iter().find(|u| **u != cur_edge.0) {
+                Some(u) => *u,
+                None => return false,
+            };
             cur_edge = (next_upper, next_lower);
             cycle_length += 2;
 
@@ -285,9 +310,11 @@ impl Cuckoo {
 
 #[cfg(test)]
 mod test {
-    use super::Cuckoo;
+    use super::{Cuckoo, CuckooEngine, CuckooParams};
+    use byteorder::{ByteOrder, LittleEndian};
     use proptest::collection::size_range;
     use proptest::prelude::any_with;
+    use PowEngine;
 
     fn _cuckoo_solve(message: &[u8]) -> bool {
         let cuckoo = Cuckoo::new(3, 6);
@@ -346,4 +373,39 @@ mod test {
             assert!(cuckoo.verify(message, proof));
         }
     }
+
+    #[test]
+    fn verify_malformed_proof() {
+        let cuckoo = Cuckoo::new(3, 6);
+        let (message, proof) = TESTSET[0];
+        let mut unordered = proof;
+        unordered.swap(0, 1);
+        assert!(!cuckoo.verify(&message, &unordered));
+        let mut repeated = proof;
+        repeated[1] = repeated[0];
+        assert!(!cuckoo.verify(&message, &repeated));
+
+        let engine = CuckooEngine::new(CuckooParams {
+            edge_bits: 3,
+            cycle_length: 6,
+        });
+        let mut proof_bytes = vec![0u8; 6 * 4];
+        LittleEndian::write_u32_into(&proof, &mut proof_bytes);
+        assert!(engine.verify(0, &message, &proof_bytes));
+        assert!(!engine.verify(0, &message, &proof_bytes[..20]));
+        assert!(!engine.verify(0, &message, &[]));
+    }
+
+    #[test]
+    fn test_params_validity() {
+        assert!(CuckooParams::default().is_valid());
+        let params = |edge_bits, cycle_length| CuckooParams {
+            edge_bits,
+            cycle_length,
+        };
+        assert!(!params(32, 42).is_valid());
+        assert!(!params(0, 42).is_valid());
+        assert!(!params(29, 41).is_valid());
+        assert!(!params(29, 0).is_valid());
+    }
 }
@@ -1,4 +1,5 @@
 use super::PowEngine;
+use bigint::U256;
 use ckb_core::header::{BlockNumber, Header, RawHeader, Seal};
 use rand::{thread_rng, Rng};
 use std::any::Any;
@@ -25,6 +26,10 @@ impl PowEngine for DummyPowEngine {
         true
     }
 
+    fn verify_header_difficulty(&self, _header: &Header, _difficulty: &U256) -> bool {
+        true
+    }
+
     fn solve_header(&self, _header: &RawHeader, nonce: u64) -> Option<Seal> {
         // Sleep for some time before returning result to miner
         let seconds = thread_rng().gen_range(5, 20);
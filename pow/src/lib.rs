@@ -10,7 +10,7 @@ extern crate serde_derive;
 #[macro_use]
 extern crate proptest;
 
-use bigint::H256;
+use bigint::{H256, U256};
 use byteorder::{ByteOrder, LittleEndian};
 use ckb_core::difficulty::{boundary_to_difficulty, difficulty_to_boundary};
 use ckb_core::header::{BlockNumber, Header, RawHeader, Seal};
@@ -27,7 +27,7 @@ pub use self::clicker::Clicker;
 pub use self::cuckoo::{Cuckoo, CuckooEngine, CuckooParams};
 pub use self::dummy::DummyPowEngine;
 
-#[derive(Clone, Deserialize, Eq, PartialEq, Hash, Debug)]
+#[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Hash, Debug)]
 pub enum Pow {
     Dummy,
     Clicker,
@@ -55,8 +55,14 @@ pub trait PowEngine: Send + Sync {
     fn init(&self, number: BlockNumber);
 
     fn verify_header(&self, header: &Header) -> bool {
+        self.verify_header_difficulty(header, &header.difficulty())
+    }
+
+    /// Verifies the proof against `difficulty` instead of the header difficulty, e.g. the
+    /// shares submitted to a mining pool, which are easier than the block itself.
+    fn verify_header_difficulty(&self, header: &Header, difficulty: &U256) -> bool {
         let proof_hash: H256 = blake2b(&header.proof()).into();
-        if boundary_to_difficulty(&proof_hash) < header.difficulty() {
+        if boundary_to_difficulty(&proof_hash) < *difficulty {
             return false;
         }
 
@@ -81,6 +87,11 @@ pub trait PowEngine: Send + Sync {
 
     fn verify(&self, number: BlockNumber, message: &[u8], proof: &[u8]) -> bool;
 
+    /// The bytes of the proofs found by `solve`, the block templates leave room for them
+    fn proof_size(&self) -> usize {
+        0
+    }
+
     fn as_any(&self) -> &dyn Any;
 }
 
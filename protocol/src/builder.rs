@@ -8,14 +8,14 @@ use flatbuffers::{FlatBufferBuilder, WIPOffset};
 use protocol_generated::ckb::protocol::{
     Block as FbsBlock, BlockBuilder, BlockProposalBuilder, BlockTransactionsBuilder,
     Bytes as FbsBytes, BytesBuilder, CellInput as FbsCellInput, CellInputBuilder,
-    CellOutput as FbsCellOutput, CellOutputBuilder, CompactBlock, CompactBlockBuilder,
-    GetBlockProposalBuilder, GetBlockTransactionsBuilder, GetBlocks as FbsGetBlocks,
-    GetBlocksBuilder, GetHeaders as FbsGetHeaders, GetHeadersBuilder, Header as FbsHeader,
-    HeaderBuilder, Headers as FbsHeaders, HeadersBuilder, OutPoint as FbsOutPoint, OutPointBuilder,
-    PrefilledTransactionBuilder, RelayMessage, RelayMessageBuilder, RelayPayload,
-    Script as FbsScript, ScriptBuilder, SyncMessage, SyncMessageBuilder, SyncPayload,
-    Transaction as FbsTransaction, TransactionBuilder, UncleBlock as FbsUncleBlock,
-    UncleBlockBuilder,
+    CellOutput as FbsCellOutput, CellOutputBuilder, CompactBlock, CompactBlockBuilder, Disconnect,
+    DisconnectBuilder, GetBlockProposalBuilder, GetBlockTransactionsBuilder,
+    GetBlocks as FbsGetBlocks, GetBlocksBuilder, GetHeaders as FbsGetHeaders, GetHeadersBuilder,
+    HandshakeBuilder, Header as FbsHeader, HeaderBuilder, Headers as FbsHeaders, HeadersBuilder,
+    NewHeaderBuilder, OutPoint as FbsOutPoint, OutPointBuilder, PrefilledTransactionBuilder,
+    RelayMessage, RelayMessageBuilder, RelayPayload, Script as FbsScript, ScriptBuilder,
+    SyncMessage, SyncMessageBuilder, SyncPayload, Transaction as FbsTransaction,
+    TransactionBuilder, TransactionRejectBuilder, UncleBlock as FbsUncleBlock, UncleBlockBuilder,
 };
 use rand::{thread_rng, Rng};
 use std::collections::HashSet;
@@ -253,18 +253,26 @@ impl<'a> FbsGetHeaders<'a> {
     pub fn build<'b>(
         fbb: &mut FlatBufferBuilder<'b>,
         block_locator_hashes: &[H256],
+        hash_stop: &H256,
     ) -> WIPOffset<FbsGetHeaders<'b>> {
         let vec = block_locator_hashes
             .iter()
             .map(|hash| FbsBytes::build(fbb, &hash))
             .collect::<Vec<_>>();
         let block_locator_hashes = fbb.create_vector(&vec);
+        // a zero stop asks for as many headers as possible, it's left out of the message
+        let hash_stop = if hash_stop.is_zero() {
+            None
+        } else {
+            Some(FbsBytes::build(fbb, hash_stop))
+        };
         let mut builder = GetHeadersBuilder::new(fbb);
         // TODO remove version from protocol?
         builder.add_version(0);
         builder.add_block_locator_hashes(block_locator_hashes);
-        // TODO PENDING hash_stop
-        // builder.add_hash_stop(...)
+        if let Some(hash_stop) = hash_stop {
+            builder.add_hash_stop(hash_stop);
+        }
         builder.finish()
     }
 }
@@ -285,12 +293,27 @@ impl<'a> FbsGetBlocks<'a> {
     }
 }
 
+impl<'a> Disconnect<'a> {
+    pub fn build<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        reason: u32,
+        message: &str,
+    ) -> WIPOffset<Disconnect<'b>> {
+        let message = fbb.create_string(message);
+        let mut builder = DisconnectBuilder::new(fbb);
+        builder.add_reason(reason);
+        builder.add_message(message);
+        builder.finish()
+    }
+}
+
 impl<'a> SyncMessage<'a> {
     pub fn build_get_headers<'b>(
         fbb: &mut FlatBufferBuilder<'b>,
         block_locator_hashes: &[H256],
+        hash_stop: &H256,
     ) -> WIPOffset<SyncMessage<'b>> {
-        let fbs_get_headers = FbsGetHeaders::build(fbb, block_locator_hashes);
+        let fbs_get_headers = FbsGetHeaders::build(fbb, block_locator_hashes, hash_stop);
         let mut builder = SyncMessageBuilder::new(fbb);
         builder.add_payload_type(SyncPayload::GetHeaders);
         builder.add_payload(fbs_get_headers.as_union_value());
@@ -329,6 +352,18 @@ impl<'a> SyncMessage<'a> {
         builder.add_payload(fbs_block.as_union_value());
         builder.finish()
     }
+
+    pub fn build_disconnect<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        reason: u32,
+        message: &str,
+    ) -> WIPOffset<SyncMessage<'b>> {
+        let disconnect = Disconnect::build(fbb, reason, message);
+        let mut builder = SyncMessageBuilder::new(fbb);
+        builder.add_payload_type(SyncPayload::Disconnect);
+        builder.add_payload(disconnect.as_union_value());
+        builder.finish()
+    }
 }
 
 impl<'a> CompactBlock<'a> {
@@ -338,6 +373,16 @@ impl<'a> CompactBlock<'a> {
         prefilled_transactions_indexes: &HashSet<usize>,
     ) -> WIPOffset<CompactBlock<'b>> {
         let nonce: u64 = thread_rng().gen();
+        Self::build_with_nonce(fbb, block, prefilled_transactions_indexes, nonce)
+    }
+
+    /// Same as `build` with a caller chosen short id nonce, the output is deterministic
+    pub fn build_with_nonce<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        block: &Block,
+        prefilled_transactions_indexes: &HashSet<usize>,
+        nonce: u64,
+    ) -> WIPOffset<CompactBlock<'b>> {
         // always prefill cellbase
         let prefilled_transactions_len = prefilled_transactions_indexes.len() + 1;
         let mut short_ids: Vec<_> = Vec::with_capacity(
@@ -506,6 +551,70 @@ impl<'a> RelayMessage<'a> {
         builder.add_payload(block_proposal.as_union_value());
         builder.finish()
     }
+
+    pub fn build_disconnect<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        reason: u32,
+        message: &str,
+    ) -> WIPOffset<RelayMessage<'b>> {
+        let disconnect = Disconnect::build(fbb, reason, message);
+        let mut builder = RelayMessageBuilder::new(fbb);
+        builder.add_payload_type(RelayPayload::Disconnect);
+        builder.add_payload(disconnect.as_union_value());
+        builder.finish()
+    }
+
+    pub fn build_new_header<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        header: &Header,
+    ) -> WIPOffset<RelayMessage<'b>> {
+        let new_header = {
+            let header = FbsHeader::build(fbb, header);
+            let mut builder = NewHeaderBuilder::new(fbb);
+            builder.add_header(header);
+            builder.finish()
+        };
+
+        let mut builder = RelayMessageBuilder::new(fbb);
+        builder.add_payload_type(RelayPayload::NewHeader);
+        builder.add_payload(new_header.as_union_value());
+        builder.finish()
+    }
+
+    pub fn build_handshake<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        header_first: bool,
+    ) -> WIPOffset<RelayMessage<'b>> {
+        let handshake = {
+            let mut builder = HandshakeBuilder::new(fbb);
+            builder.add_header_first(header_first);
+            builder.finish()
+        };
+
+        let mut builder = RelayMessageBuilder::new(fbb);
+        builder.add_payload_type(RelayPayload::Handshake);
+        builder.add_payload(handshake.as_union_value());
+        builder.finish()
+    }
+
+    pub fn build_transaction_reject<'b>(
+        fbb: &mut FlatBufferBuilder<'b>,
+        hash: &H256,
+        code: u32,
+    ) -> WIPOffset<RelayMessage<'b>> {
+        let transaction_reject = {
+            let hash = FbsBytes::build(fbb, hash);
+            let mut builder = TransactionRejectBuilder::new(fbb);
+            builder.add_hash(hash);
+            builder.add_code(code);
+            builder.finish()
+        };
+
+        let mut builder = RelayMessageBuilder::new(fbb);
+        builder.add_payload_type(RelayPayload::TransactionReject);
+        builder.add_payload(transaction_reject.as_union_value());
+        builder.finish()
+    }
 }
 #[cfg(test)]
 mod tests {
@@ -555,4 +664,42 @@ mod tests {
         let fbs_compact_block = get_root::<CompactBlock>(builder.finished_data());
         assert_eq!(1, fbs_compact_block.prefilled_transactions().unwrap().len());
     }
+
+    #[test]
+    fn build_transaction_reject() {
+        let hash = H256::from(7);
+        let builder = &mut FlatBufferBuilder::new();
+        let b = RelayMessage::build_transaction_reject(builder, &hash, 202);
+        builder.finish(b, None);
+
+        let message = get_root::<RelayMessage>(builder.finished_data());
+        let reject = message.payload_as_transaction_reject().unwrap();
+        assert_eq!(reject.code(), 202);
+        assert_eq!(
+            H256::from_slice(reject.hash().and_then(|bytes| bytes.seq()).unwrap()),
+            hash
+        );
+    }
+
+    #[test]
+    fn build_get_headers_hash_stop() {
+        let locator = vec![H256::from(1), H256::from(2)];
+        let builder = &mut FlatBufferBuilder::new();
+        let b = SyncMessage::build_get_headers(builder, &locator, &H256::from(3));
+        builder.finish(b, None);
+
+        let message = get_root::<SyncMessage>(builder.finished_data());
+        let get_headers = message.payload_as_get_headers().unwrap();
+        let hash_stop = get_headers.hash_stop().and_then(|bytes| bytes.seq());
+        assert_eq!(get_headers.block_locator_hashes().unwrap().len(), 2);
+        assert_eq!(H256::from_slice(hash_stop.unwrap()), H256::from(3));
+
+        // the zero stop isn't sent
+        let builder = &mut FlatBufferBuilder::new();
+        let b = SyncMessage::build_get_headers(builder, &locator, &H256::zero());
+        builder.finish(b, None);
+        let message = get_root::<SyncMessage>(builder.finished_data());
+        let get_headers = message.payload_as_get_headers().unwrap();
+        assert!(get_headers.hash_stop().is_none());
+    }
 }
@@ -1,149 +1,253 @@
+//! Conversions of the messages into the core types. The messages come from the peers, every
+//! field is checked for its presence and every hash and id for its size, a malformed message is
+//! an `Error` instead of a panic.
+
 use bigint::H256;
 use ckb_core;
+use ckb_core::transaction::ProposalShortId;
+use flatbuffers::{Follow, Vector};
 use protocol_generated::ckb::protocol as ckb_protocol;
-use FlatbuffersVectorIterator;
-
-impl<'a> From<ckb_protocol::Block<'a>> for ckb_core::block::Block {
-    fn from(block: ckb_protocol::Block<'a>) -> Self {
-        let commit_transactions =
-            FlatbuffersVectorIterator::new(block.commit_transactions().unwrap())
-                .map(Into::into)
-                .collect();
-
-        let uncles = FlatbuffersVectorIterator::new(block.uncles().unwrap())
-            .map(Into::into)
-            .collect();
-
-        let proposal_transactions =
-            FlatbuffersVectorIterator::new(block.proposal_transactions().unwrap())
-                .filter_map(|s| {
-                    s.seq()
-                        .and_then(ckb_core::transaction::ProposalShortId::from_slice)
-                }).collect();
-
-        ckb_core::block::BlockBuilder::default()
-            .header(block.header().unwrap().into())
-            .uncles(uncles)
-            .commit_transactions(commit_transactions)
-            .proposal_transactions(proposal_transactions)
-            .build()
+use {FlatbuffersVectorIterator, ShortTransactionID};
+
+/// Why a message can't be converted, named after the message table and field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// A required field is absent
+    Missing(&'static str),
+    /// A fixed size field, like a hash, has another size
+    Length {
+        field: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+}
+
+/// Fallible conversion of a message, in place of `std::convert::TryFrom` which isn't stable yet
+pub trait TryFrom<T>: Sized {
+    fn try_from(value: T) -> Result<Self, Error>;
+}
+
+/// A required field
+pub fn field<T>(value: Option<T>, name: &'static str) -> Result<T, Error> {
+    value.ok_or(Error::Missing(name))
+}
+
+/// The content of a required bytes field
+pub fn bytes<'a>(
+    value: Option<ckb_protocol::Bytes<'a>>,
+    name: &'static str,
+) -> Result<&'a [u8], Error> {
+    value
+        .and_then(|bytes| bytes.seq())
+        .ok_or(Error::Missing(name))
+}
+
+fn fixed_bytes<'a>(
+    value: Option<ckb_protocol::Bytes<'a>>,
+    name: &'static str,
+    expected: usize,
+) -> Result<&'a [u8], Error> {
+    let seq = bytes(value, name)?;
+    if seq.len() != expected {
+        return Err(Error::Length {
+            field: name,
+            expected,
+            actual: seq.len(),
+        });
     }
+    Ok(seq)
 }
 
-impl<'a> From<ckb_protocol::UncleBlock<'a>> for ckb_core::uncle::UncleBlock {
-    fn from(uncle_block: ckb_protocol::UncleBlock<'a>) -> Self {
-        ckb_core::uncle::UncleBlock {
-            header: uncle_block.header().unwrap().into(),
-            cellbase: uncle_block.cellbase().unwrap().into(),
-            proposal_transactions: FlatbuffersVectorIterator::new(
-                uncle_block.proposal_transactions().unwrap(),
-            ).filter_map(|s| {
-                s.seq()
-                    .and_then(ckb_core::transaction::ProposalShortId::from_slice)
-            }).collect(),
-        }
+/// A required hash field, 32 bytes
+pub fn hash(value: Option<ckb_protocol::Bytes>, name: &'static str) -> Result<H256, Error> {
+    fixed_bytes(value, name, 32).map(H256::from_slice)
+}
+
+/// Converts every item of a required vector field, the first malformed item fails it all
+pub fn vector<'a, T, U>(value: Option<Vector<'a, T>>, name: &'static str) -> Result<Vec<U>, Error>
+where
+    T: Follow<'a> + 'a,
+    U: TryFrom<T::Inner>,
+{
+    FlatbuffersVectorIterator::new(field(value, name)?)
+        .map(U::try_from)
+        .collect()
+}
+
+impl<'a> TryFrom<ckb_protocol::Bytes<'a>> for H256 {
+    fn try_from(bytes: ckb_protocol::Bytes<'a>) -> Result<Self, Error> {
+        hash(Some(bytes), "Bytes.seq")
     }
 }
 
-impl<'a> From<ckb_protocol::Header<'a>> for ckb_core::header::Header {
-    fn from(header: ckb_protocol::Header<'a>) -> Self {
-        ckb_core::header::HeaderBuilder::default()
-            .version(header.version())
-            .parent_hash(&H256::from_slice(
-                header.parent_hash().and_then(|b| b.seq()).unwrap(),
-            )).timestamp(header.timestamp())
-            .number(header.number())
-            .txs_commit(&H256::from_slice(
-                header.txs_commit().and_then(|b| b.seq()).unwrap(),
-            )).txs_proposal(&H256::from_slice(
-                header.txs_proposal().and_then(|b| b.seq()).unwrap(),
-            )).difficulty(
-                &H256::from_slice(header.difficulty().and_then(|b| b.seq()).unwrap()).into(),
-            ).cellbase_id(&H256::from_slice(
-                header.cellbase_id().and_then(|b| b.seq()).unwrap(),
-            )).uncles_hash(&H256::from_slice(
-                header.uncles_hash().and_then(|b| b.seq()).unwrap(),
-            )).nonce(header.nonce())
-            .proof(header.proof().and_then(|b| b.seq()).unwrap())
-            .uncles_count(header.uncles_count())
-            .build()
+impl<'a> TryFrom<ckb_protocol::Bytes<'a>> for ProposalShortId {
+    fn try_from(bytes: ckb_protocol::Bytes<'a>) -> Result<Self, Error> {
+        let seq = fixed_bytes(Some(bytes), "Bytes.seq", 10)?;
+        Ok(ProposalShortId::from_slice(seq).expect("checked length"))
+    }
+}
+
+impl<'a> TryFrom<ckb_protocol::Bytes<'a>> for ShortTransactionID {
+    fn try_from(bytes: ckb_protocol::Bytes<'a>) -> Result<Self, Error> {
+        fixed_bytes(Some(bytes), "Bytes.seq", 6).map(ShortTransactionID::from)
     }
 }
 
-impl<'a> From<ckb_protocol::Transaction<'a>> for ckb_core::transaction::Transaction {
-    fn from(transaction: ckb_protocol::Transaction<'a>) -> Self {
-        let deps = FlatbuffersVectorIterator::new(transaction.deps().unwrap())
-            .map(Into::into)
-            .collect();
+impl<'a> TryFrom<ckb_protocol::Bytes<'a>> for Vec<u8> {
+    fn try_from(bytes: ckb_protocol::Bytes<'a>) -> Result<Self, Error> {
+        self::bytes(Some(bytes), "Bytes.seq").map(|seq| seq.to_vec())
+    }
+}
 
-        let inputs = FlatbuffersVectorIterator::new(transaction.inputs().unwrap())
-            .map(Into::into)
-            .collect();
+impl<'a> TryFrom<ckb_protocol::Block<'a>> for ckb_core::block::Block {
+    fn try_from(block: ckb_protocol::Block<'a>) -> Result<Self, Error> {
+        let header = ckb_core::header::Header::try_from(field(block.header(), "Block.header")?)?;
+        Ok(ckb_core::block::BlockBuilder::default()
+            .header(header)
+            .uncles(vector(block.uncles(), "Block.uncles")?)
+            .commit_transactions(vector(
+                block.commit_transactions(),
+                "Block.commit_transactions",
+            )?)
+            .proposal_transactions(vector(
+                block.proposal_transactions(),
+                "Block.proposal_transactions",
+            )?)
+            .build())
+    }
+}
 
-        let outputs = FlatbuffersVectorIterator::new(transaction.outputs().unwrap())
-            .map(Into::into)
-            .collect();
+impl<'a> TryFrom<ckb_protocol::UncleBlock<'a>> for ckb_core::uncle::UncleBlock {
+    fn try_from(uncle_block: ckb_protocol::UncleBlock<'a>) -> Result<Self, Error> {
+        Ok(ckb_core::uncle::UncleBlock {
+            header: TryFrom::try_from(field(uncle_block.header(), "UncleBlock.header")?)?,
+            cellbase: TryFrom::try_from(field(uncle_block.cellbase(), "UncleBlock.cellbase")?)?,
+            proposal_transactions: vector(
+                uncle_block.proposal_transactions(),
+                "UncleBlock.proposal_transactions",
+            )?,
+        })
+    }
+}
 
-        ckb_core::transaction::TransactionBuilder::default()
+impl<'a> TryFrom<ckb_protocol::Header<'a>> for ckb_core::header::Header {
+    fn try_from(header: ckb_protocol::Header<'a>) -> Result<Self, Error> {
+        Ok(ckb_core::header::HeaderBuilder::default()
+            .version(header.version())
+            .parent_hash(&hash(header.parent_hash(), "Header.parent_hash")?)
+            .timestamp(header.timestamp())
+            .number(header.number())
+            .txs_commit(&hash(header.txs_commit(), "Header.txs_commit")?)
+            .txs_proposal(&hash(header.txs_proposal(), "Header.txs_proposal")?)
+            .difficulty(&hash(header.difficulty(), "Header.difficulty")?.into())
+            .cellbase_id(&hash(header.cellbase_id(), "Header.cellbase_id")?)
+            .uncles_hash(&hash(header.uncles_hash(), "Header.uncles_hash")?)
+            .nonce(header.nonce())
+            .proof(bytes(header.proof(), "Header.proof")?)
+            .uncles_count(header.uncles_count())
+            .build())
+    }
+}
+
+impl<'a> TryFrom<ckb_protocol::Transaction<'a>> for ckb_core::transaction::Transaction {
+    fn try_from(transaction: ckb_protocol::Transaction<'a>) -> Result<Self, Error> {
+        Ok(ckb_core::transaction::TransactionBuilder::default()
             .version(transaction.version())
-            .deps(deps)
-            .inputs(inputs)
-            .outputs(outputs)
-            .build()
+            .deps(vector(transaction.deps(), "Transaction.deps")?)
+            .inputs(vector(transaction.inputs(), "Transaction.inputs")?)
+            .outputs(vector(transaction.outputs(), "Transaction.outputs")?)
+            .build())
     }
 }
 
-impl<'a> From<ckb_protocol::OutPoint<'a>> for ckb_core::transaction::OutPoint {
-    fn from(out_point: ckb_protocol::OutPoint<'a>) -> Self {
-        ckb_core::transaction::OutPoint {
-            hash: H256::from_slice(out_point.hash().and_then(|b| b.seq()).unwrap()),
+impl<'a> TryFrom<ckb_protocol::OutPoint<'a>> for ckb_core::transaction::OutPoint {
+    fn try_from(out_point: ckb_protocol::OutPoint<'a>) -> Result<Self, Error> {
+        Ok(ckb_core::transaction::OutPoint {
+            hash: hash(out_point.hash(), "OutPoint.hash")?,
             index: out_point.index(),
-        }
+        })
     }
 }
 
-impl<'a> From<ckb_protocol::Script<'a>> for ckb_core::script::Script {
-    fn from(script: ckb_protocol::Script<'a>) -> Self {
-        let args = FlatbuffersVectorIterator::new(script.args().unwrap())
-            .map(|argument| argument.seq().unwrap().to_vec())
-            .collect();
-
-        let signed_args = FlatbuffersVectorIterator::new(script.signed_args().unwrap())
-            .map(|argument| argument.seq().unwrap().to_vec())
-            .collect();
-
-        ckb_core::script::Script {
+impl<'a> TryFrom<ckb_protocol::Script<'a>> for ckb_core::script::Script {
+    fn try_from(script: ckb_protocol::Script<'a>) -> Result<Self, Error> {
+        // the binary and the reference are optional, a present reference must be a hash
+        let reference = match script.reference() {
+            Some(reference) => Some(hash(Some(reference), "Script.reference")?),
+            None => None,
+        };
+        Ok(ckb_core::script::Script {
             version: script.version(),
-            args,
+            args: vector(script.args(), "Script.args")?,
             binary: script.binary().and_then(|s| s.seq()).map(|s| s.to_vec()),
-            signed_args,
-            reference: script
-                .reference()
-                .and_then(|s| s.seq())
-                .map(|s| H256::from_slice(s)),
-        }
+            signed_args: vector(script.signed_args(), "Script.signed_args")?,
+            reference,
+        })
     }
 }
 
-impl<'a> From<ckb_protocol::CellInput<'a>> for ckb_core::transaction::CellInput {
-    fn from(cell_input: ckb_protocol::CellInput<'a>) -> Self {
-        ckb_core::transaction::CellInput {
+impl<'a> TryFrom<ckb_protocol::CellInput<'a>> for ckb_core::transaction::CellInput {
+    fn try_from(cell_input: ckb_protocol::CellInput<'a>) -> Result<Self, Error> {
+        Ok(ckb_core::transaction::CellInput {
             previous_output: ckb_core::transaction::OutPoint {
-                hash: H256::from_slice(cell_input.hash().and_then(|b| b.seq()).unwrap()),
+                hash: hash(cell_input.hash(), "CellInput.hash")?,
                 index: cell_input.index(),
             },
-            unlock: cell_input.unlock().unwrap().into(),
-        }
+            unlock: TryFrom::try_from(field(cell_input.unlock(), "CellInput.unlock")?)?,
+        })
     }
 }
 
-impl<'a> From<ckb_protocol::CellOutput<'a>> for ckb_core::transaction::CellOutput {
-    fn from(cell_output: ckb_protocol::CellOutput<'a>) -> Self {
-        ckb_core::transaction::CellOutput {
+impl<'a> TryFrom<ckb_protocol::CellOutput<'a>> for ckb_core::transaction::CellOutput {
+    fn try_from(cell_output: ckb_protocol::CellOutput<'a>) -> Result<Self, Error> {
+        let contract = match cell_output.contract() {
+            Some(contract) => Some(TryFrom::try_from(contract)?),
+            None => None,
+        };
+        Ok(ckb_core::transaction::CellOutput {
             capacity: cell_output.capacity(),
-            data: cell_output.data().and_then(|b| b.seq()).unwrap().to_vec(),
-            lock: H256::from_slice(cell_output.lock().and_then(|b| b.seq()).unwrap()),
-            contract: cell_output.contract().map(Into::into),
-        }
+            data: bytes(cell_output.data(), "CellOutput.data")?.to_vec(),
+            lock: hash(cell_output.lock(), "CellOutput.lock")?,
+            contract,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_core::header::Header;
+    use flatbuffers::{get_root, FlatBufferBuilder};
+
+    fn decode_header(parent_hash: &[u8], with_txs_commit: bool) -> Result<Header, Error> {
+        let fbb = &mut FlatBufferBuilder::new();
+        let parent_hash = ckb_protocol::Bytes::build(fbb, parent_hash);
+        let hash = ckb_protocol::Bytes::build(fbb, &[0; 32]);
+        let header = {
+            let mut builder = ckb_protocol::HeaderBuilder::new(fbb);
+            builder.add_parent_hash(parent_hash);
+            if with_txs_commit {
+                builder.add_txs_commit(hash);
+            }
+            builder.finish()
+        };
+        fbb.finish(header, None);
+        Header::try_from(get_root::<ckb_protocol::Header>(fbb.finished_data()))
+    }
+
+    #[test]
+    fn test_malformed_header() {
+        assert_eq!(
+            decode_header(&[0; 31], true).unwrap_err(),
+            Error::Length {
+                field: "Header.parent_hash",
+                expected: 32,
+                actual: 31,
+            }
+        );
+        assert_eq!(
+            decode_header(&[0; 32], false).unwrap_err(),
+            Error::Missing("Header.txs_commit")
+        );
     }
 }
@@ -6,10 +6,17 @@ extern crate hash;
 extern crate rand;
 extern crate siphasher;
 
+#[cfg(test)]
+extern crate faster_hex;
+
 mod builder;
-mod convert;
+pub mod convert;
 mod protocol_generated;
 
+#[cfg(test)]
+mod tests;
+
+pub use convert::{Error, TryFrom};
 pub use protocol_generated::ckb::protocol::*;
 
 use bigint::{H256, H48};
@@ -35,11 +35,12 @@ pub enum SyncPayload {
   Headers = 2,
   GetBlocks = 3,
   Block = 4,
+  Disconnect = 5,
 
 }
 
 const ENUM_MIN_SYNC_PAYLOAD: u8 = 0;
-const ENUM_MAX_SYNC_PAYLOAD: u8 = 4;
+const ENUM_MAX_SYNC_PAYLOAD: u8 = 5;
 
 impl<'a> flatbuffers::Follow<'a> for SyncPayload {
   type Inner = Self;
@@ -73,21 +74,23 @@ impl flatbuffers::Push for SyncPayload {
 }
 
 #[allow(non_camel_case_types)]
-const ENUM_VALUES_SYNC_PAYLOAD:[SyncPayload; 5] = [
+const ENUM_VALUES_SYNC_PAYLOAD:[SyncPayload; 6] = [
   SyncPayload::NONE,
   SyncPayload::GetHeaders,
   SyncPayload::Headers,
   SyncPayload::GetBlocks,
-  SyncPayload::Block
+  SyncPayload::Block,
+  SyncPayload::Disconnect
 ];
 
 #[allow(non_camel_case_types)]
-const ENUM_NAMES_SYNC_PAYLOAD:[&'static str; 5] = [
+const ENUM_NAMES_SYNC_PAYLOAD:[&'static str; 6] = [
     "NONE",
     "GetHeaders",
     "Headers",
     "GetBlocks",
-    "Block"
+    "Block",
+    "Disconnect"
 ];
 
 pub fn enum_name_sync_payload(e: SyncPayload) -> &'static str {
@@ -107,11 +110,15 @@ pub enum RelayPayload {
   BlockTransactions = 4,
   GetBlockProposal = 5,
   BlockProposal = 6,
+  Disconnect = 7,
+  NewHeader = 8,
+  Handshake = 9,
+  TransactionReject = 10,
 
 }
 
 const ENUM_MIN_RELAY_PAYLOAD: u8 = 0;
-const ENUM_MAX_RELAY_PAYLOAD: u8 = 6;
+const ENUM_MAX_RELAY_PAYLOAD: u8 = 10;
 
 impl<'a> flatbuffers::Follow<'a> for RelayPayload {
   type Inner = Self;
@@ -145,25 +152,33 @@ impl flatbuffers::Push for RelayPayload {
 }
 
 #[allow(non_camel_case_types)]
-const ENUM_VALUES_RELAY_PAYLOAD:[RelayPayload; 7] = [
+const ENUM_VALUES_RELAY_PAYLOAD:[RelayPayload; 11] = [
   RelayPayload::NONE,
   RelayPayload::CompactBlock,
   RelayPayload::Transaction,
   RelayPayload::GetBlockTransactions,
   RelayPayload::BlockTransactions,
   RelayPayload::GetBlockProposal,
-  RelayPayload::BlockProposal
+  RelayPayload::BlockProposal,
+  RelayPayload::Disconnect,
+  RelayPayload::NewHeader,
+  RelayPayload::Handshake,
+  RelayPayload::TransactionReject
 ];
 
 #[allow(non_camel_case_types)]
-const ENUM_NAMES_RELAY_PAYLOAD:[&'static str; 7] = [
+const ENUM_NAMES_RELAY_PAYLOAD:[&'static str; 11] = [
     "NONE",
     "CompactBlock",
     "Transaction",
     "GetBlockTransactions",
     "BlockTransactions",
     "GetBlockProposal",
-    "BlockProposal"
+    "BlockProposal",
+    "Disconnect",
+    "NewHeader",
+    "Handshake",
+    "TransactionReject"
 ];
 
 pub fn enum_name_relay_payload(e: RelayPayload) -> &'static str {
@@ -257,6 +272,16 @@ impl<'a> SyncMessage<'a> {
     }
   }
 
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn payload_as_disconnect(&'a self) -> Option<Disconnect> {
+    if self.payload_type() == SyncPayload::Disconnect {
+      self.payload().map(|u| Disconnect::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
 }
 
 pub struct SyncMessageArgs {
@@ -1689,6 +1714,46 @@ impl<'a> RelayMessage<'a> {
     }
   }
 
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn payload_as_disconnect(&'a self) -> Option<Disconnect> {
+    if self.payload_type() == RelayPayload::Disconnect {
+      self.payload().map(|u| Disconnect::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn payload_as_new_header(&'a self) -> Option<NewHeader> {
+    if self.payload_type() == RelayPayload::NewHeader {
+      self.payload().map(|u| NewHeader::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn payload_as_handshake(&'a self) -> Option<Handshake> {
+    if self.payload_type() == RelayPayload::Handshake {
+      self.payload().map(|u| Handshake::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
+  #[inline]
+  #[allow(non_snake_case)]
+  pub fn payload_as_transaction_reject(&'a self) -> Option<TransactionReject> {
+    if self.payload_type() == RelayPayload::TransactionReject {
+      self.payload().map(|u| TransactionReject::init_from_table(u))
+    } else {
+      None
+    }
+  }
+
 }
 
 pub struct RelayMessageArgs {
@@ -2296,6 +2361,334 @@ impl<'a: 'b, 'b> BlockProposalBuilder<'a, 'b> {
   }
 }
 
+pub enum DisconnectOffset {}
+#[derive(Copy, Clone, Debug, PartialEq)]
+
+pub struct Disconnect<'a> {
+  pub _tab: flatbuffers::Table<'a>,
+}
+
+impl<'a> flatbuffers::Follow<'a> for Disconnect<'a> {
+    type Inner = Disconnect<'a>;
+    #[inline]
+    fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        Self {
+            _tab: flatbuffers::Table { buf: buf, loc: loc },
+        }
+    }
+}
+
+impl<'a> Disconnect<'a> {
+    #[inline]
+    pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+        Disconnect {
+            _tab: table,
+        }
+    }
+    #[allow(unused_mut)]
+    pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+        _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+        args: &'args DisconnectArgs<'args>) -> flatbuffers::WIPOffset<Disconnect<'bldr>> {
+      let mut builder = DisconnectBuilder::new(_fbb);
+      if let Some(x) = args.message { builder.add_message(x); }
+      builder.add_reason(args.reason);
+      builder.finish()
+    }
+
+    pub const VT_REASON: flatbuffers::VOffsetT = 4;
+    pub const VT_MESSAGE: flatbuffers::VOffsetT = 6;
+
+  #[inline]
+  pub fn reason(&self) -> u32 {
+    self._tab.get::<u32>(Disconnect::VT_REASON, Some(0)).unwrap()
+  }
+  #[inline]
+  pub fn message(&self) -> Option<&'a str> {
+    self._tab.get::<flatbuffers::ForwardsUOffset<&str>>(Disconnect::VT_MESSAGE, None)
+  }
+}
+
+pub struct DisconnectArgs<'a> {
+    pub reason: u32,
+    pub message: Option<flatbuffers::WIPOffset<&'a  str>>,
+}
+impl<'a> Default for DisconnectArgs<'a> {
+    #[inline]
+    fn default() -> Self {
+        DisconnectArgs {
+            reason: 0,
+            message: None,
+        }
+    }
+}
+pub struct DisconnectBuilder<'a: 'b, 'b> {
+  fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+  start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+}
+impl<'a: 'b, 'b> DisconnectBuilder<'a, 'b> {
+  #[inline]
+  pub fn add_reason(&mut self, reason: u32) {
+    self.fbb_.push_slot::<u32>(Disconnect::VT_REASON, reason, 0);
+  }
+  #[inline]
+  pub fn add_message(&mut self, message: flatbuffers::WIPOffset<&'b  str>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<_>>(Disconnect::VT_MESSAGE, message);
+  }
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> DisconnectBuilder<'a, 'b> {
+    let start = _fbb.start_table();
+    DisconnectBuilder {
+      fbb_: _fbb,
+      start_: start,
+    }
+  }
+  #[inline]
+  pub fn finish(self) -> flatbuffers::WIPOffset<Disconnect<'a>> {
+    let o = self.fbb_.end_table(self.start_);
+    flatbuffers::WIPOffset::new(o.value())
+  }
+}
+
+pub enum NewHeaderOffset {}
+#[derive(Copy, Clone, Debug, PartialEq)]
+
+pub struct NewHeader<'a> {
+  pub _tab: flatbuffers::Table<'a>,
+}
+
+impl<'a> flatbuffers::Follow<'a> for NewHeader<'a> {
+    type Inner = NewHeader<'a>;
+    #[inline]
+    fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        Self {
+            _tab: flatbuffers::Table { buf: buf, loc: loc },
+        }
+    }
+}
+
+impl<'a> NewHeader<'a> {
+    #[inline]
+    pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+        NewHeader {
+            _tab: table,
+        }
+    }
+    #[allow(unused_mut)]
+    pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+        _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+        args: &'args NewHeaderArgs<'args>) -> flatbuffers::WIPOffset<NewHeader<'bldr>> {
+      let mut builder = NewHeaderBuilder::new(_fbb);
+      if let Some(x) = args.header { builder.add_header(x); }
+      builder.finish()
+    }
+
+    pub const VT_HEADER: flatbuffers::VOffsetT = 4;
+
+  #[inline]
+  pub fn header(&self) -> Option<Header<'a>> {
+    self._tab.get::<flatbuffers::ForwardsUOffset<Header<'a>>>(NewHeader::VT_HEADER, None)
+  }
+}
+
+pub struct NewHeaderArgs<'a> {
+    pub header: Option<flatbuffers::WIPOffset<Header<'a >>>,
+}
+impl<'a> Default for NewHeaderArgs<'a> {
+    #[inline]
+    fn default() -> Self {
+        NewHeaderArgs {
+            header: None,
+        }
+    }
+}
+pub struct NewHeaderBuilder<'a: 'b, 'b> {
+  fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+  start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+}
+impl<'a: 'b, 'b> NewHeaderBuilder<'a, 'b> {
+  #[inline]
+  pub fn add_header(&mut self, header: flatbuffers::WIPOffset<Header<'b >>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<Header>>(NewHeader::VT_HEADER, header);
+  }
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> NewHeaderBuilder<'a, 'b> {
+    let start = _fbb.start_table();
+    NewHeaderBuilder {
+      fbb_: _fbb,
+      start_: start,
+    }
+  }
+  #[inline]
+  pub fn finish(self) -> flatbuffers::WIPOffset<NewHeader<'a>> {
+    let o = self.fbb_.end_table(self.start_);
+    flatbuffers::WIPOffset::new(o.value())
+  }
+}
+
+pub enum HandshakeOffset {}
+#[derive(Copy, Clone, Debug, PartialEq)]
+
+pub struct Handshake<'a> {
+  pub _tab: flatbuffers::Table<'a>,
+}
+
+impl<'a> flatbuffers::Follow<'a> for Handshake<'a> {
+    type Inner = Handshake<'a>;
+    #[inline]
+    fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        Self {
+            _tab: flatbuffers::Table { buf: buf, loc: loc },
+        }
+    }
+}
+
+impl<'a> Handshake<'a> {
+    #[inline]
+    pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+        Handshake {
+            _tab: table,
+        }
+    }
+    #[allow(unused_mut)]
+    pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+        _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+        args: &'args HandshakeArgs) -> flatbuffers::WIPOffset<Handshake<'bldr>> {
+      let mut builder = HandshakeBuilder::new(_fbb);
+      builder.add_header_first(args.header_first);
+      builder.finish()
+    }
+
+    pub const VT_HEADER_FIRST: flatbuffers::VOffsetT = 4;
+
+  #[inline]
+  pub fn header_first(&self) -> bool {
+    self._tab.get::<bool>(Handshake::VT_HEADER_FIRST, Some(false)).unwrap()
+  }
+}
+
+pub struct HandshakeArgs {
+    pub header_first: bool,
+}
+impl<'a> Default for HandshakeArgs {
+    #[inline]
+    fn default() -> Self {
+        HandshakeArgs {
+            header_first: false,
+        }
+    }
+}
+pub struct HandshakeBuilder<'a: 'b, 'b> {
+  fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+  start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+}
+impl<'a: 'b, 'b> HandshakeBuilder<'a, 'b> {
+  #[inline]
+  pub fn add_header_first(&mut self, header_first: bool) {
+    self.fbb_.push_slot::<bool>(Handshake::VT_HEADER_FIRST, header_first, false);
+  }
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> HandshakeBuilder<'a, 'b> {
+    let start = _fbb.start_table();
+    HandshakeBuilder {
+      fbb_: _fbb,
+      start_: start,
+    }
+  }
+  #[inline]
+  pub fn finish(self) -> flatbuffers::WIPOffset<Handshake<'a>> {
+    let o = self.fbb_.end_table(self.start_);
+    flatbuffers::WIPOffset::new(o.value())
+  }
+}
+
+pub enum TransactionRejectOffset {}
+#[derive(Copy, Clone, Debug, PartialEq)]
+
+pub struct TransactionReject<'a> {
+  pub _tab: flatbuffers::Table<'a>,
+}
+
+impl<'a> flatbuffers::Follow<'a> for TransactionReject<'a> {
+    type Inner = TransactionReject<'a>;
+    #[inline]
+    fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+        Self {
+            _tab: flatbuffers::Table { buf: buf, loc: loc },
+        }
+    }
+}
+
+impl<'a> TransactionReject<'a> {
+    #[inline]
+    pub fn init_from_table(table: flatbuffers::Table<'a>) -> Self {
+        TransactionReject {
+            _tab: table,
+        }
+    }
+    #[allow(unused_mut)]
+    pub fn create<'bldr: 'args, 'args: 'mut_bldr, 'mut_bldr>(
+        _fbb: &'mut_bldr mut flatbuffers::FlatBufferBuilder<'bldr>,
+        args: &'args TransactionRejectArgs<'args>) -> flatbuffers::WIPOffset<TransactionReject<'bldr>> {
+      let mut builder = TransactionRejectBuilder::new(_fbb);
+      builder.add_code(args.code);
+      if let Some(x) = args.hash { builder.add_hash(x); }
+      builder.finish()
+    }
+
+    pub const VT_HASH: flatbuffers::VOffsetT = 4;
+    pub const VT_CODE: flatbuffers::VOffsetT = 6;
+
+  #[inline]
+  pub fn hash(&self) -> Option<Bytes<'a>> {
+    self._tab.get::<flatbuffers::ForwardsUOffset<Bytes<'a>>>(TransactionReject::VT_HASH, None)
+  }
+  #[inline]
+  pub fn code(&self) -> u32 {
+    self._tab.get::<u32>(TransactionReject::VT_CODE, Some(0)).unwrap()
+  }
+}
+
+pub struct TransactionRejectArgs<'a> {
+    pub hash: Option<flatbuffers::WIPOffset<Bytes<'a >>>,
+    pub code: u32,
+}
+impl<'a> Default for TransactionRejectArgs<'a> {
+    #[inline]
+    fn default() -> Self {
+        TransactionRejectArgs {
+            hash: None,
+            code: 0,
+        }
+    }
+}
+pub struct TransactionRejectBuilder<'a: 'b, 'b> {
+  fbb_: &'b mut flatbuffers::FlatBufferBuilder<'a>,
+  start_: flatbuffers::WIPOffset<flatbuffers::TableUnfinishedWIPOffset>,
+}
+impl<'a: 'b, 'b> TransactionRejectBuilder<'a, 'b> {
+  #[inline]
+  pub fn add_hash(&mut self, hash: flatbuffers::WIPOffset<Bytes<'b >>) {
+    self.fbb_.push_slot_always::<flatbuffers::WIPOffset<Bytes>>(TransactionReject::VT_HASH, hash);
+  }
+  #[inline]
+  pub fn add_code(&mut self, code: u32) {
+    self.fbb_.push_slot::<u32>(TransactionReject::VT_CODE, code, 0);
+  }
+  #[inline]
+  pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> TransactionRejectBuilder<'a, 'b> {
+    let start = _fbb.start_table();
+    TransactionRejectBuilder {
+      fbb_: _fbb,
+      start_: start,
+    }
+  }
+  #[inline]
+  pub fn finish(self) -> flatbuffers::WIPOffset<TransactionReject<'a>> {
+    let o = self.fbb_.end_table(self.start_);
+    flatbuffers::WIPOffset::new(o.value())
+  }
+}
+
 #[inline]
 pub fn get_root_as_sync_message<'a>(buf: &'a [u8]) -> SyncMessage<'a> {
   flatbuffers::get_root::<SyncMessage<'a>>(buf)
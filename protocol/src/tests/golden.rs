@@ -0,0 +1,410 @@
+//! Golden vectors for the wire format.
+//!
+//! Each representative message is encoded and compared with the hex dump checked in under
+//! `fixtures/golden`, then decoded back and compared with the original value. A mismatch
+//! means the wire format changed: if that is intended, regenerate the vectors with
+//! `UPDATE_GOLDEN=1 cargo test -p ckb-protocol golden` and review the diff.
+
+use bigint::{H256, U256};
+use ckb_core::block::{Block, BlockBuilder};
+use ckb_core::header::{Header, HeaderBuilder};
+use ckb_core::script::Script;
+use ckb_core::transaction::{
+    CellInput, CellOutput, OutPoint, ProposalShortId, Transaction, TransactionBuilder,
+};
+use ckb_core::uncle::UncleBlock;
+use faster_hex::hex_string;
+use flatbuffers::{self, get_root, FlatBufferBuilder};
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use {
+    convert, short_transaction_id, short_transaction_id_keys, CompactBlock,
+    FlatbuffersVectorIterator, RelayMessage, RelayMessageBuilder, RelayPayload, ShortTransactionID,
+    SyncMessage, SyncPayload, TryFrom,
+};
+
+fn check_golden(name: &str, data: &[u8]) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("fixtures")
+        .join("golden")
+        .join(format!("{}.hex", name));
+    let actual = hex_string(data).expect("hex string");
+
+    if env::var("UPDATE_GOLDEN").is_ok() {
+        fs::write(&path, format!("{}\n", actual)).expect("write golden vector");
+        return;
+    }
+
+    let expected = fs::read_to_string(&path).unwrap_or_else(|_| {
+        panic!(
+            "missing golden vector {}, run with UPDATE_GOLDEN=1 to generate it",
+            path.display()
+        )
+    });
+    assert_eq!(expected.trim(), actual, "wire format of {} changed", name);
+}
+
+fn hashes<'a>(
+    vector: Option<flatbuffers::Vector<'a, flatbuffers::ForwardsUOffset<::Bytes<'a>>>>,
+) -> Vec<H256> {
+    convert::vector(vector, "hashes").unwrap()
+}
+
+fn proposal_ids<'a>(
+    vector: Option<flatbuffers::Vector<'a, flatbuffers::ForwardsUOffset<::Bytes<'a>>>>,
+) -> Vec<ProposalShortId> {
+    convert::vector(vector, "proposal_ids").unwrap()
+}
+
+fn transactions<'a>(
+    vector: Option<flatbuffers::Vector<'a, flatbuffers::ForwardsUOffset<::Transaction<'a>>>>,
+) -> Vec<Transaction> {
+    convert::vector(vector, "transactions").unwrap()
+}
+
+fn script() -> Script {
+    Script::new(
+        0,
+        vec![b"arg".to_vec()],
+        Some(H256::from(7)),
+        Some(b"binary".to_vec()),
+        vec![b"signed".to_vec()],
+    )
+}
+
+fn cellbase(number: u64) -> Transaction {
+    TransactionBuilder::default()
+        .input(CellInput::new_cellbase_input(number))
+        .output(CellOutput::new(50_000, Vec::new(), H256::from(1), None))
+        .build()
+}
+
+fn transaction() -> Transaction {
+    TransactionBuilder::default()
+        .version(1)
+        .dep(OutPoint::new(H256::from(2), 0))
+        .input(CellInput::new(OutPoint::new(H256::from(3), 1), script()))
+        .output(CellOutput::new(
+            100,
+            b"data".to_vec(),
+            H256::from(4),
+            Some(script()),
+        )).build()
+}
+
+fn proposal_id() -> ProposalShortId {
+    ProposalShortId::from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]).unwrap()
+}
+
+fn header_builder(number: u64) -> HeaderBuilder {
+    HeaderBuilder::default()
+        .parent_hash(&H256::from(5))
+        .timestamp(1_540_000_000_000)
+        .number(number)
+        .difficulty(&U256::from(1000))
+        .nonce(42)
+        .proof(&[9, 8, 7])
+}
+
+fn header() -> Header {
+    header_builder(10).build()
+}
+
+fn block() -> Block {
+    let uncle_cellbase = cellbase(9);
+    let uncle = BlockBuilder::default()
+        .commit_transaction(uncle_cellbase.clone())
+        .proposal_transaction(proposal_id())
+        .with_header_builder(header_builder(9).cellbase_id(&uncle_cellbase.hash()));
+
+    let cellbase = cellbase(10);
+    BlockBuilder::default()
+        .commit_transaction(cellbase.clone())
+        .commit_transaction(transaction())
+        .uncle(UncleBlock::from(uncle))
+        .proposal_transaction(proposal_id())
+        .with_header_builder(header_builder(10).cellbase_id(&cellbase.hash()))
+}
+
+#[test]
+fn sync_get_headers() {
+    let locator = vec![H256::from(1), H256::from(2)];
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = SyncMessage::build_get_headers(fbb, &locator, &H256::zero());
+    fbb.finish(message, None);
+    check_golden("sync_get_headers", fbb.finished_data());
+
+    let message = get_root::<SyncMessage>(fbb.finished_data());
+    assert_eq!(message.payload_type(), SyncPayload::GetHeaders);
+    let get_headers = message.payload_as_get_headers().unwrap();
+    assert_eq!(hashes(get_headers.block_locator_hashes()), locator);
+}
+
+#[test]
+fn sync_headers() {
+    let headers = vec![header()];
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = SyncMessage::build_headers(fbb, &headers);
+    fbb.finish(message, None);
+    check_golden("sync_headers", fbb.finished_data());
+
+    let message = get_root::<SyncMessage>(fbb.finished_data());
+    assert_eq!(message.payload_type(), SyncPayload::Headers);
+    let decoded: Vec<Header> =
+        convert::vector(message.payload_as_headers().unwrap().headers(), "headers").unwrap();
+    assert_eq!(decoded, headers);
+}
+
+#[test]
+fn sync_get_blocks() {
+    let block_hashes = vec![H256::from(1), H256::from(2)];
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = SyncMessage::build_get_blocks(fbb, &block_hashes);
+    fbb.finish(message, None);
+    check_golden("sync_get_blocks", fbb.finished_data());
+
+    let message = get_root::<SyncMessage>(fbb.finished_data());
+    assert_eq!(message.payload_type(), SyncPayload::GetBlocks);
+    let get_blocks = message.payload_as_get_blocks().unwrap();
+    assert_eq!(hashes(get_blocks.block_hashes()), block_hashes);
+}
+
+#[test]
+fn sync_block() {
+    let block = block();
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = SyncMessage::build_block(fbb, &block);
+    fbb.finish(message, None);
+    check_golden("sync_block", fbb.finished_data());
+
+    let message = get_root::<SyncMessage>(fbb.finished_data());
+    assert_eq!(message.payload_type(), SyncPayload::Block);
+    let decoded = Block::try_from(message.payload_as_block().unwrap()).unwrap();
+    assert_eq!(decoded, block);
+    assert_eq!(decoded.commit_transactions(), block.commit_transactions());
+    assert_eq!(decoded.uncles(), block.uncles());
+    assert_eq!(
+        decoded.proposal_transactions(),
+        block.proposal_transactions()
+    );
+}
+
+#[test]
+fn relay_compact_block() {
+    let block = block();
+    let nonce = 0x0102_0304_0506_0708;
+    let fbb = &mut FlatBufferBuilder::new();
+    let compact_block = CompactBlock::build_with_nonce(fbb, &block, &HashSet::new(), nonce);
+    let message = {
+        let mut builder = RelayMessageBuilder::new(fbb);
+        builder.add_payload_type(RelayPayload::CompactBlock);
+        builder.add_payload(compact_block.as_union_value());
+        builder.finish()
+    };
+    fbb.finish(message, None);
+    check_golden("relay_compact_block", fbb.finished_data());
+
+    let message = get_root::<RelayMessage>(fbb.finished_data());
+    assert_eq!(message.payload_type(), RelayPayload::CompactBlock);
+    let compact_block = message.payload_as_compact_block().unwrap();
+    assert_eq!(compact_block.nonce(), nonce);
+    assert_eq!(
+        Header::try_from(compact_block.header().unwrap()).unwrap(),
+        block.header().clone()
+    );
+
+    let prefilled: Vec<_> =
+        FlatbuffersVectorIterator::new(compact_block.prefilled_transactions().unwrap())
+            .map(|prefilled| {
+                (
+                    prefilled.index(),
+                    Transaction::try_from(prefilled.transaction().unwrap()).unwrap(),
+                )
+            }).collect();
+    assert_eq!(prefilled, vec![(0, block.commit_transactions()[0].clone())]);
+
+    let (key0, key1) = short_transaction_id_keys(block.header().nonce(), nonce);
+    let short_ids: Vec<ShortTransactionID> =
+        convert::vector(compact_block.short_ids(), "short_ids").unwrap();
+    assert_eq!(
+        short_ids,
+        vec![short_transaction_id(
+            key0,
+            key1,
+            &block.commit_transactions()[1].hash()
+        )]
+    );
+
+    let uncles: Vec<UncleBlock> = convert::vector(compact_block.uncles(), "uncles").unwrap();
+    assert_eq!(uncles, block.uncles());
+    assert_eq!(
+        proposal_ids(compact_block.proposal_transactions()),
+        block.proposal_transactions()
+    );
+}
+
+#[test]
+fn relay_transaction() {
+    let transaction = transaction();
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = RelayMessage::build_transaction(fbb, &transaction);
+    fbb.finish(message, None);
+    check_golden("relay_transaction", fbb.finished_data());
+
+    let message = get_root::<RelayMessage>(fbb.finished_data());
+    assert_eq!(message.payload_type(), RelayPayload::Transaction);
+    let decoded = Transaction::try_from(message.payload_as_transaction().unwrap()).unwrap();
+    assert_eq!(decoded, transaction);
+}
+
+#[test]
+fn relay_get_block_transactions() {
+    let hash = H256::from(6);
+    let indexes = vec![1, 3];
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = RelayMessage::build_get_block_transactions(fbb, &hash, &indexes);
+    fbb.finish(message, None);
+    check_golden("relay_get_block_transactions", fbb.finished_data());
+
+    let message = get_root::<RelayMessage>(fbb.finished_data());
+    assert_eq!(message.payload_type(), RelayPayload::GetBlockTransactions);
+    let get_block_transactions = message.payload_as_get_block_transactions().unwrap();
+    assert_eq!(
+        H256::from_slice(get_block_transactions.hash().unwrap().seq().unwrap()),
+        hash
+    );
+    let decoded: Vec<u32> =
+        FlatbuffersVectorIterator::new(get_block_transactions.indexes().unwrap()).collect();
+    assert_eq!(decoded, indexes);
+}
+
+#[test]
+fn relay_block_transactions() {
+    let hash = H256::from(6);
+    let txs = vec![transaction()];
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = RelayMessage::build_block_transactions(fbb, &hash, &txs);
+    fbb.finish(message, None);
+    check_golden("relay_block_transactions", fbb.finished_data());
+
+    let message = get_root::<RelayMessage>(fbb.finished_data());
+    assert_eq!(message.payload_type(), RelayPayload::BlockTransactions);
+    let block_transactions = message.payload_as_block_transactions().unwrap();
+    assert_eq!(
+        H256::from_slice(block_transactions.hash().unwrap().seq().unwrap()),
+        hash
+    );
+    assert_eq!(transactions(block_transactions.transactions()), txs);
+}
+
+#[test]
+fn relay_get_block_proposal() {
+    let ids = vec![proposal_id()];
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = RelayMessage::build_get_block_proposal(fbb, 10, &ids);
+    fbb.finish(message, None);
+    check_golden("relay_get_block_proposal", fbb.finished_data());
+
+    let message = get_root::<RelayMessage>(fbb.finished_data());
+    assert_eq!(message.payload_type(), RelayPayload::GetBlockProposal);
+    let get_block_proposal = message.payload_as_get_block_proposal().unwrap();
+    assert_eq!(get_block_proposal.block_number(), 10);
+    assert_eq!(
+        proposal_ids(get_block_proposal.proposal_transactions()),
+        ids
+    );
+}
+
+#[test]
+fn relay_block_proposal() {
+    let txs = vec![transaction()];
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = RelayMessage::build_block_proposal(fbb, &txs);
+    fbb.finish(message, None);
+    check_golden("relay_block_proposal", fbb.finished_data());
+
+    let message = get_root::<RelayMessage>(fbb.finished_data());
+    assert_eq!(message.payload_type(), RelayPayload::BlockProposal);
+    let block_proposal = message.payload_as_block_proposal().unwrap();
+    assert_eq!(transactions(block_proposal.transactions()), txs);
+}
+
+#[test]
+fn sync_disconnect() {
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = SyncMessage::build_disconnect(fbb, 3, "without common headers");
+    fbb.finish(message, None);
+    check_golden("sync_disconnect", fbb.finished_data());
+
+    let message = get_root::<SyncMessage>(fbb.finished_data());
+    assert_eq!(message.payload_type(), SyncPayload::Disconnect);
+    let disconnect = message.payload_as_disconnect().unwrap();
+    assert_eq!(disconnect.reason(), 3);
+    assert_eq!(disconnect.message(), Some("without common headers"));
+}
+
+#[test]
+fn relay_disconnect() {
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = RelayMessage::build_disconnect(fbb, 1, "timeout");
+    fbb.finish(message, None);
+    check_golden("relay_disconnect", fbb.finished_data());
+
+    let message = get_root::<RelayMessage>(fbb.finished_data());
+    assert_eq!(message.payload_type(), RelayPayload::Disconnect);
+    let disconnect = message.payload_as_disconnect().unwrap();
+    assert_eq!(disconnect.reason(), 1);
+    assert_eq!(disconnect.message(), Some("timeout"));
+}
+
+#[test]
+fn relay_new_header() {
+    let header = header();
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = RelayMessage::build_new_header(fbb, &header);
+    fbb.finish(message, None);
+    check_golden("relay_new_header", fbb.finished_data());
+
+    let message = get_root::<RelayMessage>(fbb.finished_data());
+    assert_eq!(message.payload_type(), RelayPayload::NewHeader);
+    let new_header = message.payload_as_new_header().unwrap();
+    assert_eq!(
+        Header::try_from(new_header.header().unwrap()).unwrap(),
+        header
+    );
+}
+
+#[test]
+fn relay_handshake() {
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = RelayMessage::build_handshake(fbb, true);
+    fbb.finish(message, None);
+    check_golden("relay_handshake", fbb.finished_data());
+
+    let message = get_root::<RelayMessage>(fbb.finished_data());
+    assert_eq!(message.payload_type(), RelayPayload::Handshake);
+    assert!(message.payload_as_handshake().unwrap().header_first());
+}
+
+#[test]
+fn relay_transaction_reject() {
+    let hash = H256::from(1);
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = RelayMessage::build_transaction_reject(fbb, &hash, 1);
+    fbb.finish(message, None);
+    check_golden("relay_transaction_reject", fbb.finished_data());
+
+    let message = get_root::<RelayMessage>(fbb.finished_data());
+    assert_eq!(message.payload_type(), RelayPayload::TransactionReject);
+    let reject = message.payload_as_transaction_reject().unwrap();
+    assert_eq!(
+        reject
+            .hash()
+            .and_then(|hash| hash.seq())
+            .map(H256::from_slice),
+        Some(hash)
+    );
+    assert_eq!(reject.code(), 1);
+}
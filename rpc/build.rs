@@ -0,0 +1,32 @@
+#[cfg(feature = "grpc")]
+extern crate protoc_grpcio;
+
+fn main() {
+    #[cfg(feature = "grpc")]
+    compile_protos();
+}
+
+// Generates the messages and the service of proto/ckb.proto into OUT_DIR, requires `protoc`
+#[cfg(feature = "grpc")]
+fn compile_protos() {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    println!("cargo:rerun-if-changed=proto/ckb.proto");
+    let out_dir = env::var("OUT_DIR").unwrap();
+    protoc_grpcio::compile_grpc_protos(&["ckb.proto"], &["proto"], &out_dir)
+        .expect("compile proto/ckb.proto");
+
+    // The generated files are included as modules, which can't have inner attributes or docs
+    for name in &["ckb.rs", "ckb_grpc.rs"] {
+        let path = Path::new(&out_dir).join(name);
+        let code = fs::read_to_string(&path).unwrap();
+        let code = code
+            .lines()
+            .filter(|line| !line.starts_with("#![") && !line.starts_with("//!"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        fs::write(&path, code).unwrap();
+    }
+}
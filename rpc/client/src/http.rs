@@ -0,0 +1,42 @@
+use futures::{Future, Stream};
+use hyper::client::HttpConnector;
+use hyper::header::ContentType;
+use hyper::{Client, Method, Request, Uri};
+use tokio_core::reactor::Handle;
+use {ClientError, RpcFuture, Transport};
+
+/// Posts the requests to the HTTP server of the node, on the event loop of the handle
+pub struct HttpTransport {
+    client: Client<HttpConnector>,
+    uri: Uri,
+}
+
+impl HttpTransport {
+    pub fn new(url: &str, handle: &Handle) -> Result<Self, ClientError> {
+        let uri = url
+            .parse::<Uri>()
+            .map_err(|err| ClientError::Transport(err.to_string()))?;
+        Ok(HttpTransport {
+            client: Client::new(handle),
+            uri,
+        })
+    }
+}
+
+impl Transport for HttpTransport {
+    fn send(&self, request: String) -> RpcFuture<String> {
+        let mut req = Request::new(Method::Post, self.uri.clone());
+        req.headers_mut().set(ContentType::json());
+        req.set_body(request);
+        Box::new(
+            self.client
+                .request(req)
+                .and_then(|res| res.body().concat2())
+                .map_err(|err| ClientError::Transport(err.to_string()))
+                .and_then(|body| {
+                    String::from_utf8(body.to_vec())
+                        .map_err(|err| ClientError::Transport(err.to_string()))
+                }),
+        )
+    }
+}
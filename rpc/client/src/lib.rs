@@ -0,0 +1,312 @@
+//! A typed client of the node RPC, every method mirrors the RPC of the same name and decodes
+//! the response into the types the node serializes it from, see `ckb_rpc::types`.
+//!
+//! The methods return futures, they are driven by the event loop of the transport:
+//!
+//! ```ignore
+//! let mut core = Core::new()?;
+//! let client = RpcClient::http("http://127.0.0.1:8114", &core.handle())?;
+//! let tip = core.run(client.get_tip_header())?;
+//! ```
+
+extern crate bigint;
+extern crate ckb_core;
+extern crate ckb_rpc;
+extern crate futures;
+extern crate hyper;
+extern crate jsonrpc_core;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate tokio_core;
+
+mod http;
+
+pub use ckb_rpc::types;
+pub use http::HttpTransport;
+
+use bigint::H256;
+use ckb_core::extras::{BlockEconomicState, BlockRejection, EpochStats, SyncStats};
+use ckb_core::header::{BlockNumber, Header};
+use ckb_core::transaction::{OutPoint, Transaction};
+use ckb_core::uncle::UncleBlock;
+use futures::Future;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio_core::reactor::Handle;
+use types::{
+    BlockHashWithHeader, BlockId, BlockTemplate, BlockWithHash, CellOutputWithOutPoint,
+    CellWithStatus, ConsensusParams, NodeInfo, PoolTransactionWithStatus, TransactionWithHash,
+    Verbose,
+};
+
+pub type RpcFuture<T> = Box<dyn Future<Item = T, Error = ClientError>>;
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The request didn't reach the node or its response didn't come back
+    Transport(String),
+    /// The response isn't a JSON-RPC response or its result isn't the type of the method
+    Json(serde_json::Error),
+    /// The node rejected the request, e.g. `send_transaction` with an invalid transaction
+    Rpc(jsonrpc_core::Error),
+}
+
+/// Carries a serialized JSON-RPC request to the node and resolves to the serialized response
+pub trait Transport {
+    fn send(&self, request: String) -> RpcFuture<String>;
+}
+
+#[derive(Deserialize)]
+struct Response {
+    #[serde(default)]
+    result: Value,
+    error: Option<jsonrpc_core::Error>,
+}
+
+pub struct RpcClient<T> {
+    transport: T,
+    next_id: AtomicUsize,
+}
+
+impl RpcClient<HttpTransport> {
+    /// A client of the node listening at `url`, e.g. `http://127.0.0.1:8114`
+    pub fn http(url: &str, handle: &Handle) -> Result<Self, ClientError> {
+        HttpTransport::new(url, handle).map(RpcClient::new)
+    }
+}
+
+impl<T: Transport> RpcClient<T> {
+    pub fn new(transport: T) -> Self {
+        RpcClient {
+            transport,
+            next_id: AtomicUsize::new(0),
+        }
+    }
+
+    fn call<R: DeserializeOwned + 'static>(
+        &self,
+        method: &str,
+        params: Vec<Value>,
+    ) -> RpcFuture<R> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let request = json!({
+            "id": id,
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params,
+        });
+        Box::new(
+            self.transport
+                .send(request.to_string())
+                .and_then(|response| {
+                    let response: Response =
+                        serde_json::from_str(&response).map_err(ClientError::Json)?;
+                    match response.error {
+                        Some(err) => Err(ClientError::Rpc(err)),
+                        None => serde_json::from_value(response.result).map_err(ClientError::Json),
+                    }
+                }),
+        )
+    }
+
+    pub fn send_transaction(&self, tx: &Transaction) -> RpcFuture<H256> {
+        self.call("send_transaction", vec![json!(tx)])
+    }
+
+    /// The node answers with its default verbosity, JSON, when `verbosity` is None
+    pub fn get_block(
+        &self,
+        hash: &H256,
+        verbosity: Option<u64>,
+    ) -> RpcFuture<Option<Verbose<BlockWithHash>>> {
+        self.call("get_block", trailing(vec![json!(hash)], verbosity))
+    }
+
+    pub fn get_header(
+        &self,
+        id: BlockId,
+        verbosity: Option<u64>,
+    ) -> RpcFuture<Option<Verbose<Header>>> {
+        self.call("get_header", trailing(vec![json!(id)], verbosity))
+    }
+
+    pub fn get_transaction(&self, hash: &H256) -> RpcFuture<Option<TransactionWithHash>> {
+        self.call("get_transaction", vec![json!(hash)])
+    }
+
+    pub fn get_pool_transaction(
+        &self,
+        hash: &H256,
+    ) -> RpcFuture<Option<PoolTransactionWithStatus>> {
+        self.call("get_pool_transaction", vec![json!(hash)])
+    }
+
+    pub fn get_block_hash(&self, number: BlockNumber) -> RpcFuture<Option<H256>> {
+        self.call("get_block_hash", vec![json!(number)])
+    }
+
+    pub fn get_tip_header(&self) -> RpcFuture<Header> {
+        self.call("get_tip_header", Vec::new())
+    }
+
+    pub fn get_block_hashes(
+        &self,
+        from: BlockNumber,
+        to: BlockNumber,
+        with_headers: Option<bool>,
+    ) -> RpcFuture<Vec<BlockHashWithHeader>> {
+        self.call(
+            "get_block_hashes",
+            trailing(vec![json!(from), json!(to)], with_headers),
+        )
+    }
+
+    pub fn get_block_template(&self) -> RpcFuture<BlockTemplate> {
+        self.call("get_block_template", Vec::new())
+    }
+
+    pub fn get_cells_by_type_hash(
+        &self,
+        type_hash: &H256,
+        from: BlockNumber,
+        to: BlockNumber,
+    ) -> RpcFuture<Vec<CellOutputWithOutPoint>> {
+        self.call(
+            "get_cells_by_type_hash",
+            vec![json!(type_hash), json!(from), json!(to)],
+        )
+    }
+
+    pub fn get_current_cell(&self, out_point: &OutPoint) -> RpcFuture<CellWithStatus> {
+        self.call("get_current_cell", vec![json!(out_point)])
+    }
+
+    pub fn get_cell_consumer(&self, out_point: &OutPoint) -> RpcFuture<Option<H256>> {
+        self.call("get_cell_consumer", vec![json!(out_point)])
+    }
+
+    pub fn get_cells_by_lock_hash(
+        &self,
+        lock_hash: &H256,
+    ) -> RpcFuture<Vec<CellOutputWithOutPoint>> {
+        self.call("get_cells_by_lock_hash", vec![json!(lock_hash)])
+    }
+
+    pub fn get_uncle(&self, hash: &H256) -> RpcFuture<Option<UncleBlock>> {
+        self.call("get_uncle", vec![json!(hash)])
+    }
+
+    pub fn get_sync_stats(&self) -> RpcFuture<SyncStats> {
+        self.call("get_sync_stats", Vec::new())
+    }
+
+    pub fn get_epoch_stats(&self, epoch: u64) -> RpcFuture<Option<EpochStats>> {
+        self.call("get_epoch_stats", vec![json!(epoch)])
+    }
+
+    pub fn get_block_economic_state(&self, hash: &H256) -> RpcFuture<Option<BlockEconomicState>> {
+        self.call("get_block_economic_state", vec![json!(hash)])
+    }
+
+    pub fn get_recent_rejections(&self) -> RpcFuture<Vec<BlockRejection>> {
+        self.call("get_recent_rejections", Vec::new())
+    }
+
+    pub fn local_node_info(&self) -> RpcFuture<NodeInfo> {
+        self.call("local_node_info", Vec::new())
+    }
+
+    pub fn get_consensus(&self) -> RpcFuture<ConsensusParams> {
+        self.call("get_consensus", Vec::new())
+    }
+}
+
+// The optional last param is left out rather than sent as null, the server takes it as missing
+fn trailing<S: serde::Serialize>(mut params: Vec<Value>, last: Option<S>) -> Vec<Value> {
+    if let Some(last) = last {
+        params.push(json!(last));
+    }
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_core::transaction::TransactionBuilder;
+    use futures::future;
+    use jsonrpc_core::{Error, ErrorCode, IoHandler, Params};
+
+    // Answers the requests in-process, with the methods registered on the handler
+    struct IoTransport(IoHandler);
+
+    impl Transport for IoTransport {
+        fn send(&self, request: String) -> RpcFuture<String> {
+            Box::new(future::ok(
+                self.0.handle_request_sync(&request).unwrap_or_default(),
+            ))
+        }
+    }
+
+    #[test]
+    fn test_typed_result() {
+        let mut io = IoHandler::new();
+        io.add_method("get_block_hash", |params: Params| -> Result<Value, Error> {
+            let (number,): (u64,) = params.parse()?;
+            Ok(if number == 0 {
+                json!(H256::from(1))
+            } else {
+                Value::Null
+            })
+        });
+        let client = RpcClient::new(IoTransport(io));
+
+        assert_eq!(
+            client.get_block_hash(0).wait().unwrap(),
+            Some(H256::from(1))
+        );
+        assert_eq!(client.get_block_hash(1).wait().unwrap(), None);
+    }
+
+    #[test]
+    fn test_trailing_param() {
+        let mut io = IoHandler::new();
+        io.add_method(
+            "get_block_hashes",
+            |params: Params| -> Result<Value, Error> {
+                let params: Vec<Value> = params.parse()?;
+                // the header is only included when the last param is sent
+                let header = if params.len() == 3 {
+                    Some(Header::default())
+                } else {
+                    None
+                };
+                Ok(json!([{ "number": 0, "hash": H256::from(1), "header": header }]))
+            },
+        );
+        let client = RpcClient::new(IoTransport(io));
+
+        let hashes = client.get_block_hashes(0, 10, None).wait().unwrap();
+        assert!(hashes[0].header.is_none());
+        let hashes = client.get_block_hashes(0, 10, Some(true)).wait().unwrap();
+        assert_eq!(hashes[0].header, Some(Header::default()));
+    }
+
+    #[test]
+    fn test_rpc_error() {
+        let mut io = IoHandler::new();
+        io.add_method("send_transaction", |_: Params| -> Result<Value, Error> {
+            Err(Error::invalid_params("invalid transaction"))
+        });
+        let client = RpcClient::new(IoTransport(io));
+
+        let tx = TransactionBuilder::default().build();
+        match client.send_transaction(&tx).wait() {
+            Err(ClientError::Rpc(err)) => assert_eq!(err.code, ErrorCode::InvalidParams),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
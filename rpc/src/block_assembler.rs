@@ -0,0 +1,471 @@
+use bigint::{H256, U256};
+use ckb_core::block::BlockBuilder;
+use ckb_core::header::{Header, HeaderBuilder, RawHeader};
+use ckb_core::transaction::{
+    CellInput, CellOutput, ProposalShortId, Transaction, TransactionBuilder,
+};
+use ckb_core::uncle::UncleBlock;
+use ckb_core::Cycle;
+use ckb_pool::txs_pool::TransactionPoolController;
+use ckb_shared::error::SharedError;
+use ckb_shared::index::ChainIndex;
+use ckb_shared::shared::{ChainProvider, Shared};
+use ckb_time::now_ms;
+use ckb_verification::verify_uncle;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use std::cmp;
+use {MAX_TEMPLATE_BYTES, MAX_TEMPLATE_CYCLES};
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct BlockTemplate {
+    pub raw_header: RawHeader,
+    pub uncles: Vec<UncleBlock>,
+    pub commit_transactions: Vec<Transaction>,
+    pub proposal_transactions: Vec<ProposalShortId>,
+}
+
+/// (type_hash, max_bytes, max_cycles, max_prop)
+pub type BlockTemplateArgs = (H256, usize, u64, usize);
+
+/// The limits of the assembled blocks, larger requests are capped to them
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct BlockAssemblerConfig {
+    pub max_bytes: usize,
+    pub max_cycles: u64,
+}
+
+impl Default for BlockAssemblerConfig {
+    fn default() -> Self {
+        BlockAssemblerConfig {
+            max_bytes: MAX_TEMPLATE_BYTES,
+            max_cycles: MAX_TEMPLATE_CYCLES,
+        }
+    }
+}
+
+// The template of the last request, valid while the tip, the pool and the uncles don't change
+struct CachedTemplate {
+    args: BlockTemplateArgs,
+    tip_hash: H256,
+    template: BlockTemplate,
+}
+
+/// Assembles the block templates handed out to the miners.
+///
+/// A template extends the current tip with a cellbase, the commit-ready transactions and the
+/// proposals packaged by the transaction pool, and the candidate uncles.
+pub struct BlockAssembler<CI> {
+    shared: Shared<CI>,
+    tx_pool: TransactionPoolController,
+    config: BlockAssemblerConfig,
+    last_args: Option<BlockTemplateArgs>,
+    cache: Option<CachedTemplate>,
+}
+
+impl<CI: ChainIndex> BlockAssembler<CI> {
+    pub fn new(
+        shared: Shared<CI>,
+        tx_pool: TransactionPoolController,
+        config: BlockAssemblerConfig,
+    ) -> Self {
+        BlockAssembler {
+            shared,
+            tx_pool,
+            config,
+            last_args: None,
+            cache: None,
+        }
+    }
+
+    /// The transaction pool or the uncle candidates changed, the next request assembles a new
+    /// template
+    pub fn invalidate(&mut self) {
+        self.cache = None;
+    }
+
+    /// Rebuilds the template of the last request on the new tip, so the miner asking for work
+    /// after the tip changed doesn't wait for the assembly.
+    pub fn refresh(&mut self) {
+        self.cache = None;
+        let args = match self.last_args {
+            Some(args) => args,
+            None => return,
+        };
+        if let Err(err) = self.get_block_template(args) {
+            error!(target: "rpc", "refresh block template failed: {:?}", err);
+        }
+    }
+
+    pub fn get_block_template(
+        &mut self,
+        args: BlockTemplateArgs,
+    ) -> Result<BlockTemplate, SharedError> {
+        self.last_args = Some(args);
+        let tip_hash = self.shared.tip_header().read().hash();
+        if let Some(ref cached) = self.cache {
+            if cached.args == args && cached.tip_hash == tip_hash {
+                return Ok(cached.template.clone());
+            }
+        }
+
+        let (template, pool_synced) = self.build_block_template(args)?;
+        // the pool lagging behind the tip only offered no commit transactions, don't keep that
+        if pool_synced {
+            self.cache = Some(CachedTemplate {
+                args,
+                tip_hash: template.raw_header.parent_hash(),
+                template: template.clone(),
+            });
+        }
+        Ok(template)
+    }
+
+    fn build_block_template(
+        &mut self,
+        (type_hash, max_bytes, max_cycles, max_prop): BlockTemplateArgs,
+    ) -> Result<(BlockTemplate, bool), SharedError> {
+        let max_bytes = cmp::min(
+            cmp::min(max_bytes, self.config.max_bytes),
+            self.shared.consensus().max_block_bytes() as usize,
+        );
+        let max_cycles = cmp::min(max_cycles, self.config.max_cycles);
+
+        let (
+            cellbase,
+            commit_transactions,
+            proposal_transactions,
+            uncles,
+            header_builder,
+            pool_synced,
+        ) = {
+            let tip_header = self.shared.tip_header().read();
+            let header = tip_header.inner();
+            let now = cmp::max(now_ms(), header.timestamp() + 1);
+            let difficulty = self
+                .shared
+                .calculate_difficulty(header)
+                .expect("get difficulty");
+
+            let max_prop = cmp::min(
+                max_prop,
+                self.shared.consensus().max_block_proposals_limit() as usize,
+            );
+            let package = self.tx_pool.package(max_bytes, max_cycles, max_prop);
+            let proposal_transactions = package.proposal_transactions;
+            let pool_synced = package.tip_hash == header.hash();
+            // The pool hasn't caught up with the tip yet, its commit candidates may
+            // conflict with or be already included in the tip block.
+            let commit_transactions = if !pool_synced {
+                debug!(target: "rpc", "txs pool is behind tip {}, commit no transactions", header.hash());
+                Vec::new()
+            } else if self
+                .shared
+                .consensus()
+                .proposal_window()
+                .proposals_range(header.number() + 1)
+                .is_none()
+            {
+                // too close to genesis, no ancestor can have proposed anything yet
+                Vec::new()
+            } else {
+                package.commit_transactions
+            };
+
+            let uncles = self.get_tip_uncles(header, &difficulty);
+            let commit_transactions = self.fit_block(
+                header,
+                &uncles,
+                &proposal_transactions,
+                commit_transactions,
+                type_hash,
+                (max_bytes, max_cycles),
+            )?;
+            let cellbase = self.create_cellbase_transaction(
+                header,
+                &commit_transactions,
+                uncles.len(),
+                type_hash,
+            )?;
+
+            let header_builder = HeaderBuilder::default()
+                .parent_hash(&header.hash())
+                .timestamp(now)
+                .number(header.number() + 1)
+                .difficulty(&difficulty)
+                .cellbase_id(&cellbase.hash());
+            (
+                cellbase,
+                commit_transactions,
+                proposal_transactions,
+                uncles,
+                header_builder,
+                pool_synced,
+            )
+        };
+
+        let block = BlockBuilder::default()
+            .commit_transaction(cellbase)
+            .commit_transactions(commit_transactions)
+            .proposal_transactions(proposal_transactions)
+            .uncles(uncles)
+            .with_header_builder(header_builder);
+
+        let template = BlockTemplate {
+            raw_header: block.header().clone().into_raw(),
+            uncles: block.uncles().to_vec(),
+            commit_transactions: block.commit_transactions().to_vec(),
+            proposal_transactions: block.proposal_transactions().to_vec(),
+        };
+        Ok((template, pool_synced))
+    }
+
+    // The pool packs the commit transactions against the limits alone, the header with the
+    // proof of work the miner adds, the cellbase, the uncles and the proposals of the block
+    // take their share of the bytes too. The transactions are committed in order while the
+    // block stays within both limits.
+    fn fit_block(
+        &self,
+        header: &Header,
+        uncles: &[UncleBlock],
+        proposal_transactions: &[ProposalShortId],
+        commit_transactions: Vec<(Transaction, Cycle)>,
+        type_hash: H256,
+        (max_bytes, max_cycles): (usize, Cycle),
+    ) -> Result<Vec<Transaction>, SharedError> {
+        let consensus = self.shared.consensus();
+        // the capacity is fixed size, the cellbase without the fees weighs as much
+        let cellbase = self.create_cellbase_transaction(header, &[], uncles.len(), type_hash)?;
+        let mut builder = BlockBuilder::default()
+            .commit_transaction(cellbase)
+            .uncles(uncles.to_vec())
+            .proposal_transactions(proposal_transactions.to_vec());
+        let max_bytes = max_bytes.saturating_sub(consensus.pow_engine().proof_size()) as u64;
+        let max_cycles = cmp::min(max_cycles, consensus.max_block_cycles());
+        let mut fitting = Vec::with_capacity(commit_transactions.len());
+        // topologically ordered, a prefix never misses the parent of a transaction
+        for (tx, cycles) in commit_transactions {
+            if builder.current_size() + tx.serialized_size() > max_bytes
+                || builder.current_cycles() + cycles > max_cycles
+            {
+                break;
+            }
+            builder = builder.commit_transaction_with_cycles(tx.clone(), cycles);
+            fitting.push(tx);
+        }
+        Ok(fitting)
+    }
+
+    fn create_cellbase_transaction(
+        &self,
+        header: &Header,
+        transactions: &[Transaction],
+        uncles_count: usize,
+        type_hash: H256,
+    ) -> Result<Transaction, SharedError> {
+        // NOTE: To generate different cellbase txid, we put header number in the input script
+        let input = CellInput::new_cellbase_input(header.number() + 1);
+        // NOTE: We could've just used byteorder to serialize u64 and hex string into bytes,
+        // but the truth is we will modify this after we designed lock script anyway, so let's
+        // stick to the simpler way and just convert everything to a single string, then to UTF8
+        // bytes, they really serve the same purpose at the moment
+        let block_reward = self.shared.block_reward(header.number() + 1)
+            + self.shared.uncles_reward(header.number() + 1, uncles_count);
+        let mut fee = 0;
+        for transaction in transactions {
+            fee += self.shared.calculate_transaction_fee(transaction)?;
+        }
+
+        let output = CellOutput::new(block_reward + fee, Vec::new(), type_hash, None);
+
+        Ok(TransactionBuilder::default()
+            .input(input)
+            .output(output)
+            .build())
+    }
+
+    // The candidates tracked by the chain service are checked in parallel by the same rules as
+    // the `UnclesVerifier`
+    fn get_tip_uncles(&self, tip: &Header, difficulty: &U256) -> Vec<UncleBlock> {
+        let shared = &self.shared;
+        let excluded = shared.uncle_exclusion_set(&tip.hash());
+        let number = tip.number() + 1;
+        let candidates = shared
+            .uncle_candidates()
+            .lock()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut uncles = candidates
+            .par_iter()
+            .filter_map(|block| {
+                let uncle = UncleBlock {
+                    header: block.header().clone(),
+                    cellbase: block.commit_transactions().first()?.clone(),
+                    proposal_transactions: block.proposal_transactions().to_vec(),
+                };
+                verify_uncle(shared, number, difficulty, &excluded, &uncle)
+                    .ok()
+                    .map(|_| uncle)
+            }).collect::<Vec<_>>();
+        uncles.truncate(shared.consensus().max_uncles_len());
+        uncles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_chain_spec::consensus::{Consensus, UNCLE_REWARD_DIVISOR};
+    use ckb_chain_spec::hardfork::HardForkSwitch;
+    use ckb_core::transaction::OutPoint;
+    use ckb_db::memorydb::MemoryKeyValueDB;
+    use ckb_notify::NotifyService;
+    use ckb_pool::txs_pool::{PoolConfig, TransactionPoolService};
+    use ckb_pow::{CuckooParams, Pow};
+    use ckb_shared::shared::SharedBuilder;
+    use ckb_shared::store::ChainKVStore;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_template_cache() {
+        let (_handle, notify) = NotifyService::default().start::<&str>(None);
+        let (tx_pool_controller, tx_pool_receivers) = TransactionPoolController::new();
+
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory().build();
+        let tx_pool_service =
+            TransactionPoolService::new(PoolConfig::default(), shared.clone(), notify);
+        let _handle = tx_pool_service.start::<&str>(None, tx_pool_receivers);
+
+        let mut assembler =
+            BlockAssembler::new(shared, tx_pool_controller, BlockAssemblerConfig::default());
+        let args = (H256::from(0), 1_000_000, 1_000_000, 1000);
+        let template = assembler.get_block_template(args).unwrap();
+        assert!(assembler.cache.is_some());
+        assert_eq!(assembler.get_block_template(args).unwrap(), template);
+
+        assembler.invalidate();
+        assert!(assembler.cache.is_none());
+        assembler.refresh();
+        // rebuilt for the arguments of the last request
+        assert_eq!(
+            assembler.cache.as_ref().map(|cached| cached.args),
+            Some(args)
+        );
+    }
+
+    #[test]
+    fn test_template_uncles() {
+        let (_handle, notify) = NotifyService::default().start::<&str>(None);
+        let (tx_pool_controller, tx_pool_receivers) = TransactionPoolController::new();
+        let consensus = Consensus::default().set_hardfork_switch(HardForkSwitch {
+            uncles_reward: Some(0),
+            ..Default::default()
+        });
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+            .consensus(consensus)
+            .build();
+        let tx_pool_service =
+            TransactionPoolService::new(PoolConfig::default(), shared.clone(), notify);
+        let _handle = tx_pool_service.start::<&str>(None, tx_pool_receivers);
+
+        let genesis = shared.consensus().genesis_block().header().clone();
+        let difficulty = shared.calculate_difficulty(&genesis).unwrap();
+        let candidate = |number, timestamp| {
+            let cellbase = TransactionBuilder::default()
+                .input(CellInput::new_cellbase_input(number))
+                .output(CellOutput::new(0, Vec::new(), H256::zero(), None))
+                .build();
+            let header_builder = HeaderBuilder::default()
+                .parent_hash(&genesis.parent_hash())
+                .number(number)
+                .timestamp(timestamp)
+                .difficulty(&difficulty)
+                .cellbase_id(&cellbase.hash());
+            Arc::new(
+                BlockBuilder::default()
+                    .commit_transaction(cellbase)
+                    .with_header_builder(header_builder),
+            )
+        };
+        // a sibling of the genesis is a valid uncle of the next block, a block at the same
+        // height as the next one is too young
+        let sibling = candidate(0, genesis.timestamp() + 1);
+        let young = candidate(1, genesis.timestamp() + 1);
+        {
+            let mut candidates = shared.uncle_candidates().lock();
+            candidates.insert(sibling.header().hash(), Arc::clone(&sibling));
+            candidates.insert(young.header().hash(), young);
+        }
+
+        let mut assembler = BlockAssembler::new(
+            shared.clone(),
+            tx_pool_controller,
+            BlockAssemblerConfig::default(),
+        );
+        let template = assembler
+            .get_block_template((H256::from(0), 1_000_000, 1_000_000, 1000))
+            .unwrap();
+        assert_eq!(template.uncles.len(), 1);
+        assert_eq!(template.uncles[0].header().hash(), sibling.header().hash());
+        // the cellbase claims the reward of the included uncle
+        assert_eq!(
+            template.commit_transactions[0].outputs_capacity(),
+            Some(shared.block_reward(1) + shared.block_reward(1) / UNCLE_REWARD_DIVISOR)
+        );
+    }
+
+    #[test]
+    fn test_fit_block() {
+        let consensus = Consensus::default().set_pow(Pow::Cuckoo(CuckooParams::default()));
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+            .consensus(consensus)
+            .build();
+        let (tx_pool_controller, _tx_pool_receivers) = TransactionPoolController::new();
+        let assembler = BlockAssembler::new(
+            shared.clone(),
+            tx_pool_controller,
+            BlockAssemblerConfig::default(),
+        );
+
+        let header = shared.consensus().genesis_block().header().clone();
+        let txs = (0..3)
+            .map(|i| {
+                TransactionBuilder::default()
+                    .input(CellInput::new(
+                        OutPoint::new(H256::from(1), i),
+                        Default::default(),
+                    )).build()
+            }).collect::<Vec<_>>();
+        let cellbase = assembler
+            .create_cellbase_transaction(&header, &[], 0, H256::zero())
+            .unwrap();
+        let block = BlockBuilder::default()
+            .commit_transaction(cellbase)
+            .commit_transactions(txs[..2].to_vec())
+            .build();
+
+        // the proof of work the miner adds takes its share of the block
+        let proof_size = shared.consensus().pow_engine().proof_size();
+        assert!(proof_size > 0);
+        let max_bytes = block.serialized_size() as usize + proof_size;
+        let candidates = txs.iter().map(|tx| (tx.clone(), 100)).collect::<Vec<_>>();
+        let fit = |max_bytes, max_cycles| {
+            assembler
+                .fit_block(
+                    &header,
+                    &[],
+                    &[],
+                    candidates.clone(),
+                    H256::zero(),
+                    (max_bytes, max_cycles),
+                ).unwrap()
+        };
+        assert_eq!(fit(max_bytes, 300), txs[..2].to_vec());
+        assert_eq!(fit(max_bytes - 1, 300), txs[..1].to_vec());
+        // the cycles stop the packing as well
+        assert_eq!(fit(usize::max_value(), 300), txs);
+        assert_eq!(fit(usize::max_value(), 299), txs[..2].to_vec());
+    }
+}
@@ -0,0 +1,214 @@
+//! Conversions of the core types into the messages of proto/ckb.proto
+
+use super::ckb as proto;
+use bigint::H256;
+use ckb_core::block::Block;
+use ckb_core::header::Header;
+use ckb_core::script::Script;
+use ckb_core::transaction::{CellInput, CellOutput, OutPoint, ProposalShortId, Transaction};
+use ckb_core::uncle::UncleBlock;
+use ckb_notify::ForkBlocks;
+use protobuf::RepeatedField;
+use types::{CellWithStatus, PoolTransactionWithStatus};
+
+fn repeated<'a, T: 'a, P: From<&'a T>>(items: &'a [T]) -> RepeatedField<P> {
+    RepeatedField::from_vec(items.iter().map(Into::into).collect())
+}
+
+fn short_ids(ids: &[ProposalShortId]) -> RepeatedField<Vec<u8>> {
+    RepeatedField::from_vec(ids.iter().map(|id| id.to_vec()).collect())
+}
+
+/// The hash of a request, which must be 32 bytes
+pub fn hash_from_bytes(bytes: &[u8]) -> Option<H256> {
+    if bytes.len() == 32 {
+        Some(H256::from_slice(bytes))
+    } else {
+        None
+    }
+}
+
+impl<'a> From<&'a OutPoint> for proto::OutPoint {
+    fn from(out_point: &'a OutPoint) -> Self {
+        let mut message = proto::OutPoint::new();
+        message.set_hash(out_point.hash.to_vec());
+        message.set_index(out_point.index);
+        message
+    }
+}
+
+impl<'a> From<&'a Script> for proto::Script {
+    fn from(script: &'a Script) -> Self {
+        let mut message = proto::Script::new();
+        message.set_version(u32::from(script.version));
+        message.set_args(RepeatedField::from_vec(script.args.clone()));
+        if let Some(ref reference) = script.reference {
+            message.set_reference(reference.to_vec());
+        }
+        if let Some(ref binary) = script.binary {
+            message.set_binary(binary.clone());
+        }
+        message.set_signed_args(RepeatedField::from_vec(script.signed_args.clone()));
+        message
+    }
+}
+
+impl<'a> From<&'a CellInput> for proto::CellInput {
+    fn from(input: &'a CellInput) -> Self {
+        let mut message = proto::CellInput::new();
+        message.set_previous_output((&input.previous_output).into());
+        message.set_unlock((&input.unlock).into());
+        message
+    }
+}
+
+impl<'a> From<&'a CellOutput> for proto::CellOutput {
+    fn from(output: &'a CellOutput) -> Self {
+        let mut message = proto::CellOutput::new();
+        message.set_capacity(output.capacity);
+        message.set_data(output.data.clone());
+        message.set_lock(output.lock.to_vec());
+        if let Some(ref contract) = output.contract {
+            message.set_contract(contract.into());
+        }
+        message
+    }
+}
+
+impl<'a> From<&'a Transaction> for proto::Transaction {
+    fn from(tx: &'a Transaction) -> Self {
+        let mut message = proto::Transaction::new();
+        message.set_hash(tx.hash().to_vec());
+        message.set_version(tx.version());
+        message.set_deps(repeated(tx.deps()));
+        message.set_inputs(repeated(tx.inputs()));
+        message.set_outputs(repeated(tx.outputs()));
+        message
+    }
+}
+
+impl<'a> From<&'a Header> for proto::Header {
+    fn from(header: &'a Header) -> Self {
+        let mut seal = proto::Seal::new();
+        seal.set_nonce(header.nonce());
+        seal.set_proof(header.proof().to_vec());
+
+        let mut message = proto::Header::new();
+        message.set_hash(header.hash().to_vec());
+        message.set_version(header.version());
+        message.set_parent_hash(header.parent_hash().to_vec());
+        message.set_timestamp(header.timestamp());
+        message.set_number(header.number());
+        message.set_txs_commit(header.txs_commit().to_vec());
+        message.set_txs_proposal(header.txs_proposal().to_vec());
+        message.set_difficulty(<[u8; 32]>::from(header.difficulty()).to_vec());
+        message.set_cellbase_id(header.cellbase_id().to_vec());
+        message.set_uncles_hash(header.uncles_hash().to_vec());
+        message.set_uncles_count(header.uncles_count());
+        message.set_seal(seal);
+        message
+    }
+}
+
+impl<'a> From<&'a UncleBlock> for proto::UncleBlock {
+    fn from(uncle: &'a UncleBlock) -> Self {
+        let mut message = proto::UncleBlock::new();
+        message.set_header((&uncle.header).into());
+        message.set_cellbase((&uncle.cellbase).into());
+        message.set_proposal_transactions(short_ids(&uncle.proposal_transactions));
+        message
+    }
+}
+
+impl<'a> From<&'a Block> for proto::Block {
+    fn from(block: &'a Block) -> Self {
+        let mut message = proto::Block::new();
+        message.set_header(block.header().into());
+        message.set_uncles(repeated(block.uncles()));
+        message.set_commit_transactions(repeated(block.commit_transactions()));
+        message.set_proposal_transactions(short_ids(block.proposal_transactions()));
+        message
+    }
+}
+
+impl<'a> From<&'a ForkBlocks> for proto::ForkBlocks {
+    fn from(fork: &'a ForkBlocks) -> Self {
+        let mut message = proto::ForkBlocks::new();
+        message.set_detached_blocks(repeated(fork.old_blks()));
+        message.set_attached_blocks(repeated(fork.new_blks()));
+        message
+    }
+}
+
+impl From<CellWithStatus> for proto::CellWithStatus {
+    fn from(cell: CellWithStatus) -> Self {
+        let mut message = proto::CellWithStatus::new();
+        if let Some(ref output) = cell.cell {
+            message.set_cell(output.into());
+        }
+        message.set_status(cell.status);
+        message
+    }
+}
+
+impl From<PoolTransactionWithStatus> for proto::PoolTransaction {
+    fn from(entry: PoolTransactionWithStatus) -> Self {
+        let mut message = proto::PoolTransaction::new();
+        message.set_transaction((&entry.transaction.transaction).into());
+        message.set_status(entry.status);
+        message.set_expired_proposals(entry.expired_proposals);
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigint::U256;
+    use ckb_core::header::HeaderBuilder;
+    use ckb_core::transaction::TransactionBuilder;
+
+    #[test]
+    fn test_header_bytes() {
+        let header = HeaderBuilder::default()
+            .number(10)
+            .difficulty(&U256::from(0x1234))
+            .nonce(7)
+            .build();
+        let message = proto::Header::from(&header);
+
+        assert_eq!(message.get_hash(), &header.hash()[..]);
+        assert_eq!(message.get_number(), 10);
+        // big endian, as U256 serializes in the JSON-RPC
+        let difficulty = message.get_difficulty();
+        assert_eq!(difficulty.len(), 32);
+        assert_eq!(&difficulty[30..], &[0x12, 0x34]);
+        assert!(difficulty[..30].iter().all(|byte| *byte == 0));
+        assert_eq!(message.get_seal().get_nonce(), 7);
+    }
+
+    #[test]
+    fn test_script_optional_fields() {
+        let script = Script::new(0, vec![vec![1]], None, Some(Vec::new()), Vec::new());
+        let message = proto::Script::from(&script);
+        // an empty binary is still set, an absent reference isn't
+        assert!(message.has_binary());
+        assert!(!message.has_reference());
+
+        let output = CellOutput::new(100, Vec::new(), H256::from(1), None);
+        let tx = TransactionBuilder::default()
+            .input(CellInput::new(OutPoint::new(H256::from(2), 1), script))
+            .output(output)
+            .build();
+        let message = proto::Transaction::from(&tx);
+        assert_eq!(message.get_hash(), &tx.hash()[..]);
+        assert_eq!(message.get_inputs()[0].get_previous_output().get_index(), 1);
+        assert!(!message.get_outputs()[0].has_contract());
+    }
+
+    #[test]
+    fn test_hash_from_bytes() {
+        assert_eq!(hash_from_bytes(&[1; 32]), Some(H256::from([1; 32])));
+        assert_eq!(hash_from_bytes(&[1; 31]), None);
+    }
+}
@@ -0,0 +1,33 @@
+//! The gRPC interface of proto/ckb.proto, an alternative to the JSON-RPC for the clients
+//! preferring streamed subscriptions.
+
+mod convert;
+mod server;
+
+#[cfg_attr(feature = "cargo-clippy", allow(clippy))]
+#[allow(
+    unknown_lints,
+    dead_code,
+    missing_docs,
+    non_camel_case_types,
+    non_snake_case
+)]
+#[allow(non_upper_case_globals, trivial_casts, unused_imports, unused_results)]
+pub mod ckb {
+    include!(concat!(env!("OUT_DIR"), "/ckb.rs"));
+}
+
+#[cfg_attr(feature = "cargo-clippy", allow(clippy))]
+#[allow(
+    unknown_lints,
+    dead_code,
+    missing_docs,
+    non_camel_case_types,
+    non_snake_case
+)]
+#[allow(non_upper_case_globals, trivial_casts, unused_imports, unused_results)]
+pub mod ckb_grpc {
+    include!(concat!(env!("OUT_DIR"), "/ckb_grpc.rs"));
+}
+
+pub use self::server::GrpcServer;
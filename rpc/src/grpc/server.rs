@@ -0,0 +1,352 @@
+use super::ckb as proto;
+use super::ckb_grpc::{create_node, Node};
+use super::convert::hash_from_bytes;
+use channel::Receiver;
+use ckb_core::cell::CellProvider;
+use ckb_core::transaction::{OutPoint, ProposalShortId};
+use ckb_notify::{MsgNewTip, MsgSwitchFork, NotifyController, GRPC_SUBSCRIBER};
+use ckb_pool::txs_pool::TransactionPoolController;
+use ckb_shared::index::ChainIndex;
+use ckb_shared::shared::{ChainProvider, Shared};
+use ckb_util::Mutex;
+use futures::sync::mpsc::{self, Receiver as StreamReceiver, Sender};
+use futures::{Future, Sink, Stream};
+use grpcio::{
+    self, Environment, RpcContext, RpcStatus, RpcStatusCode, Server, ServerBuilder,
+    ServerStreamingSink, UnarySink, WriteFlags,
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use types::{CellWithStatus, PoolTransactionWithStatus};
+
+/// The number of threads polling the gRPC completion queues
+const GRPC_THREADS: usize = 2;
+
+/// The notifications buffered for a subscriber, a client falling further behind is disconnected
+/// rather than buffering without bound
+const SUBSCRIBER_BUFFER: usize = 64;
+
+// The streams of a subscription, each notification is sent to all of them
+struct Subscribers<T> {
+    senders: Arc<Mutex<Vec<Sender<T>>>>,
+}
+
+impl<T> Clone for Subscribers<T> {
+    fn clone(&self) -> Self {
+        Subscribers {
+            senders: Arc::clone(&self.senders),
+        }
+    }
+}
+
+impl<T: Clone> Subscribers<T> {
+    fn new() -> Self {
+        Subscribers {
+            senders: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    fn subscribe(&self) -> StreamReceiver<T> {
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_BUFFER);
+        self.senders.lock().push(sender);
+        receiver
+    }
+
+    fn is_empty(&self) -> bool {
+        self.senders.lock().is_empty()
+    }
+
+    // The streams of disconnected clients dropped their receivers, they are removed here. So are
+    // the senders of the clients whose buffer is full, their streams end after the buffered items
+    fn publish(&self, item: &T) {
+        let mut senders = self.senders.lock();
+        let live = senders
+            .drain(..)
+            .filter_map(|mut sender| match sender.try_send(item.clone()) {
+                Ok(()) => Some(sender),
+                Err(err) => {
+                    if err.is_full() {
+                        debug!(target: "rpc", "grpc subscriber is too slow, disconnect it");
+                    }
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        *senders = live;
+    }
+
+    // Converts and publishes the notifications until the notify service stops
+    fn forward<M, F>(&self, name: &str, receiver: Receiver<M>, convert: F)
+    where
+        M: Send + 'static,
+        T: Send + 'static,
+        F: Fn(&M) -> T + Send + 'static,
+    {
+        let subscribers = self.clone();
+        thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                while let Some(msg) = receiver.recv() {
+                    if !subscribers.is_empty() {
+                        subscribers.publish(&convert(&msg));
+                    }
+                }
+            })
+            .expect("start grpc subscription thread");
+    }
+}
+
+#[derive(Clone)]
+struct NodeImpl<CI> {
+    shared: Shared<CI>,
+    tx_pool: TransactionPoolController,
+    new_tip: Subscribers<proto::Block>,
+    switch_fork: Subscribers<proto::ForkBlocks>,
+}
+
+fn reply<T>(ctx: &RpcContext, sink: UnarySink<T>, result: Result<T, RpcStatus>) {
+    let sending = match result {
+        Ok(message) => sink.success(message),
+        Err(status) => sink.fail(status),
+    };
+    ctx.spawn(sending.map_err(|err| debug!(target: "rpc", "grpc reply failed: {:?}", err)));
+}
+
+fn stream<T: Send + 'static>(
+    ctx: &RpcContext,
+    sink: ServerStreamingSink<T>,
+    receiver: StreamReceiver<T>,
+) {
+    // the receiver never fails, it ends with the server
+    let items = receiver
+        .map(|item| (item, WriteFlags::default()))
+        .map_err(|_| grpcio::Error::RemoteStopped);
+    ctx.spawn(
+        sink.send_all(items)
+            .map(|_| ())
+            .map_err(|err| debug!(target: "rpc", "grpc subscription closed: {:?}", err)),
+    );
+}
+
+fn request_hash(bytes: &[u8]) -> Result<::bigint::H256, RpcStatus> {
+    hash_from_bytes(bytes).ok_or_else(|| {
+        RpcStatus::new(
+            RpcStatusCode::InvalidArgument,
+            Some("hash must be 32 bytes".to_string()),
+        )
+    })
+}
+
+impl<CI: ChainIndex + 'static> Node for NodeImpl<CI> {
+    fn get_tip_header(&mut self, ctx: RpcContext, _: proto::Empty, sink: UnarySink<proto::Header>) {
+        let header = proto::Header::from(self.shared.tip_header().read().inner());
+        reply(&ctx, sink, Ok(header));
+    }
+
+    fn get_block_hash(
+        &mut self,
+        ctx: RpcContext,
+        req: proto::GetBlockHashRequest,
+        sink: UnarySink<proto::GetBlockHashReply>,
+    ) {
+        let mut message = proto::GetBlockHashReply::new();
+        if let Some(hash) = self.shared.block_hash(req.get_number()) {
+            message.set_hash(hash.to_vec());
+        }
+        reply(&ctx, sink, Ok(message));
+    }
+
+    fn get_block(
+        &mut self,
+        ctx: RpcContext,
+        req: proto::GetBlockRequest,
+        sink: UnarySink<proto::GetBlockReply>,
+    ) {
+        let result = request_hash(req.get_hash()).map(|hash| {
+            let mut message = proto::GetBlockReply::new();
+            if let Some(block) = self.shared.block(&hash) {
+                message.set_block((&block).into());
+            }
+            message
+        });
+        reply(&ctx, sink, result);
+    }
+
+    fn get_header(
+        &mut self,
+        ctx: RpcContext,
+        req: proto::GetHeaderRequest,
+        sink: UnarySink<proto::GetHeaderReply>,
+    ) {
+        let hash = if req.has_number() {
+            Ok(self.shared.block_hash(req.get_number()))
+        } else {
+            request_hash(req.get_hash()).map(Some)
+        };
+        let result = hash.map(|hash| {
+            let mut message = proto::GetHeaderReply::new();
+            if let Some(header) = hash.and_then(|hash| self.shared.block_header(&hash)) {
+                message.set_header((&header).into());
+            }
+            message
+        });
+        reply(&ctx, sink, result);
+    }
+
+    fn get_transaction(
+        &mut self,
+        ctx: RpcContext,
+        req: proto::GetTransactionRequest,
+        sink: UnarySink<proto::GetTransactionReply>,
+    ) {
+        let result = request_hash(req.get_hash()).map(|hash| {
+            let mut message = proto::GetTransactionReply::new();
+            if let Some(tx) = self.shared.get_transaction(&hash) {
+                message.set_transaction((&tx).into());
+            }
+            message
+        });
+        reply(&ctx, sink, result);
+    }
+
+    fn get_pool_transaction(
+        &mut self,
+        ctx: RpcContext,
+        req: proto::GetTransactionRequest,
+        sink: UnarySink<proto::GetPoolTransactionReply>,
+    ) {
+        let result = request_hash(req.get_hash()).map(|hash| {
+            let mut message = proto::GetPoolTransactionReply::new();
+            // the short id may collide, only answer for the requested transaction
+            let entry = self
+                .tx_pool
+                .get_pool_transaction(ProposalShortId::from_h256(&hash))
+                .filter(|entry| entry.transaction.hash() == hash);
+            if let Some(entry) = entry {
+                message.set_transaction(PoolTransactionWithStatus::from(entry).into());
+            }
+            message
+        });
+        reply(&ctx, sink, result);
+    }
+
+    fn get_current_cell(
+        &mut self,
+        ctx: RpcContext,
+        req: proto::OutPoint,
+        sink: UnarySink<proto::CellWithStatus>,
+    ) {
+        let result = request_hash(req.get_hash()).map(|hash| {
+            let out_point = OutPoint::new(hash, req.get_index());
+            CellWithStatus::from(self.shared.cell(&out_point)).into()
+        });
+        reply(&ctx, sink, result);
+    }
+
+    fn subscribe_new_tip(
+        &mut self,
+        ctx: RpcContext,
+        _: proto::Empty,
+        sink: ServerStreamingSink<proto::Block>,
+    ) {
+        stream(&ctx, sink, self.new_tip.subscribe());
+    }
+
+    fn subscribe_switch_fork(
+        &mut self,
+        ctx: RpcContext,
+        _: proto::Empty,
+        sink: ServerStreamingSink<proto::ForkBlocks>,
+    ) {
+        stream(&ctx, sink, self.switch_fork.subscribe());
+    }
+}
+
+pub struct GrpcServer {
+    pub listen_addr: String,
+}
+
+impl GrpcServer {
+    /// Serves until the returned server is dropped
+    pub fn start<CI: ChainIndex + 'static>(
+        &self,
+        shared: Shared<CI>,
+        tx_pool: TransactionPoolController,
+        notify: &NotifyController,
+    ) -> Server {
+        let addr: SocketAddr = self
+            .listen_addr
+            .parse()
+            .expect("grpc_listen_addr must be an ip:port socket address");
+
+        let new_tip = Subscribers::new();
+        new_tip.forward(
+            "grpc_new_tip",
+            notify.subscribe_new_tip(GRPC_SUBSCRIBER),
+            |block: &MsgNewTip| proto::Block::from(&**block),
+        );
+        let switch_fork = Subscribers::new();
+        switch_fork.forward(
+            "grpc_switch_fork",
+            notify.subscribe_switch_fork(GRPC_SUBSCRIBER),
+            |fork: &MsgSwitchFork| proto::ForkBlocks::from(&**fork),
+        );
+
+        let service = create_node(NodeImpl {
+            shared,
+            tx_pool,
+            new_tip,
+            switch_fork,
+        });
+        let mut server = ServerBuilder::new(Arc::new(Environment::new(GRPC_THREADS)))
+            .register_service(service)
+            .bind(addr.ip().to_string(), addr.port())
+            .build()
+            .expect("start grpc server");
+        server.start();
+
+        for &(ref host, port) in server.bind_addrs() {
+            info!(target: "rpc", "gRPC now listening on {}:{}", host, port);
+        }
+        server
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscribers() {
+        let subscribers = Subscribers::new();
+        let mut first = subscribers.subscribe().wait();
+        let second = subscribers.subscribe();
+        subscribers.publish(&1);
+        assert_eq!(first.next(), Some(Ok(1)));
+
+        // the closed stream is left out of the next notification
+        drop(second);
+        subscribers.publish(&2);
+        assert_eq!(subscribers.senders.lock().len(), 1);
+        assert_eq!(first.next(), Some(Ok(2)));
+    }
+
+    #[test]
+    fn test_slow_subscriber() {
+        let subscribers = Subscribers::new();
+        let mut fast = subscribers.subscribe().wait();
+        let slow = subscribers.subscribe();
+
+        // the channel holds the buffer and one more item for its single sender
+        for i in 0..SUBSCRIBER_BUFFER + 2 {
+            subscribers.publish(&i);
+            assert_eq!(fast.next(), Some(Ok(i)));
+        }
+        assert_eq!(subscribers.senders.lock().len(), 1);
+
+        // the stream of the slow subscriber ends after its buffered items
+        let received: Vec<usize> = slow.wait().map(|item| item.unwrap()).collect();
+        assert_eq!(received, (0..SUBSCRIBER_BUFFER + 1).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,97 @@
+use ckb_core::header::BlockNumber;
+use ckb_network::NetworkService;
+use ckb_shared::index::ChainIndex;
+use ckb_shared::shared::{ChainProvider, Shared};
+use ckb_time::now_ms;
+use ckb_util::Mutex;
+use jsonrpc_http_server::hyper::Request;
+use jsonrpc_http_server::{RequestMiddleware, RequestMiddlewareAction, Response};
+use std::sync::Arc;
+
+/// The node is considered stalled when its tip didn't advance and it had no peers for this long
+pub const READY_STALL_TIMEOUT: u64 = 10 * 60 * 1000;
+
+struct Progress {
+    tip_number: BlockNumber,
+    tip_advanced_at: u64,
+    peers_seen_at: u64,
+}
+
+/// Answers `GET /healthz` and `GET /readyz` on the RPC listen address,
+/// every other request is passed through to the JSON-RPC handler.
+///
+/// `/healthz` only tells the process is alive, `/readyz` additionally requires
+/// the database to be readable, the network to be listening and the node not stalled.
+pub struct HealthMiddleware<CI> {
+    network: Arc<NetworkService>,
+    shared: Shared<CI>,
+    progress: Mutex<Progress>,
+}
+
+impl<CI: ChainIndex + 'static> HealthMiddleware<CI> {
+    pub fn new(network: Arc<NetworkService>, shared: Shared<CI>) -> Self {
+        let now = now_ms();
+        let tip_number = shared.tip_header().read().number();
+        HealthMiddleware {
+            network,
+            shared,
+            progress: Mutex::new(Progress {
+                tip_number,
+                tip_advanced_at: now,
+                peers_seen_at: now,
+            }),
+        }
+    }
+
+    fn readiness(&self) -> Result<String, String> {
+        if self.shared.block_hash(0).is_none() {
+            return Err("database is not readable".to_string());
+        }
+
+        if self.network.external_url().is_none() {
+            return Err("network is not listening".to_string());
+        }
+
+        let now = now_ms();
+        let tip_number = self.shared.tip_header().read().number();
+        let peers = self.network.connected_peers_count();
+
+        let mut progress = self.progress.lock();
+        if tip_number > progress.tip_number {
+            progress.tip_number = tip_number;
+            progress.tip_advanced_at = now;
+        }
+        if peers > 0 {
+            progress.peers_seen_at = now;
+        }
+
+        let last_active = ::std::cmp::max(progress.tip_advanced_at, progress.peers_seen_at);
+        if now.saturating_sub(last_active) > READY_STALL_TIMEOUT {
+            return Err(format!(
+                "node stalled at tip {} without peers",
+                progress.tip_number
+            ));
+        }
+
+        Ok(json!({ "tip_number": tip_number, "peers": peers }).to_string())
+    }
+}
+
+impl<CI: ChainIndex + 'static> RequestMiddleware for HealthMiddleware<CI> {
+    fn on_request(&self, request: Request) -> RequestMiddlewareAction {
+        match request.path() {
+            "/healthz" => Response::ok(json!({ "status": "ok" }).to_string()).into(),
+            "/readyz" => match self.readiness() {
+                Ok(status) => Response::ok(status).into(),
+                Err(reason) => {
+                    warn!(target: "rpc", "readiness probe failed: {}", reason);
+                    Response::service_unavailable(reason).into()
+                }
+            },
+            _ => RequestMiddlewareAction::Proceed {
+                should_continue_on_invalid_cors: false,
+                request,
+            },
+        }
+    }
+}
@@ -1,21 +1,28 @@
 use super::{
-    BlockTemplate, BlockWithHash, CellOutputWithOutPoint, CellWithStatus, Config, RpcController,
-    TransactionWithHash,
+    block_by_hash, block_hashes, consensus_params, fill_cycles, header_by_id, node_info,
+    rejected_transaction_error, transaction_with_cycles, BlockHashWithHeader, BlockId,
+    BlockTemplate, BlockWithHash, CellOutputWithOutPoint, CellWithStatus, Config, ConsensusParams,
+    HealthMiddleware, NodeInfo, Permit, PoolTransactionWithStatus, RpcController,
+    TransactionWithHash, Verbose, VERBOSITY_JSON,
 };
 use bigint::H256;
 use ckb_core::cell::CellProvider;
+use ckb_core::extras::{BlockEconomicState, BlockRejection, EpochStats, SyncStats};
 use ckb_core::header::{BlockNumber, Header};
-use ckb_core::transaction::{OutPoint, Transaction};
+use ckb_core::transaction::{OutPoint, ProposalShortId, Transaction};
+use ckb_core::uncle::UncleBlock;
 use ckb_network::NetworkService;
-use ckb_pool::txs_pool::TransactionPoolController;
+use ckb_pool::txs_pool::{PoolError, TransactionPoolController};
 use ckb_pow::Clicker;
 use ckb_protocol::RelayMessage;
 use ckb_shared::index::ChainIndex;
 use ckb_shared::shared::{ChainProvider, Shared};
+use ckb_shared::store::ChainStore;
 use ckb_sync::RELAY_PROTOCOL_ID;
 use flatbuffers::FlatBufferBuilder;
 use jsonrpc_core::{Error, IoHandler, Result};
 use jsonrpc_http_server::ServerBuilder;
+use jsonrpc_macros::Trailing;
 use jsonrpc_server_utils::cors::AccessControlAllowOrigin;
 use jsonrpc_server_utils::hosts::DomainsValidation;
 use std::sync::Arc;
@@ -31,14 +38,25 @@ build_rpc_trait! {
         #[rpc(name = "send_transaction")]
         fn send_transaction(&self, Transaction) -> Result<H256>;
 
+        // The last param is the verbosity, 0 for the hex of the serialized block, 1 (default) for JSON
         // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_block","params": ["0x0f9da6db98d0acd1ae0cf7ae3ee0b2b5ad2855d93c18d27c0961f985a62a93c3"]}' -H 'content-type:application/json' 'http://localhost:8114'
         #[rpc(name = "get_block")]
-        fn get_block(&self, H256) -> Result<Option<BlockWithHash>>;
+        fn get_block(&self, H256, Trailing<u64>) -> Result<Option<Verbose<BlockWithHash>>>;
+
+        // Takes a hash or a main chain number, the last param is the verbosity as for get_block
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_header","params": [1, 0]}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_header")]
+        fn get_header(&self, BlockId, Trailing<u64>) -> Result<Option<Verbose<Header>>>;
 
         // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_transaction","params": ["0x0f9da6db98d0acd1ae0cf7ae3ee0b2b5ad2855d93c18d27c0961f985a62a93c3"]}' -H 'content-type:application/json' 'http://localhost:8114'
         #[rpc(name = "get_transaction")]
         fn get_transaction(&self, H256) -> Result<Option<TransactionWithHash>>;
 
+        // A transaction still in the pool and its status there
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_pool_transaction","params": ["0x0f9da6db98d0acd1ae0cf7ae3ee0b2b5ad2855d93c18d27c0961f985a62a93c3"]}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_pool_transaction")]
+        fn get_pool_transaction(&self, H256) -> Result<Option<PoolTransactionWithStatus>>;
+
         // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_block_hash","params": [1]}' -H 'content-type:application/json' 'http://localhost:8114'
         #[rpc(name = "get_block_hash")]
         fn get_block_hash(&self, u64) -> Result<Option<H256>>;
@@ -47,6 +65,11 @@ build_rpc_trait! {
         #[rpc(name = "get_tip_header")]
         fn get_tip_header(&self) -> Result<Header>;
 
+        // Returns at most MAX_BLOCK_HASHES_RANGE main chain hashes, headers are included when the last param is true
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_block_hashes","params": [1, 100, true]}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_block_hashes")]
+        fn get_block_hashes(&self, u64, u64, Trailing<bool>) -> Result<Vec<BlockHashWithHeader>>;
+
         // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_block_template","params": []}' -H 'content-type:application/json' 'http://localhost:8114'
         #[rpc(name = "get_block_template")]
         fn get_block_template(&self) -> Result<BlockTemplate>;
@@ -59,6 +82,50 @@ build_rpc_trait! {
         #[rpc(name = "get_current_cell")]
         fn get_current_cell(&self, OutPoint) -> Result<CellWithStatus>;
 
+        // Only answered when `chain.cell_consumer_index` is enabled
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_cell_consumer","params": [{"hash": "0x1b1c832d02fdb4339f9868c8a8636c3d9dd10bd53ac7ce99595825bd6beeffb3", "index": 1}]}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_cell_consumer")]
+        fn get_cell_consumer(&self, OutPoint) -> Result<Option<H256>>;
+
+        // Only answered when `chain.lock_hash_index` is enabled
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_cells_by_lock_hash","params": ["0x1b1c832d02fdb4339f9868c8a8636c3d9dd10bd53ac7ce99595825bd6beeffb3"]}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_cells_by_lock_hash")]
+        fn get_cells_by_lock_hash(&self, H256) -> Result<Vec<CellOutputWithOutPoint>>;
+
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_uncle","params": ["0x0f9da6db98d0acd1ae0cf7ae3ee0b2b5ad2855d93c18d27c0961f985a62a93c3"]}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_uncle")]
+        fn get_uncle(&self, H256) -> Result<Option<UncleBlock>>;
+
+        // Cumulative since the database was created, received bytes are flushed with the next processed block
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_sync_stats","params": []}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_sync_stats")]
+        fn get_sync_stats(&self) -> Result<SyncStats>;
+
+        // Null until the main chain completes the epoch
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_epoch_stats","params": [1]}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_epoch_stats")]
+        fn get_epoch_stats(&self, u64) -> Result<Option<EpochStats>>;
+
+        // Null unless the block is on the main chain, the genesis has no reward to claim
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_block_economic_state","params": ["0x0f9da6db98d0acd1ae0cf7ae3ee0b2b5ad2855d93c18d27c0961f985a62a93c3"]}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_block_economic_state")]
+        fn get_block_economic_state(&self, H256) -> Result<Option<BlockEconomicState>>;
+
+        // Oldest first, at most the last 128 rejected blocks since the node started
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_recent_rejections","params": []}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_recent_rejections")]
+        fn get_recent_rejections(&self) -> Result<Vec<BlockRejection>>;
+
+        // The node id, the addresses other nodes can dial and the number of connected peers
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"local_node_info","params": []}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "local_node_info")]
+        fn local_node_info(&self) -> Result<NodeInfo>;
+
+        // The consensus parameters of the chain, they differ between the dev chain, the testnet and the mainnet
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_consensus","params": []}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_consensus")]
+        fn get_consensus(&self) -> Result<ConsensusParams>;
+
         #[rpc(name = "local_node_id")]
         fn local_node_id(&self) -> Result<Option<String>>;
 
@@ -73,6 +140,7 @@ struct RpcImpl<CI> {
     pub rpc: RpcController,
     pub tx_pool: TransactionPoolController,
     pub pow: Arc<Clicker>,
+    pub spec_hash: H256,
 }
 
 impl<CI: ChainIndex + 'static> IntegrationTestRpc for RpcImpl<CI> {
@@ -85,6 +153,11 @@ impl<CI: ChainIndex + 'static> IntegrationTestRpc for RpcImpl<CI> {
         let tx_hash = tx.hash();
         let pool_result = self.tx_pool.add_transaction(tx.clone());
         debug!(target: "rpc", "send_transaction add to pool result: {:?}", pool_result);
+        match pool_result {
+            // resending a pool transaction relays it again
+            Ok(_) | Err(PoolError::AlreadyInPool) => {}
+            Err(err) => return Err(rejected_transaction_error(err)),
+        }
 
         let fbb = &mut FlatBufferBuilder::new();
         let message = RelayMessage::build_transaction(fbb, &tx);
@@ -99,26 +172,72 @@ impl<CI: ChainIndex + 'static> IntegrationTestRpc for RpcImpl<CI> {
         Ok(tx_hash)
     }
 
-    fn get_block(&self, hash: H256) -> Result<Option<BlockWithHash>> {
-        Ok(self.shared.block(&hash).map(Into::into))
+    fn get_block(
+        &self,
+        hash: H256,
+        verbosity: Trailing<u64>,
+    ) -> Result<Option<Verbose<BlockWithHash>>> {
+        block_by_hash(&self.shared, &hash, verbosity.unwrap_or(VERBOSITY_JSON))
+    }
+
+    fn get_header(&self, id: BlockId, verbosity: Trailing<u64>) -> Result<Option<Verbose<Header>>> {
+        header_by_id(&self.shared, id, verbosity.unwrap_or(VERBOSITY_JSON))
     }
 
     fn get_transaction(&self, hash: H256) -> Result<Option<TransactionWithHash>> {
-        Ok(self.shared.get_transaction(&hash).map(Into::into))
+        Ok(self
+            .shared
+            .get_transaction(&hash)
+            .map(|tx| transaction_with_cycles(&self.shared, tx)))
+    }
+
+    fn get_pool_transaction(&self, hash: H256) -> Result<Option<PoolTransactionWithStatus>> {
+        let id = ProposalShortId::from_h256(&hash);
+        // the short id may collide, only answer for the requested transaction
+        Ok(self
+            .tx_pool
+            .get_pool_transaction(id)
+            .filter(|entry| entry.transaction.hash() == hash)
+            .map(|entry| {
+                let mut entry = PoolTransactionWithStatus::from(entry);
+                fill_cycles(&self.shared, &mut entry.transaction);
+                entry
+            }))
     }
 
     fn get_block_hash(&self, number: BlockNumber) -> Result<Option<H256>> {
         Ok(self.shared.block_hash(number))
     }
 
+    fn get_block_hashes(
+        &self,
+        from: BlockNumber,
+        to: BlockNumber,
+        with_headers: Trailing<bool>,
+    ) -> Result<Vec<BlockHashWithHeader>> {
+        block_hashes(
+            &self.shared,
+            from,
+            to,
+            with_headers.unwrap_or(false),
+            &Permit::unlimited(),
+        )
+    }
+
     fn get_tip_header(&self) -> Result<Header> {
         Ok(self.shared.tip_header().read().inner().clone())
     }
 
     fn get_block_template(&self) -> Result<BlockTemplate> {
+        // the block assembler caps the budget to its config
         Ok(self
             .rpc
-            .get_block_template(H256::from(0), 20000, 20000)
+            .get_block_template(
+                H256::from(0),
+                usize::max_value(),
+                u64::max_value(),
+                20000,
+            )
             .unwrap())
     }
 
@@ -160,6 +279,59 @@ impl<CI: ChainIndex + 'static> IntegrationTestRpc for RpcImpl<CI> {
         Ok(self.shared.cell(&out_point).into())
     }
 
+    fn get_cell_consumer(&self, out_point: OutPoint) -> Result<Option<H256>> {
+        Ok(self.shared.store().get_cell_consumer(&out_point))
+    }
+
+    fn get_cells_by_lock_hash(&self, lock_hash: H256) -> Result<Vec<CellOutputWithOutPoint>> {
+        let store = self.shared.store();
+        store
+            .get_cells_by_lock_hash(&lock_hash)
+            .into_iter()
+            .map(|out_point| {
+                let output = store
+                    .get_transaction(&out_point.hash)
+                    .and_then(|tx| tx.outputs().get(out_point.index as usize).cloned())
+                    .ok_or_else(Error::internal_error)?;
+                Ok(CellOutputWithOutPoint {
+                    outpoint: out_point,
+                    capacity: output.capacity,
+                    lock: output.lock_hash(),
+                })
+            }).collect()
+    }
+
+    fn get_uncle(&self, hash: H256) -> Result<Option<UncleBlock>> {
+        Ok(self.shared.store().get_uncle(&hash))
+    }
+
+    fn get_sync_stats(&self) -> Result<SyncStats> {
+        Ok(self.shared.sync_stats().lock().clone())
+    }
+
+    fn get_epoch_stats(&self, epoch: u64) -> Result<Option<EpochStats>> {
+        Ok(self.shared.store().get_epoch_stats(epoch))
+    }
+
+    fn get_block_economic_state(&self, hash: H256) -> Result<Option<BlockEconomicState>> {
+        Ok(self
+            .shared
+            .block_number(&hash)
+            .and_then(|_| self.shared.store().get_block_economic_state(&hash)))
+    }
+
+    fn get_recent_rejections(&self) -> Result<Vec<BlockRejection>> {
+        Ok(self.rpc.get_recent_rejections())
+    }
+
+    fn local_node_info(&self) -> Result<NodeInfo> {
+        Ok(node_info(&self.network))
+    }
+
+    fn get_consensus(&self) -> Result<ConsensusParams> {
+        Ok(consensus_params(self.shared.consensus(), self.spec_hash))
+    }
+
     fn local_node_id(&self) -> Result<Option<String>> {
         Ok(self.network.external_url())
     }
@@ -171,6 +343,8 @@ impl<CI: ChainIndex + 'static> IntegrationTestRpc for RpcImpl<CI> {
 
 pub struct RpcServer {
     pub config: Config,
+    /// Hash of the chain spec the node runs, returned by get_consensus
+    pub spec_hash: H256,
 }
 
 impl RpcServer {
@@ -184,6 +358,8 @@ impl RpcServer {
     ) where
         CI: ChainIndex + 'static,
     {
+        let health = HealthMiddleware::new(Arc::clone(&network), shared.clone());
+
         let mut io = IoHandler::new();
         io.extend_with(
             RpcImpl {
@@ -192,6 +368,7 @@ impl RpcServer {
                 tx_pool,
                 rpc,
                 pow,
+                spec_hash: self.spec_hash,
             }.to_delegate(),
         );
 
@@ -199,7 +376,8 @@ impl RpcServer {
             .cors(DomainsValidation::AllowOnly(vec![
                 AccessControlAllowOrigin::Null,
                 AccessControlAllowOrigin::Any,
-            ])).start_http(&self.config.listen_addr.parse().unwrap())
+            ])).request_middleware(health)
+            .start_http(&self.config.listen_addr.parse().unwrap())
             .unwrap();
 
         info!(target: "rpc", "Now listening on {:?}", server.address());
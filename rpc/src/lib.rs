@@ -1,4 +1,6 @@
 extern crate bigint;
+extern crate bincode;
+extern crate faster_hex;
 extern crate flatbuffers;
 extern crate jsonrpc_core;
 #[macro_use]
@@ -7,6 +9,7 @@ extern crate jsonrpc_http_server;
 extern crate jsonrpc_server_utils;
 #[macro_use]
 extern crate log;
+extern crate ckb_chain_spec;
 extern crate ckb_core;
 #[cfg(test)]
 extern crate ckb_db;
@@ -14,28 +17,59 @@ extern crate ckb_network;
 extern crate ckb_notify;
 extern crate ckb_pool;
 extern crate ckb_protocol;
+extern crate ckb_script;
 extern crate ckb_shared;
 extern crate ckb_sync;
 extern crate ckb_time;
-#[cfg(test)]
+extern crate ckb_util;
 extern crate ckb_verification;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
-#[cfg(feature = "integration_test")]
+#[macro_use]
+extern crate serde_json;
 extern crate ckb_pow;
 #[macro_use]
 extern crate crossbeam_channel as channel;
 extern crate fnv;
+extern crate rayon;
+#[cfg(feature = "grpc")]
+extern crate futures;
+#[cfg(feature = "grpc")]
+extern crate grpcio;
+#[cfg(feature = "grpc")]
+extern crate protobuf;
 
 use bigint::H256;
-use ckb_core::block::Block;
-use ckb_core::cell::CellStatus;
-use ckb_core::header::Header;
-use ckb_core::transaction::{Capacity, CellOutput, OutPoint, Transaction};
+use ckb_chain_spec::consensus::Consensus;
+use ckb_core::header::{BlockNumber, Header};
+use ckb_core::transaction::Transaction;
+use ckb_network::NetworkService;
+use ckb_pool::txs_pool::PoolError;
+use ckb_script::ScriptLocation;
+use ckb_shared::shared::ChainProvider;
+use ckb_verification::TransactionError;
+use faster_hex::hex_string;
+use jsonrpc_core::{Error, ErrorCode};
+use serde::Serialize;
+use std::collections::HashMap;
 
+mod block_assembler;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod health;
+mod limits;
 mod service;
+pub mod types;
 
-pub use service::{BlockTemplate, RpcController, RpcReceivers, RpcService};
+pub use block_assembler::{BlockAssembler, BlockAssemblerConfig, BlockTemplate};
+pub use health::HealthMiddleware;
+pub use limits::{MethodLimit, MethodLimits, Permit};
+pub use service::{RpcController, RpcReceivers, RpcService};
+pub use types::{
+    BlockHashWithHeader, BlockId, BlockWithHash, CellOutputWithOutPoint, CellWithStatus,
+    ConsensusParams, NodeInfo, PoolTransactionWithStatus, TransactionWithHash, Verbose,
+};
 
 #[cfg(feature = "integration_test")]
 mod integration_test;
@@ -47,73 +81,383 @@ pub use integration_test::RpcServer;
 #[cfg(not(feature = "integration_test"))]
 pub use server::RpcServer;
 
-#[derive(Serialize)]
-pub struct TransactionWithHash {
-    pub hash: H256,
-    pub transaction: Transaction,
-}
+/// Verbosity of get_header and get_block: the hex of the serialized bytes, which the hash is
+/// computed from, or the JSON structure
+pub const VERBOSITY_RAW: u64 = 0;
+pub const VERBOSITY_JSON: u64 = 1;
 
-impl From<Transaction> for TransactionWithHash {
-    fn from(transaction: Transaction) -> Self {
-        Self {
-            hash: transaction.hash(),
-            transaction,
+fn verbose<S: Serialize, T: From<S>>(value: S, verbosity: u64) -> Result<Verbose<T>, Error> {
+    match verbosity {
+        VERBOSITY_RAW => {
+            let bytes = bincode::serialize(&value).map_err(|_| Error::internal_error())?;
+            let hex = hex_string(&bytes).map_err(|_| Error::internal_error())?;
+            Ok(Verbose::Raw(hex))
         }
+        VERBOSITY_JSON => Ok(Verbose::Json(value.into())),
+        _ => Err(Error::invalid_params(format!(
+            "verbosity must be {} or {}",
+            VERBOSITY_RAW, VERBOSITY_JSON
+        ))),
     }
 }
 
-#[derive(Serialize)]
-pub struct BlockWithHash {
-    pub hash: H256,
-    pub header: Header,
-    pub transactions: Vec<TransactionWithHash>,
+fn header_by_id<CP: ChainProvider>(
+    provider: &CP,
+    id: BlockId,
+    verbosity: u64,
+) -> Result<Option<Verbose<Header>>, Error> {
+    let header = match id {
+        BlockId::Hash(hash) => provider.block_header(&hash),
+        BlockId::Number(number) => provider
+            .block_hash(number)
+            .and_then(|hash| provider.block_header(&hash)),
+    };
+    match header {
+        Some(header) => verbose(header, verbosity).map(Some),
+        None => Ok(None),
+    }
 }
 
-impl From<Block> for BlockWithHash {
-    fn from(block: Block) -> Self {
-        Self {
-            header: block.header().clone(),
-            transactions: block
-                .commit_transactions()
-                .iter()
-                .map(|tx| tx.clone().into())
-                .collect(),
-            hash: block.header().hash(),
+fn block_by_hash<CP: ChainProvider>(
+    provider: &CP,
+    hash: &H256,
+    verbosity: u64,
+) -> Result<Option<Verbose<BlockWithHash>>, Error> {
+    let block = match provider.block(hash) {
+        Some(block) => block,
+        None => return Ok(None),
+    };
+    let mut block: Verbose<BlockWithHash> = verbose(block, verbosity)?;
+    if let Verbose::Json(ref mut block) = block {
+        for transaction in &mut block.transactions {
+            fill_cycles(provider, transaction);
         }
     }
+    Ok(Some(block))
 }
 
-// This is used as return value of get_cells_by_type_hash RPC:
-// it contains both OutPoint data used for referencing a cell, as well as
-// cell's own data such as lock and capacity
-#[derive(Serialize)]
-pub struct CellOutputWithOutPoint {
-    pub outpoint: OutPoint,
-    pub capacity: Capacity,
-    pub lock: H256,
+// The cycles are known as long as the verify cache remembers the scripts of the transaction
+fn fill_cycles<CP: ChainProvider>(provider: &CP, transaction: &mut TransactionWithHash) {
+    transaction.cycles = provider
+        .txs_verify_cache()
+        .lock()
+        .get(&transaction.hash)
+        .cloned();
 }
 
-#[derive(Serialize)]
-pub struct CellWithStatus {
-    pub cell: Option<CellOutput>,
-    pub status: String,
+fn transaction_with_cycles<CP: ChainProvider>(
+    provider: &CP,
+    transaction: Transaction,
+) -> TransactionWithHash {
+    let mut transaction = transaction.into();
+    fill_cycles(provider, &mut transaction);
+    transaction
 }
 
-impl From<CellStatus> for CellWithStatus {
-    fn from(status: CellStatus) -> Self {
-        let (cell, status) = match status {
-            CellStatus::Current(cell) => (Some(cell), "current"),
-            CellStatus::Old => (None, "old"),
-            CellStatus::Unknown => (None, "unknown"),
+/// The max number of blocks a single get_block_hashes call may cover
+pub const MAX_BLOCK_HASHES_RANGE: u64 = 1_000;
+
+/// The default transactions budget of a block template
+pub const MAX_TEMPLATE_BYTES: usize = 1_048_576;
+pub const MAX_TEMPLATE_CYCLES: u64 = 1_000_000_000;
+
+// Main chain hashes for the inclusive range `from..=to`, stops at the tip
+fn block_hashes<CP: ChainProvider>(
+    provider: &CP,
+    from: BlockNumber,
+    to: BlockNumber,
+    with_headers: bool,
+    permit: &Permit,
+) -> Result<Vec<BlockHashWithHeader>, Error> {
+    if from > to || to - from >= MAX_BLOCK_HASHES_RANGE {
+        return Err(Error::invalid_params(format!(
+            "range must be ascending and cover at most {} blocks",
+            MAX_BLOCK_HASHES_RANGE
+        )));
+    }
+
+    let mut result = Vec::new();
+    for number in from..=to {
+        permit.check_timeout()?;
+        let hash = match provider.block_hash(number) {
+            Some(hash) => hash,
+            None => break,
         };
-        Self {
-            cell,
-            status: status.to_string(),
+        let header = if with_headers {
+            Some(
+                provider
+                    .block_header(&hash)
+                    .ok_or_else(Error::internal_error)?,
+            )
+        } else {
+            None
+        };
+        result.push(BlockHashWithHeader {
+            number,
+            hash,
+            header,
+        });
+    }
+    Ok(result)
+}
+
+fn node_info(network: &NetworkService) -> NodeInfo {
+    NodeInfo {
+        node_id: network.node_id(),
+        addresses: network.external_urls(),
+        connected_peers: network.connected_peers_count(),
+    }
+}
+
+fn consensus_params(consensus: &Consensus, spec_hash: H256) -> ConsensusParams {
+    ConsensusParams {
+        id: consensus.id.clone(),
+        spec_hash,
+        genesis_hash: consensus.genesis_block().header().hash(),
+        initial_block_reward: consensus.initial_block_reward(),
+        max_uncles_age: consensus.max_uncles_age(),
+        max_uncles_len: consensus.max_uncles_len(),
+        orphan_rate_target: consensus.orphan_rate_target(),
+        pow_time_span: consensus.pow_time_span,
+        pow_spacing: consensus.pow_spacing,
+        target_block_interval: consensus.target_block_interval(),
+        difficulty_adjustment_interval: consensus.difficulty_adjustment_interval(),
+        difficulty_bounds: consensus.difficulty_bounds(),
+        min_difficulty: consensus.min_difficulty(),
+        proposal_window: consensus.proposal_window(),
+        max_block_proposals_limit: consensus.max_block_proposals_limit(),
+        max_block_bytes: consensus.max_block_bytes(),
+        max_tx_bytes: consensus.max_tx_bytes(),
+        max_cell_data_bytes: consensus.max_cell_data_bytes(),
+        max_block_data_bytes: consensus.max_block_data_bytes(),
+        median_time_block_count: consensus.median_time_block_count(),
+        max_block_time_drift: consensus.max_block_time_drift(),
+        pow: consensus.pow.clone(),
+        hardfork_switch: consensus.hardfork_switch().clone(),
+    }
+}
+
+// Returned by send_transaction when the pool rejects the transaction, the error code is the
+// stable rejection code, and the data of script failures carry the failed script so clients
+// don't need to replay it
+fn rejected_transaction_error(err: PoolError) -> Error {
+    let code = err.code();
+    let mut data = match err {
+        PoolError::InvalidTx(TransactionError::ScriptFailure(failure)) => {
+            let (group, index) = match failure.location {
+                ScriptLocation::Input(index) => ("input", index),
+                ScriptLocation::Output(index) => ("output", index),
+            };
+            json!({
+                "error": "ScriptFailure",
+                "group": group,
+                "index": index,
+                "script_hash": failure.script_hash,
+                "exit_code": failure.exit_code(),
+                "cycles": failure.cycles,
+                "reason": format!("{:?}", failure.error),
+            })
         }
+        err => json!({ "error": format!("{:?}", err) }),
+    };
+    data["code"] = json!(code);
+    Error {
+        code: ErrorCode::ServerError(i64::from(code)),
+        message: "invalid transaction".to_string(),
+        data: Some(data),
     }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct Config {
     pub listen_addr: String,
+    #[serde(default)]
+    pub block_assembler: BlockAssemblerConfig,
+    /// Serves the gRPC interface on this address, only when built with the `grpc` feature
+    #[serde(default)]
+    pub grpc_listen_addr: Option<String>,
+    /// Number of threads serving the requests
+    #[serde(default = "default_threads")]
+    pub threads: usize,
+    /// Concurrency limits and timeouts by method name, the methods left out aren't limited
+    #[serde(default = "limits::default_method_limits")]
+    pub method_limits: HashMap<String, MethodLimit>,
+}
+
+fn default_threads() -> usize {
+    limits::DEFAULT_THREADS
+}
+
+impl Config {
+    /// Rejects the settings which would leave the server or a method unable to serve any call
+    pub fn validate(&self) -> Result<(), String> {
+        if self.threads == 0 {
+            return Err("rpc threads must be at least 1".to_string());
+        }
+        for (method, limit) in &self.method_limits {
+            limit.validate(method)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigint::U256;
+    use ckb_core::transaction::TransactionBuilder;
+    use ckb_db::memorydb::MemoryKeyValueDB;
+    use ckb_script::{ScriptError, ScriptFailure};
+    use ckb_shared::shared::SharedBuilder;
+    use ckb_shared::store::ChainKVStore;
+
+    #[test]
+    fn test_block_hashes() {
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory().build();
+        let permit = Permit::unlimited();
+
+        // only the genesis block exists
+        let hashes = block_hashes(&shared, 0, 10, true, &permit).unwrap();
+        assert_eq!(hashes.len(), 1);
+        assert_eq!(hashes[0].hash, shared.genesis_hash());
+        assert_eq!(
+            hashes[0].header.as_ref().map(|header| header.hash()),
+            Some(shared.genesis_hash())
+        );
+        assert!(block_hashes(&shared, 0, 0, false, &permit).unwrap()[0].header.is_none());
+
+        assert!(block_hashes(&shared, 10, 0, false, &permit).is_err());
+        assert!(block_hashes(&shared, 0, MAX_BLOCK_HASHES_RANGE, false, &permit).is_err());
+        assert!(block_hashes(&shared, 0, MAX_BLOCK_HASHES_RANGE - 1, false, &permit).is_ok());
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let mut config = Config {
+            listen_addr: "127.0.0.1:0".to_string(),
+            block_assembler: BlockAssemblerConfig::default(),
+            grpc_listen_addr: None,
+            threads: default_threads(),
+            method_limits: limits::default_method_limits(),
+        };
+        assert!(config.validate().is_ok());
+
+        config.threads = 0;
+        assert!(config.validate().is_err());
+
+        config.threads = 1;
+        config
+            .method_limits
+            .get_mut("get_block_hashes")
+            .unwrap()
+            .max_concurrency = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_verbosity() {
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory().build();
+        let genesis = shared.consensus().genesis_block().clone();
+        let genesis_hash = shared.genesis_hash();
+
+        assert_eq!(
+            header_by_id(&shared, BlockId::Number(0), VERBOSITY_JSON).unwrap(),
+            Some(Verbose::Json(genesis.header().clone()))
+        );
+        let raw = hex_string(&bincode::serialize(genesis.header()).unwrap()).unwrap();
+        assert_eq!(
+            header_by_id(&shared, BlockId::Hash(genesis_hash), VERBOSITY_RAW).unwrap(),
+            Some(Verbose::Raw(raw))
+        );
+        assert_eq!(header_by_id(&shared, BlockId::Number(1), VERBOSITY_JSON).unwrap(), None);
+        assert!(header_by_id(&shared, BlockId::Number(0), 2).is_err());
+
+        let raw = hex_string(&bincode::serialize(&genesis).unwrap()).unwrap();
+        match block_by_hash(&shared, &genesis_hash, VERBOSITY_RAW).unwrap() {
+            Some(Verbose::Raw(hex)) => assert_eq!(hex, raw),
+            _ => panic!("raw block expected"),
+        }
+        match block_by_hash(&shared, &genesis_hash, VERBOSITY_JSON).unwrap() {
+            Some(Verbose::Json(block)) => assert_eq!(block.hash, genesis_hash),
+            _ => panic!("json block expected"),
+        }
+    }
+
+    #[test]
+    fn test_transaction_cycles() {
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory().build();
+        let transaction = TransactionBuilder::default().build();
+        assert_eq!(
+            transaction_with_cycles(&shared, transaction.clone()).cycles,
+            None
+        );
+
+        shared
+            .txs_verify_cache()
+            .lock()
+            .insert(transaction.hash(), 1000);
+        let with_cycles = transaction_with_cycles(&shared, transaction);
+        assert_eq!(with_cycles.cycles, Some(1000));
+        assert_eq!(
+            serde_json::to_value(&with_cycles).unwrap()["cycles"],
+            json!(1000)
+        );
+    }
+
+    #[test]
+    fn test_consensus_params() {
+        let consensus = Consensus::default()
+            .set_max_block_bytes(1000)
+            .set_target_block_interval(Some(5000));
+        let params = consensus_params(&consensus, H256::from(1));
+        assert_eq!(params.spec_hash, H256::from(1));
+        assert_eq!(
+            params.genesis_hash,
+            consensus.genesis_block().header().hash()
+        );
+        assert_eq!(params.max_block_bytes, 1000);
+        assert_eq!(params.min_difficulty, U256::one());
+
+        let json = serde_json::to_value(&params).unwrap();
+        assert_eq!(json["target_block_interval"], json!(5000));
+        assert_eq!(json["proposal_window"], json!({"close": 1, "far": 10}));
+        assert_eq!(json["pow"], json!("Dummy"));
+    }
+
+    #[test]
+    fn test_rejection_code() {
+        let err = rejected_transaction_error(PoolError::InvalidTx(TransactionError::Empty));
+        assert_eq!(err.code, ErrorCode::ServerError(104));
+        assert_eq!(err.data, Some(json!({ "error": "Empty", "code": 104 })));
+
+        let err = rejected_transaction_error(PoolError::DoubleSpent);
+        assert_eq!(
+            err.data,
+            Some(json!({ "error": "DoubleSpent", "code": 202 }))
+        );
+        assert_eq!(err.code, ErrorCode::ServerError(202));
+    }
+
+    #[test]
+    fn test_script_failure_data() {
+        let failure = ScriptFailure {
+            location: ScriptLocation::Output(1),
+            script_hash: H256::from(3),
+            error: ScriptError::ValidationFailure(2),
+            cycles: 700,
+        };
+        let err = rejected_transaction_error(PoolError::InvalidTx(
+            TransactionError::ScriptFailure(failure),
+        ));
+        let data = err.data.unwrap();
+        assert_eq!(data["group"], json!("output"));
+        assert_eq!(data["index"], json!(1));
+        assert_eq!(
+            data["script_hash"],
+            serde_json::to_value(H256::from(3)).unwrap()
+        );
+        assert_eq!(data["exit_code"], json!(2));
+        assert_eq!(data["cycles"], json!(700));
+        assert_eq!(data["code"], json!(108));
+    }
 }
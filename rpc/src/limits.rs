@@ -0,0 +1,191 @@
+//! Per-method limits of the RPC server, so a burst of heavy queries like the range scans of an
+//! explorer can't take all the server threads from cheap calls like `send_transaction`.
+
+use ckb_time::now_ms;
+use jsonrpc_core::{Error, ErrorCode};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of threads serving the RPC requests
+pub const DEFAULT_THREADS: usize = 4;
+
+const BUSY_ERROR_CODE: i64 = -32001;
+const TIMEOUT_ERROR_CODE: i64 = -32002;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct MethodLimit {
+    /// Calls of the method running at once, the calls beyond are rejected as busy
+    pub max_concurrency: usize,
+    /// Milliseconds a call may run, only the scanning methods `get_block_hashes`,
+    /// `get_cells_by_type_hash` and `get_cells_by_lock_hash` check it
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+impl MethodLimit {
+    /// A limit admitting no call at all is a misconfiguration
+    pub fn validate(&self, method: &str) -> Result<(), String> {
+        if self.max_concurrency == 0 {
+            return Err(format!("rpc method {} has max_concurrency 0", method));
+        }
+        if self.timeout == Some(0) {
+            return Err(format!("rpc method {} has timeout 0", method));
+        }
+        Ok(())
+    }
+}
+
+/// The range scans are limited by default, the other methods aren't
+pub fn default_method_limits() -> HashMap<String, MethodLimit> {
+    let scan = MethodLimit {
+        max_concurrency: 2,
+        timeout: Some(5_000),
+    };
+    let scans = [
+        "get_block_hashes",
+        "get_cells_by_type_hash",
+        "get_cells_by_lock_hash",
+    ];
+    scans
+        .iter()
+        .map(|method| (method.to_string(), scan.clone()))
+        .collect()
+}
+
+pub struct MethodLimits {
+    methods: HashMap<String, (MethodLimit, AtomicUsize)>,
+}
+
+impl MethodLimits {
+    pub fn new(limits: &HashMap<String, MethodLimit>) -> Self {
+        MethodLimits {
+            methods: limits
+                .iter()
+                .map(|(method, limit)| (method.clone(), (limit.clone(), AtomicUsize::new(0))))
+                .collect(),
+        }
+    }
+
+    /// Admits a call of `method`, it counts against the limit until the permit is dropped
+    pub fn enter(&self, method: &str) -> Result<Permit, Error> {
+        let (limit, running) = match self.methods.get(method) {
+            Some(&(ref limit, ref running)) => (limit, running),
+            None => return Ok(Permit::unlimited()),
+        };
+        if running.fetch_add(1, Ordering::SeqCst) >= limit.max_concurrency {
+            running.fetch_sub(1, Ordering::SeqCst);
+            return Err(Error {
+                code: ErrorCode::ServerError(BUSY_ERROR_CODE),
+                message: format!("too many concurrent {} calls, retry later", method),
+                data: None,
+            });
+        }
+        Ok(Permit {
+            running: Some(running),
+            deadline: limit.timeout.map(|timeout| now_ms() + timeout),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Permit<'a> {
+    running: Option<&'a AtomicUsize>,
+    deadline: Option<u64>,
+}
+
+impl Permit<'static> {
+    /// The permit of a call no limit applies to
+    pub fn unlimited() -> Self {
+        Permit {
+            running: None,
+            deadline: None,
+        }
+    }
+}
+
+impl<'a> Permit<'a> {
+    /// Checked along a scan, fails once the call has run for its timeout
+    pub fn check_timeout(&self) -> Result<(), Error> {
+        match self.deadline {
+            Some(deadline) if now_ms() >= deadline => Err(Error {
+                code: ErrorCode::ServerError(TIMEOUT_ERROR_CODE),
+                message: "call timed out, narrow the query".to_string(),
+                data: None,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        if let Some(running) = self.running {
+            running.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrency_limit() {
+        let limits = MethodLimits::new(&default_method_limits());
+        let first = limits.enter("get_cells_by_type_hash").unwrap();
+        let second = limits.enter("get_cells_by_type_hash").unwrap();
+        assert_eq!(
+            limits.enter("get_cells_by_type_hash").unwrap_err().code,
+            ErrorCode::ServerError(BUSY_ERROR_CODE)
+        );
+        // the other methods are limited on their own or not at all
+        assert!(limits.enter("get_cells_by_lock_hash").is_ok());
+        assert!(limits.enter("send_transaction").is_ok());
+
+        drop(first);
+        assert!(limits.enter("get_cells_by_type_hash").is_ok());
+        assert!(second.check_timeout().is_ok());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut config = HashMap::new();
+        config.insert(
+            "get_cells_by_type_hash".to_string(),
+            MethodLimit {
+                max_concurrency: 1,
+                timeout: Some(0),
+            },
+        );
+        let limits = MethodLimits::new(&config);
+        let permit = limits.enter("get_cells_by_type_hash").unwrap();
+        assert_eq!(
+            permit.check_timeout().unwrap_err().code,
+            ErrorCode::ServerError(TIMEOUT_ERROR_CODE)
+        );
+        assert!(Permit::unlimited().check_timeout().is_ok());
+    }
+
+    #[test]
+    fn test_validate() {
+        let limit = MethodLimit {
+            max_concurrency: 1,
+            timeout: None,
+        };
+        assert!(limit.validate("get_block_hashes").is_ok());
+        assert!(
+            MethodLimit {
+                max_concurrency: 0,
+                ..limit.clone()
+            }.validate("get_block_hashes")
+            .is_err()
+        );
+        assert!(
+            MethodLimit {
+                timeout: Some(0),
+                ..limit
+            }.validate("get_block_hashes")
+            .is_err()
+        );
+    }
+}
@@ -1,18 +1,29 @@
-use super::service::{BlockTemplate, RpcController};
-use super::{BlockWithHash, CellOutputWithOutPoint, CellWithStatus, Config, TransactionWithHash};
+use super::block_assembler::BlockTemplate;
+use super::service::RpcController;
+use super::{
+    block_by_hash, block_hashes, consensus_params, fill_cycles, header_by_id, node_info,
+    rejected_transaction_error, transaction_with_cycles, BlockHashWithHeader, BlockId,
+    BlockWithHash, CellOutputWithOutPoint, CellWithStatus, Config, ConsensusParams,
+    HealthMiddleware, MethodLimits, NodeInfo, PoolTransactionWithStatus, TransactionWithHash,
+    Verbose, VERBOSITY_JSON,
+};
 use bigint::H256;
 use ckb_core::cell::CellProvider;
+use ckb_core::extras::{BlockEconomicState, BlockRejection, EpochStats, SyncStats};
 use ckb_core::header::{BlockNumber, Header};
-use ckb_core::transaction::{OutPoint, Transaction};
+use ckb_core::transaction::{OutPoint, ProposalShortId, Transaction};
+use ckb_core::uncle::UncleBlock;
 use ckb_network::NetworkService;
-use ckb_pool::txs_pool::TransactionPoolController;
+use ckb_pool::txs_pool::{PoolError, TransactionPoolController};
 use ckb_protocol::RelayMessage;
 use ckb_shared::index::ChainIndex;
 use ckb_shared::shared::{ChainProvider, Shared};
+use ckb_shared::store::ChainStore;
 use ckb_sync::RELAY_PROTOCOL_ID;
 use flatbuffers::FlatBufferBuilder;
 use jsonrpc_core::{Error, IoHandler, Result};
 use jsonrpc_http_server::ServerBuilder;
+use jsonrpc_macros::Trailing;
 use jsonrpc_server_utils::cors::AccessControlAllowOrigin;
 use jsonrpc_server_utils::hosts::DomainsValidation;
 use std::sync::Arc;
@@ -23,14 +34,25 @@ build_rpc_trait! {
         #[rpc(name = "send_transaction")]
         fn send_transaction(&self, Transaction) -> Result<H256>;
 
+        // The last param is the verbosity, 0 for the hex of the serialized block, 1 (default) for JSON
         // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_block","params": ["0x0f9da6db98d0acd1ae0cf7ae3ee0b2b5ad2855d93c18d27c0961f985a62a93c3"]}' -H 'content-type:application/json' 'http://localhost:8114'
         #[rpc(name = "get_block")]
-        fn get_block(&self, H256) -> Result<Option<BlockWithHash>>;
+        fn get_block(&self, H256, Trailing<u64>) -> Result<Option<Verbose<BlockWithHash>>>;
+
+        // Takes a hash or a main chain number, the last param is the verbosity as for get_block
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_header","params": [1, 0]}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_header")]
+        fn get_header(&self, BlockId, Trailing<u64>) -> Result<Option<Verbose<Header>>>;
 
         // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_transaction","params": ["0x0f9da6db98d0acd1ae0cf7ae3ee0b2b5ad2855d93c18d27c0961f985a62a93c3"]}' -H 'content-type:application/json' 'http://localhost:8114'
         #[rpc(name = "get_transaction")]
         fn get_transaction(&self, H256) -> Result<Option<TransactionWithHash>>;
 
+        // A transaction still in the pool and its status there
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_pool_transaction","params": ["0x0f9da6db98d0acd1ae0cf7ae3ee0b2b5ad2855d93c18d27c0961f985a62a93c3"]}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_pool_transaction")]
+        fn get_pool_transaction(&self, H256) -> Result<Option<PoolTransactionWithStatus>>;
+
         // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_block_hash","params": [1]}' -H 'content-type:application/json' 'http://localhost:8114'
         #[rpc(name = "get_block_hash")]
         fn get_block_hash(&self, u64) -> Result<Option<H256>>;
@@ -39,6 +61,11 @@ build_rpc_trait! {
         #[rpc(name = "get_tip_header")]
         fn get_tip_header(&self) -> Result<Header>;
 
+        // Returns at most MAX_BLOCK_HASHES_RANGE main chain hashes, headers are included when the last param is true
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_block_hashes","params": [1, 100, true]}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_block_hashes")]
+        fn get_block_hashes(&self, u64, u64, Trailing<bool>) -> Result<Vec<BlockHashWithHeader>>;
+
         // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_block_template","params": []}' -H 'content-type:application/json' 'http://localhost:8114'
         #[rpc(name = "get_block_template")]
         fn get_block_template(&self) -> Result<BlockTemplate>;
@@ -50,6 +77,50 @@ build_rpc_trait! {
         // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_current_cell","params": [{"hash": "0x1b1c832d02fdb4339f9868c8a8636c3d9dd10bd53ac7ce99595825bd6beeffb3", "index": 1}]}' -H 'content-type:application/json' 'http://localhost:3030'
         #[rpc(name = "get_current_cell")]
         fn get_current_cell(&self, OutPoint) -> Result<CellWithStatus>;
+
+        // Only answered when `chain.cell_consumer_index` is enabled
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_cell_consumer","params": [{"hash": "0x1b1c832d02fdb4339f9868c8a8636c3d9dd10bd53ac7ce99595825bd6beeffb3", "index": 1}]}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_cell_consumer")]
+        fn get_cell_consumer(&self, OutPoint) -> Result<Option<H256>>;
+
+        // Only answered when `chain.lock_hash_index` is enabled
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_cells_by_lock_hash","params": ["0x1b1c832d02fdb4339f9868c8a8636c3d9dd10bd53ac7ce99595825bd6beeffb3"]}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_cells_by_lock_hash")]
+        fn get_cells_by_lock_hash(&self, H256) -> Result<Vec<CellOutputWithOutPoint>>;
+
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_uncle","params": ["0x0f9da6db98d0acd1ae0cf7ae3ee0b2b5ad2855d93c18d27c0961f985a62a93c3"]}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_uncle")]
+        fn get_uncle(&self, H256) -> Result<Option<UncleBlock>>;
+
+        // Cumulative since the database was created, received bytes are flushed with the next processed block
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_sync_stats","params": []}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_sync_stats")]
+        fn get_sync_stats(&self) -> Result<SyncStats>;
+
+        // Null until the main chain completes the epoch
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_epoch_stats","params": [1]}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_epoch_stats")]
+        fn get_epoch_stats(&self, u64) -> Result<Option<EpochStats>>;
+
+        // Null unless the block is on the main chain, the genesis has no reward to claim
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_block_economic_state","params": ["0x0f9da6db98d0acd1ae0cf7ae3ee0b2b5ad2855d93c18d27c0961f985a62a93c3"]}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_block_economic_state")]
+        fn get_block_economic_state(&self, H256) -> Result<Option<BlockEconomicState>>;
+
+        // Oldest first, at most the last 128 rejected blocks since the node started
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_recent_rejections","params": []}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_recent_rejections")]
+        fn get_recent_rejections(&self) -> Result<Vec<BlockRejection>>;
+
+        // The node id, the addresses other nodes can dial and the number of connected peers
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"local_node_info","params": []}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "local_node_info")]
+        fn local_node_info(&self) -> Result<NodeInfo>;
+
+        // The consensus parameters of the chain, they differ between the dev chain, the testnet and the mainnet
+        // curl -d '{"id": 2, "jsonrpc": "2.0", "method":"get_consensus","params": []}' -H 'content-type:application/json' 'http://localhost:8114'
+        #[rpc(name = "get_consensus")]
+        fn get_consensus(&self) -> Result<ConsensusParams>;
     }
 }
 
@@ -58,13 +129,21 @@ struct RpcImpl<CI> {
     shared: Shared<CI>,
     tx_pool: TransactionPoolController,
     controller: RpcController,
+    spec_hash: H256,
+    limits: MethodLimits,
 }
 
 impl<CI: ChainIndex + 'static> Rpc for RpcImpl<CI> {
     fn send_transaction(&self, tx: Transaction) -> Result<H256> {
+        let _permit = self.limits.enter("send_transaction")?;
         let tx_hash = tx.hash();
         let pool_result = self.tx_pool.add_transaction(tx.clone());
         debug!(target: "rpc", "send_transaction add to pool result: {:?}", pool_result);
+        match pool_result {
+            // resending a pool transaction relays it again
+            Ok(_) | Err(PoolError::AlreadyInPool) => {}
+            Err(err) => return Err(rejected_transaction_error(err)),
+        }
 
         let fbb = &mut FlatBufferBuilder::new();
         let message = RelayMessage::build_transaction(fbb, &tx);
@@ -79,19 +158,66 @@ impl<CI: ChainIndex + 'static> Rpc for RpcImpl<CI> {
         Ok(tx_hash)
     }
 
-    fn get_block(&self, hash: H256) -> Result<Option<BlockWithHash>> {
-        Ok(self.shared.block(&hash).map(Into::into))
+    fn get_block(
+        &self,
+        hash: H256,
+        verbosity: Trailing<u64>,
+    ) -> Result<Option<Verbose<BlockWithHash>>> {
+        let _permit = self.limits.enter("get_block")?;
+        block_by_hash(&self.shared, &hash, verbosity.unwrap_or(VERBOSITY_JSON))
+    }
+
+    fn get_header(&self, id: BlockId, verbosity: Trailing<u64>) -> Result<Option<Verbose<Header>>> {
+        let _permit = self.limits.enter("get_header")?;
+        header_by_id(&self.shared, id, verbosity.unwrap_or(VERBOSITY_JSON))
     }
 
     fn get_transaction(&self, hash: H256) -> Result<Option<TransactionWithHash>> {
-        Ok(self.shared.get_transaction(&hash).map(Into::into))
+        let _permit = self.limits.enter("get_transaction")?;
+        Ok(self
+            .shared
+            .get_transaction(&hash)
+            .map(|tx| transaction_with_cycles(&self.shared, tx)))
+    }
+
+    fn get_pool_transaction(&self, hash: H256) -> Result<Option<PoolTransactionWithStatus>> {
+        let _permit = self.limits.enter("get_pool_transaction")?;
+        let id = ProposalShortId::from_h256(&hash);
+        // the short id may collide, only answer for the requested transaction
+        Ok(self
+            .tx_pool
+            .get_pool_transaction(id)
+            .filter(|entry| entry.transaction.hash() == hash)
+            .map(|entry| {
+                let mut entry = PoolTransactionWithStatus::from(entry);
+                fill_cycles(&self.shared, &mut entry.transaction);
+                entry
+            }))
     }
 
     fn get_block_hash(&self, number: BlockNumber) -> Result<Option<H256>> {
+        let _permit = self.limits.enter("get_block_hash")?;
         Ok(self.shared.block_hash(number))
     }
 
+    fn get_block_hashes(
+        &self,
+        from: BlockNumber,
+        to: BlockNumber,
+        with_headers: Trailing<bool>,
+    ) -> Result<Vec<BlockHashWithHeader>> {
+        let permit = self.limits.enter("get_block_hashes")?;
+        block_hashes(
+            &self.shared,
+            from,
+            to,
+            with_headers.unwrap_or(false),
+            &permit,
+        )
+    }
+
     fn get_tip_header(&self) -> Result<Header> {
+        let _permit = self.limits.enter("get_tip_header")?;
         Ok(self.shared.tip_header().read().inner().clone())
     }
 
@@ -102,8 +228,10 @@ impl<CI: ChainIndex + 'static> Rpc for RpcImpl<CI> {
         from: u64,
         to: u64,
     ) -> Result<Vec<CellOutputWithOutPoint>> {
+        let permit = self.limits.enter("get_cells_by_type_hash")?;
         let mut result = Vec::new();
         for block_number in from..=to {
+            permit.check_timeout()?;
             if let Some(block_hash) = self.shared.block_hash(block_number) {
                 let block = self
                     .shared
@@ -130,20 +258,93 @@ impl<CI: ChainIndex + 'static> Rpc for RpcImpl<CI> {
         Ok(result)
     }
 
-    // TODO: the max size
     fn get_block_template(&self) -> Result<BlockTemplate> {
+        let _permit = self.limits.enter("get_block_template")?;
+        // the block assembler caps the budget to its config
         self.controller
-            .get_block_template(H256::from(0), 20000, 20000)
+            .get_block_template(
+                H256::from(0),
+                usize::max_value(),
+                u64::max_value(),
+                20000,
+            )
             .map_err(|_| Error::internal_error())
     }
 
     fn get_current_cell(&self, out_point: OutPoint) -> Result<CellWithStatus> {
+        let _permit = self.limits.enter("get_current_cell")?;
         Ok(self.shared.cell(&out_point).into())
     }
+
+    fn get_cell_consumer(&self, out_point: OutPoint) -> Result<Option<H256>> {
+        let _permit = self.limits.enter("get_cell_consumer")?;
+        Ok(self.shared.store().get_cell_consumer(&out_point))
+    }
+
+    fn get_cells_by_lock_hash(&self, lock_hash: H256) -> Result<Vec<CellOutputWithOutPoint>> {
+        let permit = self.limits.enter("get_cells_by_lock_hash")?;
+        let store = self.shared.store();
+        store
+            .get_cells_by_lock_hash(&lock_hash)
+            .into_iter()
+            .map(|out_point| {
+                permit.check_timeout()?;
+                let output = store
+                    .get_transaction(&out_point.hash)
+                    .and_then(|tx| tx.outputs().get(out_point.index as usize).cloned())
+                    .ok_or_else(Error::internal_error)?;
+                Ok(CellOutputWithOutPoint {
+                    outpoint: out_point,
+                    capacity: output.capacity,
+                    lock: output.lock_hash(),
+                })
+            }).collect()
+    }
+
+    fn get_uncle(&self, hash: H256) -> Result<Option<UncleBlock>> {
+        let _permit = self.limits.enter("get_uncle")?;
+        Ok(self.shared.store().get_uncle(&hash))
+    }
+
+    fn get_sync_stats(&self) -> Result<SyncStats> {
+        let _permit = self.limits.enter("get_sync_stats")?;
+        Ok(self.shared.sync_stats().lock().clone())
+    }
+
+    fn get_epoch_stats(&self, epoch: u64) -> Result<Option<EpochStats>> {
+        let _permit = self.limits.enter("get_epoch_stats")?;
+        Ok(self.shared.store().get_epoch_stats(epoch))
+    }
+
+    fn get_block_economic_state(&self, hash: H256) -> Result<Option<BlockEconomicState>> {
+        let _permit = self.limits.enter("get_block_economic_state")?;
+        // a detached block keeps its state until it's attached again
+        Ok(self
+            .shared
+            .block_number(&hash)
+            .and_then(|_| self.shared.store().get_block_economic_state(&hash)))
+    }
+
+    fn get_recent_rejections(&self) -> Result<Vec<BlockRejection>> {
+        let _permit = self.limits.enter("get_recent_rejections")?;
+        Ok(self.controller.get_recent_rejections())
+    }
+
+    fn local_node_info(&self) -> Result<NodeInfo> {
+        let _permit = self.limits.enter("local_node_info")?;
+        Ok(node_info(&self.network))
+    }
+
+    fn get_consensus(&self) -> Result<ConsensusParams> {
+        let _permit = self.limits.enter("get_consensus")?;
+        Ok(consensus_params(self.shared.consensus(), self.spec_hash))
+    }
 }
 
 pub struct RpcServer {
     pub config: Config,
+    /// Hash of the chain spec the node runs, returned by get_consensus
+    pub spec_hash: H256,
 }
 
 impl RpcServer {
@@ -156,6 +357,8 @@ impl RpcServer {
     ) where
         CI: ChainIndex,
     {
+        let health = HealthMiddleware::new(Arc::clone(&network), shared.clone());
+
         let mut io = IoHandler::new();
         io.extend_with(
             RpcImpl {
@@ -163,6 +366,8 @@ impl RpcServer {
                 shared,
                 tx_pool,
                 controller,
+                spec_hash: self.spec_hash,
+                limits: MethodLimits::new(&self.config.method_limits),
             }.to_delegate(),
         );
 
@@ -170,10 +375,66 @@ impl RpcServer {
             .cors(DomainsValidation::AllowOnly(vec![
                 AccessControlAllowOrigin::Null,
                 AccessControlAllowOrigin::Any,
-            ])).start_http(&self.config.listen_addr.parse().unwrap())
+            ])).request_middleware(health)
+            .threads(self.config.threads)
+            .start_http(&self.config.listen_addr.parse().unwrap())
             .unwrap();
 
         info!(target: "rpc", "Now listening on {:?}", server.address());
         server.wait();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_db::memorydb::MemoryKeyValueDB;
+    use ckb_network::NetworkConfig;
+    use ckb_shared::shared::SharedBuilder;
+    use ckb_shared::store::ChainKVStore;
+    use serde_json::{self, Value};
+    use std::collections::HashMap;
+    use MethodLimit;
+
+    fn call(method_limits: &HashMap<String, MethodLimit>, request: &str) -> Value {
+        let mut network_config = NetworkConfig::default();
+        network_config.listen_addresses = vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()];
+        network_config.generate_random_key().unwrap();
+        let network = NetworkService::run_in_thread(&network_config, Vec::new()).unwrap();
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory().build();
+        let (tx_pool, _tx_pool_receivers) = TransactionPoolController::new();
+        let (controller, _rpc_receivers) = RpcController::new();
+
+        let mut io = IoHandler::new();
+        io.extend_with(
+            RpcImpl {
+                network: Arc::new(network),
+                shared,
+                tx_pool,
+                controller,
+                spec_hash: H256::zero(),
+                limits: MethodLimits::new(method_limits),
+            }.to_delegate(),
+        );
+        let response = io.handle_request_sync(request).expect("response");
+        serde_json::from_str(&response).unwrap()
+    }
+
+    #[test]
+    fn test_get_block_hashes_timeout() {
+        let request = r#"{"id":2,"jsonrpc":"2.0","method":"get_block_hashes","params":[0,0]}"#;
+        let mut method_limits = HashMap::new();
+        let response = call(&method_limits, request);
+        assert_eq!(response["result"][0]["number"], json!(0));
+
+        method_limits.insert(
+            "get_block_hashes".to_string(),
+            MethodLimit {
+                max_concurrency: 1,
+                timeout: Some(0),
+            },
+        );
+        let response = call(&method_limits, request);
+        assert_eq!(response["error"]["code"], json!(-32002));
+    }
+}
@@ -0,0 +1,172 @@
+//! The JSON types of the RPC, serialized by the node and deserialized by `ckb-rpc-client`
+
+use bigint::{H256, U256};
+use ckb_chain_spec::consensus::{DifficultyBounds, ProposalWindow};
+use ckb_chain_spec::hardfork::HardForkSwitch;
+use ckb_core::block::Block;
+use ckb_core::cell::CellStatus;
+use ckb_core::header::{BlockNumber, Header};
+use ckb_core::transaction::{Capacity, CellOutput, OutPoint, Transaction};
+use ckb_pool::txs_pool::{PoolTransaction, PoolTxStatus};
+use ckb_pow::Pow;
+
+pub use block_assembler::BlockTemplate;
+
+// `size` is the serialized size the pool uses for its own size accounting,
+// `cycles` is only reported when the node has already run the scripts.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TransactionWithHash {
+    pub hash: H256,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cycles: Option<u64>,
+    pub transaction: Transaction,
+}
+
+impl From<Transaction> for TransactionWithHash {
+    fn from(transaction: Transaction) -> Self {
+        Self {
+            hash: transaction.hash(),
+            size: transaction.serialized_size(),
+            cycles: None,
+            transaction,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlockWithHash {
+    pub hash: H256,
+    pub size: u64,
+    pub header: Header,
+    pub transactions: Vec<TransactionWithHash>,
+}
+
+impl From<Block> for BlockWithHash {
+    fn from(block: Block) -> Self {
+        Self {
+            size: block.serialized_size(),
+            header: block.header().clone(),
+            transactions: block
+                .commit_transactions()
+                .iter()
+                .map(|tx| tx.clone().into())
+                .collect(),
+            hash: block.header().hash(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Verbose<T> {
+    Raw(String),
+    Json(T),
+}
+
+/// A main chain block, by hash or by number
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum BlockId {
+    Hash(H256),
+    Number(BlockNumber),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlockHashWithHeader {
+    pub number: BlockNumber,
+    pub hash: H256,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header: Option<Header>,
+}
+
+// This is used as return value of get_cells_by_type_hash RPC:
+// it contains both OutPoint data used for referencing a cell, as well as
+// cell's own data such as lock and capacity
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CellOutputWithOutPoint {
+    pub outpoint: OutPoint,
+    pub capacity: Capacity,
+    pub lock: H256,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NodeInfo {
+    pub node_id: String,
+    pub addresses: Vec<String>,
+    pub connected_peers: usize,
+}
+
+// Returned by get_consensus, the parameters of the chain the node runs
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConsensusParams {
+    pub id: String,
+    pub spec_hash: H256,
+    pub genesis_hash: H256,
+    pub initial_block_reward: Capacity,
+    pub max_uncles_age: usize,
+    pub max_uncles_len: usize,
+    pub orphan_rate_target: f32,
+    pub pow_time_span: u64,
+    pub pow_spacing: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_block_interval: Option<u64>,
+    pub difficulty_adjustment_interval: BlockNumber,
+    pub difficulty_bounds: DifficultyBounds,
+    pub min_difficulty: U256,
+    pub proposal_window: ProposalWindow,
+    pub max_block_proposals_limit: u64,
+    pub max_block_bytes: u64,
+    pub max_tx_bytes: u64,
+    pub max_cell_data_bytes: u64,
+    pub max_block_data_bytes: u64,
+    pub median_time_block_count: usize,
+    pub max_block_time_drift: u64,
+    pub pow: Pow,
+    pub hardfork_switch: HardForkSwitch,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CellWithStatus {
+    pub cell: Option<CellOutput>,
+    pub status: String,
+}
+
+impl From<CellStatus> for CellWithStatus {
+    fn from(status: CellStatus) -> Self {
+        let (cell, status) = match status {
+            CellStatus::Current(cell) => (Some(cell), "current"),
+            CellStatus::Old => (None, "old"),
+            CellStatus::Unknown => (None, "unknown"),
+        };
+        Self {
+            cell,
+            status: status.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PoolTransactionWithStatus {
+    pub transaction: TransactionWithHash,
+    pub status: String,
+    pub expired_proposals: u32,
+}
+
+impl From<PoolTransaction> for PoolTransactionWithStatus {
+    fn from(entry: PoolTransaction) -> Self {
+        let status = match entry.status {
+            PoolTxStatus::Pending => "pending",
+            PoolTxStatus::ProposalExpired => "proposal expired, re-proposing",
+            PoolTxStatus::Proposed => "proposed",
+            PoolTxStatus::Mineable => "mineable",
+            PoolTxStatus::Orphan => "orphan",
+            PoolTxStatus::Conflict => "conflict",
+        };
+        Self {
+            transaction: entry.transaction.into(),
+            status: status.to_string(),
+            expired_proposals: entry.expired_proposals,
+        }
+    }
+}
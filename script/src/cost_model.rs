@@ -0,0 +1,9 @@
+//! The cycles a script run is charged, counted by the VM as it executes the instructions.
+
+use ckb_core::Cycle;
+use ckb_vm::instructions::Instruction;
+
+/// Every executed instruction costs a cycle, the cycles of a run bound its execution time
+pub fn instruction_cycles(_instruction: &Instruction) -> Cycle {
+    1
+}
@@ -4,29 +4,71 @@ extern crate ckb_protocol;
 extern crate ckb_vm;
 #[cfg(test)]
 extern crate crypto;
-#[cfg(test)]
 extern crate faster_hex;
 extern crate flatbuffers;
 extern crate fnv;
-#[cfg(test)]
 extern crate hash;
 #[macro_use]
 extern crate log;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 #[cfg(test)]
 #[macro_use]
 extern crate proptest;
 
+mod cost_model;
+mod profile;
 mod syscalls;
 mod verify;
 
+use bigint::H256;
+use ckb_core::Cycle;
 use ckb_vm::Error as VMInternalError;
 
-pub use verify::TransactionScriptsVerifier;
+pub use cost_model::instruction_cycles;
+pub use profile::{ScriptProfile, ScriptProfiler, TransactionProfile};
+pub use verify::{
+    chain_id_arg, script_args, signing_message, TransactionScriptsVerifier, CHAIN_ID_VERSION,
+};
 
 #[derive(Debug, PartialEq, Clone, Copy, Eq)]
 pub enum ScriptError {
     NoScript,
     InvalidReferenceIndex,
+    /// No VM runs scripts of this version
+    UnsupportedVersion(u8),
     ValidationFailure(u8),
+    /// The run was stopped for using up the cycles left to the transaction
+    ExceededMaximumCycles,
     VMError(VMInternalError),
 }
+
+/// The script of a transaction which failed the verification.
+#[derive(Debug, PartialEq, Clone, Copy, Eq, Serialize)]
+pub enum ScriptLocation {
+    /// Unlock script of the input at the index
+    Input(usize),
+    /// Contract of the output at the index
+    Output(usize),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Eq)]
+pub struct ScriptFailure {
+    pub location: ScriptLocation,
+    /// `type_hash` of the failed script
+    pub script_hash: H256,
+    pub error: ScriptError,
+    /// The cycles the failed script consumed before it stopped
+    pub cycles: Cycle,
+}
+
+impl ScriptFailure {
+    /// Exit code returned by the script, only present when the script ran to completion.
+    pub fn exit_code(&self) -> Option<u8> {
+        match self.error {
+            ScriptError::ValidationFailure(code) => Some(code),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! Profiles of the transaction scripts, for the contract developers to optimize their scripts
+//! against what the verifier actually runs.
+
+use super::{ScriptError, ScriptFailure, ScriptLocation};
+use bigint::H256;
+use ckb_core::Cycle;
+use serde::Serializer;
+
+/// A script run, see `TransactionScriptsVerifier::profile`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ScriptProfile {
+    pub location: ScriptLocation,
+    /// `type_hash` of the script
+    pub script_hash: H256,
+    /// The cycles the VM counted for the run, until it stopped when the script failed
+    pub cycles: Cycle,
+    /// Syscall number and count, the most frequent first
+    pub syscalls: Vec<(u64, u64)>,
+    #[serde(serialize_with = "serialize_error")]
+    pub error: Option<ScriptError>,
+}
+
+/// All the scripts of a transaction, the unlocks of the inputs then the contracts of the
+/// outputs, in order
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TransactionProfile {
+    pub hash: H256,
+    pub scripts: Vec<ScriptProfile>,
+}
+
+impl TransactionProfile {
+    /// The result of verifying the scripts, the total cycles or the first failure
+    pub fn result(&self) -> Result<Cycle, ScriptFailure> {
+        let mut cycles = 0;
+        for script in &self.scripts {
+            match script.error {
+                Some(error) => {
+                    return Err(ScriptFailure {
+                        location: script.location,
+                        script_hash: script.script_hash.clone(),
+                        error,
+                        cycles: script.cycles,
+                    })
+                }
+                None => cycles += script.cycles,
+            }
+        }
+        Ok(cycles)
+    }
+}
+
+/// Receives the profile of every transaction whose scripts a verifier runs, possibly from
+/// several threads at once
+pub trait ScriptProfiler: Send + Sync {
+    fn record(&self, profile: &TransactionProfile);
+}
+
+// The VM errors aren't serializable, a report only needs to read them
+fn serialize_error<S: Serializer>(
+    error: &Option<ScriptError>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match error {
+        Some(error) => serializer.serialize_some(&format!("{:?}", error)),
+        None => serializer.serialize_none(),
+    }
+}
@@ -0,0 +1,37 @@
+use ckb_vm::{CoreMachine, Error as VMError, Memory, Register, Syscalls, A7};
+use fnv::FnvHashMap;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Counts the syscalls of a script run by number and leaves them to the other modules, it has
+/// to be the first module of the machine. The clones share their counts.
+#[derive(Clone, Default)]
+pub struct SyscallCounter {
+    counts: Rc<RefCell<FnvHashMap<u64, u64>>>,
+}
+
+impl SyscallCounter {
+    /// Syscall number and count, the most frequent first
+    pub fn counts(&self) -> Vec<(u64, u64)> {
+        let mut counts = self
+            .counts
+            .borrow()
+            .iter()
+            .map(|(number, count)| (*number, *count))
+            .collect::<Vec<_>>();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        counts
+    }
+}
+
+impl<R: Register, M: Memory> Syscalls<R, M> for SyscallCounter {
+    fn initialize(&mut self, _machine: &mut CoreMachine<R, M>) -> Result<(), VMError> {
+        Ok(())
+    }
+
+    fn ecall(&mut self, machine: &mut CoreMachine<R, M>) -> Result<bool, VMError> {
+        let number = machine.registers()[A7].to_u64();
+        *self.counts.borrow_mut().entry(number).or_insert(0) += 1;
+        Ok(false)
+    }
+}
@@ -1,10 +1,12 @@
 mod builder;
+mod counter;
 mod debugger;
 mod fetch_script_hash;
 mod mmap_cell;
 mod mmap_tx;
 
 pub use self::builder::build_tx;
+pub use self::counter::SyscallCounter;
 pub use self::debugger::Debugger;
 pub use self::fetch_script_hash::FetchScriptHash;
 pub use self::mmap_cell::MmapCell;
@@ -82,6 +84,25 @@ mod tests {
     use proptest::collection::size_range;
     use proptest::prelude::any_with;
 
+    #[test]
+    fn test_syscall_counter() {
+        let mut machine = DefaultCoreMachine::<u64, SparseMemory>::default();
+        let mut counter = SyscallCounter::default();
+        for number in &[
+            DEBUG_PRINT_SYSCALL_NUMBER,
+            MMAP_TX_SYSCALL_NUMBER,
+            DEBUG_PRINT_SYSCALL_NUMBER,
+        ] {
+            machine.registers_mut()[A7] = *number;
+            // the syscall is left to the next module
+            assert_eq!(counter.ecall(&mut machine), Ok(false));
+        }
+        assert_eq!(
+            counter.counts(),
+            vec![(DEBUG_PRINT_SYSCALL_NUMBER, 2), (MMAP_TX_SYSCALL_NUMBER, 1)]
+        );
+    }
+
     fn _test_mmap_tx_all(tx: &Vec<u8>) {
         let mut machine = DefaultCoreMachine::<u64, SparseMemory>::default();
         let size_addr = 0;
@@ -1,12 +1,59 @@
-use super::ScriptError;
+use super::{ScriptError, ScriptFailure, ScriptLocation, ScriptProfile, TransactionProfile};
 use bigint::H256;
 use ckb_core::cell::ResolvedTransaction;
 use ckb_core::script::Script;
 use ckb_core::transaction::{CellInput, CellOutput};
-use ckb_vm::{DefaultMachine, SparseMemory};
+use ckb_core::Cycle;
+use ckb_vm::{DefaultMachine, Error as VMInternalError, SparseMemory};
+use cost_model::instruction_cycles;
+use faster_hex::hex_to;
 use flatbuffers::FlatBufferBuilder;
 use fnv::FnvHashMap;
-use syscalls::{build_tx, Debugger, FetchScriptHash, MmapCell, MmapTx};
+use hash::sha3_256;
+use std::io::Write;
+use syscalls::{build_tx, Debugger, FetchScriptHash, MmapCell, MmapTx, SyscallCounter};
+
+/// From this script version on, the verifier appends the chain id to the script arguments
+pub const CHAIN_ID_VERSION: u8 = 2;
+
+/// The chain id argument appended to the arguments of the scripts from `CHAIN_ID_VERSION`,
+/// hex encoded like the other arguments of the system lock script
+pub fn chain_id_arg(chain_id: &H256) -> Vec<u8> {
+    let mut hex = vec![0; 64];
+    hex_to(chain_id, &mut hex).expect("hex chain id");
+    hex
+}
+
+/// The message the system lock script checks the signature of: the arguments following the
+/// signature, then the chain id for the versions binding it. A signature for one chain is
+/// invalid on any other.
+pub fn signing_message(version: u8, args: &[Vec<u8>], chain_id: &H256) -> H256 {
+    let mut bytes = vec![];
+    for argument in args {
+        bytes.write_all(argument).expect("write to vec");
+    }
+    if version >= CHAIN_ID_VERSION {
+        bytes
+            .write_all(&chain_id_arg(chain_id))
+            .expect("write to vec");
+    }
+    sha3_256(sha3_256(&bytes)).into()
+}
+
+/// The arguments a script runs with, in this order: the program name, the `signed_args` fixed
+/// by the lock hash of the cell, the `args` supplied at spend time, then the chain id for the
+/// versions from `CHAIN_ID_VERSION`. Only the `signed_args` are covered by `Script::type_hash`,
+/// a script must not accept its fixed arguments from the `args`.
+pub fn script_args(script: &Script, chain_id: &H256) -> Vec<Vec<u8>> {
+    let mut args = Vec::with_capacity(script.signed_args.len() + script.args.len() + 2);
+    args.push(b"verify".to_vec());
+    args.extend_from_slice(&script.signed_args);
+    args.extend_from_slice(&script.args);
+    if script.version >= CHAIN_ID_VERSION {
+        args.push(chain_id_arg(chain_id));
+    }
+    args
+}
 
 // This struct leverages CKB VM to verify transaction inputs.
 // FlatBufferBuilder owned Vec<u8> that grows as needed, in the
@@ -18,10 +65,12 @@ pub struct TransactionScriptsVerifier<'a> {
     tx_builder: FlatBufferBuilder<'a>,
     input_cells: Vec<&'a CellOutput>,
     hash: H256,
+    chain_id: H256,
 }
 
 impl<'a> TransactionScriptsVerifier<'a> {
-    pub fn new(rtx: &'a ResolvedTransaction) -> TransactionScriptsVerifier<'a> {
+    /// `chain_id` is appended to the arguments of the scripts from `CHAIN_ID_VERSION`
+    pub fn new(rtx: &'a ResolvedTransaction, chain_id: H256) -> TransactionScriptsVerifier<'a> {
         let dep_cells: FnvHashMap<H256, &'a CellOutput> = rtx
             .dep_cells
             .iter()
@@ -55,6 +104,7 @@ impl<'a> TransactionScriptsVerifier<'a> {
             outputs,
             input_cells,
             hash: rtx.transaction.hash(),
+            chain_id,
         }
     }
 
@@ -85,48 +135,146 @@ impl<'a> TransactionScriptsVerifier<'a> {
         Err(ScriptError::NoScript)
     }
 
-    pub fn verify_script(&self, script: &Script, prefix: &str) -> Result<(), ScriptError> {
-        self.extract_script(script).and_then(|script_binary| {
-            let mut args = vec![b"verify".to_vec()];
-            args.extend_from_slice(&script.signed_args.as_slice());
-            args.extend_from_slice(&script.args.as_slice());
-
-            let mut machine = DefaultMachine::<u64, SparseMemory>::default();
-            machine.add_syscall_module(Box::new(self.build_mmap_tx()));
-            machine.add_syscall_module(Box::new(self.build_mmap_cell()));
-            machine.add_syscall_module(Box::new(self.build_fetch_script_hash()));
-            machine.add_syscall_module(Box::new(Debugger::new(prefix)));
-            machine
-                .run(script_binary, &args)
-                .map_err(ScriptError::VMError)
-                .and_then(|code| {
-                    if code == 0 {
-                        Ok(())
-                    } else {
-                        Err(ScriptError::ValidationFailure(code))
-                    }
-                })
-        })
+    /// The cycles the VM counted running the script, at most `max_cycles`
+    pub fn verify_script(
+        &self,
+        script: &Script,
+        prefix: &str,
+        max_cycles: Cycle,
+    ) -> Result<Cycle, ScriptError> {
+        self.run_script(script, prefix, max_cycles, None)
+            .map_err(|(error, _)| error)
+    }
+
+    // A failure comes with the cycles the script consumed until it stopped
+    fn run_script(
+        &self,
+        script: &Script,
+        prefix: &str,
+        max_cycles: Cycle,
+        counter: Option<SyscallCounter>,
+    ) -> Result<Cycle, (ScriptError, Cycle)> {
+        let script_binary = self.extract_script(script).map_err(|error| (error, 0))?;
+        // Whether the version is active yet is a consensus rule checked by the caller,
+        // here the version only selects the VM running the script.
+        match script.version {
+            // Version 1 only changes the script hash and version 2 also gets the chain id
+            // as the last argument, the signature of the system lock script covers it.
+            // Both still run on the first VM.
+            0 | 1 | CHAIN_ID_VERSION => {
+                let args = script_args(script, &self.chain_id);
+                self.run_vm_v0(script_binary, &args, prefix, max_cycles, counter)
+            }
+            version => Err((ScriptError::UnsupportedVersion(version), 0)),
+        }
+    }
+
+    fn run_vm_v0(
+        &self,
+        binary: &[u8],
+        args: &[Vec<u8>],
+        prefix: &str,
+        max_cycles: Cycle,
+        counter: Option<SyscallCounter>,
+    ) -> Result<Cycle, (ScriptError, Cycle)> {
+        let mut machine = DefaultMachine::<u64, SparseMemory>::new_with_cost_model(
+            Box::new(instruction_cycles),
+            max_cycles,
+        );
+        if let Some(counter) = counter {
+            machine.add_syscall_module(Box::new(counter));
+        }
+        machine.add_syscall_module(Box::new(self.build_mmap_tx()));
+        machine.add_syscall_module(Box::new(self.build_mmap_cell()));
+        machine.add_syscall_module(Box::new(self.build_fetch_script_hash()));
+        machine.add_syscall_module(Box::new(Debugger::new(prefix)));
+        match machine.run(binary, args) {
+            Ok(0) => Ok(machine.cycles()),
+            Ok(code) => Err((ScriptError::ValidationFailure(code), machine.cycles())),
+            Err(VMInternalError::InvalidCycles) => {
+                Err((ScriptError::ExceededMaximumCycles, machine.cycles()))
+            }
+            Err(err) => Err((ScriptError::VMError(err), machine.cycles())),
+        }
     }
 
-    pub fn verify(&self) -> Result<(), ScriptError> {
+    /// The cycles of all the scripts of the transaction. They may cost `max_cycles` together,
+    /// each script runs with what the previous ones left.
+    pub fn verify(&self, max_cycles: Cycle) -> Result<Cycle, ScriptFailure> {
+        let mut cycles = 0;
         for (i, input) in self.inputs.iter().enumerate() {
             let prefix = format!("Transaction {}, input {}", self.hash, i);
-            self.verify_script(&input.unlock, &prefix).map_err(|e| {
+            let max_cycles = max_cycles - cycles;
+            let result = self.run_script(&input.unlock, &prefix, max_cycles, None);
+            cycles += result.map_err(|(e, consumed)| {
                 info!(target: "script", "Error validating input {} of transaction {}: {:?}", i, self.hash, e);
-                e
+                ScriptFailure {
+                    location: ScriptLocation::Input(i),
+                    script_hash: input.unlock.type_hash(),
+                    error: e,
+                    cycles: consumed,
+                }
             })?;
         }
         for (i, output) in self.outputs.iter().enumerate() {
             if let Some(ref contract) = output.contract {
                 let prefix = format!("Transaction {}, output {}", self.hash, i);
-                self.verify_script(contract, &prefix).map_err(|e| {
+                let max_cycles = max_cycles - cycles;
+                let result = self.run_script(contract, &prefix, max_cycles, None);
+                cycles += result.map_err(|(e, consumed)| {
                     info!(target: "script", "Error validating output {} of transaction {}: {:?}", i, self.hash, e);
-                    e
+                    ScriptFailure {
+                        location: ScriptLocation::Output(i),
+                        script_hash: contract.type_hash(),
+                        error: e,
+                        cycles: consumed,
+                    }
                 })?;
             }
         }
-        Ok(())
+        Ok(cycles)
+    }
+
+    /// Runs all the scripts, also the ones following a failure, and records the cycles and the
+    /// syscalls of each run. `profile(max_cycles).result()` is what `verify(max_cycles)`
+    /// returns.
+    pub fn profile(&self, max_cycles: Cycle) -> TransactionProfile {
+        let mut cycles = 0;
+        let unlocks = self
+            .inputs
+            .iter()
+            .enumerate()
+            .map(|(i, input)| (ScriptLocation::Input(i), &input.unlock));
+        let contracts = self.outputs.iter().enumerate().filter_map(|(i, output)| {
+            output
+                .contract
+                .as_ref()
+                .map(|contract| (ScriptLocation::Output(i), contract))
+        });
+        let scripts = unlocks
+            .chain(contracts)
+            .map(|(location, script)| {
+                let prefix = format!("Transaction {}, {:?}", self.hash, location);
+                let counter = SyscallCounter::default();
+                let result =
+                    self.run_script(script, &prefix, max_cycles - cycles, Some(counter.clone()));
+                let (script_cycles, error) = match result {
+                    Ok(script_cycles) => (script_cycles, None),
+                    Err((error, consumed)) => (consumed, Some(error)),
+                };
+                cycles += script_cycles;
+                ScriptProfile {
+                    location,
+                    script_hash: script.type_hash(),
+                    cycles: script_cycles,
+                    syscalls: counter.counts(),
+                    error,
+                }
+            }).collect();
+        TransactionProfile {
+            hash: self.hash.clone(),
+            scripts,
+        }
     }
 }
 
@@ -146,6 +294,8 @@ mod tests {
     use std::io::{Read, Write};
     use std::path::Path;
 
+    const MAX_CYCLES: Cycle = 1_000_000_000;
+
     fn open_cell_verify() -> File {
         File::open(
             Path::new(env!("CARGO_MANIFEST_DIR")).join("../nodes_template/spec/cells/verify"),
@@ -196,9 +346,9 @@ mod tests {
             input_cells: vec![],
         };
 
-        let verifier = TransactionScriptsVerifier::new(&rtx);
+        let verifier = TransactionScriptsVerifier::new(&rtx, H256::zero());
 
-        assert!(verifier.verify().is_ok());
+        assert!(verifier.verify(MAX_CYCLES).is_ok());
     }
 
     #[test]
@@ -241,9 +391,60 @@ mod tests {
             input_cells: vec![],
         };
 
-        let verifier = TransactionScriptsVerifier::new(&rtx);
+        let verifier = TransactionScriptsVerifier::new(&rtx, H256::zero());
 
-        assert!(verifier.verify().is_err());
+        let failure = verifier.verify(MAX_CYCLES).unwrap_err();
+        assert_eq!(failure.location, ScriptLocation::Input(0));
+        assert_eq!(
+            failure.script_hash,
+            rtx.transaction.inputs()[0].unlock.type_hash()
+        );
+    }
+
+    #[test]
+    fn check_chain_id_signature() {
+        let mut file = open_cell_verify();
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer).unwrap();
+
+        let gen = Generator::new();
+        let privkey = gen.random_privkey();
+        let chain_id = H256::from(1);
+        let mut args = vec![b"foo".to_vec(), b"bar".to_vec()];
+        let message = signing_message(CHAIN_ID_VERSION, &args, &chain_id);
+        let signature = privkey.sign_recoverable(&message).unwrap();
+
+        let signature_der = signature.serialize_der();
+        let mut hex_signature = vec![0; signature_der.len() * 2];
+        hex_to(&signature_der, &mut hex_signature).expect("hex privkey");
+        args.insert(0, hex_signature);
+
+        let privkey = privkey.pubkey().unwrap().serialize();
+        let mut hex_privkey = vec![0; privkey.len() * 2];
+        hex_to(&privkey, &mut hex_privkey).expect("hex privkey");
+
+        let script = Script::new(
+            CHAIN_ID_VERSION,
+            args,
+            None,
+            Some(buffer),
+            vec![hex_privkey],
+        );
+        let input = CellInput::new(OutPoint::null(), script);
+        let transaction = TransactionBuilder::default().input(input.clone()).build();
+        let rtx = ResolvedTransaction {
+            transaction,
+            dep_cells: vec![],
+            input_cells: vec![],
+        };
+
+        let verifier = TransactionScriptsVerifier::new(&rtx, chain_id);
+        assert!(verifier.verify(MAX_CYCLES).is_ok());
+        // the same transaction replayed on another chain
+        let failure = TransactionScriptsVerifier::new(&rtx, H256::from(2))
+            .verify(MAX_CYCLES)
+            .unwrap_err();
+        assert_eq!(failure.location, ScriptLocation::Input(0));
     }
 
     #[test]
@@ -291,9 +492,9 @@ mod tests {
             input_cells: vec![],
         };
 
-        let verifier = TransactionScriptsVerifier::new(&rtx);
+        let verifier = TransactionScriptsVerifier::new(&rtx, H256::zero());
 
-        assert!(verifier.verify().is_ok());
+        assert!(verifier.verify(MAX_CYCLES).is_ok());
     }
 
     #[test]
@@ -338,9 +539,9 @@ mod tests {
             input_cells: vec![],
         };
 
-        let verifier = TransactionScriptsVerifier::new(&rtx);
+        let verifier = TransactionScriptsVerifier::new(&rtx, H256::zero());
 
-        assert!(verifier.verify().is_err());
+        assert!(verifier.verify(MAX_CYCLES).is_err());
     }
 
     fn create_always_success_script() -> Script {
@@ -393,9 +594,76 @@ mod tests {
             input_cells: vec![],
         };
 
-        let verifier = TransactionScriptsVerifier::new(&rtx);
+        let verifier = TransactionScriptsVerifier::new(&rtx, H256::zero());
+
+        // both the unlock and the contract are charged
+        let unlock_cycles = verifier
+            .verify_script(&input.unlock, "", MAX_CYCLES)
+            .unwrap();
+        let contract_cycles = verifier
+            .verify_script(output.contract.as_ref().unwrap(), "", MAX_CYCLES)
+            .unwrap();
+        assert!(unlock_cycles > 0);
+        assert!(contract_cycles > 0);
+        let cycles = unlock_cycles + contract_cycles;
+        assert_eq!(verifier.verify(MAX_CYCLES), Ok(cycles));
+
+        // the scripts share the budget of the transaction, the contract is stopped when it
+        // runs out of the cycles the unlock left
+        let failure = verifier.verify(cycles - 1).unwrap_err();
+        assert_eq!(failure.location, ScriptLocation::Output(0));
+        assert_eq!(failure.error, ScriptError::ExceededMaximumCycles);
+        assert_eq!(failure.cycles, contract_cycles - 1);
+    }
+
+    #[test]
+    fn check_profile() {
+        // the unlock has no signature and fails, the contract still runs
+        let mut file = open_cell_verify();
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer).unwrap();
+        let unlock = Script::new(0, vec![b"foo".to_vec()], None, Some(buffer), vec![]);
+        let input = CellInput::new(OutPoint::null(), unlock);
+        let contract = create_always_success_script();
+        let output = CellOutput::new(0, Vec::new(), H256::from(0), Some(contract.clone()));
 
-        assert!(verifier.verify().is_ok());
+        let transaction = TransactionBuilder::default()
+            .input(input.clone())
+            .output(output)
+            .build();
+        let rtx = ResolvedTransaction {
+            transaction,
+            dep_cells: vec![],
+            input_cells: vec![],
+        };
+
+        let verifier = TransactionScriptsVerifier::new(&rtx, H256::zero());
+        let profile = verifier.profile(MAX_CYCLES);
+        assert_eq!(profile.hash, rtx.transaction.hash());
+        assert_eq!(profile.result(), verifier.verify(MAX_CYCLES));
+
+        let locations = profile
+            .scripts
+            .iter()
+            .map(|script| script.location)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            locations,
+            vec![ScriptLocation::Input(0), ScriptLocation::Output(0)]
+        );
+        assert!(profile.scripts[0].error.is_some());
+        // the cycles the unlock ran until it failed
+        assert!(profile.scripts[0].cycles > 0);
+        assert_eq!(
+            profile.result().unwrap_err().cycles,
+            profile.scripts[0].cycles
+        );
+        assert_eq!(profile.scripts[1].error, None);
+        assert_eq!(profile.scripts[1].script_hash, contract.type_hash());
+        assert_eq!(
+            profile.scripts[1].cycles,
+            verifier.verify_script(&contract, "", MAX_CYCLES).unwrap()
+        );
     }
 
     #[test]
@@ -442,8 +710,112 @@ mod tests {
             input_cells: vec![],
         };
 
-        let verifier = TransactionScriptsVerifier::new(&rtx);
+        let verifier = TransactionScriptsVerifier::new(&rtx, H256::zero());
 
-        assert!(verifier.verify().is_err());
+        assert!(verifier.verify(MAX_CYCLES).is_err());
+    }
+
+    #[test]
+    fn check_script_version_dispatch() {
+        let mut script = create_always_success_script();
+        let transaction = TransactionBuilder::default()
+            .input(CellInput::new(OutPoint::null(), script.clone()))
+            .build();
+        let rtx = ResolvedTransaction {
+            transaction,
+            dep_cells: vec![],
+            input_cells: vec![],
+        };
+        let verifier = TransactionScriptsVerifier::new(&rtx, H256::zero());
+
+        assert!(verifier.verify_script(&script, "", MAX_CYCLES).is_ok());
+        script.version = 1;
+        assert!(verifier.verify_script(&script, "", MAX_CYCLES).is_ok());
+        script.version = 2;
+        assert!(verifier.verify_script(&script, "", MAX_CYCLES).is_ok());
+        script.version = 3;
+        assert_eq!(
+            verifier.verify_script(&script, "", MAX_CYCLES),
+            Err(ScriptError::UnsupportedVersion(3))
+        );
+    }
+
+    #[test]
+    fn check_args_split() {
+        let mut file = open_cell_verify();
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer).unwrap();
+
+        let gen = Generator::new();
+        let privkey = gen.random_privkey();
+        let args = vec![b"foo".to_vec(), b"bar".to_vec()];
+        let signature = privkey
+            .sign_recoverable(&signing_message(0, &args, &H256::zero()))
+            .unwrap();
+        let signature_der = signature.serialize_der();
+        let mut hex_signature = vec![0; signature_der.len() * 2];
+        hex_to(&signature_der, &mut hex_signature).expect("hex signature");
+        let pubkey = privkey.pubkey().unwrap().serialize();
+        let mut hex_pubkey = vec![0; pubkey.len() * 2];
+        hex_to(&pubkey, &mut hex_pubkey).expect("hex pubkey");
+
+        let script = Script::new(
+            0,
+            vec![hex_signature.clone(), args[0].clone(), args[1].clone()],
+            None,
+            Some(buffer),
+            vec![hex_pubkey.clone()],
+        );
+        assert_eq!(
+            script_args(&script, &H256::zero()),
+            vec![
+                b"verify".to_vec(),
+                hex_pubkey.clone(),
+                hex_signature.clone(),
+                args[0].clone(),
+                args[1].clone(),
+            ]
+        );
+
+        let verify = |script: &Script| {
+            let transaction = TransactionBuilder::default()
+                .input(CellInput::new(OutPoint::null(), script.clone()))
+                .build();
+            let rtx = ResolvedTransaction {
+                transaction,
+                dep_cells: vec![],
+                input_cells: vec![],
+            };
+            let verifier = TransactionScriptsVerifier::new(&rtx, H256::zero());
+            verifier.verify_script(script, "", MAX_CYCLES)
+        };
+        assert!(verify(&script).is_ok());
+
+        // the witness arguments in another order
+        let mut reordered = script.clone();
+        reordered.args.swap(1, 2);
+        let result = verify(&reordered);
+        assert!(result.is_err());
+        assert_eq!(verify(&reordered), result);
+
+        // a witness argument left out
+        let mut omitted = script.clone();
+        omitted.args.pop();
+        assert!(verify(&omitted).is_err());
+
+        // the fixed argument left out
+        let mut unsigned = script.clone();
+        unsigned.signed_args.clear();
+        assert!(verify(&unsigned).is_err());
+
+        // the fixed argument given as a witness runs the same, but isn't the same lock
+        let mut moved = unsigned.clone();
+        moved.args.insert(0, hex_pubkey);
+        assert_eq!(
+            script_args(&moved, &H256::zero()),
+            script_args(&script, &H256::zero())
+        );
+        assert!(verify(&moved).is_ok());
+        assert_ne!(moved.type_hash(), script.type_hash());
     }
 }
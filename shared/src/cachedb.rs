@@ -1,5 +1,5 @@
 use ckb_db::batch::{Batch, Col, Operation};
-use ckb_db::kvdb::{KeyValueDB, Result};
+use ckb_db::kvdb::{KeyValueDB, KeyValueIter, Result};
 use ckb_util::RwLock;
 use fnv::FnvHashMap;
 use lru_cache::LruCache;
@@ -84,4 +84,9 @@ where
         }
         self.db.partial_read(col, key, range)
     }
+
+    // the cache is written through, the db has every key
+    fn iter<'a>(&'a self, col: Col) -> Result<KeyValueIter<'a>> {
+        self.db.iter(col)
+    }
 }
@@ -2,16 +2,28 @@ use super::flat_serializer::serialized_addresses;
 use bigint::H256;
 use bincode::{deserialize, serialize};
 use ckb_core::block::Block;
-use ckb_core::extras::{BlockExt, TransactionAddress};
+use ckb_core::extras::{BlockEconomicState, BlockExt, EpochStats, SyncStats, TransactionAddress};
 use ckb_core::header::{BlockNumber, Header};
-use ckb_core::transaction::{Transaction, TransactionBuilder};
+use ckb_core::transaction::{CellOutput, OutPoint, Transaction, TransactionBuilder};
+use ckb_core::uncle::UncleBlock;
 use ckb_db::batch::Batch;
 use ckb_db::kvdb::KeyValueDB;
 use error::SharedError;
+use fnv::FnvHashMap;
 use store::{ChainKVStore, ChainStore};
-use {COLUMN_BLOCK_BODY, COLUMN_INDEX, COLUMN_META, COLUMN_TRANSACTION_ADDR};
+use {
+    COLUMN_BLOCK_BODY, COLUMN_BLOCK_UNCLE, COLUMN_CELL_CONSUMER, COLUMN_CELL_SET,
+    COLUMN_ECONOMIC_STATE, COLUMN_EPOCH_STATS, COLUMN_INDEX, COLUMN_LOCK_INDEX, COLUMN_META,
+    COLUMN_TRANSACTION_ADDR, COLUMN_UNCLE,
+};
 
 const META_TIP_HEADER_KEY: &[u8] = b"TIP_HEADER";
+const META_SYNC_STATS_KEY: &[u8] = b"SYNC_STATS";
+const META_CELL_SET_LEN_KEY: &[u8] = b"CELL_SET_LEN";
+const META_REORG_TARGET_KEY: &[u8] = b"REORG_TARGET";
+const META_UNCLES_BY_HASH_KEY: &[u8] = b"UNCLES_BY_HASH";
+
+pub type CellSetIter<'a> = Box<dyn Iterator<Item = (OutPoint, CellOutput)> + 'a>;
 
 // maintain chain index, extend chainstore
 pub trait ChainIndex: ChainStore {
@@ -19,16 +31,65 @@ pub trait ChainIndex: ChainStore {
     fn get_block_hash(&self, number: BlockNumber) -> Option<H256>;
     fn get_block_number(&self, hash: &H256) -> Option<BlockNumber>;
     fn get_tip_header(&self) -> Option<Header>;
+    fn get_sync_stats(&self) -> Option<SyncStats>;
+    /// The block a reorg too deep for a single pass is moving the main chain to, until it's the tip
+    fn get_reorg_target(&self) -> Option<H256>;
+    /// Only epochs completed by the main chain have statistics
+    fn get_epoch_stats(&self, epoch: u64) -> Option<EpochStats>;
+    /// Kept for every block which was on the main chain once, detached or not
+    fn get_block_economic_state(&self, hash: &H256) -> Option<BlockEconomicState>;
     fn get_transaction(&self, h: &H256) -> Option<Transaction>;
     fn get_transaction_address(&self, hash: &H256) -> Option<TransactionAddress>;
+    fn get_cell_consumer(&self, out_point: &OutPoint) -> Option<H256>;
+    /// Live cells locked by the lock hash, genesis cells are not indexed
+    fn get_cells_by_lock_hash(&self, lock_hash: &H256) -> Vec<OutPoint>;
+    /// The output of a live cell of the main chain, a single lookup in the cell set
+    fn get_live_cell(&self, out_point: &OutPoint) -> Option<CellOutput>;
+    /// None when the database was created before the cell set was kept
+    fn get_cell_set_len(&self) -> Option<u64>;
+    fn cell_set_iter<'a>(&'a self) -> CellSetIter<'a>;
+    /// Builds the cell set of a database created before it, by replaying the main chain
+    fn init_cell_set(&self, tip_number: BlockNumber);
+    /// False when the database was created before the blocks only kept the hashes of their uncles
+    fn uncles_by_hash(&self) -> bool;
+    /// Moves the uncles of such a database to COLUMN_UNCLE, must run before any block is read
+    fn init_uncles_by_hash(&self);
 
     fn insert_block_hash(&self, batch: &mut Batch, number: BlockNumber, hash: &H256);
     fn delete_block_hash(&self, batch: &mut Batch, number: BlockNumber);
     fn insert_block_number(&self, batch: &mut Batch, hash: &H256, number: BlockNumber);
     fn delete_block_number(&self, batch: &mut Batch, hash: &H256);
     fn insert_tip_header(&self, batch: &mut Batch, h: &Header);
+    fn insert_sync_stats(&self, batch: &mut Batch, stats: &SyncStats);
+    fn insert_reorg_target(&self, batch: &mut Batch, hash: &H256);
+    fn delete_reorg_target(&self, batch: &mut Batch);
+    fn insert_epoch_stats(&self, batch: &mut Batch, stats: &EpochStats);
+    fn delete_epoch_stats(&self, batch: &mut Batch, epoch: u64);
+    fn insert_block_economic_state(
+        &self,
+        batch: &mut Batch,
+        hash: &H256,
+        state: &BlockEconomicState,
+    );
     fn insert_transaction_address(&self, batch: &mut Batch, block_hash: &H256, txs: &[Transaction]);
     fn delete_transaction_address(&self, batch: &mut Batch, txs: &[Transaction]);
+    fn insert_cell_consumer(&self, batch: &mut Batch, txs: &[Transaction]);
+    fn delete_cell_consumer(&self, batch: &mut Batch, txs: &[Transaction]);
+    /// Reverts the detached transactions, in reverse order, then applies the attached ones.
+    /// Both must be given in a single call since the pending batch isn't readable.
+    fn update_lock_index(
+        &self,
+        batch: &mut Batch,
+        detached: &[&Transaction],
+        attached: &[&Transaction],
+    );
+    /// Same contract as `update_lock_index`, the cell set is kept for every main chain block.
+    fn update_cell_set(
+        &self,
+        batch: &mut Batch,
+        detached: &[&Transaction],
+        attached: &[&Transaction],
+    );
 }
 
 impl<T: 'static + KeyValueDB> ChainIndex for ChainKVStore<T> {
@@ -64,6 +125,9 @@ impl<T: 'static + KeyValueDB> ChainIndex for ChainKVStore<T> {
             self.insert_block_hash(batch, 0, &genesis_hash);
             self.insert_block_number(batch, &genesis_hash, 0);
             self.insert_transaction_address(batch, &genesis_hash, genesis.commit_transactions());
+            let txs = genesis.commit_transactions().iter().collect::<Vec<_>>();
+            self.update_cell_set(batch, &[], &txs);
+            batch.insert(COLUMN_META, META_UNCLES_BY_HASH_KEY.to_vec(), Vec::new());
             Ok(())
         }).expect("genesis init");
     }
@@ -84,6 +148,27 @@ impl<T: 'static + KeyValueDB> ChainIndex for ChainKVStore<T> {
             .map(Into::into)
     }
 
+    fn get_sync_stats(&self) -> Option<SyncStats> {
+        self.get(COLUMN_META, META_SYNC_STATS_KEY)
+            .map(|raw| deserialize(&raw[..]).unwrap())
+    }
+
+    fn get_reorg_target(&self) -> Option<H256> {
+        self.get(COLUMN_META, META_REORG_TARGET_KEY)
+            .map(|raw| H256::from(&raw[..]))
+    }
+
+    fn get_epoch_stats(&self, epoch: u64) -> Option<EpochStats> {
+        let key = serialize(&epoch).unwrap();
+        self.get(COLUMN_EPOCH_STATS, &key)
+            .map(|raw| deserialize(&raw[..]).unwrap())
+    }
+
+    fn get_block_economic_state(&self, hash: &H256) -> Option<BlockEconomicState> {
+        self.get(COLUMN_ECONOMIC_STATE, &hash)
+            .map(|raw| deserialize(&raw[..]).unwrap())
+    }
+
     fn get_transaction(&self, h: &H256) -> Option<Transaction> {
         self.get_transaction_address(h)
             .and_then(|d| {
@@ -102,10 +187,55 @@ impl<T: 'static + KeyValueDB> ChainIndex for ChainKVStore<T> {
             .map(|raw| deserialize(&raw[..]).unwrap())
     }
 
+    fn get_cell_consumer(&self, out_point: &OutPoint) -> Option<H256> {
+        let key = serialize(out_point).unwrap();
+        self.get(COLUMN_CELL_CONSUMER, &key)
+            .map(|raw| H256::from(&raw[..]))
+    }
+
     fn insert_tip_header(&self, batch: &mut Batch, h: &Header) {
         batch.insert(COLUMN_META, META_TIP_HEADER_KEY.to_vec(), h.hash().to_vec());
     }
 
+    fn insert_sync_stats(&self, batch: &mut Batch, stats: &SyncStats) {
+        batch.insert(
+            COLUMN_META,
+            META_SYNC_STATS_KEY.to_vec(),
+            serialize(stats).unwrap(),
+        );
+    }
+
+    fn insert_reorg_target(&self, batch: &mut Batch, hash: &H256) {
+        batch.insert(COLUMN_META, META_REORG_TARGET_KEY.to_vec(), hash.to_vec());
+    }
+
+    fn delete_reorg_target(&self, batch: &mut Batch) {
+        batch.delete(COLUMN_META, META_REORG_TARGET_KEY.to_vec());
+    }
+
+    fn insert_epoch_stats(&self, batch: &mut Batch, stats: &EpochStats) {
+        let key = serialize(&stats.epoch).unwrap();
+        batch.insert(COLUMN_EPOCH_STATS, key, serialize(stats).unwrap());
+    }
+
+    fn delete_epoch_stats(&self, batch: &mut Batch, epoch: u64) {
+        let key = serialize(&epoch).unwrap();
+        batch.delete(COLUMN_EPOCH_STATS, key);
+    }
+
+    fn insert_block_economic_state(
+        &self,
+        batch: &mut Batch,
+        hash: &H256,
+        state: &BlockEconomicState,
+    ) {
+        batch.insert(
+            COLUMN_ECONOMIC_STATE,
+            hash.to_vec(),
+            serialize(state).unwrap(),
+        );
+    }
+
     fn insert_block_hash(&self, batch: &mut Batch, number: BlockNumber, hash: &H256) {
         let key = serialize(&number).unwrap();
         batch.insert(COLUMN_INDEX, key, hash.to_vec());
@@ -142,6 +272,264 @@ impl<T: 'static + KeyValueDB> ChainIndex for ChainKVStore<T> {
         }
     }
 
+    fn insert_cell_consumer(&self, batch: &mut Batch, txs: &[Transaction]) {
+        for tx in txs.iter().filter(|tx| !tx.is_cellbase()) {
+            let hash = tx.hash();
+            for out_point in tx.input_pts() {
+                batch.insert(
+                    COLUMN_CELL_CONSUMER,
+                    serialize(&out_point).unwrap(),
+                    hash.to_vec(),
+                );
+            }
+        }
+    }
+
+    fn delete_cell_consumer(&self, batch: &mut Batch, txs: &[Transaction]) {
+        for tx in txs.iter().filter(|tx| !tx.is_cellbase()) {
+            for out_point in tx.input_pts() {
+                batch.delete(COLUMN_CELL_CONSUMER, serialize(&out_point).unwrap());
+            }
+        }
+    }
+
+    fn get_cells_by_lock_hash(&self, lock_hash: &H256) -> Vec<OutPoint> {
+        self.get(COLUMN_LOCK_INDEX, &lock_hash)
+            .map(|raw| deserialize(&raw[..]).unwrap())
+            .unwrap_or_else(Vec::new)
+    }
+
+    fn update_lock_index(
+        &self,
+        batch: &mut Batch,
+        detached: &[&Transaction],
+        attached: &[&Transaction],
+    ) {
+        let txs: FnvHashMap<H256, &Transaction> = detached
+            .iter()
+            .chain(attached)
+            .map(|tx| (tx.hash(), *tx))
+            .collect();
+        let spent_lock_hash = |out_point: &OutPoint| {
+            let index = out_point.index as usize;
+            match txs.get(&out_point.hash) {
+                Some(tx) => tx.outputs().get(index).map(|output| output.lock_hash()),
+                None => self
+                    .get_transaction(&out_point.hash)
+                    .and_then(|tx| tx.outputs().get(index).map(|output| output.lock_hash())),
+            }
+        };
+        let mut cells: FnvHashMap<H256, Vec<OutPoint>> = FnvHashMap::default();
+
+        for tx in detached.iter().rev() {
+            let hash = tx.hash();
+            for (index, output) in tx.outputs().iter().enumerate() {
+                let out_point = OutPoint::new(hash, index as u32);
+                cells
+                    .entry(output.lock_hash())
+                    .or_insert_with(|| self.get_cells_by_lock_hash(&output.lock_hash()))
+                    .retain(|cell| cell != &out_point);
+            }
+            if !tx.is_cellbase() {
+                for out_point in tx.input_pts() {
+                    if let Some(lock_hash) = spent_lock_hash(&out_point) {
+                        cells
+                            .entry(lock_hash)
+                            .or_insert_with(|| self.get_cells_by_lock_hash(&lock_hash))
+                            .push(out_point);
+                    }
+                }
+            }
+        }
+
+        for tx in attached {
+            if !tx.is_cellbase() {
+                for out_point in tx.input_pts() {
+                    if let Some(lock_hash) = spent_lock_hash(&out_point) {
+                        cells
+                            .entry(lock_hash)
+                            .or_insert_with(|| self.get_cells_by_lock_hash(&lock_hash))
+                            .retain(|cell| cell != &out_point);
+                    }
+                }
+            }
+            let hash = tx.hash();
+            for (index, output) in tx.outputs().iter().enumerate() {
+                cells
+                    .entry(output.lock_hash())
+                    .or_insert_with(|| self.get_cells_by_lock_hash(&output.lock_hash()))
+                    .push(OutPoint::new(hash, index as u32));
+            }
+        }
+
+        for (lock_hash, out_points) in cells {
+            if out_points.is_empty() {
+                batch.delete(COLUMN_LOCK_INDEX, lock_hash.to_vec());
+            } else {
+                batch.insert(
+                    COLUMN_LOCK_INDEX,
+                    lock_hash.to_vec(),
+                    serialize(&out_points).unwrap(),
+                );
+            }
+        }
+    }
+
+    fn get_live_cell(&self, out_point: &OutPoint) -> Option<CellOutput> {
+        let key = serialize(out_point).unwrap();
+        self.get(COLUMN_CELL_SET, &key)
+            .map(|raw| deserialize(&raw[..]).unwrap())
+    }
+
+    fn get_cell_set_len(&self) -> Option<u64> {
+        self.get(COLUMN_META, META_CELL_SET_LEN_KEY)
+            .map(|raw| deserialize(&raw[..]).unwrap())
+    }
+
+    fn cell_set_iter<'a>(&'a self) -> CellSetIter<'a> {
+        Box::new(self.iter(COLUMN_CELL_SET).map(|(key, value)| {
+            (
+                deserialize(&key[..]).unwrap(),
+                deserialize(&value[..]).unwrap(),
+            )
+        }))
+    }
+
+    fn init_cell_set(&self, tip_number: BlockNumber) {
+        let mut cells = FnvHashMap::default();
+        for number in 0..=tip_number {
+            let block = self
+                .get_block_hash(number)
+                .and_then(|hash| self.get_block(&hash))
+                .expect("main chain block stored");
+            for tx in block.commit_transactions() {
+                if !tx.is_cellbase() {
+                    for out_point in tx.input_pts() {
+                        cells.remove(&out_point);
+                    }
+                }
+                let hash = tx.hash();
+                for (index, output) in tx.outputs().iter().enumerate() {
+                    cells.insert(OutPoint::new(hash, index as u32), output.clone());
+                }
+            }
+        }
+
+        // a single batch, an interrupted replay starts over on the next launch
+        self.save_with_batch(|batch| {
+            for (out_point, output) in &cells {
+                batch.insert(
+                    COLUMN_CELL_SET,
+                    serialize(out_point).unwrap(),
+                    serialize(output).unwrap(),
+                );
+            }
+            batch.insert(
+                COLUMN_META,
+                META_CELL_SET_LEN_KEY.to_vec(),
+                serialize(&(cells.len() as u64)).unwrap(),
+            );
+            Ok(())
+        }).expect("cell set init");
+    }
+
+    fn uncles_by_hash(&self) -> bool {
+        self.get(COLUMN_META, META_UNCLES_BY_HASH_KEY).is_some()
+    }
+
+    fn init_uncles_by_hash(&self) {
+        // a single batch as well, the blocks keep their uncles until it's written
+        self.save_with_batch(|batch| {
+            for (hash, raw) in self.iter(COLUMN_BLOCK_UNCLE) {
+                let uncles: Vec<UncleBlock> = deserialize(&raw[..]).unwrap();
+                let uncle_hashes = uncles
+                    .iter()
+                    .map(|uncle| uncle.header().hash())
+                    .collect::<Vec<H256>>();
+                for (uncle_hash, uncle) in uncle_hashes.iter().zip(&uncles) {
+                    batch.insert(COLUMN_UNCLE, uncle_hash.to_vec(), serialize(uncle).unwrap());
+                }
+                batch.insert(COLUMN_BLOCK_UNCLE, hash, serialize(&uncle_hashes).unwrap());
+            }
+            batch.insert(COLUMN_META, META_UNCLES_BY_HASH_KEY.to_vec(), Vec::new());
+            Ok(())
+        }).expect("uncles init");
+    }
+
+    fn update_cell_set(
+        &self,
+        batch: &mut Batch,
+        detached: &[&Transaction],
+        attached: &[&Transaction],
+    ) {
+        let txs: FnvHashMap<H256, &Transaction> = detached
+            .iter()
+            .chain(attached)
+            .map(|tx| (tx.hash(), *tx))
+            .collect();
+        let spent_output = |out_point: &OutPoint| {
+            let index = out_point.index as usize;
+            match txs.get(&out_point.hash) {
+                Some(tx) => tx.outputs().get(index).cloned(),
+                None => self
+                    .get_transaction(&out_point.hash)
+                    .and_then(|tx| tx.outputs().get(index).cloned()),
+            }
+        };
+        // the final state of every touched cell, None when it's spent or its transaction detached
+        let mut cells: FnvHashMap<OutPoint, Option<CellOutput>> = FnvHashMap::default();
+
+        for tx in detached.iter().rev() {
+            let hash = tx.hash();
+            for index in 0..tx.outputs().len() {
+                cells.insert(OutPoint::new(hash, index as u32), None);
+            }
+            if !tx.is_cellbase() {
+                for out_point in tx.input_pts() {
+                    let output = spent_output(&out_point);
+                    cells.insert(out_point, output);
+                }
+            }
+        }
+
+        for tx in attached {
+            if !tx.is_cellbase() {
+                for out_point in tx.input_pts() {
+                    cells.insert(out_point, None);
+                }
+            }
+            let hash = tx.hash();
+            for (index, output) in tx.outputs().iter().enumerate() {
+                cells.insert(OutPoint::new(hash, index as u32), Some(output.clone()));
+            }
+        }
+
+        let mut len = self.get_cell_set_len().unwrap_or(0);
+        for (out_point, output) in cells {
+            let key = serialize(&out_point).unwrap();
+            let live = self.get(COLUMN_CELL_SET, &key).is_some();
+            match output {
+                Some(output) => {
+                    if !live {
+                        len += 1;
+                    }
+                    batch.insert(COLUMN_CELL_SET, key, serialize(&output).unwrap());
+                }
+                None => {
+                    if live {
+                        len -= 1;
+                        batch.delete(COLUMN_CELL_SET, key);
+                    }
+                }
+            }
+        }
+        batch.insert(
+            COLUMN_META,
+            META_CELL_SET_LEN_KEY.to_vec(),
+            serialize(&len).unwrap(),
+        );
+    }
+
     fn delete_block_hash(&self, batch: &mut Batch, number: BlockNumber) {
         let key = serialize(&number).unwrap();
         batch.delete(COLUMN_INDEX, key);
@@ -157,6 +545,8 @@ mod tests {
     use super::super::COLUMNS;
     use super::*;
     use ckb_chain_spec::consensus::Consensus;
+    use ckb_core::block::BlockBuilder;
+    use ckb_core::header::HeaderBuilder;
     use ckb_db::diskdb::RocksDB;
     use tempfile;
 
@@ -186,4 +576,86 @@ mod tests {
 
         assert_eq!(*block.header(), store.get_tip_header().unwrap());
     }
+
+    #[test]
+    fn init_uncles_by_hash() {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("init_uncles_by_hash")
+            .tempdir()
+            .unwrap();
+        let store = ChainKVStore::new(RocksDB::open(tmp_dir, COLUMNS));
+        let genesis = Consensus::default().genesis_block().clone();
+        store.init(&genesis);
+        assert!(store.uncles_by_hash());
+
+        let uncle: UncleBlock = genesis.clone().into();
+        let uncle_hash = uncle.header().hash();
+        let blocks: Vec<Block> = (1..3)
+            .map(|nonce| {
+                BlockBuilder::default()
+                    .header(HeaderBuilder::default().nonce(nonce).build())
+                    .uncle(uncle.clone())
+                    .build()
+            }).collect();
+        // the blocks keep their uncles, as in a database created before COLUMN_UNCLE
+        store
+            .save_with_batch(|batch| {
+                for block in &blocks {
+                    store.insert_block(batch, block);
+                    batch.insert(
+                        COLUMN_BLOCK_UNCLE,
+                        block.header().hash().to_vec(),
+                        serialize(block.uncles()).unwrap(),
+                    );
+                }
+                batch.delete(COLUMN_UNCLE, uncle_hash.to_vec());
+                batch.delete(COLUMN_META, META_UNCLES_BY_HASH_KEY.to_vec());
+                Ok(())
+            }).unwrap();
+        assert!(!store.uncles_by_hash());
+
+        store.init_uncles_by_hash();
+        assert!(store.uncles_by_hash());
+        assert_eq!(store.get_uncle(&uncle_hash), Some(uncle));
+        for block in &blocks {
+            assert_eq!(store.get_block(&block.header().hash()), Some(block.clone()));
+        }
+    }
+
+    #[test]
+    fn init_cell_set() {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("init_cell_set")
+            .tempdir()
+            .unwrap();
+        let store = ChainKVStore::new(RocksDB::open(tmp_dir, COLUMNS));
+        let tx = TransactionBuilder::default()
+            .outputs(vec![CellOutput::new(100, Vec::new(), H256::zero(), None); 2])
+            .build();
+        let genesis = BlockBuilder::default()
+            .commit_transaction(tx.clone())
+            .build();
+        store.init(&genesis);
+        assert_eq!(store.get_cell_set_len(), Some(2));
+        assert_eq!(
+            store.get_live_cell(&OutPoint::new(tx.hash(), 1)),
+            Some(tx.outputs()[1].clone())
+        );
+
+        // drop the cell set, as in a database created before it
+        store
+            .save_with_batch(|batch| {
+                for (out_point, _) in store.cell_set_iter() {
+                    batch.delete(COLUMN_CELL_SET, serialize(&out_point).unwrap());
+                }
+                batch.delete(COLUMN_META, META_CELL_SET_LEN_KEY.to_vec());
+                Ok(())
+            }).unwrap();
+        assert_eq!(store.get_cell_set_len(), None);
+        assert_eq!(store.cell_set_iter().count(), 0);
+
+        store.init_cell_set(0);
+        assert_eq!(store.get_cell_set_len(), Some(2));
+        assert_eq!(store.cell_set_iter().count(), 2);
+    }
 }
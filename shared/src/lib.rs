@@ -36,7 +36,7 @@ pub mod store;
 use ckb_db::batch::Col;
 
 // REMEMBER to update the const defined in util/avl/src/lib.rs as well
-pub const COLUMNS: u32 = 12;
+pub const COLUMNS: u32 = 18;
 pub const COLUMN_INDEX: Col = Some(0);
 pub const COLUMN_BLOCK_HEADER: Col = Some(1);
 pub const COLUMN_BLOCK_BODY: Col = Some(2);
@@ -49,3 +49,9 @@ pub const COLUMN_OUTPUT_ROOT: Col = Some(8);
 pub const COLUMN_BLOCK_TRANSACTION_ADDRESSES: Col = Some(9);
 pub const COLUMN_BLOCK_TRANSACTION_IDS: Col = Some(10);
 pub const COLUMN_BLOCK_PROPOSAL_IDS: Col = Some(11);
+pub const COLUMN_CELL_CONSUMER: Col = Some(12);
+pub const COLUMN_UNCLE: Col = Some(13);
+pub const COLUMN_LOCK_INDEX: Col = Some(14);
+pub const COLUMN_CELL_SET: Col = Some(15);
+pub const COLUMN_EPOCH_STATS: Col = Some(16);
+pub const COLUMN_ECONOMIC_STATE: Col = Some(17);
@@ -1,25 +1,60 @@
 use super::{COLUMNS, COLUMN_BLOCK_HEADER};
 use bigint::{H256, U256};
 use cachedb::CacheDB;
-use ckb_chain_spec::consensus::Consensus;
+use ckb_chain_spec::consensus::{Consensus, TARGET_INTERVAL_WINDOW, UNCLE_REWARD_DIVISOR};
 use ckb_core::block::Block;
 use ckb_core::cell::{CellProvider, CellStatus};
-use ckb_core::extras::BlockExt;
+use ckb_core::extras::{BlockExt, SyncStats};
 use ckb_core::header::{BlockNumber, Header};
 use ckb_core::transaction::{Capacity, OutPoint, ProposalShortId, Transaction};
 use ckb_core::transaction_meta::TransactionMeta;
 use ckb_core::uncle::UncleBlock;
+use ckb_core::Cycle;
 use ckb_db::diskdb::RocksDB;
+use ckb_db::DBConfig;
 use ckb_db::kvdb::KeyValueDB;
 use ckb_db::memorydb::MemoryKeyValueDB;
-use ckb_util::RwLock;
+use ckb_util::{Mutex, RwLock};
 use error::SharedError;
-use fnv::FnvHashSet;
-use index::ChainIndex;
+use fnv::{FnvHashMap, FnvHashSet};
+use index::{CellSetIter, ChainIndex};
+use lru_cache::LruCache;
+use std::cmp;
+use std::collections::VecDeque;
 use std::path::Path;
 use std::sync::Arc;
 use store::ChainKVStore;
 
+/// Number of transactions remembered as having passed their scripts
+pub const TXS_VERIFY_CACHE_SIZE: usize = 10_000;
+
+/// The cycles of the transactions whose scripts passed, by hash, shared by the pool and the
+/// block verification so a relayed transaction isn't executed again when its block arrives
+pub type TxsVerifyCache = LruCache<H256, Cycle>;
+
+/// The stale blocks within the uncle age of the tip, by hash
+pub type UncleCandidates = FnvHashMap<H256, Arc<Block>>;
+
+// The tip last asked for its uncle exclusion set and the parent hashes and the uncle hashes of
+// it and its ancestors within the uncle age, newest first
+#[derive(Default)]
+struct UncleExclusion {
+    tip: H256,
+    window: VecDeque<(H256, Vec<H256>)>,
+}
+
+impl UncleExclusion {
+    fn set(&self) -> FnvHashSet<H256> {
+        let mut set = FnvHashSet::default();
+        set.insert(self.tip);
+        for &(parent_hash, ref uncles) in &self.window {
+            set.insert(parent_hash);
+            set.extend(uncles.iter().cloned());
+        }
+        set
+    }
+}
+
 #[derive(Default, Debug, PartialEq, Clone, Eq)]
 pub struct TipHeader {
     inner: Header,
@@ -64,7 +99,13 @@ impl TipHeader {
 pub struct Shared<CI> {
     store: Arc<CI>,
     tip_header: Arc<RwLock<TipHeader>>,
+    sync_stats: Arc<Mutex<SyncStats>>,
+    txs_verify_cache: Arc<Mutex<TxsVerifyCache>>,
+    uncle_exclusion: Arc<Mutex<UncleExclusion>>,
+    uncle_candidates: Arc<Mutex<UncleCandidates>>,
     consensus: Consensus,
+    // Set on the handle of the thread holding the tip lock, see `tip_lock_holder`
+    tip_lock_held: bool,
 }
 
 impl<CI: ChainIndex> ::std::clone::Clone for Shared<CI> {
@@ -72,7 +113,12 @@ impl<CI: ChainIndex> ::std::clone::Clone for Shared<CI> {
         Shared {
             store: Arc::clone(&self.store),
             tip_header: Arc::clone(&self.tip_header),
+            sync_stats: Arc::clone(&self.sync_stats),
+            txs_verify_cache: Arc::clone(&self.txs_verify_cache),
+            uncle_exclusion: Arc::clone(&self.uncle_exclusion),
+            uncle_candidates: Arc::clone(&self.uncle_candidates),
             consensus: self.consensus.clone(),
+            tip_lock_held: self.tip_lock_held,
         }
     }
 }
@@ -84,7 +130,17 @@ impl<CI: ChainIndex> Shared<CI> {
             let header = {
                 let genesis = consensus.genesis_block();
                 match store.get_tip_header() {
-                    Some(h) => h,
+                    Some(h) => {
+                        // the database was created before the uncles were stored by hash
+                        if !store.uncles_by_hash() {
+                            store.init_uncles_by_hash();
+                        }
+                        // the database was created before the cell set was kept
+                        if store.get_cell_set_len().is_none() {
+                            store.init_cell_set(h.number());
+                        }
+                        h
+                    }
                     None => {
                         store.init(&genesis);
                         genesis.header().clone()
@@ -109,10 +165,17 @@ impl<CI: ChainIndex> Shared<CI> {
             )))
         };
 
+        let sync_stats = Arc::new(Mutex::new(store.get_sync_stats().unwrap_or_default()));
+
         Shared {
             store: Arc::new(store),
             tip_header,
+            sync_stats,
+            txs_verify_cache: Arc::new(Mutex::new(LruCache::new(TXS_VERIFY_CACHE_SIZE, false))),
+            uncle_exclusion: Arc::new(Mutex::new(UncleExclusion::default())),
+            uncle_candidates: Arc::new(Mutex::new(FnvHashMap::default())),
             consensus,
+            tip_lock_held: false,
         }
     }
 
@@ -120,35 +183,69 @@ impl<CI: ChainIndex> Shared<CI> {
         &self.tip_header
     }
 
+    /// A handle for the thread holding the tip write lock, whose cell lookups read the tip
+    /// from the store instead of waiting for the lock. Only the chain service moves the tip,
+    /// it writes the stored tip along with the cell set, so it verifies the blocks of a batch
+    /// through it without releasing the lock.
+    pub fn tip_lock_holder(&self) -> Self {
+        Shared {
+            tip_lock_held: true,
+            ..self.clone()
+        }
+    }
+
     pub fn store(&self) -> &Arc<CI> {
         &self.store
     }
+
+    /// In-memory copy of the persisted sync statistics, written back by the chain service
+    /// on every processed block
+    pub fn sync_stats(&self) -> &Mutex<SyncStats> {
+        &self.sync_stats
+    }
+
+    /// Kept by the chain service as blocks are processed, the block assembler picks the uncles
+    /// of its templates from them
+    pub fn uncle_candidates(&self) -> &Mutex<UncleCandidates> {
+        &self.uncle_candidates
+    }
+
+    // The cell set follows the tip, the caller holds the tip lock so the chain service can't
+    // move both meanwhile
+    fn tip_cell(&self, out_point: &OutPoint) -> CellStatus {
+        match self.store.get_live_cell(out_point) {
+            Some(output) => CellStatus::Current(output),
+            None => match self.store.get_transaction(&out_point.hash) {
+                Some(ref tx) if (out_point.index as usize) < tx.outputs().len() => {
+                    CellStatus::Old
+                }
+                _ => CellStatus::Unknown,
+            },
+        }
+    }
 }
 
 impl<CI: ChainIndex> CellProvider for Shared<CI> {
     fn cell(&self, out_point: &OutPoint) -> CellStatus {
-        let index = out_point.index as usize;
-        let tip_header = self.tip_header().read();
-        if let Some(meta) = self.get_transaction_meta(&tip_header.output_root, &out_point.hash) {
-            if index < meta.len() {
-                if !meta.is_spent(index) {
-                    let mut transaction = self
-                        .store
-                        .get_transaction(&out_point.hash)
-                        .expect("transaction must exist");
-                    CellStatus::Current(transaction.outputs()[index].clone())
-                } else {
-                    CellStatus::Old
-                }
-            } else {
-                CellStatus::Unknown
-            }
-        } else {
-            CellStatus::Unknown
+        if self.tip_lock_held {
+            return self.tip_cell(out_point);
         }
+        let _tip_header = self.tip_header().read();
+        self.tip_cell(out_point)
     }
 
     fn cell_at(&self, out_point: &OutPoint, parent: &H256) -> CellStatus {
+        if self.tip_lock_held {
+            if self.store.get_tip_header().map(|header| header.hash()) == Some(*parent) {
+                return self.tip_cell(out_point);
+            }
+        } else {
+            let tip_header = self.tip_header().read();
+            if tip_header.hash() == *parent {
+                return self.tip_cell(out_point);
+            }
+        }
+        // a fork, the transaction metas of the parent tell the spent cells
         let index = out_point.index as usize;
         if let Some(meta) = self.get_transaction_meta_at(&out_point.hash, parent) {
             if index < meta.len() {
@@ -168,6 +265,33 @@ impl<CI: ChainIndex> CellProvider for Shared<CI> {
             CellStatus::Unknown
         }
     }
+
+    fn cellbase_number(&self, out_point: &OutPoint) -> Option<BlockNumber> {
+        self.store
+            .get_transaction(&out_point.hash)
+            .filter(Transaction::is_cellbase)
+            .and_then(|_| self.store.get_transaction_address(&out_point.hash))
+            .and_then(|address| self.store.get_block_number(&address.block_hash))
+    }
+
+    // The fork blocks are walked back to the main chain, a main chain cellbase only counts up
+    // to the fork point
+    fn cellbase_number_at(&self, out_point: &OutPoint, parent: &H256) -> Option<BlockNumber> {
+        let mut hash = *parent;
+        loop {
+            let header = self.store.get_header(&hash)?;
+            if self.store.get_block_hash(header.number()) == Some(hash) {
+                return self
+                    .cellbase_number(out_point)
+                    .filter(|number| *number <= header.number());
+            }
+            let body = self.store.get_block_body(&hash)?;
+            if body.first().map(|cellbase| cellbase.hash()) == Some(out_point.hash) {
+                return Some(header.number());
+            }
+            hash = header.parent_hash();
+        }
+    }
 }
 
 pub trait ChainProvider: Sync + Send {
@@ -203,17 +327,62 @@ pub trait ChainProvider: Sync + Send {
 
     fn block_reward(&self, block_number: BlockNumber) -> Capacity;
 
+    /// Added to the reward of the block at `block_number` for including `uncles_count` uncles,
+    /// nothing before the epoch the `uncles_reward` hard fork activates at
+    fn uncles_reward(&self, block_number: BlockNumber, uncles_count: usize) -> Capacity;
+
     fn get_ancestor(&self, base: &H256, number: BlockNumber) -> Option<Header>;
 
+    /// Block locator of the chain ending at the stored header `tip`, for headers-first sync:
+    /// the hashes of the last 10 blocks, then with doubling gaps, ending at genesis
+    fn get_locator(&self, tip: &Header) -> Vec<H256>;
+
+    /// Block locator of the chain ending at `tip`, whose headers missing from the store, like
+    /// the headers downloaded ahead of their blocks, are resolved by `get_header`
+    fn get_locator_with(&self, tip: &Header, get_header: &Fn(&H256) -> Option<Header>)
+        -> Vec<H256>;
+
     // Loops through all inputs and outputs of given transaction to calculate
     // fee that miner can obtain. Could result in error state when input
     // transaction is missing.
     fn calculate_transaction_fee(&self, transaction: &Transaction)
         -> Result<Capacity, SharedError>;
 
+    /// Fee of the transaction, its input transactions resolved by `get_transaction`, they may
+    /// not be on the main chain yet, like those of the blocks attached in the same batch
+    fn calculate_transaction_fee_with(
+        &self,
+        transaction: &Transaction,
+        get_transaction: &Fn(&H256) -> Option<Transaction>,
+    ) -> Result<Capacity, SharedError>;
+
     fn calculate_difficulty(&self, last: &Header) -> Option<U256>;
 
+    /// Difficulty of the child of `last` like `calculate_difficulty`, for the headers missing
+    /// from the store, like the headers downloaded ahead of their blocks: the ancestors of
+    /// `last` are resolved by `get_ancestor` and their total uncles count by
+    /// `get_total_uncles_count`
+    fn calculate_difficulty_with(
+        &self,
+        last: &Header,
+        get_ancestor: &Fn(&H256, BlockNumber) -> Option<Header>,
+        get_total_uncles_count: &Fn(&H256) -> Option<u64>,
+    ) -> Option<U256>;
+
     fn consensus(&self) -> &Consensus;
+
+    /// Number of live cells on the main chain
+    fn cell_set_len(&self) -> u64;
+
+    /// The live cells of the main chain, in no particular order
+    fn cell_set_iter<'a>(&'a self) -> CellSetIter<'a>;
+
+    /// Transactions already script-verified, cleared by the chain service on a reorg
+    fn txs_verify_cache(&self) -> &Mutex<TxsVerifyCache>;
+
+    /// The hashes an uncle of a child of `tip` can't have: `tip`, its ancestors within the uncle
+    /// age and the uncles they include
+    fn uncle_exclusion_set(&self, tip: &H256) -> FnvHashSet<H256>;
 }
 
 impl<CI: ChainIndex> ChainProvider for Shared<CI> {
@@ -279,6 +448,18 @@ impl<CI: ChainIndex> ChainProvider for Shared<CI> {
         self.consensus.initial_block_reward()
     }
 
+    fn uncles_reward(&self, block_number: BlockNumber, uncles_count: usize) -> Capacity {
+        let epoch = self.consensus.epoch_number(block_number);
+        if !self
+            .consensus
+            .hardfork_switch()
+            .is_uncles_reward_enabled(epoch)
+        {
+            return 0;
+        }
+        self.block_reward(block_number) / UNCLE_REWARD_DIVISOR * uncles_count as Capacity
+    }
+
     fn get_ancestor(&self, base: &H256, number: BlockNumber) -> Option<Header> {
         // if base in the main chain
         if let Some(n_number) = self.block_number(base) {
@@ -312,6 +493,62 @@ impl<CI: ChainIndex> ChainProvider for Shared<CI> {
         None
     }
 
+    fn get_locator(&self, tip: &Header) -> Vec<H256> {
+        self.get_locator_with(tip, &|hash| self.block_header(hash))
+    }
+
+    fn get_locator_with(
+        &self,
+        tip: &Header,
+        get_header: &Fn(&H256) -> Option<Header>,
+    ) -> Vec<H256> {
+        let mut step = 1;
+        let mut locator = Vec::with_capacity(32);
+        let mut index = tip.number();
+        // a fork is walked back by parent until it joins the main chain, from there the hashes
+        // come from the number index
+        let mut fork_header = Some(tip.clone());
+        loop {
+            fork_header = fork_header
+                .filter(|header| self.block_hash(header.number()) != Some(header.hash()));
+            let hash = match fork_header {
+                Some(ref header) => header.hash(),
+                None => match self.block_hash(index) {
+                    Some(hash) => hash,
+                    None => break,
+                },
+            };
+            locator.push(hash);
+
+            if locator.len() >= 10 {
+                step <<= 1;
+            }
+
+            if index < step {
+                // always include genesis hash
+                if index != 0 {
+                    locator.push(self.genesis_hash());
+                }
+                break;
+            }
+            index -= step;
+
+            if let Some(mut header) = fork_header.take() {
+                while header.number() > index {
+                    header = match get_header(&header.parent_hash()) {
+                        Some(parent) => parent,
+                        None => return locator,
+                    };
+                    if self.block_hash(header.number()) == Some(header.hash()) {
+                        break;
+                    }
+                }
+                fork_header = Some(header);
+            }
+        }
+        locator
+    }
+
     /// Proposals in blocks from bn-n(exclusive) to bn(inclusive)
     fn union_proposal_ids_n(&self, bn: BlockNumber, n: usize) -> Vec<Vec<ProposalShortId>> {
         let m = if bn > n as u64 { n } else { bn as usize };
@@ -347,11 +584,19 @@ impl<CI: ChainIndex> ChainProvider for Shared<CI> {
     fn calculate_transaction_fee(
         &self,
         transaction: &Transaction,
+    ) -> Result<Capacity, SharedError> {
+        self.calculate_transaction_fee_with(transaction, &|hash| self.get_transaction(hash))
+    }
+
+    fn calculate_transaction_fee_with(
+        &self,
+        transaction: &Transaction,
+        get_transaction: &Fn(&H256) -> Option<Transaction>,
     ) -> Result<Capacity, SharedError> {
         let mut fee = 0;
         for input in transaction.inputs() {
             let previous_output = &input.previous_output;
-            match self.get_transaction(&previous_output.hash) {
+            match get_transaction(&previous_output.hash) {
                 Some(previous_transaction) => {
                     let index = previous_output.index as usize;
                     if index < previous_transaction.outputs().len() {
@@ -363,11 +608,9 @@ impl<CI: ChainIndex> ChainProvider for Shared<CI> {
                 None => return Err(SharedError::InvalidInput),
             }
         }
-        let spent_capacity: Capacity = transaction
-            .outputs()
-            .iter()
-            .map(|output| output.capacity)
-            .sum();
+        let spent_capacity = transaction
+            .outputs_capacity()
+            .ok_or(SharedError::InvalidOutput)?;
         if spent_capacity > fee {
             return Err(SharedError::InvalidOutput);
         }
@@ -379,6 +622,23 @@ impl<CI: ChainIndex> ChainProvider for Shared<CI> {
     // HR_m = HR_last/ (1 + o)
     // Diff= HR_m * T_interval / H = Diff_last * o_last / o
     fn calculate_difficulty(&self, last: &Header) -> Option<U256> {
+        self.calculate_difficulty_with(
+            last,
+            &|hash, number| self.get_ancestor(hash, number),
+            &|hash| self.block_ext(hash).map(|ext| ext.total_uncles_count),
+        )
+    }
+
+    fn calculate_difficulty_with(
+        &self,
+        last: &Header,
+        get_ancestor: &Fn(&H256, BlockNumber) -> Option<Header>,
+        get_total_uncles_count: &Fn(&H256) -> Option<u64>,
+    ) -> Option<U256> {
+        if let Some(target_block_interval) = self.consensus.target_block_interval() {
+            return self.calculate_interval_difficulty(last, target_block_interval, get_ancestor);
+        }
+
         let last_hash = last.hash();
         let last_number = last.number();
         let last_difficulty = last.difficulty();
@@ -389,33 +649,26 @@ impl<CI: ChainIndex> ChainProvider for Shared<CI> {
             return Some(last_difficulty);
         }
 
-        let start = last_number.saturating_sub(interval);
-        if let Some(start_header) = self.get_ancestor(&last_hash, start) {
-            let start_total_uncles_count = self
-                .block_ext(&start_header.hash())
-                .expect("block_ext exist")
-                .total_uncles_count;
+        let epoch = self.consensus.epoch_number(last_number + 1);
+        if self
+            .consensus
+            .hardfork_switch()
+            .is_epoch_duration_difficulty_enabled(epoch)
+        {
+            return self.calculate_epoch_difficulty(last, get_ancestor);
+        }
 
-            let last_total_uncles_count = self
-                .block_ext(&last_hash)
-                .expect("block_ext exist")
-                .total_uncles_count;
+        let start = last_number.saturating_sub(interval);
+        if let Some(start_header) = get_ancestor(&last_hash, start) {
+            let start_total_uncles_count = get_total_uncles_count(&start_header.hash())?;
+            let last_total_uncles_count = get_total_uncles_count(&last_hash)?;
 
             let difficulty = last_difficulty
                 * U256::from(last_total_uncles_count - start_total_uncles_count)
                 * U256::from((1.0 / self.consensus.orphan_rate_target()) as u64)
                 / U256::from(interval);
 
-            let min_difficulty = self.consensus.min_difficulty();
-            let max_difficulty = last_difficulty * 2;
-            if difficulty > max_difficulty {
-                return Some(max_difficulty);
-            }
-
-            if difficulty < min_difficulty {
-                return Some(min_difficulty);
-            }
-            return Some(difficulty);
+            return Some(self.consensus.bound_difficulty(last_difficulty, difficulty));
         }
         None
     }
@@ -423,6 +676,107 @@ impl<CI: ChainIndex> ChainProvider for Shared<CI> {
     fn consensus(&self) -> &Consensus {
         &self.consensus
     }
+
+    fn cell_set_len(&self) -> u64 {
+        self.store.get_cell_set_len().unwrap_or(0)
+    }
+
+    fn cell_set_iter<'a>(&'a self) -> CellSetIter<'a> {
+        self.store.cell_set_iter()
+    }
+
+    fn txs_verify_cache(&self) -> &Mutex<TxsVerifyCache> {
+        &self.txs_verify_cache
+    }
+
+    // Cached for the last asked tip, a child of it only reads its own header and uncles, so the
+    // verification of every new block doesn't walk back the whole uncle age again
+    fn uncle_exclusion_set(&self, tip: &H256) -> FnvHashSet<H256> {
+        let max_uncles_age = self.consensus.max_uncles_age();
+        let mut cached = self.uncle_exclusion.lock();
+        if cached.tip == *tip {
+            return cached.set();
+        }
+
+        let entry = |header: &Header| {
+            let uncles = self
+                .uncles(&header.hash())
+                .unwrap_or_default()
+                .iter()
+                .map(|uncle| uncle.header().hash())
+                .collect::<Vec<_>>();
+            (header.parent_hash(), uncles)
+        };
+
+        match self.block_header(tip) {
+            Some(ref header) if header.parent_hash() == cached.tip => {
+                cached.window.push_front(entry(header));
+                cached.window.truncate(max_uncles_age);
+            }
+            _ => {
+                let mut window = VecDeque::with_capacity(max_uncles_age);
+                let mut hash = *tip;
+                while window.len() < max_uncles_age {
+                    match self.block_header(&hash) {
+                        Some(header) => {
+                            window.push_back(entry(&header));
+                            hash = header.parent_hash();
+                        }
+                        None => break,
+                    }
+                }
+                cached.window = window;
+            }
+        }
+        cached.tip = *tip;
+        cached.set()
+    }
+}
+
+impl<CI: ChainIndex> Shared<CI> {
+    // Scales the difficulty by the ratio between the target and the average interval of the
+    // last TARGET_INTERVAL_WINDOW blocks, within the difficulty bounds each block.
+    fn calculate_interval_difficulty(
+        &self,
+        last: &Header,
+        target_block_interval: u64,
+        get_ancestor: &Fn(&H256, BlockNumber) -> Option<Header>,
+    ) -> Option<U256> {
+        let last_difficulty = last.difficulty();
+        if last.is_genesis() {
+            return Some(last_difficulty);
+        }
+
+        let start_number = last.number().saturating_sub(TARGET_INTERVAL_WINDOW);
+        let start_header = get_ancestor(&last.hash(), start_number)?;
+        let blocks = last.number() - start_number;
+        let average_interval = cmp::max(
+            last.timestamp().saturating_sub(start_header.timestamp()) / blocks,
+            1,
+        );
+
+        let difficulty =
+            last_difficulty * U256::from(target_block_interval) / U256::from(average_interval);
+
+        Some(self.consensus.bound_difficulty(last_difficulty, difficulty))
+    }
+
+    // Retargets from the duration of the epoch ending with `last`, measured from the headers
+    // whichever chain they are on
+    fn calculate_epoch_difficulty(
+        &self,
+        last: &Header,
+        get_ancestor: &Fn(&H256, BlockNumber) -> Option<Header>,
+    ) -> Option<U256> {
+        let interval = self.consensus.difficulty_adjustment_interval();
+        let start_number = last.number().saturating_sub(interval);
+        let start_header = get_ancestor(&last.hash(), start_number)?;
+        Some(self.consensus.retarget_difficulty(
+            last.difficulty(),
+            last.number() - start_number,
+            last.timestamp().saturating_sub(start_header.timestamp()),
+        ))
+    }
 }
 
 pub struct SharedBuilder<CI> {
@@ -436,10 +790,13 @@ impl<CI: ChainIndex> SharedBuilder<CI> {
         SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_simple(db)
     }
 
-    pub fn new_rocks<P: AsRef<Path>>(path: P) -> SharedBuilder<ChainKVStore<CacheDB<RocksDB>>> {
+    pub fn new_rocks<P: AsRef<Path>>(
+        path: P,
+        config: &DBConfig,
+    ) -> SharedBuilder<ChainKVStore<CacheDB<RocksDB>>> {
         let db = CacheDB::new(
-            RocksDB::open(path, COLUMNS),
-            &[(COLUMN_BLOCK_HEADER.unwrap(), 4096)],
+            RocksDB::open_with_config(path, COLUMNS, config),
+            &[(COLUMN_BLOCK_HEADER.unwrap(), config.header_cache_size)],
         );
         SharedBuilder::<ChainKVStore<CacheDB<RocksDB>>>::new_simple(db)
     }
@@ -463,3 +820,100 @@ impl<CI: ChainIndex> SharedBuilder<CI> {
         Shared::new(self.store, consensus)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_core::block::BlockBuilder;
+    use ckb_core::header::HeaderBuilder;
+    use ckb_chain_spec::hardfork::HardForkSwitch;
+    use ckb_core::transaction::{CellInput, CellOutput, TransactionBuilder};
+    use store::ChainStore;
+
+    #[test]
+    fn test_tip_lock_holder() {
+        let tx = TransactionBuilder::default()
+            .input(CellInput::new(OutPoint::null(), Default::default()))
+            .output(CellOutput::new(100_000_000, vec![], H256::default(), None))
+            .build();
+        let out_point = OutPoint::new(tx.hash(), 0);
+        let genesis_block = BlockBuilder::default().commit_transaction(tx);
+        let consensus = Consensus::default().set_genesis_block(genesis_block);
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+            .consensus(consensus)
+            .build();
+        let genesis = shared.consensus().genesis_block().header().hash();
+
+        // the cells of the tip are read without the lock its holder keeps
+        let _tip_header = shared.tip_header().write();
+        let holder = shared.tip_lock_holder();
+        assert!(holder.cell(&out_point).is_current());
+        assert!(holder.cell_at(&out_point, &genesis).is_current());
+        assert!(holder.cell_at(&out_point, &H256::from(1)).is_unknown());
+    }
+
+    #[test]
+    fn test_uncles_reward() {
+        let mut consensus = Consensus::default()
+            .set_initial_block_reward(3_200)
+            .set_hardfork_switch(HardForkSwitch {
+                uncles_reward: Some(1),
+                ..Default::default()
+            });
+        consensus.pow_time_span = 4;
+        consensus.pow_spacing = 1;
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+            .consensus(consensus)
+            .build();
+        assert_eq!(shared.uncles_reward(3, 2), 0);
+        assert_eq!(shared.uncles_reward(4, 0), 0);
+        assert_eq!(shared.uncles_reward(4, 2), 200);
+    }
+
+    #[test]
+    fn test_uncle_exclusion_set() {
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory().build();
+        let genesis = shared.consensus().genesis_block().header().clone();
+        let child = |parent: &Header, timestamp, uncles: Vec<UncleBlock>| {
+            let cellbase = TransactionBuilder::default()
+                .input(CellInput::new_cellbase_input(parent.number() + 1))
+                .build();
+            BlockBuilder::default()
+                .commit_transaction(cellbase)
+                .uncles(uncles)
+                .with_header_builder(
+                    HeaderBuilder::default()
+                        .parent_hash(&parent.hash())
+                        .number(parent.number() + 1)
+                        .timestamp(timestamp),
+                )
+        };
+        let block1 = child(&genesis, 1, Vec::new());
+        let uncle = child(&genesis, 2, Vec::new());
+        let block2 = child(block1.header(), 3, vec![uncle.clone().into()]);
+        shared
+            .store()
+            .save_with_batch(|batch| {
+                shared.store().insert_block(batch, &block1);
+                shared.store().insert_block(batch, &block2);
+                Ok(())
+            }).unwrap();
+        let (hash1, hash2) = (block1.header().hash(), block2.header().hash());
+
+        let mut expected = FnvHashSet::default();
+        expected.insert(hash1);
+        expected.insert(genesis.hash());
+        expected.insert(genesis.parent_hash());
+        assert_eq!(shared.uncle_exclusion_set(&hash1), expected);
+
+        // extended by the child of the cached tip
+        expected.insert(hash2);
+        expected.insert(uncle.header().hash());
+        assert_eq!(shared.uncle_exclusion_set(&hash2), expected);
+
+        // walked back again for any other block
+        expected.remove(&hash2);
+        expected.remove(&uncle.header().hash());
+        assert_eq!(shared.uncle_exclusion_set(&hash1), expected);
+    }
+}
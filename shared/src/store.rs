@@ -10,7 +10,7 @@ use ckb_core::transaction::{OutPoint, ProposalShortId, Transaction, TransactionB
 use ckb_core::transaction_meta::TransactionMeta;
 use ckb_core::uncle::UncleBlock;
 use ckb_db::batch::{Batch, Col};
-use ckb_db::kvdb::KeyValueDB;
+use ckb_db::kvdb::{KeyValueDB, KeyValueIter};
 use ckb_util::RwLock;
 use error::SharedError;
 use std::ops::Range;
@@ -18,7 +18,7 @@ use std::sync::Arc;
 use {
     COLUMN_BLOCK_BODY, COLUMN_BLOCK_HEADER, COLUMN_BLOCK_PROPOSAL_IDS,
     COLUMN_BLOCK_TRANSACTION_ADDRESSES, COLUMN_BLOCK_TRANSACTION_IDS, COLUMN_BLOCK_UNCLE,
-    COLUMN_EXT, COLUMN_OUTPUT_ROOT, COLUMN_TRANSACTION_META,
+    COLUMN_EXT, COLUMN_OUTPUT_ROOT, COLUMN_TRANSACTION_META, COLUMN_UNCLE,
 };
 
 pub struct ChainKVStore<T: KeyValueDB> {
@@ -47,6 +47,10 @@ impl<T: 'static + KeyValueDB> ChainKVStore<T> {
             .partial_read(col, key, range)
             .expect("db operation should be ok")
     }
+
+    pub fn iter(&self, col: Col) -> KeyValueIter {
+        self.db.iter(col).expect("db operation should be ok")
+    }
 }
 
 pub struct ChainStoreHeaderIterator<'a, T: ChainStore>
@@ -64,6 +68,7 @@ pub trait ChainStore: Sync + Send {
     fn get_block_body(&self, block_hash: &H256) -> Option<Vec<Transaction>>;
     fn get_block_proposal_txs_ids(&self, h: &H256) -> Option<Vec<ProposalShortId>>;
     fn get_block_uncles(&self, block_hash: &H256) -> Option<Vec<UncleBlock>>;
+    fn get_uncle(&self, uncle_hash: &H256) -> Option<UncleBlock>;
     fn get_transaction_meta(&self, root: H256, key: H256) -> Option<TransactionMeta>;
     fn get_block_ext(&self, block_hash: &H256) -> Option<BlockExt>;
 
@@ -74,6 +79,8 @@ pub trait ChainStore: Sync + Send {
         cells: Vec<(Vec<OutPoint>, Vec<OutPoint>)>,
     ) -> Option<H256>;
 
+    /// The uncles are stored once whatever the number of blocks including them, and kept as long
+    /// as these blocks are, side blocks included
     fn insert_block(&self, batch: &mut Batch, b: &Block);
     fn insert_block_ext(&self, batch: &mut Batch, block_hash: &H256, ext: &BlockExt);
     fn insert_output_root(&self, batch: &mut Batch, block_hash: H256, r: H256);
@@ -151,8 +158,20 @@ impl<T: 'static + KeyValueDB> ChainStore for ChainKVStore<T> {
     }
 
     fn get_block_uncles(&self, h: &H256) -> Option<Vec<UncleBlock>> {
-        // TODO Q use builder
-        self.get(COLUMN_BLOCK_UNCLE, &h)
+        // uncles are stored once in COLUMN_UNCLE, blocks only keep their hashes
+        self.get(COLUMN_BLOCK_UNCLE, &h).map(|raw| {
+            let uncle_hashes: Vec<H256> = deserialize(&raw[..]).unwrap();
+            uncle_hashes
+                .iter()
+                .map(|uncle_hash| {
+                    self.get_uncle(uncle_hash)
+                        .expect("block uncles must be stored")
+                }).collect()
+        })
+    }
+
+    fn get_uncle(&self, uncle_hash: &H256) -> Option<UncleBlock> {
+        self.get(COLUMN_UNCLE, &uncle_hash)
             .map(|raw| deserialize(&raw[..]).unwrap())
     }
 
@@ -285,10 +304,24 @@ impl<T: 'static + KeyValueDB> ChainStore for ChainKVStore<T> {
             hash.clone(),
             serialize(&txs_ids).expect("serializing txs hash should be ok"),
         );
+        let uncle_hashes = b
+            .uncles()
+            .iter()
+            .map(|uncle| uncle.header().hash())
+            .collect::<Vec<H256>>();
+        for (uncle_hash, uncle) in uncle_hashes.iter().zip(b.uncles()) {
+            if self.get(COLUMN_UNCLE, &uncle_hash).is_none() {
+                batch.insert(
+                    COLUMN_UNCLE,
+                    uncle_hash.to_vec(),
+                    serialize(uncle).expect("serializing uncle should be ok"),
+                );
+            }
+        }
         batch.insert(
             COLUMN_BLOCK_UNCLE,
             hash.clone(),
-            serialize(b.uncles()).expect("serializing uncles should be ok"),
+            serialize(&uncle_hashes).expect("serializing uncle hashes should be ok"),
         );
         batch.insert(COLUMN_BLOCK_BODY, hash.clone(), block_data);
         batch.insert(
@@ -418,4 +451,40 @@ mod tests {
         );
         assert_eq!(ext, store.get_block_ext(&hash).unwrap());
     }
+
+    #[test]
+    fn save_and_get_shared_uncles() {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("save_and_get_shared_uncles")
+            .tempdir()
+            .unwrap();
+        let db = RocksDB::open(tmp_dir, COLUMNS);
+        let store = ChainKVStore::new(db);
+        let consensus = Consensus::default();
+        let uncle: UncleBlock = consensus.genesis_block().clone().into();
+        let uncle_hash = uncle.header().hash();
+
+        let blocks: Vec<Block> = (1..3)
+            .map(|nonce| {
+                BlockBuilder::default()
+                    .header(HeaderBuilder::default().nonce(nonce).build())
+                    .uncle(uncle.clone())
+                    .build()
+            }).collect();
+
+        for block in &blocks {
+            assert!(
+                store
+                    .save_with_batch(|batch| {
+                        store.insert_block(batch, block);
+                        Ok(())
+                    }).is_ok()
+            );
+            assert_eq!(block, &store.get_block(&block.header().hash()).unwrap());
+        }
+        assert_eq!(Some(uncle.clone()), store.get_uncle(&uncle_hash));
+        // a single copy for both blocks
+        assert_eq!(1, store.iter(COLUMN_UNCLE).count());
+    }
+
 }
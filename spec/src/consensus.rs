@@ -1,21 +1,109 @@
-use bigint::U256;
+use bigint::{H256, U256};
 use ckb_core::block::{Block, BlockBuilder};
 use ckb_core::header::HeaderBuilder;
 use ckb_core::transaction::Capacity;
-use ckb_core::BlockNumber;
+use ckb_core::{BlockNumber, Cycle};
 use ckb_pow::{Pow, PowEngine};
+use hardfork::{EpochNumber, HardForkSwitch};
+use std::cmp;
+use std::fmt;
+use std::ops::RangeInclusive;
 use std::sync::Arc;
 
+// The engine of `Consensus::pow`, built once so the verifiers and the miner share one instance.
+// It follows from `pow`, which is what consensuses are compared and printed by.
+#[derive(Clone)]
+struct PowEngineHandle(Arc<dyn PowEngine>);
+
+impl PartialEq for PowEngineHandle {
+    fn eq(&self, _other: &PowEngineHandle) -> bool {
+        true
+    }
+}
+
+impl fmt::Debug for PowEngineHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PowEngine")
+    }
+}
+
 pub const DEFAULT_BLOCK_REWARD: Capacity = 5_000;
+// Every included uncle adds this fraction of the block reward to the cellbase
+pub const UNCLE_REWARD_DIVISOR: Capacity = 32;
 pub const MAX_UNCLE_LEN: usize = 2;
 pub const MAX_UNCLE_AGE: usize = 6;
-pub const TRANSACTION_PROPAGATION_TIME: BlockNumber = 1;
-pub const TRANSACTION_PROPAGATION_TIMEOUT: BlockNumber = 10;
+pub const PROPOSAL_WINDOW: ProposalWindow = ProposalWindow { close: 1, far: 10 };
+pub const MAX_BLOCK_PROPOSALS_LIMIT: u64 = 3_000;
+pub const MAX_BLOCK_BYTES: u64 = 2 * 1024 * 1024; // 2 MiB
+pub const MAX_TX_BYTES: u64 = 512 * 1024; // 512 KiB
+pub const MAX_CELL_DATA_BYTES: u64 = 256 * 1024; // 256 KiB
+pub const MAX_BLOCK_DATA_BYTES: u64 = 1024 * 1024; // 1 MiB
+pub const MAX_BLOCK_CYCLES: Cycle = 1_000_000_000;
+// Blocks added on top of the block of a cellbase before its outputs can be spent
+pub const CELLBASE_MATURITY: BlockNumber = 100;
 
 //TODO：find best ORPHAN_RATE_TARGET
 pub const ORPHAN_RATE_TARGET: f32 = 0.1;
 pub const POW_TIME_SPAN: u64 = 12 * 60 * 60 * 1000; // 12 hours
 pub const POW_SPACING: u64 = 15 * 1000; //15s
+// Number of blocks averaged when targeting a block interval
+pub const TARGET_INTERVAL_WINDOW: BlockNumber = 10;
+pub const DIFFICULTY_BOUNDS: DifficultyBounds = DifficultyBounds {
+    max_increase: 2,
+    max_decrease: 2,
+};
+// A block's timestamp must be greater than the median of that many previous blocks
+pub const MEDIAN_TIME_BLOCK_COUNT: usize = 11;
+pub const MAX_BLOCK_TIME_DRIFT: u64 = 15 * 1000; // 15 Second
+
+/// A transaction proposed in block `p` may be committed from block `p + close` to
+/// block `p + far`. The genesis block proposes nothing.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProposalWindow {
+    pub close: BlockNumber,
+    pub far: BlockNumber,
+}
+
+impl Default for ProposalWindow {
+    fn default() -> Self {
+        PROPOSAL_WINDOW
+    }
+}
+
+impl ProposalWindow {
+    /// Numbers of the ancestors whose proposals can be committed in block `number`,
+    /// `None` if there is no such ancestor yet
+    pub fn proposals_range(&self, number: BlockNumber) -> Option<RangeInclusive<BlockNumber>> {
+        let start = cmp::max(1, number.saturating_sub(self.far));
+        number
+            .checked_sub(self.close)
+            .filter(|end| *end >= start)
+            .map(|end| start..=end)
+    }
+
+    /// Whether a transaction proposed in block `proposed` can be committed in block `number`
+    pub fn can_commit(&self, proposed: BlockNumber, number: BlockNumber) -> bool {
+        self.proposals_range(number)
+            .map_or(false, |range| *range.start() <= proposed && proposed <= *range.end())
+    }
+}
+
+/// A difficulty adjustment multiplies the last difficulty by at most `max_increase` and divides
+/// it by at most `max_decrease`, so a few blocks with forged timestamps or uncle counts can't
+/// swing it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DifficultyBounds {
+    pub max_increase: u64,
+    pub max_decrease: u64,
+}
+
+impl Default for DifficultyBounds {
+    fn default() -> Self {
+        DIFFICULTY_BOUNDS
+    }
+}
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct Consensus {
@@ -27,10 +115,34 @@ pub struct Consensus {
     pub orphan_rate_target: f32,
     pub pow_time_span: u64,
     pub pow_spacing: u64,
-    pub transaction_propagation_time: BlockNumber,
-    pub transaction_propagation_timeout: BlockNumber,
+    pub proposal_window: ProposalWindow,
+    // Max number of proposal short ids in a block or in any of its uncles
+    pub max_block_proposals_limit: u64,
+    // Max serialized size of a block, and of every transaction in it or in the pool
+    pub max_block_bytes: u64,
+    pub max_tx_bytes: u64,
+    // Max data size of a cell, and of all the outputs data committed in a block
+    pub max_cell_data_bytes: u64,
+    pub max_block_data_bytes: u64,
+    // Max cycles of the scripts of all the transactions committed in a block
+    pub max_block_cycles: Cycle,
+    // A cellbase output of block `n` can be spent from block `n + cellbase_maturity`, the
+    // reward of a block which may still be reorganized away can't be moved
+    pub cellbase_maturity: BlockNumber,
+    // Set with `set_pow`, which also builds the engine
     pub pow: Pow,
+    pow_engine: PowEngineHandle,
     pub verification: bool,
+    // Dev chains only: when set, difficulty is adjusted on every block so blocks are
+    // produced every that many milliseconds on average, instead of targeting the orphan rate
+    pub target_block_interval: Option<u64>,
+    pub hardfork_switch: HardForkSwitch,
+    pub difficulty_bounds: DifficultyBounds,
+    // The difficulty never drops below it, the genesis difficulty if unset
+    pub min_difficulty: Option<U256>,
+    pub median_time_block_count: usize,
+    // How far ahead of the local clock a block's timestamp may be, in milliseconds
+    pub max_block_time_drift: u64,
 }
 
 // genesis difficulty should not be zero
@@ -48,10 +160,23 @@ impl Default for Consensus {
             orphan_rate_target: ORPHAN_RATE_TARGET,
             pow_time_span: POW_TIME_SPAN,
             pow_spacing: POW_SPACING,
-            transaction_propagation_time: TRANSACTION_PROPAGATION_TIME,
-            transaction_propagation_timeout: TRANSACTION_PROPAGATION_TIMEOUT,
+            proposal_window: PROPOSAL_WINDOW,
+            max_block_proposals_limit: MAX_BLOCK_PROPOSALS_LIMIT,
+            max_block_bytes: MAX_BLOCK_BYTES,
+            max_tx_bytes: MAX_TX_BYTES,
+            max_cell_data_bytes: MAX_CELL_DATA_BYTES,
+            max_block_data_bytes: MAX_BLOCK_DATA_BYTES,
+            max_block_cycles: MAX_BLOCK_CYCLES,
+            cellbase_maturity: CELLBASE_MATURITY,
             pow: Pow::Dummy,
+            pow_engine: PowEngineHandle(Pow::Dummy.engine()),
             verification: true,
+            target_block_interval: None,
+            hardfork_switch: HardForkSwitch::default(),
+            difficulty_bounds: DIFFICULTY_BOUNDS,
+            min_difficulty: None,
+            median_time_block_count: MEDIAN_TIME_BLOCK_COUNT,
+            max_block_time_drift: MAX_BLOCK_TIME_DRIFT,
         }
     }
 }
@@ -73,6 +198,7 @@ impl Consensus {
     }
 
     pub fn set_pow(mut self, pow: Pow) -> Self {
+        self.pow_engine = PowEngineHandle(pow.engine());
         self.pow = pow;
         self
     }
@@ -82,10 +208,86 @@ impl Consensus {
         self
     }
 
+    pub fn set_target_block_interval(mut self, target_block_interval: Option<u64>) -> Self {
+        self.target_block_interval = target_block_interval;
+        self
+    }
+
+    pub fn set_hardfork_switch(mut self, hardfork_switch: HardForkSwitch) -> Self {
+        self.hardfork_switch = hardfork_switch;
+        self
+    }
+
+    pub fn set_proposal_window(mut self, proposal_window: ProposalWindow) -> Self {
+        self.proposal_window = proposal_window;
+        self
+    }
+
+    pub fn set_difficulty_bounds(mut self, difficulty_bounds: DifficultyBounds) -> Self {
+        self.difficulty_bounds = difficulty_bounds;
+        self
+    }
+
+    pub fn set_min_difficulty(mut self, min_difficulty: Option<U256>) -> Self {
+        self.min_difficulty = min_difficulty;
+        self
+    }
+
+    pub fn set_max_block_proposals_limit(mut self, max_block_proposals_limit: u64) -> Self {
+        self.max_block_proposals_limit = max_block_proposals_limit;
+        self
+    }
+
+    pub fn set_max_block_bytes(mut self, max_block_bytes: u64) -> Self {
+        self.max_block_bytes = max_block_bytes;
+        self
+    }
+
+    pub fn set_max_tx_bytes(mut self, max_tx_bytes: u64) -> Self {
+        self.max_tx_bytes = max_tx_bytes;
+        self
+    }
+
+    pub fn set_max_cell_data_bytes(mut self, max_cell_data_bytes: u64) -> Self {
+        self.max_cell_data_bytes = max_cell_data_bytes;
+        self
+    }
+
+    pub fn set_max_block_data_bytes(mut self, max_block_data_bytes: u64) -> Self {
+        self.max_block_data_bytes = max_block_data_bytes;
+        self
+    }
+
+    pub fn set_max_block_cycles(mut self, max_block_cycles: Cycle) -> Self {
+        self.max_block_cycles = max_block_cycles;
+        self
+    }
+
+    pub fn set_cellbase_maturity(mut self, cellbase_maturity: BlockNumber) -> Self {
+        self.cellbase_maturity = cellbase_maturity;
+        self
+    }
+
+    pub fn set_median_time_block_count(mut self, median_time_block_count: usize) -> Self {
+        self.median_time_block_count = median_time_block_count;
+        self
+    }
+
+    pub fn set_max_block_time_drift(mut self, max_block_time_drift: u64) -> Self {
+        self.max_block_time_drift = max_block_time_drift;
+        self
+    }
+
     pub fn genesis_block(&self) -> &Block {
         &self.genesis_block
     }
 
+    /// The genesis hash, version 2 scripts sign it so their transactions can't be replayed on
+    /// another chain
+    pub fn chain_id(&self) -> H256 {
+        self.genesis_block.header().hash()
+    }
+
     pub fn max_uncles_len(&self) -> usize {
         self.max_uncles_len
     }
@@ -94,8 +296,91 @@ impl Consensus {
         self.max_uncles_age
     }
 
+    pub fn proposal_window(&self) -> ProposalWindow {
+        self.proposal_window
+    }
+
+    pub fn max_block_proposals_limit(&self) -> u64 {
+        self.max_block_proposals_limit
+    }
+
+    pub fn max_block_bytes(&self) -> u64 {
+        self.max_block_bytes
+    }
+
+    pub fn max_tx_bytes(&self) -> u64 {
+        self.max_tx_bytes
+    }
+
+    pub fn max_cell_data_bytes(&self) -> u64 {
+        self.max_cell_data_bytes
+    }
+
+    pub fn max_block_data_bytes(&self) -> u64 {
+        self.max_block_data_bytes
+    }
+
+    pub fn max_block_cycles(&self) -> Cycle {
+        self.max_block_cycles
+    }
+
+    pub fn cellbase_maturity(&self) -> BlockNumber {
+        self.cellbase_maturity
+    }
+
+    /// The maturity the cellbase outputs spent in block `number` must reach, none before the
+    /// epoch the `cellbase_maturity` hard fork activates at
+    pub fn cellbase_maturity_at(&self, number: BlockNumber) -> BlockNumber {
+        if self
+            .hardfork_switch
+            .is_cellbase_maturity_enabled(self.epoch_number(number))
+        {
+            self.cellbase_maturity
+        } else {
+            0
+        }
+    }
+
     pub fn min_difficulty(&self) -> U256 {
-        self.genesis_block.header().difficulty()
+        self.min_difficulty
+            .unwrap_or_else(|| self.genesis_block.header().difficulty())
+    }
+
+    pub fn difficulty_bounds(&self) -> DifficultyBounds {
+        self.difficulty_bounds
+    }
+
+    pub fn median_time_block_count(&self) -> usize {
+        self.median_time_block_count
+    }
+
+    pub fn max_block_time_drift(&self) -> u64 {
+        self.max_block_time_drift
+    }
+
+    /// Caps the adjustment of `last_difficulty` to `difficulty` by the difficulty bounds, the
+    /// result is never below the min difficulty
+    pub fn bound_difficulty(&self, last_difficulty: U256, difficulty: U256) -> U256 {
+        let bounds = self.difficulty_bounds;
+        let max_difficulty = last_difficulty * U256::from(bounds.max_increase);
+        let min_difficulty = cmp::max(
+            last_difficulty / U256::from(bounds.max_decrease),
+            self.min_difficulty(),
+        );
+        cmp::max(cmp::min(difficulty, max_difficulty), min_difficulty)
+    }
+
+    /// Scales the difficulty of the last epoch by the ratio between its expected and actual
+    /// duration, `pow_spacing` per block interval, damped by the difficulty bounds
+    pub fn retarget_difficulty(
+        &self,
+        last_difficulty: U256,
+        intervals: BlockNumber,
+        duration: u64,
+    ) -> U256 {
+        let expected = self.pow_spacing * intervals;
+        let difficulty = last_difficulty * U256::from(expected) / U256::from(cmp::max(duration, 1));
+        self.bound_difficulty(last_difficulty, difficulty)
     }
 
     pub fn initial_block_reward(&self) -> Capacity {
@@ -106,11 +391,113 @@ impl Consensus {
         self.pow_time_span / self.pow_spacing
     }
 
+    /// Blocks in the same epoch share the difficulty and the active consensus rules
+    pub fn epoch_number(&self, number: BlockNumber) -> EpochNumber {
+        number / self.difficulty_adjustment_interval()
+    }
+
+    pub fn hardfork_switch(&self) -> &HardForkSwitch {
+        &self.hardfork_switch
+    }
+
     pub fn orphan_rate_target(&self) -> f32 {
         self.orphan_rate_target
     }
 
+    pub fn target_block_interval(&self) -> Option<u64> {
+        self.target_block_interval
+    }
+
     pub fn pow_engine(&self) -> Arc<dyn PowEngine> {
-        self.pow.engine()
+        Arc::clone(&self.pow_engine.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_pow::Clicker;
+
+    #[test]
+    fn test_proposals_range_near_genesis() {
+        let window = ProposalWindow { close: 2, far: 4 };
+        assert_eq!(window.proposals_range(0), None);
+        assert_eq!(window.proposals_range(1), None);
+        assert_eq!(window.proposals_range(2), None);
+        assert_eq!(window.proposals_range(3), Some(1..=1));
+        assert_eq!(window.proposals_range(5), Some(1..=3));
+        assert_eq!(window.proposals_range(6), Some(2..=4));
+
+        assert!(!window.can_commit(0, 2));
+        assert!(window.can_commit(1, 3));
+        assert!(window.can_commit(1, 5));
+        assert!(!window.can_commit(1, 6));
+        assert!(!window.can_commit(4, 5));
+    }
+
+    #[test]
+    fn test_bound_difficulty() {
+        let consensus = Consensus::default()
+            .set_difficulty_bounds(DifficultyBounds {
+                max_increase: 4,
+                max_decrease: 2,
+            }).set_min_difficulty(Some(U256::from(100)));
+        let last = U256::from(1000);
+        assert_eq!(
+            consensus.bound_difficulty(last, U256::from(3000)),
+            U256::from(3000)
+        );
+        assert_eq!(
+            consensus.bound_difficulty(last, U256::from(5000)),
+            U256::from(4000)
+        );
+        assert_eq!(
+            consensus.bound_difficulty(last, U256::from(400)),
+            U256::from(500)
+        );
+        // the floor wins over the max decrease
+        assert_eq!(
+            consensus.bound_difficulty(U256::from(150), U256::from(1)),
+            U256::from(100)
+        );
+    }
+
+    #[test]
+    fn test_retarget_difficulty() {
+        let mut consensus = Consensus::default();
+        consensus.pow_spacing = 10;
+        let last = U256::from(1000);
+        // 100 intervals expected to last 1000ms
+        assert_eq!(consensus.retarget_difficulty(last, 100, 1000), last);
+        assert_eq!(
+            consensus.retarget_difficulty(last, 100, 800),
+            U256::from(1250)
+        );
+        assert_eq!(
+            consensus.retarget_difficulty(last, 100, 1250),
+            U256::from(800)
+        );
+        // damped to the bounds, even for an epoch of a single millisecond or less
+        assert_eq!(
+            consensus.retarget_difficulty(last, 100, 0),
+            U256::from(2000)
+        );
+        assert_eq!(
+            consensus.retarget_difficulty(last, 100, 10_000),
+            U256::from(500)
+        );
+    }
+
+    #[test]
+    fn test_shared_pow_engine() {
+        let consensus = Consensus::default().set_pow(Pow::Clicker);
+        let engine = consensus.pow_engine();
+        assert!(engine.as_any().downcast_ref::<Clicker>().is_some());
+        // the verifiers and the miner get the same instance, even of a cloned consensus
+        assert!(Arc::ptr_eq(&engine, &consensus.pow_engine()));
+        assert!(Arc::ptr_eq(&engine, &consensus.clone().pow_engine()));
+
+        assert_ne!(consensus, Consensus::default());
+        assert_eq!(Consensus::default(), Consensus::default());
     }
 }
@@ -0,0 +1,108 @@
+//! Consensus rule changes scheduled by epoch.
+//!
+//! Every rule change is named by a field of `HardForkSwitch` holding the epoch it activates
+//! at. Verifiers ask the switch whether a rule is enabled for the epoch of the block they
+//! verify, instead of comparing block numbers themselves. A rule without an epoch is never
+//! enabled.
+
+use ckb_core::BlockNumber;
+
+/// Difficulty adjustment period, see `Consensus::epoch_number`
+pub type EpochNumber = BlockNumber;
+
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HardForkSwitch {
+    /// Blocks may declare header version 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_version_1: Option<EpochNumber>,
+    /// Scripts may declare version 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script_version_1: Option<EpochNumber>,
+    /// Scripts may declare version 2, whose signatures are bound to the chain id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script_version_2: Option<EpochNumber>,
+    /// Difficulty is retargeted from the duration of the last epoch instead of its uncle rate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub epoch_duration_difficulty: Option<EpochNumber>,
+    /// The cellbase may claim a share of the block reward for each uncle the block includes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uncles_reward: Option<EpochNumber>,
+    /// The cellbase outputs can only be spent once `cellbase_maturity` blocks were added on top
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cellbase_maturity: Option<EpochNumber>,
+}
+
+impl HardForkSwitch {
+    fn is_enabled(activation: Option<EpochNumber>, epoch: EpochNumber) -> bool {
+        activation.map_or(false, |activation| epoch >= activation)
+    }
+
+    pub fn is_block_version_1_enabled(&self, epoch: EpochNumber) -> bool {
+        Self::is_enabled(self.block_version_1, epoch)
+    }
+
+    pub fn is_script_version_1_enabled(&self, epoch: EpochNumber) -> bool {
+        Self::is_enabled(self.script_version_1, epoch)
+    }
+
+    pub fn is_script_version_2_enabled(&self, epoch: EpochNumber) -> bool {
+        Self::is_enabled(self.script_version_2, epoch)
+    }
+
+    pub fn is_epoch_duration_difficulty_enabled(&self, epoch: EpochNumber) -> bool {
+        Self::is_enabled(self.epoch_duration_difficulty, epoch)
+    }
+
+    pub fn is_uncles_reward_enabled(&self, epoch: EpochNumber) -> bool {
+        Self::is_enabled(self.uncles_reward, epoch)
+    }
+
+    pub fn is_cellbase_maturity_enabled(&self, epoch: EpochNumber) -> bool {
+        Self::is_enabled(self.cellbase_maturity, epoch)
+    }
+
+    /// The highest header version allowed in the epoch
+    pub fn max_block_version(&self, epoch: EpochNumber) -> u32 {
+        if self.is_block_version_1_enabled(epoch) {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// The highest script version allowed in the epoch
+    pub fn max_script_version(&self, epoch: EpochNumber) -> u8 {
+        if self.is_script_version_2_enabled(epoch) {
+            2
+        } else if self.is_script_version_1_enabled(epoch) {
+            1
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_activation_epoch() {
+        let switch = HardForkSwitch {
+            block_version_1: Some(2),
+            script_version_1: None,
+            script_version_2: Some(3),
+            epoch_duration_difficulty: None,
+            uncles_reward: None,
+            cellbase_maturity: None,
+        };
+        assert_eq!(switch.max_block_version(0), 0);
+        assert_eq!(switch.max_block_version(1), 0);
+        assert_eq!(switch.max_block_version(2), 1);
+        assert_eq!(switch.max_block_version(3), 1);
+        assert_eq!(HardForkSwitch::default().max_block_version(1000), 0);
+        assert_eq!(switch.max_script_version(2), 0);
+        assert_eq!(switch.max_script_version(3), 2);
+    }
+}
@@ -12,34 +12,82 @@ extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 extern crate ckb_pow;
+extern crate hash;
 
 use bigint::{H256, U256};
 use ckb_core::block::BlockBuilder;
 use ckb_core::header::HeaderBuilder;
 use ckb_core::transaction::{CellOutput, Transaction, TransactionBuilder};
-use ckb_core::Capacity;
-use ckb_pow::{Pow, PowEngine};
-use consensus::Consensus;
+use ckb_core::{BlockNumber, Capacity, Cycle};
+use ckb_pow::Pow;
+use consensus::{
+    Consensus, DifficultyBounds, ProposalWindow, CELLBASE_MATURITY, MAX_BLOCK_BYTES,
+    MAX_BLOCK_CYCLES, MAX_BLOCK_DATA_BYTES, MAX_CELL_DATA_BYTES, MAX_TX_BYTES,
+};
+use hardfork::HardForkSwitch;
+use hash::sha3_256;
 use std::error::Error;
 use std::fs::File;
 use std::io::Read;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
 
 pub mod consensus;
+pub mod hardfork;
 
 #[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
 pub struct ChainSpec {
     pub name: String,
+    /// Tells apart the networks of a same genesis, see `network_magic`
+    #[serde(default)]
+    pub network_id: u32,
     pub genesis: Genesis,
     pub params: Params,
     pub system_cells: Vec<SystemCell>,
     pub pow: Pow,
 }
 
-#[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
+/// The unset parameters are left out of the encoding hashed by `ChainSpec::hash`, so adding a
+/// parameter doesn't change the hash of the existing chains.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct Params {
     pub initial_block_reward: Capacity,
+    /// Target block interval in milliseconds, only meant for dev chains
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_block_interval: Option<u64>,
+    /// Activation epochs of the consensus rule changes
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub hardfork: HardForkSwitch,
+    /// Blocks in which proposed transactions can be committed, relative to the proposing block
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub proposal_window: ProposalWindow,
+    /// How far the difficulty can move in one adjustment
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub difficulty_bounds: DifficultyBounds,
+    /// Floor of the difficulty, the genesis difficulty by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_difficulty: Option<U256>,
+    /// Max serialized size of a block in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_block_bytes: Option<u64>,
+    /// Max serialized size of a transaction in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tx_bytes: Option<u64>,
+    /// Max data size of an output in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_cell_data_bytes: Option<u64>,
+    /// Max total size of the outputs data committed in a block in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_block_data_bytes: Option<u64>,
+    /// Max total cycles of the scripts of the transactions committed in a block
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_block_cycles: Option<Cycle>,
+    /// Blocks added on top of the block of a cellbase before its outputs can be spent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cellbase_maturity: Option<BlockNumber>,
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
 }
 
 #[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
@@ -54,11 +102,8 @@ pub struct Genesis {
     pub version: u32,
     pub parent_hash: H256,
     pub timestamp: u64,
-    pub txs_commit: H256,
-    pub txs_proposal: H256,
     pub difficulty: U256,
     pub cellbase_id: H256,
-    pub uncles_hash: H256,
 }
 
 #[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
@@ -90,33 +135,106 @@ impl ChainSpec {
         Ok(spec)
     }
 
-    pub fn pow_engine(&self) -> Arc<dyn PowEngine> {
-        self.pow.engine()
+    /// Identifies the chain: the name, the consensus parameters and the genesis, which covers
+    /// the system cells, but not where the spec and the cells are stored
+    pub fn hash(&self) -> Result<H256, Box<Error>> {
+        let consensus = self.to_consensus()?;
+        let mut encoded = serde_json::to_vec(&(&self.name, &self.params, &self.pow))?;
+        encoded.extend_from_slice(&consensus.genesis_block().header().hash());
+        Ok(sha3_256(encoded).into())
+    }
+
+    /// Prefixes the messages of the network, derived from the genesis and the network id only,
+    /// the nodes of a chain keep talking to each other across parameter changes
+    pub fn network_magic(&self) -> Result<[u8; 4], Box<Error>> {
+        let consensus = self.to_consensus()?;
+        let mut encoded = Vec::new();
+        encoded.extend_from_slice(&consensus.genesis_block().header().hash());
+        encoded.extend((0..4).map(|i| (self.network_id >> (8 * i)) as u8));
+        let hash = sha3_256(encoded);
+        let mut magic = [0u8; 4];
+        magic.copy_from_slice(&hash[..4]);
+        Ok(magic)
     }
 
     pub fn to_consensus(&self) -> Result<Consensus, Box<Error>> {
-        let header = HeaderBuilder::default()
+        let proposal_window = self.params.proposal_window;
+        // a transaction can't be committed in the block proposing it
+        if proposal_window.close == 0 || proposal_window.close > proposal_window.far {
+            return Err(format!("invalid proposal window {:?}", proposal_window).into());
+        }
+        let difficulty_bounds = self.params.difficulty_bounds;
+        if difficulty_bounds.max_increase == 0 || difficulty_bounds.max_decrease == 0 {
+            return Err(format!("invalid difficulty bounds {:?}", difficulty_bounds).into());
+        }
+        if self.params.min_difficulty == Some(U256::zero()) {
+            return Err("min difficulty should not be zero".into());
+        }
+        if let Pow::Cuckoo(params) = self.pow {
+            if !params.is_valid() {
+                return Err(format!("invalid cuckoo params {:?}", params).into());
+            }
+        }
+        let max_block_bytes = self.params.max_block_bytes.unwrap_or(MAX_BLOCK_BYTES);
+        let max_tx_bytes = self.params.max_tx_bytes.unwrap_or(MAX_TX_BYTES);
+        if max_tx_bytes == 0 || max_tx_bytes > max_block_bytes {
+            return Err(format!(
+                "invalid size limits, max_block_bytes {} max_tx_bytes {}",
+                max_block_bytes, max_tx_bytes
+            ).into());
+        }
+        let max_cell_data_bytes = self
+            .params
+            .max_cell_data_bytes
+            .unwrap_or(MAX_CELL_DATA_BYTES);
+        let max_block_data_bytes = self
+            .params
+            .max_block_data_bytes
+            .unwrap_or(MAX_BLOCK_DATA_BYTES);
+        if max_cell_data_bytes == 0
+            || max_cell_data_bytes > max_block_data_bytes
+            || max_block_data_bytes > max_block_bytes
+        {
+            return Err(format!(
+                "invalid data limits, max_cell_data_bytes {} max_block_data_bytes {}",
+                max_cell_data_bytes, max_block_data_bytes
+            ).into());
+        }
+        let max_block_cycles = self.params.max_block_cycles.unwrap_or(MAX_BLOCK_CYCLES);
+        if max_block_cycles == 0 {
+            return Err("max block cycles should not be zero".into());
+        }
+        let cellbase_maturity = self.params.cellbase_maturity.unwrap_or(CELLBASE_MATURITY);
+
+        let header_builder = HeaderBuilder::default()
             .version(self.genesis.version)
             .parent_hash(&self.genesis.parent_hash)
             .timestamp(self.genesis.timestamp)
-            .txs_commit(&self.genesis.txs_commit)
-            .txs_proposal(&self.genesis.txs_proposal)
             .difficulty(&self.genesis.difficulty)
             .nonce(self.genesis.seal.nonce)
             .proof(&self.genesis.seal.proof)
-            .cellbase_id(&self.genesis.cellbase_id)
-            .uncles_hash(&self.genesis.uncles_hash)
-            .build();
+            .cellbase_id(&self.genesis.cellbase_id);
 
+        // the roots are computed from the body, the genesis is verified against them
         let genesis_block = BlockBuilder::default()
             .commit_transaction(build_system_cell_transaction(&self.system_cells)?)
-            .header(header)
-            .build();
+            .with_header_builder(header_builder);
 
         let consensus = Consensus::default()
             .set_id(self.name.clone())
             .set_genesis_block(genesis_block)
             .set_initial_block_reward(self.params.initial_block_reward)
+            .set_target_block_interval(self.params.target_block_interval)
+            .set_hardfork_switch(self.params.hardfork.clone())
+            .set_proposal_window(proposal_window)
+            .set_difficulty_bounds(difficulty_bounds)
+            .set_min_difficulty(self.params.min_difficulty)
+            .set_max_block_bytes(max_block_bytes)
+            .set_max_tx_bytes(max_tx_bytes)
+            .set_max_cell_data_bytes(max_cell_data_bytes)
+            .set_max_block_data_bytes(max_block_data_bytes)
+            .set_max_block_cycles(max_block_cycles)
+            .set_cellbase_maturity(cellbase_maturity)
             .set_pow(self.pow.clone());
 
         Ok(consensus)
@@ -147,8 +265,197 @@ pub mod test {
             Path::new(env!("CARGO_MANIFEST_DIR")).join("../nodes_template/spec/dev.json"),
         );
         assert!(dev.is_ok(), format!("{:?}", dev));
-        for cell in &dev.unwrap().system_cells {
+        let dev = dev.unwrap();
+        for cell in &dev.system_cells {
             assert!(cell.path.exists());
         }
+        let consensus = dev.to_consensus().unwrap();
+        assert_eq!(consensus.target_block_interval(), Some(5000));
+        assert!(consensus.hardfork_switch().is_block_version_1_enabled(0));
+        assert!(consensus.hardfork_switch().is_script_version_1_enabled(0));
+        assert!(consensus.hardfork_switch().is_uncles_reward_enabled(0));
+        assert!(consensus.hardfork_switch().is_cellbase_maturity_enabled(0));
+        assert_eq!(
+            consensus.proposal_window(),
+            ProposalWindow { close: 1, far: 10 }
+        );
+        assert_eq!(
+            consensus.difficulty_bounds(),
+            DifficultyBounds {
+                max_increase: 4,
+                max_decrease: 4,
+            }
+        );
+        assert_eq!(consensus.min_difficulty(), U256::from(0x100));
+
+        let genesis = consensus.genesis_block();
+        assert_eq!(genesis.header().txs_commit(), genesis.cal_txs_commit());
+        assert_eq!(genesis.header().txs_proposal(), genesis.cal_txs_proposal());
+        assert_eq!(genesis.header().uncles_hash(), genesis.cal_uncles_hash());
+    }
+
+    #[test]
+    fn test_chain_spec_hash() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../nodes_template/spec/dev.json");
+        let dev = ChainSpec::read_from_file(&path).unwrap();
+        let hash = dev.hash().unwrap();
+        assert_eq!(
+            ChainSpec::read_from_file(&path).unwrap().hash().unwrap(),
+            hash
+        );
+
+        let mut other = dev.clone();
+        other.params.proposal_window.far += 1;
+        assert_ne!(other.hash().unwrap(), hash);
+
+        // the parameters left unset don't count, new ones don't move the hash of a chain
+        let mut params = dev.params.clone();
+        params.difficulty_bounds = DifficultyBounds::default();
+        params.max_block_bytes = None;
+        params.cellbase_maturity = None;
+        let expected: serde_json::Value = serde_json::from_str(
+            r#"{
+                "initial_block_reward": 50000,
+                "target_block_interval": 5000,
+                "hardfork": {
+                    "block_version_1": 0,
+                    "script_version_1": 0,
+                    "script_version_2": 0,
+                    "uncles_reward": 0,
+                    "cellbase_maturity": 0
+                },
+                "min_difficulty": "0x100"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(serde_json::to_value(&params).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_network_magic() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../nodes_template/spec/dev.json");
+        let dev = ChainSpec::read_from_file(&path).unwrap();
+        let magic = dev.network_magic().unwrap();
+
+        let mut spec = dev.clone();
+        spec.params.proposal_window.far += 1;
+        spec.params.max_block_cycles = Some(1_000);
+        assert_eq!(spec.network_magic().unwrap(), magic);
+
+        let mut spec = dev.clone();
+        spec.network_id += 1;
+        assert_ne!(spec.network_magic().unwrap(), magic);
+
+        let mut spec = dev.clone();
+        spec.genesis.timestamp += 1;
+        assert_ne!(spec.network_magic().unwrap(), magic);
+    }
+
+    #[test]
+    fn test_invalid_difficulty_params() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../nodes_template/spec/dev.json");
+        let dev = ChainSpec::read_from_file(&path).unwrap();
+
+        let mut spec = dev.clone();
+        spec.params.difficulty_bounds.max_decrease = 0;
+        assert!(spec.to_consensus().is_err());
+
+        let mut spec = dev.clone();
+        spec.params.min_difficulty = Some(U256::zero());
+        assert!(spec.to_consensus().is_err());
+    }
+
+    #[test]
+    fn test_size_params() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../nodes_template/spec/dev.json");
+        let dev = ChainSpec::read_from_file(&path).unwrap();
+        let consensus = dev.to_consensus().unwrap();
+        assert_eq!(consensus.max_block_bytes(), MAX_BLOCK_BYTES);
+        assert_eq!(consensus.max_tx_bytes(), MAX_TX_BYTES);
+
+        let mut spec = dev.clone();
+        spec.params.max_tx_bytes = Some(1000);
+        assert_eq!(spec.to_consensus().unwrap().max_tx_bytes(), 1000);
+
+        // a transaction must fit in a block
+        spec.params.max_block_bytes = Some(999);
+        assert!(spec.to_consensus().is_err());
+
+        let mut spec = dev.clone();
+        spec.params.max_tx_bytes = Some(0);
+        assert!(spec.to_consensus().is_err());
+    }
+
+    #[test]
+    fn test_cuckoo_params() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../nodes_template/spec/dev.json");
+        let dev = ChainSpec::read_from_file(&path).unwrap();
+        assert!(dev.to_consensus().is_ok());
+
+        // the edges of a proof are u32
+        let mut spec = dev.clone();
+        spec.pow =
+            serde_json::from_str(r#"{"Cuckoo": {"edge_bits": 32, "cycle_length": 12}}"#).unwrap();
+        assert!(spec.to_consensus().is_err());
+
+        // a cycle has an even length
+        spec.pow =
+            serde_json::from_str(r#"{"Cuckoo": {"edge_bits": 15, "cycle_length": 11}}"#).unwrap();
+        assert!(spec.to_consensus().is_err());
+    }
+
+    #[test]
+    fn test_data_size_params() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../nodes_template/spec/dev.json");
+        let dev = ChainSpec::read_from_file(&path).unwrap();
+        let consensus = dev.to_consensus().unwrap();
+        assert_eq!(consensus.max_cell_data_bytes(), MAX_CELL_DATA_BYTES);
+        assert_eq!(consensus.max_block_data_bytes(), MAX_BLOCK_DATA_BYTES);
+
+        let mut spec = dev.clone();
+        spec.params.max_cell_data_bytes = Some(1000);
+        assert_eq!(spec.to_consensus().unwrap().max_cell_data_bytes(), 1000);
+
+        // a cell must fit in the data budget of a block
+        spec.params.max_block_data_bytes = Some(999);
+        assert!(spec.to_consensus().is_err());
+
+        // the data budget is part of the block size
+        let mut spec = dev.clone();
+        spec.params.max_block_data_bytes = Some(MAX_BLOCK_BYTES + 1);
+        assert!(spec.to_consensus().is_err());
+
+        let mut spec = dev.clone();
+        spec.params.max_cell_data_bytes = Some(0);
+        assert!(spec.to_consensus().is_err());
+    }
+
+    #[test]
+    fn test_cycles_params() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../nodes_template/spec/dev.json");
+        let dev = ChainSpec::read_from_file(&path).unwrap();
+        let consensus = dev.to_consensus().unwrap();
+        assert_eq!(consensus.max_block_cycles(), MAX_BLOCK_CYCLES);
+
+        let mut spec = dev.clone();
+        spec.params.max_block_cycles = Some(1000);
+        assert_eq!(spec.to_consensus().unwrap().max_block_cycles(), 1000);
+
+        spec.params.max_block_cycles = Some(0);
+        assert!(spec.to_consensus().is_err());
+    }
+
+    #[test]
+    fn test_cellbase_maturity_param() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../nodes_template/spec/dev.json");
+        let dev = ChainSpec::read_from_file(&path).unwrap();
+        assert_eq!(
+            dev.to_consensus().unwrap().cellbase_maturity(),
+            CELLBASE_MATURITY
+        );
+
+        let mut spec = dev.clone();
+        spec.params.cellbase_maturity = Some(0);
+        assert_eq!(spec.to_consensus().unwrap().cellbase_maturity(), 0);
     }
 }
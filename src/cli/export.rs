@@ -1,3 +1,4 @@
+use super::super::helper::lock_data_dir;
 use super::super::setup::Setup;
 use ckb_db::diskdb::RocksDB;
 use ckb_instrument::{Export, Format};
@@ -10,12 +11,18 @@ pub fn export(setup: &Setup, matches: &ArgMatches) {
     let format = value_t!(matches.value_of("format"), Format).unwrap_or_else(|e| e.exit());
     let target = value_t!(matches.value_of("target"), String).unwrap_or_else(|e| e.exit());
 
+    let _dir_lock = lock_data_dir(&setup.dirs);
     let db_path = setup.dirs.join("db");
+    let spec_hash = setup
+        .chain_spec
+        .hash()
+        .unwrap_or_else(|e| panic!("Chain spec error {:?} ", e));
 
-    let shared = SharedBuilder::<ChainKVStore<CacheDB<RocksDB>>>::new_rocks(&db_path)
-        .consensus(setup.chain_spec.to_consensus().unwrap())
-        .build();
-    Export::new(shared, format, target.into())
+    let shared =
+        SharedBuilder::<ChainKVStore<CacheDB<RocksDB>>>::new_rocks(&db_path, &setup.configs.db)
+            .consensus(setup.chain_spec.to_consensus().unwrap())
+            .build();
+    Export::new(shared, format, target.into(), spec_hash)
         .execute()
         .unwrap_or_else(|e| panic!("Export error {:?} ", e));
 }
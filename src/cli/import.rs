@@ -1,3 +1,4 @@
+use super::super::helper::lock_data_dir;
 use super::super::setup::Setup;
 use ckb_chain::chain::{ChainBuilder, ChainController};
 use ckb_db::diskdb::RocksDB;
@@ -11,16 +12,28 @@ pub fn import(setup: &Setup, matches: &ArgMatches) {
     let format = value_t!(matches.value_of("format"), Format).unwrap_or_else(|e| e.exit());
     let source = value_t!(matches.value_of("source"), String).unwrap_or_else(|e| e.exit());
 
+    let _dir_lock = lock_data_dir(&setup.dirs);
     let db_path = setup.dirs.join("db");
+    let spec_hash = setup
+        .chain_spec
+        .hash()
+        .unwrap_or_else(|e| panic!("Chain spec error {:?} ", e));
 
-    let shared = SharedBuilder::<ChainKVStore<CacheDB<RocksDB>>>::new_rocks(&db_path)
-        .consensus(setup.chain_spec.to_consensus().unwrap())
-        .build();
+    let shared =
+        SharedBuilder::<ChainKVStore<CacheDB<RocksDB>>>::new_rocks(&db_path, &setup.configs.db)
+            .consensus(setup.chain_spec.to_consensus().unwrap())
+            .build();
     let (chain_controller, chain_receivers) = ChainController::new();
-    let chain_service = ChainBuilder::new(shared).build();
+    let mut config = setup.configs.chain.clone();
+    config.skip_script_verify = matches.is_present("no-script-verify");
+    if config.skip_script_verify {
+        warn!(target: "main", "Importing without verifying transaction scripts");
+    }
+
+    let chain_service = ChainBuilder::new(shared).config(config).build();
     let _handle = chain_service.start(Some("ImportChainService"), chain_receivers);
 
-    Import::new(chain_controller, format, source.into())
+    Import::new(chain_controller, format, source.into(), spec_hash)
         .execute()
         .unwrap_or_else(|e| panic!("Import error {:?} ", e));
 }
@@ -1,49 +1,71 @@
-use super::super::helper::wait_for_exit;
+use super::super::helper::{lock_data_dir, wait_for_exit};
+use super::super::reload::ConfigReloader;
 use super::super::Setup;
 use bigint::H256;
 use ckb_chain::chain::{ChainBuilder, ChainController};
+use ckb_chain_spec::consensus::Consensus;
 use ckb_core::script::Script;
 use ckb_core::transaction::{CellInput, OutPoint, Transaction, TransactionBuilder};
 use ckb_db::diskdb::RocksDB;
+#[cfg(feature = "miner")]
 use ckb_miner::MinerService;
-use ckb_network::CKBProtocol;
 use ckb_network::NetworkConfig;
 use ckb_network::NetworkService;
+use ckb_network::{CKBProtocol, CKBProtocolHandler};
 use ckb_notify::NotifyService;
 use ckb_pool::txs_pool::{TransactionPoolController, TransactionPoolService};
 use ckb_pow::PowEngine;
+#[cfg(feature = "grpc")]
+use ckb_rpc::grpc::GrpcServer;
 use ckb_rpc::{RpcController, RpcServer, RpcService};
+use ckb_script::{signing_message, CHAIN_ID_VERSION};
 use ckb_shared::cachedb::CacheDB;
 use ckb_shared::index::ChainIndex;
 use ckb_shared::shared::{ChainProvider, Shared, SharedBuilder};
 use ckb_shared::store::ChainKVStore;
-use ckb_sync::{Relayer, Synchronizer, RELAY_PROTOCOL_ID, SYNC_PROTOCOL_ID};
+use ckb_sync::{Relayer, SyncShared, Synchronizer, RELAY_PROTOCOL_ID, SYNC_PROTOCOL_ID};
 use clap::ArgMatches;
 use crypto::secp::{Generator, Privkey};
 use faster_hex::{hex_string, hex_to};
-use hash::sha3_256;
 use serde_json;
-use std::io::Write;
+use std::path::Path;
 use std::sync::Arc;
 use std::thread;
 
-pub fn run(setup: Setup) {
+/// In `light` mode, the node only syncs blocks and serves RPC queries.
+pub fn run(setup: Setup, config_path: &Path, light: bool) {
+    let _dir_lock = lock_data_dir(&setup.dirs);
+    // the running config, compared with the config file on reload
+    let configs = setup.configs.clone();
     let consensus = setup.chain_spec.to_consensus().unwrap();
-    let pow_engine = setup.chain_spec.pow_engine();
+    let pow_engine = consensus.pow_engine();
     let db_path = setup.dirs.join("db");
 
-    let shared = SharedBuilder::<ChainKVStore<CacheDB<RocksDB>>>::new_rocks(&db_path)
-        .consensus(consensus)
-        .build();
+    let shared =
+        SharedBuilder::<ChainKVStore<CacheDB<RocksDB>>>::new_rocks(&db_path, &setup.configs.db)
+            .consensus(consensus)
+            .build();
 
     let (_handle, notify) = NotifyService::default().start(Some("notify"));
     let (chain_controller, chain_receivers) = ChainController::new();
     let (tx_pool_controller, tx_pool_receivers) = TransactionPoolController::new();
     let (rpc_controller, rpc_receivers) = RpcController::new();
 
+    if light {
+        info!(target: "main", "Start in light mode, blocks are neither mined nor relayed");
+    }
     let chain_service = ChainBuilder::new(shared.clone())
         .notify(notify.clone())
+        .config(setup.configs.chain)
         .build();
+    chain_service
+        .verify_genesis(shared.consensus().genesis_block())
+        .unwrap_or_else(|e| {
+            panic!(
+                "Genesis verification error {:?}, the database may come from another chain spec",
+                e
+            )
+        });
     let _handle = chain_service.start(Some("ChainService"), chain_receivers);
 
     info!(target: "main", "chain genesis hash: {:?}", shared.genesis_hash());
@@ -52,55 +74,78 @@ pub fn run(setup: Setup) {
         TransactionPoolService::new(setup.configs.pool, shared.clone(), notify.clone());
     let _handle = tx_pool_service.start(Some("TransactionPoolService"), tx_pool_receivers);
 
-    let rpc_service = RpcService::new(shared.clone(), tx_pool_controller.clone());
+    let rpc_service = RpcService::new(
+        shared.clone(),
+        tx_pool_controller.clone(),
+        setup.configs.rpc.block_assembler.clone(),
+    );
     let _handle = rpc_service.start(Some("RpcService"), rpc_receivers, &notify);
 
-    let synchronizer = Arc::new(Synchronizer::new(
-        chain_controller.clone(),
-        shared.clone(),
-        setup.configs.sync,
-    ));
+    // kept until exit, dropping the server stops it
+    #[cfg(feature = "grpc")]
+    let _grpc_server = setup
+        .configs
+        .rpc
+        .grpc_listen_addr
+        .clone()
+        .map(|listen_addr| {
+            GrpcServer { listen_addr }.start(shared.clone(), tx_pool_controller.clone(), &notify)
+        });
 
-    let relayer = Arc::new(Relayer::new(
+    // the relayer and the synchronizer decide over the same peers and blocks
+    let sync_shared = SyncShared::new(
         chain_controller.clone(),
         shared.clone(),
-        tx_pool_controller.clone(),
-    ));
+        &setup.configs.sync,
+    );
+    let header_first_relay = setup.configs.sync.header_first_relay;
+    let synchronizer = Arc::new(Synchronizer::new(sync_shared.clone(), setup.configs.sync));
 
-    let network_config = NetworkConfig::from(setup.configs.network);
-    let protocol_base_name = "ckb";
-    let protocols = vec![
-        CKBProtocol::new(
-            protocol_base_name.to_string(),
-            synchronizer as Arc<_>,
-            SYNC_PROTOCOL_ID,
-            &[1][..],
-        ),
-        CKBProtocol::new(
-            protocol_base_name.to_string(),
-            relayer as Arc<_>,
-            RELAY_PROTOCOL_ID,
-            &[1][..],
-        ),
-    ];
+    let mut network_config = NetworkConfig::from(setup.configs.network);
+    // nodes of other chains can't exchange messages with us even if they connect
+    network_config.magic = setup
+        .chain_spec
+        .network_magic()
+        .unwrap_or_else(|e| panic!("Chain spec error {:?}", e));
+    let protocols = protocols(light, synchronizer.clone() as Arc<_>, || {
+        Arc::new(Relayer::new(
+            sync_shared,
+            tx_pool_controller.clone(),
+            header_first_relay,
+        )) as Arc<_>
+    });
     let network = Arc::new(
         NetworkService::run_in_thread(&network_config, protocols)
             .expect("Create and start network"),
     );
 
-    let miner_service = MinerService::new(
-        setup.configs.miner,
-        Arc::clone(&pow_engine),
-        &shared,
-        chain_controller.clone(),
-        rpc_controller.clone(),
-        Arc::clone(&network),
-        &notify,
-    );
-    let _handle = miner_service.start(Some("MinerService"));
+    let _handle = ConfigReloader::new(config_path, configs, Arc::clone(&network))
+        .start()
+        .expect("Start ConfigReloader failed");
+
+    #[cfg(feature = "miner")]
+    {
+        if !light {
+            let miner_service = MinerService::new(
+                setup.configs.miner,
+                Arc::clone(&pow_engine),
+                &shared,
+                chain_controller.clone(),
+                rpc_controller.clone(),
+                Arc::clone(&network),
+                &notify,
+            );
+            let _handle = miner_service.start(Some("MinerService"));
+        }
+    }
 
+    let spec_hash = setup
+        .chain_spec
+        .hash()
+        .unwrap_or_else(|e| panic!("Chain spec error {:?}", e));
     let rpc_server = RpcServer {
         config: setup.configs.rpc,
+        spec_hash,
     };
 
     setup_rpc(
@@ -117,6 +162,34 @@ pub fn run(setup: Setup) {
     info!(target: "main", "Finishing work, please wait...");
 }
 
+/// The network protocols of the node. A light node neither relays blocks nor transactions, it
+/// only downloads blocks by sync, the relayer is only built for a full node.
+fn protocols<F>(
+    light: bool,
+    synchronizer: Arc<CKBProtocolHandler>,
+    relayer: F,
+) -> Vec<CKBProtocol<Arc<CKBProtocolHandler>>>
+where
+    F: FnOnce() -> Arc<CKBProtocolHandler>,
+{
+    let protocol_base_name = "ckb";
+    let mut protocols = vec![CKBProtocol::new(
+        protocol_base_name.to_string(),
+        synchronizer,
+        SYNC_PROTOCOL_ID,
+        &[1][..],
+    )];
+    if !light {
+        protocols.push(CKBProtocol::new(
+            protocol_base_name.to_string(),
+            relayer(),
+            RELAY_PROTOCOL_ID,
+            &[1][..],
+        ));
+    }
+    protocols
+}
+
 #[cfg(feature = "integration_test")]
 fn setup_rpc<CI: ChainIndex + 'static>(
     server: RpcServer,
@@ -159,8 +232,20 @@ fn setup_rpc<CI: ChainIndex + 'static>(
     });
 }
 
+// The system lock scripts of the wallet commands bind their signatures to the chain when the
+// spec enables it from the genesis, older chains keep the version 0 scripts
+fn system_script_version(consensus: &Consensus) -> u8 {
+    if consensus.hardfork_switch().is_script_version_2_enabled(0) {
+        CHAIN_ID_VERSION
+    } else {
+        0
+    }
+}
+
 pub fn sign(setup: &Setup, matches: &ArgMatches) {
     let consensus = setup.chain_spec.to_consensus().unwrap();
+    let version = system_script_version(&consensus);
+    let chain_id = consensus.chain_id();
     let system_cell_tx = &consensus.genesis_block().commit_transactions()[0];
     let system_cell_data_hash = system_cell_tx.outputs()[0].data_hash();
     let system_cell_tx_hash = system_cell_tx.hash();
@@ -175,13 +260,8 @@ pub fn sign(setup: &Setup, matches: &ArgMatches) {
     let transaction: Transaction = serde_json::from_str(&json).unwrap();
     let mut inputs = Vec::new();
     for unsigned_input in transaction.inputs() {
-        let mut bytes = vec![];
-        for argument in &unsigned_input.unlock.args {
-            bytes.write_all(argument).unwrap();
-        }
-        let hash1 = sha3_256(&bytes);
-        let hash2 = sha3_256(hash1);
-        let signature = privkey.sign_recoverable(&hash2.into()).unwrap();
+        let message = signing_message(version, &unsigned_input.unlock.args, &chain_id);
+        let signature = privkey.sign_recoverable(&message).unwrap();
         let signature_der = signature.serialize_der();
         let mut hex_signature = vec![0; signature_der.len() * 2];
         hex_to(&signature_der, &mut hex_signature).expect("hex signature");
@@ -193,7 +273,7 @@ pub fn sign(setup: &Setup, matches: &ArgMatches) {
         let mut hex_pubkey = vec![0; pubkey_ser.len() * 2];
         hex_to(&pubkey_ser, &mut hex_pubkey).expect("hex pubkey");
         let script = Script::new(
-            0,
+            version,
             new_args,
             Some(system_cell_data_hash),
             None,
@@ -229,7 +309,7 @@ pub fn type_hash(setup: &Setup, matches: &ArgMatches) {
     hex_to(&pubkey_ser, &mut hex_pubkey).expect("hex pubkey");
 
     let script = Script::new(
-        0,
+        system_script_version(&consensus),
         Vec::new(),
         Some(system_cell_data_hash),
         None,
@@ -242,3 +322,43 @@ pub fn keygen() {
     let result: H256 = Generator::new().random_privkey().into();
     println!("{:?}", result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_network::{CKBProtocolContext, PeerIndex, ProtocolId};
+
+    struct DummyHandler;
+
+    impl CKBProtocolHandler for DummyHandler {
+        fn initialize(&self, _nc: Box<CKBProtocolContext>) {}
+        fn received(&self, _nc: Box<CKBProtocolContext>, _peer: PeerIndex, _data: &[u8]) {}
+        fn connected(&self, _nc: Box<CKBProtocolContext>, _peer: PeerIndex) {}
+        fn disconnected(&self, _nc: Box<CKBProtocolContext>, _peer: PeerIndex) {}
+    }
+
+    fn protocol_ids(light: bool) -> (Vec<ProtocolId>, bool) {
+        let mut relayer_built = false;
+        let ids = protocols(light, Arc::new(DummyHandler), || {
+            relayer_built = true;
+            Arc::new(DummyHandler)
+        }).iter()
+        .map(|protocol| protocol.id())
+        .collect();
+        (ids, relayer_built)
+    }
+
+    #[test]
+    fn test_full_node_protocols() {
+        assert_eq!(
+            protocol_ids(false),
+            (vec![SYNC_PROTOCOL_ID, RELAY_PROTOCOL_ID], true)
+        );
+    }
+
+    #[test]
+    fn test_light_node_protocols() {
+        // a light node only syncs, it doesn't even build the relayer
+        assert_eq!(protocol_ids(true), (vec![SYNC_PROTOCOL_ID], false));
+    }
+}
@@ -1,5 +1,8 @@
 use ckb_util::{Condvar, Mutex};
 use ctrlc;
+use dir::{DirLock, Directories};
+#[cfg(unix)]
+use nix::sys::signal::{SigSet, Signal};
 use std::sync::Arc;
 
 pub fn wait_for_exit() {
@@ -15,3 +18,37 @@ pub fn wait_for_exit() {
     let mut l = exit.0.lock();
     exit.1.wait(&mut l);
 }
+
+/// Holds the data dir lock until dropped, exits if another node uses the same data dir
+pub fn lock_data_dir(dirs: &Directories) -> DirLock {
+    dirs.lock().unwrap_or_else(|err| {
+        eprintln!("Lock data dir failed: {}", err);
+        ::std::process::exit(1);
+    })
+}
+
+/// Blocks SIGHUP in the calling thread and the threads it spawns, the signal is only received
+/// by `wait_for_reload`.
+#[cfg(unix)]
+pub fn block_reload_signal() {
+    reload_signal()
+        .thread_block()
+        .expect("Block SIGHUP for config reload");
+}
+
+#[cfg(not(unix))]
+pub fn block_reload_signal() {}
+
+/// Waits until the node is asked to reload its config
+#[cfg(unix)]
+pub fn wait_for_reload() {
+    let signals = reload_signal();
+    while signals.wait().is_err() {}
+}
+
+#[cfg(unix)]
+fn reload_signal() -> SigSet {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGHUP);
+    signals
+}
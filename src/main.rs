@@ -9,15 +9,16 @@ extern crate ckb_chain;
 extern crate ckb_chain_spec;
 extern crate ckb_core;
 extern crate ckb_db;
+#[cfg(feature = "miner")]
 extern crate ckb_miner;
 extern crate ckb_network;
 extern crate ckb_notify;
 extern crate ckb_pool;
 extern crate ckb_rpc;
+extern crate ckb_script;
 extern crate ckb_shared;
 extern crate ckb_sync;
 extern crate ckb_util;
-extern crate hash;
 extern crate logger;
 #[macro_use]
 extern crate serde_derive;
@@ -28,12 +29,15 @@ extern crate ckb_pow;
 extern crate config as config_tool;
 extern crate crypto;
 extern crate faster_hex;
+#[cfg(unix)]
+extern crate nix;
 extern crate serde_json;
 #[cfg(test)]
 extern crate tempfile;
 
 mod cli;
 mod helper;
+mod reload;
 mod setup;
 
 use build_info::Version;
@@ -50,6 +54,9 @@ fn main() {
         .long_version(version.long().as_str())
         .get_matches();
 
+    // Must happen before any thread is spawned, see `reload`
+    helper::block_reload_signal();
+
     let config_path = get_config_path(&matches);
     let setup = match Setup::setup(&config_path) {
         Ok(setup) => {
@@ -73,9 +80,9 @@ fn main() {
             ("keygen", _) => cli::keygen(),
             _ => unreachable!(),
         },
-        ("run", Some(_)) => {
+        ("run", Some(run_matches)) => {
             info!(target: "main", "Start with config {}", config_path.display());
-            cli::run(setup);
+            cli::run(setup, &config_path, run_matches.is_present("light"));
         }
         ("export", Some(export_matches)) => cli::export(&setup, export_matches),
         ("import", Some(import_matches)) => cli::import(&setup, import_matches),
@@ -0,0 +1,167 @@
+//! Reloads the config of a running node on SIGHUP.
+//!
+//! Only the log filter and the peer limits are applied at runtime, the changes of every other
+//! setting are rejected until the node is restarted.
+
+use ckb_network::NetworkService;
+use logger;
+use setup::{Configs, Setup};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+pub struct ConfigReloader {
+    config_path: PathBuf,
+    configs: Configs,
+    network: Arc<NetworkService>,
+}
+
+impl ConfigReloader {
+    pub fn new<P: AsRef<Path>>(
+        config_path: P,
+        configs: Configs,
+        network: Arc<NetworkService>,
+    ) -> Self {
+        ConfigReloader {
+            config_path: config_path.as_ref().to_path_buf(),
+            configs,
+            network,
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn start(mut self) -> io::Result<thread::JoinHandle<()>> {
+        use helper::wait_for_reload;
+
+        thread::Builder::new()
+            .name("ConfigReloader".to_string())
+            .spawn(move || loop {
+                wait_for_reload();
+                info!(target: "main", "Reload config {}", self.config_path.display());
+                match Setup::setup(&self.config_path) {
+                    Ok(setup) => self.apply(setup.configs),
+                    Err(err) => {
+                        error!(target: "main", "Reload config failed, keep the running config: {:?}", err)
+                    }
+                }
+            })
+    }
+
+    // There is no SIGHUP, the config is only read at startup
+    #[cfg(not(unix))]
+    pub fn start(self) -> io::Result<thread::JoinHandle<()>> {
+        thread::Builder::new()
+            .name("ConfigReloader".to_string())
+            .spawn(|| ())
+    }
+
+    fn apply(&mut self, configs: Configs) {
+        for setting in restart_required(&self.configs, &configs) {
+            warn!(target: "main", "Config {} changed, it requires a restart and is not applied", setting);
+        }
+
+        if self.configs.logger.filter != configs.logger.filter {
+            info!(target: "main", "Config logger.filter: {:?} -> {:?}", self.configs.logger.filter, configs.logger.filter);
+            logger::reload_filter(configs.logger.filter.as_ref().map(String::as_str));
+            self.configs.logger.filter = configs.logger.filter;
+        }
+
+        {
+            let (old, new) = (&self.configs.network, &configs.network);
+            if old.max_incoming_peers() != new.max_incoming_peers()
+                || old.max_outgoing_peers() != new.max_outgoing_peers()
+            {
+                info!(
+                    target: "main", "Config network peer limits: incoming {} -> {}, outgoing {} -> {}",
+                    old.max_incoming_peers(), new.max_incoming_peers(),
+                    old.max_outgoing_peers(), new.max_outgoing_peers()
+                );
+                self.network
+                    .set_max_peers(new.max_incoming_peers(), new.max_outgoing_peers());
+            }
+        }
+        self.configs.network.max_peers = configs.network.max_peers;
+        self.configs.network.outgoing_peers_ratio = configs.network.outgoing_peers_ratio;
+    }
+}
+
+/// The changed settings which can't be applied to a running node
+fn restart_required(old: &Configs, new: &Configs) -> Vec<&'static str> {
+    let mut settings = Vec::new();
+    if old.data_dir != new.data_dir {
+        settings.push("data_dir");
+    }
+    if old.ckb != new.ckb {
+        settings.push("ckb");
+    }
+    if old.logger.file != new.logger.file {
+        settings.push("logger.file");
+    }
+    if old.logger.color != new.logger.color {
+        settings.push("logger.color");
+    }
+    if old.chain != new.chain {
+        settings.push("chain");
+    }
+    if old.db != new.db {
+        settings.push("db");
+    }
+    // everything but the peer limits
+    let mut network = new.network.clone();
+    network.max_peers = old.network.max_peers;
+    network.outgoing_peers_ratio = old.network.outgoing_peers_ratio;
+    if old.network != network {
+        settings.push("network");
+    }
+    if old.rpc != new.rpc {
+        settings.push("rpc");
+    }
+    #[cfg(feature = "miner")]
+    {
+        if old.miner != new.miner {
+            settings.push("miner");
+        }
+    }
+    if old.sync != new.sync {
+        settings.push("sync");
+    }
+    if old.pool != new.pool {
+        settings.push("pool");
+    }
+    settings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn default_configs() -> Configs {
+        let config_path =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("nodes_template/default.json");
+        Setup::setup(config_path).unwrap().configs
+    }
+
+    #[test]
+    fn test_runtime_settings() {
+        let old = default_configs();
+        let mut new = old.clone();
+        new.logger.filter = Some("debug".to_string());
+        new.network.max_peers += 1;
+        new.network.outgoing_peers_ratio = Some(2);
+        assert!(restart_required(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn test_restart_required() {
+        let old = default_configs();
+        let mut new = old.clone();
+        new.logger.color = !old.logger.color;
+        new.network.boot_nodes.push("/ip4/1.1.1.1/tcp/1".to_string());
+        new.pool.max_pool_size += 1;
+        assert_eq!(
+            restart_required(&old, &new),
+            vec!["logger.color", "network", "pool"]
+        );
+    }
+}
@@ -1,4 +1,7 @@
+use ckb_chain::Config as ChainConfig;
 use ckb_chain_spec::ChainSpec;
+use ckb_db::DBConfig;
+#[cfg(feature = "miner")]
 use ckb_miner::Config as MinerConfig;
 use ckb_network::Config as NetworkConfig;
 use ckb_pool::txs_pool::PoolConfig;
@@ -20,18 +23,23 @@ pub struct Setup {
     pub dirs: Directories,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct CKB {
     pub chain: PathBuf,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct Configs {
     pub data_dir: PathBuf,
     pub ckb: CKB,
     pub logger: LogConfig,
+    #[serde(default)]
+    pub chain: ChainConfig,
+    #[serde(default)]
+    pub db: DBConfig,
     pub network: NetworkConfig,
     pub rpc: RpcConfig,
+    #[cfg(feature = "miner")]
     pub miner: MinerConfig,
     pub sync: SyncConfig,
     pub pool: PoolConfig,
@@ -57,13 +65,21 @@ impl Setup {
         if let Some(file) = configs.logger.file {
             let mut path = dirs.join("logs");
             path.push(file);
-            configs.logger.file = Some(path.to_str().unwrap().to_string());
+            configs.logger.file = Some(path.to_string_lossy().to_string());
         }
         if configs.network.config_dir_path.is_none() {
             configs.network.config_dir_path =
                 Some(dirs.join("network").to_string_lossy().to_string());
         }
 
+        if configs.chain.quarantine {
+            configs.chain.quarantine_dir = Some(dirs.join("quarantine"));
+        }
+        if configs.chain.script_profile {
+            configs.chain.script_profile_path = Some(dirs.join("script_profile.json"));
+        }
+
+        configs.rpc.validate()?;
         let chain_spec = ChainSpec::read_from_file(&configs.ckb.chain)?;
 
         Ok(Setup {
@@ -232,4 +248,37 @@ pub mod test {
         assert!(setup.is_ok());
         assert_eq!(setup.unwrap().chain_spec.name, "ckb_test_custom");
     }
+
+    #[test]
+    fn test_custom_chain_spec_without_txs_roots() {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("test_custom_chain_spec_without_txs_roots")
+            .tempdir()
+            .unwrap();
+
+        // the roots of the genesis are computed from its body, the spec may leave them out
+        let chain_spec = test_chain_spec()
+            .lines()
+            .filter(|line| !line.contains("txs_commit") && !line.contains("txs_proposal"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let chain_spec_path = tmp_dir.path().join("ckb_test_custom.json");
+        let test_conifg = format!(
+            r#"
+        {{
+            "ckb": {{
+                "chain": "{}"
+            }}
+        }}"#,
+            chain_spec_path.to_str().unwrap()
+        );
+
+        let config_path = tmp_dir.path().join("config.json");
+        write_file(&config_path, &test_conifg);
+        write_file(&chain_spec_path, &chain_spec);
+
+        let setup = override_default_config_file(&config_path);
+        assert!(setup.is_ok());
+        assert_eq!(setup.unwrap().chain_spec.name, "ckb_test_custom");
+    }
 }
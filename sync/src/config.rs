@@ -1,12 +1,31 @@
-#[derive(Clone, Debug, Deserialize)]
+use ckb_core::BlockNumber;
+
+/// Default of `Config::long_range_fork_depth`
+pub const LONG_RANGE_FORK_DEPTH: BlockNumber = 100;
+
+fn default_long_range_fork_depth() -> BlockNumber {
+    LONG_RANGE_FORK_DEPTH
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct Config {
     pub orphan_block_limit: usize,
+    /// Ask the peers to announce new blocks with their headers only, we download the blocks by
+    /// sync. Saves the bandwidth of duplicate pushes at the cost of a round trip per block
+    #[serde(default)]
+    pub header_first_relay: bool,
+    /// Valid headers of a peer forking from our chain deeper than this many blocks below our
+    /// tip raise an alert, a sign of a network split or a 51% attack
+    #[serde(default = "default_long_range_fork_depth")]
+    pub long_range_fork_depth: BlockNumber,
 }
 
 impl Config {
     pub fn default() -> Self {
         Config {
             orphan_block_limit: 1024,
+            header_first_relay: false,
+            long_range_fork_depth: LONG_RANGE_FORK_DEPTH,
         }
     }
 }
@@ -0,0 +1,97 @@
+use bigint::H256;
+use ckb_util::Mutex;
+use fnv::FnvHashSet;
+use std::sync::Arc;
+
+/// Blocks currently handed over to the chain by either the relayer or the synchronizer.
+///
+/// The same block may arrive by compact block relay and by sync download at the same time,
+/// whichever path marks it first processes it and the other one skips it.
+#[derive(Clone, Default)]
+pub struct InFlightBlocks {
+    inner: Arc<Mutex<FnvHashSet<H256>>>,
+}
+
+impl InFlightBlocks {
+    /// Returns `None` if the block is already being processed, the mark is cleared
+    /// once the returned guard is dropped.
+    pub fn begin(&self, hash: H256) -> Option<InFlightBlock> {
+        if self.inner.lock().insert(hash) {
+            Some(InFlightBlock {
+                hash,
+                blocks: self.clone(),
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.inner.lock().contains(hash)
+    }
+}
+
+pub struct InFlightBlock {
+    hash: H256,
+    blocks: InFlightBlocks,
+}
+
+impl Drop for InFlightBlock {
+    fn drop(&mut self) {
+        self.blocks.inner.lock().remove(&self.hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+    use std::thread;
+
+    #[test]
+    fn test_begin_once() {
+        let blocks = InFlightBlocks::default();
+        let hash = H256::from(1);
+
+        let guard = blocks.begin(hash);
+        assert!(guard.is_some());
+        assert!(blocks.contains(&hash));
+        assert!(blocks.begin(hash).is_none());
+
+        drop(guard);
+        assert!(!blocks.contains(&hash));
+        assert!(blocks.begin(hash).is_some());
+    }
+
+    #[test]
+    fn test_begin_race() {
+        let blocks = InFlightBlocks::default();
+        let barrier = Arc::new(Barrier::new(2));
+        let started = Arc::new(AtomicUsize::new(0));
+        let finished = Arc::new(Barrier::new(3));
+
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let blocks = blocks.clone();
+                let barrier = Arc::clone(&barrier);
+                let started = Arc::clone(&started);
+                let finished = Arc::clone(&finished);
+                thread::spawn(move || {
+                    barrier.wait();
+                    let guard = blocks.begin(H256::from(1));
+                    if guard.is_some() {
+                        started.fetch_add(1, Ordering::SeqCst);
+                    }
+                    // hold the guard until both threads tried
+                    finished.wait();
+                })
+            }).collect();
+
+        finished.wait();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(started.load(Ordering::SeqCst), 1);
+    }
+}
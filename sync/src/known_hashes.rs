@@ -0,0 +1,95 @@
+use bigint::H256;
+use ckb_network::PeerIndex;
+use fnv::{FnvHashMap, FnvHashSet};
+use std::collections::VecDeque;
+use ANNOUNCED_BLOCKS_WINDOW;
+
+/// Hashes known to each peer, either sent by the peer or sent to it, like the announced
+/// compact blocks and the relayed transactions.
+///
+/// Only the last `window` hashes are kept per peer, older hashes fall out of the window
+/// and are treated as unknown again.
+pub struct KnownHashes {
+    window: usize,
+    peers: FnvHashMap<PeerIndex, PeerWindow>,
+}
+
+impl Default for KnownHashes {
+    fn default() -> Self {
+        KnownHashes::new(ANNOUNCED_BLOCKS_WINDOW)
+    }
+}
+
+#[derive(Default)]
+struct PeerWindow {
+    order: VecDeque<H256>,
+    hashes: FnvHashSet<H256>,
+}
+
+impl KnownHashes {
+    pub fn new(window: usize) -> Self {
+        KnownHashes {
+            window,
+            peers: FnvHashMap::default(),
+        }
+    }
+
+    /// Marks the hash as known to the peer, returns `false` if it already was.
+    pub fn insert(&mut self, peer: PeerIndex, hash: H256) -> bool {
+        let known = self.peers.entry(peer).or_insert_with(PeerWindow::default);
+        if !known.hashes.insert(hash) {
+            return false;
+        }
+        known.order.push_back(hash);
+        if known.order.len() > self.window {
+            if let Some(oldest) = known.order.pop_front() {
+                known.hashes.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn contains(&self, peer: PeerIndex, hash: &H256) -> bool {
+        self.peers
+            .get(&peer)
+            .map_or(false, |known| known.hashes.contains(hash))
+    }
+
+    pub fn remove_peer(&mut self, peer: PeerIndex) {
+        self.peers.remove(&peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_once_per_peer() {
+        let mut known = KnownHashes::new(4);
+        assert!(known.insert(1, H256::from(1)));
+        assert!(!known.insert(1, H256::from(1)));
+        assert!(known.insert(2, H256::from(1)));
+        assert!(known.contains(1, &H256::from(1)));
+        assert!(!known.contains(3, &H256::from(1)));
+
+        known.remove_peer(1);
+        assert!(!known.contains(1, &H256::from(1)));
+        assert!(known.contains(2, &H256::from(1)));
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let mut known = KnownHashes::new(2);
+        known.insert(1, H256::from(1));
+        known.insert(1, H256::from(2));
+        // a duplicate doesn't move the window
+        known.insert(1, H256::from(1));
+        known.insert(1, H256::from(3));
+
+        assert!(!known.contains(1, &H256::from(1)));
+        assert!(known.contains(1, &H256::from(2)));
+        assert!(known.contains(1, &H256::from(3)));
+        assert!(known.insert(1, H256::from(1)));
+    }
+}
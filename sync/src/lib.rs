@@ -23,23 +23,32 @@ extern crate bitflags;
 #[macro_use]
 extern crate serde_derive;
 extern crate ckb_chain_spec;
+extern crate ckb_notify;
 #[cfg(test)]
 extern crate ckb_db;
 #[cfg(test)]
-extern crate ckb_notify;
-#[cfg(test)]
 extern crate crossbeam_channel;
 
 mod config;
+mod in_flight_blocks;
+mod known_hashes;
+mod orphan_block_pool;
+mod peer_scores;
 mod relayer;
+mod sync_shared;
 mod synchronizer;
 
 #[cfg(test)]
 mod tests;
 
 pub use config::Config;
-pub use relayer::Relayer;
+pub use in_flight_blocks::InFlightBlocks;
+pub use orphan_block_pool::OrphanBlockPool;
+pub use peer_scores::PeerScores;
+pub use relayer::{announce_order, Relayer};
+pub use sync_shared::SyncShared;
 pub use synchronizer::Synchronizer;
+pub use ckb_notify::MAX_TIP_AGE;
 
 use ckb_network::ProtocolId;
 
@@ -47,9 +56,16 @@ pub const MAX_HEADERS_LEN: usize = 2_000;
 pub const MAX_INVENTORY_LEN: usize = 50_000;
 pub const MAX_SCHEDULED_LEN: usize = 4 * 1024;
 pub const MAX_BLOCKS_TO_ANNOUNCE: usize = 8;
+/// The blocks we mine are announced to this many of the fastest peers first
+pub const FAST_ANNOUNCE_PEERS: usize = 4;
+/// Milliseconds the other peers wait for the announcement of a block we mine
+pub const ANNOUNCE_STAGGER: u64 = 100;
+/// Block hashes remembered per peer to skip duplicate announcements
+pub const ANNOUNCED_BLOCKS_WINDOW: usize = 64;
+/// Transaction hashes remembered per peer to skip relaying them back
+pub const KNOWN_TXS_WINDOW: usize = 1024;
 pub const MAX_UNCONNECTING_HEADERS: usize = 10;
 pub const MAX_BLOCKS_IN_TRANSIT_PER_PEER: usize = 16;
-pub const MAX_TIP_AGE: u64 = 60 * 60 * 1000;
 pub const STALE_RELAY_AGE_LIMIT: u64 = 30 * 24 * 60 * 60 * 1000;
 pub const BLOCK_DOWNLOAD_WINDOW: u64 = 1024;
 pub const PER_FETCH_BLOCK_LIMIT: usize = 128;
@@ -71,3 +87,25 @@ pub const EVICTION_HEADERS_RESPONSE_TIME: u64 = 120 * 1000; // 2 minutes
 pub const MAX_LOCATOR_SIZE: usize = 101;
 
 pub const BLOCK_DOWNLOAD_TIMEOUT: u64 = 30 * 1000; // 30s
+
+// Download headers from at most this many peers at once during the initial
+// block download, the block bodies are fetched from every peer.
+pub const MAX_HEADERS_SYNC_PEERS: usize = 3;
+// A getheaders without response frees its headers sync slot after this time.
+pub const HEADERS_RESPONSE_TIMEOUT: u64 = 30 * 1000; // 30s
+
+// A peer is banned once its misbehavior penalties add up to this score.
+pub const PEER_BAN_SCORE: u32 = 100;
+pub const PEER_BAN_TIME: u64 = 24 * 60 * 60 * 1000; // 24 hours
+
+// Undecodable messages are never sent by honest peers.
+pub const MALFORMED_MESSAGE_PENALTY: u32 = 100;
+// The header of a relayed compact block failed the verification.
+pub const INVALID_COMPACT_BLOCK_PENALTY: u32 = 50;
+// Transactions of a compact block we didn't ask for, or asked for already.
+pub const UNSOLICITED_BLOCK_TRANSACTIONS_PENALTY: u32 = 20;
+// Headers more than MAX_HEADERS_LEN or not chained to each other.
+pub const MALFORMED_HEADERS_PENALTY: u32 = 20;
+
+// GetBlockTransactions sent for a compact block before the whole block is downloaded instead.
+pub const MAX_BLOCK_TRANSACTIONS_ROUND_TRIPS: usize = 2;
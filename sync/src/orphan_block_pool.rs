@@ -0,0 +1,212 @@
+use bigint::H256;
+use ckb_core::block::Block;
+use ckb_time::now_ms;
+use ckb_util::RwLock;
+use fnv::{FnvHashMap, FnvHashSet};
+use std::collections::hash_map::Entry;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+pub type ParentHash = H256;
+
+/// Orphans cached for longer are dropped, their parents aren't coming
+pub const ORPHAN_BLOCK_EXPIRY_MS: u64 = 10 * 60 * 1000;
+
+/// Blocks received before their parents, shared by the relayer and the synchronizer.
+///
+/// Orphans are keyed by parent hash, once a block is connected every orphan descending
+/// from it is taken out and processed again. The pool holds a limited number of blocks, each
+/// for `ORPHAN_BLOCK_EXPIRY_MS` at most.
+#[derive(Clone)]
+pub struct OrphanBlockPool {
+    inner: Arc<RwLock<InnerPool>>,
+}
+
+struct InnerPool {
+    blocks: FnvHashMap<ParentHash, FnvHashSet<Block>>,
+    // the parent hash and the insertion time of every cached block
+    hashes: FnvHashMap<H256, (ParentHash, u64)>,
+    limit: usize,
+}
+
+impl InnerPool {
+    fn remove_expired(&mut self, now: u64) {
+        let expired: Vec<(H256, ParentHash)> = self
+            .hashes
+            .iter()
+            .filter(|&(_, &(_, inserted))| inserted + ORPHAN_BLOCK_EXPIRY_MS <= now)
+            .map(|(hash, &(parent_hash, _))| (*hash, parent_hash))
+            .collect();
+        for (hash, parent_hash) in expired {
+            self.hashes.remove(&hash);
+            if let Entry::Occupied(mut entry) = self.blocks.entry(parent_hash) {
+                entry
+                    .get_mut()
+                    .retain(|block| block.header().hash() != hash);
+                if entry.get().is_empty() {
+                    entry.remove();
+                }
+            }
+        }
+    }
+}
+
+impl OrphanBlockPool {
+    pub fn with_capacity(capacity: usize) -> Self {
+        OrphanBlockPool {
+            inner: Arc::new(RwLock::new(InnerPool {
+                blocks: FnvHashMap::with_capacity_and_hasher(capacity, Default::default()),
+                hashes: FnvHashMap::default(),
+                limit: capacity,
+            })),
+        }
+    }
+
+    /// Insert orphaned block, for which we have already requested its parent block.
+    ///
+    /// Returns `false` if the block is already cached or the pool is full of unexpired blocks.
+    pub fn insert(&self, block: Block) -> bool {
+        let mut guard = self.inner.write();
+        let now = now_ms();
+        guard.remove_expired(now);
+        let hash = block.header().hash();
+        if guard.hashes.len() >= guard.limit || guard.hashes.contains_key(&hash) {
+            return false;
+        }
+        let parent_hash = block.header().parent_hash();
+        guard.hashes.insert(hash, (parent_hash, now));
+        guard
+            .blocks
+            .entry(parent_hash)
+            .or_insert_with(FnvHashSet::default)
+            .insert(block);
+        true
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.inner.read().hashes.contains_key(hash)
+    }
+
+    /// Removes the descendants of the block, parents always come before their children.
+    pub fn remove_blocks_by_parent(&self, hash: &H256) -> VecDeque<Block> {
+        let mut guard = self.inner.write();
+        let inner = &mut *guard;
+        let mut queue: VecDeque<H256> = VecDeque::new();
+        queue.push_back(*hash);
+
+        let mut removed: VecDeque<Block> = VecDeque::new();
+        while let Some(parent_hash) = queue.pop_front() {
+            if let Entry::Occupied(entry) = inner.blocks.entry(parent_hash) {
+                let (_, orphaned) = entry.remove_entry();
+                for block in orphaned {
+                    let hash = block.header().hash();
+                    inner.hashes.remove(&hash);
+                    queue.push_back(hash);
+                    removed.push_back(block);
+                }
+            }
+        }
+        removed
+    }
+
+    /// Number of cached blocks
+    pub fn len(&self) -> usize {
+        self.inner.read().hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_chain_spec::consensus::Consensus;
+    use ckb_core::block::BlockBuilder;
+    use ckb_core::header::{Header, HeaderBuilder};
+    use ckb_time::{now_ms, set_mock_timer};
+    use std::collections::HashSet;
+    use std::iter::FromIterator;
+
+    fn gen_block(parent_header: Header) -> Block {
+        let header = HeaderBuilder::default()
+            .parent_hash(&parent_header.hash())
+            .timestamp(now_ms())
+            .number(parent_header.number() + 1)
+            .nonce(parent_header.nonce() + 1)
+            .build();
+
+        BlockBuilder::default().header(header).build()
+    }
+
+    #[test]
+    fn test_remove_blocks_by_parent() {
+        let consensus = Consensus::default();
+        let block_number = 200;
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut parent = consensus.genesis_block().header().clone();
+        let pool = OrphanBlockPool::with_capacity(200);
+        for _ in 1..block_number {
+            let new_block = gen_block(parent);
+            blocks.push(new_block.clone());
+            pool.insert(new_block.clone());
+            parent = new_block.header().clone();
+        }
+
+        let orphan = pool.remove_blocks_by_parent(&consensus.genesis_block().header().hash());
+        let orphan: HashSet<Block> = HashSet::from_iter(orphan.into_iter());
+        let block: HashSet<Block> = HashSet::from_iter(blocks.into_iter());
+        assert_eq!(orphan, block)
+    }
+
+    #[test]
+    fn test_insert_limit() {
+        let consensus = Consensus::default();
+        let pool = OrphanBlockPool::with_capacity(2);
+        let block1 = gen_block(consensus.genesis_block().header().clone());
+        let block2 = gen_block(block1.header().clone());
+        let block3 = gen_block(block2.header().clone());
+
+        assert!(pool.insert(block2.clone()));
+        assert!(!pool.insert(block2.clone()));
+        assert!(pool.insert(block3.clone()));
+        // full, the block is dropped
+        assert!(!pool.insert(block1.clone()));
+        assert!(!pool.contains(&block1.header().hash()));
+        assert_eq!(pool.len(), 2);
+
+        let orphan = pool.remove_blocks_by_parent(&block1.header().hash());
+        assert_eq!(
+            orphan,
+            vec![block2, block3].into_iter().collect::<VecDeque<_>>()
+        );
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let consensus = Consensus::default();
+        let pool = OrphanBlockPool::with_capacity(2);
+        let block1 = gen_block(consensus.genesis_block().header().clone());
+        let block2 = gen_block(block1.header().clone());
+        let block3 = gen_block(block2.header().clone());
+
+        set_mock_timer(0);
+        assert!(pool.insert(block2.clone()));
+        set_mock_timer(1);
+        assert!(pool.insert(block3.clone()));
+
+        set_mock_timer(ORPHAN_BLOCK_EXPIRY_MS);
+        // the first orphan expired, it leaves room for another
+        assert!(pool.insert(block1.clone()));
+        assert!(!pool.contains(&block2.header().hash()));
+        assert!(pool.contains(&block3.header().hash()));
+        assert_eq!(pool.len(), 2);
+
+        let orphan = pool.remove_blocks_by_parent(&consensus.genesis_block().header().hash());
+        assert_eq!(orphan, vec![block1].into_iter().collect::<VecDeque<_>>());
+        // waits for its expired parent until it expires too
+        assert!(pool.contains(&block3.header().hash()));
+    }
+}
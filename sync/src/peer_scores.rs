@@ -0,0 +1,95 @@
+use ckb_network::PeerId;
+use ckb_util::Mutex;
+use fnv::FnvHashMap;
+use std::sync::Arc;
+use PEER_BAN_SCORE;
+
+/// Upper bound of the scored peers, the lowest score is forgotten first.
+pub const MAX_SCORED_PEERS: usize = 4096;
+
+/// Misbehavior penalties of the peers, shared by the relayer and the synchronizer.
+///
+/// Every offence adds its penalty to the peer's score, a peer reaching `PEER_BAN_SCORE` is
+/// dropped and banned by the network. The scores are kept by peer id, a peer can't clear its
+/// penalties by reconnecting.
+#[derive(Clone, Default)]
+pub struct PeerScores {
+    inner: Arc<Mutex<FnvHashMap<PeerId, u32>>>,
+}
+
+impl PeerScores {
+    /// Adds the penalty of an offence, returns `true` if the peer has to be banned.
+    ///
+    /// The score of a banned peer starts over, the ban itself is kept by the network.
+    pub fn misbehave(&self, peer_id: &PeerId, penalty: u32) -> bool {
+        let mut scores = self.inner.lock();
+        if !scores.contains_key(peer_id) && scores.len() >= MAX_SCORED_PEERS {
+            let lowest = scores
+                .iter()
+                .min_by_key(|(_, score)| **score)
+                .map(|(peer_id, _)| peer_id.clone());
+            if let Some(lowest) = lowest {
+                scores.remove(&lowest);
+            }
+        }
+        let banned = {
+            let score = scores.entry(peer_id.clone()).or_insert(0);
+            *score = score.saturating_add(penalty);
+            *score >= PEER_BAN_SCORE
+        };
+        if banned {
+            scores.remove(peer_id);
+        }
+        banned
+    }
+
+    pub fn score(&self, peer_id: &PeerId) -> u32 {
+        self.inner.lock().get(peer_id).cloned().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ckb_network::random_peer_id;
+
+    #[test]
+    fn test_misbehave() {
+        let scores = PeerScores::default();
+        let peer1 = random_peer_id().unwrap();
+        let peer2 = random_peer_id().unwrap();
+        assert!(!scores.misbehave(&peer1, PEER_BAN_SCORE - 1));
+        assert!(!scores.misbehave(&peer2, PEER_BAN_SCORE - 1));
+        assert_eq!(scores.score(&peer1), PEER_BAN_SCORE - 1);
+
+        assert!(scores.misbehave(&peer1, 1));
+        assert_eq!(scores.score(&peer1), 0);
+        // the penalties of the other peers are kept
+        assert_eq!(scores.score(&peer2), PEER_BAN_SCORE - 1);
+    }
+
+    #[test]
+    fn test_shared_scores() {
+        let scores = PeerScores::default();
+        let shared = scores.clone();
+        let peer = random_peer_id().unwrap();
+        assert!(!shared.misbehave(&peer, 1));
+        assert_eq!(scores.score(&peer), 1);
+    }
+
+    #[test]
+    fn test_max_scored_peers() {
+        let scores = PeerScores::default();
+        let lowest = random_peer_id().unwrap();
+        assert!(!scores.misbehave(&lowest, 1));
+        for _ in 1..MAX_SCORED_PEERS {
+            assert!(!scores.misbehave(&random_peer_id().unwrap(), 2));
+        }
+
+        let peer = random_peer_id().unwrap();
+        assert!(!scores.misbehave(&peer, 3));
+        assert_eq!(scores.score(&peer), 3);
+        assert_eq!(scores.score(&lowest), 0);
+        assert_eq!(scores.inner.lock().len(), MAX_SCORED_PEERS);
+    }
+}
@@ -1,4 +1,5 @@
-use ckb_protocol::{BlockProposal, FlatbuffersVectorIterator};
+use ckb_core::transaction::Transaction;
+use ckb_protocol::{convert, BlockProposal, Error};
 use ckb_shared::index::ChainIndex;
 use relayer::Relayer;
 
@@ -15,9 +16,20 @@ where
         BlockProposalProcess { message, relayer }
     }
 
-    pub fn execute(self) {
-        FlatbuffersVectorIterator::new(self.message.transactions().unwrap()).for_each(|tx| {
-            let _ = self.relayer.tx_pool.add_transaction(tx.into());
-        })
+    pub fn execute(self) -> Result<(), Error> {
+        let txs: Vec<Transaction> =
+            convert::vector(self.message.transactions(), "BlockProposal.transactions")?;
+
+        {
+            let mut inflight = self.relayer.state.inflight_proposals.lock();
+            for tx in &txs {
+                inflight.remove(&tx.proposal_short_id());
+            }
+        }
+
+        for tx in txs {
+            let _ = self.relayer.tx_pool.add_transaction(tx);
+        }
+        Ok(())
     }
 }
@@ -1,11 +1,11 @@
-use bigint::H256;
 use ckb_core::transaction::Transaction;
 use ckb_network::CKBProtocolContext;
 use ckb_network::PeerIndex;
-use ckb_protocol::{BlockTransactions, FlatbuffersVectorIterator};
+use ckb_protocol::{convert, BlockTransactions, Error};
 use ckb_shared::index::ChainIndex;
-use relayer::Relayer;
+use relayer::{ReconstructionResult, Relayer};
 use std::sync::Arc;
+use UNSOLICITED_BLOCK_TRANSACTIONS_PENALTY;
 
 pub struct BlockTransactionsProcess<'a, CI: ChainIndex + 'a> {
     message: &'a BlockTransactions<'a>,
@@ -32,24 +32,67 @@ where
         }
     }
 
-    pub fn execute(self) {
-        let hash = H256::from_slice(self.message.hash().and_then(|b| b.seq()).unwrap());
-        if let Some(compact_block) = self
+    pub fn execute(self) -> Result<(), Error> {
+        let hash = convert::hash(self.message.hash(), "BlockTransactions.hash")?;
+        let pending = self
             .relayer
             .state
             .pending_compact_blocks
             .write()
-            .remove(&hash)
-        {
-            let transactions: Vec<Transaction> =
-                FlatbuffersVectorIterator::new(self.message.transactions().unwrap())
-                    .map(Into::into)
-                    .collect();
+            .remove(&hash);
+        if let Some(mut pending) = pending {
+            let transactions: Vec<Transaction> = convert::vector(
+                self.message.transactions(),
+                "BlockTransactions.transactions",
+            )?;
+            if transactions.len() != pending.requested.len() {
+                debug!(target: "relay", "peer {} sent {} transactions of block {:?}, {} requested", self.peer, transactions.len(), hash, pending.requested.len());
+                self.relayer.request_full_block(self.nc, self.peer, &hash);
+                return Ok(());
+            }
+            let requested = pending.requested.split_off(0);
+            pending
+                .filled
+                .extend(requested.into_iter().zip(transactions));
 
-            if let (Some(block), _) = self.relayer.reconstruct_block(&compact_block, transactions) {
-                self.relayer
-                    .accept_block(self.nc, self.peer, &Arc::new(block));
+            match self
+                .relayer
+                .reconstruct_block(&pending.compact_block, &pending.filled)
+            {
+                ReconstructionResult::Block(block) => {
+                    self.relayer
+                        .accept_block(self.nc, self.peer, &Arc::new(block));
+                }
+                ReconstructionResult::Missing(missing_indexes) => {
+                    // the received transactions can be colliding ones too, ask again
+                    // until the round trips are used up
+                    if self.relayer.request_block_transactions(
+                        self.nc,
+                        self.peer,
+                        &mut pending,
+                        missing_indexes,
+                    ) {
+                        self.relayer
+                            .state
+                            .pending_compact_blocks
+                            .write()
+                            .insert(hash, pending);
+                    } else {
+                        self.relayer.request_full_block(self.nc, self.peer, &hash);
+                    }
+                }
+                ReconstructionResult::Invalid => {
+                    self.relayer.request_full_block(self.nc, self.peer, &hash);
+                }
             }
+        } else {
+            self.relayer.misbehave(
+                self.nc,
+                self.peer,
+                UNSOLICITED_BLOCK_TRANSACTIONS_PENALTY,
+                "unsolicited block transactions",
+            );
         }
+        Ok(())
     }
 }
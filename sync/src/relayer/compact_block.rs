@@ -2,7 +2,7 @@ use bigint::H48;
 use ckb_core::header::Header;
 use ckb_core::transaction::{ProposalShortId, Transaction};
 use ckb_core::uncle::UncleBlock;
-use ckb_protocol::{self, FlatbuffersVectorIterator};
+use ckb_protocol::{self, convert, Error, TryFrom};
 
 pub type ShortTransactionID = H48;
 
@@ -22,36 +22,33 @@ pub struct PrefilledTransaction {
     pub transaction: Transaction,
 }
 
-impl<'a> From<ckb_protocol::CompactBlock<'a>> for CompactBlock {
-    fn from(b: ckb_protocol::CompactBlock<'a>) -> Self {
-        CompactBlock {
-            header: b.header().unwrap().into(),
+impl<'a> TryFrom<ckb_protocol::CompactBlock<'a>> for CompactBlock {
+    fn try_from(b: ckb_protocol::CompactBlock<'a>) -> Result<Self, Error> {
+        Ok(CompactBlock {
+            header: TryFrom::try_from(convert::field(b.header(), "CompactBlock.header")?)?,
             nonce: b.nonce(),
-            short_ids: FlatbuffersVectorIterator::new(b.short_ids().unwrap())
-                .map(|bytes| ShortTransactionID::from(bytes.seq().unwrap()))
-                .collect(),
-            prefilled_transactions: FlatbuffersVectorIterator::new(
-                b.prefilled_transactions().unwrap(),
-            ).map(Into::into)
-            .collect(),
-
-            uncles: FlatbuffersVectorIterator::new(b.uncles().unwrap())
-                .map(Into::into)
-                .collect(),
-
-            proposal_transactions: FlatbuffersVectorIterator::new(
-                b.proposal_transactions().unwrap(),
-            ).filter_map(|bytes| ProposalShortId::from_slice(bytes.seq().unwrap()))
-            .collect(),
-        }
+            short_ids: convert::vector(b.short_ids(), "CompactBlock.short_ids")?,
+            prefilled_transactions: convert::vector(
+                b.prefilled_transactions(),
+                "CompactBlock.prefilled_transactions",
+            )?,
+            uncles: convert::vector(b.uncles(), "CompactBlock.uncles")?,
+            proposal_transactions: convert::vector(
+                b.proposal_transactions(),
+                "CompactBlock.proposal_transactions",
+            )?,
+        })
     }
 }
 
-impl<'a> From<ckb_protocol::PrefilledTransaction<'a>> for PrefilledTransaction {
-    fn from(pt: ckb_protocol::PrefilledTransaction<'a>) -> Self {
-        PrefilledTransaction {
+impl<'a> TryFrom<ckb_protocol::PrefilledTransaction<'a>> for PrefilledTransaction {
+    fn try_from(pt: ckb_protocol::PrefilledTransaction<'a>) -> Result<Self, Error> {
+        Ok(PrefilledTransaction {
             index: pt.index() as usize,
-            transaction: pt.transaction().unwrap().into(),
-        }
+            transaction: TryFrom::try_from(convert::field(
+                pt.transaction(),
+                "PrefilledTransaction.transaction",
+            )?)?,
+        })
     }
 }
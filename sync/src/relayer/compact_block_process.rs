@@ -1,13 +1,14 @@
 use super::compact_block::CompactBlock;
 use ckb_network::{CKBProtocolContext, PeerIndex};
-use ckb_protocol::{CompactBlock as FbsCompactBlock, RelayMessage};
+use ckb_protocol::{CompactBlock as FbsCompactBlock, Error, TryFrom};
 use ckb_shared::index::ChainIndex;
 use ckb_shared::shared::ChainProvider;
 use ckb_util::RwLockUpgradableReadGuard;
 use ckb_verification::{HeaderResolverWrapper, HeaderVerifier, Verifier};
-use flatbuffers::FlatBufferBuilder;
-use relayer::Relayer;
+use fnv::FnvHashMap;
+use relayer::{PendingCompactBlock, ReconstructionResult, Relayer};
 use std::sync::Arc;
+use INVALID_COMPACT_BLOCK_PENALTY;
 
 pub struct CompactBlockProcess<'a, CI: ChainIndex + 'a> {
     message: &'a FbsCompactBlock<'a>,
@@ -34,48 +35,91 @@ where
         }
     }
 
-    pub fn execute(self) {
-        let compact_block: CompactBlock = (*self.message).into();
+    pub fn execute(self) -> Result<(), Error> {
+        let compact_block = CompactBlock::try_from(*self.message)?;
         let block_hash = compact_block.header.hash();
-        let pending_compact_blocks = self.relayer.state.pending_compact_blocks.upgradable_read();
-        if pending_compact_blocks.get(&block_hash).is_none()
-            && self.relayer.get_block(&block_hash).is_none()
+        // the peer announced the block already, or we announced it to the peer
+        if !self
+            .relayer
+            .sync_shared
+            .known_blocks
+            .lock()
+            .insert(self.peer, block_hash)
         {
-            let resolver =
-                HeaderResolverWrapper::new(&compact_block.header, self.relayer.shared.clone());
-            let header_verifier =
-                HeaderVerifier::new(Arc::clone(&self.relayer.shared.consensus().pow_engine()));
+            debug!(target: "relay", "skip duplicate compact block {:?} from peer {}", block_hash, self.peer);
+            return Ok(());
+        }
+        let pending_compact_blocks = self.relayer.state.pending_compact_blocks.upgradable_read();
+        if pending_compact_blocks.contains_key(&block_hash) {
+            // another peer announced the block first, its missing transactions are requested
+            debug!(target: "relay", "compact block {:?} already pending reconstruction", block_hash);
+            return Ok(());
+        }
+        if self.relayer.get_block(&block_hash).is_none() {
+            let pow_engine = self.relayer.sync_shared.shared.consensus().pow_engine();
+            let verified = if self
+                .relayer
+                .sync_shared
+                .shared
+                .block_header(&compact_block.header.parent_hash())
+                .is_some()
+            {
+                let resolver = HeaderResolverWrapper::new(
+                    &compact_block.header,
+                    self.relayer.sync_shared.shared.clone(),
+                );
+                HeaderVerifier::new(Arc::clone(&pow_engine))
+                    .verify(&resolver)
+                    .is_ok()
+            } else {
+                // the parent is unknown, the block is reconstructed and kept as orphan
+                // until its ancestors are downloaded, only the proof of work can be checked
+                pow_engine.verify_header(&compact_block.header)
+            };
 
-            if header_verifier.verify(&resolver).is_ok() {
+            if verified {
                 self.relayer
                     .request_proposal_txs(self.nc, self.peer, &compact_block);
 
-                match self.relayer.reconstruct_block(&compact_block, Vec::new()) {
-                    (Some(block), _) => {
+                match self
+                    .relayer
+                    .reconstruct_block(&compact_block, &FnvHashMap::default())
+                {
+                    ReconstructionResult::Block(block) => {
                         self.relayer
                             .accept_block(self.nc, self.peer, &Arc::new(block))
                     }
-                    (None, missing_indexes) => {
-                        {
+                    ReconstructionResult::Missing(missing_indexes) => {
+                        let mut pending = PendingCompactBlock::new(compact_block);
+                        if self.relayer.request_block_transactions(
+                            self.nc,
+                            self.peer,
+                            &mut pending,
+                            missing_indexes,
+                        ) {
                             let mut write_guard =
                                 RwLockUpgradableReadGuard::upgrade(pending_compact_blocks);
-                            write_guard.insert(block_hash, compact_block.clone());
+                            write_guard.insert(block_hash, pending);
                         }
-
-                        let fbb = &mut FlatBufferBuilder::new();
-                        let message = RelayMessage::build_get_block_transactions(
-                            fbb,
-                            &block_hash,
-                            &missing_indexes
-                                .into_iter()
-                                .map(|i| i as u32)
-                                .collect::<Vec<_>>(),
+                    }
+                    ReconstructionResult::Invalid => {
+                        self.relayer.misbehave(
+                            self.nc,
+                            self.peer,
+                            INVALID_COMPACT_BLOCK_PENALTY,
+                            "malformed compact block",
                         );
-                        fbb.finish(message, None);
-                        let _ = self.nc.send(self.peer, fbb.finished_data().to_vec());
                     }
                 }
+            } else {
+                self.relayer.misbehave(
+                    self.nc,
+                    self.peer,
+                    INVALID_COMPACT_BLOCK_PENALTY,
+                    "invalid compact block",
+                );
             }
         }
+        Ok(())
     }
 }
@@ -1,6 +1,6 @@
 use ckb_core::transaction::ProposalShortId;
 use ckb_network::{CKBProtocolContext, PeerIndex};
-use ckb_protocol::{FlatbuffersVectorIterator, GetBlockProposal, RelayMessage};
+use ckb_protocol::{convert, Error, GetBlockProposal, RelayMessage};
 use ckb_shared::index::ChainIndex;
 use flatbuffers::FlatBufferBuilder;
 use relayer::Relayer;
@@ -30,12 +30,16 @@ where
         }
     }
 
-    pub fn execute(self) {
+    pub fn execute(self) -> Result<(), Error> {
+        let proposal_transactions: Vec<ProposalShortId> = convert::vector(
+            self.message.proposal_transactions(),
+            "GetBlockProposal.proposal_transactions",
+        )?;
         let mut pending_proposals_request = self.relayer.state.pending_proposals_request.lock();
 
         let transactions = {
-            FlatbuffersVectorIterator::new(self.message.proposal_transactions().unwrap())
-                .filter_map(|bytes| ProposalShortId::from_slice(bytes.seq().unwrap()))
+            proposal_transactions
+                .into_iter()
                 .filter_map(|short_id| {
                     self.relayer.tx_pool.get_transaction(short_id).or({
                         pending_proposals_request
@@ -53,5 +57,6 @@ where
         fbb.finish(message, None);
 
         let _ = self.nc.send(self.peer, fbb.finished_data().to_vec());
+        Ok(())
     }
 }
@@ -1,6 +1,5 @@
-use bigint::H256;
 use ckb_network::{CKBProtocolContext, PeerIndex};
-use ckb_protocol::{GetBlockTransactions, RelayMessage};
+use ckb_protocol::{convert, Error, GetBlockTransactions, RelayMessage};
 use ckb_shared::index::ChainIndex;
 use flatbuffers::FlatBufferBuilder;
 use relayer::Relayer;
@@ -30,15 +29,13 @@ where
         }
     }
 
-    pub fn execute(self) {
-        let hash = H256::from_slice(self.message.hash().and_then(|bytes| bytes.seq()).unwrap());
+    pub fn execute(self) -> Result<(), Error> {
+        let hash = convert::hash(self.message.hash(), "GetBlockTransactions.hash")?;
+        let indexes = convert::field(self.message.indexes(), "GetBlockTransactions.indexes")?;
         debug!(target: "relay", "get_block_transactions {:?}", hash);
 
         if let Some(block) = self.relayer.get_block(&hash) {
-            let transactions = self
-                .message
-                .indexes()
-                .unwrap()
+            let transactions = indexes
                 .safe_slice()
                 .iter()
                 .filter_map(|i| block.commit_transactions().get(*i as usize).cloned())
@@ -50,5 +47,6 @@ where
 
             let _ = self.nc.send(self.peer, fbb.finished_data().to_vec());
         }
+        Ok(())
     }
 }
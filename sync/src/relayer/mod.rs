@@ -6,47 +6,139 @@ pub mod compact_block;
 mod compact_block_process;
 mod get_block_proposal_process;
 mod get_block_transactions_process;
+mod new_header_process;
 mod transaction_process;
 
 use self::block_proposal_process::BlockProposalProcess;
 use self::block_transactions_process::BlockTransactionsProcess;
-use self::compact_block::CompactBlock;
+use self::compact_block::{CompactBlock, ShortTransactionID};
 use self::compact_block_process::CompactBlockProcess;
 use self::get_block_proposal_process::GetBlockProposalProcess;
 use self::get_block_transactions_process::GetBlockTransactionsProcess;
+use self::new_header_process::NewHeaderProcess;
 use self::transaction_process::TransactionProcess;
 use bigint::H256;
-use ckb_chain::chain::ChainController;
 use ckb_core::block::{Block, BlockBuilder};
-use ckb_core::transaction::{ProposalShortId, Transaction};
-use ckb_network::{CKBProtocolContext, CKBProtocolHandler, PeerIndex, TimerToken};
+use ckb_core::BlockNumber;
+use ckb_core::transaction::{OutPoint, ProposalShortId, Transaction};
+use ckb_network::{CKBProtocolContext, CKBProtocolHandler, PeerIndex, Score, Severity, TimerToken};
 use ckb_pool::txs_pool::TransactionPoolController;
-use ckb_protocol::{short_transaction_id, short_transaction_id_keys, RelayMessage, RelayPayload};
+use ckb_protocol::{
+    short_transaction_id, short_transaction_id_keys, Error as ProtocolError, RelayMessage,
+    RelayPayload, SyncMessage,
+};
 use ckb_shared::index::ChainIndex;
-use ckb_shared::shared::{ChainProvider, Shared};
+use ckb_shared::shared::ChainProvider;
 use ckb_util::{Mutex, RwLock};
 use flatbuffers::{get_root, FlatBufferBuilder};
 use fnv::{FnvHashMap, FnvHashSet};
+use known_hashes::KnownHashes;
+use std::cmp::{self, Reverse};
+use std::collections::hash_map::Entry;
 use std::collections::HashSet;
 use std::sync::Arc;
 use std::time::Duration;
+use sync_shared::SyncShared;
+use {
+    FAST_ANNOUNCE_PEERS, KNOWN_TXS_WINDOW, MALFORMED_MESSAGE_PENALTY,
+    MAX_BLOCK_TRANSACTIONS_ROUND_TRIPS, SYNC_PROTOCOL_ID,
+};
 
 pub const TX_PROPOSAL_TOKEN: TimerToken = 0;
 
+/// Pings within the same milliseconds count as equally fast when ordering the announcements
+const ANNOUNCE_PING_GRANULARITY: u64 = 10;
+
+/// Splits the peers to announce a new block to in the `FAST_ANNOUNCE_PEERS` peers to send it to
+/// first and the others, which get it `ANNOUNCE_STAGGER` later.
+///
+/// The peers answering the pings the fastest come first, the best scored ones among the equally
+/// fast. The peers not pinged yet come last.
+pub fn announce_order(
+    nc: &CKBProtocolContext,
+    peers: Vec<PeerIndex>,
+) -> (Vec<PeerIndex>, Vec<PeerIndex>) {
+    let ranked = peers
+        .into_iter()
+        .map(|peer| match nc.session_info(peer) {
+            Some(session) => (peer, session.peer.ping, session.peer.score),
+            None => (peer, None, Score::min_value()),
+        }).collect();
+    split_announcements(ranked)
+}
+
+pub(crate) fn split_announcements(
+    mut ranked: Vec<(PeerIndex, Option<Duration>, Score)>,
+) -> (Vec<PeerIndex>, Vec<PeerIndex>) {
+    ranked.sort_by_key(|&(peer, ping, score)| {
+        let ping = ping.map(|ping| {
+            (ping.as_secs() * 1000 + u64::from(ping.subsec_millis())) / ANNOUNCE_PING_GRANULARITY
+        });
+        (ping.is_none(), ping, Reverse(score), peer)
+    });
+    let mut first = ranked
+        .into_iter()
+        .map(|(peer, _, _)| peer)
+        .collect::<Vec<_>>();
+    let rest = first.split_off(cmp::min(FAST_ANNOUNCE_PEERS, first.len()));
+    (first, rest)
+}
+
+/// Indexes the pool transactions by short id, `None` marks the short ids shared by several
+/// transactions
+pub(crate) fn index_by_short_id<F>(
+    txs: Vec<Transaction>,
+    short_id: F,
+) -> FnvHashMap<ShortTransactionID, Option<Transaction>>
+where
+    F: Fn(&H256) -> ShortTransactionID,
+{
+    let mut txs_map: FnvHashMap<ShortTransactionID, Option<Transaction>> =
+        FnvHashMap::default();
+    for tx in txs {
+        let short_id = short_id(&tx.hash());
+        match txs_map.entry(short_id) {
+            Entry::Occupied(mut entry) => {
+                if entry
+                    .get()
+                    .as_ref()
+                    .map_or(false, |known| known.hash() != tx.hash())
+                {
+                    debug!(target: "relay", "short id {:?} collides in the pool", short_id);
+                    entry.insert(None);
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Some(tx));
+            }
+        }
+    }
+    txs_map
+}
+
+// The disconnect message telling the peer why it is dropped
+fn disconnect_message(severity: &Severity) -> Vec<u8> {
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = RelayMessage::build_disconnect(fbb, severity.code(), severity.message());
+    fbb.finish(message, None);
+    fbb.finished_data().to_vec()
+}
+
 pub struct Relayer<CI: ChainIndex> {
-    chain: ChainController,
-    shared: Shared<CI>,
+    sync_shared: SyncShared<CI>,
     tx_pool: TransactionPoolController,
     state: Arc<RelayState>,
+    /// Whether we ask the peers to announce new blocks with their headers only
+    header_first: bool,
 }
 
 impl<CI: ChainIndex> ::std::clone::Clone for Relayer<CI> {
     fn clone(&self) -> Self {
         Relayer {
-            chain: self.chain.clone(),
-            shared: self.shared.clone(),
+            sync_shared: self.sync_shared.clone(),
             tx_pool: self.tx_pool.clone(),
             state: Arc::clone(&self.state),
+            header_first: self.header_first,
         }
     }
 }
@@ -56,19 +148,24 @@ where
     CI: ChainIndex + 'static,
 {
     pub fn new(
-        chain: ChainController,
-        shared: Shared<CI>,
+        sync_shared: SyncShared<CI>,
         tx_pool: TransactionPoolController,
+        header_first: bool,
     ) -> Self {
         Relayer {
-            chain,
-            shared,
+            sync_shared,
             tx_pool,
             state: Arc::new(RelayState::default()),
+            header_first,
         }
     }
 
-    fn process(&self, nc: &CKBProtocolContext, peer: PeerIndex, message: RelayMessage) {
+    fn process(
+        &self,
+        nc: &CKBProtocolContext,
+        peer: PeerIndex,
+        message: RelayMessage,
+    ) -> Result<(), ProtocolError> {
         match message.payload_type() {
             RelayPayload::CompactBlock => CompactBlockProcess::new(
                 &message.payload_as_compact_block().unwrap(),
@@ -102,16 +199,58 @@ where
                 BlockProposalProcess::new(&message.payload_as_block_proposal().unwrap(), self)
                     .execute()
             }
-            RelayPayload::NONE => {}
+            RelayPayload::Disconnect => {
+                let disconnect = message.payload_as_disconnect().unwrap();
+                info!(target: "relay", "peer {} is disconnecting, reason {}: {}", peer, disconnect.reason(), disconnect.message().unwrap_or_default());
+                Ok(())
+            }
+            RelayPayload::NewHeader => {
+                NewHeaderProcess::new(&message.payload_as_new_header().unwrap(), self, peer, nc)
+                    .execute()
+            }
+            RelayPayload::Handshake => {
+                let header_first = message.payload_as_handshake().unwrap().header_first();
+                debug!(target: "relay", "peer {} prefers header first relay: {}", peer, header_first);
+                let mut header_first_peers = self.state.header_first_peers.lock();
+                if header_first {
+                    header_first_peers.insert(peer);
+                } else {
+                    header_first_peers.remove(&peer);
+                }
+                Ok(())
+            }
+            RelayPayload::TransactionReject => {
+                let reject = message.payload_as_transaction_reject().unwrap();
+                let hash = reject
+                    .hash()
+                    .and_then(|bytes| bytes.seq())
+                    .filter(|seq| seq.len() == 32)
+                    .map(H256::from_slice);
+                debug!(target: "relay", "peer {} rejected transaction {:?}, code {}", peer, hash, reject.code());
+                Ok(())
+            }
+            RelayPayload::NONE => Ok(()),
         }
     }
 
+    pub fn report_peer(&self, nc: &CKBProtocolContext, peer: PeerIndex, reason: Severity) {
+        self.sync_shared
+            .report_peer(nc, peer, reason, &disconnect_message);
+    }
+
+    pub fn misbehave(&self, nc: &CKBProtocolContext, peer: PeerIndex, penalty: u32, reason: &str) {
+        warn!(target: "relay", "peer {} misbehaves: {}", peer, reason);
+        self.sync_shared
+            .misbehave(nc, peer, penalty, reason, &disconnect_message);
+    }
+
     pub fn request_proposal_txs(
         &self,
         nc: &CKBProtocolContext,
         peer: PeerIndex,
         block: &CompactBlock,
     ) {
+        let number = block.header.number();
         let mut inflight = self.state.inflight_proposals.lock();
         let unknown_ids = block
             .proposal_transactions
@@ -121,107 +260,309 @@ where
                     .uncles
                     .iter()
                     .flat_map(|uncle| uncle.proposal_transactions()),
-            ).filter(|x| !self.tx_pool.contains_key(**x) && inflight.insert(**x))
+            ).filter(|x| {
+                !self.tx_pool.contains_key(**x) && inflight.insert(**x, number).is_none()
+            })
             .cloned()
             .collect::<Vec<_>>();
+        if unknown_ids.is_empty() {
+            return;
+        }
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_get_block_proposal(fbb, number, &unknown_ids);
+        fbb.finish(message, None);
+
+        let _ = nc.send(peer, fbb.finished_data().to_vec());
+    }
+
+    /// Asks the peer which relayed an orphan for the transactions creating its missing cells
+    pub fn request_orphan_parents(
+        &self,
+        nc: &CKBProtocolContext,
+        peer: PeerIndex,
+        missing: &[OutPoint],
+    ) {
+        let number = self.sync_shared.shared.tip_header().read().number();
+        let mut inflight = self.state.inflight_proposals.lock();
+        let parent_ids = missing
+            .iter()
+            .map(|out_point| ProposalShortId::from_h256(&out_point.hash))
+            .collect::<FnvHashSet<_>>()
+            .into_iter()
+            .filter(|id| inflight.insert(*id, number).is_none())
+            .collect::<Vec<_>>();
+        if parent_ids.is_empty() {
+            return;
+        }
 
         let fbb = &mut FlatBufferBuilder::new();
-        let message =
-            RelayMessage::build_get_block_proposal(fbb, block.header.number(), &unknown_ids);
+        let message = RelayMessage::build_get_block_proposal(fbb, number, &parent_ids);
         fbb.finish(message, None);
 
         let _ = nc.send(peer, fbb.finished_data().to_vec());
     }
 
     pub fn accept_block(&self, nc: &CKBProtocolContext, peer: PeerIndex, block: &Arc<Block>) {
-        if self.chain.process_block(Arc::clone(&block)).is_ok() {
-            let fbb = &mut FlatBufferBuilder::new();
-            let message = RelayMessage::build_compact_block(fbb, block, &HashSet::new());
-            fbb.finish(message, None);
+        let hash = block.header().hash();
+        if self.sync_shared.shared.block_header(&hash).is_some() {
+            debug!(target: "relay", "block {:?} already stored", hash);
+            return;
+        }
+        if self
+            .sync_shared
+            .shared
+            .block_header(&block.header().parent_hash())
+            .is_none()
+        {
+            self.insert_orphan_block(nc, peer, block);
+            return;
+        }
 
-            for peer_id in nc.connected_peers() {
-                if peer_id != peer {
-                    let _ = nc.send(peer_id, fbb.finished_data().to_vec());
+        if self.process_block(nc, peer, block) {
+            for orphan in self
+                .sync_shared
+                .orphan_block_pool
+                .remove_blocks_by_parent(&hash)
+            {
+                // descendants of a block which failed verification can't be connected either
+                if self
+                    .sync_shared
+                    .shared
+                    .block_header(&orphan.header().parent_hash())
+                    .is_some()
+                {
+                    self.process_block(nc, peer, &Arc::new(orphan));
+                } else {
+                    debug!(target: "relay", "drop orphan block {:?}, its parent is rejected", orphan.header().hash());
                 }
             }
         }
     }
 
-    pub fn reconstruct_block(
-        &self,
-        compact_block: &CompactBlock,
-        transactions: Vec<Transaction>,
-    ) -> (Option<Block>, Vec<usize>) {
-        let (key0, key1) =
-            short_transaction_id_keys(compact_block.header.nonce(), compact_block.nonce);
+    fn process_block(&self, nc: &CKBProtocolContext, peer: PeerIndex, block: &Arc<Block>) -> bool {
+        let hash = block.header().hash();
+        // the synchronizer is processing the same block
+        let _in_flight = match self.sync_shared.in_flight_blocks.begin(hash) {
+            Some(in_flight) => in_flight,
+            None => {
+                debug!(target: "relay", "block {:?} already in flight", hash);
+                return false;
+            }
+        };
+
+        if self
+            .sync_shared
+            .chain
+            .process_block_from_peer(Arc::clone(&block), peer)
+            .is_err()
+        {
+            return false;
+        }
+
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_compact_block(fbb, block, &HashSet::new());
+        fbb.finish(message, None);
+        let header_fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_new_header(header_fbb, block.header());
+        header_fbb.finish(message, None);
+
+        // the block is already known to the peers that announced it to us or sent it by sync
+        let mut known_blocks = self.sync_shared.known_blocks.lock();
+        let header_first_peers = self.state.header_first_peers.lock();
+        for peer_id in nc.connected_peers() {
+            if peer_id != peer && known_blocks.insert(peer_id, hash) {
+                let data = if header_first_peers.contains(&peer_id) {
+                    header_fbb.finished_data()
+                } else {
+                    fbb.finished_data()
+                };
+                let _ = nc.send(peer_id, data.to_vec());
+            }
+        }
+        true
+    }
 
-        let mut txs = transactions;
-        txs.extend(self.tx_pool.get_potential_transactions());
+    /// Keeps the block until its parent is connected and asks the peer for the missing
+    /// ancestors, they are downloaded by the synchronizer.
+    fn insert_orphan_block(&self, nc: &CKBProtocolContext, peer: PeerIndex, block: &Arc<Block>) {
+        if !self
+            .sync_shared
+            .orphan_block_pool
+            .insert(Block::clone(block))
+        {
+            debug!(target: "relay", "orphan block {:?} already cached or pool full", block.header().hash());
+            return;
+        }
+        debug!(target: "relay", "insert orphan block {} {:?}", block.header().number(), block.header().hash());
+        self.request_headers(nc, peer, &block.header().hash());
+    }
 
-        let mut txs_map = FnvHashMap::default();
-        for tx in txs {
-            let short_id = short_transaction_id(key0, key1, &tx.hash());
-            txs_map.insert(short_id, tx);
+    /// Asks the peer for the transactions of the pending block at `indexes`, returns `false`
+    /// without asking once the block used up its round trips.
+    pub fn request_block_transactions(
+        &self,
+        nc: &CKBProtocolContext,
+        peer: PeerIndex,
+        pending: &mut PendingCompactBlock,
+        indexes: Vec<usize>,
+    ) -> bool {
+        if pending.round_trips >= MAX_BLOCK_TRANSACTIONS_ROUND_TRIPS {
+            return false;
         }
+        pending.round_trips += 1;
 
-        let short_ids_iter = &mut compact_block.short_ids.iter();
-        let mut block_transactions = Vec::with_capacity(
-            compact_block.prefilled_transactions.len() + compact_block.short_ids.len(),
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_get_block_transactions(
+            fbb,
+            &pending.compact_block.header.hash(),
+            &indexes.iter().map(|i| *i as u32).collect::<Vec<_>>(),
         );
+        fbb.finish(message, None);
+        let _ = nc.send(peer, fbb.finished_data().to_vec());
+        pending.requested = indexes;
+        true
+    }
 
-        // fill transactions gap
-        compact_block.prefilled_transactions.iter().for_each(|pt| {
-            let gap = pt.index - block_transactions.len();
-            if gap > 0 {
-                short_ids_iter
-                    .take(gap)
-                    .for_each(|short_id| block_transactions.push(txs_map.remove(short_id)));
-            }
-            block_transactions.push(Some(pt.transaction.clone()));
-        });
+    #[cfg(test)]
+    pub(crate) fn pending_round_trips(&self, hash: &H256) -> Option<usize> {
+        self.state
+            .pending_compact_blocks
+            .read()
+            .get(hash)
+            .map(|pending| pending.round_trips)
+    }
 
-        // append remain transactions
-        short_ids_iter.for_each(|short_id| block_transactions.push(txs_map.remove(short_id)));
+    /// Gives up reconstructing the block, it is downloaded by sync like any block whose header
+    /// is known
+    pub fn request_full_block(&self, nc: &CKBProtocolContext, peer: PeerIndex, hash: &H256) {
+        debug!(target: "relay", "can't reconstruct block {:?}, download it from peer {}", hash, peer);
+        self.request_headers(nc, peer, hash);
+    }
 
+    /// Asks the peer for the headers following our tip up to the header of `hash_stop`, the
+    /// synchronizer downloads the blocks
+    fn request_headers(&self, nc: &CKBProtocolContext, peer: PeerIndex, hash_stop: &H256) {
+        let tip_header = self.sync_shared.shared.tip_header().read().inner().clone();
+        let locator_hash = self.sync_shared.shared.get_locator(&tip_header);
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = SyncMessage::build_get_headers(fbb, &locator_hash, hash_stop);
+        fbb.finish(message, None);
+        let _ = nc.send_protocol(peer, SYNC_PROTOCOL_ID, fbb.finished_data().to_vec());
+    }
+
+    /// Rebuilds the block from the compact block, the transactions received for it by index
+    /// and the pool transactions.
+    ///
+    /// A short id matching several pool transactions, or used more than once by the block, is
+    /// ambiguous, its transaction is requested like a missing one. Transactions which don't
+    /// match the header's commitment reveal an undetected collision, all those taken from the
+    /// pool are requested then.
+    pub fn reconstruct_block(
+        &self,
+        compact_block: &CompactBlock,
+        filled: &FnvHashMap<usize, Transaction>,
+    ) -> ReconstructionResult {
+        let (key0, key1) =
+            short_transaction_id_keys(compact_block.header.nonce(), compact_block.nonce);
+
+        // Fast path: every transaction is prefilled, typically a block with only the cellbase,
+        // nothing to look up in the pool and nothing can be missing
+        let txs_map = if compact_block.short_ids.is_empty() {
+            FnvHashMap::default()
+        } else {
+            index_by_short_id(self.tx_pool.get_potential_transactions(), |hash| {
+                short_transaction_id(key0, key1, hash)
+            })
+        };
+        let mut short_id_counts = FnvHashMap::default();
+        for short_id in &compact_block.short_ids {
+            *short_id_counts.entry(short_id).or_insert(0) += 1;
+        }
+
+        let total = compact_block.prefilled_transactions.len() + compact_block.short_ids.len();
+        let mut prefilled = compact_block.prefilled_transactions.iter().peekable();
+        let mut short_ids = compact_block.short_ids.iter();
+        let mut block_transactions = Vec::with_capacity(total);
         let mut missing_indexes = Vec::new();
-        for (i, t) in block_transactions.iter().enumerate() {
-            if t.is_none() {
-                missing_indexes.push(i);
+        let mut pool_indexes = Vec::new();
+        for index in 0..total {
+            let is_prefilled = prefilled.peek().map_or(false, |pt| pt.index == index);
+            let tx = if is_prefilled {
+                prefilled.next().map(|pt| pt.transaction.clone())
+            } else if let Some(short_id) = short_ids.next() {
+                if let Some(tx) = filled.get(&index) {
+                    Some(tx.clone())
+                } else if short_id_counts[short_id] > 1 {
+                    None
+                } else {
+                    pool_indexes.push(index);
+                    txs_map.get(short_id).and_then(Clone::clone)
+                }
+            } else {
+                // the prefilled indexes are out of order or out of range
+                return ReconstructionResult::Invalid;
+            };
+            match tx {
+                Some(tx) => block_transactions.push(tx),
+                None => missing_indexes.push(index),
             }
         }
+        if prefilled.next().is_some() {
+            return ReconstructionResult::Invalid;
+        }
+        if !missing_indexes.is_empty() {
+            return ReconstructionResult::Missing(missing_indexes);
+        }
 
-        if missing_indexes.is_empty() {
-            let block = BlockBuilder::default()
-                .header(compact_block.header.clone())
-                .uncles(compact_block.uncles.clone())
-                .commit_transactions(block_transactions.into_iter().map(|t| t.unwrap()).collect())
-                .proposal_transactions(compact_block.proposal_transactions.clone())
-                .build();
-
-            (Some(block), missing_indexes)
+        let block = BlockBuilder::default()
+            .header(compact_block.header.clone())
+            .uncles(compact_block.uncles.clone())
+            .commit_transactions(block_transactions)
+            .proposal_transactions(compact_block.proposal_transactions.clone())
+            .build();
+        if block.cal_txs_commit() == block.header().txs_commit() {
+            ReconstructionResult::Block(block)
+        } else if pool_indexes.is_empty() {
+            ReconstructionResult::Invalid
         } else {
-            (None, missing_indexes)
+            debug!(target: "relay", "block {:?} doesn't match its transactions, request those taken from the pool", block.header().hash());
+            ReconstructionResult::Missing(pool_indexes)
+        }
+    }
+
+    // Proposals requested for blocks which already fell out of the commit window
+    // can't be committed any more, stop tracking them
+    fn prune_inflight_proposals(&self) {
+        let tip_number = self.sync_shared.shared.tip_header().read().number();
+        let timeout = self.sync_shared.shared.consensus().proposal_window().far;
+        if tip_number < timeout {
+            return;
         }
+        let tail = tip_number - timeout;
+        self.state
+            .inflight_proposals
+            .lock()
+            .retain(|_, number| *number > tail);
     }
 
     fn prune_tx_proposal_request(&self, nc: &CKBProtocolContext) {
         let mut pending_proposals_request = self.state.pending_proposals_request.lock();
+        if pending_proposals_request.is_empty() {
+            return;
+        }
+        // the requests are answered or dropped, a transaction still unknown is not waited for
+        let ids = pending_proposals_request.keys().cloned().collect();
         let mut peer_txs = FnvHashMap::default();
-        let mut remove_ids = Vec::new();
-
-        for (id, peers) in pending_proposals_request.iter() {
-            if let Some(tx) = self.tx_pool.get_transaction(*id) {
+        for tx in self.tx_pool.get_transactions(ids) {
+            if let Some(peers) = pending_proposals_request.get(&tx.proposal_short_id()) {
                 for peer in peers {
-                    let mut tx_set = peer_txs.entry(*peer).or_insert_with(Vec::new);
+                    let tx_set = peer_txs.entry(*peer).or_insert_with(Vec::new);
                     tx_set.push(tx.clone());
                 }
             }
-            remove_ids.push(*id);
-        }
-
-        for id in remove_ids {
-            pending_proposals_request.remove(&id);
         }
+        pending_proposals_request.clear();
 
         for (peer, txs) in peer_txs {
             let fbb = &mut FlatBufferBuilder::new();
@@ -236,7 +577,7 @@ where
     }
 
     pub fn get_block(&self, hash: &H256) -> Option<Block> {
-        self.shared.block(hash)
+        self.sync_shared.shared.block(hash)
     }
 }
 
@@ -249,33 +590,120 @@ where
     }
 
     fn received(&self, nc: Box<CKBProtocolContext>, peer: PeerIndex, data: &[u8]) {
+        self.sync_shared
+            .shared
+            .sync_stats()
+            .lock()
+            .record_received("relay", data.len());
         // TODO use flatbuffers verifier
+        if data.len() < 4 {
+            self.misbehave(
+                nc.as_ref(),
+                peer,
+                MALFORMED_MESSAGE_PENALTY,
+                "malformed message",
+            );
+            return;
+        }
         let msg = get_root::<RelayMessage>(data);
         debug!(target: "relay", "msg {:?}", msg.payload_type());
-        self.process(nc.as_ref(), peer, msg);
+        // every payload accessor of `process` relies on the payload being present
+        if msg.payload().is_none() {
+            self.misbehave(
+                nc.as_ref(),
+                peer,
+                MALFORMED_MESSAGE_PENALTY,
+                "message without payload",
+            );
+            return;
+        }
+        if let Err(err) = self.process(nc.as_ref(), peer, msg) {
+            self.misbehave(
+                nc.as_ref(),
+                peer,
+                MALFORMED_MESSAGE_PENALTY,
+                &format!("malformed message: {:?}", err),
+            );
+        }
     }
 
-    fn connected(&self, _nc: Box<CKBProtocolContext>, peer: PeerIndex) {
+    fn connected(&self, nc: Box<CKBProtocolContext>, peer: PeerIndex) {
         info!(target: "sync", "peer={} RelayProtocol.connected", peer);
-        // do nothing
+        self.sync_shared.connected(peer);
+        // declare how we want new blocks announced, compact blocks unless told otherwise
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_handshake(fbb, self.header_first);
+        fbb.finish(message, None);
+        let _ = nc.send(peer, fbb.finished_data().to_vec());
     }
 
     fn disconnected(&self, _nc: Box<CKBProtocolContext>, peer: PeerIndex) {
         info!(target: "sync", "peer={} RelayProtocol.disconnected", peer);
-        // TODO
+        self.sync_shared.disconnected(peer);
+        self.state.header_first_peers.lock().remove(&peer);
+        self.state.known_txs.lock().remove_peer(peer);
     }
 
     fn timer_triggered(&self, nc: Box<CKBProtocolContext>, token: TimerToken) {
         match token as usize {
-            TX_PROPOSAL_TOKEN => self.prune_tx_proposal_request(nc.as_ref()),
+            TX_PROPOSAL_TOKEN => {
+                self.prune_tx_proposal_request(nc.as_ref());
+                self.prune_inflight_proposals();
+            }
             _ => unreachable!(),
         }
     }
 }
 
-#[derive(Default)]
+#[derive(Debug, PartialEq)]
+pub enum ReconstructionResult {
+    Block(Block),
+    /// The transactions at these indexes are unknown or ambiguous, they have to be requested
+    Missing(Vec<usize>),
+    /// The compact block or the transactions received for it contradict its header
+    Invalid,
+}
+
+/// A compact block waiting for the transactions requested from the peer
+pub struct PendingCompactBlock {
+    pub compact_block: CompactBlock,
+    /// Indexes of the requested transactions, the peer sends them back in this order
+    pub requested: Vec<usize>,
+    /// The transactions received so far by index
+    pub filled: FnvHashMap<usize, Transaction>,
+    /// GetBlockTransactions sent for the block
+    pub round_trips: usize,
+}
+
+impl PendingCompactBlock {
+    pub fn new(compact_block: CompactBlock) -> Self {
+        PendingCompactBlock {
+            compact_block,
+            requested: Vec::new(),
+            filled: FnvHashMap::default(),
+            round_trips: 0,
+        }
+    }
+}
+
 pub struct RelayState {
-    pub pending_compact_blocks: RwLock<FnvHashMap<H256, CompactBlock>>,
-    pub inflight_proposals: Mutex<FnvHashSet<ProposalShortId>>,
+    pub pending_compact_blocks: RwLock<FnvHashMap<H256, PendingCompactBlock>>,
+    pub inflight_proposals: Mutex<FnvHashMap<ProposalShortId, BlockNumber>>,
     pub pending_proposals_request: Mutex<FnvHashMap<ProposalShortId, FnvHashSet<PeerIndex>>>,
+    /// Peers which asked for new blocks to be announced with their headers only
+    pub header_first_peers: Mutex<FnvHashSet<PeerIndex>>,
+    /// Transactions known to each peer: relayed by it or relayed to it
+    pub known_txs: Mutex<KnownHashes>,
+}
+
+impl Default for RelayState {
+    fn default() -> Self {
+        RelayState {
+            pending_compact_blocks: RwLock::new(FnvHashMap::default()),
+            inflight_proposals: Mutex::new(FnvHashMap::default()),
+            pending_proposals_request: Mutex::new(FnvHashMap::default()),
+            header_first_peers: Mutex::new(FnvHashSet::default()),
+            known_txs: Mutex::new(KnownHashes::new(KNOWN_TXS_WINDOW)),
+        }
+    }
 }
@@ -0,0 +1,82 @@
+use ckb_core::header::Header;
+use ckb_network::{CKBProtocolContext, PeerIndex};
+use ckb_protocol::{convert, Error, NewHeader, TryFrom};
+use ckb_shared::index::ChainIndex;
+use ckb_shared::shared::ChainProvider;
+use ckb_verification::{HeaderResolverWrapper, HeaderVerifier, Verifier};
+use relayer::Relayer;
+use std::sync::Arc;
+
+pub struct NewHeaderProcess<'a, CI: ChainIndex + 'a> {
+    message: &'a NewHeader<'a>,
+    relayer: &'a Relayer<CI>,
+    peer: PeerIndex,
+    nc: &'a CKBProtocolContext,
+}
+
+impl<'a, CI> NewHeaderProcess<'a, CI>
+where
+    CI: ChainIndex + 'static,
+{
+    pub fn new(
+        message: &'a NewHeader,
+        relayer: &'a Relayer<CI>,
+        peer: PeerIndex,
+        nc: &'a CKBProtocolContext,
+    ) -> Self {
+        NewHeaderProcess {
+            message,
+            relayer,
+            peer,
+            nc,
+        }
+    }
+
+    pub fn execute(self) -> Result<(), Error> {
+        let header = Header::try_from(convert::field(self.message.header(), "NewHeader.header")?)?;
+        let block_hash = header.hash();
+        if !self
+            .relayer
+            .sync_shared
+            .known_blocks
+            .lock()
+            .insert(self.peer, block_hash)
+        {
+            debug!(target: "relay", "skip duplicate header {:?} from peer {}", block_hash, self.peer);
+            return Ok(());
+        }
+        if self
+            .relayer
+            .sync_shared
+            .shared
+            .block_header(&block_hash)
+            .is_some()
+        {
+            return Ok(());
+        }
+
+        let pow_engine = self.relayer.sync_shared.shared.consensus().pow_engine();
+        let verified = if self
+            .relayer
+            .sync_shared
+            .shared
+            .block_header(&header.parent_hash())
+            .is_some()
+        {
+            let resolver =
+                HeaderResolverWrapper::new(&header, self.relayer.sync_shared.shared.clone());
+            HeaderVerifier::new(Arc::clone(&pow_engine))
+                .verify(&resolver)
+                .is_ok()
+        } else {
+            pow_engine.verify_header(&header)
+        };
+
+        // the block is downloaded by sync, as the headers leading to it
+        if verified {
+            debug!(target: "relay", "new header {} {:?} from peer {}, request the block", header.number(), block_hash, self.peer);
+            self.relayer.request_headers(self.nc, self.peer, &block_hash);
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,9 @@
 use ckb_core::transaction::Transaction;
-use ckb_network::{CKBProtocolContext, PeerIndex};
-use ckb_protocol::{RelayMessage, Transaction as FbsTransaction};
+use ckb_network::{CKBProtocolContext, PeerIndex, Severity};
+use ckb_pool::txs_pool::{InsertionResult, PoolError};
+use ckb_protocol::{Error, RelayMessage, Transaction as FbsTransaction, TryFrom};
 use ckb_shared::index::ChainIndex;
+use ckb_shared::shared::ChainProvider;
 use flatbuffers::FlatBufferBuilder;
 use relayer::Relayer;
 
@@ -30,18 +32,62 @@ where
         }
     }
 
-    pub fn execute(self) {
-        let tx: Transaction = (*self.message).into();
-        if self.relayer.tx_pool.add_transaction(tx.clone()).is_ok() {
-            let fbb = &mut FlatBufferBuilder::new();
-            let message = RelayMessage::build_transaction(fbb, &tx);
-            fbb.finish(message, None);
+    pub fn execute(self) -> Result<(), Error> {
+        let tx = Transaction::try_from(*self.message)?;
+        let tx_hash = tx.hash();
+        self.relayer
+            .state
+            .known_txs
+            .lock()
+            .insert(self.peer, tx_hash);
+        // the pool queues a transaction it doesn't know until it is proposed, even one already
+        // committed, which the peers behind the tip keep relaying
+        if self
+            .relayer
+            .sync_shared
+            .shared
+            .contain_transaction(&tx_hash)
+        {
+            debug!(target: "relay", "peer {} relayed committed transaction {}", self.peer, tx_hash);
+            return Ok(());
+        }
+        match self.relayer.tx_pool.add_transaction(tx.clone()) {
+            // held until its parents arrive, it isn't relayed before it can be verified
+            Ok(InsertionResult::Orphan(missing)) => {
+                self.relayer
+                    .request_orphan_parents(self.nc, self.peer, &missing);
+            }
+            Ok(_) => {
+                let fbb = &mut FlatBufferBuilder::new();
+                let message = RelayMessage::build_transaction(fbb, &tx);
+                fbb.finish(message, None);
+
+                // the peers which relayed it to us or got it from us already know it
+                let mut known_txs = self.relayer.state.known_txs.lock();
+                for peer_id in self.nc.connected_peers() {
+                    if known_txs.insert(peer_id, tx_hash) {
+                        let _ = self.nc.send(peer_id, fbb.finished_data().to_vec());
+                    }
+                }
+            }
+            // the peer most likely didn't know we have it
+            Err(PoolError::AlreadyInPool) => {}
+            Err(err) => {
+                let fbb = &mut FlatBufferBuilder::new();
+                let message = RelayMessage::build_transaction_reject(fbb, &tx_hash, err.code());
+                fbb.finish(message, None);
+                let _ = self.nc.send(self.peer, fbb.finished_data().to_vec());
 
-            for peer_id in self.nc.connected_peers() {
-                if peer_id != self.peer {
-                    let _ = self.nc.send(peer_id, fbb.finished_data().to_vec());
+                if let PoolError::DeadCell(out_point) = err {
+                    debug!(target: "relay", "peer {} relayed transaction {} spending dead cell {:?}", self.peer, tx_hash, out_point);
+                    self.relayer.report_peer(
+                        self.nc,
+                        self.peer,
+                        Severity::Bad("relay transaction spending dead cell"),
+                    );
                 }
             }
         }
+        Ok(())
     }
 }
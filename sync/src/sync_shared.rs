@@ -0,0 +1,145 @@
+use ckb_chain::chain::ChainController;
+use ckb_network::{CKBProtocolContext, PeerIndex, Severity};
+use ckb_shared::index::ChainIndex;
+use ckb_shared::shared::Shared;
+use ckb_util::Mutex;
+use config::Config;
+use fnv::FnvHashMap;
+use in_flight_blocks::InFlightBlocks;
+use known_hashes::KnownHashes;
+use orphan_block_pool::OrphanBlockPool;
+use peer_scores::PeerScores;
+use std::sync::Arc;
+use std::time::Duration;
+use synchronizer::peers::Peers;
+use PEER_BAN_TIME;
+
+/// The state both the synchronizer and the relayer decide over.
+///
+/// One instance is handed to the two protocol handlers, so a peer, a block in flight or an
+/// orphan block looks the same to both whichever protocol it came through.
+pub struct SyncShared<CI: ChainIndex> {
+    pub chain: ChainController,
+    pub shared: Shared<CI>,
+    pub peers: Arc<Peers>,
+    pub peer_scores: PeerScores,
+    /// Blocks known to each peer: announced by it, announced to it or downloaded from it
+    pub known_blocks: Arc<Mutex<KnownHashes>>,
+    pub in_flight_blocks: InFlightBlocks,
+    pub orphan_block_pool: OrphanBlockPool,
+    // The protocols connected to each peer
+    connected_protocols: Arc<Mutex<FnvHashMap<PeerIndex, usize>>>,
+}
+
+impl<CI: ChainIndex> ::std::clone::Clone for SyncShared<CI> {
+    fn clone(&self) -> Self {
+        SyncShared {
+            chain: self.chain.clone(),
+            shared: self.shared.clone(),
+            peers: Arc::clone(&self.peers),
+            peer_scores: self.peer_scores.clone(),
+            known_blocks: Arc::clone(&self.known_blocks),
+            in_flight_blocks: self.in_flight_blocks.clone(),
+            orphan_block_pool: self.orphan_block_pool.clone(),
+            connected_protocols: Arc::clone(&self.connected_protocols),
+        }
+    }
+}
+
+impl<CI: ChainIndex> SyncShared<CI> {
+    pub fn new(chain: ChainController, shared: Shared<CI>, config: &Config) -> Self {
+        SyncShared {
+            chain,
+            shared,
+            peers: Arc::new(Peers::default()),
+            peer_scores: PeerScores::default(),
+            known_blocks: Arc::new(Mutex::new(KnownHashes::default())),
+            in_flight_blocks: InFlightBlocks::default(),
+            orphan_block_pool: OrphanBlockPool::with_capacity(config.orphan_block_limit),
+            connected_protocols: Arc::new(Mutex::new(FnvHashMap::default())),
+        }
+    }
+
+    /// Tells the peer why it is dropped before reporting it to the network, `disconnect` builds
+    /// the message of the calling protocol
+    pub fn report_peer(
+        &self,
+        nc: &CKBProtocolContext,
+        peer: PeerIndex,
+        reason: Severity,
+        disconnect: &Fn(&Severity) -> Vec<u8>,
+    ) {
+        let _ = nc.send(peer, disconnect(&reason));
+        nc.report_peer(peer, reason);
+    }
+
+    /// Adds the penalty of the offence, drops and bans the peer once it has misbehaved too much
+    pub fn misbehave(
+        &self,
+        nc: &CKBProtocolContext,
+        peer: PeerIndex,
+        penalty: u32,
+        reason: &str,
+        disconnect: &Fn(&Severity) -> Vec<u8>,
+    ) {
+        let peer_id = match nc.session_info(peer) {
+            Some(session) => session.peer.peer_id,
+            None => return,
+        };
+        if self.peer_scores.misbehave(&peer_id, penalty) {
+            let _ = nc.send(peer, disconnect(&Severity::Bad(reason)));
+            nc.ban_peer(peer, Duration::from_millis(PEER_BAN_TIME));
+        }
+    }
+
+    /// One of the protocols connected to the peer
+    pub fn connected(&self, peer: PeerIndex) {
+        *self.connected_protocols.lock().entry(peer).or_insert(0) += 1;
+    }
+
+    /// One of the protocols disconnected from the peer, the state shared by the protocols is
+    /// forgotten once none of them is left
+    pub fn disconnected(&self, peer: PeerIndex) {
+        let mut connected_protocols = self.connected_protocols.lock();
+        let last = match connected_protocols.get_mut(&peer) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => true,
+        };
+        if last {
+            connected_protocols.remove(&peer);
+            self.known_blocks.lock().remove_peer(peer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigint::H256;
+    use ckb_db::memorydb::MemoryKeyValueDB;
+    use ckb_shared::shared::SharedBuilder;
+    use ckb_shared::store::ChainKVStore;
+
+    #[test]
+    fn test_disconnected() {
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory().build();
+        let (chain, _chain_receivers) = ChainController::new();
+        let sync_shared = SyncShared::new(chain, shared, &Config::default());
+        let hash = H256::from(1);
+
+        // the synchronizer and the relayer
+        sync_shared.connected(0);
+        sync_shared.connected(0);
+        sync_shared.known_blocks.lock().insert(0, hash);
+
+        // the other protocol still relies on what the peer knows
+        sync_shared.disconnected(0);
+        assert!(sync_shared.known_blocks.lock().contains(0, &hash));
+
+        sync_shared.disconnected(0);
+        assert!(!sync_shared.known_blocks.lock().contains(0, &hash));
+    }
+}
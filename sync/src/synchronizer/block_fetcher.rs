@@ -24,7 +24,7 @@ where
     CI: ChainIndex,
 {
     pub fn new(synchronizer: &Synchronizer<CI>, peer: PeerIndex) -> Self {
-        let tip_header = synchronizer.shared.tip_header().read().clone();
+        let tip_header = synchronizer.sync_shared.shared.tip_header().read().clone();
         BlockFetcher {
             tip_header,
             peer,
@@ -32,7 +32,7 @@ where
         }
     }
     pub fn initial_and_check_inflight(&self) -> bool {
-        let mut blocks_inflight = self.synchronizer.peers.blocks_inflight.write();
+        let mut blocks_inflight = self.synchronizer.sync_shared.peers.blocks_inflight.write();
         let inflight = blocks_inflight
             .entry(self.peer)
             .or_insert_with(Default::default);
@@ -57,6 +57,7 @@ where
 
     pub fn peer_best_known_header(&self) -> Option<HeaderView> {
         self.synchronizer
+            .sync_shared
             .peers
             .best_known_headers
             .read()
@@ -67,14 +68,16 @@ where
     pub fn last_common_header(&self, best: &HeaderView) -> Option<Header> {
         let guard = self
             .synchronizer
+            .sync_shared
             .peers
             .last_common_headers
             .upgradable_read();
 
         let last_common_header = try_option!(guard.get(&self.peer).cloned().or_else(|| {
             if best.number() < self.tip_header.number() {
-                let last_common_hash = self.synchronizer.shared.block_hash(best.number())?;
-                self.synchronizer.shared.block_header(&last_common_hash)
+                let shared = &self.synchronizer.sync_shared.shared;
+                let last_common_hash = shared.block_hash(best.number())?;
+                shared.block_header(&last_common_hash)
             } else {
                 Some(self.tip_header.inner().clone())
             }
@@ -172,8 +175,7 @@ where
         let mut v_fetch = Vec::with_capacity(PER_FETCH_BLOCK_LIMIT);
 
         {
-            let mut guard = self.synchronizer.peers.blocks_inflight.write();
-            let inflight = guard.get_mut(&self.peer).expect("inflight already init");
+            let mut guard = self.synchronizer.sync_shared.peers.blocks_inflight.write();
 
             while n_height < max_height && v_fetch.len() < PER_FETCH_BLOCK_LIMIT {
                 n_height += 1;
@@ -184,7 +186,17 @@ where
                 let to_fetch_hash = to_fetch.hash();
 
                 let block_status = self.synchronizer.get_block_status(&to_fetch_hash);
-                if block_status == BlockStatus::VALID_MASK && inflight.insert(to_fetch_hash) {
+                if block_status != BlockStatus::VALID_MASK {
+                    continue;
+                }
+                // downloading from another peer already
+                if guard.iter().any(|(peer, inflight)| {
+                    *peer != self.peer && inflight.blocks.contains(&to_fetch_hash)
+                }) {
+                    continue;
+                }
+                let inflight = guard.get_mut(&self.peer).expect("inflight already init");
+                if inflight.insert(to_fetch_hash) {
                     debug!(
                         target: "sync", "[Synchronizer] inflight insert {:#?}------------{:?}",
                         to_fetch.number(),
@@ -1,6 +1,6 @@
 use ckb_core::block::Block;
 use ckb_network::{CKBProtocolContext, PeerIndex};
-use ckb_protocol::Block as PBlock;
+use ckb_protocol::{Block as PBlock, Error, TryFrom};
 use ckb_shared::index::ChainIndex;
 use synchronizer::Synchronizer;
 
@@ -27,11 +27,18 @@ where
         }
     }
 
-    pub fn execute(self) {
-        let block: Block = (*self.message).into();
+    pub fn execute(self) -> Result<(), Error> {
+        let block = Block::try_from(*self.message)?;
         debug!(target: "sync", "BlockProcess received block {} {:?}", block.header().number(), block.header().hash());
 
-        self.synchronizer.peers.block_received(self.peer, &block);
+        let sync_shared = &self.synchronizer.sync_shared;
+        sync_shared.peers.block_received(self.peer, &block);
+        // the relayer doesn't announce the block back to the peer it came from
+        sync_shared
+            .known_blocks
+            .lock()
+            .insert(self.peer, block.header().hash());
         self.synchronizer.process_new_block(self.peer, block);
+        Ok(())
     }
 }
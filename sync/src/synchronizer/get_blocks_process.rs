@@ -1,6 +1,5 @@
-use bigint::H256;
 use ckb_network::{CKBProtocolContext, PeerIndex};
-use ckb_protocol::{FlatbuffersVectorIterator, GetBlocks, SyncMessage};
+use ckb_protocol::{convert, Error, GetBlocks, SyncMessage};
 use ckb_shared::index::ChainIndex;
 use flatbuffers::FlatBufferBuilder;
 use synchronizer::Synchronizer;
@@ -30,9 +29,9 @@ where
         }
     }
 
-    pub fn execute(self) {
-        FlatbuffersVectorIterator::new(self.message.block_hashes().unwrap()).for_each(|bytes| {
-            let block_hash = H256::from_slice(bytes.seq().unwrap());
+    pub fn execute(self) -> Result<(), Error> {
+        let block_hashes = convert::vector(self.message.block_hashes(), "GetBlocks.block_hashes")?;
+        for block_hash in block_hashes {
             debug!(target: "sync", "get_blocks {:?}", block_hash);
             if let Some(block) = self.synchronizer.get_block(&block_hash) {
                 debug!(target: "sync", "respond_block {} {:?}", block.header().number(), block.header().hash());
@@ -44,6 +43,7 @@ where
                 // TODO response not found
                 // TODO add timeout check in synchronizer
             }
-        })
+        }
+        Ok(())
     }
 }
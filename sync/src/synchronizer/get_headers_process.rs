@@ -1,7 +1,7 @@
 use bigint::H256;
 use ckb_core::header::Header;
 use ckb_network::{CKBProtocolContext, PeerIndex, Severity};
-use ckb_protocol::{FlatbuffersVectorIterator, GetHeaders, SyncMessage};
+use ckb_protocol::{convert, Error, GetHeaders, SyncMessage};
 use ckb_shared::index::ChainIndex;
 use flatbuffers::FlatBufferBuilder;
 use synchronizer::Synchronizer;
@@ -32,24 +32,27 @@ where
         }
     }
 
-    pub fn execute(self) {
+    pub fn execute(self) -> Result<(), Error> {
         if self.synchronizer.is_initial_block_download() {
             info!(target: "sync", "Ignoring getheaders from peer={} because node is in initial block download", self.peer);
-            return;
+            return Ok(());
         }
         if let Some(locator) = self.message.block_locator_hashes() {
             let locator_size = locator.len();
             if locator_size > MAX_LOCATOR_SIZE {
                 warn!(target: "sync", " getheaders locator size {} from peer={}", locator_size, self.peer);
-                self.nc
-                    .report_peer(self.peer, Severity::Bad("over maximum locator size"));
-                return;
+                self.synchronizer
+                    .report_peer(self.nc, self.peer, Severity::Bad("over maximum locator size"));
+                return Ok(());
             }
 
-            let hash_stop = H256::zero(); // TODO PENDING self.message.hash_stop().unwrap().into();
-            let block_locator_hashes = FlatbuffersVectorIterator::new(locator)
-                .map(|bytes| H256::from_slice(bytes.seq().unwrap()))
-                .collect::<Vec<_>>();
+            // an absent hash_stop asks for as many headers as allowed
+            let hash_stop = match self.message.hash_stop() {
+                Some(bytes) => convert::hash(Some(bytes), "GetHeaders.hash_stop")?,
+                None => H256::zero(),
+            };
+            let block_locator_hashes: Vec<H256> =
+                convert::vector(Some(locator), "GetHeaders.block_locator_hashes")?;
 
             if let Some(block_number) = self
                 .synchronizer
@@ -57,7 +60,10 @@ where
             {
                 debug!(target: "sync", "\n\nheaders latest_common={} tip={} begin\n\n", block_number, {self.synchronizer.tip_header().number()});
 
-                self.synchronizer.peers.getheaders_received(self.peer);
+                self.synchronizer
+                    .sync_shared
+                    .peers
+                    .getheaders_received(self.peer);
                 let headers: Vec<Header> = self
                     .synchronizer
                     .get_locator_response(block_number, &hash_stop);
@@ -73,9 +79,10 @@ where
                 warn!(target: "sync", "\n\nunknown block headers from peer {} {:#?}\n\n", self.peer, block_locator_hashes);
                 // Got 'headers' message without known blocks
                 // ban or close peers
-                self.nc
-                    .report_peer(self.peer, Severity::Bad("without common headers"));
+                self.synchronizer
+                    .report_peer(self.nc, self.peer, Severity::Bad("without common headers"));
             }
         }
+        Ok(())
     }
 }
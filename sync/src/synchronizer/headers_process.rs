@@ -1,14 +1,16 @@
-use bigint::U256;
+use bigint::{H256, U256};
 use ckb_core::header::Header;
 use ckb_network::{CKBProtocolContext, PeerIndex};
-use ckb_protocol::{FlatbuffersVectorIterator, Headers};
+use ckb_protocol::{convert, Error, Headers};
 use ckb_shared::index::ChainIndex;
 use ckb_shared::shared::ChainProvider;
-use ckb_verification::{Error as VerifyError, HeaderResolver, HeaderVerifier, Verifier};
+use ckb_verification::{
+    BlockMedianTimeContext, Error as VerifyError, HeaderResolver, HeaderVerifier, Verifier,
+};
 use log;
 use std::sync::Arc;
 use synchronizer::{BlockStatus, Synchronizer};
-use MAX_HEADERS_LEN;
+use {MALFORMED_HEADERS_PENALTY, MAX_HEADERS_LEN};
 
 pub struct HeadersProcess<'a, CI: ChainIndex + 'a> {
     message: &'a Headers<'a>,
@@ -37,6 +39,17 @@ impl<'a, CI: ChainIndex + 'a> VerifierResolver<'a, CI> {
     }
 }
 
+// The headers being synced are resolved before they are stored
+impl<CI: ChainIndex> BlockMedianTimeContext for Synchronizer<CI> {
+    fn median_time_block_count(&self) -> usize {
+        self.consensus().median_time_block_count()
+    }
+
+    fn median_time_header(&self, hash: &H256) -> Option<Header> {
+        self.get_header(hash)
+    }
+}
+
 impl<'a, CI: ChainIndex> HeaderResolver for VerifierResolver<'a, CI> {
     fn header(&self) -> &Header {
         self.header
@@ -46,54 +59,30 @@ impl<'a, CI: ChainIndex> HeaderResolver for VerifierResolver<'a, CI> {
         self.parent
     }
 
+    // The same retargeting as the chain, the ancestors of the parent may be headers synced
+    // ahead of their blocks
     fn calculate_difficulty(&self) -> Option<U256> {
+        let synchronizer = self.synchronizer;
         self.parent().and_then(|parent| {
-            let parent_hash = parent.hash();
-            let parent_number = parent.number();
-            let last_difficulty = parent.difficulty();
-
-            let interval = self
-                .synchronizer
-                .consensus()
-                .difficulty_adjustment_interval();
-
-            if self.header().number() % interval != 0 {
-                return Some(last_difficulty);
-            }
+            synchronizer.sync_shared.shared.calculate_difficulty_with(
+                parent,
+                &|hash, number| synchronizer.get_ancestor(hash, number),
+                &|hash| {
+                    synchronizer
+                        .get_header_view(hash)
+                        .map(|view| view.total_uncles_count())
+                },
+            )
+        })
+    }
 
-            let start = parent_number.saturating_sub(interval);
-
-            if let Some(start_header) = self.synchronizer.get_ancestor(&parent_hash, start) {
-                let start_total_uncles_count = self
-                    .synchronizer
-                    .get_header_view(&start_header.hash())
-                    .expect("start header_view exist")
-                    .total_uncles_count();
-
-                let last_total_uncles_count = self
-                    .synchronizer
-                    .get_header_view(&parent_hash)
-                    .expect("last header_view exist")
-                    .total_uncles_count();
-
-                let difficulty = last_difficulty
-                    * U256::from(last_total_uncles_count - start_total_uncles_count)
-                    * U256::from((1.0 / self.synchronizer.consensus().orphan_rate_target()) as u64)
-                    / U256::from(interval);
-
-                let min_difficulty = self.synchronizer.consensus().min_difficulty();
-                let max_difficulty = last_difficulty * 2;
-                if difficulty > max_difficulty {
-                    return Some(max_difficulty);
-                }
+    fn median_time(&self) -> Option<u64> {
+        self.parent
+            .and_then(|parent| self.synchronizer.block_median_time(&parent.hash()))
+    }
 
-                if difficulty < min_difficulty {
-                    return Some(min_difficulty);
-                }
-                return Some(difficulty);
-            }
-            None
-        })
+    fn max_block_time_drift(&self) -> u64 {
+        self.synchronizer.consensus().max_block_time_drift()
     }
 }
 
@@ -116,11 +105,15 @@ where
     }
 
     fn is_empty(&self) -> bool {
-        self.message.headers().unwrap().len() == 0
+        self.message
+            .headers()
+            .map_or(false, |headers| headers.len() == 0)
     }
 
     fn is_oversize(&self) -> bool {
-        self.message.headers().unwrap().len() > MAX_HEADERS_LEN
+        self.message
+            .headers()
+            .map_or(false, |headers| headers.len() > MAX_HEADERS_LEN)
     }
 
     fn is_continuous(&self, headers: &[Header]) -> bool {
@@ -143,53 +136,76 @@ where
         let parent = self.synchronizer.get_header(&first.parent_hash());
         let resolver = VerifierResolver::new(parent.as_ref(), &first, &self.synchronizer);
         let verifier = HeaderVerifier::new(Arc::clone(
-            &self.synchronizer.shared.consensus().pow_engine(),
+            &self
+                .synchronizer
+                .sync_shared
+                .shared
+                .consensus()
+                .pow_engine(),
         ));
         let acceptor =
             HeaderAcceptor::new(first, self.peer, &self.synchronizer, resolver, verifier);
         acceptor.accept()
     }
 
-    pub fn execute(self) {
+    pub fn execute(self) -> Result<(), Error> {
         debug!(target: "sync", "HeadersProcess begin");
 
         if self.is_oversize() {
-            self.synchronizer.peers.misbehavior(self.peer, 20);
-            debug!(target: "sync", "HeadersProcess is_oversize");
-            return ();
+            self.synchronizer.misbehave(
+                self.nc,
+                self.peer,
+                MALFORMED_HEADERS_PENALTY,
+                "oversized headers",
+            );
+            return Ok(());
         }
 
         if self.is_empty() {
             debug!(target: "sync", "HeadersProcess is_empty");
-            return ();
+            self.synchronizer
+                .sync_shared
+                .peers
+                .headers_synced(self.peer);
+            return Ok(());
         }
 
-        let headers = FlatbuffersVectorIterator::new(self.message.headers().unwrap())
-            .map(Into::into)
-            .collect::<Vec<Header>>();
+        let headers: Vec<Header> = convert::vector(self.message.headers(), "Headers.headers")?;
 
         if !self.is_continuous(&headers) {
-            self.synchronizer.peers.misbehavior(self.peer, 20);
-            debug!(target: "sync", "HeadersProcess is not continuous");
-            return ();
+            self.synchronizer.misbehave(
+                self.nc,
+                self.peer,
+                MALFORMED_HEADERS_PENALTY,
+                "non-continuous headers",
+            );
+            return Ok(());
         }
 
         let result = self.accept_first(&headers[0]);
         if !result.is_valid() {
             if result.misbehavior > 0 {
-                self.synchronizer
-                    .peers
-                    .misbehavior(self.peer, result.misbehavior);
+                self.synchronizer.misbehave(
+                    self.nc,
+                    self.peer,
+                    result.misbehavior,
+                    &format!("invalid header: {:?}", result.error),
+                );
             }
             debug!(target: "sync", "\n\nHeadersProcess accept_first is_valid {:?} headers = {:#?}\n\n", result, headers[0]);
-            return ();
+            return Ok(());
         }
 
         for window in headers.windows(2) {
             if let [parent, header] = &window {
                 let resolver = VerifierResolver::new(Some(&parent), &header, &self.synchronizer);
                 let verifier = HeaderVerifier::new(Arc::clone(
-                    &self.synchronizer.shared.consensus().pow_engine(),
+                    &self
+                        .synchronizer
+                        .sync_shared
+                        .shared
+                        .consensus()
+                        .pow_engine(),
                 ));
                 let acceptor =
                     HeaderAcceptor::new(&header, self.peer, &self.synchronizer, resolver, verifier);
@@ -197,20 +213,29 @@ where
 
                 if !result.is_valid() {
                     if result.misbehavior > 0 {
-                        self.synchronizer
-                            .peers
-                            .misbehavior(self.peer, result.misbehavior);
+                        self.synchronizer.misbehave(
+                            self.nc,
+                            self.peer,
+                            result.misbehavior,
+                            &format!("invalid header: {:?}", result.error),
+                        );
                     }
                     debug!(target: "sync", "HeadersProcess accept is invalid {:?}", result);
-                    return ();
+                    return Ok(());
                 }
             }
         }
 
+        self.synchronizer.check_long_range_fork(self.peer, &headers);
+
         if log_enabled!(target: "sync", log::Level::Debug) {
             let own = { self.synchronizer.best_known_header.read().clone() };
-            let chain_tip = self.synchronizer.shared.tip_header().read();
-            let peer_state = self.synchronizer.peers.best_known_header(self.peer);
+            let chain_tip = self.synchronizer.sync_shared.shared.tip_header().read();
+            let peer_state = self
+                .synchronizer
+                .sync_shared
+                .peers
+                .best_known_header(self.peer);
             debug!(
                 target: "sync",
                 concat!(
@@ -243,7 +268,17 @@ where
             let start = headers.last().expect("empty checked");
             self.synchronizer
                 .send_getheaders_to_peer(self.nc, self.peer, start);
+            self.synchronizer
+                .sync_shared
+                .peers
+                .headers_requested(self.peer);
+        } else {
+            self.synchronizer
+                .sync_shared
+                .peers
+                .headers_synced(self.peer);
         }
+        Ok(())
     }
 }
 
@@ -1,44 +1,44 @@
 mod block_fetcher;
-mod block_pool;
 mod block_process;
 mod get_blocks_process;
 mod get_headers_process;
 mod header_view;
 mod headers_process;
-mod peers;
+pub mod peers;
 
 use self::block_fetcher::BlockFetcher;
-use self::block_pool::OrphanBlockPool;
 use self::block_process::BlockProcess;
 use self::get_blocks_process::GetBlocksProcess;
 use self::get_headers_process::GetHeadersProcess;
 use self::header_view::HeaderView;
 use self::headers_process::HeadersProcess;
-use self::peers::Peers;
+use self::peers::{HeadersSyncState, Peers};
 use bigint::H256;
-use ckb_chain::chain::ChainController;
 use ckb_chain::error::ProcessBlockError;
 use ckb_chain_spec::consensus::Consensus;
 use ckb_core::block::Block;
 use ckb_core::header::{BlockNumber, Header};
 use ckb_network::{CKBProtocolContext, CKBProtocolHandler, PeerIndex, Severity, TimerToken};
-use ckb_protocol::{SyncMessage, SyncPayload};
+use ckb_protocol::{Error as ProtocolError, SyncMessage, SyncPayload};
 use ckb_shared::index::ChainIndex;
-use ckb_shared::shared::{ChainProvider, Shared};
+use ckb_shared::shared::ChainProvider;
 use ckb_time::now_ms;
 use ckb_util::{RwLock, RwLockUpgradableReadGuard};
 use config::Config;
 use flatbuffers::{get_root, FlatBufferBuilder};
+use fnv::FnvHashSet;
 use std::cmp;
 use std::collections::HashMap;
+use std::iter;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
+use sync_shared::SyncShared;
 use {
     CHAIN_SYNC_TIMEOUT, EVICTION_HEADERS_RESPONSE_TIME, HEADERS_DOWNLOAD_TIMEOUT_BASE,
-    HEADERS_DOWNLOAD_TIMEOUT_PER_HEADER, MAX_HEADERS_LEN,
-    MAX_OUTBOUND_PEERS_TO_PROTECT_FROM_DISCONNECT, MAX_TIP_AGE, POW_SPACE,
+    HEADERS_DOWNLOAD_TIMEOUT_PER_HEADER, MALFORMED_MESSAGE_PENALTY, MAX_HEADERS_LEN,
+    MAX_HEADERS_SYNC_PEERS, MAX_OUTBOUND_PEERS_TO_PROTECT_FROM_DISCONNECT, MAX_TIP_AGE, POW_SPACE,
 };
 
 pub const SEND_GET_HEADERS_TOKEN: TimerToken = 0;
@@ -69,30 +69,22 @@ pub type BlockStatusMap = Arc<RwLock<HashMap<H256, BlockStatus>>>;
 pub type BlockHeaderMap = Arc<RwLock<HashMap<H256, HeaderView>>>;
 
 pub struct Synchronizer<CI: ChainIndex> {
-    chain: ChainController,
-    shared: Shared<CI>,
+    pub sync_shared: SyncShared<CI>,
     pub status_map: BlockStatusMap,
     pub header_map: BlockHeaderMap,
     pub best_known_header: Arc<RwLock<HeaderView>>,
-    pub n_sync: Arc<AtomicUsize>,
-    pub peers: Arc<Peers>,
     pub config: Arc<Config>,
-    pub orphan_block_pool: Arc<OrphanBlockPool>,
     pub outbound_peers_with_protect: Arc<AtomicUsize>,
 }
 
 impl<CI: ChainIndex> ::std::clone::Clone for Synchronizer<CI> {
     fn clone(&self) -> Self {
         Synchronizer {
-            chain: self.chain.clone(),
-            shared: self.shared.clone(),
+            sync_shared: self.sync_shared.clone(),
             status_map: Arc::clone(&self.status_map),
             header_map: Arc::clone(&self.header_map),
             best_known_header: Arc::clone(&self.best_known_header),
-            n_sync: Arc::clone(&self.n_sync),
-            peers: Arc::clone(&self.peers),
             config: Arc::clone(&self.config),
-            orphan_block_pool: Arc::clone(&self.orphan_block_pool),
             outbound_peers_with_protect: Arc::clone(&self.outbound_peers_with_protect),
         }
     }
@@ -103,11 +95,20 @@ fn is_outbound(nc: &CKBProtocolContext, peer: PeerIndex) -> Option<bool> {
         .map(|session_info| session_info.peer.is_outgoing())
 }
 
+// The disconnect message telling the peer why it is dropped
+fn disconnect_message(severity: &Severity) -> Vec<u8> {
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = SyncMessage::build_disconnect(fbb, severity.code(), severity.message());
+    fbb.finish(message, None);
+    fbb.finished_data().to_vec()
+}
+
 impl<CI: ChainIndex> Synchronizer<CI> {
-    pub fn new(chain: ChainController, shared: Shared<CI>, config: Config) -> Synchronizer<CI> {
+    pub fn new(sync_shared: SyncShared<CI>, config: Config) -> Synchronizer<CI> {
         let (total_difficulty, header, total_uncles_count) = {
-            let tip_header = shared.tip_header().read();
-            let block_ext = shared
+            let tip_header = sync_shared.shared.tip_header().read();
+            let block_ext = sync_shared
+                .shared
                 .block_ext(&tip_header.hash())
                 .expect("tip block_ext must exist");
             (
@@ -117,23 +118,23 @@ impl<CI: ChainIndex> Synchronizer<CI> {
             )
         };
         let best_known_header = HeaderView::new(header, total_difficulty, total_uncles_count);
-        let orphan_block_limit = config.orphan_block_limit;
 
         Synchronizer {
             config: Arc::new(config),
-            chain,
-            shared,
-            peers: Arc::new(Peers::default()),
-            orphan_block_pool: Arc::new(OrphanBlockPool::with_capacity(orphan_block_limit)),
+            sync_shared,
             best_known_header: Arc::new(RwLock::new(best_known_header)),
             status_map: Arc::new(RwLock::new(HashMap::new())),
             header_map: Arc::new(RwLock::new(HashMap::new())),
-            n_sync: Arc::new(AtomicUsize::new(0)),
             outbound_peers_with_protect: Arc::new(AtomicUsize::new(0)),
         }
     }
 
-    fn process(&self, nc: &CKBProtocolContext, peer: PeerIndex, message: SyncMessage) {
+    fn process(
+        &self,
+        nc: &CKBProtocolContext,
+        peer: PeerIndex,
+        message: SyncMessage,
+    ) -> Result<(), ProtocolError> {
         match message.payload_type() {
             SyncPayload::GetHeaders => {
                 GetHeadersProcess::new(&message.payload_as_get_headers().unwrap(), self, peer, nc)
@@ -150,15 +151,31 @@ impl<CI: ChainIndex> Synchronizer<CI> {
             SyncPayload::Block => {
                 BlockProcess::new(&message.payload_as_block().unwrap(), self, peer, nc).execute()
             }
-            SyncPayload::NONE => {}
+            SyncPayload::Disconnect => {
+                let disconnect = message.payload_as_disconnect().unwrap();
+                info!(target: "sync", "peer {} is disconnecting, reason {}: {}", peer, disconnect.reason(), disconnect.message().unwrap_or_default());
+                Ok(())
+            }
+            SyncPayload::NONE => Ok(()),
         }
     }
 
+    pub fn report_peer(&self, nc: &CKBProtocolContext, peer: PeerIndex, reason: Severity) {
+        self.sync_shared
+            .report_peer(nc, peer, reason, &disconnect_message);
+    }
+
+    pub fn misbehave(&self, nc: &CKBProtocolContext, peer: PeerIndex, penalty: u32, reason: &str) {
+        warn!(target: "sync", "peer {} misbehaves: {}", peer, reason);
+        self.sync_shared
+            .misbehave(nc, peer, penalty, reason, &disconnect_message);
+    }
+
     pub fn get_block_status(&self, hash: &H256) -> BlockStatus {
         let guard = self.status_map.upgradable_read();
         match guard.get(hash).cloned() {
             Some(s) => s,
-            None => if self.shared.block_header(hash).is_some() {
+            None => if self.sync_shared.shared.block_header(hash).is_some() {
                 let mut write_guard = RwLockUpgradableReadGuard::upgrade(guard);
                 write_guard.insert(*hash, BlockStatus::BLOCK_HAVE_MASK);
                 BlockStatus::BLOCK_HAVE_MASK
@@ -169,7 +186,7 @@ impl<CI: ChainIndex> Synchronizer<CI> {
     }
 
     pub fn peers(&self) -> Arc<Peers> {
-        Arc::clone(&self.peers)
+        Arc::clone(&self.sync_shared.peers)
     }
 
     pub fn insert_block_status(&self, hash: H256, status: BlockStatus) {
@@ -181,7 +198,14 @@ impl<CI: ChainIndex> Synchronizer<CI> {
     }
 
     pub fn is_initial_block_download(&self) -> bool {
-        now_ms().saturating_sub(self.shared.tip_header().read().inner().timestamp()) > MAX_TIP_AGE
+        let tip_timestamp = self
+            .sync_shared
+            .shared
+            .tip_header()
+            .read()
+            .inner()
+            .timestamp();
+        now_ms().saturating_sub(tip_timestamp) > MAX_TIP_AGE
     }
 
     pub fn get_headers_sync_timeout(&self, header: &Header) -> u64 {
@@ -199,34 +223,14 @@ impl<CI: ChainIndex> Synchronizer<CI> {
     }
 
     pub fn tip_header(&self) -> Header {
-        self.shared.tip_header().read().inner().clone()
+        self.sync_shared.shared.tip_header().read().inner().clone()
     }
 
+    /// The locator of `start`, which may be a header downloaded ahead of its block
     pub fn get_locator(&self, start: &Header) -> Vec<H256> {
-        let mut step = 1;
-        let mut locator = Vec::with_capacity(32);
-        let mut index = start.number();
-        let base = start.hash();
-        loop {
-            let header = self
-                .get_ancestor(&base, index)
-                .expect("index calculated in get_locator");
-            locator.push(header.hash());
-
-            if locator.len() >= 10 {
-                step <<= 1;
-            }
-
-            if index < step {
-                // always include genesis hash
-                if index != 0 {
-                    locator.push(self.shared.genesis_hash());
-                }
-                break;
-            }
-            index -= step;
-        }
-        locator
+        self.sync_shared
+            .shared
+            .get_locator_with(start, &|hash| self.get_header(hash))
     }
 
     pub fn locate_latest_common_block(
@@ -238,7 +242,7 @@ impl<CI: ChainIndex> Synchronizer<CI> {
             return None;
         }
 
-        if locator.last().expect("empty checked") != &self.shared.genesis_hash() {
+        if locator.last().expect("empty checked") != &self.sync_shared.shared.genesis_hash() {
             return None;
         }
 
@@ -246,7 +250,7 @@ impl<CI: ChainIndex> Synchronizer<CI> {
         let (index, latest_common) = locator
             .iter()
             .enumerate()
-            .map(|(index, hash)| (index, self.shared.block_number(hash)))
+            .map(|(index, hash)| (index, self.sync_shared.shared.block_number(hash)))
             .find(|(_index, number)| number.is_some())
             .expect("locator last checked");
 
@@ -256,16 +260,16 @@ impl<CI: ChainIndex> Synchronizer<CI> {
 
         if let Some(header) = locator
             .get(index - 1)
-            .and_then(|hash| self.shared.block_header(hash))
+            .and_then(|hash| self.sync_shared.shared.block_header(hash))
         {
             let mut block_hash = header.parent_hash();
             loop {
-                let block_header = match self.shared.block_header(&block_hash) {
+                let block_header = match self.sync_shared.shared.block_header(&block_hash) {
                     None => break latest_common,
                     Some(block_header) => block_header,
                 };
 
-                if let Some(block_number) = self.shared.block_number(&block_hash) {
+                if let Some(block_number) = self.sync_shared.shared.block_number(&block_hash) {
                     return Some(block_number);
                 }
 
@@ -278,20 +282,23 @@ impl<CI: ChainIndex> Synchronizer<CI> {
 
     pub fn get_header_view(&self, hash: &H256) -> Option<HeaderView> {
         self.header_map.read().get(hash).cloned().or_else(|| {
-            self.shared.block_header(hash).and_then(|header| {
-                self.shared.block_ext(&hash).map(|block_ext| {
-                    HeaderView::new(
-                        header,
-                        block_ext.total_difficulty,
-                        block_ext.total_uncles_count,
-                    )
+            self.sync_shared
+                .shared
+                .block_header(hash)
+                .and_then(|header| {
+                    self.sync_shared.shared.block_ext(&hash).map(|block_ext| {
+                        HeaderView::new(
+                            header,
+                            block_ext.total_difficulty,
+                            block_ext.total_uncles_count,
+                        )
+                    })
                 })
-            })
         })
     }
 
     pub fn consensus(&self) -> &Consensus {
-        self.shared.consensus()
+        self.sync_shared.shared.consensus()
     }
 
     pub fn get_header(&self, hash: &H256) -> Option<Header> {
@@ -300,11 +307,11 @@ impl<CI: ChainIndex> Synchronizer<CI> {
             .get(hash)
             .map(|view| view.inner())
             .cloned()
-            .or_else(|| self.shared.block_header(hash))
+            .or_else(|| self.sync_shared.shared.block_header(hash))
     }
 
     pub fn get_block(&self, hash: &H256) -> Option<Block> {
-        self.shared.block(hash)
+        self.sync_shared.shared.block(hash)
     }
 
     pub fn get_ancestor(&self, base: &H256, number: BlockNumber) -> Option<Header> {
@@ -328,17 +335,80 @@ impl<CI: ChainIndex> Synchronizer<CI> {
         None
     }
 
+    /// Blocks below our tip where the valid `headers` leave our main chain, none if they extend
+    /// it or an ancestor is unknown. The walk back stops past `max_depth`, a deeper fork is
+    /// only known to be deeper than `max_depth`.
+    pub fn fork_depth(&self, headers: &[Header], max_depth: BlockNumber) -> Option<BlockNumber> {
+        let shared = &self.sync_shared.shared;
+        let is_main_chain =
+            |header: &Header| shared.block_hash(header.number()) == Some(header.hash());
+        let tip_number = self.tip_header().number();
+        let mut header = headers
+            .iter()
+            .find(|header| !is_main_chain(header))?
+            .clone();
+        loop {
+            // the fork point is the parent of `header` or one of its ancestors
+            let depth = tip_number.saturating_sub(header.number().saturating_sub(1));
+            if depth > max_depth {
+                return Some(depth);
+            }
+            header = self.get_header(&header.parent_hash())?;
+            if is_main_chain(&header) {
+                return Some(tip_number.saturating_sub(header.number()));
+            }
+        }
+    }
+
+    /// Alerts when the valid `headers` of `peer` fork from our main chain deeper than
+    /// `Config::long_range_fork_depth`. We stay on our branch until the fork has more work,
+    /// the alert tells the operator about a possible network split or 51% attack.
+    pub fn check_long_range_fork(&self, peer: PeerIndex, headers: &[Header]) {
+        let max_depth = self.config.long_range_fork_depth;
+        let depth = match self.fork_depth(headers, max_depth) {
+            Some(depth) if depth > max_depth => depth,
+            _ => return,
+        };
+        if self.sync_shared.peers.long_range_fork_detected(peer) {
+            let last = headers.last().expect("fork headers are not empty");
+            error!(
+                target: "sync",
+                "long-range fork: peer {} has valid headers up to {} {:?} forking at least {} blocks below our tip {}",
+                peer,
+                last.number(),
+                last.hash(),
+                depth,
+                self.tip_header().number(),
+            );
+            self.sync_shared.shared.sync_stats().lock().long_range_forks += 1;
+        }
+    }
+
+    /// Headers of the main chain following `block_number`, up to and including `hash_stop`
     pub fn get_locator_response(&self, block_number: BlockNumber, hash_stop: &H256) -> Vec<Header> {
         let tip_number = self.tip_header().number();
         let max_height = cmp::min(
             block_number + 1 + MAX_HEADERS_LEN as BlockNumber,
             tip_number + 1,
         );
-        (block_number + 1..max_height)
-            .filter_map(|block_number| self.shared.block_hash(block_number))
-            .take_while(|block_hash| block_hash != hash_stop)
-            .filter_map(|block_hash| self.shared.block_header(&block_hash))
-            .collect()
+        let mut headers = Vec::new();
+        for block_number in block_number + 1..max_height {
+            let header = match self
+                .sync_shared
+                .shared
+                .block_hash(block_number)
+                .and_then(|block_hash| self.sync_shared.shared.block_header(&block_hash))
+            {
+                Some(header) => header,
+                None => break,
+            };
+            let stop = header.hash() == *hash_stop;
+            headers.push(header);
+            if stop {
+                break;
+            }
+        }
+        headers
     }
 
     pub fn insert_header_view(&self, header: &Header, peer: PeerIndex) {
@@ -362,7 +432,9 @@ impl<CI: ChainIndex> Synchronizer<CI> {
                 header_view
             };
 
-            self.peers.new_header_received(peer, &header_view);
+            self.sync_shared
+                .peers
+                .new_header_received(peer, &header_view);
 
             let mut header_map = self.header_map.write();
             header_map.insert(header.hash(), header_view);
@@ -408,56 +480,94 @@ impl<CI: ChainIndex> Synchronizer<CI> {
         }
     }
 
-    fn accept_block(&self, peer: PeerIndex, block: &Arc<Block>) -> Result<(), ProcessBlockError> {
-        self.chain.process_block(Arc::clone(&block))?;
+    // The blocks are processed in a single batch, parents first. A block the relayer is
+    // processing is left to it, a block whose parent is unknown goes to the orphan pool, so do
+    // the descendants of both. The blocks after a rejected one are dropped with it.
+    fn accept_blocks(
+        &self,
+        peer: PeerIndex,
+        blocks: Vec<Arc<Block>>,
+    ) -> Result<(), ProcessBlockError> {
+        let shared = &self.sync_shared.shared;
+        let mut batch = Vec::with_capacity(blocks.len());
+        let mut in_flight = Vec::with_capacity(blocks.len());
+        let mut connected = FnvHashSet::default();
+        for block in blocks {
+            let hash = block.header().hash();
+            if shared.block_header(&hash).is_some() {
+                self.mark_block_accepted(peer, &block);
+                connected.insert(hash);
+                continue;
+            }
+            let parent_hash = block.header().parent_hash();
+            if !connected.contains(&parent_hash) && shared.output_root(&parent_hash).is_none() {
+                debug!(
+                    target: "sync", "[Synchronizer] insert_orphan_block {:#?}------------{:?}",
+                    block.header().number(),
+                    hash
+                );
+                self.sync_shared
+                    .orphan_block_pool
+                    .insert(Block::clone(&block));
+                continue;
+            }
+            match self.sync_shared.in_flight_blocks.begin(hash) {
+                Some(guard) => in_flight.push(guard),
+                None => {
+                    debug!(target: "sync", "[Synchronizer] block {:?} already in flight", hash);
+                    continue;
+                }
+            }
+            connected.insert(hash);
+            batch.push(block);
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let result = self
+            .sync_shared
+            .chain
+            .process_blocks_from_peer(batch.clone(), peer);
+        // the blocks before a rejected one are stored
+        for block in batch {
+            if shared.block_header(&block.header().hash()).is_some() {
+                self.mark_block_accepted(peer, &block);
+            }
+        }
+        result
+    }
+
+    fn mark_block_accepted(&self, peer: PeerIndex, block: &Block) {
         self.mark_block_stored(block.header().hash());
-        self.peers.set_last_common_header(peer, &block.header());
-        Ok(())
+        self.sync_shared
+            .peers
+            .set_last_common_header(peer, &block.header());
     }
 
-    //FIXME: guarantee concurrent block process
+    // The orphans descending from the block are processed along with it
     fn insert_new_block(&self, peer: PeerIndex, block: Block) {
-        let block = Arc::new(block);
         if self
+            .sync_shared
             .shared
             .output_root(&block.header().parent_hash())
             .is_some()
         {
-            let accept_ret = self.accept_block(peer, &block);
-            if accept_ret.is_ok() {
-                let pre_orphan_block = self
-                    .orphan_block_pool
-                    .remove_blocks_by_parent(&block.header().hash());
-                for block in pre_orphan_block {
-                    let block = Arc::new(block);
-                    if self
-                        .shared
-                        .output_root(&block.header().parent_hash())
-                        .is_some()
-                    {
-                        let ret = self.accept_block(peer, &block);
-                        if ret.is_err() {
-                            debug!(
-                                target: "sync", "[Synchronizer] accept_block {:#?} error {:?}",
-                                block,
-                                ret.unwrap_err()
-                            );
-                        }
-                    } else {
-                        debug!(
-                            target: "sync", "[Synchronizer] insert_orphan_block {:#?}------------{:?}",
-                            block.header().number(),
-                            block.header().hash()
-                        );
-                        self.orphan_block_pool.insert(Block::clone(&block));
-                    }
-                }
-            } else {
+            let hash = block.header().hash();
+            let orphans = self
+                .sync_shared
+                .orphan_block_pool
+                .remove_blocks_by_parent(&hash);
+            let blocks = iter::once(block)
+                .chain(orphans)
+                .map(Arc::new)
+                .collect::<Vec<_>>();
+            if let Err(err) = self.accept_blocks(peer, blocks) {
                 debug!(
-                    target: "sync", "[Synchronizer] accept_block {:#?} error {:?}",
-                    block,
-                    accept_ret.unwrap_err()
-                )
+                    target: "sync", "[Synchronizer] accept_blocks {:?} error {:?}",
+                    hash,
+                    err
+                );
             }
         } else {
             debug!(
@@ -465,7 +575,7 @@ impl<CI: ChainIndex> Synchronizer<CI> {
                 block.header().number(),
                 block.header().hash()
             );
-            self.orphan_block_pool.insert(Block::clone(&block));
+            self.sync_shared.orphan_block_pool.insert(block);
         }
 
         debug!(target: "sync", "[Synchronizer] insert_new_block finish");
@@ -477,7 +587,7 @@ impl<CI: ChainIndex> Synchronizer<CI> {
 
     fn on_connected(&self, nc: &CKBProtocolContext, peer: PeerIndex) {
         let tip = self.tip_header();
-        let timeout = self.get_headers_sync_timeout(&tip);
+        let timeout = now_ms() + self.get_headers_sync_timeout(&tip);
 
         let protect_outbound = is_outbound(nc, peer).unwrap_or_else(|| false)
             && self.outbound_peers_with_protect.load(Ordering::Acquire)
@@ -488,9 +598,10 @@ impl<CI: ChainIndex> Synchronizer<CI> {
                 .fetch_add(1, Ordering::Release);
         }
 
-        self.peers.on_connected(peer, timeout, protect_outbound);
-        self.n_sync.fetch_add(1, Ordering::Release);
-        self.send_getheaders_to_peer(nc, peer, &tip);
+        self.sync_shared
+            .peers
+            .on_connected(peer, timeout, protect_outbound);
+        self.send_getheaders_to_all(nc);
     }
 
     pub fn send_getheaders_to_peer(
@@ -501,7 +612,7 @@ impl<CI: ChainIndex> Synchronizer<CI> {
     ) {
         let locator_hash = self.get_locator(header);
         let fbb = &mut FlatBufferBuilder::new();
-        let message = SyncMessage::build_get_headers(fbb, &locator_hash);
+        let message = SyncMessage::build_get_headers(fbb, &locator_hash, &H256::zero());
         fbb.finish(message, None);
         let _ = nc.send(peer, fbb.finished_data().to_vec());
     }
@@ -515,15 +626,19 @@ impl<CI: ChainIndex> Synchronizer<CI> {
     //     If their best known block is still behind when that new timeout is
     //     reached, disconnect.
     pub fn eviction(&self, nc: &CKBProtocolContext) {
-        let mut peer_state = self.peers.state.write();
-        let best_known_headers = self.peers.best_known_headers.read();
+        let mut peer_state = self.sync_shared.peers.state.write();
+        let best_known_headers = self.sync_shared.peers.best_known_headers.read();
         let is_initial_block_download = self.is_initial_block_download();
         let mut eviction = Vec::new();
         for (peer, state) in peer_state.iter_mut() {
             let now = now_ms();
-            // headers_sync_timeout
+            // headers_sync_timeout, peers waiting for a headers sync slot are not counted
             if let Some(timeout) = state.headers_sync_timeout {
-                if now > timeout && is_initial_block_download && !state.disconnect {
+                if now > timeout
+                    && is_initial_block_download
+                    && !state.disconnect
+                    && state.headers_sync != HeadersSyncState::Idle
+                {
                     eviction.push(*peer);
                     state.disconnect = true;
                     continue;
@@ -533,7 +648,7 @@ impl<CI: ChainIndex> Synchronizer<CI> {
                 if !state.chain_sync.protect && is_outbound {
                     let best_known_header = best_known_headers.get(peer);
 
-                    let chain_tip = self.shared.tip_header().read();
+                    let chain_tip = self.sync_shared.shared.tip_header().read();
                     if best_known_header.map(|h| h.total_difficulty())
                         >= Some(chain_tip.total_difficulty())
                     {
@@ -579,23 +694,15 @@ impl<CI: ChainIndex> Synchronizer<CI> {
         }
         for peer in eviction {
             warn!(target: "sync", "timeout eviction peer={}", peer);
-            nc.report_peer(peer, Severity::Timeout);
+            self.report_peer(nc, peer, Severity::Timeout);
         }
     }
 
+    // Headers are downloaded from a few peers at a time during the initial block download,
+    // afterwards every peer without a pending getheaders is polled.
     fn send_getheaders_to_all(&self, nc: &CKBProtocolContext) {
-        let peers: Vec<PeerIndex> = self
-            .peers
-            .state
-            .read()
-            .iter()
-            .filter(|(_, state)| state.sync_started)
-            .map(|(peer_id, _)| peer_id)
-            .cloned()
-            .collect();
-        debug!(target: "sync", "send_getheaders to peers= {:?}", &peers);
         let tip = {
-            let local = { self.shared.tip_header().read().clone() };
+            let local = { self.sync_shared.shared.tip_header().read().clone() };
             let best_known = self.best_known_header();
             if local.total_difficulty() > best_known.total_difficulty()
                 || (local.total_difficulty() == best_known.total_difficulty()
@@ -606,18 +713,32 @@ impl<CI: ChainIndex> Synchronizer<CI> {
                 best_known.into_inner()
             }
         };
+        let limit = if self.is_initial_block_download() {
+            MAX_HEADERS_SYNC_PEERS
+        } else {
+            usize::max_value()
+        };
+        let timeout = now_ms() + self.get_headers_sync_timeout(&tip);
+        let peers = self
+            .sync_shared
+            .peers
+            .select_headers_sync_peers(limit, timeout);
+        debug!(target: "sync", "send_getheaders to peers= {:?}", &peers);
         for peer in peers {
             self.send_getheaders_to_peer(nc, peer, &tip);
         }
     }
 
+    // Block bodies are downloaded from every peer in parallel, BlockFetcher only schedules
+    // blocks on the best header chain which are not in transit from another peer.
     fn find_blocks_to_fetch(&self, nc: &CKBProtocolContext) {
         let peers: Vec<PeerIndex> = self
+            .sync_shared
             .peers
             .state
             .read()
             .iter()
-            .filter(|(_, state)| state.sync_started)
+            .filter(|(_, state)| !state.disconnect)
             .map(|(peer_id, _)| peer_id)
             .cloned()
             .collect();
@@ -651,26 +772,57 @@ where
     }
 
     fn received(&self, nc: Box<CKBProtocolContext>, peer: PeerIndex, data: &[u8]) {
+        self.sync_shared
+            .shared
+            .sync_stats()
+            .lock()
+            .record_received("sync", data.len());
         // TODO use flatbuffers verifier
+        if data.len() < 4 {
+            self.misbehave(
+                nc.as_ref(),
+                peer,
+                MALFORMED_MESSAGE_PENALTY,
+                "malformed message",
+            );
+            return;
+        }
         let msg = get_root::<SyncMessage>(&data);
         debug!(target: "sync", "msg {:?}", msg.payload_type());
-        self.process(nc.as_ref(), peer, msg);
+        // every payload accessor of `process` relies on the payload being present
+        if msg.payload().is_none() {
+            self.misbehave(
+                nc.as_ref(),
+                peer,
+                MALFORMED_MESSAGE_PENALTY,
+                "message without payload",
+            );
+            return;
+        }
+        if let Err(err) = self.process(nc.as_ref(), peer, msg) {
+            self.misbehave(
+                nc.as_ref(),
+                peer,
+                MALFORMED_MESSAGE_PENALTY,
+                &format!("malformed message: {:?}", err),
+            );
+        }
     }
 
     fn connected(&self, nc: Box<CKBProtocolContext>, peer: PeerIndex) {
-        if self.n_sync.load(Ordering::Acquire) == 0 || !self.is_initial_block_download() {
-            debug!(target: "sync", "init_getheaders peer={:?} connected", peer);
-            self.on_connected(nc.as_ref(), peer);
-        }
+        debug!(target: "sync", "init_getheaders peer={:?} connected", peer);
+        self.sync_shared.connected(peer);
+        self.on_connected(nc.as_ref(), peer);
     }
 
     fn disconnected(&self, _nc: Box<CKBProtocolContext>, peer: PeerIndex) {
         info!(target: "sync", "peer={} SyncProtocol.disconnected", peer);
-        self.peers.disconnected(peer);
+        self.sync_shared.peers.disconnected(peer);
+        self.sync_shared.disconnected(peer);
     }
 
     fn timer_triggered(&self, nc: Box<CKBProtocolContext>, token: TimerToken) {
-        if !self.peers.state.read().is_empty() {
+        if !self.sync_shared.peers.state.read().is_empty() {
             match token as usize {
                 SEND_GET_HEADERS_TOKEN => {
                     self.send_getheaders_to_all(nc.as_ref());
@@ -694,11 +846,12 @@ mod tests {
     extern crate env_logger;
 
     use self::block_process::BlockProcess;
-    use self::headers_process::HeadersProcess;
+    use self::headers_process::{HeadersProcess, VerifierResolver};
     use super::*;
     use bigint::U256;
-    use ckb_chain::chain::ChainBuilder;
+    use ckb_chain::chain::{ChainBuilder, ChainController};
     use ckb_chain_spec::consensus::Consensus;
+    use ckb_chain_spec::hardfork::HardForkSwitch;
     use ckb_core::block::BlockBuilder;
     use ckb_core::header::{Header, HeaderBuilder};
     use ckb_core::transaction::{CellInput, CellOutput, Transaction, TransactionBuilder};
@@ -710,14 +863,16 @@ mod tests {
     use ckb_notify::{NotifyController, NotifyService, MINER_SUBSCRIBER};
     use ckb_protocol::{Block as FbsBlock, Headers as FbsHeaders};
     use ckb_shared::index::ChainIndex;
-    use ckb_shared::shared::SharedBuilder;
+    use ckb_shared::shared::{Shared, SharedBuilder};
     use ckb_shared::store::ChainKVStore;
     use ckb_time::set_mock_timer;
     use ckb_util::Mutex;
+    use ckb_verification::HeaderResolver;
     use flatbuffers::FlatBufferBuilder;
     use fnv::{FnvHashMap, FnvHashSet};
     use std::ops::Deref;
     use std::time::Duration;
+    use PEER_BAN_SCORE;
 
     fn start_chain(
         consensus: Option<Consensus>,
@@ -746,7 +901,8 @@ mod tests {
         chain_controller: ChainController,
         shared: Shared<CI>,
     ) -> Synchronizer<CI> {
-        Synchronizer::new(chain_controller, shared, Config::default())
+        let config = Config::default();
+        Synchronizer::new(SyncShared::new(chain_controller, shared, &config), config)
     }
 
     #[test]
@@ -824,6 +980,69 @@ mod tests {
         expect.push(shared.genesis_hash());
 
         assert_eq!(expect, locator);
+        assert_eq!(shared.get_locator(&shared.tip_header().read().inner()), locator);
+    }
+
+    #[test]
+    fn test_fork_locator() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        for i in 1..30 {
+            insert_block(&chain_controller, &shared, i, i);
+        }
+
+        // a fork of 5 blocks from block 10 is stored beside the main chain
+        let mut parent = shared.block_header(&shared.block_hash(10).unwrap()).unwrap();
+        let mut fork_hashes = Vec::new();
+        for i in 1..6 {
+            let difficulty = shared.calculate_difficulty(&parent).unwrap();
+            let new_block = gen_block(parent, difficulty, i + 1000);
+            chain_controller
+                .process_block(Arc::new(new_block.clone()))
+                .expect("process block ok");
+            fork_hashes.insert(0, new_block.header().hash());
+            parent = new_block.header().clone();
+        }
+        // the main chain stays the longer one
+        assert!(shared.block_hash(15) != Some(parent.hash()));
+
+        let locator = shared.get_locator(&parent);
+        let mut expect = fork_hashes;
+        for i in [10, 9, 8, 7, 6, 4, 0].iter() {
+            expect.push(shared.block_hash(*i).unwrap());
+        }
+        assert_eq!(locator, expect);
+
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        assert_eq!(synchronizer.get_locator(&parent), locator);
+    }
+
+    #[test]
+    fn test_locator_of_downloaded_headers() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        for i in 1..21 {
+            insert_block(&chain_controller, &shared, i, i);
+        }
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+
+        // 5 headers are downloaded ahead of their blocks
+        let mut parent = shared.tip_header().read().inner().clone();
+        let mut header_hashes = Vec::new();
+        for i in 1..6 {
+            let header = gen_block(parent.clone(), parent.difficulty(), i + 1000)
+                .header()
+                .clone();
+            synchronizer.insert_header_view(&header, 0);
+            header_hashes.insert(0, header.hash());
+            parent = header;
+        }
+
+        let mut expect = header_hashes;
+        for i in [20, 19, 18, 17, 16, 14, 10, 2, 0].iter() {
+            expect.push(shared.block_hash(*i).unwrap());
+        }
+        assert_eq!(synchronizer.get_locator(&parent), expect);
+        // the store alone doesn't know the parents of the downloaded headers
+        assert_eq!(shared.get_locator(&parent), vec![parent.hash()]);
     }
 
     #[test]
@@ -917,6 +1136,69 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_long_range_fork() {
+        let consensus = Consensus::default();
+        let (chain_controller1, shared1, _notify1) = start_chain(Some(consensus.clone()), None);
+        let (chain_controller2, shared2, _notify2) = start_chain(Some(consensus.clone()), None);
+
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut parent = consensus.genesis_block().header().clone();
+        for i in 1..200 {
+            let difficulty = shared1.calculate_difficulty(&parent).unwrap();
+            let new_block = gen_block(parent, difficulty, i);
+            blocks.push(new_block.clone());
+            for chain_controller in &[&chain_controller1, &chain_controller2] {
+                chain_controller
+                    .process_block(Arc::new(new_block.clone()))
+                    .expect("process block ok");
+            }
+            parent = new_block.header().clone();
+        }
+
+        // the second chain forks 49 blocks below the tip of the first
+        let mut fork_headers = Vec::new();
+        parent = blocks[149].header().clone();
+        for i in 1..60 {
+            let difficulty = shared2.calculate_difficulty(&parent).unwrap();
+            let new_block = gen_block(parent, difficulty, i + 1000);
+            chain_controller2
+                .process_block(Arc::new(new_block.clone()))
+                .expect("process block ok");
+            fork_headers.push(new_block.header().clone());
+            parent = new_block.header().clone();
+        }
+
+        let synchronizer = gen_synchronizer(chain_controller1.clone(), shared1.clone());
+        let peer = 1;
+        for header in &fork_headers {
+            synchronizer.insert_header_view(header, peer);
+        }
+
+        let main_headers = blocks[100..110]
+            .iter()
+            .map(|block| block.header().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(synchronizer.fork_depth(&main_headers, 100), None);
+        assert_eq!(synchronizer.fork_depth(&fork_headers[50..], 100), Some(49));
+        // a walk stopping early reports a bound deeper than the limit
+        assert_eq!(synchronizer.fork_depth(&fork_headers[50..], 10), Some(11));
+
+        synchronizer.check_long_range_fork(peer, &fork_headers[50..]);
+        assert_eq!(shared1.sync_stats().lock().long_range_forks, 0);
+
+        let mut config = Config::default();
+        config.long_range_fork_depth = 40;
+        let synchronizer = Synchronizer::new(synchronizer.sync_shared.clone(), config);
+        for header in &fork_headers {
+            synchronizer.insert_header_view(header, peer);
+        }
+        synchronizer.check_long_range_fork(peer, &fork_headers[50..]);
+        // the peer is alerted once
+        synchronizer.check_long_range_fork(peer, &fork_headers[50..]);
+        assert_eq!(shared1.sync_stats().lock().long_range_forks, 1);
+    }
+
     #[test]
     fn test_get_ancestor() {
         let consensus = Consensus::default();
@@ -944,6 +1226,29 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_accept_blocks_in_flight() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller, shared.clone());
+        let parent = shared.tip_header().read().inner().clone();
+        let difficulty = shared.calculate_difficulty(&parent).unwrap();
+        let block = Arc::new(gen_block(parent, difficulty, 1));
+        let hash = block.header().hash();
+
+        // the relayer got the same block first
+        let in_flight = synchronizer.sync_shared.in_flight_blocks.begin(hash);
+        assert!(synchronizer.accept_blocks(0, vec![block.clone()]).is_ok());
+        assert!(shared.block_header(&hash).is_none());
+        assert_eq!(synchronizer.get_block_status(&hash), BlockStatus::UNKNOWN);
+
+        drop(in_flight);
+        assert!(synchronizer.accept_blocks(0, vec![block.clone()]).is_ok());
+        assert_eq!(shared.tip_header().read().hash(), hash);
+        assert!(!synchronizer.sync_shared.in_flight_blocks.contains(&hash));
+        // stored blocks are not processed again
+        assert!(synchronizer.accept_blocks(0, vec![block.clone()]).is_ok());
+    }
+
     #[test]
     fn test_process_new_block() {
         let consensus = Consensus::default();
@@ -978,6 +1283,92 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_insert_new_block_with_orphans() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller, shared.clone());
+
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut parent = shared.tip_header().read().inner().clone();
+        for i in 1..40 {
+            let difficulty = shared.calculate_difficulty(&parent).unwrap();
+            let new_block = gen_block(parent, difficulty, i + 100);
+            parent = new_block.header().clone();
+            blocks.push(new_block);
+        }
+
+        // the descendants arrive first and wait for the first block
+        for block in blocks[1..].iter().rev() {
+            synchronizer.insert_new_block(0, block.clone());
+        }
+        assert_eq!(
+            synchronizer.sync_shared.orphan_block_pool.len(),
+            blocks.len() - 1
+        );
+        synchronizer.insert_new_block(0, blocks[0].clone());
+
+        assert_eq!(shared.tip_header().read().inner(), blocks[38].header());
+        assert!(synchronizer.sync_shared.orphan_block_pool.is_empty());
+        for block in &blocks {
+            assert_eq!(
+                synchronizer.get_block_status(&block.header().hash()),
+                BlockStatus::BLOCK_HAVE_MASK
+            );
+        }
+    }
+
+    #[test]
+    fn test_calculate_difficulty_of_synced_headers() {
+        let genesis_block = BlockBuilder::default()
+            .with_header_builder(HeaderBuilder::default().difficulty(&U256::from(1000)));
+        let mut consensus = Consensus::default()
+            .set_genesis_block(genesis_block)
+            .set_verification(false)
+            .set_min_difficulty(Some(U256::one()))
+            .set_hardfork_switch(HardForkSwitch {
+                epoch_duration_difficulty: Some(0),
+                ..Default::default()
+            });
+        consensus.pow_time_span = 4;
+        consensus.pow_spacing = 1;
+        let (chain_controller, shared, _notify) = start_chain(Some(consensus.clone()), None);
+        let (chain_controller2, shared2, _notify2) = start_chain(Some(consensus), None);
+        let synchronizer = gen_synchronizer(chain_controller2, shared2);
+
+        // the first epoch lasts twice the expected duration, only its headers are synced
+        let mut parent = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
+        for timestamp in &[2, 4, 6] {
+            let number = parent.number() + 1;
+            let header_builder = HeaderBuilder::default()
+                .parent_hash(&parent.hash())
+                .timestamp(*timestamp)
+                .number(number)
+                .difficulty(&shared.calculate_difficulty(&parent).unwrap());
+            let block = BlockBuilder::default()
+                .commit_transaction(create_cellbase(number))
+                .with_header_builder(header_builder);
+            chain_controller
+                .process_block(Arc::new(block.clone()))
+                .expect("process block ok");
+            parent = block.header().clone();
+            synchronizer.header_map.write().insert(
+                parent.hash(),
+                HeaderView::new(parent.clone(), U256::zero(), 0),
+            );
+        }
+
+        let header = HeaderBuilder::default()
+            .parent_hash(&parent.hash())
+            .number(parent.number() + 1)
+            .build();
+        let resolver = VerifierResolver::new(Some(&parent), &header, &synchronizer);
+        assert_eq!(resolver.calculate_difficulty(), Some(U256::from(500)));
+        assert_eq!(
+            resolver.calculate_difficulty(),
+            shared.calculate_difficulty(&parent)
+        );
+    }
+
     #[test]
     fn test_get_locator_response() {
         let consensus = Consensus::default();
@@ -1003,6 +1394,11 @@ mod tests {
         assert_eq!(headers.first().unwrap(), blocks[180].header());
         assert_eq!(headers.last().unwrap(), blocks[199].header());
 
+        // the stop header ends the response
+        let stopped = synchronizer.get_locator_response(180, &blocks[189].header().hash());
+        assert_eq!(stopped.len(), 10);
+        assert_eq!(stopped.last().unwrap(), blocks[189].header());
+
         for window in headers.windows(2) {
             if let [parent, header] = &window {
                 assert_eq!(header.parent_hash(), parent.hash());
@@ -1022,6 +1418,8 @@ mod tests {
                 peer_id: random_peer_id().unwrap(),
                 endpoint_role: Endpoint::Dialer,
                 last_ping_time: None,
+                ping: None,
+                score: 0,
                 remote_addresses: vec![],
                 identify_info: None,
             },
@@ -1064,6 +1462,10 @@ mod tests {
             unimplemented!();
         }
 
+        fn cancel_timer(&self, _token: TimerToken) -> Result<(), NetworkError> {
+            unimplemented!();
+        }
+
         /// Returns information on p2p session
         fn session_info(&self, peer: PeerIndex) -> Option<SessionInfo> {
             self.sessions.get(&peer).cloned()
@@ -1139,9 +1541,11 @@ mod tests {
         let fbs_headers = get_root::<FbsHeaders>(fbb.finished_data());
 
         let peer = 1usize;
-        HeadersProcess::new(&fbs_headers, &synchronizer1, peer, &mock_network_context(0)).execute();
+        HeadersProcess::new(&fbs_headers, &synchronizer1, peer, &mock_network_context(0))
+            .execute()
+            .expect("well formed headers");
 
-        let best_known_header = synchronizer1.peers.best_known_header(peer);
+        let best_known_header = synchronizer1.sync_shared.peers.best_known_header(peer);
 
         assert_eq!(best_known_header.unwrap().inner(), headers.last().unwrap());
 
@@ -1169,11 +1573,14 @@ mod tests {
             fbb.finish(fbs_block, None);
             let fbs_block = get_root::<FbsBlock>(fbb.finished_data());
 
-            BlockProcess::new(&fbs_block, &synchronizer1, peer, &mock_network_context(0)).execute();
+            BlockProcess::new(&fbs_block, &synchronizer1, peer, &mock_network_context(0))
+                .execute()
+                .expect("well formed block");
         }
 
         assert_eq!(
             &synchronizer1
+                .sync_shared
                 .peers
                 .last_common_headers
                 .read()
@@ -1202,6 +1609,11 @@ mod tests {
         peers.on_connected(0, 0, true);
         peers.on_connected(1, 0, false);
         peers.on_connected(2, MAX_TIP_AGE * 2, false);
+        // peers waiting for a headers sync slot are not evicted
+        peers.on_connected(3, 0, false);
+        peers.headers_requested(0);
+        peers.headers_requested(1);
+        peers.headers_requested(2);
         synchronizer.eviction(&network_context);
         let disconnected = network_context.disconnected.lock();
         assert_eq!(
@@ -1209,6 +1621,32 @@ mod tests {
             &FnvHashSet::from_iter(vec![0, 1].into_iter())
         )
     }
+    #[test]
+    fn test_headers_sync_peers_limit() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+        let peers = synchronizer.peers();
+        for peer in 0..5 {
+            peers.on_connected(peer, 0, false);
+        }
+
+        let selected = peers.select_headers_sync_peers(MAX_HEADERS_SYNC_PEERS, 0);
+        assert_eq!(selected.len(), MAX_HEADERS_SYNC_PEERS);
+        // all slots are taken until a peer answers
+        assert!(
+            peers
+                .select_headers_sync_peers(MAX_HEADERS_SYNC_PEERS, 0)
+                .is_empty()
+        );
+
+        peers.headers_synced(selected[0]);
+        let next = peers.select_headers_sync_peers(MAX_HEADERS_SYNC_PEERS, 0);
+        assert_eq!(next.len(), 1);
+        // the peers we never synced headers from go first
+        assert!(!selected.contains(&next[0]));
+    }
+
     #[test]
     fn test_chain_sync_timeout() {
         use std::iter::FromIterator;
@@ -1299,4 +1737,27 @@ mod tests {
             )
         }
     }
+
+    #[test]
+    fn test_misbehave_across_reconnections() {
+        let (chain_controller, shared, _notify) = start_chain(None, None);
+        let synchronizer = gen_synchronizer(chain_controller.clone(), shared.clone());
+
+        // the same peer connected twice, as peer 0 and then as peer 1
+        let session = mock_session_info();
+        let peer_id = session.peer.peer_id.clone();
+        let mut network_context = mock_network_context(0);
+        network_context.sessions.insert(0, session.clone());
+        network_context.sessions.insert(1, session);
+
+        let scores = &synchronizer.sync_shared.peer_scores;
+        synchronizer.misbehave(&network_context, 0, PEER_BAN_SCORE - 1, "test");
+        assert_eq!(scores.score(&peer_id), PEER_BAN_SCORE - 1);
+
+        synchronizer.disconnected(Box::new(network_context.clone()), 0);
+        // reconnecting doesn't clear the penalties, the next offence gets the peer banned
+        assert_eq!(scores.score(&peer_id), PEER_BAN_SCORE - 1);
+        synchronizer.misbehave(&network_context, 1, 1, "test");
+        assert_eq!(scores.score(&peer_id), 0);
+    }
 }
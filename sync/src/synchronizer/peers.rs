@@ -7,6 +7,7 @@ use ckb_shared::shared::TipHeader;
 use ckb_time::now_ms;
 use ckb_util::RwLock;
 use fnv::{FnvHashMap, FnvHashSet};
+use HEADERS_RESPONSE_TIMEOUT;
 
 // const BANSCORE: u32 = 100;
 
@@ -50,20 +51,49 @@ impl Default for ChainSyncState {
     }
 }
 
+// Headers-first sync: the header chain of a peer is downloaded and verified
+// before any of its block bodies are requested.
+//   Idle -> Requested: the peer got a headers sync slot and a getheaders is sent
+//   Requested -> Requested: a full headers message, the next batch is requested
+//   Requested -> Synced: less than MAX_HEADERS_LEN headers, we know the peer's best chain
+//   Requested(timeout) / Synced -> Requested: the peer is polled again when a slot is free
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeadersSyncState {
+    Idle,
+    Requested(u64), // ms
+    Synced,
+}
+
+impl Default for HeadersSyncState {
+    fn default() -> Self {
+        HeadersSyncState::Idle
+    }
+}
+
+impl HeadersSyncState {
+    pub fn is_requested(&self, now: u64) -> bool {
+        match *self {
+            HeadersSyncState::Requested(at) => now < at + HEADERS_RESPONSE_TIMEOUT,
+            _ => false,
+        }
+    }
+}
+
 #[derive(Clone, Default, Debug, PartialEq)]
 pub struct PeerState {
     pub negotiate: Negotiate,
-    pub sync_started: bool,
+    pub headers_sync: HeadersSyncState,
     pub last_block_announcement: Option<u64>, //ms
     pub headers_sync_timeout: Option<u64>,
     pub disconnect: bool,
     pub chain_sync: ChainSyncState,
+    /// The peer showed us a long-range fork, it's alerted once per connection
+    pub long_range_fork: bool,
 }
 
 #[derive(Debug, Default)]
 pub struct Peers {
     pub state: RwLock<FnvHashMap<PeerIndex, PeerState>>,
-    pub misbehavior: RwLock<FnvHashMap<PeerIndex, u32>>,
     pub blocks_inflight: RwLock<FnvHashMap<PeerIndex, BlocksInflight>>,
     pub best_known_headers: RwLock<FnvHashMap<PeerIndex, HeaderView>>,
     pub last_common_headers: RwLock<FnvHashMap<PeerIndex, Header>>,
@@ -111,17 +141,6 @@ impl BlocksInflight {
 }
 
 impl Peers {
-    pub fn misbehavior(&self, peer: PeerIndex, score: u32) {
-        if score == 0 {
-            return;
-        }
-
-        let mut map = self.misbehavior.write();
-        map.entry(peer)
-            .and_modify(|e| *e += score)
-            .or_insert_with(|| score);
-    }
-
     pub fn on_connected(&self, peer: PeerIndex, headers_sync_timeout: u64, protect: bool) {
         self.state
             .write()
@@ -134,11 +153,12 @@ impl Peers {
                 chain_sync.protect = protect;
                 PeerState {
                     negotiate: Negotiate::default(),
-                    sync_started: true,
+                    headers_sync: HeadersSyncState::Idle,
                     last_block_announcement: None,
                     headers_sync_timeout: Some(headers_sync_timeout),
                     disconnect: false,
                     chain_sync,
+                    long_range_fork: false,
                 }
             });
     }
@@ -168,18 +188,72 @@ impl Peers {
     pub fn connected(&self, peer: PeerIndex) {
         self.state.write().entry(peer).or_insert_with(|| PeerState {
             negotiate: Negotiate::default(),
-            sync_started: true,
+            headers_sync: HeadersSyncState::Idle,
             last_block_announcement: None,
             headers_sync_timeout: None,
             disconnect: false,
             chain_sync: ChainSyncState::default(),
+            long_range_fork: false,
         });
     }
 
+    /// Picks the peers to send a getheaders to, at most `limit` requests are in flight.
+    ///
+    /// Peers we have never synced headers from go first, the headers sync timeout
+    /// of a peer starts when it leaves `Idle`.
+    pub fn select_headers_sync_peers(
+        &self,
+        limit: usize,
+        headers_sync_timeout: u64,
+    ) -> Vec<PeerIndex> {
+        let now = now_ms();
+        let mut guard = self.state.write();
+        let requested = guard
+            .values()
+            .filter(|state| state.headers_sync.is_requested(now))
+            .count();
+        let mut candidates: Vec<(PeerIndex, HeadersSyncState)> = guard
+            .iter()
+            .filter(|(_, state)| !state.disconnect && !state.headers_sync.is_requested(now))
+            .map(|(peer, state)| (*peer, state.headers_sync))
+            .collect();
+        candidates.sort_by_key(|(_, headers_sync)| *headers_sync == HeadersSyncState::Synced);
+
+        candidates
+            .into_iter()
+            .take(limit.saturating_sub(requested))
+            .map(|(peer, headers_sync)| {
+                let state = guard.get_mut(&peer).expect("candidate state exists");
+                if headers_sync == HeadersSyncState::Idle {
+                    state.headers_sync_timeout = Some(headers_sync_timeout);
+                }
+                state.headers_sync = HeadersSyncState::Requested(now);
+                peer
+            }).collect()
+    }
+
+    pub fn headers_requested(&self, peer: PeerIndex) {
+        if let Some(state) = self.state.write().get_mut(&peer) {
+            state.headers_sync = HeadersSyncState::Requested(now_ms());
+        }
+    }
+
+    pub fn headers_synced(&self, peer: PeerIndex) {
+        if let Some(state) = self.state.write().get_mut(&peer) {
+            state.headers_sync = HeadersSyncState::Synced;
+        }
+    }
+
+    /// Marks the peer as on a long-range fork, false if it already was
+    pub fn long_range_fork_detected(&self, peer: PeerIndex) -> bool {
+        let mut guard = self.state.write();
+        let state = guard.entry(peer).or_insert_with(PeerState::default);
+        !::std::mem::replace(&mut state.long_range_fork, true)
+    }
+
     pub fn disconnected(&self, peer: PeerIndex) {
         self.state.write().remove(&peer);
         self.best_known_headers.write().remove(&peer);
-        // self.misbehavior.write().remove(peer);
         self.blocks_inflight.write().remove(&peer);
         self.last_common_headers.write().remove(&peer);
     }
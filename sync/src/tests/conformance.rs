@@ -0,0 +1,305 @@
+//! Conformance vectors of the relay protocol.
+//!
+//! Each scenario under `fixtures/conformance` is an exchange between a peer and a node on a fixed
+//! chain: the messages the peer sends, the messages the node answers with and the chain and pool
+//! state the exchange leaves. The messages of the peer are replayed against the relayer and
+//! everything the node answers or ends up with is compared with the file, so other
+//! implementations can check their wire compatibility against the same files. When a change of
+//! the exchanges is intended, regenerate the vectors with
+//! `UPDATE_CONFORMANCE=1 cargo test -p ckb-sync conformance` and review the diff.
+
+use bigint::H256;
+use ckb_chain_spec::consensus::MAX_CELL_DATA_BYTES;
+use ckb_core::block::{Block, BlockBuilder};
+use ckb_core::header::HeaderBuilder;
+use ckb_core::transaction::{
+    CellInput, CellOutput, OutPoint, ProposalShortId, Transaction, TransactionBuilder,
+};
+use ckb_core::BlockNumber;
+use ckb_db::memorydb::MemoryKeyValueDB;
+use ckb_network::CKBProtocolHandler;
+use ckb_protocol::{
+    CompactBlock as FbsCompactBlock, RelayMessage, RelayMessageBuilder, RelayPayload,
+};
+use ckb_shared::shared::{ChainProvider, Shared};
+use ckb_shared::store::ChainKVStore;
+use flatbuffers::FlatBufferBuilder;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::channel;
+use tests::relayer::{create_valid_script, setup_relayer_at};
+use tests::TestNetworkContext;
+use RELAY_PROTOCOL_ID;
+
+/// The chain of every scenario, three blocks after a genesis block mined at this time
+const GENESIS_TIMESTAMP: u64 = 1_540_000_000_000;
+const CHAIN_HEIGHT: u64 = 3;
+/// The short ids of the compact blocks are salted with a random nonce, the vectors use this one
+const COMPACT_BLOCK_NONCE: u64 = 0x0102_0304_0506_0708;
+const PEER: usize = 0;
+
+/// A line of a vector
+#[derive(Clone, Debug, PartialEq)]
+enum Step {
+    /// `recv <hex>`, a message of the peer to the node
+    Recv(Vec<u8>),
+    /// `send <hex>`, a message of the node to the peer, answering the last received one
+    Send(Vec<u8>),
+    /// `tip <number> <hash>`, the tip of the node
+    Tip(BlockNumber, H256),
+    /// `pool <hash> <status>`, the pool status of a transaction, `none` if not in the pool
+    Pool(H256, String),
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).expect("hex string"))
+        .collect()
+}
+
+fn format_steps(steps: &[Step]) -> String {
+    steps
+        .iter()
+        .map(|step| match *step {
+            Step::Recv(ref data) => format!("recv {}\n", encode_hex(data)),
+            Step::Send(ref data) => format!("send {}\n", encode_hex(data)),
+            Step::Tip(number, ref hash) => format!("tip {} {}\n", number, encode_hex(hash)),
+            Step::Pool(ref hash, ref status) => {
+                format!("pool {} {}\n", encode_hex(hash), status)
+            }
+        })
+        .collect()
+}
+
+fn parse_steps(text: &str) -> Vec<Step> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            let kind = fields.next().expect("step kind");
+            let mut field = || {
+                fields
+                    .next()
+                    .unwrap_or_else(|| panic!("short step {}", line))
+            };
+            match kind {
+                "recv" => Step::Recv(decode_hex(field())),
+                "send" => Step::Send(decode_hex(field())),
+                "tip" => Step::Tip(
+                    field().parse().expect("tip number"),
+                    H256::from_slice(&decode_hex(field())),
+                ),
+                "pool" => Step::Pool(H256::from_slice(&decode_hex(field())), field().to_string()),
+                _ => panic!("unknown step {}", line),
+            }
+        })
+        .collect()
+}
+
+/// Replays the messages of the peer against a new node, the answers of the node and the state
+/// it is in at every `tip` and `pool` step make up the returned exchange
+fn replay(steps: &[Step]) -> Vec<Step> {
+    let (relayer, shared, _chain_controller, tx_pool) =
+        setup_relayer_at(CHAIN_HEIGHT, GENESIS_TIMESTAMP);
+    let (sender, receiver) = channel();
+    let mut msg_senders = HashMap::new();
+    msg_senders.insert((RELAY_PROTOCOL_ID, PEER), sender);
+
+    let mut exchange = Vec::new();
+    for step in steps {
+        match *step {
+            Step::Recv(ref data) => {
+                let nc = TestNetworkContext {
+                    protocol: RELAY_PROTOCOL_ID,
+                    msg_senders: msg_senders.clone(),
+                    timer_senders: HashMap::new(),
+                };
+                relayer.received(Box::new(nc), PEER, data);
+                exchange.push(Step::Recv(data.clone()));
+                exchange.extend(receiver.try_iter().map(Step::Send));
+            }
+            // the answers are recorded along the message they answer
+            Step::Send(_) => {}
+            Step::Tip(..) => {
+                let tip = shared.tip_header().read();
+                exchange.push(Step::Tip(tip.number(), tip.hash()));
+            }
+            Step::Pool(ref hash, _) => {
+                let status = tx_pool
+                    .get_pool_transaction(ProposalShortId::from_h256(hash))
+                    .filter(|entry| entry.transaction.hash() == *hash)
+                    .map(|entry| format!("{:?}", entry.status))
+                    .unwrap_or_else(|| "none".to_string());
+                exchange.push(Step::Pool(*hash, status));
+            }
+        }
+    }
+    exchange
+}
+
+/// Checks the vector `name` against the relayer, `scenario` is the exchange the vector is
+/// generated from, without the answers of the node
+fn check_conformance(name: &str, scenario: &[Step]) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("fixtures")
+        .join("conformance")
+        .join(format!("{}.txt", name));
+
+    if env::var("UPDATE_CONFORMANCE").is_ok() {
+        let exchange = replay(scenario);
+        let state = exchange
+            .iter()
+            .filter(|step| match **step {
+                Step::Send(_) => false,
+                _ => true,
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(state, scenario, "scenario {} ended in another state", name);
+        fs::write(&path, format_steps(&exchange)).expect("write conformance vector");
+        return;
+    }
+
+    let expected = parse_steps(&fs::read_to_string(&path).unwrap_or_else(|_| {
+        panic!(
+            "missing conformance vector {}, run with UPDATE_CONFORMANCE=1 to generate it",
+            path.display()
+        )
+    }));
+    assert_eq!(
+        replay(&expected),
+        expected,
+        "relay exchange {} changed",
+        name
+    );
+}
+
+fn chain_tip() -> (Shared<ChainKVStore<MemoryKeyValueDB>>, Block) {
+    let (_relayer, shared, _chain_controller, _tx_pool) =
+        setup_relayer_at(CHAIN_HEIGHT, GENESIS_TIMESTAMP);
+    let tip = shared.block(&shared.tip_header().read().hash()).unwrap();
+    (shared, tip)
+}
+
+fn next_block(
+    shared: &Shared<ChainKVStore<MemoryKeyValueDB>>,
+    parent: &Block,
+    transactions: Vec<Transaction>,
+    proposals: Vec<ProposalShortId>,
+) -> Block {
+    let number = parent.header().number() + 1;
+    let cellbase = TransactionBuilder::default()
+        .input(CellInput::new_cellbase_input(number))
+        .output(CellOutput::default())
+        .build();
+
+    let header_builder = HeaderBuilder::default()
+        .parent_hash(&parent.header().hash())
+        .number(number)
+        .timestamp(parent.header().timestamp() + 1)
+        .difficulty(&shared.calculate_difficulty(&parent.header()).unwrap())
+        .cellbase_id(&cellbase.hash());
+
+    BlockBuilder::default()
+        .commit_transaction(cellbase)
+        .commit_transactions(transactions)
+        .proposal_transactions(proposals)
+        .with_header_builder(header_builder)
+}
+
+fn spend(parent: &Transaction, index: u32, data: Vec<u8>) -> Transaction {
+    TransactionBuilder::default()
+        .input(CellInput::new(
+            OutPoint::new(parent.hash(), index),
+            create_valid_script(),
+        ))
+        .output(CellOutput::new(50, data, H256::zero(), None))
+        .build()
+}
+
+fn compact_block_message(block: &Block) -> Vec<u8> {
+    let fbb = &mut FlatBufferBuilder::new();
+    let compact_block =
+        FbsCompactBlock::build_with_nonce(fbb, block, &HashSet::new(), COMPACT_BLOCK_NONCE);
+    let message = {
+        let mut builder = RelayMessageBuilder::new(fbb);
+        builder.add_payload_type(RelayPayload::CompactBlock);
+        builder.add_payload(compact_block.as_union_value());
+        builder.finish()
+    };
+    fbb.finish(message, None);
+    fbb.finished_data().to_vec()
+}
+
+fn transaction_message(tx: &Transaction) -> Vec<u8> {
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = RelayMessage::build_transaction(fbb, tx);
+    fbb.finish(message, None);
+    fbb.finished_data().to_vec()
+}
+
+/// A compact block proposing an unknown transaction, then one committing it: the node asks for
+/// the proposal and for the missing transaction, and accepts the block once it has it
+#[test]
+fn conformance_compact_block_missing_transaction() {
+    let (shared, tip) = chain_tip();
+    let tx = spend(&tip.commit_transactions()[0], 0, Vec::new());
+    let proposal_block = next_block(&shared, &tip, Vec::new(), vec![tx.proposal_short_id()]);
+    let commit_block = next_block(&shared, &proposal_block, vec![tx.clone()], Vec::new());
+
+    let block_transactions = {
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_block_transactions(
+            fbb,
+            &commit_block.header().hash(),
+            &[tx.clone()],
+        );
+        fbb.finish(message, None);
+        fbb.finished_data().to_vec()
+    };
+
+    check_conformance(
+        "compact_block_missing_transaction",
+        &[
+            Step::Recv(compact_block_message(&proposal_block)),
+            Step::Tip(
+                proposal_block.header().number(),
+                proposal_block.header().hash(),
+            ),
+            Step::Recv(compact_block_message(&commit_block)),
+            Step::Recv(block_transactions),
+            Step::Tip(commit_block.header().number(), commit_block.header().hash()),
+            Step::Pool(tx.hash(), "none".to_string()),
+        ],
+    );
+}
+
+/// A transaction the node queues for its proposal, then one it rejects with its reason
+#[test]
+fn conformance_relayed_transactions() {
+    let (_shared, tip) = chain_tip();
+    let valid = spend(&tip.commit_transactions()[0], 1, Vec::new());
+    let oversized = spend(
+        &tip.commit_transactions()[0],
+        2,
+        vec![0; MAX_CELL_DATA_BYTES as usize + 1],
+    );
+
+    check_conformance(
+        "relayed_transactions",
+        &[
+            Step::Recv(transaction_message(&valid)),
+            Step::Pool(valid.hash(), "Pending".to_string()),
+            Step::Recv(transaction_message(&oversized)),
+            Step::Pool(oversized.hash(), "none".to_string()),
+        ],
+    );
+}
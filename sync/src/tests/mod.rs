@@ -8,6 +8,7 @@ use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
+mod conformance;
 mod relayer;
 mod synchronizer;
 
@@ -141,10 +142,13 @@ impl CKBProtocolContext for TestNetworkContext {
     /// Send a packet over the network to another peer using specified protocol.
     fn send_protocol(
         &self,
-        _peer: PeerIndex,
-        _protocol: ProtocolId,
-        _data: Vec<u8>,
+        peer: PeerIndex,
+        protocol: ProtocolId,
+        data: Vec<u8>,
     ) -> Result<(), NetworkError> {
+        if let Some(sender) = self.msg_senders.get(&(protocol, peer)) {
+            let _ = sender.send(data);
+        }
         Ok(())
     }
 
@@ -161,6 +165,10 @@ impl CKBProtocolContext for TestNetworkContext {
         Ok(())
     }
 
+    fn cancel_timer(&self, _token: TimerToken) -> Result<(), NetworkError> {
+        Ok(())
+    }
+
     fn ban_peer(&self, _peer: PeerIndex, _duration: Duration) {}
 
     /// Returns information on p2p session
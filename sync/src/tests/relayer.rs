@@ -1,29 +1,39 @@
 use bigint::{H256, U256};
 use ckb_chain::chain::{ChainBuilder, ChainController};
-use ckb_chain_spec::consensus::Consensus;
-use ckb_core::block::BlockBuilder;
+use ckb_chain_spec::consensus::{Consensus, MAX_CELL_DATA_BYTES};
+use ckb_core::block::{Block, BlockBuilder};
 use ckb_core::header::HeaderBuilder;
 use ckb_core::script::Script;
-use ckb_core::transaction::{CellInput, CellOutput, OutPoint, TransactionBuilder};
+use ckb_core::transaction::{CellInput, CellOutput, OutPoint, Transaction, TransactionBuilder};
 use ckb_db::memorydb::MemoryKeyValueDB;
+use ckb_network::CKBProtocolHandler;
 use ckb_notify::NotifyService;
 use ckb_pool::txs_pool::{PoolConfig, TransactionPoolController, TransactionPoolService};
-use ckb_protocol::RelayMessage;
+use ckb_protocol::{short_transaction_id, short_transaction_id_keys, RelayMessage, SyncMessage};
 use ckb_shared::shared::{ChainProvider, Shared, SharedBuilder};
 use ckb_shared::store::ChainKVStore;
 use ckb_time::now_ms;
+use ckb_verification::TransactionError;
 use flatbuffers::get_root;
 use flatbuffers::FlatBufferBuilder;
-use relayer::TX_PROPOSAL_TOKEN;
-use std::collections::HashSet;
+use fnv::FnvHashMap;
+use relayer::compact_block::{CompactBlock, PrefilledTransaction};
+use relayer::{
+    index_by_short_id, split_announcements, PendingCompactBlock, ReconstructionResult,
+    TX_PROPOSAL_TOKEN,
+};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
 use std::sync::mpsc::channel;
 use std::sync::{Arc, Barrier};
 use std::{thread, time};
-use tests::TestNode;
-use {Relayer, RELAY_PROTOCOL_ID};
+use tests::{TestNetworkContext, TestNode};
+use {
+    Config, Relayer, SyncShared, FAST_ANNOUNCE_PEERS, MAX_BLOCK_TRANSACTIONS_ROUND_TRIPS,
+    RELAY_PROTOCOL_ID, SYNC_PROTOCOL_ID,
+};
 
 #[test]
 fn relay_compact_block_with_one_tx() {
@@ -271,19 +281,496 @@ fn relay_compact_block_with_missing_indexs() {
     assert_eq!(shared2.tip_header().read().number(), 5);
 }
 
+#[test]
+fn relay_compact_block_with_only_cellbase() {
+    let (node, shared, _chain_controller) = setup_node(3);
+
+    let block = next_block(&shared);
+
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = RelayMessage::build_compact_block(fbb, &block, &HashSet::new());
+    fbb.finish(message, None);
+
+    let (sender, receiver) = channel();
+    let mut msg_senders = HashMap::new();
+    msg_senders.insert((RELAY_PROTOCOL_ID, 0), sender);
+    let nc = TestNetworkContext {
+        protocol: RELAY_PROTOCOL_ID,
+        msg_senders,
+        timer_senders: HashMap::new(),
+    };
+    node.protocols[&RELAY_PROTOCOL_ID].received(Box::new(nc), 0, fbb.finished_data());
+
+    // reconstructed and accepted without any request to the peer
+    assert_eq!(shared.tip_header().read().number(), 4);
+    assert!(receiver.try_recv().is_err());
+}
+
+#[test]
+fn relay_new_header_to_header_first_peer() {
+    let (node, shared, _chain_controller) = setup_node(3);
+
+    let block = next_block(&shared);
+
+    let (sender0, receiver0) = channel();
+    let (sender1, receiver1) = channel();
+    let mut msg_senders = HashMap::new();
+    msg_senders.insert((RELAY_PROTOCOL_ID, 0), sender0);
+    msg_senders.insert((RELAY_PROTOCOL_ID, 1), sender1);
+    let nc = || {
+        Box::new(TestNetworkContext {
+            protocol: RELAY_PROTOCOL_ID,
+            msg_senders: msg_senders.clone(),
+            timer_senders: HashMap::new(),
+        })
+    };
+
+    // peer 0 only wants the headers of new blocks
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = RelayMessage::build_handshake(fbb, true);
+    fbb.finish(message, None);
+    node.protocols[&RELAY_PROTOCOL_ID].received(nc(), 0, fbb.finished_data());
+
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = RelayMessage::build_compact_block(fbb, &block, &HashSet::new());
+    fbb.finish(message, None);
+    node.protocols[&RELAY_PROTOCOL_ID].received(nc(), 1, fbb.finished_data());
+    assert_eq!(shared.tip_header().read().number(), 4);
+
+    let data = receiver0.try_recv().expect("block announced to peer 0");
+    let message = get_root::<RelayMessage>(&data);
+    let header = message.payload_as_new_header().unwrap().header().unwrap();
+    assert_eq!(header.number(), 4);
+    assert!(receiver1.try_recv().is_err());
+}
+
+#[test]
+fn reject_relayed_transaction() {
+    let (node, _shared, _chain_controller) = setup_node(1);
+
+    let (sender0, receiver0) = channel();
+    let (sender1, receiver1) = channel();
+    let mut msg_senders = HashMap::new();
+    msg_senders.insert((RELAY_PROTOCOL_ID, 0), sender0);
+    msg_senders.insert((RELAY_PROTOCOL_ID, 1), sender1);
+    let nc = Box::new(TestNetworkContext {
+        protocol: RELAY_PROTOCOL_ID,
+        msg_senders,
+        timer_senders: HashMap::new(),
+    });
+
+    // the data of the output exceeds the limit of a cell
+    let data = vec![0; MAX_CELL_DATA_BYTES as usize + 1];
+    let tx = TransactionBuilder::default()
+        .input(CellInput::new(
+            OutPoint::new(H256::from(1), 0),
+            Default::default(),
+        ))
+        .output(CellOutput::new(50, data, H256::default(), None))
+        .build();
+    let fbb = &mut FlatBufferBuilder::new();
+    let message = RelayMessage::build_transaction(fbb, &tx);
+    fbb.finish(message, None);
+    node.protocols[&RELAY_PROTOCOL_ID].received(nc, 0, fbb.finished_data());
+
+    let data = receiver0.try_recv().expect("rejection sent to peer 0");
+    let message = get_root::<RelayMessage>(&data);
+    let reject = message.payload_as_transaction_reject().unwrap();
+    let expected = TransactionError::ExceededMaximumCellDataBytes {
+        max: MAX_CELL_DATA_BYTES,
+        actual: MAX_CELL_DATA_BYTES + 1,
+    };
+    assert_eq!(reject.code(), expected.code());
+    assert_eq!(
+        H256::from_slice(reject.hash().and_then(|bytes| bytes.seq()).unwrap()),
+        tx.hash()
+    );
+    assert!(receiver1.try_recv().is_err());
+}
+
+#[test]
+fn relay_transaction_once_per_peer() {
+    let (node, shared, _chain_controller) = setup_node(1);
+
+    let mut receivers = Vec::new();
+    let mut msg_senders = HashMap::new();
+    for peer in 0..3 {
+        let (sender, receiver) = channel();
+        msg_senders.insert((RELAY_PROTOCOL_ID, peer), sender);
+        receivers.push(receiver);
+    }
+    let nc = || {
+        Box::new(TestNetworkContext {
+            protocol: RELAY_PROTOCOL_ID,
+            msg_senders: msg_senders.clone(),
+            timer_senders: HashMap::new(),
+        })
+    };
+    let relay = |peer, tx: &Transaction| {
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_transaction(fbb, tx);
+        fbb.finish(message, None);
+        node.protocols[&RELAY_PROTOCOL_ID].received(nc(), peer, fbb.finished_data());
+    };
+
+    let tx = TransactionBuilder::default()
+        .input(CellInput::new(
+            OutPoint::new(H256::from(1), 0),
+            create_valid_script(),
+        )).output(CellOutput::new(50, Vec::new(), H256::zero(), None))
+        .build();
+    relay(0, &tx);
+    assert!(receivers[0].try_recv().is_err());
+    assert!(receivers[1].try_recv().is_ok());
+    assert!(receivers[2].try_recv().is_ok());
+
+    // relayed back by a peer it was sent to, nobody gets it again
+    relay(1, &tx);
+    assert!(receivers
+        .iter()
+        .all(|receiver| receiver.try_recv().is_err()));
+
+    // the transactions of the tip are committed already
+    let tip = shared.block(&shared.tip_header().read().hash()).unwrap();
+    relay(0, &tip.commit_transactions()[0]);
+    assert!(receivers
+        .iter()
+        .all(|receiver| receiver.try_recv().is_err()));
+}
+
+#[test]
+fn reconstruct_block_with_duplicate_short_ids() {
+    let (relayer, _shared, _chain_controller) = setup_relayer(0);
+
+    let cellbase = TransactionBuilder::default()
+        .input(CellInput::new_cellbase_input(1))
+        .output(CellOutput::new(50, Vec::new(), H256::zero(), None))
+        .build();
+    let txs = (0..2)
+        .map(|i| {
+            TransactionBuilder::default()
+                .input(CellInput::new(
+                    OutPoint::new(cellbase.hash(), i),
+                    create_valid_script(),
+                )).output(CellOutput::new(25, Vec::new(), H256::zero(), None))
+                .build()
+        }).collect::<Vec<_>>();
+    let block = BlockBuilder::default()
+        .commit_transaction(cellbase.clone())
+        .commit_transactions(txs.clone())
+        .with_header_builder(HeaderBuilder::default().number(1));
+
+    // both transactions are announced by the same short id, neither can be picked
+    let nonce = 0;
+    let (key0, key1) = short_transaction_id_keys(block.header().nonce(), nonce);
+    let short_id = short_transaction_id(key0, key1, &txs[0].hash());
+    let compact_block = CompactBlock {
+        header: block.header().clone(),
+        uncles: Vec::new(),
+        nonce,
+        short_ids: vec![short_id, short_id],
+        prefilled_transactions: vec![PrefilledTransaction {
+            index: 0,
+            transaction: cellbase,
+        }],
+        proposal_transactions: Vec::new(),
+    };
+
+    let mut filled = FnvHashMap::default();
+    assert_eq!(
+        relayer.reconstruct_block(&compact_block, &filled),
+        ReconstructionResult::Missing(vec![1, 2])
+    );
+
+    filled.insert(1, txs[1].clone());
+    filled.insert(2, txs[0].clone());
+    assert_eq!(
+        relayer.reconstruct_block(&compact_block, &filled),
+        ReconstructionResult::Invalid
+    );
+
+    filled.insert(1, txs[0].clone());
+    filled.insert(2, txs[1].clone());
+    assert_eq!(
+        relayer.reconstruct_block(&compact_block, &filled),
+        ReconstructionResult::Block(block)
+    );
+}
+
+#[test]
+fn index_pool_transactions_by_short_id() {
+    let txs = (0..2)
+        .map(|i| {
+            TransactionBuilder::default()
+                .input(CellInput::new(
+                    OutPoint::new(H256::from(1), i),
+                    Default::default(),
+                )).build()
+        }).collect::<Vec<_>>();
+    let (key0, key1) = short_transaction_id_keys(0, 0);
+    let txs_map = index_by_short_id(txs.clone(), |hash| short_transaction_id(key0, key1, hash));
+    assert_eq!(txs_map.len(), 2);
+    assert!(
+        txs.iter()
+            .all(|tx| txs_map[&short_transaction_id(key0, key1, &tx.hash())] == Some(tx.clone()))
+    );
+
+    // the same transaction twice isn't a collision
+    let short_id = short_transaction_id(key0, key1, &txs[0].hash());
+    let txs_map = index_by_short_id(vec![txs[0].clone(), txs[0].clone()], |_| short_id);
+    assert_eq!(txs_map[&short_id], Some(txs[0].clone()));
+
+    // two transactions sharing a short id, none of them can be picked
+    let txs_map = index_by_short_id(
+        vec![txs[0].clone(), txs[1].clone(), txs[0].clone()],
+        |_| short_id,
+    );
+    assert_eq!(txs_map.len(), 1);
+    assert_eq!(txs_map[&short_id], None);
+}
+
+#[test]
+fn reconstruct_block_with_block_transactions() {
+    let (relayer, _shared, _chain_controller) = setup_relayer(1);
+
+    let mut receivers = Vec::new();
+    let mut msg_senders = HashMap::new();
+    for peer in 0..2 {
+        let (sender, receiver) = channel();
+        msg_senders.insert((RELAY_PROTOCOL_ID, peer), sender);
+        receivers.push(receiver);
+    }
+    let nc = || {
+        Box::new(TestNetworkContext {
+            protocol: RELAY_PROTOCOL_ID,
+            msg_senders: msg_senders.clone(),
+            timer_senders: HashMap::new(),
+        })
+    };
+
+    // the parent is unknown, the block is kept as orphan once reconstructed
+    let cellbase = TransactionBuilder::default()
+        .input(CellInput::new_cellbase_input(10))
+        .output(CellOutput::default())
+        .build();
+    let txs = (0..2)
+        .map(|i| {
+            TransactionBuilder::default()
+                .input(CellInput::new(
+                    OutPoint::new(H256::from(1), i),
+                    Default::default(),
+                )).output(CellOutput::new(50, Vec::new(), H256::zero(), None))
+                .build()
+        }).collect::<Vec<_>>();
+    let block = BlockBuilder::default()
+        .commit_transaction(cellbase.clone())
+        .commit_transactions(txs.clone())
+        .with_header_builder(
+            HeaderBuilder::default()
+                .parent_hash(&H256::from(1))
+                .number(10)
+                .cellbase_id(&cellbase.hash()),
+        );
+    let hash = block.header().hash();
+
+    let send_compact_block = |peer| {
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_compact_block(fbb, &block, &HashSet::new());
+        fbb.finish(message, None);
+        relayer.received(nc(), peer, fbb.finished_data());
+    };
+    let send_block_transactions = |peer, transactions: &[Transaction]| {
+        let fbb = &mut FlatBufferBuilder::new();
+        let message = RelayMessage::build_block_transactions(fbb, &hash, transactions);
+        fbb.finish(message, None);
+        relayer.received(nc(), peer, fbb.finished_data());
+    };
+    let requested_indexes = |peer: usize| {
+        let data = receivers[peer].try_recv().expect("transactions requested");
+        let message = get_root::<RelayMessage>(&data);
+        message
+            .payload_as_get_block_transactions()
+            .and_then(|request| request.indexes())
+            .map(|indexes| indexes.safe_slice().to_vec())
+            .expect("GetBlockTransactions")
+    };
+
+    send_compact_block(0);
+    assert_eq!(requested_indexes(0), vec![1, 2]);
+    assert_eq!(relayer.pending_round_trips(&hash), Some(1));
+
+    // less transactions than requested, the block is downloaded whole instead
+    send_block_transactions(0, &txs[..1]);
+    assert_eq!(relayer.pending_round_trips(&hash), None);
+    assert!(receivers[0].try_recv().is_err());
+
+    send_compact_block(1);
+    assert_eq!(requested_indexes(1), vec![1, 2]);
+    send_block_transactions(1, &txs);
+    assert_eq!(relayer.pending_round_trips(&hash), None);
+    assert!(receivers[1].try_recv().is_err());
+}
+
+#[test]
+fn bound_block_transactions_round_trips() {
+    let (relayer, _shared, _chain_controller) = setup_relayer(1);
+    let (sender, receiver) = channel();
+    let mut msg_senders = HashMap::new();
+    msg_senders.insert((RELAY_PROTOCOL_ID, 0), sender);
+    let nc = TestNetworkContext {
+        protocol: RELAY_PROTOCOL_ID,
+        msg_senders,
+        timer_senders: HashMap::new(),
+    };
+
+    let block = BlockBuilder::default().with_header_builder(HeaderBuilder::default().number(10));
+    let compact_block = CompactBlock {
+        header: block.header().clone(),
+        uncles: Vec::new(),
+        nonce: 0,
+        short_ids: Vec::new(),
+        prefilled_transactions: Vec::new(),
+        proposal_transactions: Vec::new(),
+    };
+    let mut pending = PendingCompactBlock::new(compact_block);
+    for round_trip in 1..=MAX_BLOCK_TRANSACTIONS_ROUND_TRIPS {
+        assert!(relayer.request_block_transactions(&nc, 0, &mut pending, vec![round_trip]));
+        assert_eq!(pending.round_trips, round_trip);
+        assert_eq!(pending.requested, vec![round_trip]);
+        assert!(receiver.try_recv().is_ok());
+    }
+
+    // the round trips are used up, nothing is asked anymore
+    assert!(!relayer.request_block_transactions(&nc, 0, &mut pending, vec![0]));
+    assert_eq!(pending.round_trips, MAX_BLOCK_TRANSACTIONS_ROUND_TRIPS);
+    assert!(receiver.try_recv().is_err());
+}
+
+#[test]
+fn request_headers_up_to_full_block() {
+    let (relayer, shared, _chain_controller) = setup_relayer(2);
+    let (sender, receiver) = channel();
+    let mut msg_senders = HashMap::new();
+    msg_senders.insert((SYNC_PROTOCOL_ID, 0), sender);
+    let nc = TestNetworkContext {
+        protocol: RELAY_PROTOCOL_ID,
+        msg_senders,
+        timer_senders: HashMap::new(),
+    };
+
+    let hash = H256::from(7);
+    relayer.request_full_block(&nc, 0, &hash);
+
+    // the headers following the tip are asked up to the block only
+    let data = receiver.try_recv().unwrap();
+    let message = get_root::<SyncMessage>(&data);
+    let get_headers = message.payload_as_get_headers().unwrap();
+    let hash_stop = get_headers
+        .hash_stop()
+        .and_then(|bytes| bytes.seq())
+        .unwrap();
+    assert_eq!(H256::from_slice(hash_stop), hash);
+    let locator = get_headers.block_locator_hashes().unwrap();
+    assert_eq!(
+        H256::from_slice(locator.get(0).seq().unwrap()),
+        shared.tip_header().read().hash()
+    );
+}
+
+#[test]
+fn announce_to_fastest_peers_first() {
+    let ms = time::Duration::from_millis;
+    let peers = vec![
+        (0, None, 10),
+        (1, Some(ms(300)), 0),
+        (2, Some(ms(20)), 0),
+        (3, Some(ms(25)), 5),
+        (4, Some(ms(80)), 0),
+        (5, Some(ms(5)), 0),
+        (6, None, 0),
+    ];
+    let (first, rest) = split_announcements(peers);
+    // the pings of 2 and 3 are equally fast, 3 has the better score
+    assert_eq!(first, vec![5, 3, 2, 4]);
+    assert_eq!(first.len(), FAST_ANNOUNCE_PEERS);
+    // the peers not pinged yet come last, every peer gets the block once
+    assert_eq!(rest, vec![1, 0, 6]);
+
+    let (first, rest) = split_announcements(vec![(0, Some(ms(5)), 0)]);
+    assert_eq!((first, rest), (vec![0], Vec::new()));
+}
+
+// A block with only a cellbase on top of the tip
+fn next_block(shared: &Shared<ChainKVStore<MemoryKeyValueDB>>) -> Block {
+    let parent = shared.block(&shared.tip_header().read().hash()).unwrap();
+    let number = parent.header().number() + 1;
+    let cellbase = TransactionBuilder::default()
+        .input(CellInput::new_cellbase_input(number))
+        .output(CellOutput::default())
+        .build();
+
+    let header_builder = HeaderBuilder::default()
+        .parent_hash(&parent.header().hash())
+        .number(number)
+        .timestamp(parent.header().timestamp() + 1)
+        .difficulty(&shared.calculate_difficulty(&parent.header()).unwrap())
+        .cellbase_id(&cellbase.hash());
+
+    BlockBuilder::default()
+        .commit_transaction(cellbase)
+        .with_header_builder(header_builder)
+}
+
 fn setup_node(
     height: u64,
 ) -> (
     TestNode,
     Shared<ChainKVStore<MemoryKeyValueDB>>,
     ChainController,
+) {
+    let (relayer, shared, chain_controller) = setup_relayer(height);
+
+    let mut node = TestNode::default();
+    node.add_protocol(
+        RELAY_PROTOCOL_ID,
+        Arc::new(relayer),
+        vec![TX_PROPOSAL_TOKEN],
+    );
+    (node, shared, chain_controller)
+}
+
+fn setup_relayer(
+    height: u64,
+) -> (
+    Relayer<ChainKVStore<MemoryKeyValueDB>>,
+    Shared<ChainKVStore<MemoryKeyValueDB>>,
+    ChainController,
+) {
+    let (relayer, shared, chain_controller, _tx_pool_controller) =
+        setup_relayer_at(height, now_ms());
+    (relayer, shared, chain_controller)
+}
+
+/// A relayer on a chain of `height` blocks after a genesis block mined at `timestamp`, the same
+/// `timestamp` gives the same chain
+pub fn setup_relayer_at(
+    height: u64,
+    timestamp: u64,
+) -> (
+    Relayer<ChainKVStore<MemoryKeyValueDB>>,
+    Shared<ChainKVStore<MemoryKeyValueDB>>,
+    ChainController,
+    TransactionPoolController,
 ) {
     let mut block = BlockBuilder::default().with_header_builder(
         HeaderBuilder::default()
-            .timestamp(now_ms())
+            .timestamp(timestamp)
             .difficulty(&U256::from(1000)),
     );
-    let consensus = Consensus::default().set_genesis_block(block.clone());
+    // the transactions of the tests spend the cellbases of the latest blocks
+    let consensus = Consensus::default()
+        .set_genesis_block(block.clone())
+        .set_cellbase_maturity(0);
 
     let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
         .consensus(consensus)
@@ -330,20 +817,18 @@ fn setup_node(
             .expect("process block should be OK");
     }
 
-    let relayer = Relayer::new(chain_controller.clone(), shared.clone(), tx_pool_controller);
-
-    let mut node = TestNode::default();
-    node.add_protocol(
-        RELAY_PROTOCOL_ID,
-        Arc::new(relayer),
-        vec![TX_PROPOSAL_TOKEN],
+    let config = Config::default();
+    let relayer = Relayer::new(
+        SyncShared::new(chain_controller.clone(), shared.clone(), &config),
+        tx_pool_controller.clone(),
+        config.header_first_relay,
     );
-    (node, shared, chain_controller)
+    (relayer, shared, chain_controller, tx_pool_controller)
 }
 
 // This helper is copied from pool test
 // TODO should provide some helper or add validation option to pool / chain for testing
-fn create_valid_script() -> Script {
+pub fn create_valid_script() -> Script {
     let mut file = File::open(
         Path::new(env!("CARGO_MANIFEST_DIR")).join("../nodes_template/spec/cells/always_success"),
     ).unwrap();
@@ -16,7 +16,7 @@ use std::sync::Arc;
 use std::thread;
 use synchronizer::BLOCK_FETCH_TOKEN;
 use tests::TestNode;
-use {Config, Synchronizer, SYNC_PROTOCOL_ID};
+use {Config, SyncShared, Synchronizer, SYNC_PROTOCOL_ID};
 
 #[test]
 fn basic_sync() {
@@ -95,7 +95,11 @@ fn setup_node(height: u64) -> (TestNode, Shared<ChainKVStore<MemoryKeyValueDB>>)
             .expect("process block should be OK");
     }
 
-    let synchronizer = Synchronizer::new(chain_controller, shared.clone(), Config::default());
+    let config = Config::default();
+    let synchronizer = Synchronizer::new(
+        SyncShared::new(chain_controller, shared.clone(), &config),
+        config,
+    );
     let mut node = TestNode::default();
     node.add_protocol(
         SYNC_PROTOCOL_ID,
@@ -4,7 +4,7 @@ use bincode::{deserialize, serialize};
 use ckb_core::transaction_meta::TransactionMeta;
 use ckb_db::batch::{Batch, Col};
 use ckb_db::kvdb::KeyValueDB;
-use hash::sha3_256;
+use hash::consensus_hash;
 
 // DB node in the avl
 #[derive(Eq, PartialEq, Debug, Serialize, Deserialize, Clone)]
@@ -26,7 +26,7 @@ pub fn get(db: &KeyValueDB, col: Col, h: H256) -> Result<DBNode> {
 pub fn insert(col: Col, batch: &mut Batch, node: &DBNode) -> H256 {
     let raw = serialize(node).unwrap();
 
-    let h: H256 = sha3_256(&raw).into();
+    let h: H256 = consensus_hash(&raw).into();
 
     batch.insert(col, h.to_vec(), raw);
 
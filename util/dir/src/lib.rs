@@ -1,6 +1,15 @@
-use std::fs;
+extern crate fs2;
+#[cfg(test)]
+extern crate tempfile;
+
+use fs2::FileExt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
 use std::path::{Path, PathBuf};
 
+/// The lock file in the data dir, held by the node using the dir
+pub const LOCK_FILE: &str = "ckb.lock";
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Directories {
     pub base: PathBuf,
@@ -17,4 +26,87 @@ impl Directories {
         fs::create_dir_all(&result).expect("Unable to create dir");
         result
     }
+
+    /// Takes the advisory lock of the data dir, fails if another process holds it.
+    ///
+    /// The lock is released when the returned `DirLock` is dropped or the process exits,
+    /// the lock file itself is left in place.
+    pub fn lock(&self) -> io::Result<DirLock> {
+        fs::create_dir_all(&self.base)?;
+        let path = self.base.join(LOCK_FILE);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+        file.try_lock_exclusive().map_err(|err| {
+            if err.kind() == fs2::lock_contended_error().kind() {
+                io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!(
+                        "data dir {} is used by another process",
+                        self.base.display()
+                    ),
+                )
+            } else {
+                err
+            }
+        })?;
+        Ok(DirLock { file, path })
+    }
+}
+
+/// The held lock of a data dir
+#[derive(Debug)]
+pub struct DirLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl DirLock {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile;
+
+    #[test]
+    fn test_lock_exclusive() {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("test_lock_exclusive")
+            .tempdir()
+            .unwrap();
+        let dirs = Directories::new(tmp_dir.path());
+
+        let lock = dirs.lock().unwrap();
+        assert_eq!(lock.path(), tmp_dir.path().join(LOCK_FILE).as_path());
+        let err = dirs.lock().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        drop(lock);
+        assert!(dirs.lock().is_ok());
+    }
+
+    #[test]
+    fn test_lock_creates_dir() {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("test_lock_creates_dir")
+            .tempdir()
+            .unwrap();
+        // a nested dir with a space, which needs no quoting on any platform
+        let dirs = Directories::new(tmp_dir.path().join("data dir").join("node"));
+
+        let _lock = dirs.lock().unwrap();
+        assert!(dirs.base.join(LOCK_FILE).is_file());
+    }
 }
@@ -0,0 +1,107 @@
+//! The consensus hash.
+//!
+//! Transaction, header, script, uncle and merkle digests all go through this module,
+//! so the algorithm used by consensus is chosen in exactly one place.
+
+use blake2_rfc::blake2b::Blake2b;
+use tiny_keccak::Keccak;
+
+/// blake2b personalization, keeps CKB digests apart from other blake2b users
+pub const CKB_HASH_PERSONALIZATION: &[u8] = b"ckb-default-hash";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha3_256,
+    Blake2b256,
+}
+
+/// The algorithm currently used by consensus
+pub const CONSENSUS_HASH_ALGORITHM: HashAlgorithm = HashAlgorithm::Sha3_256;
+
+/// Incremental hasher for the given algorithm, always producing 32 bytes digests
+pub enum Hasher {
+    Sha3_256(Keccak),
+    Blake2b256(Blake2b),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha3_256 => Hasher::Sha3_256(Keccak::new_sha3_256()),
+            HashAlgorithm::Blake2b256 => Hasher::Blake2b256(Blake2b::with_params(
+                32,
+                &[],
+                &[],
+                CKB_HASH_PERSONALIZATION,
+            )),
+        }
+    }
+
+    pub fn consensus() -> Self {
+        Self::new(CONSENSUS_HASH_ALGORITHM)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha3_256(keccak) => keccak.update(data),
+            Hasher::Blake2b256(blake2b) => blake2b.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> [u8; 32] {
+        let mut result = [0u8; 32];
+        match self {
+            Hasher::Sha3_256(keccak) => keccak.finalize(&mut result),
+            Hasher::Blake2b256(blake2b) => result.copy_from_slice(blake2b.finalize().as_bytes()),
+        }
+        result
+    }
+}
+
+pub fn hash_with<T: AsRef<[u8]>>(algorithm: HashAlgorithm, s: T) -> [u8; 32] {
+    let mut hasher = Hasher::new(algorithm);
+    hasher.update(s.as_ref());
+    hasher.finalize()
+}
+
+pub fn consensus_hash<T: AsRef<[u8]>>(s: T) -> [u8; 32] {
+    hash_with(CONSENSUS_HASH_ALGORITHM, s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha3_256;
+
+    #[test]
+    fn sha3_256_matches() {
+        assert_eq!(hash_with(HashAlgorithm::Sha3_256, b"hello"), sha3_256(b"hello"));
+    }
+
+    #[test]
+    fn personalized_blake2b_256() {
+        let expected = [
+            0x44, 0xf4, 0xc6, 0x97, 0x44, 0xd5, 0xf8, 0xc5, 0x5d, 0x64, 0x20, 0x62, 0x94, 0x9d,
+            0xca, 0xe4, 0x9b, 0xc4, 0xe7, 0xef, 0x43, 0xd3, 0x88, 0xc5, 0xa1, 0x2f, 0x42, 0xb5,
+            0x63, 0x3d, 0x16, 0x3e,
+        ];
+        assert_eq!(hash_with(HashAlgorithm::Blake2b256, []), expected);
+
+        let expected = [
+            0x2d, 0xa1, 0x28, 0x93, 0x73, 0xa9, 0xf6, 0xb7, 0xed, 0x21, 0xdb, 0x94, 0x8f, 0x4d,
+            0xc5, 0xd9, 0x42, 0xcf, 0x40, 0x23, 0xea, 0xef, 0x1d, 0x5a, 0x2b, 0x1a, 0x45, 0xb9,
+            0xd1, 0x2d, 0x10, 0x36,
+        ];
+        assert_eq!(hash_with(HashAlgorithm::Blake2b256, b"hello"), expected);
+    }
+
+    #[test]
+    fn incremental_update() {
+        for algorithm in &[HashAlgorithm::Sha3_256, HashAlgorithm::Blake2b256] {
+            let mut hasher = Hasher::new(*algorithm);
+            hasher.update(b"hel");
+            hasher.update(b"lo");
+            assert_eq!(hasher.finalize(), hash_with(*algorithm, b"hello"));
+        }
+    }
+}
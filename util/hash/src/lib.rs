@@ -1,7 +1,10 @@
 extern crate blake2_rfc;
 extern crate tiny_keccak;
 
+pub mod ckb_hash;
+
 pub use blake2_rfc::blake2b::Blake2b;
+pub use ckb_hash::consensus_hash;
 pub use tiny_keccak::Keccak as Sha3;
 
 pub fn sha3_256<T: AsRef<[u8]>>(s: T) -> [u8; 32] {
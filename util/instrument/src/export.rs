@@ -1,5 +1,7 @@
 use super::format::Format;
 use super::iter::ChainIterator;
+use super::manifest::{ChunkHasher, Manifest, CHUNK_SIZE};
+use bigint::H256;
 use ckb_shared::index::ChainIndex;
 use ckb_shared::shared::{ChainProvider, Shared};
 #[cfg(feature = "progress_bar")]
@@ -18,14 +20,17 @@ pub struct Export<CI> {
     pub shared: Shared<CI>,
     /// which format be used to export
     pub format: Format,
+    /// hash of the chain spec, recorded in the manifest
+    pub spec_hash: H256,
 }
 
 impl<CI: ChainIndex> Export<CI> {
-    pub fn new(shared: Shared<CI>, format: Format, target: PathBuf) -> Self {
+    pub fn new(shared: Shared<CI>, format: Format, target: PathBuf, spec_hash: H256) -> Self {
         Export {
             shared,
             format,
             target,
+            spec_hash,
         }
     }
 
@@ -47,32 +52,55 @@ impl<CI: ChainIndex> Export<CI> {
         }
     }
 
-    #[cfg(not(feature = "progress_bar"))]
-    pub fn write_to_json(self) -> Result<(), Box<Error>> {
+    // The blocks are written to a temporary file first, the manifest heading the archive
+    // is only known once they all are
+    fn blocks_file(&self) -> PathBuf {
+        self.target.join(format!("{}.blocks", self.file_name()))
+    }
+
+    fn write_archive(&self, chunks: Vec<H256>) -> Result<(), Box<Error>> {
+        let manifest = Manifest {
+            spec_hash: self.spec_hash,
+            chunk_size: CHUNK_SIZE,
+            chunks,
+        };
         let f = fs::OpenOptions::new()
             .create_new(true)
             .read(true)
             .write(true)
             .open(&self.target.join(self.file_name()))?;
         let mut writer = io::BufWriter::new(f);
+        writer.write_all(&serde_json::to_vec(&manifest)?)?;
+        writer.write_all(b"\n")?;
+
+        let mut blocks = fs::File::open(self.blocks_file())?;
+        io::copy(&mut blocks, &mut writer)?;
+        writer.flush()?;
+        fs::remove_file(self.blocks_file())?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "progress_bar"))]
+    pub fn write_to_json(self) -> Result<(), Box<Error>> {
+        let f = fs::File::create(self.blocks_file())?;
+        let mut writer = io::BufWriter::new(f);
+        let mut hasher = ChunkHasher::new(CHUNK_SIZE);
 
         for block in self.iter() {
-            let block: Block = block.into();
             let encoded = serde_json::to_vec(&block)?;
+            hasher.update(&encoded);
             writer.write_all(&encoded)?;
             writer.write_all(b"\n")?;
         }
-        Ok(())
+        writer.flush()?;
+        self.write_archive(hasher.finish())
     }
 
     #[cfg(feature = "progress_bar")]
     pub fn write_to_json(self) -> Result<(), Box<Error>> {
-        let f = fs::OpenOptions::new()
-            .create_new(true)
-            .read(true)
-            .write(true)
-            .open(&self.target.join(self.file_name()))?;
+        let f = fs::File::create(self.blocks_file())?;
         let mut writer = io::BufWriter::new(f);
+        let mut hasher = ChunkHasher::new(CHUNK_SIZE);
 
         let blocks_iter = self.iter();
         let progress_bar = ProgressBar::new(blocks_iter.len());
@@ -83,10 +111,13 @@ impl<CI: ChainIndex> Export<CI> {
         );
         for block in blocks_iter {
             let encoded = serde_json::to_vec(&block)?;
+            hasher.update(&encoded);
             writer.write_all(&encoded)?;
             writer.write_all(b"\n")?;
             progress_bar.inc(1);
         }
+        writer.flush()?;
+        self.write_archive(hasher.finish())?;
         progress_bar.finish_with_message("done!");
         Ok(())
     }
@@ -1,4 +1,6 @@
 use super::format::Format;
+use super::manifest::{ChunkHasher, Manifest};
+use bigint::H256;
 use ckb_chain::chain::ChainController;
 use ckb_core::block::Block;
 #[cfg(feature = "progress_bar")]
@@ -8,9 +10,13 @@ use std::error::Error;
 use std::fs;
 use std::io;
 use std::io::BufRead;
+use std::mem;
 use std::path::PathBuf;
 use std::sync::Arc;
 
+/// Number of blocks handed to the chain service at once
+const IMPORT_BATCH_SIZE: usize = 100;
+
 /// Export block date from file to database.
 pub struct Import {
     /// source file contains block data
@@ -18,38 +24,87 @@ pub struct Import {
     chain: ChainController,
     /// source file format
     format: Format,
+    /// hash of the chain spec, the archive must have been exported with the same
+    spec_hash: H256,
 }
 
 impl Import {
-    pub fn new(chain: ChainController, format: Format, source: PathBuf) -> Self {
+    pub fn new(chain: ChainController, format: Format, source: PathBuf, spec_hash: H256) -> Self {
         Import {
             format,
             chain,
             source,
+            spec_hash,
         }
     }
 
     pub fn execute(self) -> Result<(), Box<Error>> {
         match self.format {
-            Format::Json => self.read_from_json(),
+            Format::Json => {
+                self.verify_json()?;
+                self.read_from_json()
+            }
             _ => Ok(()),
         }
     }
 
+    /// Checks the archive against its manifest before anything is imported
+    pub fn verify_json(&self) -> Result<Manifest, Box<Error>> {
+        let f = fs::File::open(&self.source)?;
+        let mut lines = io::BufReader::new(f).lines();
+
+        let manifest: Manifest = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)
+                .map_err(|e| format!("invalid archive manifest: {}", e))?,
+            None => return Err("empty archive".into()),
+        };
+        if manifest.spec_hash != self.spec_hash {
+            return Err(format!(
+                "archive exported from chain spec {:?}, expected {:?}",
+                manifest.spec_hash, self.spec_hash
+            ).into());
+        }
+
+        let mut hasher = ChunkHasher::new(manifest.chunk_size);
+        for line in lines {
+            hasher.update(line?.as_bytes());
+        }
+        let chunks = hasher.finish();
+        if chunks.len() != manifest.chunks.len() {
+            return Err(format!(
+                "archive has {} chunks, the manifest {}",
+                chunks.len(),
+                manifest.chunks.len()
+            ).into());
+        }
+        if let Some(index) = chunks
+            .iter()
+            .zip(manifest.chunks.iter())
+            .position(|(actual, expected)| actual != expected)
+        {
+            return Err(format!("archive chunk {} is corrupted", index).into());
+        }
+        Ok(manifest)
+    }
+
     #[cfg(not(feature = "progress_bar"))]
     pub fn read_from_json(&self) -> Result<(), Box<Error>> {
         let f = fs::File::open(&self.source)?;
         let reader = io::BufReader::new(f);
 
-        for line in reader.lines() {
+        let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+        // skip the manifest
+        for line in reader.lines().skip(1) {
             let s = line?;
             let block: Arc<Block> = Arc::new(serde_json::from_str(&s)?);
             if !block.is_genesis() {
-                self.chain
-                    .process_block(block)
-                    .expect("import occur malformation data");
+                batch.push(block);
+            }
+            if batch.len() == IMPORT_BATCH_SIZE {
+                self.process_batch(&mut batch);
             }
         }
+        self.process_batch(&mut batch);
         Ok(())
     }
 
@@ -64,17 +119,32 @@ impl Import {
                 .template("[{elapsed_precise}] {bar:50.cyan/blue} {bytes:>6}/{total_bytes:6} {msg}")
                 .progress_chars("##-"),
         );
-        for line in reader.lines() {
+        let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+        for (index, line) in reader.lines().enumerate() {
             let s = line?;
-            let block: Arc<Block> = Arc::new(serde_json::from_str(&s)?);
-            if !block.is_genesis() {
-                self.chain
-                    .process_block(block)
-                    .expect("import occur malformation data");
+            // skip the manifest
+            if index > 0 {
+                let block: Arc<Block> = Arc::new(serde_json::from_str(&s)?);
+                if !block.is_genesis() {
+                    batch.push(block);
+                }
+                if batch.len() == IMPORT_BATCH_SIZE {
+                    self.process_batch(&mut batch);
+                }
             }
             progress_bar.inc(s.as_bytes().len() as u64);
         }
+        self.process_batch(&mut batch);
         progress_bar.finish_with_message("done!");
         Ok(())
     }
+
+    fn process_batch(&self, batch: &mut Vec<Arc<Block>>) {
+        if !batch.is_empty() {
+            let blocks = mem::replace(batch, Vec::with_capacity(IMPORT_BATCH_SIZE));
+            self.chain
+                .process_blocks(blocks)
+                .expect("import occur malformation data");
+        }
+    }
 }
@@ -6,19 +6,27 @@
 //!   export function.
 //! - [Import](instrument::import::Import) import block data which
 //!   export from `Export`.
+//! - [Manifest](instrument::manifest::Manifest) heads the exported archives, so `Import`
+//!   rejects corrupted archives or archives of another chain before importing anything.
 
+extern crate bigint;
 extern crate ckb_chain;
 extern crate ckb_core;
 extern crate ckb_shared;
+extern crate hash;
 #[cfg(feature = "progress_bar")]
 extern crate indicatif;
+#[macro_use]
+extern crate serde_derive;
 extern crate serde_json;
 
 mod export;
 mod format;
 mod import;
 mod iter;
+mod manifest;
 
 pub use export::Export;
 pub use format::Format;
 pub use import::Import;
+pub use manifest::Manifest;
@@ -0,0 +1,100 @@
+use bigint::H256;
+use hash::sha3_256;
+
+/// Number of blocks covered by each checksum of the manifest
+pub const CHUNK_SIZE: usize = 1000;
+
+/// First line of an exported archive, describing the blocks which follow it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Hash of the chain spec the blocks were exported from
+    pub spec_hash: H256,
+    pub chunk_size: usize,
+    /// Checksum of each chunk of `chunk_size` blocks, the last chunk may be shorter
+    pub chunks: Vec<H256>,
+}
+
+/// Computes the chunk checksums of the archive lines, a line per block.
+///
+/// Each line is hashed after its length, a little endian u64, so moving bytes between the lines
+/// of a chunk changes its checksum.
+pub struct ChunkHasher {
+    chunk_size: usize,
+    lines: usize,
+    buffer: Vec<u8>,
+    chunks: Vec<H256>,
+}
+
+impl ChunkHasher {
+    pub fn new(chunk_size: usize) -> Self {
+        ChunkHasher {
+            chunk_size,
+            lines: 0,
+            buffer: Vec::new(),
+            chunks: Vec::new(),
+        }
+    }
+
+    /// Adds a line, without its line break
+    pub fn update(&mut self, line: &[u8]) {
+        self.buffer.extend_from_slice(&length_prefix(line.len()));
+        self.buffer.extend_from_slice(line);
+        self.lines += 1;
+        if self.lines == self.chunk_size {
+            self.finish_chunk();
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<H256> {
+        if self.lines > 0 {
+            self.finish_chunk();
+        }
+        self.chunks
+    }
+
+    fn finish_chunk(&mut self) {
+        self.chunks.push(sha3_256(&self.buffer).into());
+        self.buffer.clear();
+        self.lines = 0;
+    }
+}
+
+fn length_prefix(len: usize) -> [u8; 8] {
+    let len = len as u64;
+    let mut prefix = [0u8; 8];
+    for (i, byte) in prefix.iter_mut().enumerate() {
+        *byte = (len >> (8 * i)) as u8;
+    }
+    prefix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_hasher() {
+        let mut hasher = ChunkHasher::new(2);
+        hasher.update(b"a");
+        hasher.update(b"b");
+        hasher.update(b"c");
+        assert_eq!(
+            hasher.finish(),
+            vec![
+                H256::from(sha3_256(b"\x01\0\0\0\0\0\0\0a\x01\0\0\0\0\0\0\0b")),
+                H256::from(sha3_256(b"\x01\0\0\0\0\0\0\0c")),
+            ]
+        );
+
+        let checksums = |lines: &[&str]| {
+            let mut hasher = ChunkHasher::new(2);
+            for line in lines {
+                hasher.update(line.as_bytes());
+            }
+            hasher.finish()
+        };
+        assert_ne!(checksums(&["ab", "c"]), checksums(&["a", "bc"]));
+
+        assert!(ChunkHasher::new(2).finish().is_empty());
+    }
+}
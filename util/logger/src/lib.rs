@@ -16,7 +16,7 @@ use crossbeam_channel::unbounded;
 use env_logger::filter::{Builder, Filter};
 use log::{LevelFilter, SetLoggerError};
 use log::{Log, Metadata, Record};
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 use regex::Regex;
 use std::io::Write;
 use std::{fs, thread};
@@ -26,24 +26,33 @@ enum Message {
     Terminate,
 }
 
+lazy_static! {
+    // The filter of the installed logger, replaced when the config is reloaded
+    static ref FILTER: RwLock<Filter> = RwLock::new(Builder::new().build());
+}
+
+fn build_filter(config_filter: Option<&str>) -> Filter {
+    let mut builder = Builder::new();
+
+    if let Ok(ref env_filter) = std::env::var("NERVOS_LOG") {
+        builder.parse(env_filter);
+    }
+
+    if let Some(config_filter) = config_filter {
+        builder.parse(config_filter);
+    }
+    builder.build()
+}
+
 #[derive(Debug)]
 pub struct Logger {
     sender: crossbeam_channel::Sender<Message>,
     handle: Mutex<Option<thread::JoinHandle<()>>>,
-    filter: Filter,
 }
 
 impl Logger {
     fn new(config: Config) -> Logger {
-        let mut builder = Builder::new();
-
-        if let Ok(ref env_filter) = std::env::var("NERVOS_LOG") {
-            builder.parse(env_filter);
-        }
-
-        if let Some(ref config_filter) = config.filter {
-            builder.parse(config_filter);
-        }
+        *FILTER.write() = build_filter(config.filter.as_ref().map(String::as_str));
 
         let (sender, receiver) = unbounded();
         let file = config.file;
@@ -85,12 +94,11 @@ impl Logger {
         Logger {
             sender,
             handle: Mutex::new(Some(tb)),
-            filter: builder.build(),
         }
     }
 
     pub fn filter(&self) -> LevelFilter {
-        self.filter.filter()
+        FILTER.read().filter()
     }
 }
 
@@ -113,12 +121,12 @@ impl Default for Config {
 
 impl Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        self.filter.enabled(metadata)
+        FILTER.read().enabled(metadata)
     }
 
     fn log(&self, record: &Record) {
         // Check if the record is matched by the filter
-        if self.filter.matches(record) {
+        if FILTER.read().matches(record) {
             let thread = thread::current();
             let thread_name = thread.name().unwrap_or_default();
 
@@ -159,6 +167,13 @@ pub fn init(config: Config) -> Result<(), SetLoggerError> {
     log::set_boxed_logger(Box::new(logger))
 }
 
+/// Replaces the filter of the running logger, `NERVOS_LOG` still applies.
+pub fn reload_filter(config_filter: Option<&str>) {
+    let filter = build_filter(config_filter);
+    log::set_max_level(filter.filter());
+    *FILTER.write() = filter;
+}
+
 pub fn flush() {
     log::logger().flush()
 }
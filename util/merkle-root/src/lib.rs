@@ -2,7 +2,7 @@ extern crate bigint;
 extern crate hash;
 
 use bigint::H256;
-use hash::Sha3;
+use hash::ckb_hash::Hasher;
 
 fn lowest_children_len(amount: usize) -> usize {
     let mut n: usize = 1;
@@ -51,12 +51,10 @@ pub fn merkle_root(input: &[H256]) -> H256 {
 }
 
 fn merge(left: &H256, right: &H256) -> H256 {
-    let mut hash = [0u8; 32];
-    let mut sha3 = Sha3::new_sha3_256();
-    sha3.update(left);
-    sha3.update(right);
-    sha3.finalize(&mut hash);
-    hash.into()
+    let mut hasher = Hasher::consensus();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
 }
 
 #[cfg(test)]
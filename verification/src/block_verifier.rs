@@ -1,22 +1,87 @@
-use super::header_verifier::HeaderResolver;
-use super::{TransactionVerifier, Verifier};
+use super::header_verifier::{BlockMedianTimeContext, HeaderResolver};
+use super::{MaturityVerifier, TransactionVerifier, Verifier};
 use bigint::{H256, U256};
 use ckb_core::block::Block;
 use ckb_core::cell::{CellProvider, CellStatus};
 use ckb_core::header::Header;
-use ckb_core::transaction::{Capacity, CellInput, OutPoint};
-use ckb_shared::shared::ChainProvider;
+use ckb_core::transaction::{CellInput, OutPoint};
+use ckb_core::uncle::UncleBlock;
+use ckb_core::{BlockNumber, Cycle};
+use ckb_script::ScriptProfiler;
+use ckb_shared::index::ChainIndex;
+use ckb_shared::shared::{ChainProvider, Shared};
 use error::TransactionError;
-use error::{CellbaseError, CommitError, Error, UnclesError};
+use error::{CellbaseError, CommitError, Error, GenesisError, UnclesError, VersionError};
 use fnv::{FnvHashMap, FnvHashSet};
 use merkle_root::merkle_root;
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// The checks run by `BlockVerifier` once the transactions are known not to be empty
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockCheck {
+    Version,
+    ProposalsLimit,
+    /// The serialized size of the block and of every committed transaction
+    Size,
+    Duplicate,
+    MerkleRoot,
+    /// Looks up the proposals of the window in the store
+    Commit,
+    /// Resolves the inputs of every committed transaction to sum the fees
+    Cellbase,
+    /// Includes the PoW of every uncle
+    Uncles,
+    /// Includes the scripts of every committed transaction
+    Transactions,
+}
+
+impl BlockCheck {
+    /// The check only reads the block and the consensus, not the chain, so it can run before
+    /// the parent of the block is stored
+    pub fn is_context_free(self) -> bool {
+        match self {
+            BlockCheck::Version
+            | BlockCheck::ProposalsLimit
+            | BlockCheck::Size
+            | BlockCheck::Duplicate
+            | BlockCheck::MerkleRoot => true,
+            BlockCheck::Commit
+            | BlockCheck::Cellbase
+            | BlockCheck::Uncles
+            | BlockCheck::Transactions => false,
+        }
+    }
+}
+
+/// From the cheapest check to the most expensive, so that an invalid block is rejected with as
+/// little work as possible
+pub const DEFAULT_CHECK_ORDER: [BlockCheck; 9] = [
+    BlockCheck::Version,
+    BlockCheck::ProposalsLimit,
+    BlockCheck::Size,
+    BlockCheck::Duplicate,
+    BlockCheck::MerkleRoot,
+    BlockCheck::Commit,
+    BlockCheck::Cellbase,
+    BlockCheck::Uncles,
+    BlockCheck::Transactions,
+];
 
 //TODO: cellbase, witness
 pub struct BlockVerifier<P> {
+    // Verify the genesis block, which replaces all the other checks
+    genesis: GenesisVerifier<P>,
     // Verify if the committed transactions is empty
     empty: EmptyVerifier,
+    // Verify if the header versions are active per the hard fork switch
+    version: VersionVerifier<P>,
+    // Verify if the proposed transactions exceed the consensus limit
+    proposals_limit: ProposalsLimitVerifier<P>,
+    // Verify if the block or any committed transaction exceeds the consensus size limits
+    size: SizeVerifier<P>,
     // Verify if the committed and proposed transactions contains duplicate
     duplicate: DuplicateVerifier,
     // Verify the cellbase
@@ -29,18 +94,25 @@ pub struct BlockVerifier<P> {
     commit: CommitVerifier<P>,
     // Verify all the committed transactions through TransactionVerifier
     transactions: TransactionsVerifier<P>,
+    // The order the checks above are run in, after the empty one
+    order: Vec<BlockCheck>,
 }
 
 impl<P: ChainProvider + CellProvider + Clone> ::std::clone::Clone for BlockVerifier<P> {
     fn clone(&self) -> Self {
         BlockVerifier {
+            genesis: self.genesis.clone(),
             empty: self.empty.clone(),
+            version: self.version.clone(),
+            proposals_limit: self.proposals_limit.clone(),
+            size: self.size.clone(),
             duplicate: self.duplicate.clone(),
             cellbase: self.cellbase.clone(),
             merkle_root: self.merkle_root.clone(),
             uncles: self.uncles.clone(),
             commit: self.commit.clone(),
             transactions: self.transactions.clone(),
+            order: self.order.clone(),
         }
     }
 }
@@ -52,14 +124,45 @@ where
     pub fn new(provider: P) -> Self {
         BlockVerifier {
             // TODO change all new fn's chain to reference
+            genesis: GenesisVerifier::new(provider.clone()),
             empty: EmptyVerifier::new(),
+            version: VersionVerifier::new(provider.clone()),
+            proposals_limit: ProposalsLimitVerifier::new(provider.clone()),
+            size: SizeVerifier::new(provider.clone()),
             duplicate: DuplicateVerifier::new(),
             cellbase: CellbaseVerifier::new(provider.clone()),
             merkle_root: MerkleRootVerifier::new(),
             uncles: UnclesVerifier::new(provider.clone()),
             commit: CommitVerifier::new(provider.clone()),
             transactions: TransactionsVerifier::new(provider),
+            order: DEFAULT_CHECK_ORDER.to_vec(),
+        }
+    }
+
+    /// Skips the transaction scripts but keeps every other check,
+    /// only meant for importing blocks from a trusted archive
+    pub fn skip_script_verify(mut self) -> Self {
+        self.transactions = self.transactions.skip_script_verify();
+        self
+    }
+
+    /// Every transaction script run is recorded by `profiler`, the scripts of the transactions
+    /// already verified are run again
+    pub fn profile_scripts(mut self, profiler: Arc<dyn ScriptProfiler>) -> Self {
+        self.transactions = self.transactions.profile_scripts(profiler);
+        self
+    }
+
+    /// Runs the given checks first, in order, then the remaining ones in the default order
+    pub fn check_order(mut self, order: &[BlockCheck]) -> Self {
+        let mut checks = Vec::with_capacity(DEFAULT_CHECK_ORDER.len());
+        for check in order.iter().chain(DEFAULT_CHECK_ORDER.iter()) {
+            if !checks.contains(check) {
+                checks.push(*check);
+            }
         }
+        self.order = checks;
+        self
     }
 }
 
@@ -67,15 +170,125 @@ impl<P: ChainProvider + CellProvider + Clone> Verifier for BlockVerifier<P> {
     type Target = Block;
 
     fn verify(&self, target: &Block) -> Result<(), Error> {
+        if target.is_genesis() {
+            return self.genesis.verify(target);
+        }
         // EmptyTransactionsVerifier must be executed first. Other verifiers may depend on the
         // assumption that the transactions list is not empty.
         self.empty.verify(target)?;
-        self.duplicate.verify(target)?;
-        self.cellbase.verify(target)?;
-        self.merkle_root.verify(target)?;
-        self.commit.verify(target)?;
-        self.uncles.verify(target)?;
-        self.transactions.verify(target)
+        for check in &self.order {
+            self.check(*check, target, false)?;
+        }
+        Ok(())
+    }
+}
+
+impl<P: ChainProvider + CellProvider + Clone> BlockVerifier<P> {
+    /// Same checks as `verify`, but every committed transaction is verified and all the
+    /// failures are reported instead of stopping at the first one, intended for dry-run usage.
+    pub fn verify_all(&self, target: &Block) -> Result<(), Error> {
+        if target.is_genesis() {
+            return self.genesis.verify(target);
+        }
+        self.empty.verify(target)?;
+        for check in &self.order {
+            self.check(*check, target, true)?;
+        }
+        Ok(())
+    }
+
+    /// The context-free checks of `verify`, the blocks of a batch run them all at once before
+    /// any of them is stored. The genesis is left to `verify_contextual`.
+    pub fn verify_context_free(&self, target: &Block) -> Result<(), Error> {
+        if target.is_genesis() {
+            return Ok(());
+        }
+        self.empty.verify(target)?;
+        for check in self.order.iter().filter(|check| check.is_context_free()) {
+            self.check(*check, target, false)?;
+        }
+        Ok(())
+    }
+
+    /// The remaining checks of `verify`, for a block which passed `verify_context_free` and
+    /// whose parent is stored
+    pub fn verify_contextual(&self, target: &Block) -> Result<(), Error> {
+        if target.is_genesis() {
+            return self.genesis.verify(target);
+        }
+        for check in self.order.iter().filter(|check| !check.is_context_free()) {
+            self.check(*check, target, false)?;
+        }
+        Ok(())
+    }
+
+    fn check(&self, check: BlockCheck, target: &Block, all: bool) -> Result<(), Error> {
+        match check {
+            BlockCheck::Version => self.version.verify(target),
+            BlockCheck::ProposalsLimit => self.proposals_limit.verify(target),
+            BlockCheck::Size => self.size.verify(target),
+            BlockCheck::Duplicate => self.duplicate.verify(target),
+            BlockCheck::MerkleRoot => self.merkle_root.verify(target),
+            BlockCheck::Commit => self.commit.verify(target),
+            BlockCheck::Cellbase => self.cellbase.verify(target),
+            BlockCheck::Uncles => self.uncles.verify(target),
+            BlockCheck::Transactions if all => self.transactions.verify_all(target),
+            BlockCheck::Transactions => self.transactions.verify(target),
+        }
+    }
+}
+
+/// The genesis has no parent, no proposal window and no reward to claim, so it is checked
+/// against the chain spec instead: a block numbered 0 must be the spec's genesis, and the one
+/// in the store too, which catches a database initialized from another spec.
+#[derive(Clone)]
+pub struct GenesisVerifier<CP> {
+    provider: CP,
+}
+
+impl<CP: ChainProvider + Clone> GenesisVerifier<CP> {
+    pub fn new(provider: CP) -> Self {
+        GenesisVerifier { provider }
+    }
+
+    pub fn verify(&self, block: &Block) -> Result<(), Error> {
+        let header = block.header();
+        if header.parent_hash() != H256::zero() {
+            return Err(Error::Genesis(GenesisError::InvalidParentHash));
+        }
+        if !block.uncles().is_empty() {
+            return Err(Error::Genesis(GenesisError::NonEmptyUncles));
+        }
+        if !block.proposal_transactions().is_empty() {
+            return Err(Error::Genesis(GenesisError::NonEmptyProposals));
+        }
+        // the hash only covers the header, the roots tie the body to it
+        if header.uncles_count() != 0 {
+            return Err(Error::Uncles(UnclesError::MissMatchCount {
+                expected: header.uncles_count(),
+                actual: 0,
+            }));
+        }
+        let uncles_hash = block.cal_uncles_hash();
+        if header.uncles_hash() != uncles_hash {
+            return Err(Error::Uncles(UnclesError::InvalidHash {
+                expected: header.uncles_hash(),
+                actual: uncles_hash,
+            }));
+        }
+        MerkleRootVerifier::new().verify(block)?;
+
+        let expected_hashes = ::std::iter::once(self.provider.genesis_hash())
+            .chain(self.provider.block_hash(0));
+        for expected in expected_hashes {
+            if header.hash() != expected {
+                return Err(Error::Genesis(GenesisError::Mismatch {
+                    expected,
+                    actual: header.hash(),
+                }));
+            }
+        }
+        Ok(())
     }
 }
 
@@ -114,21 +327,18 @@ impl<CP: ChainProvider + Clone> CellbaseVerifier<CP> {
         {
             return Err(Error::Cellbase(CellbaseError::InvalidInput));
         }
-        let block_reward = self.provider.block_reward(block.header().number());
+        let block_reward = self.provider.block_reward(block.header().number())
+            + self
+                .provider
+                .uncles_reward(block.header().number(), block.uncles().len());
         let mut fee = 0;
         for transaction in block.commit_transactions().iter().skip(1) {
             fee += self.provider.calculate_transaction_fee(transaction)?;
         }
         let total_reward = block_reward + fee;
-        let output_capacity: Capacity = cellbase_transaction
-            .outputs()
-            .iter()
-            .map(|output| output.capacity)
-            .sum();
-        if output_capacity > total_reward {
-            Err(Error::Cellbase(CellbaseError::InvalidReward))
-        } else {
-            Ok(())
+        match cellbase_transaction.outputs_capacity() {
+            Some(output_capacity) if output_capacity <= total_reward => Ok(()),
+            _ => Err(Error::Cellbase(CellbaseError::InvalidReward)),
         }
     }
 }
@@ -150,6 +360,101 @@ impl EmptyVerifier {
     }
 }
 
+#[derive(Clone)]
+pub struct ProposalsLimitVerifier<CP> {
+    provider: CP,
+}
+
+impl<CP: ChainProvider + Clone> ProposalsLimitVerifier<CP> {
+    pub fn new(provider: CP) -> Self {
+        ProposalsLimitVerifier { provider }
+    }
+
+    pub fn verify(&self, block: &Block) -> Result<(), Error> {
+        let limit = self.provider.consensus().max_block_proposals_limit();
+        if block.proposal_transactions().len() as u64 > limit {
+            Err(Error::ExceededMaximumProposalsLimit)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SizeVerifier<CP> {
+    provider: CP,
+}
+
+impl<CP: ChainProvider + Clone> SizeVerifier<CP> {
+    pub fn new(provider: CP) -> Self {
+        SizeVerifier { provider }
+    }
+
+    pub fn verify(&self, block: &Block) -> Result<(), Error> {
+        let consensus = self.provider.consensus();
+        let block_bytes = block.serialized_size();
+        if block_bytes > consensus.max_block_bytes() {
+            return Err(Error::ExceededMaximumBlockBytes {
+                max: consensus.max_block_bytes(),
+                actual: block_bytes,
+            });
+        }
+        let data_bytes: u64 = block
+            .commit_transactions()
+            .iter()
+            .map(|tx| tx.outputs_data_bytes())
+            .sum();
+        if data_bytes > consensus.max_block_data_bytes() {
+            return Err(Error::ExceededMaximumBlockDataBytes {
+                max: consensus.max_block_data_bytes(),
+                actual: data_bytes,
+            });
+        }
+        let max_tx_bytes = consensus.max_tx_bytes();
+        for (index, tx) in block.commit_transactions().iter().enumerate() {
+            let tx_bytes = tx.serialized_size();
+            if tx_bytes > max_tx_bytes {
+                return Err(Error::Transactions(vec![(
+                    index,
+                    TransactionError::ExceededMaximumBytes {
+                        max: max_tx_bytes,
+                        actual: tx_bytes,
+                    },
+                )]));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct VersionVerifier<CP> {
+    provider: CP,
+}
+
+impl<CP: ChainProvider + Clone> VersionVerifier<CP> {
+    pub fn new(provider: CP) -> Self {
+        VersionVerifier { provider }
+    }
+
+    pub fn verify(&self, block: &Block) -> Result<(), Error> {
+        let consensus = self.provider.consensus();
+        let headers = ::std::iter::once(block.header())
+            .chain(block.uncles().iter().map(|uncle| uncle.header()));
+        for header in headers {
+            let epoch = consensus.epoch_number(header.number());
+            let max = consensus.hardfork_switch().max_block_version(epoch);
+            if header.version() > max {
+                return Err(Error::Version(VersionError {
+                    max,
+                    actual: header.version(),
+                }));
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone)]
 pub struct DuplicateVerifier {}
 
@@ -213,6 +518,16 @@ impl MerkleRootVerifier {
     }
 }
 
+impl<CI: ChainIndex> BlockMedianTimeContext for Shared<CI> {
+    fn median_time_block_count(&self) -> usize {
+        self.consensus().median_time_block_count()
+    }
+
+    fn median_time_header(&self, hash: &H256) -> Option<Header> {
+        self.block_header(hash)
+    }
+}
+
 pub struct HeaderResolverWrapper<'a, CP> {
     provider: CP,
     header: &'a Header,
@@ -230,7 +545,9 @@ impl<'a, CP: ChainProvider> HeaderResolverWrapper<'a, CP> {
     }
 }
 
-impl<'a, CP: ChainProvider> HeaderResolver for HeaderResolverWrapper<'a, CP> {
+impl<'a, CP: ChainProvider + BlockMedianTimeContext> HeaderResolver
+    for HeaderResolverWrapper<'a, CP>
+{
     fn header(&self) -> &Header {
         self.header
     }
@@ -243,6 +560,15 @@ impl<'a, CP: ChainProvider> HeaderResolver for HeaderResolverWrapper<'a, CP> {
         self.parent()
             .and_then(|parent| self.provider.calculate_difficulty(parent))
     }
+
+    fn median_time(&self) -> Option<u64> {
+        self.parent()
+            .and_then(|parent| self.provider.block_median_time(&parent.hash()))
+    }
+
+    fn max_block_time_drift(&self) -> u64 {
+        self.provider.consensus().max_block_time_drift()
+    }
 }
 
 // TODO redo uncle verifier, check uncle proposal duplicate
@@ -302,123 +628,110 @@ impl<CP: ChainProvider + Clone> UnclesVerifier<CP> {
             }));
         }
 
-        // verify uncles age
-        let max_uncles_age = self.provider.consensus().max_uncles_age();
-        for uncle in block.uncles() {
-            let depth = block.header().number().saturating_sub(uncle.number());
-
-            if depth > max_uncles_age as u64 || depth < 1 {
-                return Err(Error::Uncles(UnclesError::InvalidDepth {
-                    min: block.header().number() - max_uncles_age as u64,
-                    max: block.header().number() - 1,
-                    actual: uncle.number(),
-                }));
-            }
-        }
-
-        // cB
-        // cB.p^0       1 depth, valid uncle
-        // cB.p^1   ---/  2
-        // cB.p^2   -----/  3
-        // cB.p^3   -------/  4
-        // cB.p^4   ---------/  5
-        // cB.p^5   -----------/  6
-        // cB.p^6   -------------/
-        // cB.p^7
-        // verify uncles is not included in main chain
-        // TODO: cache context
-        let mut excluded = FnvHashSet::default();
-        let mut included = FnvHashSet::default();
+        let mut excluded = self
+            .provider
+            .uncle_exclusion_set(&block.header().parent_hash());
         excluded.insert(block.header().hash());
-        let mut block_hash = block.header().parent_hash();
-        excluded.insert(block_hash);
-        for _ in 0..max_uncles_age {
-            if let Some(block) = self.provider.block(&block_hash) {
-                excluded.insert(block.header().parent_hash());
-                for uncle in block.uncles() {
-                    excluded.insert(uncle.header.hash());
-                }
-
-                block_hash = block.header().parent_hash();
-            } else {
-                break;
-            }
-        }
-
-        let block_difficulty_epoch =
-            block.header().number() / self.provider.consensus().difficulty_adjustment_interval();
-
+        let mut included = FnvHashSet::default();
         for uncle in block.uncles() {
-            let uncle_difficulty_epoch =
-                uncle.header().number()
-                    / self.provider.consensus().difficulty_adjustment_interval();
-
-            if uncle.header().difficulty() != block.header().difficulty() {
-                return Err(Error::Uncles(UnclesError::InvalidDifficulty));
-            }
-
-            if block_difficulty_epoch != uncle_difficulty_epoch {
-                return Err(Error::Uncles(UnclesError::InvalidDifficultyEpoch));
+            verify_uncle(
+                &self.provider,
+                block.header().number(),
+                &block.header().difficulty(),
+                &excluded,
+                uncle,
+            ).map_err(Error::Uncles)?;
+
+            let uncle_hash = uncle.header().hash();
+            if !included.insert(uncle_hash) {
+                return Err(Error::Uncles(UnclesError::Duplicate(uncle_hash)));
             }
+        }
 
-            if uncle.header().cellbase_id() != uncle.cellbase().hash() {
-                return Err(Error::Uncles(UnclesError::InvalidCellbase));
-            }
+        Ok(())
+    }
+}
 
-            let uncle_header = uncle.header.clone();
+/// Checks a single uncle of the block at `number` with `difficulty`, the block assembler runs it
+/// on its candidates so it never includes an uncle the `UnclesVerifier` rejects.
+///
+/// The duplicates among the uncles of a block are left to the caller.
+pub fn verify_uncle<CP: ChainProvider>(
+    provider: &CP,
+    number: BlockNumber,
+    difficulty: &U256,
+    excluded: &FnvHashSet<H256>,
+    uncle: &UncleBlock,
+) -> Result<(), UnclesError> {
+    let consensus = provider.consensus();
+    let max_uncles_age = consensus.max_uncles_age() as u64;
+    let depth = number.saturating_sub(uncle.number());
+    if depth > max_uncles_age || depth < 1 {
+        return Err(UnclesError::InvalidDepth {
+            min: number.saturating_sub(max_uncles_age),
+            max: number.saturating_sub(1),
+            actual: uncle.number(),
+        });
+    }
 
-            let uncle_hash = uncle_header.hash();
-            if included.contains(&uncle_hash) {
-                return Err(Error::Uncles(UnclesError::Duplicate(uncle_hash)));
-            }
+    if uncle.header().difficulty() != *difficulty {
+        return Err(UnclesError::InvalidDifficulty);
+    }
 
-            if excluded.contains(&uncle_hash) {
-                return Err(Error::Uncles(UnclesError::InvalidInclude(uncle_hash)));
-            }
+    if consensus.epoch_number(number) != consensus.epoch_number(uncle.number()) {
+        return Err(UnclesError::InvalidDifficultyEpoch);
+    }
 
-            let proposals = uncle
-                .proposal_transactions()
-                .iter()
-                .map(|id| id.hash())
-                .collect::<Vec<_>>();
+    if uncle.header().cellbase_id() != uncle.cellbase().hash() {
+        return Err(UnclesError::InvalidCellbase);
+    }
 
-            if uncle_header.txs_proposal() != merkle_root(&proposals[..]) {
-                return Err(Error::Uncles(UnclesError::ProposalTransactionsRoot));
-            }
+    let uncle_hash = uncle.header().hash();
+    if excluded.contains(&uncle_hash) {
+        return Err(UnclesError::InvalidInclude(uncle_hash));
+    }
 
-            let mut seen = HashSet::with_capacity(uncle.proposal_transactions().len());
-            if !uncle
-                .proposal_transactions()
-                .iter()
-                .all(|id| seen.insert(id))
-            {
-                return Err(Error::Uncles(UnclesError::ProposalTransactionDuplicate));
-            }
+    if uncle.proposal_transactions().len() as u64 > consensus.max_block_proposals_limit() {
+        return Err(UnclesError::ExceededMaximumProposalsLimit);
+    }
 
-            if !self
-                .provider
-                .consensus()
-                .pow_engine()
-                .verify_header(&uncle_header)
-            {
-                return Err(Error::Uncles(UnclesError::InvalidProof));
-            }
+    let proposals = uncle
+        .proposal_transactions()
+        .iter()
+        .map(|id| id.hash())
+        .collect::<Vec<_>>();
+    if uncle.header().txs_proposal() != merkle_root(&proposals[..]) {
+        return Err(UnclesError::ProposalTransactionsRoot);
+    }
 
-            included.insert(uncle_hash);
-        }
+    let mut seen = HashSet::with_capacity(uncle.proposal_transactions().len());
+    if !uncle
+        .proposal_transactions()
+        .iter()
+        .all(|id| seen.insert(id))
+    {
+        return Err(UnclesError::ProposalTransactionDuplicate);
+    }
 
-        Ok(())
+    if !consensus.pow_engine().verify_header(uncle.header()) {
+        return Err(UnclesError::InvalidProof);
     }
+
+    Ok(())
 }
 
 pub struct TransactionsVerifier<P> {
     provider: P,
+    script_verify: bool,
+    profiler: Option<Arc<dyn ScriptProfiler>>,
 }
 
 impl<P: ChainProvider + CellProvider + Clone> ::std::clone::Clone for TransactionsVerifier<P> {
     fn clone(&self) -> Self {
         TransactionsVerifier {
             provider: self.provider.clone(),
+            script_verify: self.script_verify,
+            profiler: self.profiler.clone(),
         }
     }
 }
@@ -454,14 +767,53 @@ impl<'a, P: CellProvider> CellProvider for TransactionsVerifierWrapper<'a, P> {
             }
         }
     }
+
+    fn cellbase_number(&self, o: &OutPoint) -> Option<BlockNumber> {
+        match self.output_indexs.get(&o.hash) {
+            // the first transaction of the block is its cellbase
+            Some(&0) => Some(self.block.header().number()),
+            Some(_) => None,
+            None => self
+                .verifier
+                .provider
+                .cellbase_number_at(o, &self.block.header().parent_hash()),
+        }
+    }
 }
 
 impl<P: ChainProvider + CellProvider> TransactionsVerifier<P> {
     pub fn new(provider: P) -> Self {
-        TransactionsVerifier { provider }
+        TransactionsVerifier {
+            provider,
+            script_verify: true,
+            profiler: None,
+        }
+    }
+
+    pub fn skip_script_verify(mut self) -> Self {
+        self.script_verify = false;
+        self
+    }
+
+    pub fn profile_scripts(mut self, profiler: Arc<dyn ScriptProfiler>) -> Self {
+        self.profiler = Some(profiler);
+        self
     }
 
+    /// Stops verifying the remaining transactions once any of them fails.
     pub fn verify(&self, block: &Block) -> Result<(), Error> {
+        self.verify_transactions(block, true)
+    }
+
+    /// Verifies all the transactions and collects every failure.
+    pub fn verify_all(&self, block: &Block) -> Result<(), Error> {
+        self.verify_transactions(block, false)
+    }
+
+    fn verify_transactions(&self, block: &Block, fail_fast: bool) -> Result<(), Error> {
+        // the transactions are verified independently below, none of them sees the others' spends
+        Self::verify_conflicts(block)?;
+
         let mut output_indexs = FnvHashMap::default();
 
         for (i, tx) in block.commit_transactions().iter().enumerate() {
@@ -474,25 +826,94 @@ impl<P: ChainProvider + CellProvider> TransactionsVerifier<P> {
         };
 
         let parent_hash = block.header().parent_hash();
+        let number = block.header().number();
+        let consensus = self.provider.consensus();
+        let max_script_version = consensus
+            .hardfork_switch()
+            .max_script_version(consensus.epoch_number(number));
+        let max_cell_data_bytes = consensus.max_cell_data_bytes();
+        // a transaction alone may cost the cycles of the block, the total is checked below
+        let max_cycles = consensus.max_block_cycles();
+        let chain_id = consensus.chain_id();
+        let cellbase_maturity = consensus.cellbase_maturity_at(number);
+        let cache = self.provider.txs_verify_cache();
+        let failed = AtomicBool::new(false);
         // make verifiers orthogonal
         // skip first tx, assume the first is cellbase, other verifier will verify cellbase
-        let err: Vec<(usize, TransactionError)> = block
+        let results: Vec<Result<Cycle, (usize, TransactionError)>> = block
             .commit_transactions()
             .par_iter()
             .skip(1)
-            .map(|x| wrapper.resolve_transaction_at(x, &parent_hash))
             .enumerate()
-            .filter_map(|(index, tx)| {
-                TransactionVerifier::new(&tx)
-                    .verify()
-                    .err()
-                    .map(|e| (index, e))
+            .filter_map(|(index, x)| {
+                // other workers may still be running, the flag only saves the remaining work
+                if fail_fast && failed.load(Ordering::Relaxed) {
+                    return None;
+                }
+                let tx = wrapper.resolve_transaction_at(x, &parent_hash);
+                let verifier = TransactionVerifier::new(
+                    &tx,
+                    max_script_version,
+                    max_cell_data_bytes,
+                    max_cycles,
+                    chain_id,
+                );
+                let maturity = MaturityVerifier::new(x, &wrapper, number, cellbase_maturity);
+                let result = maturity.verify().and_then(|_| {
+                    if !self.script_verify {
+                        verifier.verify_without_script().map(|_| 0)
+                    } else if let Some(ref profiler) = self.profiler {
+                        verifier.verify_profiled(&**profiler)
+                    } else {
+                        verifier.verify_with_cache(cache)
+                    }
+                });
+                Some(result.map_err(|e| {
+                    failed.store(true, Ordering::Relaxed);
+                    (index, e)
+                }))
             }).collect();
-        if err.is_empty() {
-            Ok(())
-        } else {
-            Err(Error::Transactions(err))
+
+        let mut cycles: Cycle = 0;
+        let mut err = Vec::new();
+        for result in results {
+            match result {
+                Ok(tx_cycles) => cycles += tx_cycles,
+                Err(e) => err.push(e),
+            }
+        }
+        if !err.is_empty() {
+            return Err(Error::Transactions(err));
+        }
+        // the scripts aren't run, so not metered, when skipped
+        let max = consensus.max_block_cycles();
+        if cycles > max {
+            return Err(Error::ExceededMaximumBlockCycles {
+                max,
+                actual: cycles,
+            });
+        }
+        Ok(())
+    }
+
+    fn verify_conflicts(block: &Block) -> Result<(), Error> {
+        let mut spent = FnvHashMap::default();
+        for (index, tx) in block.commit_transactions().iter().skip(1).enumerate() {
+            for out_point in tx.input_pts() {
+                match spent.insert(out_point, index) {
+                    // the same input twice in one transaction is left to DuplicateInputsVerifier
+                    Some(first) if first != index => {
+                        return Err(Error::Conflict {
+                            out_point,
+                            first,
+                            second: index,
+                        });
+                    }
+                    _ => {}
+                }
+            }
         }
+        Ok(())
     }
 }
 
@@ -508,36 +929,37 @@ impl<CP: ChainProvider + Clone> CommitVerifier<CP> {
 
     pub fn verify(&self, block: &Block) -> Result<(), Error> {
         let block_number = block.header().number();
-        let t_prop = self.provider.consensus().transaction_propagation_time;
-        let mut walk = self.provider.consensus().transaction_propagation_timeout;
-        let start = block_number.saturating_sub(t_prop);
+        let proposals_range = self
+            .provider
+            .consensus()
+            .proposal_window()
+            .proposals_range(block_number);
 
-        if start < 1 {
-            return Ok(());
-        }
-
-        let mut block_hash = block.header().parent_hash();
         let mut proposal_txs_ids = FnvHashSet::default();
+        if let Some(range) = proposals_range {
+            let mut block_hash = block.header().parent_hash();
+            loop {
+                let block = self
+                    .provider
+                    .block(&block_hash)
+                    .ok_or_else(|| Error::Commit(CommitError::AncestorNotFound))?;
+                let number = block.header().number();
+                if number < *range.start() {
+                    break;
+                }
+                if number <= *range.end() {
+                    proposal_txs_ids.extend(
+                        block.proposal_transactions().iter().chain(
+                            block
+                                .uncles()
+                                .iter()
+                                .flat_map(|uncle| uncle.proposal_transactions()),
+                        ),
+                    );
+                }
 
-        while walk > 0 {
-            let block = self
-                .provider
-                .block(&block_hash)
-                .ok_or_else(|| Error::Commit(CommitError::AncestorNotFound))?;
-            if block.is_genesis() {
-                break;
+                block_hash = block.header().parent_hash();
             }
-            proposal_txs_ids.extend(
-                block.proposal_transactions().iter().chain(
-                    block
-                        .uncles()
-                        .iter()
-                        .flat_map(|uncle| uncle.proposal_transactions()),
-                ),
-            );
-
-            block_hash = block.header().parent_hash();
-            walk -= 1;
         }
 
         let commited_ids: FnvHashSet<_> = block
@@ -1,6 +1,7 @@
 use bigint::{H256, U256};
+use ckb_core::transaction::OutPoint;
 use ckb_core::BlockNumber;
-use ckb_script::ScriptError;
+use ckb_script::ScriptFailure;
 use ckb_shared::error::SharedError;
 
 /// Block verification error
@@ -8,22 +9,36 @@ use ckb_shared::error::SharedError;
 pub enum Error {
     /// PoW proof is corrupt or does not meet the difficulty target.
     Pow(PowError),
+    /// The header version of the block or of an uncle isn't active in its epoch.
+    Version(VersionError),
     /// The field timestamp in block header is invalid.
     Timestamp(TimestampError),
     /// The field number in block header is invalid.
     Number(NumberError),
     /// The field difficulty in block header is invalid.
     Difficulty(DifficultyError),
-    /// Committed transactions verification error. It contains errors for all the transactions that
-    /// fail the verification. The errors are stored as a Vec of tuple, where the first item is the
+    /// Committed transactions verification error. It contains errors for the transactions that
+    /// fail the verification, all of them in collect-all mode and at least the first one found
+    /// otherwise. The errors are stored as a Vec of tuple, where the first item is the
     /// transaction index in the block and the second item is the transaction verification error.
     Transactions(Vec<(usize, TransactionError)>),
+    /// Two committed transactions spend the same cell. The indexes of the earlier and the later
+    /// transaction are counted like in `Transactions`, from the first one after the cellbase.
+    Conflict { out_point: OutPoint, first: usize, second: usize },
     /// This is a wrapper of error encountered when invoking chain API.
     Chain(SharedError),
     /// The committed transactions list is empty.
     CommitTransactionsEmpty,
     /// There are duplicate proposed transactions.
     ProposalTransactionDuplicate,
+    /// The number of proposed transactions exceeds the consensus limit.
+    ExceededMaximumProposalsLimit,
+    /// The serialized size of the block exceeds the consensus limit.
+    ExceededMaximumBlockBytes { max: u64, actual: u64 },
+    /// The total size of the outputs data committed in the block exceeds the consensus limit.
+    ExceededMaximumBlockDataBytes { max: u64, actual: u64 },
+    /// The scripts of the committed transactions cost more cycles than the consensus limit.
+    ExceededMaximumBlockCycles { max: u64, actual: u64 },
     /// There are duplicate committed transactions.
     CommitTransactionDuplicate,
     /// The merkle tree hash of proposed transactions does not match the one in header.
@@ -39,6 +54,8 @@ pub enum Error {
     /// This error is returned when the committed transactions does not meet the 2-phases
     /// propose-then-commit consensus rule.
     Commit(CommitError),
+    /// The block numbered 0 is not the genesis block of the chain spec.
+    Genesis(GenesisError),
 }
 
 #[derive(Debug, PartialEq, Clone, Eq)]
@@ -49,6 +66,19 @@ pub enum CommitError {
     Invalid,
 }
 
+#[derive(Debug, PartialEq, Clone, Eq)]
+pub enum GenesisError {
+    /// The block hash differs from the genesis in the chain spec or in the store.
+    Mismatch { expected: H256, actual: H256 },
+    /// The genesis has no parent, its parent hash must be zero.
+    InvalidParentHash,
+    /// The genesis has no ancestors to propose or to include as uncles.
+    NonEmptyProposals,
+    NonEmptyUncles,
+    /// The genesis is only accepted as a whole block, never through a header alone.
+    UnexpectedHeader,
+}
+
 #[derive(Debug, PartialEq, Clone, Eq)]
 pub enum CellbaseError {
     InvalidInput,
@@ -81,6 +111,7 @@ pub enum UnclesError {
     InvalidProof,
     ProposalTransactionsRoot,
     ProposalTransactionDuplicate,
+    ExceededMaximumProposalsLimit,
     Duplicate(H256),
     InvalidInclude(H256),
     InvalidCellbase,
@@ -94,10 +125,16 @@ pub enum PowError {
 
 #[derive(Debug, PartialEq, Clone, Copy, Eq)]
 pub enum TimestampError {
-    ZeroBlockTime { min: u64, found: u64 },
+    BlockTimeTooOld { min: u64, found: u64 },
     FutureBlockTime { max: u64, found: u64 },
 }
 
+#[derive(Debug, PartialEq, Clone, Copy, Eq)]
+pub struct VersionError {
+    pub max: u32,
+    pub actual: u32,
+}
+
 #[derive(Debug, PartialEq, Clone, Copy, Eq)]
 pub struct NumberError {
     pub expected: u64,
@@ -117,11 +154,83 @@ pub enum TransactionError {
     DuplicateInputs,
     Empty,
     InvalidCapacity,
-    InvalidScript,
-    ScriptFailure(ScriptError),
+    /// The outputs capacity overflows, a wrapped sum would pass as less than the inputs
+    OutputsSumOverflow,
+    /// The input's lock script doesn't hash to the lock committed by the spent output
+    LockHashMismatch,
+    /// Which script failed and why
+    ScriptFailure(ScriptFailure),
+    /// A script declares a version that isn't active yet
+    ScriptVersion { max: u8, actual: u8 },
     InvalidSignature,
     DoubleSpent,
     UnknownInput,
+    /// The serialized size of the transaction exceeds the consensus limit
+    ExceededMaximumBytes { max: u64, actual: u64 },
+    /// The data of an output exceeds the consensus limit of a cell
+    ExceededMaximumCellDataBytes { max: u64, actual: u64 },
+    /// The outputs data exceed the data budget of a block, the transaction can't be committed
+    ExceededMaximumDataBytes { max: u64, actual: u64 },
+    /// Spends a cellbase output before it matured, see `Consensus::cellbase_maturity`
+    CellbaseImmaturity,
+}
+
+impl TransactionError {
+    /// Stable code of the rejection, returned by the RPC and sent to the relaying peer so
+    /// clients don't need to parse the error. Codes are never reused or renumbered, the
+    /// transaction errors take 1xx, the pool errors 2xx, the block errors 3xx and the chain
+    /// errors 4xx.
+    pub fn code(&self) -> u32 {
+        match self {
+            TransactionError::NullInput => 101,
+            TransactionError::OutofBound => 102,
+            TransactionError::DuplicateInputs => 103,
+            TransactionError::Empty => 104,
+            TransactionError::InvalidCapacity => 105,
+            TransactionError::OutputsSumOverflow => 106,
+            TransactionError::LockHashMismatch => 107,
+            TransactionError::ScriptFailure(_) => 108,
+            TransactionError::ScriptVersion { .. } => 109,
+            TransactionError::InvalidSignature => 110,
+            TransactionError::DoubleSpent => 111,
+            TransactionError::UnknownInput => 112,
+            TransactionError::ExceededMaximumBytes { .. } => 113,
+            TransactionError::ExceededMaximumCellDataBytes { .. } => 114,
+            TransactionError::ExceededMaximumDataBytes { .. } => 115,
+            // 116 was running out of cycles, the VM now stops such a script with a failure
+            TransactionError::CellbaseImmaturity => 117,
+        }
+    }
+}
+
+impl Error {
+    /// Stable code of the block rejection, see `TransactionError::code`
+    pub fn code(&self) -> u32 {
+        match self {
+            Error::Pow(_) => 301,
+            Error::Version(_) => 302,
+            Error::Timestamp(_) => 303,
+            Error::Number(_) => 304,
+            Error::Difficulty(_) => 305,
+            Error::Transactions(_) => 306,
+            Error::Conflict { .. } => 307,
+            Error::Chain(_) => 308,
+            Error::CommitTransactionsEmpty => 309,
+            Error::ProposalTransactionDuplicate => 310,
+            Error::ExceededMaximumProposalsLimit => 311,
+            Error::ExceededMaximumBlockBytes { .. } => 312,
+            Error::ExceededMaximumBlockDataBytes { .. } => 313,
+            Error::ExceededMaximumBlockCycles { .. } => 314,
+            Error::CommitTransactionDuplicate => 315,
+            Error::ProposalTransactionsRoot => 316,
+            Error::CommitTransactionsRoot => 317,
+            Error::UnknownParent(_) => 318,
+            Error::Uncles(_) => 319,
+            Error::Cellbase(_) => 320,
+            Error::Commit(_) => 321,
+            Error::Genesis(_) => 322,
+        }
+    }
 }
 
 impl From<SharedError> for Error {
@@ -1,10 +1,9 @@
 use super::Verifier;
-use bigint::U256;
+use bigint::{H256, U256};
 use ckb_core::header::Header;
 use ckb_pow::PowEngine;
 use ckb_time::now_ms;
-use error::{DifficultyError, Error, NumberError, PowError, TimestampError};
-use shared::ALLOWED_FUTURE_BLOCKTIME;
+use error::{DifficultyError, Error, GenesisError, NumberError, PowError, TimestampError};
 use std::marker::PhantomData;
 use std::sync::Arc;
 
@@ -14,6 +13,10 @@ pub trait HeaderResolver {
     fn parent(&self) -> Option<&Header>;
     /// resolves header difficulty
     fn calculate_difficulty(&self) -> Option<U256>;
+    /// resolves the median timestamp of the median time window ending at the parent
+    fn median_time(&self) -> Option<u64>;
+    /// how far ahead of the local clock the header timestamp may be
+    fn max_block_time_drift(&self) -> u64;
 }
 
 pub struct HeaderVerifier<T> {
@@ -34,6 +37,11 @@ impl<T: HeaderResolver> Verifier for HeaderVerifier<T> {
     type Target = T;
     fn verify(&self, target: &T) -> Result<(), Error> {
         let header = target.header();
+        // The genesis has no parent to check against, it comes with the chain spec and is
+        // verified as a whole block by BlockVerifier
+        if header.is_genesis() {
+            return Err(Error::Genesis(GenesisError::UnexpectedHeader));
+        }
 
         // POW check first
         PowVerifier::new(header, &self.pow).verify()?;
@@ -41,36 +49,78 @@ impl<T: HeaderResolver> Verifier for HeaderVerifier<T> {
             .parent()
             .ok_or_else(|| Error::UnknownParent(header.parent_hash()))?;
         NumberVerifier::new(parent, header).verify()?;
-        TimestampVerifier::new(parent, header).verify()?;
+        let median_time = target
+            .median_time()
+            .ok_or_else(|| Error::UnknownParent(header.parent_hash()))?;
+        TimestampVerifier::new(header, median_time, target.max_block_time_drift()).verify()?;
         DifficultyVerifier::verify(target)?;
         Ok(())
     }
 }
 
+/// The median of the timestamps, the lower one of the two middle ones for an even count
+pub fn median_time(mut timestamps: Vec<u64>) -> u64 {
+    timestamps.sort();
+    timestamps
+        .get(timestamps.len().saturating_sub(1) / 2)
+        .cloned()
+        .unwrap_or(0)
+}
+
+/// The headers the median time of a block is resolved from, the chain store for the stored
+/// blocks, the synchronizer also knows the headers of the blocks it is downloading
+pub trait BlockMedianTimeContext {
+    /// Number of blocks in the median time window, see `Consensus::median_time_block_count`
+    fn median_time_block_count(&self) -> usize;
+
+    fn median_time_header(&self, hash: &H256) -> Option<Header>;
+
+    /// The median timestamp of the window ending at the block `hash`, which holds fewer blocks
+    /// near the genesis. `None` if a header of the window is unknown.
+    fn block_median_time(&self, hash: &H256) -> Option<u64> {
+        let count = self.median_time_block_count();
+        let mut timestamps = Vec::with_capacity(count);
+        let mut hash = *hash;
+        loop {
+            let header = self.median_time_header(&hash)?;
+            timestamps.push(header.timestamp());
+            if timestamps.len() >= count || header.is_genesis() {
+                return Some(median_time(timestamps));
+            }
+            hash = header.parent_hash();
+        }
+    }
+}
+
+/// A header's timestamp must be greater than the median time of the blocks before it, which
+/// a few blocks with forged timestamps can't move, and at most `max_block_time_drift` ahead of
+/// the local clock.
 pub struct TimestampVerifier<'a> {
-    parent: &'a Header,
     header: &'a Header,
+    median_time: u64,
+    max_block_time_drift: u64,
     now: u64,
 }
 
 impl<'a> TimestampVerifier<'a> {
-    pub fn new(parent: &'a Header, header: &'a Header) -> Self {
+    pub fn new(header: &'a Header, median_time: u64, max_block_time_drift: u64) -> Self {
         TimestampVerifier {
-            parent,
             header,
+            median_time,
+            max_block_time_drift,
             now: now_ms(),
         }
     }
 
     pub fn verify(&self) -> Result<(), Error> {
-        let min = self.parent.timestamp() + 1;
+        let min = self.median_time + 1;
         if self.header.timestamp() < min {
-            return Err(Error::Timestamp(TimestampError::ZeroBlockTime {
+            return Err(Error::Timestamp(TimestampError::BlockTimeTooOld {
                 min,
                 found: self.header.timestamp(),
             }));
         }
-        let max = self.now + ALLOWED_FUTURE_BLOCKTIME;
+        let max = self.now + self.max_block_time_drift;
         if self.header.timestamp() > max {
             return Err(Error::Timestamp(TimestampError::FutureBlockTime {
                 max,
@@ -4,6 +4,7 @@ extern crate ckb_pow;
 extern crate ckb_script;
 extern crate ckb_shared;
 extern crate ckb_time;
+extern crate ckb_util;
 extern crate fnv;
 extern crate merkle_root;
 extern crate rayon;
@@ -18,20 +19,30 @@ extern crate ckb_db;
 extern crate ckb_notify;
 #[cfg(test)]
 extern crate hash;
+#[cfg(test)]
+extern crate lru_cache;
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
 
 mod block_verifier;
 mod error;
 mod header_verifier;
-mod shared;
 mod transaction_verifier;
 
 #[cfg(test)]
 pub mod tests;
 
-pub use block_verifier::{BlockVerifier, HeaderResolverWrapper};
-pub use error::{Error, TransactionError};
-pub use header_verifier::{HeaderResolver, HeaderVerifier};
-pub use transaction_verifier::TransactionVerifier;
+pub use block_verifier::{
+    verify_uncle, BlockCheck, BlockVerifier, CommitVerifier, GenesisVerifier,
+    HeaderResolverWrapper, SizeVerifier, DEFAULT_CHECK_ORDER,
+};
+pub use ckb_script::{
+    ScriptError, ScriptFailure, ScriptProfile, ScriptProfiler, TransactionProfile,
+};
+pub use error::{Error, GenesisError, TransactionError, UnclesError};
+pub use header_verifier::{median_time, BlockMedianTimeContext, HeaderResolver, HeaderVerifier};
+pub use transaction_verifier::{DataSizeVerifier, MaturityVerifier, TransactionVerifier};
 
 pub trait Verifier {
     type Target;
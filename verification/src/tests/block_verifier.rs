@@ -1,12 +1,32 @@
-use super::super::block_verifier::{BlockVerifier, CellbaseVerifier, EmptyVerifier};
-use super::super::error::{CellbaseError, Error as VerifyError};
+use super::super::block_verifier::{
+    BlockCheck, BlockVerifier, CellbaseVerifier, EmptyVerifier, GenesisVerifier,
+    ProposalsLimitVerifier, SizeVerifier, TransactionsVerifier, VersionVerifier,
+};
+use super::super::error::{
+    CellbaseError, Error as VerifyError, GenesisError, TransactionError, UnclesError, VersionError,
+};
 use super::dummy::DummyChainProvider;
 use bigint::H256;
+use ckb_chain_spec::consensus::Consensus;
+use ckb_chain_spec::hardfork::HardForkSwitch;
 use ckb_core::block::BlockBuilder;
-use ckb_core::transaction::{CellInput, CellOutput, OutPoint, Transaction, TransactionBuilder};
+use ckb_core::cell::ResolvedTransaction;
+use ckb_core::header::HeaderBuilder;
+use ckb_core::script::Script;
+use ckb_core::transaction::{
+    CellInput, CellOutput, OutPoint, ProposalShortId, Transaction, TransactionBuilder,
+};
+use ckb_core::uncle::UncleBlock;
 use ckb_core::Capacity;
+use ckb_db::memorydb::MemoryKeyValueDB;
+use ckb_script::TransactionScriptsVerifier;
 use ckb_shared::error::SharedError;
+use ckb_shared::shared::{ChainProvider, SharedBuilder};
+use ckb_shared::store::ChainKVStore;
 use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
 use Verifier;
 
 fn create_cellbase_transaction_with_capacity(capacity: Capacity) -> Transaction {
@@ -54,6 +74,7 @@ pub fn test_block_with_one_cellbase_at_first() {
 
     let provider = DummyChainProvider {
         block_reward: 100,
+        uncle_reward: 0,
         transaction_fees: transaction_fees,
     };
 
@@ -102,6 +123,7 @@ pub fn test_cellbase_with_less_reward() {
 
     let provider = DummyChainProvider {
         block_reward: 100,
+        uncle_reward: 0,
         transaction_fees: transaction_fees,
     };
 
@@ -122,6 +144,7 @@ pub fn test_cellbase_with_fee() {
 
     let provider = DummyChainProvider {
         block_reward: 100,
+        uncle_reward: 0,
         transaction_fees: transaction_fees,
     };
 
@@ -142,6 +165,7 @@ pub fn test_cellbase_with_more_reward_than_available() {
 
     let provider = DummyChainProvider {
         block_reward: 100,
+        uncle_reward: 0,
         transaction_fees: transaction_fees,
     };
 
@@ -152,6 +176,33 @@ pub fn test_cellbase_with_more_reward_than_available() {
     );
 }
 
+#[test]
+pub fn test_cellbase_with_uncles_reward() {
+    let uncle = UncleBlock {
+        header: HeaderBuilder::default().build(),
+        cellbase: create_cellbase_transaction(),
+        proposal_transactions: Vec::new(),
+    };
+    let block = |capacity| {
+        BlockBuilder::default()
+            .commit_transaction(create_cellbase_transaction_with_capacity(capacity))
+            .uncles(vec![uncle.clone(), uncle.clone()])
+            .build()
+    };
+    let provider = DummyChainProvider {
+        block_reward: 100,
+        uncle_reward: 5,
+        transaction_fees: HashMap::new(),
+    };
+
+    let verifier = CellbaseVerifier::new(provider);
+    assert!(verifier.verify(&block(110)).is_ok());
+    assert_eq!(
+        verifier.verify(&block(111)),
+        Err(VerifyError::Cellbase(CellbaseError::InvalidReward))
+    );
+}
+
 #[test]
 pub fn test_cellbase_with_invalid_transaction() {
     let mut transaction_fees = HashMap::<H256, Result<Capacity, SharedError>>::new();
@@ -165,6 +216,7 @@ pub fn test_cellbase_with_invalid_transaction() {
 
     let provider = DummyChainProvider {
         block_reward: 100,
+        uncle_reward: 0,
         transaction_fees: transaction_fees,
     };
 
@@ -194,6 +246,7 @@ pub fn test_cellbase_with_two_outputs() {
 
     let provider = DummyChainProvider {
         block_reward: 150,
+        uncle_reward: 0,
         transaction_fees: transaction_fees,
     };
 
@@ -220,6 +273,42 @@ pub fn test_cellbase_with_two_outputs_and_more_rewards_than_maximum() {
 
     let provider = DummyChainProvider {
         block_reward: 100,
+        uncle_reward: 0,
+        transaction_fees: transaction_fees,
+    };
+
+    let verifier = CellbaseVerifier::new(provider);
+    assert_eq!(
+        verifier.verify(&block),
+        Err(VerifyError::Cellbase(CellbaseError::InvalidReward))
+    );
+}
+
+#[test]
+pub fn test_cellbase_with_overflowing_outputs() {
+    let mut transaction_fees = HashMap::<H256, Result<Capacity, SharedError>>::new();
+    let transaction = create_normal_transaction();
+    transaction_fees.insert(transaction.hash(), Ok(0));
+
+    // the wrapped sum would be 99, within the reward
+    let cellbase_transaction = TransactionBuilder::default()
+        .input(CellInput::new_cellbase_input(0))
+        .output(CellOutput::new(
+            u64::max_value(),
+            Vec::new(),
+            H256::default(),
+            None,
+        )).output(CellOutput::new(100, Vec::new(), H256::default(), None))
+        .build();
+
+    let block = BlockBuilder::default()
+        .commit_transaction(cellbase_transaction)
+        .commit_transaction(transaction)
+        .build();
+
+    let provider = DummyChainProvider {
+        block_reward: 150,
+        uncle_reward: 0,
         transaction_fees: transaction_fees,
     };
 
@@ -230,13 +319,50 @@ pub fn test_cellbase_with_two_outputs_and_more_rewards_than_maximum() {
     );
 }
 
+#[test]
+pub fn test_check_order() {
+    let mut transaction_fees = HashMap::<H256, Result<Capacity, SharedError>>::new();
+    let transaction = create_normal_transaction();
+    transaction_fees.insert(transaction.hash(), Ok(0));
+
+    // the reward is exceeded and the header does not commit to the transactions
+    let block = BlockBuilder::default()
+        .commit_transaction(create_cellbase_transaction_with_capacity(150))
+        .commit_transaction(transaction)
+        .build();
+
+    let provider = DummyChainProvider {
+        block_reward: 100,
+        uncle_reward: 0,
+        transaction_fees: transaction_fees,
+    };
+
+    let verifier = BlockVerifier::new(provider);
+    assert_eq!(
+        verifier
+            .clone()
+            .check_order(&[BlockCheck::MerkleRoot])
+            .verify(&block),
+        Err(VerifyError::CommitTransactionsRoot)
+    );
+    assert_eq!(
+        verifier
+            .check_order(&[BlockCheck::Cellbase, BlockCheck::MerkleRoot])
+            .verify(&block),
+        Err(VerifyError::Cellbase(CellbaseError::InvalidReward))
+    );
+}
+
 #[test]
 pub fn test_empty_transactions() {
-    let block = BlockBuilder::default().build();
+    let block = BlockBuilder::default()
+        .header(HeaderBuilder::default().number(1).build())
+        .build();
     let transaction_fees = HashMap::<H256, Result<Capacity, SharedError>>::new();
 
     let provider = DummyChainProvider {
         block_reward: 150,
+        uncle_reward: 0,
         transaction_fees: transaction_fees,
     };
 
@@ -252,3 +378,412 @@ pub fn test_empty_transactions() {
         Err(VerifyError::CommitTransactionsEmpty)
     );
 }
+
+#[test]
+pub fn test_genesis_verifier() {
+    let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory().build();
+    let genesis = shared.consensus().genesis_block().clone();
+    let verifier = GenesisVerifier::new(shared.clone());
+    // the genesis has no cellbase, it goes through BlockVerifier all the same
+    assert!(BlockVerifier::new(shared).verify(&genesis).is_ok());
+
+    let other = BlockBuilder::default()
+        .block(genesis.clone())
+        .header(
+            HeaderBuilder::default()
+                .difficulty(&genesis.header().difficulty())
+                .timestamp(1)
+                .build(),
+        ).build();
+    assert_eq!(
+        verifier.verify(&other),
+        Err(VerifyError::Genesis(GenesisError::Mismatch {
+            expected: genesis.header().hash(),
+            actual: other.header().hash(),
+        }))
+    );
+
+    let block = BlockBuilder::default()
+        .block(genesis.clone())
+        .proposal_transaction(ProposalShortId::from_slice(&[1; 10]).unwrap())
+        .build();
+    assert_eq!(
+        verifier.verify(&block),
+        Err(VerifyError::Genesis(GenesisError::NonEmptyProposals))
+    );
+
+    // the same header over another body
+    let block = BlockBuilder::default()
+        .block(genesis.clone())
+        .commit_transaction(create_cellbase_transaction())
+        .build();
+    assert_eq!(
+        verifier.verify(&block),
+        Err(VerifyError::CommitTransactionsRoot)
+    );
+
+    let header = HeaderBuilder::default()
+        .header(genesis.header().clone())
+        .uncles_hash(&H256::from(1))
+        .build();
+    let block = BlockBuilder::default()
+        .block(genesis.clone())
+        .header(header)
+        .build();
+    assert_eq!(
+        verifier.verify(&block),
+        Err(VerifyError::Uncles(UnclesError::InvalidHash {
+            expected: H256::from(1),
+            actual: H256::zero(),
+        }))
+    );
+}
+
+#[test]
+pub fn test_exceeded_maximum_proposals_limit() {
+    let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+        .consensus(Consensus::default().set_max_block_proposals_limit(2))
+        .build();
+    let verifier = ProposalsLimitVerifier::new(shared);
+
+    let proposal_ids: Vec<ProposalShortId> = (0..3u8)
+        .map(|i| ProposalShortId::from_slice(&[i; 10]).unwrap())
+        .collect();
+
+    let block = BlockBuilder::default()
+        .commit_transaction(create_cellbase_transaction())
+        .proposal_transactions(proposal_ids[..2].to_vec())
+        .build();
+    assert!(verifier.verify(&block).is_ok());
+
+    let block = BlockBuilder::default()
+        .commit_transaction(create_cellbase_transaction())
+        .proposal_transactions(proposal_ids)
+        .build();
+    assert_eq!(
+        verifier.verify(&block),
+        Err(VerifyError::ExceededMaximumProposalsLimit)
+    );
+}
+
+#[test]
+pub fn test_exceeded_maximum_bytes() {
+    let block = BlockBuilder::default()
+        .commit_transaction(create_cellbase_transaction())
+        .commit_transaction(create_normal_transaction())
+        .build();
+    let block_bytes = block.serialized_size();
+    let tx_bytes = create_normal_transaction().serialized_size();
+    let verifier = |max_block_bytes, max_tx_bytes| {
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+            .consensus(
+                Consensus::default()
+                    .set_max_block_bytes(max_block_bytes)
+                    .set_max_tx_bytes(max_tx_bytes),
+            ).build();
+        SizeVerifier::new(shared)
+    };
+
+    assert!(verifier(block_bytes, tx_bytes).verify(&block).is_ok());
+    assert_eq!(
+        verifier(block_bytes - 1, tx_bytes).verify(&block),
+        Err(VerifyError::ExceededMaximumBlockBytes {
+            max: block_bytes - 1,
+            actual: block_bytes,
+        })
+    );
+    assert_eq!(
+        verifier(block_bytes, tx_bytes - 1).verify(&block),
+        Err(VerifyError::Transactions(vec![(
+            1,
+            TransactionError::ExceededMaximumBytes {
+                max: tx_bytes - 1,
+                actual: tx_bytes,
+            }
+        )]))
+    );
+}
+
+#[test]
+pub fn test_exceeded_maximum_data_bytes() {
+    let data_transaction = |index, data_bytes| {
+        TransactionBuilder::default()
+            .input(CellInput::new(
+                OutPoint::new(H256::from(1), index),
+                Default::default(),
+            )).output(CellOutput::new(
+                100,
+                vec![1; data_bytes],
+                H256::default(),
+                None,
+            )).build()
+    };
+    let block = BlockBuilder::default()
+        .commit_transaction(create_cellbase_transaction())
+        .commit_transaction(data_transaction(0, 100))
+        .commit_transaction(data_transaction(1, 50))
+        .build();
+    let verifier = |max_block_data_bytes| {
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+            .consensus(Consensus::default().set_max_block_data_bytes(max_block_data_bytes))
+            .build();
+        SizeVerifier::new(shared)
+    };
+
+    // the budget covers the data of all the transactions together
+    assert!(verifier(150).verify(&block).is_ok());
+    assert_eq!(
+        verifier(149).verify(&block),
+        Err(VerifyError::ExceededMaximumBlockDataBytes {
+            max: 149,
+            actual: 150,
+        })
+    );
+}
+
+#[test]
+pub fn test_block_version_activation() {
+    let consensus = Consensus::default().set_hardfork_switch(HardForkSwitch {
+        block_version_1: Some(2),
+        ..Default::default()
+    });
+    let epoch_length = consensus.difficulty_adjustment_interval();
+    let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+        .consensus(consensus)
+        .build();
+    let verifier = VersionVerifier::new(shared);
+
+    let block = |version, number| {
+        BlockBuilder::default()
+            .commit_transaction(create_cellbase_transaction())
+            .header(HeaderBuilder::default().version(version).number(number).build())
+            .build()
+    };
+
+    assert!(verifier.verify(&block(0, 2 * epoch_length - 1)).is_ok());
+    assert_eq!(
+        verifier.verify(&block(1, 2 * epoch_length - 1)),
+        Err(VerifyError::Version(VersionError { max: 0, actual: 1 }))
+    );
+    assert!(verifier.verify(&block(1, 2 * epoch_length)).is_ok());
+    assert_eq!(
+        verifier.verify(&block(2, 2 * epoch_length)),
+        Err(VerifyError::Version(VersionError { max: 1, actual: 2 }))
+    );
+}
+
+#[test]
+pub fn test_transactions_verifier_fail_fast() {
+    let cellbase = TransactionBuilder::default()
+        .input(CellInput::new_cellbase_input(0))
+        .outputs(vec![
+            CellOutput::new(100, Vec::new(), H256::default(), None);
+            3
+        ]).build();
+    // every transaction spends more capacity than its input holds
+    let transactions: Vec<Transaction> = (0..3)
+        .map(|index| {
+            TransactionBuilder::default()
+                .input(CellInput::new(
+                    OutPoint::new(cellbase.hash(), index),
+                    Default::default(),
+                )).output(CellOutput::new(200, Vec::new(), H256::default(), None))
+                .build()
+        }).collect();
+
+    let block = BlockBuilder::default()
+        .commit_transaction(cellbase)
+        .commit_transactions(transactions)
+        .build();
+
+    let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+        .consensus(Consensus::default().set_cellbase_maturity(0))
+        .build();
+    let verifier = TransactionsVerifier::new(shared);
+    match verifier.verify(&block) {
+        Err(VerifyError::Transactions(errors)) => {
+            assert!(!errors.is_empty() && errors.len() <= 3);
+        }
+        result => panic!("unexpected result {:?}", result),
+    }
+
+    let mut errors = match verifier.verify_all(&block) {
+        Err(VerifyError::Transactions(errors)) => errors,
+        result => panic!("unexpected result {:?}", result),
+    };
+    errors.sort_by_key(|(index, _)| *index);
+    assert_eq!(
+        errors,
+        vec![
+            (0, TransactionError::InvalidCapacity),
+            (1, TransactionError::InvalidCapacity),
+            (2, TransactionError::InvalidCapacity),
+        ]
+    );
+}
+
+#[test]
+pub fn test_transactions_verifier_skip_script_verify() {
+    let cellbase = TransactionBuilder::default()
+        .input(CellInput::new_cellbase_input(0))
+        .output(CellOutput::new(100, Vec::new(), H256::default(), None))
+        .build();
+    // the default unlock script has no binary to run
+    let transaction = TransactionBuilder::default()
+        .input(CellInput::new(
+            OutPoint::new(cellbase.hash(), 0),
+            Default::default(),
+        )).output(CellOutput::new(100, Vec::new(), H256::default(), None))
+        .build();
+
+    let block = BlockBuilder::default()
+        .commit_transaction(cellbase)
+        .commit_transaction(transaction)
+        .build();
+
+    let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+        .consensus(Consensus::default().set_cellbase_maturity(0))
+        .build();
+    let verifier = TransactionsVerifier::new(shared);
+    match verifier.verify(&block) {
+        Err(VerifyError::Transactions(errors)) => match errors[0] {
+            (0, TransactionError::ScriptFailure(_)) => {}
+            ref error => panic!("unexpected error {:?}", error),
+        },
+        result => panic!("unexpected result {:?}", result),
+    }
+
+    assert_eq!(verifier.skip_script_verify().verify(&block), Ok(()));
+}
+
+#[test]
+pub fn test_transactions_verifier_conflict() {
+    let cellbase = TransactionBuilder::default()
+        .input(CellInput::new_cellbase_input(0))
+        .outputs(vec![
+            CellOutput::new(100, Vec::new(), H256::default(), None);
+            2
+        ]).build();
+    let spend = |index, capacity| {
+        TransactionBuilder::default()
+            .input(CellInput::new(
+                OutPoint::new(cellbase.hash(), index),
+                Default::default(),
+            )).output(CellOutput::new(capacity, Vec::new(), H256::default(), None))
+            .build()
+    };
+    // the second and the third transaction spend the same cellbase output
+    let transactions = vec![spend(0, 100), spend(1, 100), spend(1, 50)];
+
+    let block = BlockBuilder::default()
+        .commit_transaction(cellbase.clone())
+        .commit_transactions(transactions)
+        .build();
+
+    let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory().build();
+    let verifier = TransactionsVerifier::new(shared).skip_script_verify();
+    let expected = Err(VerifyError::Conflict {
+        out_point: OutPoint::new(cellbase.hash(), 1),
+        first: 1,
+        second: 2,
+    });
+    assert_eq!(verifier.verify(&block), expected);
+    assert_eq!(verifier.verify_all(&block), expected);
+}
+
+#[test]
+pub fn test_transactions_verifier_cellbase_maturity() {
+    let cellbase = TransactionBuilder::default()
+        .input(CellInput::new_cellbase_input(0))
+        .output(CellOutput::new(100, Vec::new(), H256::default(), None))
+        .build();
+    let transaction = TransactionBuilder::default()
+        .input(CellInput::new(
+            OutPoint::new(cellbase.hash(), 0),
+            Default::default(),
+        )).output(CellOutput::new(100, Vec::new(), H256::default(), None))
+        .build();
+    let block = BlockBuilder::default()
+        .commit_transaction(cellbase)
+        .commit_transaction(transaction)
+        .build();
+
+    let verifier = |cellbase_maturity, activation| {
+        let consensus = Consensus::default()
+            .set_cellbase_maturity(cellbase_maturity)
+            .set_hardfork_switch(HardForkSwitch {
+                cellbase_maturity: activation,
+                ..Default::default()
+            });
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+            .consensus(consensus)
+            .build();
+        TransactionsVerifier::new(shared).skip_script_verify()
+    };
+    // the cellbase of the block itself is the youngest one there is
+    assert_eq!(
+        verifier(1, Some(0)).verify(&block),
+        Err(VerifyError::Transactions(vec![(
+            0,
+            TransactionError::CellbaseImmaturity
+        )]))
+    );
+    assert_eq!(verifier(0, Some(0)).verify(&block), Ok(()));
+    // not before the hard fork
+    assert_eq!(verifier(1, Some(1)).verify(&block), Ok(()));
+    assert_eq!(verifier(1, None).verify(&block), Ok(()));
+}
+
+#[test]
+pub fn test_transactions_verifier_cycles() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../nodes_template/spec/cells");
+    let mut file = File::open(path.join("always_success")).unwrap();
+    let mut binary = Vec::new();
+    file.read_to_end(&mut binary).unwrap();
+    let unlock = Script::new(0, Vec::new(), None, Some(binary), Vec::new());
+    let output = CellOutput::new(100, Vec::new(), unlock.type_hash(), None);
+    let cellbase = TransactionBuilder::default()
+        .input(CellInput::new_cellbase_input(0))
+        .outputs(vec![output; 2])
+        .build();
+    let spend = |index| {
+        TransactionBuilder::default()
+            .input(CellInput::new(
+                OutPoint::new(cellbase.hash(), index),
+                unlock.clone(),
+            )).output(CellOutput::new(100, Vec::new(), H256::default(), None))
+            .build()
+    };
+    let block = BlockBuilder::default()
+        .commit_transaction(cellbase.clone())
+        .commit_transactions(vec![spend(0), spend(1)])
+        .build();
+
+    let verifier = |max_block_cycles| {
+        let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+            .consensus(
+                Consensus::default()
+                    .set_max_block_cycles(max_block_cycles)
+                    .set_cellbase_maturity(0),
+            )
+            .build();
+        TransactionsVerifier::new(shared)
+    };
+    // the budget covers the scripts of all the transactions together
+    let rtx = ResolvedTransaction {
+        transaction: spend(0),
+        dep_cells: vec![],
+        input_cells: vec![],
+    };
+    let tx_cycles = TransactionScriptsVerifier::new(&rtx, H256::zero())
+        .verify(u64::max_value())
+        .unwrap();
+    assert_eq!(verifier(2 * tx_cycles).verify(&block), Ok(()));
+    assert_eq!(
+        verifier(2 * tx_cycles - 1).verify(&block),
+        Err(VerifyError::ExceededMaximumBlockCycles {
+            max: 2 * tx_cycles - 1,
+            actual: 2 * tx_cycles,
+        })
+    );
+}
@@ -2,7 +2,7 @@ use super::super::block_verifier::CommitVerifier;
 use super::super::error::{CommitError, Error};
 use bigint::{H256, U256};
 use ckb_chain::chain::{ChainBuilder, ChainController};
-use ckb_chain_spec::consensus::Consensus;
+use ckb_chain_spec::consensus::{Consensus, ProposalWindow};
 use ckb_core::block::{Block, BlockBuilder};
 use ckb_core::header::{Header, HeaderBuilder};
 use ckb_core::script::Script;
@@ -315,7 +315,7 @@ fn test_proposal_timeout() {
     );
     parent = block.header().clone();
 
-    let timeout = shared.consensus().transaction_propagation_timeout;
+    let timeout = shared.consensus().proposal_window().far;
 
     for _ in 0..timeout - 1 {
         let block = gen_block(parent, vec![], vec![], vec![]);
@@ -346,3 +346,54 @@ fn test_proposal_timeout() {
         Err(Error::Commit(CommitError::Invalid))
     );
 }
+
+#[test]
+fn test_proposal_too_early() {
+    let tx = TransactionBuilder::default()
+        .input(CellInput::new(OutPoint::null(), Default::default()))
+        .outputs(vec![
+            CellOutput::new(
+                100_000_000,
+                Vec::new(),
+                H256::default(),
+                None,
+            );
+            100
+        ]).build();
+    let root_hash = tx.hash();
+    let genesis_block = BlockBuilder::default().commit_transaction(tx).build();
+    let consensus = Consensus::default()
+        .set_genesis_block(genesis_block)
+        .set_proposal_window(ProposalWindow { close: 2, far: 10 });
+    let (chain_controller, shared) = start_chain(Some(consensus));
+
+    let mut parent = shared.block_header(&shared.block_hash(0).unwrap()).unwrap();
+
+    let tx = create_transaction(root_hash);
+    let block = gen_block(parent, vec![], vec![tx.proposal_short_id()], vec![]);
+    assert!(
+        chain_controller
+            .process_block(Arc::new(block.clone()))
+            .is_ok()
+    );
+    parent = block.header().clone();
+
+    // the proposal is 1 block old, the window opens at 2
+    let verifier = CommitVerifier::new(shared.clone());
+    let new_block = gen_block(parent.clone(), vec![tx.clone()], vec![], vec![]);
+    assert_eq!(
+        verifier.verify(&new_block),
+        Err(Error::Commit(CommitError::Invalid))
+    );
+
+    let block = gen_block(parent, vec![], vec![], vec![]);
+    assert!(
+        chain_controller
+            .process_block(Arc::new(block.clone()))
+            .is_ok()
+    );
+    parent = block.header().clone();
+
+    let new_block = gen_block(parent, vec![tx], vec![], vec![]);
+    assert_eq!(verifier.verify(&new_block), Ok(()));
+}
@@ -8,13 +8,17 @@ use ckb_core::transaction::{Capacity, OutPoint, ProposalShortId, Transaction};
 use ckb_core::transaction_meta::TransactionMeta;
 use ckb_core::uncle::UncleBlock;
 use ckb_shared::error::SharedError;
-use ckb_shared::shared::ChainProvider;
+use ckb_shared::index::CellSetIter;
+use ckb_shared::shared::{ChainProvider, TxsVerifyCache};
+use ckb_util::Mutex;
+use fnv::FnvHashSet;
 use std::collections::HashMap;
 
 #[derive(Default, Clone)]
 pub struct DummyChainProvider {
     pub transaction_fees: HashMap<H256, Result<Capacity, SharedError>>,
     pub block_reward: Capacity,
+    pub uncle_reward: Capacity,
 }
 
 impl ChainProvider for DummyChainProvider {
@@ -22,6 +26,10 @@ impl ChainProvider for DummyChainProvider {
         self.block_reward
     }
 
+    fn uncles_reward(&self, _block_number: BlockNumber, uncles_count: usize) -> Capacity {
+        self.uncle_reward * uncles_count as Capacity
+    }
+
     fn calculate_transaction_fee(
         &self,
         transaction: &Transaction,
@@ -29,6 +37,14 @@ impl ChainProvider for DummyChainProvider {
         self.transaction_fees[&transaction.hash()].clone()
     }
 
+    fn calculate_transaction_fee_with(
+        &self,
+        _transaction: &Transaction,
+        _get_transaction: &Fn(&H256) -> Option<Transaction>,
+    ) -> Result<Capacity, SharedError> {
+        panic!("Not implemented!");
+    }
+
     fn union_proposal_ids_n(&self, _bn: BlockNumber, _n: usize) -> Vec<Vec<ProposalShortId>> {
         panic!("Not implemented!");
     }
@@ -93,13 +109,50 @@ impl ChainProvider for DummyChainProvider {
         panic!("Not implemented!");
     }
 
+    fn get_locator(&self, _tip: &Header) -> Vec<H256> {
+        panic!("Not implemented!");
+    }
+
+    fn get_locator_with(
+        &self,
+        _tip: &Header,
+        _get_header: &Fn(&H256) -> Option<Header>,
+    ) -> Vec<H256> {
+        panic!("Not implemented!");
+    }
+
     fn calculate_difficulty(&self, _last: &Header) -> Option<U256> {
         panic!("Not implemented!");
     }
 
+    fn calculate_difficulty_with(
+        &self,
+        _last: &Header,
+        _get_ancestor: &Fn(&H256, BlockNumber) -> Option<Header>,
+        _get_total_uncles_count: &Fn(&H256) -> Option<u64>,
+    ) -> Option<U256> {
+        panic!("Not implemented!");
+    }
+
     fn consensus(&self) -> &Consensus {
         panic!("Not implemented!");
     }
+
+    fn cell_set_len(&self) -> u64 {
+        panic!("Not implemented!");
+    }
+
+    fn cell_set_iter<'a>(&'a self) -> CellSetIter<'a> {
+        panic!("Not implemented!");
+    }
+
+    fn txs_verify_cache(&self) -> &Mutex<TxsVerifyCache> {
+        panic!("Not implemented!");
+    }
+
+    fn uncle_exclusion_set(&self, _tip: &H256) -> FnvHashSet<H256> {
+        panic!("Not implemented!");
+    }
 }
 
 impl CellProvider for DummyChainProvider {
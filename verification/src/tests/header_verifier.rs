@@ -0,0 +1,177 @@
+use super::super::block_verifier::HeaderResolverWrapper;
+use super::super::error::{Error, TimestampError};
+use super::super::header_verifier::{median_time, BlockMedianTimeContext, HeaderVerifier};
+use super::super::Verifier;
+use bigint::H256;
+use ckb_chain::chain::{ChainBuilder, ChainController};
+use ckb_chain_spec::consensus::Consensus;
+use ckb_core::block::{Block, BlockBuilder};
+use ckb_core::header::{Header, HeaderBuilder};
+use ckb_core::transaction::{CellInput, CellOutput, TransactionBuilder};
+use ckb_db::memorydb::MemoryKeyValueDB;
+use ckb_shared::shared::{ChainProvider, Shared, SharedBuilder};
+use ckb_shared::store::ChainKVStore;
+use ckb_time::set_mock_timer;
+use std::sync::Arc;
+
+const NOW: u64 = 10_000;
+
+fn gen_block(parent_header: &Header, timestamp: u64) -> Block {
+    let number = parent_header.number() + 1;
+    let cellbase = TransactionBuilder::default()
+        .input(CellInput::new_cellbase_input(number))
+        .output(CellOutput::new(0, vec![], H256::from(0), None))
+        .build();
+    let header_builder = HeaderBuilder::default()
+        .parent_hash(&parent_header.hash())
+        .timestamp(timestamp)
+        .number(number)
+        .difficulty(&parent_header.difficulty())
+        .cellbase_id(&cellbase.hash());
+
+    BlockBuilder::default()
+        .commit_transaction(cellbase)
+        .with_header_builder(header_builder)
+}
+
+/// Appends blocks with the timestamps to the genesis, they are stored without verification
+fn start_chain(
+    consensus: Consensus,
+    timestamps: &[u64],
+) -> (ChainController, Shared<ChainKVStore<MemoryKeyValueDB>>) {
+    let shared = SharedBuilder::<ChainKVStore<MemoryKeyValueDB>>::new_memory()
+        .consensus(consensus.set_verification(false))
+        .build();
+
+    let (chain_controller, chain_receivers) = ChainController::new();
+    let chain_service = ChainBuilder::new(shared.clone()).build();
+    let _handle = chain_service.start::<&str>(None, chain_receivers);
+
+    for timestamp in timestamps {
+        let block = gen_block(&tip_header(&shared), *timestamp);
+        chain_controller
+            .process_block(Arc::new(block))
+            .expect("process block should be OK");
+    }
+    (chain_controller, shared)
+}
+
+fn tip_header(shared: &Shared<ChainKVStore<MemoryKeyValueDB>>) -> Header {
+    shared.tip_header().read().inner().clone()
+}
+
+fn verify_next(
+    shared: &Shared<ChainKVStore<MemoryKeyValueDB>>,
+    timestamp: u64,
+) -> Result<(), Error> {
+    set_mock_timer(NOW);
+    let block = gen_block(&tip_header(shared), timestamp);
+    let resolver = HeaderResolverWrapper::new(block.header(), shared.clone());
+    HeaderVerifier::new(shared.consensus().pow_engine()).verify(&resolver)
+}
+
+fn too_old(min: u64, found: u64) -> Result<(), Error> {
+    Err(Error::Timestamp(TimestampError::BlockTimeTooOld {
+        min,
+        found,
+    }))
+}
+
+#[test]
+fn test_median_time() {
+    assert_eq!(median_time(vec![3, 1, 2]), 2);
+    // the lower of the two middle timestamps
+    assert_eq!(median_time(vec![4, 1, 3, 2]), 2);
+    assert_eq!(median_time(vec![7]), 7);
+    assert_eq!(median_time(Vec::new()), 0);
+}
+
+#[test]
+fn test_block_median_time() {
+    let consensus = Consensus::default().set_median_time_block_count(3);
+    let (_chain_controller, shared) = start_chain(consensus, &[1003, 1001, 1002, 1000]);
+
+    let hash = |number| shared.block_hash(number).unwrap();
+    // the median of 1001, 1002 and 1000
+    assert_eq!(shared.block_median_time(&hash(4)), Some(1001));
+    // the window of the first block holds the genesis at 0
+    assert_eq!(shared.block_median_time(&hash(1)), Some(0));
+    assert_eq!(shared.block_median_time(&H256::from(1)), None);
+}
+
+#[test]
+fn test_timestamp_at_median_time() {
+    // the window holds the 11 blocks 1001..=1011, their median is 1006
+    let timestamps = (1001..1012).collect::<Vec<_>>();
+    let (_chain_controller, shared) = start_chain(Consensus::default(), &timestamps);
+
+    assert_eq!(verify_next(&shared, 1006), too_old(1007, 1006));
+    // older than the parent, but newer than the median time
+    assert_eq!(verify_next(&shared, 1007), Ok(()));
+}
+
+#[test]
+fn test_median_time_block_count() {
+    let timestamps = (1001..1012).collect::<Vec<_>>();
+    let consensus = Consensus::default().set_median_time_block_count(3);
+    let (_chain_controller, shared) = start_chain(consensus, &timestamps);
+
+    // the median of 1009, 1010 and 1011
+    assert_eq!(verify_next(&shared, 1010), too_old(1011, 1010));
+    assert_eq!(verify_next(&shared, 1011), Ok(()));
+}
+
+#[test]
+fn test_median_time_window_near_genesis() {
+    // the window holds the genesis at 0 and the blocks 1001 and 1002
+    let (_chain_controller, shared) = start_chain(Consensus::default(), &[1001, 1002]);
+
+    assert_eq!(verify_next(&shared, 1001), too_old(1002, 1001));
+    assert_eq!(verify_next(&shared, 1002), Ok(()));
+}
+
+#[test]
+fn test_decreasing_timestamps() {
+    let timestamps = (1001..1012).collect::<Vec<_>>();
+    let (chain_controller, shared) = start_chain(Consensus::default(), &timestamps);
+
+    // every block goes back in time by 1ms, the median time catches up after two blocks
+    for timestamp in &[1010, 1009] {
+        assert_eq!(verify_next(&shared, *timestamp), Ok(()));
+        let block = gen_block(&tip_header(&shared), *timestamp);
+        chain_controller
+            .process_block(Arc::new(block))
+            .expect("process block should be OK");
+    }
+    assert_eq!(verify_next(&shared, 1008), too_old(1009, 1008));
+}
+
+#[test]
+fn test_far_future_timestamp() {
+    let (_chain_controller, shared) = start_chain(Consensus::default(), &[1001]);
+    let max = NOW + shared.consensus().max_block_time_drift();
+
+    assert_eq!(verify_next(&shared, max), Ok(()));
+    assert_eq!(
+        verify_next(&shared, max + 1),
+        Err(Error::Timestamp(TimestampError::FutureBlockTime {
+            max,
+            found: max + 1,
+        }))
+    );
+}
+
+#[test]
+fn test_max_block_time_drift() {
+    let consensus = Consensus::default().set_max_block_time_drift(0);
+    let (_chain_controller, shared) = start_chain(consensus, &[1001]);
+
+    assert_eq!(verify_next(&shared, NOW), Ok(()));
+    assert_eq!(
+        verify_next(&shared, NOW + 1),
+        Err(Error::Timestamp(TimestampError::FutureBlockTime {
+            max: NOW,
+            found: NOW + 1,
+        }))
+    );
+}
@@ -1,5 +1,6 @@
 mod block_verifier;
 mod commit_verifier;
 mod dummy;
+mod header_verifier;
 mod transaction_verifier;
 mod uncle_verifier;
@@ -1,11 +1,20 @@
 use super::super::transaction_verifier::{
-    CapacityVerifier, DuplicateInputsVerifier, EmptyVerifier, NullVerifier,
+    CapacityVerifier, DataSizeVerifier, DuplicateInputsVerifier, EmptyVerifier, InputVerifier,
+    NullVerifier, ScriptVersionVerifier, TransactionVerifier,
 };
 use bigint::H256;
+use ckb_chain_spec::consensus::Consensus;
+use ckb_chain_spec::hardfork::HardForkSwitch;
 use ckb_core::cell::CellStatus;
 use ckb_core::cell::ResolvedTransaction;
+use ckb_core::script::Script;
 use ckb_core::transaction::{CellInput, CellOutput, OutPoint, TransactionBuilder};
+use ckb_core::transaction::Transaction;
+use ckb_script::{ScriptError, ScriptFailure, ScriptLocation};
+use ckb_util::Mutex;
 use error::TransactionError;
+use lru_cache::LruCache;
+use proptest::prelude::*;
 
 #[test]
 pub fn test_null() {
@@ -26,6 +35,23 @@ pub fn test_empty() {
     assert_eq!(verifier.verify().err(), Some(TransactionError::Empty));
 }
 
+#[test]
+pub fn test_cell_data_size() {
+    let transaction = TransactionBuilder::default()
+        .output(CellOutput::new(100, vec![1; 10], H256::from(0), None))
+        .output(CellOutput::new(100, vec![1; 11], H256::from(0), None))
+        .build();
+
+    assert!(DataSizeVerifier::new(&transaction, 11).verify().is_ok());
+    assert_eq!(
+        DataSizeVerifier::new(&transaction, 10).verify().err(),
+        Some(TransactionError::ExceededMaximumCellDataBytes {
+            max: 10,
+            actual: 11,
+        })
+    );
+}
+
 #[test]
 pub fn test_capacity_outofbound() {
     let transaction = TransactionBuilder::default()
@@ -47,6 +73,33 @@ pub fn test_capacity_outofbound() {
     assert_eq!(verifier.verify().err(), Some(TransactionError::OutofBound));
 }
 
+#[test]
+pub fn test_capacity_outputs_overflow() {
+    // the wrapped sum of the outputs would be 49, less than the input
+    let transaction = TransactionBuilder::default()
+        .outputs(vec![
+            CellOutput::new(u64::max_value(), Vec::new(), H256::from(0), None),
+            CellOutput::new(50, Vec::new(), H256::from(0), None),
+        ]).build();
+
+    let rtx = ResolvedTransaction {
+        transaction,
+        dep_cells: Vec::new(),
+        input_cells: vec![CellStatus::Current(CellOutput::new(
+            100,
+            Vec::new(),
+            H256::from(0),
+            None,
+        ))],
+    };
+    let verifier = CapacityVerifier::new(&rtx);
+
+    assert_eq!(
+        verifier.verify().err(),
+        Some(TransactionError::OutputsSumOverflow)
+    );
+}
+
 #[test]
 pub fn test_capacity_invalid() {
     let transaction = TransactionBuilder::default()
@@ -86,3 +139,137 @@ pub fn test_duplicate_inputs() {
         Some(TransactionError::DuplicateInputs)
     );
 }
+
+proptest! {
+    #[test]
+    fn duplicated_input_is_rejected(ref tx in any::<Transaction>(), ref input in any::<CellInput>()) {
+        let transaction = TransactionBuilder::default()
+            .transaction(tx.clone())
+            .inputs(vec![input.clone(), input.clone()])
+            .build();
+        let verifier = DuplicateInputsVerifier::new(&transaction);
+        prop_assert_eq!(verifier.verify().err(), Some(TransactionError::DuplicateInputs));
+    }
+}
+
+#[test]
+pub fn test_lock_hash_mismatch() {
+    let unlock = Script::default();
+    let transaction = TransactionBuilder::default()
+        .input(CellInput::new(
+            OutPoint::new(H256::from(1), 0),
+            unlock.clone(),
+        )).build();
+
+    let rtx = ResolvedTransaction {
+        transaction: transaction.clone(),
+        dep_cells: Vec::new(),
+        input_cells: vec![CellStatus::Current(CellOutput::new(
+            50,
+            Vec::new(),
+            H256::from(0),
+            None,
+        ))],
+    };
+    let verifier = InputVerifier::new(&rtx);
+    assert_eq!(
+        verifier.verify().err(),
+        Some(TransactionError::LockHashMismatch)
+    );
+
+    let rtx = ResolvedTransaction {
+        transaction,
+        dep_cells: Vec::new(),
+        input_cells: vec![CellStatus::Current(CellOutput::new(
+            50,
+            Vec::new(),
+            unlock.type_hash(),
+            None,
+        ))],
+    };
+    let verifier = InputVerifier::new(&rtx);
+    assert!(verifier.verify().is_ok());
+}
+
+#[test]
+pub fn test_script_version_activation() {
+    let consensus = Consensus::default().set_hardfork_switch(HardForkSwitch {
+        script_version_1: Some(2),
+        ..Default::default()
+    });
+    let epoch_length = consensus.difficulty_adjustment_interval();
+    let max_script_version = |number| {
+        consensus
+            .hardfork_switch()
+            .max_script_version(consensus.epoch_number(number))
+    };
+    let transaction = |version| {
+        let contract = Script::new(version, Vec::new(), None, None, Vec::new());
+        TransactionBuilder::default()
+            .input(CellInput::new(OutPoint::new(H256::from(1), 0), Script::default()))
+            .output(CellOutput::new(50, Vec::new(), H256::from(0), Some(contract)))
+            .build()
+    };
+
+    let before = max_script_version(2 * epoch_length - 1);
+    assert!(ScriptVersionVerifier::new(&transaction(0), before).verify().is_ok());
+    assert_eq!(
+        ScriptVersionVerifier::new(&transaction(1), before).verify().err(),
+        Some(TransactionError::ScriptVersion { max: 0, actual: 1 })
+    );
+
+    let after = max_script_version(2 * epoch_length);
+    assert!(ScriptVersionVerifier::new(&transaction(1), after).verify().is_ok());
+    assert_eq!(
+        ScriptVersionVerifier::new(&transaction(2), after).verify().err(),
+        Some(TransactionError::ScriptVersion { max: 1, actual: 2 })
+    );
+}
+
+#[test]
+pub fn test_verify_with_cache() {
+    // the unlock has no script to run, it can only pass from the cache
+    let unlock = Script::default();
+    let transaction = TransactionBuilder::default()
+        .input(CellInput::new(OutPoint::new(H256::from(1), 0), unlock.clone()))
+        .output(CellOutput::new(50, Vec::new(), H256::from(0), None))
+        .build();
+    let rtx = ResolvedTransaction {
+        transaction,
+        dep_cells: Vec::new(),
+        input_cells: vec![CellStatus::Current(CellOutput::new(
+            50,
+            Vec::new(),
+            unlock.type_hash(),
+            None,
+        ))],
+    };
+    let verifier = TransactionVerifier::new(&rtx, 0, 100, 1_000_000, H256::zero());
+    let cache = Mutex::new(LruCache::new(10, false));
+
+    let failure = Err(TransactionError::ScriptFailure(ScriptFailure {
+        location: ScriptLocation::Input(0),
+        script_hash: unlock.type_hash(),
+        error: ScriptError::NoScript,
+        cycles: 0,
+    }));
+    assert_eq!(verifier.verify_with_cache(&cache), failure);
+    assert!(!cache.lock().contains_key(&rtx.transaction.hash()));
+
+    // the cycles of the first run are reported
+    cache.lock().insert(rtx.transaction.hash(), 7);
+    assert_eq!(verifier.verify_with_cache(&cache), Ok(7));
+    assert_eq!(verifier.verify(), failure);
+
+    // the other checks still run for a cached transaction
+    let spent = ResolvedTransaction {
+        transaction: rtx.transaction.clone(),
+        dep_cells: Vec::new(),
+        input_cells: vec![CellStatus::Old],
+    };
+    let verifier = TransactionVerifier::new(&spent, 0, 100, 1_000_000, H256::zero());
+    assert_eq!(
+        verifier.verify_with_cache(&cache),
+        Err(TransactionError::DoubleSpent)
+    );
+}
@@ -1,38 +1,92 @@
-use ckb_core::cell::ResolvedTransaction;
+use bigint::H256;
+use ckb_core::cell::{CellProvider, ResolvedTransaction};
 use ckb_core::transaction::{Capacity, Transaction};
-use ckb_script::TransactionScriptsVerifier;
+use ckb_core::{BlockNumber, Cycle};
+use ckb_script::{ScriptProfiler, TransactionScriptsVerifier};
+use ckb_shared::shared::TxsVerifyCache;
+use ckb_util::Mutex;
 use error::TransactionError;
 use std::collections::HashSet;
 
 pub struct TransactionVerifier<'a> {
     pub null: NullVerifier<'a>,
     pub empty: EmptyVerifier<'a>,
+    pub data_size: DataSizeVerifier<'a>,
     pub capacity: CapacityVerifier<'a>,
     pub duplicate_inputs: DuplicateInputsVerifier<'a>,
     pub inputs: InputVerifier<'a>,
+    pub script_version: ScriptVersionVerifier<'a>,
     pub script: ScriptVerifier<'a>,
 }
 
 impl<'a> TransactionVerifier<'a> {
-    pub fn new(rtx: &'a ResolvedTransaction) -> Self {
+    /// `max_script_version` is the highest script version active in the epoch the
+    /// transaction is committed in, see `HardForkSwitch::max_script_version`.
+    /// `max_cell_data_bytes` is the consensus limit of the data of an output.
+    /// `max_cycles` bounds the cycles of all the scripts, see `Consensus::max_block_cycles`.
+    /// `chain_id` is the one of `Consensus::chain_id`, see `ScriptVerifier`.
+    pub fn new(
+        rtx: &'a ResolvedTransaction,
+        max_script_version: u8,
+        max_cell_data_bytes: u64,
+        max_cycles: Cycle,
+        chain_id: H256,
+    ) -> Self {
         TransactionVerifier {
             null: NullVerifier::new(&rtx.transaction),
             empty: EmptyVerifier::new(&rtx.transaction),
+            data_size: DataSizeVerifier::new(&rtx.transaction, max_cell_data_bytes),
             duplicate_inputs: DuplicateInputsVerifier::new(&rtx.transaction),
-            script: ScriptVerifier::new(rtx),
+            script_version: ScriptVersionVerifier::new(&rtx.transaction, max_script_version),
+            script: ScriptVerifier::new(rtx, max_cycles, chain_id),
             capacity: CapacityVerifier::new(rtx),
             inputs: InputVerifier::new(rtx),
         }
     }
 
-    pub fn verify(&self) -> Result<(), TransactionError> {
+    /// The cycles of the scripts of the transaction
+    pub fn verify(&self) -> Result<Cycle, TransactionError> {
+        // InputVerifier should be executed before ScriptVerifier
+        self.verify_without_script()?;
+        self.script.verify()
+    }
+
+    /// Runs the scripts with `profiler` recording them, whether they are in a cache or not
+    pub fn verify_profiled(
+        &self,
+        profiler: &dyn ScriptProfiler,
+    ) -> Result<Cycle, TransactionError> {
+        self.verify_without_script()?;
+        self.script.verify_profiled(profiler)
+    }
+
+    /// Skips the scripts of a transaction found in `cache`, they passed before and their result
+    /// only depends on the transaction and the cells it references, and records the
+    /// transactions passing them. The other checks always run.
+    pub fn verify_with_cache(
+        &self,
+        cache: &Mutex<TxsVerifyCache>,
+    ) -> Result<Cycle, TransactionError> {
+        self.verify_without_script()?;
+        let hash = self.script.resolved_transaction.transaction.hash();
+        if let Some(cycles) = cache.lock().get(&hash) {
+            return Ok(*cycles);
+        }
+        // the lock isn't held while the scripts run, the verifications are parallel
+        let cycles = self.script.verify()?;
+        cache.lock().insert(hash, cycles);
+        Ok(cycles)
+    }
+
+    /// Runs every check except the scripts, only meant for blocks from a trusted source
+    pub fn verify_without_script(&self) -> Result<(), TransactionError> {
         self.empty.verify()?;
+        self.data_size.verify()?;
         self.null.verify()?;
         self.capacity.verify()?;
         self.duplicate_inputs.verify()?;
-        // InputVerifier should be executed before ScriptVerifier
         self.inputs.verify()?;
-        self.script.verify()?;
+        self.script_version.verify()?;
         Ok(())
     }
 }
@@ -52,11 +106,13 @@ impl<'a> InputVerifier<'a> {
         let mut inputs = self.resolved_transaction.transaction.inputs().iter();
         for cs in &self.resolved_transaction.input_cells {
             if cs.is_current() {
-                if let Some(ref input) = cs.get_current() {
+                if let Some(ref output) = cs.get_current() {
+                    // The input reveals the lock script the spent output committed to,
+                    // its args are the witness checked when the script runs.
                     // TODO: remove this once VM mmap is in place so we can
                     // do P2SH within the VM.
-                    if input.lock != inputs.next().unwrap().unlock.type_hash() {
-                        return Err(TransactionError::InvalidScript);
+                    if output.lock_hash() != inputs.next().unwrap().lock_hash() {
+                        return Err(TransactionError::LockHashMismatch);
                     }
                 }
             } else if cs.is_old() {
@@ -77,22 +133,80 @@ impl<'a> InputVerifier<'a> {
     }
 }
 
+/// Runs the unlock scripts of the inputs and the contracts of the outputs.
+///
+/// Scripts from version 2 get the chain id as their last argument. The system lock script
+/// checks its signature over all the arguments following it, so the signature of a version 2
+/// unlock also covers the chain id, and a transaction signed for one chain fails on any other.
+///
+/// The VM counts the cycles of the scripts and stops the one using up `max_cycles`.
 pub struct ScriptVerifier<'a> {
     resolved_transaction: &'a ResolvedTransaction,
+    max_cycles: Cycle,
+    chain_id: H256,
 }
 
 impl<'a> ScriptVerifier<'a> {
-    pub fn new(resolved_transaction: &'a ResolvedTransaction) -> Self {
+    pub fn new(
+        resolved_transaction: &'a ResolvedTransaction,
+        max_cycles: Cycle,
+        chain_id: H256,
+    ) -> Self {
         ScriptVerifier {
             resolved_transaction,
+            max_cycles,
+            chain_id,
         }
     }
 
-    pub fn verify(&self) -> Result<(), TransactionError> {
-        TransactionScriptsVerifier::new(&self.resolved_transaction)
-            .verify()
+    pub fn verify(&self) -> Result<Cycle, TransactionError> {
+        TransactionScriptsVerifier::new(&self.resolved_transaction, self.chain_id)
+            .verify(self.max_cycles)
             .map_err(TransactionError::ScriptFailure)
     }
+
+    pub fn verify_profiled(
+        &self,
+        profiler: &dyn ScriptProfiler,
+    ) -> Result<Cycle, TransactionError> {
+        let profile = TransactionScriptsVerifier::new(&self.resolved_transaction, self.chain_id)
+            .profile(self.max_cycles);
+        profiler.record(&profile);
+        profile.result().map_err(TransactionError::ScriptFailure)
+    }
+}
+
+pub struct ScriptVersionVerifier<'a> {
+    transaction: &'a Transaction,
+    max_script_version: u8,
+}
+
+impl<'a> ScriptVersionVerifier<'a> {
+    pub fn new(transaction: &'a Transaction, max_script_version: u8) -> Self {
+        ScriptVersionVerifier {
+            transaction,
+            max_script_version,
+        }
+    }
+
+    pub fn verify(&self) -> Result<(), TransactionError> {
+        let unlocks = self.transaction.inputs().iter().map(|input| &input.unlock);
+        let contracts = self
+            .transaction
+            .outputs()
+            .iter()
+            .filter_map(|output| output.contract.as_ref());
+        match unlocks
+            .chain(contracts)
+            .find(|script| script.version > self.max_script_version)
+        {
+            Some(script) => Err(TransactionError::ScriptVersion {
+                max: self.max_script_version,
+                actual: script.version,
+            }),
+            None => Ok(()),
+        }
+    }
 }
 
 pub struct EmptyVerifier<'a> {
@@ -113,6 +227,78 @@ impl<'a> EmptyVerifier<'a> {
     }
 }
 
+pub struct DataSizeVerifier<'a> {
+    transaction: &'a Transaction,
+    max_cell_data_bytes: u64,
+}
+
+impl<'a> DataSizeVerifier<'a> {
+    pub fn new(transaction: &'a Transaction, max_cell_data_bytes: u64) -> Self {
+        DataSizeVerifier {
+            transaction,
+            max_cell_data_bytes,
+        }
+    }
+
+    pub fn verify(&self) -> Result<(), TransactionError> {
+        match self
+            .transaction
+            .outputs()
+            .iter()
+            .map(|output| output.data.len() as u64)
+            .find(|bytes| *bytes > self.max_cell_data_bytes)
+        {
+            Some(actual) => Err(TransactionError::ExceededMaximumCellDataBytes {
+                max: self.max_cell_data_bytes,
+                actual,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Rejects the spending of a cellbase output before `cellbase_maturity` blocks were added on
+/// top of the block of the cellbase. It needs the origin of the inputs, so it runs along
+/// `TransactionVerifier` with the provider the transaction was resolved with.
+pub struct MaturityVerifier<'a, P: 'a> {
+    transaction: &'a Transaction,
+    provider: &'a P,
+    block_number: BlockNumber,
+    cellbase_maturity: BlockNumber,
+}
+
+impl<'a, P: CellProvider> MaturityVerifier<'a, P> {
+    /// `block_number` is the number of the block committing the transaction
+    pub fn new(
+        transaction: &'a Transaction,
+        provider: &'a P,
+        block_number: BlockNumber,
+        cellbase_maturity: BlockNumber,
+    ) -> Self {
+        MaturityVerifier {
+            transaction,
+            provider,
+            block_number,
+            cellbase_maturity,
+        }
+    }
+
+    pub fn verify(&self) -> Result<(), TransactionError> {
+        let immature = self.transaction.input_pts().iter().any(|out_point| {
+            self.provider
+                .cellbase_number(out_point)
+                .map_or(false, |number| {
+                    self.block_number < number.saturating_add(self.cellbase_maturity)
+                })
+        });
+        if immature {
+            Err(TransactionError::CellbaseImmaturity)
+        } else {
+            Ok(())
+        }
+    }
+}
+
 pub struct DuplicateInputsVerifier<'a> {
     transaction: &'a Transaction,
 }
@@ -169,6 +355,7 @@ impl<'a> CapacityVerifier<'a> {
     }
 
     pub fn verify(&self) -> Result<(), TransactionError> {
+        // the inputs are live cells, their sum is bounded by the capacity issued so far
         let inputs_total = self
             .resolved_transaction
             .input_cells
@@ -179,9 +366,8 @@ impl<'a> CapacityVerifier<'a> {
         let outputs_total = self
             .resolved_transaction
             .transaction
-            .outputs()
-            .iter()
-            .fold(0, |acc, output| acc + output.capacity);
+            .outputs_capacity()
+            .ok_or(TransactionError::OutputsSumOverflow)?;
 
         if inputs_total < outputs_total {
             Err(TransactionError::InvalidCapacity)